@@ -0,0 +1,91 @@
+//! Autonomous system (ASN) lookup of IP addresses, backed by a MaxMind ASN database
+
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind},
+    net::IpAddr,
+    path::Path,
+};
+
+use log::trace;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+/// MaxMind ASN database, like GeoLite2-ASN
+pub struct AsnDatabase {
+    reader: Reader<Vec<u8>>,
+}
+
+impl fmt::Debug for AsnDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsnDatabase")
+            .field("database_type", &self.reader.metadata.database_type)
+            .field("build_epoch", &self.reader.metadata.build_epoch)
+            .finish()
+    }
+}
+
+impl AsnDatabase {
+    /// Load the database from a `.mmdb` file
+    pub fn open<P: AsRef<Path>>(p: P) -> io::Result<AsnDatabase> {
+        AsnDatabase::from_bytes(fs::read(p)?)
+    }
+
+    /// Load the database from bytes of a `.mmdb` file
+    pub fn from_bytes(buf: Vec<u8>) -> io::Result<AsnDatabase> {
+        match Reader::from_source(buf) {
+            Ok(reader) => Ok(AsnDatabase { reader }),
+            Err(err) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid asn database, {}", err),
+            )),
+        }
+    }
+
+    /// Autonomous system number of `ip`, `None` if it is not in the database
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        match self.reader.lookup::<geoip2::Asn>(ip) {
+            Ok(asn) => asn.autonomous_system_number,
+            Err(MaxMindDBError::AddressNotFoundError(..)) => None,
+            Err(err) => {
+                trace!("asn database lookup {} failed, error: {}", ip, err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, net::Ipv4Addr, process, sync::Arc};
+
+    use crate::acl::AccessControl;
+
+    use super::*;
+
+    // Generated by testdata/gen_asn_test_mmdb.py
+    const TEST_DATABASE: &[u8] = include_bytes!("testdata/asn-test.mmdb");
+
+    #[test]
+    fn asn_rules_matched() {
+        let asn_database = AsnDatabase::from_bytes(TEST_DATABASE.to_vec()).unwrap();
+        let net_a = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let net_b = IpAddr::from(Ipv4Addr::new(198, 51, 100, 7));
+        let unknown = IpAddr::from(Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(asn_database.lookup(net_a), Some(64496));
+        assert_eq!(asn_database.lookup(net_b), Some(64511));
+        assert_eq!(asn_database.lookup(unknown), None);
+
+        let path = env::temp_dir().join(format!("ss-acl-asn-test-{}.acl", process::id()));
+        fs::write(&path, "[proxy_all]\n[bypass_list]\nAS64496\n").unwrap();
+        let mut acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // ASN rules never match without a database
+        assert!(acl.check_ip_in_proxy_list(&net_a));
+
+        acl.set_asn_database(Arc::new(asn_database));
+        assert!(!acl.check_ip_in_proxy_list(&net_a));
+        assert!(acl.check_ip_in_proxy_list(&net_b));
+        assert!(acl.check_ip_in_proxy_list(&unknown));
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! This is for advance controlling server behaviors in both local and proxy servers.
 
+#[cfg(feature = "acl-asn")]
+use std::sync::Arc;
 use std::{
     borrow::Cow,
     collections::HashSet,
@@ -21,8 +23,12 @@ use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
 use shadowsocks::{context::Context, relay::socks5::Address};
 
+#[cfg(feature = "acl-asn")]
+pub use self::asn::AsnDatabase;
 use self::sub_domains_tree::SubDomainsTree;
 
+#[cfg(feature = "acl-asn")]
+mod asn;
 mod sub_domains_tree;
 
 /// Strategy mode that ACL is running
@@ -41,6 +47,8 @@ struct Rules {
     rule_regex: RegexSet,
     rule_set: HashSet<String>,
     rule_tree: SubDomainsTree,
+    #[cfg(feature = "acl-asn")]
+    asn: HashSet<u32>,
 }
 
 impl fmt::Debug for Rules {
@@ -79,7 +87,12 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
-        write!(f, "], rule_tree: {:?} }}", self.rule_tree)
+        write!(f, "], rule_tree: {:?}", self.rule_tree)?;
+
+        #[cfg(feature = "acl-asn")]
+        write!(f, ", asn: {:?}", self.asn)?;
+
+        f.write_str(" }")
     }
 }
 
@@ -102,6 +115,8 @@ impl Rules {
             rule_regex,
             rule_set,
             rule_tree,
+            #[cfg(feature = "acl-asn")]
+            asn: HashSet::new(),
         }
     }
 
@@ -122,6 +137,12 @@ impl Rules {
         }
     }
 
+    /// Check if the autonomous system number matches any rules
+    #[cfg(feature = "acl-asn")]
+    fn check_asn_matched(&self, asn: u32) -> bool {
+        self.asn.contains(&asn)
+    }
+
     /// Check if the specified ASCII host matches any rules
     fn check_host_matched(&self, host: &str) -> bool {
         let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
@@ -130,6 +151,11 @@ impl Rules {
 
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
+        #[cfg(feature = "acl-asn")]
+        if !self.asn.is_empty() {
+            return false;
+        }
+
         self.ipv4.is_empty() && self.ipv6.is_empty()
     }
 
@@ -146,6 +172,8 @@ struct ParsingRules {
     rules_regex: Vec<String>,
     rules_set: HashSet<String>,
     rules_tree: SubDomainsTree,
+    #[cfg(feature = "acl-asn")]
+    asn: HashSet<u32>,
 }
 
 impl ParsingRules {
@@ -157,6 +185,8 @@ impl ParsingRules {
             rules_regex: Vec::new(),
             rules_set: HashSet::new(),
             rules_tree: SubDomainsTree::new(),
+            #[cfg(feature = "acl-asn")]
+            asn: HashSet::new(),
         }
     }
 
@@ -172,6 +202,12 @@ impl ParsingRules {
         self.ipv6.add(rule);
     }
 
+    #[cfg(feature = "acl-asn")]
+    fn add_asn_rule(&mut self, asn: u32) {
+        trace!("ASN-RULE AS{}", asn);
+        self.asn.insert(asn);
+    }
+
     fn add_regex_rule(&mut self, mut rule: String) {
         static TREE_SET_RULE_EQUIV: Lazy<Regex> = Lazy::new(|| {
             RegexBuilder::new(
@@ -234,6 +270,52 @@ impl ParsingRules {
         Ok(())
     }
 
+    /// Add a rule line, which could be a CIDR, IP address, ASN, regex or domain with preceding `|` or `||`
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        if let Some(rule) = line.strip_prefix("||") {
+            return self.add_tree_rule(rule);
+        }
+
+        if let Some(rule) = line.strip_prefix('|') {
+            return self.add_set_rule(rule);
+        }
+
+        #[cfg(feature = "acl-asn")]
+        if let Some(asn) = line
+            .strip_prefix("AS")
+            .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            self.add_asn_rule(asn);
+            return Ok(());
+        }
+
+        match line.parse::<IpNet>() {
+            Ok(IpNet::V4(v4)) => {
+                self.add_ipv4_rule(v4);
+            }
+            Ok(IpNet::V6(v6)) => {
+                self.add_ipv6_rule(v6);
+            }
+            Err(..) => {
+                // Maybe it is a pure IpAddr
+                match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => {
+                        self.add_ipv4_rule(v4);
+                    }
+                    Ok(IpAddr::V6(v6)) => {
+                        self.add_ipv6_rule(v6);
+                    }
+                    Err(..) => {
+                        self.add_regex_rule(line.to_owned());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn check_is_ascii<'a>(&self, str: &'a str) -> io::Result<&'a str> {
         if str.is_ascii() {
             // Remove the last `.` of FQDN
@@ -256,13 +338,72 @@ impl ParsingRules {
     }
 
     fn into_rules(self) -> io::Result<Rules> {
-        Ok(Rules::new(
+        #[allow(unused_mut)]
+        let mut rules = Rules::new(
             self.ipv4,
             self.ipv6,
             Self::compile_regex(self.name, self.rules_regex)?,
             self.rules_set,
             self.rules_tree,
-        ))
+        );
+
+        #[cfg(feature = "acl-asn")]
+        {
+            rules.asn = self.asn;
+        }
+
+        Ok(rules)
+    }
+}
+
+/// Destination rules that are not bound to an ACL file
+///
+/// Rules are in the same format of lines in ACL file's sections, for selecting behaviors by target addresses.
+#[derive(Debug, Clone)]
+pub struct AddressRules {
+    rules: Rules,
+}
+
+impl AddressRules {
+    /// Parse rules line by line, empty lines and comments are skipped
+    pub fn from_lines<I, S>(name: &'static str, lines: I) -> io::Result<AddressRules>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut parsing = ParsingRules::new(name);
+        for line in lines {
+            let line = line.as_ref().trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if !line.is_ascii() {
+                warn!("{} rule {} containing non-ASCII characters, skipped", name, line);
+                continue;
+            }
+
+            parsing.add_rule(line)?;
+        }
+
+        Ok(AddressRules {
+            rules: parsing.into_rules()?,
+        })
+    }
+
+    /// Check if `IpAddr` matches any rules
+    pub fn check_ip_matched(&self, ip: &IpAddr) -> bool {
+        self.rules.check_ip_matched(ip)
+    }
+
+    /// Check if target address matches any rules, domain names are not resolved
+    pub fn check_address_matched(&self, addr: &Address) -> bool {
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.rules.check_ip_matched(&saddr.ip()),
+            Address::DomainNameAddress(ref host, ..) => {
+                self.rules.check_host_matched(&AccessControl::convert_to_ascii(host))
+            }
+        }
     }
 }
 
@@ -315,12 +456,16 @@ impl ParsingRules {
 /// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
 /// - Domain with preceding `|` for exact matching, like `|google.com`
 /// - Domain with preceding `||` for matching with subdomains, like `||google.com`
+/// - Autonomous system numbers, like `AS64496`, matching IP addresses by the ASN database
+///   set with `set_asn_database` (feature `acl-asn`)
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
     black_list: Rules,
     white_list: Rules,
     mode: Mode,
+    #[cfg(feature = "acl-asn")]
+    asn_database: Option<Arc<AsnDatabase>>,
 }
 
 impl AccessControl {
@@ -358,16 +503,6 @@ impl AccessControl {
                 continue;
             }
 
-            if let Some(rule) = line.strip_prefix("||") {
-                curr.add_tree_rule(rule)?;
-                continue;
-            }
-
-            if let Some(rule) = line.strip_prefix('|') {
-                curr.add_set_rule(rule)?;
-                continue;
-            }
-
             match line {
                 "[reject_all]" | "[bypass_all]" => {
                     mode = Mode::WhiteList;
@@ -389,30 +524,7 @@ impl AccessControl {
                     curr = &mut proxy;
                     trace!("loading white_list / proxy_list");
                 }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr.add_ipv4_rule(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr.add_ipv6_rule(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr.add_ipv4_rule(v4);
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr.add_ipv6_rule(v6);
-                                }
-                                Err(..) => {
-                                    curr.add_regex_rule(line.to_owned());
-                                }
-                            }
-                        }
-                    }
-                }
+                _ => curr.add_rule(line)?,
             }
         }
 
@@ -421,9 +533,43 @@ impl AccessControl {
             black_list: bypass.into_rules()?,
             white_list: proxy.into_rules()?,
             mode,
+            #[cfg(feature = "acl-asn")]
+            asn_database: None,
         })
     }
 
+    /// Set the ASN database for matching ASN rules
+    ///
+    /// ASN rules never match without a database.
+    #[cfg(feature = "acl-asn")]
+    pub fn set_asn_database(&mut self, asn_database: Arc<AsnDatabase>) {
+        self.asn_database = Some(asn_database);
+    }
+
+    /// Get the ASN database
+    #[cfg(feature = "acl-asn")]
+    pub fn asn_database(&self) -> Option<&Arc<AsnDatabase>> {
+        self.asn_database.as_ref()
+    }
+
+    /// Check if `ip` matches IP or ASN rules of `rules`
+    fn check_ip_matched(&self, rules: &Rules, ip: &IpAddr) -> bool {
+        if rules.check_ip_matched(ip) {
+            return true;
+        }
+
+        #[cfg(feature = "acl-asn")]
+        if let Some(ref asn_database) = self.asn_database {
+            if !rules.asn.is_empty() {
+                if let Some(asn) = asn_database.lookup(*ip) {
+                    return rules.check_asn_matched(asn);
+                }
+            }
+        }
+
+        false
+    }
+
     /// Check if domain name is in proxy_list.
     /// If so, it should be resolved from remote (for Android's DNS relay)
     ///
@@ -471,8 +617,8 @@ impl AccessControl {
     /// Check if `IpAddr` should be proxied
     pub fn check_ip_in_proxy_list(&self, ip: &IpAddr) -> bool {
         match self.mode {
-            Mode::BlackList => !self.black_list.check_ip_matched(ip),
-            Mode::WhiteList => self.white_list.check_ip_matched(ip),
+            Mode::BlackList => !self.check_ip_matched(&self.black_list, ip),
+            Mode::WhiteList => self.check_ip_matched(&self.white_list, ip),
         }
     }
 
@@ -527,11 +673,11 @@ impl AccessControl {
         match self.mode {
             Mode::BlackList => {
                 // Only clients in black_list will be blocked
-                self.black_list.check_ip_matched(&addr.ip())
+                self.check_ip_matched(&self.black_list, &addr.ip())
             }
             Mode::WhiteList => {
                 // Only clients in white_list will be proxied
-                !self.white_list.check_ip_matched(&addr.ip())
+                !self.check_ip_matched(&self.white_list, &addr.ip())
             }
         }
     }
@@ -542,7 +688,7 @@ impl AccessControl {
     ///       resolved addresses are checked in the `lookup_outbound_then!` macro
     pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address) -> bool {
         match outbound {
-            Address::SocketAddress(saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
+            Address::SocketAddress(saddr) => self.check_ip_matched(&self.outbound_block, &saddr.ip()),
             Address::DomainNameAddress(host, port) => {
                 if self.outbound_block.check_host_matched(&Self::convert_to_ascii(host)) {
                     return true;
@@ -550,7 +696,7 @@ impl AccessControl {
 
                 if let Ok(vaddr) = context.dns_resolve(host, *port).await {
                     for addr in vaddr {
-                        if self.outbound_block.check_ip_matched(&addr.ip()) {
+                        if self.check_ip_matched(&self.outbound_block, &addr.ip()) {
                             return true;
                         }
                     }
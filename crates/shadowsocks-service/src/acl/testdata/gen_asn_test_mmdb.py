@@ -0,0 +1,100 @@
+#!/usr/bin/env python3
+# Generates asn-test.mmdb, a tiny IPv4 MaxMind ASN database for unit tests
+#
+# 192.0.2.0/24    => AS64496 "Documentation Net A"
+# 198.51.100.0/24 => AS64511 "Documentation Net B"
+
+import ipaddress
+import os
+import struct
+
+NETWORKS = [
+    ("192.0.2.0/24", 64496, "Documentation Net A"),
+    ("198.51.100.0/24", 64511, "Documentation Net B"),
+]
+
+
+def ctrl(type_id, size):
+    # Sizes up to 284 bytes are enough for the test data
+    assert size < 285
+    ext = b""
+    if size >= 29:
+        ext = bytes([size - 29])
+        size = 29
+    if type_id <= 7:
+        return bytes([(type_id << 5) | size]) + ext
+    return bytes([size, type_id - 7]) + ext
+
+
+def enc_uint(type_id, value, width):
+    raw = value.to_bytes(width, "big").lstrip(b"\x00")
+    return ctrl(type_id, len(raw)) + raw
+
+
+def enc_str(s):
+    raw = s.encode()
+    return ctrl(2, len(raw)) + raw
+
+
+def enc_map(pairs):
+    out = ctrl(7, len(pairs))
+    for k, v in pairs:
+        out += enc_str(k) + v
+    return out
+
+
+def build():
+    data = b""
+    # Trie nodes as [left, right], children are node indexes, ("data", offset) or None
+    nodes = [[None, None]]
+    for cidr, asn, org in NETWORKS:
+        offset = len(data)
+        data += enc_map([
+            ("autonomous_system_number", enc_uint(6, asn, 4)),
+            ("autonomous_system_organization", enc_str(org)),
+        ])
+        net = ipaddress.ip_network(cidr)
+        bits = int(net.network_address)
+        node = 0
+        for i in range(net.prefixlen):
+            bit = (bits >> (31 - i)) & 1
+            if i == net.prefixlen - 1:
+                nodes[node][bit] = ("data", offset)
+            else:
+                if nodes[node][bit] is None:
+                    nodes.append([None, None])
+                    nodes[node][bit] = len(nodes) - 1
+                node = nodes[node][bit]
+
+    node_count = len(nodes)
+
+    def record(r):
+        if r is None:
+            return node_count
+        if isinstance(r, tuple):
+            return node_count + 16 + r[1]
+        return r
+
+    tree = b""
+    for left, right in nodes:
+        tree += record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big")
+
+    metadata = enc_map([
+        ("binary_format_major_version", enc_uint(5, 2, 2)),
+        ("binary_format_minor_version", enc_uint(5, 0, 2)),
+        ("build_epoch", ctrl(9, 1) + b"\x01"),
+        ("database_type", enc_str("GeoLite2-ASN")),
+        ("description", enc_map([("en", enc_str("shadowsocks-rust ASN test database"))])),
+        ("ip_version", enc_uint(5, 4, 2)),
+        ("languages", ctrl(11, 1) + enc_str("en")),
+        ("node_count", enc_uint(6, node_count, 4)),
+        ("record_size", enc_uint(5, 24, 2)),
+    ])
+
+    return tree + b"\x00" * 16 + data + b"\xab\xcd\xefMaxMind.com" + metadata
+
+
+if __name__ == "__main__":
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "asn-test.mmdb")
+    with open(path, "wb") as f:
+        f.write(build())
@@ -41,6 +41,8 @@
 //!
 //! These defined server will be used with a load balancing algorithm.
 
+#[cfg(feature = "acl-asn")]
+use std::sync::Arc;
 use std::{
     borrow::Cow,
     convert::{From, Infallible},
@@ -64,8 +66,10 @@ use log::warn;
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "local-tunnel", feature = "local-dns"))]
 use shadowsocks::relay::socks5::Address;
+#[cfg(feature = "local-udp-quic")]
+use shadowsocks::relay::udprelay::{QuicTransportConfig, TlsVersion};
 use shadowsocks::{
-    config::{ManagerAddr, Mode, ReplayAttackPolicy, ServerAddr, ServerConfig, ServerWeight},
+    config::{ManagerAddr, Mode, ReplayAttackPolicy, ResolveStrategy, ServerAddr, ServerConfig, ServerWeight},
     crypto::v1::CipherKind,
     plugin::PluginConfig,
 };
@@ -73,10 +77,24 @@ use shadowsocks::{
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
 
 use crate::acl::AccessControl;
+#[cfg(feature = "local")]
+use crate::acl::AddressRules;
+#[cfg(feature = "acl-asn")]
+use crate::acl::AsnDatabase;
 #[cfg(feature = "local-dns")]
 use crate::local::dns::NameServerAddr;
+#[cfg(feature = "local-tun")]
+use crate::local::tun::{ProxyProtocolVersion, TcpEarlyDataPolicy, DEFAULT_EARLY_DATA_RESET_TIMEOUT};
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+use crate::local::tunnel::UdpHopLimitMode;
+#[cfg(feature = "local-tunnel")]
+use crate::local::tunnel::{UdpCapacityMode, UdpNatMode, UdpTtlRules};
 #[cfg(feature = "local")]
-use crate::local::socks::config::Socks5AuthConfig;
+use crate::local::{
+    loadbalancing::{ServerPoolClass, ServerPoolClassifier, ServerUnavailablePolicy},
+    socks::config::Socks5AuthConfig,
+};
+use crate::net::{UdpChannelFullPolicy, UdpPaddingPolicy};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -106,6 +124,30 @@ struct SSBalancerConfig {
     check_interval: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_best_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_budget: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unavailable_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_ramp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_pools: Option<Vec<SSServerPoolConfig>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSServerPoolConfig {
+    pool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    targets: Option<Vec<String>>,
+}
+
+#[cfg(feature = "local-tunnel")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSUdpTtlOverrideConfig {
+    timeout: u64,
+    targets: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -139,6 +181,8 @@ struct SSConfig {
     plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_flow_metadata: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
@@ -147,6 +191,44 @@ struct SSConfig {
     udp_timeout: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_max_associations: Option<usize>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_capacity_mode: Option<String>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_nat_mode: Option<String>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_outbound_pool_size: Option<usize>,
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_mmsg_batch_size: Option<usize>,
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_hop_limit_mode: Option<String>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_send_channel_size: Option<usize>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_max_payload_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_padding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_channel_full_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_drain_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_hedged_send: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_reconnect_backoff_min: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_reconnect_backoff_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conn_error_log_interval: Option<u64>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_ttl_overrides: Option<Vec<SSUdpTtlOverrideConfig>>,
 
     #[serde(skip_serializing_if = "Option::is_none", alias = "shadowsocks")]
     servers: Option<Vec<SSServerExtConfig>>,
@@ -164,6 +246,9 @@ struct SSConfig {
     no_delay: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     keep_alive: Option<u64>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_first_byte_timeout: Option<u64>,
 
     #[cfg(all(unix, not(target_os = "android")))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -248,6 +333,81 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-tun")]
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_interface_address: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_mtu: Option<u32>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_auto_correct_mtu: Option<bool>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_clamp_mss_on_mtu_blackhole: Option<bool>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_conn_rate_limit: Option<u32>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_conn_rate_burst: Option<u32>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_bandwidth_limit: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_bandwidth_burst: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_global_bandwidth_limit: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_global_bandwidth_burst: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_send_mss: Option<u16>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_recv_mss: Option<u16>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_mss_overhead: Option<u16>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_idle_timeout: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_connect_timeout: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_recv_buffer_autotune_max: Option<u32>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_round_budget: Option<usize>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_out_queue_limit: Option<usize>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_reset_on_remote_failure: Option<bool>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_reset_on_server_down: Option<bool>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_early_data_policy: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_early_data_timeout: Option<u64>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_proxy_protocol: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_tcp_resolve_strategy: Option<String>,
+    #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "android")))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_outbound_fwmark: Option<u32>,
+    #[cfg(feature = "local-tun-capture")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_capture_path: Option<String>,
 
     /// SOCKS5
     #[cfg(feature = "local")]
@@ -277,6 +437,8 @@ struct SSServerExtConfig {
     plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin_flow_metadata: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
@@ -293,6 +455,32 @@ struct SSServerExtConfig {
     tcp_weight: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_weight: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolve_strategy: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_flows: Option<usize>,
+
+    #[cfg(feature = "local-udp-quic")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_quic: Option<SSQuicTransportConfig>,
+}
+
+#[cfg(feature = "local-udp-quic")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSQuicTransportConfig {
+    server_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root_certificate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_tls_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cipher_suites: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spki_pins: Option<Vec<String>>,
 }
 
 /// Server config type
@@ -821,6 +1009,80 @@ pub struct LocalConfig {
     /// Tun interface's address and netmask
     #[cfg(feature = "local-tun")]
     pub tun_interface_address: Option<IpNet>,
+    /// Tun interface's MTU, checked against the device's real MTU
+    #[cfg(feature = "local-tun")]
+    pub tun_mtu: Option<u32>,
+    /// Use the device's real MTU if it differs from `tun_mtu`
+    #[cfg(feature = "local-tun")]
+    pub tun_auto_correct_mtu: bool,
+    /// Clamp MSS of new TCP connections on paths where an MTU black hole was detected
+    #[cfg(feature = "local-tun")]
+    pub tun_clamp_mss_on_mtu_blackhole: bool,
+    /// New TCP connections allowed from each source IP per second, excess SYNs are replied with RST
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_conn_rate_limit: Option<u32>,
+    /// Burst of new TCP connections allowed from each source IP, defaults to `tun_tcp_conn_rate_limit`
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_conn_rate_burst: Option<u32>,
+    /// Bytes per second allowed in each direction of a TCP connection, unlimited if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_bandwidth_limit: Option<u64>,
+    /// Burst of bytes allowed in each direction of a TCP connection, defaults to `tun_tcp_bandwidth_limit`
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_bandwidth_burst: Option<u64>,
+    /// Bytes per second allowed in each direction of all TCP connections together, unlimited if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_global_bandwidth_limit: Option<u64>,
+    /// Burst of bytes allowed in each direction of all TCP connections, defaults to `tun_tcp_global_bandwidth_limit`
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_global_bandwidth_burst: Option<u64>,
+    /// Maximum size of TCP segments sent to clients, derived from the MTU if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_send_mss: Option<u16>,
+    /// Maximum size of TCP segments advertised to clients, derived from the MTU if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_recv_mss: Option<u16>,
+    /// Overhead subtracted from the MSS derived from the MTU advertised to clients, nothing if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_mss_overhead: Option<u16>,
+    /// Reset TCP connections without data in both directions for this long, 2 hours if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_idle_timeout: Option<Duration>,
+    /// Reset TCP connections whose remotes are not connected in this duration, the system's connect timeout if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_connect_timeout: Option<Duration>,
+    /// Grow receive buffers of TCP connections up to this many bytes while clients send faster than they could hold,
+    /// disabled if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_recv_buffer_autotune_max: Option<u32>,
+    /// Move at most this many bytes in each direction of a TCP connection in a round of the stack, unlimited if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_round_budget: Option<usize>,
+    /// Maximum frames produced by the TCP stack waiting to be written to tun, `DEFAULT_OUT_QUEUE_LIMIT` if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_out_queue_limit: Option<usize>,
+    /// Reset TCP connections of clients if the outbound connection fails in the middle of relaying, instead of
+    /// closing them as if the remote finished
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_reset_on_remote_failure: bool,
+    /// Reset TCP connections of clients relayed through a server when the balancer marks it down
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_reset_on_server_down: bool,
+    /// Behavior for data sent by TCP clients before the outbound connection is established
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_early_data_policy: TcpEarlyDataPolicy,
+    /// Write a PROXY protocol header with endpoints of TCP clients to upstreams before relaying
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// How domain name targets of tun's TCP connections bypassing servers are resolved, races families by default
+    #[cfg(feature = "local-tun")]
+    pub tun_tcp_resolve_strategy: Option<ResolveStrategy>,
+    /// `SO_MARK` of outbound sockets of tun, overrides `outbound_fwmark` for avoiding routing loops
+    #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "android")))]
+    pub tun_outbound_fwmark: Option<u32>,
+    /// Capture raw IP frames of the tun's TCP stack to this file
+    #[cfg(feature = "local-tun-capture")]
+    pub tun_capture_path: Option<PathBuf>,
     /// Tun interface's file descriptor
     #[cfg(all(feature = "local-tun", unix))]
     pub tun_device_fd: Option<std::os::unix::io::RawFd>,
@@ -864,6 +1126,54 @@ impl LocalConfig {
             tun_interface_name: None,
             #[cfg(feature = "local-tun")]
             tun_interface_address: None,
+            #[cfg(feature = "local-tun")]
+            tun_mtu: None,
+            #[cfg(feature = "local-tun")]
+            tun_auto_correct_mtu: false,
+            #[cfg(feature = "local-tun")]
+            tun_clamp_mss_on_mtu_blackhole: false,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_conn_rate_limit: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_conn_rate_burst: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_bandwidth_limit: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_bandwidth_burst: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_global_bandwidth_limit: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_global_bandwidth_burst: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_send_mss: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_recv_mss: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_mss_overhead: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_idle_timeout: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_connect_timeout: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_recv_buffer_autotune_max: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_round_budget: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_out_queue_limit: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_reset_on_remote_failure: false,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_reset_on_server_down: false,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_early_data_policy: TcpEarlyDataPolicy::Buffer,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_proxy_protocol: None,
+            #[cfg(feature = "local-tun")]
+            tun_tcp_resolve_strategy: None,
+            #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "android")))]
+            tun_outbound_fwmark: None,
+            #[cfg(feature = "local-tun-capture")]
+            tun_capture_path: None,
             #[cfg(all(feature = "local-tun", unix))]
             tun_device_fd: None,
             #[cfg(all(feature = "local-tun", unix))]
@@ -990,6 +1300,16 @@ pub struct BalancerConfig {
     pub check_interval: Option<Duration>,
     /// Interval for checking the best server
     pub check_best_interval: Option<Duration>,
+    /// Total retries allowed to be made by all connections, refilled over time
+    pub retry_budget: Option<u32>,
+    /// Behavior when all servers are unavailable
+    #[cfg(feature = "local")]
+    pub unavailable_policy: ServerUnavailablePolicy,
+    /// Duration of ramping up flows sent to a server after it recovered
+    pub recovery_ramp: Option<Duration>,
+    /// Classes of flows that are sent to servers of named pools
+    #[cfg(feature = "local")]
+    pub server_pools: ServerPoolClassifier,
 }
 
 /// Configuration
@@ -1027,6 +1347,10 @@ pub struct Config {
     ///
     /// If this is not set, sockets will be set with a default timeout
     pub keep_alive: Option<Duration>,
+    /// Reset TCP connections of tunnel and tun whose server sends nothing in this duration after the client's first
+    /// payload, disabled by default
+    #[cfg(feature = "local")]
+    pub tcp_first_byte_timeout: Option<Duration>,
 
     /// `RLIMIT_NOFILE` option for *nix systems
     #[cfg(all(unix, not(target_os = "android")))]
@@ -1050,6 +1374,8 @@ pub struct Config {
     pub inbound_send_buffer_size: Option<u32>,
     /// Set `SO_RCVBUF` for inbound sockets
     pub inbound_recv_buffer_size: Option<u32>,
+    /// Hint of TCP initial congestion window (in segments) for inbound connections, only honored by tun
+    pub inbound_tcp_initial_window: Option<u32>,
     /// Set `SO_SNDBUF` for outbound sockets
     pub outbound_send_buffer_size: Option<u32>,
     /// Set `SO_RCVBUF` for outbound sockets
@@ -1065,9 +1391,54 @@ pub struct Config {
     pub udp_timeout: Option<Duration>,
     /// Maximum number of UDP Associations, default is unconfigured
     pub udp_max_associations: Option<usize>,
+    /// Behavior of UDP tunnel when new clients come while `udp_max_associations` is reached
+    #[cfg(feature = "local-tunnel")]
+    pub udp_capacity_mode: UdpCapacityMode,
+    /// How UDP tunnel keeps associations for clients, and which responses are sent back to them
+    #[cfg(feature = "local-tunnel")]
+    pub udp_nat_mode: UdpNatMode,
+    /// Number of outbound sockets that each UDP tunnel association is striped across, packets may be reordered
+    #[cfg(feature = "local-tunnel")]
+    pub udp_outbound_pool_size: usize,
+    /// Datagrams received or sent together by each `recvmmsg` / `sendmmsg` of UDP tunnel, `DEFAULT_UDP_MMSG_BATCH_SIZE` if not set
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    pub udp_mmsg_batch_size: Option<usize>,
+    /// Hop limit of UDP tunnel's packets relayed to servers, clients' hop limits are only received if they are relayed
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    pub udp_hop_limit_mode: UdpHopLimitMode,
+    /// Packets queued in each UDP tunnel association's send channel, `UDP_ASSOCIATION_SEND_CHANNEL_SIZE` if not set
+    #[cfg(feature = "local-tunnel")]
+    pub udp_send_channel_size: Option<usize>,
+    /// Largest packet accepted from UDP tunnel's clients, larger ones are dropped. `MAXIMUM_UDP_PAYLOAD_SIZE` if not set
+    #[cfg(feature = "local-tunnel")]
+    pub udp_max_payload_size: Option<usize>,
+    /// Padding of UDP packets relayed between local and server, must be enabled on both ends
+    ///
+    /// Applies to all UDP relays of local, and all clients of server.
+    pub udp_padding: Option<UdpPaddingPolicy>,
+    /// Behavior when a UDP association's send channel is full, packets are dropped by default
+    pub udp_channel_full_policy: UdpChannelFullPolicy,
+    /// Deadline of sending packets queued in UDP associations before they are evicted or closed, discarded by default
+    pub udp_drain_timeout: Option<Duration>,
+    /// Send proxied UDP packets to the two best servers and use the first response, doubles the upstream bandwidth
+    pub udp_hedged_send: bool,
+    /// Delay of reconnecting UDP associations to servers after the first failure, `DEFAULT_UDP_RECONNECT_BACKOFF_MIN`
+    /// if not set
+    pub udp_reconnect_backoff_min: Option<Duration>,
+    /// Maximum delay of reconnecting UDP associations to servers after consecutive failures,
+    /// `DEFAULT_UDP_RECONNECT_BACKOFF_MAX` if not set
+    pub udp_reconnect_backoff_max: Option<Duration>,
+    /// Interval of logging connection errors of each destination, repeats are summarized. Disabled by default
+    pub conn_error_log_interval: Option<Duration>,
+    /// Timeouts of UDP tunnel's associations by their forward addresses, overriding `udp_timeout`
+    #[cfg(feature = "local-tunnel")]
+    pub udp_ttl_rules: UdpTtlRules,
 
     /// ACL configuration
     pub acl: Option<AccessControl>,
+    /// ASN database for ACL's ASN rules
+    #[cfg(feature = "acl-asn")]
+    pub asn_database: Option<Arc<AsnDatabase>>,
 
     /// Flow statistic report Unix socket path (only for Android)
     #[cfg(feature = "local-flow-stat")]
@@ -1157,6 +1528,8 @@ impl Config {
             no_delay: false,
             fast_open: false,
             keep_alive: None,
+            #[cfg(feature = "local")]
+            tcp_first_byte_timeout: None,
 
             #[cfg(all(unix, not(target_os = "android")))]
             nofile: None,
@@ -1172,6 +1545,7 @@ impl Config {
 
             inbound_send_buffer_size: None,
             inbound_recv_buffer_size: None,
+            inbound_tcp_initial_window: None,
             outbound_send_buffer_size: None,
             outbound_recv_buffer_size: None,
 
@@ -1181,8 +1555,33 @@ impl Config {
 
             udp_timeout: None,
             udp_max_associations: None,
+            #[cfg(feature = "local-tunnel")]
+            udp_capacity_mode: UdpCapacityMode::Evict,
+            #[cfg(feature = "local-tunnel")]
+            udp_nat_mode: UdpNatMode::Peer,
+            #[cfg(feature = "local-tunnel")]
+            udp_outbound_pool_size: 1,
+            #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+            udp_mmsg_batch_size: None,
+            #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+            udp_hop_limit_mode: UdpHopLimitMode::Default,
+            #[cfg(feature = "local-tunnel")]
+            udp_send_channel_size: None,
+            #[cfg(feature = "local-tunnel")]
+            udp_max_payload_size: None,
+            udp_padding: None,
+            udp_channel_full_policy: UdpChannelFullPolicy::Drop,
+            udp_drain_timeout: None,
+            udp_hedged_send: false,
+            udp_reconnect_backoff_min: None,
+            udp_reconnect_backoff_max: None,
+            conn_error_log_interval: None,
+            #[cfg(feature = "local-tunnel")]
+            udp_ttl_rules: UdpTtlRules::new(),
 
             acl: None,
+            #[cfg(feature = "acl-asn")]
+            asn_database: None,
 
             #[cfg(feature = "local-flow-stat")]
             stat_path: None,
@@ -1434,6 +1833,83 @@ impl Config {
                             local_config.tun_interface_name = Some(tun_interface_name);
                         }
 
+                        #[cfg(feature = "local-tun")]
+                        {
+                            local_config.tun_mtu = local.tun_mtu;
+                            local_config.tun_auto_correct_mtu = local.tun_auto_correct_mtu.unwrap_or(false);
+                            local_config.tun_clamp_mss_on_mtu_blackhole =
+                                local.tun_clamp_mss_on_mtu_blackhole.unwrap_or(false);
+                            local_config.tun_tcp_conn_rate_limit = local.tun_tcp_conn_rate_limit;
+                            local_config.tun_tcp_conn_rate_burst = local.tun_tcp_conn_rate_burst;
+                            local_config.tun_tcp_bandwidth_limit = local.tun_tcp_bandwidth_limit;
+                            local_config.tun_tcp_bandwidth_burst = local.tun_tcp_bandwidth_burst;
+                            local_config.tun_tcp_global_bandwidth_limit = local.tun_tcp_global_bandwidth_limit;
+                            local_config.tun_tcp_global_bandwidth_burst = local.tun_tcp_global_bandwidth_burst;
+                            local_config.tun_tcp_send_mss = local.tun_tcp_send_mss;
+                            local_config.tun_tcp_recv_mss = local.tun_tcp_recv_mss;
+                            local_config.tun_tcp_mss_overhead = local.tun_tcp_mss_overhead;
+                            local_config.tun_tcp_idle_timeout = local.tun_tcp_idle_timeout.map(Duration::from_secs);
+                            local_config.tun_tcp_connect_timeout =
+                                local.tun_tcp_connect_timeout.map(Duration::from_secs);
+                            local_config.tun_tcp_recv_buffer_autotune_max = local.tun_tcp_recv_buffer_autotune_max;
+                            local_config.tun_tcp_round_budget = local.tun_tcp_round_budget;
+                            local_config.tun_tcp_out_queue_limit = local.tun_tcp_out_queue_limit;
+                            local_config.tun_tcp_reset_on_remote_failure =
+                                local.tun_tcp_reset_on_remote_failure.unwrap_or(false);
+                            local_config.tun_tcp_reset_on_server_down =
+                                local.tun_tcp_reset_on_server_down.unwrap_or(false);
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        if let Some(policy) = local.tun_tcp_early_data_policy {
+                            match policy.parse::<TcpEarlyDataPolicy>() {
+                                Ok(TcpEarlyDataPolicy::Reset { timeout }) => {
+                                    let timeout =
+                                        local.tun_tcp_early_data_timeout.map_or(timeout, Duration::from_millis);
+                                    local_config.tun_tcp_early_data_policy = TcpEarlyDataPolicy::Reset { timeout };
+                                }
+                                Ok(policy) => local_config.tun_tcp_early_data_policy = policy,
+                                Err(..) => {
+                                    let err =
+                                        Error::new(ErrorKind::Invalid, "invalid `tun_tcp_early_data_policy`", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        if let Some(version) = local.tun_tcp_proxy_protocol {
+                            match version.parse::<ProxyProtocolVersion>() {
+                                Ok(version) => local_config.tun_tcp_proxy_protocol = Some(version),
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Invalid, "invalid `tun_tcp_proxy_protocol`", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        if let Some(strategy) = local.tun_tcp_resolve_strategy {
+                            match strategy.parse::<ResolveStrategy>() {
+                                Ok(strategy) => local_config.tun_tcp_resolve_strategy = Some(strategy),
+                                Err(..) => {
+                                    let err =
+                                        Error::new(ErrorKind::Invalid, "invalid `tun_tcp_resolve_strategy`", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "android")))]
+                        {
+                            local_config.tun_outbound_fwmark = local.tun_outbound_fwmark;
+                        }
+
+                        #[cfg(feature = "local-tun-capture")]
+                        if let Some(tun_capture_path) = local.tun_capture_path {
+                            local_config.tun_capture_path = Some(PathBuf::from(tun_capture_path));
+                        }
+
                         #[cfg(feature = "local")]
                         if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
                             local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
@@ -1489,6 +1965,7 @@ impl Config {
                             plugin: p.clone(),
                             plugin_opts: config.plugin_opts.clone(),
                             plugin_args: config.plugin_args.clone().unwrap_or_default(),
+                            flow_metadata: config.plugin_flow_metadata.unwrap_or(false),
                         };
                         nsvr.set_plugin(plugin);
                     }
@@ -1574,6 +2051,7 @@ impl Config {
                             plugin: p,
                             plugin_opts: svr.plugin_opts,
                             plugin_args: svr.plugin_args.unwrap_or_default(),
+                            flow_metadata: svr.plugin_flow_metadata.unwrap_or(false),
                         };
                         nsvr.set_plugin(plugin);
                     }
@@ -1608,6 +2086,73 @@ impl Config {
                     nsvr.set_weight(weight);
                 }
 
+                if let Some(strategy) = svr.resolve_strategy {
+                    match strategy.parse::<ResolveStrategy>() {
+                        Ok(strategy) => nsvr.set_resolve_strategy(strategy),
+                        Err(..) => {
+                            let err = Error::new(ErrorKind::Invalid, "invalid `resolve_strategy`", None);
+                            return Err(err);
+                        }
+                    }
+                }
+
+                if let Some(pool) = svr.pool {
+                    nsvr.set_pool(pool);
+                }
+
+                if let Some(max_flows) = svr.max_flows {
+                    nsvr.set_max_flows(max_flows);
+                }
+
+                #[cfg(feature = "local-udp-quic")]
+                if let Some(udp_quic) = svr.udp_quic {
+                    let mut quic_transport = QuicTransportConfig::new(udp_quic.server_name);
+                    if let Some(root_certificate) = udp_quic.root_certificate {
+                        if let Err(err) = quic_transport.load_root_certificates(&root_certificate) {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `udp_quic` root_certificate",
+                                Some(format!("{}, {}", root_certificate, err)),
+                            );
+                            return Err(err);
+                        }
+                    }
+                    if let Some(min_tls_version) = udp_quic.min_tls_version {
+                        match min_tls_version.parse::<TlsVersion>() {
+                            Ok(v) => quic_transport.set_min_tls_version(v),
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "invalid `udp_quic` min_tls_version",
+                                    Some(min_tls_version),
+                                );
+                                return Err(err);
+                            }
+                        }
+                    }
+                    for cipher_suite in udp_quic.cipher_suites.unwrap_or_default() {
+                        if let Err(err) = quic_transport.add_cipher_suite(&cipher_suite) {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `udp_quic` cipher_suites",
+                                Some(err.to_string()),
+                            );
+                            return Err(err);
+                        }
+                    }
+                    for spki_pin in udp_quic.spki_pins.unwrap_or_default() {
+                        if let Err(err) = quic_transport.add_spki_pin(&spki_pin) {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `udp_quic` spki_pins",
+                                Some(err.to_string()),
+                            );
+                            return Err(err);
+                        }
+                    }
+                    nsvr.set_quic_transport(quic_transport);
+                }
+
                 nconfig.server.push(nsvr);
             }
         }
@@ -1669,6 +2214,7 @@ impl Config {
                         plugin: p,
                         plugin_opts: config.plugin_opts,
                         plugin_args: config.plugin_args.unwrap_or_default(),
+                        flow_metadata: false,
                     });
                 }
             }
@@ -1701,12 +2247,144 @@ impl Config {
             nconfig.keep_alive = Some(Duration::from_secs(d));
         }
 
+        #[cfg(feature = "local")]
+        {
+            nconfig.tcp_first_byte_timeout = config.tcp_first_byte_timeout.map(Duration::from_secs);
+        }
+
         // UDP
         nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
 
         // Maximum associations to be kept simultaneously
         nconfig.udp_max_associations = config.udp_max_associations;
 
+        #[cfg(feature = "local-tunnel")]
+        if let Some(mode) = config.udp_capacity_mode {
+            match mode.parse::<UdpCapacityMode>() {
+                Ok(m) => nconfig.udp_capacity_mode = m,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `udp_capacity_mode`", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if let Some(mode) = config.udp_nat_mode {
+            match mode.parse::<UdpNatMode>() {
+                Ok(m) => nconfig.udp_nat_mode = m,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `udp_nat_mode`", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if let Some(size) = config.udp_outbound_pool_size {
+            if size == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`udp_outbound_pool_size` must be at least 1", None);
+                return Err(err);
+            }
+            nconfig.udp_outbound_pool_size = size;
+        }
+
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        if let Some(size) = config.udp_mmsg_batch_size {
+            if size == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`udp_mmsg_batch_size` must be at least 1", None);
+                return Err(err);
+            }
+            nconfig.udp_mmsg_batch_size = Some(size);
+        }
+
+        #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+        if let Some(mode) = config.udp_hop_limit_mode {
+            match mode.parse::<UdpHopLimitMode>() {
+                Ok(m) => nconfig.udp_hop_limit_mode = m,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `udp_hop_limit_mode`", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if let Some(size) = config.udp_send_channel_size {
+            if size == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`udp_send_channel_size` must be at least 1", None);
+                return Err(err);
+            }
+            nconfig.udp_send_channel_size = Some(size);
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if let Some(size) = config.udp_max_payload_size {
+            if size == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`udp_max_payload_size` must be at least 1", None);
+                return Err(err);
+            }
+            nconfig.udp_max_payload_size = Some(size);
+        }
+
+        if let Some(padding) = config.udp_padding {
+            match padding.parse::<UdpPaddingPolicy>() {
+                Ok(p) => nconfig.udp_padding = Some(p),
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `udp_padding`", Some(err.to_string()));
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(policy) = config.udp_channel_full_policy {
+            match policy.parse::<UdpChannelFullPolicy>() {
+                Ok(p) => nconfig.udp_channel_full_policy = p,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `udp_channel_full_policy`", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        nconfig.udp_drain_timeout = config.udp_drain_timeout.map(Duration::from_millis);
+
+        if let Some(h) = config.udp_hedged_send {
+            nconfig.udp_hedged_send = h;
+        }
+
+        nconfig.udp_reconnect_backoff_min = config.udp_reconnect_backoff_min.map(Duration::from_millis);
+        nconfig.udp_reconnect_backoff_max = config.udp_reconnect_backoff_max.map(Duration::from_millis);
+        if let (Some(min), Some(max)) = (nconfig.udp_reconnect_backoff_min, nconfig.udp_reconnect_backoff_max) {
+            if min > max {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`udp_reconnect_backoff_min` is larger than `udp_reconnect_backoff_max`",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        nconfig.conn_error_log_interval = config.conn_error_log_interval.map(Duration::from_secs);
+
+        #[cfg(feature = "local-tunnel")]
+        for ttl_override in config.udp_ttl_overrides.unwrap_or_default() {
+            match AddressRules::from_lines("udp_ttl_overrides", ttl_override.targets) {
+                Ok(rules) => nconfig
+                    .udp_ttl_rules
+                    .add_rule(rules, Duration::from_secs(ttl_override.timeout)),
+                Err(err) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "invalid udp_ttl_overrides targets",
+                        Some(err.to_string()),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
         // RLIMIT_NOFILE
         #[cfg(all(unix, not(target_os = "android")))]
         {
@@ -1743,7 +2421,46 @@ impl Config {
                 max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
                 check_interval: balancer.check_interval.map(Duration::from_secs),
                 check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+                retry_budget: balancer.retry_budget,
+                recovery_ramp: balancer.recovery_ramp.map(Duration::from_secs),
+                ..Default::default()
             };
+
+            #[cfg(feature = "local")]
+            if let Some(policy) = balancer.unavailable_policy {
+                match policy.parse::<ServerUnavailablePolicy>() {
+                    Ok(p) => nconfig.balancer.unavailable_policy = p,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer unavailable policy", None);
+                        return Err(err);
+                    }
+                }
+            }
+
+            #[cfg(feature = "local")]
+            for pool_config in balancer.server_pools.unwrap_or_default() {
+                let mut class = ServerPoolClass::new(pool_config.pool);
+                for ports in pool_config.ports.unwrap_or_default() {
+                    if class.add_ports_str(&ports).is_err() {
+                        let err = Error::new(ErrorKind::Malformed, "invalid balancer server_pools ports", None);
+                        return Err(err);
+                    }
+                }
+                if let Some(targets) = pool_config.targets {
+                    match AddressRules::from_lines("balancer.server_pools", targets) {
+                        Ok(rules) => class.set_targets(rules),
+                        Err(err) => {
+                            let err = Error::new(
+                                ErrorKind::Malformed,
+                                "invalid balancer server_pools targets",
+                                Some(err.to_string()),
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
+                nconfig.balancer.server_pools.add_class(class);
+            }
         }
 
         Ok(nconfig)
@@ -2137,6 +2854,79 @@ impl fmt::Display for Config {
                         tun_interface_name: local.tun_interface_name.clone(),
                         #[cfg(feature = "local-tun")]
                         tun_interface_address: local.tun_interface_address.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-tun")]
+                        tun_mtu: local.tun_mtu,
+                        #[cfg(feature = "local-tun")]
+                        tun_auto_correct_mtu: if local.tun_auto_correct_mtu { Some(true) } else { None },
+                        #[cfg(feature = "local-tun")]
+                        tun_clamp_mss_on_mtu_blackhole: if local.tun_clamp_mss_on_mtu_blackhole {
+                            Some(true)
+                        } else {
+                            None
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_conn_rate_limit: local.tun_tcp_conn_rate_limit,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_conn_rate_burst: local.tun_tcp_conn_rate_burst,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_bandwidth_limit: local.tun_tcp_bandwidth_limit,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_bandwidth_burst: local.tun_tcp_bandwidth_burst,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_global_bandwidth_limit: local.tun_tcp_global_bandwidth_limit,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_global_bandwidth_burst: local.tun_tcp_global_bandwidth_burst,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_send_mss: local.tun_tcp_send_mss,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_recv_mss: local.tun_tcp_recv_mss,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_mss_overhead: local.tun_tcp_mss_overhead,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_idle_timeout: local.tun_tcp_idle_timeout.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_connect_timeout: local.tun_tcp_connect_timeout.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_recv_buffer_autotune_max: local.tun_tcp_recv_buffer_autotune_max,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_round_budget: local.tun_tcp_round_budget,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_out_queue_limit: local.tun_tcp_out_queue_limit,
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_reset_on_remote_failure: if local.tun_tcp_reset_on_remote_failure {
+                            Some(true)
+                        } else {
+                            None
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_reset_on_server_down: if local.tun_tcp_reset_on_server_down {
+                            Some(true)
+                        } else {
+                            None
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_early_data_policy: match local.tun_tcp_early_data_policy {
+                            TcpEarlyDataPolicy::Buffer => None,
+                            policy => Some(policy.to_string()),
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_early_data_timeout: match local.tun_tcp_early_data_policy {
+                            TcpEarlyDataPolicy::Reset { timeout } if timeout != DEFAULT_EARLY_DATA_RESET_TIMEOUT => {
+                                Some(timeout.as_millis() as u64)
+                            }
+                            _ => None,
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_proxy_protocol: local.tun_tcp_proxy_protocol.map(|v| v.to_string()),
+                        #[cfg(feature = "local-tun")]
+                        tun_tcp_resolve_strategy: local.tun_tcp_resolve_strategy.map(|s| s.to_string()),
+                        #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "android")))]
+                        tun_outbound_fwmark: local.tun_outbound_fwmark,
+                        #[cfg(feature = "local-tun-capture")]
+                        tun_capture_path: local
+                            .tun_capture_path
+                            .as_ref()
+                            .map(|p| p.to_string_lossy().into_owned()),
 
                         #[cfg(feature = "local")]
                         socks5_auth_config_path: None,
@@ -2173,6 +2963,7 @@ impl fmt::Display for Config {
                         Some(p.plugin_args.clone())
                     }
                 });
+                jconf.plugin_flow_metadata = svr.plugin().and_then(|p| p.flow_metadata.then_some(true));
                 jconf.timeout = svr.timeout().map(|t| t.as_secs());
                 jconf.mode = Some(svr.mode().to_string());
             }
@@ -2202,6 +2993,7 @@ impl fmt::Display for Config {
                                 Some(p.plugin_args.clone())
                             }
                         }),
+                        plugin_flow_metadata: svr.plugin().and_then(|p| p.flow_metadata.then_some(true)),
                         timeout: svr.timeout().map(|t| t.as_secs()),
                         remarks: svr.remarks().map(ToOwned::to_owned),
                         id: svr.id().map(ToOwned::to_owned),
@@ -2216,6 +3008,24 @@ impl fmt::Display for Config {
                         } else {
                             None
                         },
+                        resolve_strategy: svr.resolve_strategy().map(|s| s.to_string()),
+                        pool: svr.pool().map(ToOwned::to_owned),
+                        max_flows: svr.max_flows(),
+                        // Root certificates were loaded from files, their paths are not kept
+                        #[cfg(feature = "local-udp-quic")]
+                        udp_quic: svr.quic_transport().map(|q| SSQuicTransportConfig {
+                            server_name: q.server_name().to_owned(),
+                            root_certificate: None,
+                            min_tls_version: q.min_tls_version().map(|v| v.to_string()),
+                            cipher_suites: match q.cipher_suites() {
+                                suites if suites.is_empty() => None,
+                                suites => Some(suites),
+                            },
+                            spki_pins: match q.spki_pins() {
+                                pins if pins.is_empty() => None,
+                                pins => Some(pins),
+                            },
+                        }),
                     });
                 }
 
@@ -2273,6 +3083,11 @@ impl fmt::Display for Config {
             jconf.keep_alive = Some(keepalive.as_secs());
         }
 
+        #[cfg(feature = "local")]
+        {
+            jconf.tcp_first_byte_timeout = self.tcp_first_byte_timeout.map(|t| t.as_secs());
+        }
+
         match self.dns {
             DnsConfig::System => {}
             #[cfg(feature = "trust-dns")]
@@ -2289,6 +3104,54 @@ impl fmt::Display for Config {
 
         jconf.udp_max_associations = self.udp_max_associations;
 
+        #[cfg(feature = "local-tunnel")]
+        if self.udp_capacity_mode != UdpCapacityMode::Evict {
+            jconf.udp_capacity_mode = Some(self.udp_capacity_mode.to_string());
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if self.udp_nat_mode != UdpNatMode::Peer {
+            jconf.udp_nat_mode = Some(self.udp_nat_mode.to_string());
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        if self.udp_outbound_pool_size != 1 {
+            jconf.udp_outbound_pool_size = Some(self.udp_outbound_pool_size);
+        }
+
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        {
+            jconf.udp_mmsg_batch_size = self.udp_mmsg_batch_size;
+        }
+
+        #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+        if self.udp_hop_limit_mode != UdpHopLimitMode::Default {
+            jconf.udp_hop_limit_mode = Some(self.udp_hop_limit_mode.to_string());
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        {
+            jconf.udp_send_channel_size = self.udp_send_channel_size;
+            jconf.udp_max_payload_size = self.udp_max_payload_size;
+        }
+
+        jconf.udp_padding = self.udp_padding.map(|p| p.to_string());
+
+        if self.udp_channel_full_policy != UdpChannelFullPolicy::Drop {
+            jconf.udp_channel_full_policy = Some(self.udp_channel_full_policy.to_string());
+        }
+
+        jconf.udp_drain_timeout = self.udp_drain_timeout.map(|t| t.as_millis() as u64);
+
+        if self.udp_hedged_send {
+            jconf.udp_hedged_send = Some(self.udp_hedged_send);
+        }
+
+        jconf.udp_reconnect_backoff_min = self.udp_reconnect_backoff_min.map(|t| t.as_millis() as u64);
+        jconf.udp_reconnect_backoff_max = self.udp_reconnect_backoff_max.map(|t| t.as_millis() as u64);
+
+        jconf.conn_error_log_interval = self.conn_error_log_interval.map(|t| t.as_secs());
+
         #[cfg(all(unix, not(target_os = "android")))]
         {
             jconf.nofile = self.nofile;
@@ -2312,14 +3175,29 @@ impl fmt::Display for Config {
         }
 
         // Balancer
-        if self.balancer.max_server_rtt.is_some() || self.balancer.check_interval.is_some() {
+        if self.balancer.max_server_rtt.is_some()
+            || self.balancer.check_interval.is_some()
+            || self.balancer.retry_budget.is_some()
+            || self.balancer.recovery_ramp.is_some()
+        {
             jconf.balancer = Some(SSBalancerConfig {
                 max_server_rtt: self.balancer.max_server_rtt.as_ref().map(Duration::as_secs),
                 check_interval: self.balancer.check_interval.as_ref().map(Duration::as_secs),
                 check_best_interval: self.balancer.check_best_interval.as_ref().map(Duration::as_secs),
+                retry_budget: self.balancer.retry_budget,
+                recovery_ramp: self.balancer.recovery_ramp.as_ref().map(Duration::as_secs),
+                ..Default::default()
             });
         }
 
+        #[cfg(feature = "local")]
+        if self.balancer.unavailable_policy != ServerUnavailablePolicy::default() {
+            jconf
+                .balancer
+                .get_or_insert_with(SSBalancerConfig::default)
+                .unavailable_policy = Some(self.balancer.unavailable_policy.to_string());
+        }
+
         write!(f, "{}", json5::to_string(&jconf).unwrap())
     }
 }
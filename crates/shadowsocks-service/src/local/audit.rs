@@ -0,0 +1,250 @@
+//! Audit trail of closed flows
+//!
+//! Records are appended to a JSON Lines file by a dedicated writer thread. Relay tasks only push records into a
+//! bounded channel, records are dropped (and counted) if the writer couldn't keep up, so the relay path never blocks
+//! on disk I/O.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use log::{error, warn};
+use serde::Serialize;
+use shadowsocks::{relay::socks5::Address, ServerAddr};
+
+/// Default number of records buffered before the writer thread
+pub const DEFAULT_AUDIT_BUFFER_SIZE: usize = 4096;
+
+/// Record of a closed flow
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Time when the flow was accepted, seconds since UNIX epoch
+    pub start_time: f64,
+    /// Time when the flow was closed, seconds since UNIX epoch
+    pub end_time: f64,
+    /// `tcp` or `udp`
+    pub protocol: &'static str,
+    /// Client address
+    pub src_addr: String,
+    /// Target address
+    pub dst_addr: String,
+    /// Server relayed through, `None` if bypassed
+    pub server: Option<String>,
+    /// Bytes from client to target
+    pub tx: u64,
+    /// Bytes from target to client
+    pub rx: u64,
+    /// Error if the flow was aborted
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// Create a TCP record closing now
+    pub fn tcp(
+        start_time: SystemTime,
+        src_addr: SocketAddr,
+        dst_addr: &Address,
+        server: Option<&ServerAddr>,
+    ) -> AuditRecord {
+        AuditRecord {
+            start_time: unix_time(start_time),
+            end_time: unix_time(SystemTime::now()),
+            protocol: "tcp",
+            src_addr: src_addr.to_string(),
+            dst_addr: dst_addr.to_string(),
+            server: server.map(ToString::to_string),
+            tx: 0,
+            rx: 0,
+            error: None,
+        }
+    }
+}
+
+fn unix_time(t: SystemTime) -> f64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Appends `AuditRecord`s to a JSON Lines file
+///
+/// The file is rotated to `<path>.1` when it grows beyond `max_file_size`. Pending records are flushed when the sink
+/// is dropped.
+pub struct AuditSink {
+    tx: Option<SyncSender<AuditRecord>>,
+    dropped: AtomicU64,
+    writer_handle: Option<JoinHandle<()>>,
+}
+
+impl AuditSink {
+    /// Open (or create) `path` for appending records
+    pub fn open<P: AsRef<Path>>(path: P, max_file_size: Option<u64>) -> io::Result<AuditSink> {
+        let writer = AuditWriter::open(path.as_ref().to_owned(), max_file_size)?;
+        let (tx, rx) = mpsc::sync_channel(DEFAULT_AUDIT_BUFFER_SIZE);
+
+        let writer_handle = thread::Builder::new()
+            .name("audit-writer".to_owned())
+            .spawn(move || writer.run(rx))?;
+
+        Ok(AuditSink {
+            tx: Some(tx),
+            dropped: AtomicU64::new(0),
+            writer_handle: Some(writer_handle),
+        })
+    }
+
+    /// Queue a record to be written, never blocks
+    pub fn record(&self, record: AuditRecord) {
+        let tx = self.tx.as_ref().expect("audit sink closed");
+        match tx.try_send(record) {
+            Ok(..) => {}
+            Err(TrySendError::Full(..)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("audit sink is full, {} records dropped", dropped);
+            }
+            Err(TrySendError::Disconnected(..)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of records dropped because the writer couldn't keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for AuditSink {
+    fn drop(&mut self) {
+        // Closes the channel, writer thread exits after writing all pending records
+        self.tx.take();
+        if let Some(handle) = self.writer_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct AuditWriter {
+    path: PathBuf,
+    max_file_size: Option<u64>,
+    file: BufWriter<File>,
+    file_size: u64,
+}
+
+impl AuditWriter {
+    fn open(path: PathBuf, max_file_size: Option<u64>) -> io::Result<AuditWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let file_size = file.metadata()?.len();
+        Ok(AuditWriter {
+            path,
+            max_file_size,
+            file: BufWriter::new(file),
+            file_size,
+        })
+    }
+
+    fn run(mut self, rx: Receiver<AuditRecord>) {
+        while let Ok(record) = rx.recv() {
+            self.write(&record);
+            // Write all the pending records before flushing
+            while let Ok(record) = rx.try_recv() {
+                self.write(&record);
+            }
+            if let Err(err) = self.file.flush() {
+                error!("audit failed to flush {}, error: {}", self.path.display(), err);
+            }
+        }
+    }
+
+    fn write(&mut self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("audit failed to serialize record {:?}, error: {}", record, err);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Some(max_file_size) = self.max_file_size {
+            if self.file_size > 0 && self.file_size + line.len() as u64 > max_file_size {
+                if let Err(err) = self.rotate() {
+                    error!("audit failed to rotate {}, error: {}", self.path.display(), err);
+                }
+            }
+        }
+
+        match self.file.write_all(&line) {
+            Ok(..) => self.file_size += line.len() as u64,
+            Err(err) => error!("audit failed to write {}, error: {}", self.path.display(), err),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.file_size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, net::Ipv4Addr, process};
+
+    use super::*;
+
+    #[test]
+    fn records_persisted_after_close() {
+        let dir = env::temp_dir().join(format!("ss-audit-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let _ = fs::remove_file(&path);
+
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 50000);
+        let dst_addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let server = ServerAddr::from(SocketAddr::new(Ipv4Addr::new(203, 0, 113, 1).into(), 8388));
+
+        let sink = AuditSink::open(&path, None).unwrap();
+        for tx in 0..3 {
+            let mut record = AuditRecord::tcp(SystemTime::now(), src_addr, &dst_addr, Some(&server));
+            record.tx = tx;
+            record.rx = 1024;
+            sink.record(record);
+        }
+        drop(sink);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let records = content
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2]["tx"], 2);
+        assert_eq!(records[0]["dst_addr"], "example.com:443");
+        assert_eq!(records[0]["server"], "203.0.113.1:8388");
+
+        // Appended to the existing file
+        let sink = AuditSink::open(&path, None).unwrap();
+        sink.record(AuditRecord::tcp(SystemTime::now(), src_addr, &dst_addr, None));
+        drop(sink);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
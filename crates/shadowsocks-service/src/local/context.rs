@@ -1,8 +1,8 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+use std::{sync::Arc, time::Duration};
 
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
@@ -16,7 +16,24 @@ use shadowsocks::{
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(feature = "acl-asn")]
+use crate::acl::AsnDatabase;
+#[cfg(feature = "local-audit")]
+use crate::local::audit::AuditSink;
+#[cfg(feature = "local-flight-recorder")]
+use crate::local::flight_recorder::FlightRecorder;
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    local::log_throttle::ConnErrorLogThrottle,
+    net::{
+        ConnectBackoff,
+        FlowStat,
+        UdpPaddingPolicy,
+        DEFAULT_UDP_RECONNECT_BACKOFF_MAX,
+        DEFAULT_UDP_RECONNECT_BACKOFF_MIN,
+    },
+};
 
 /// Local Service Context
 pub struct ServiceContext {
@@ -27,9 +44,36 @@ pub struct ServiceContext {
     // Access Control
     acl: Option<AccessControl>,
 
+    // Database for ACL's ASN rules
+    #[cfg(feature = "acl-asn")]
+    asn_database: Option<Arc<AsnDatabase>>,
+
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
 
+    // Recent flow events for debugging
+    #[cfg(feature = "local-flight-recorder")]
+    flight_recorder: Arc<FlightRecorder>,
+
+    // Audit trail of closed flows
+    #[cfg(feature = "local-audit")]
+    audit_sink: Option<Arc<AuditSink>>,
+
+    // Deadline of sending packets queued in UDP associations before they are torn down
+    udp_drain_timeout: Option<Duration>,
+
+    // Send proxied UDP packets to the two best servers
+    udp_hedged_send: bool,
+
+    // Minimum and maximum delay of reconnecting UDP associations to servers after failures
+    udp_reconnect_backoff: (Duration, Duration),
+
+    // Padding of proxied UDP packets, servers must enable the same padding
+    udp_padding: Option<UdpPaddingPolicy>,
+
+    // Collapsing repeated connection errors of the same destination
+    conn_error_log_throttle: ConnErrorLogThrottle,
+
     // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
     #[cfg(feature = "local-dns")]
     reverse_lookup_cache: Mutex<LruCache<IpAddr, bool>>,
@@ -49,7 +93,18 @@ impl ServiceContext {
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
             acl: None,
+            #[cfg(feature = "acl-asn")]
+            asn_database: None,
             flow_stat: Arc::new(FlowStat::new()),
+            #[cfg(feature = "local-flight-recorder")]
+            flight_recorder: Arc::new(FlightRecorder::default()),
+            #[cfg(feature = "local-audit")]
+            audit_sink: None,
+            udp_drain_timeout: None,
+            udp_hedged_send: false,
+            udp_reconnect_backoff: (DEFAULT_UDP_RECONNECT_BACKOFF_MIN, DEFAULT_UDP_RECONNECT_BACKOFF_MAX),
+            udp_padding: None,
+            conn_error_log_throttle: ConnErrorLogThrottle::default(),
             #[cfg(feature = "local-dns")]
             reverse_lookup_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
                 Duration::from_secs(3 * 24 * 60 * 60),
@@ -89,10 +144,30 @@ impl ServiceContext {
     }
 
     /// Set Access Control List
-    pub fn set_acl(&mut self, acl: AccessControl) {
+    #[allow(unused_mut)]
+    pub fn set_acl(&mut self, mut acl: AccessControl) {
+        #[cfg(feature = "acl-asn")]
+        if let Some(ref asn_database) = self.asn_database {
+            acl.set_asn_database(asn_database.clone());
+        }
         self.acl = Some(acl);
     }
 
+    /// Set ASN database, shared with the Access Control List for matching ASN rules
+    #[cfg(feature = "acl-asn")]
+    pub fn set_asn_database(&mut self, asn_database: Arc<AsnDatabase>) {
+        if let Some(ref mut acl) = self.acl {
+            acl.set_asn_database(asn_database.clone());
+        }
+        self.asn_database = Some(asn_database);
+    }
+
+    /// Get ASN database
+    #[cfg(feature = "acl-asn")]
+    pub fn asn_database(&self) -> Option<&Arc<AsnDatabase>> {
+        self.asn_database.as_ref()
+    }
+
     /// Get Access Control List reference
     pub fn acl(&self) -> Option<&AccessControl> {
         self.acl.as_ref()
@@ -108,6 +183,88 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get cloned flight recorder
+    #[cfg(feature = "local-flight-recorder")]
+    pub fn flight_recorder(&self) -> Arc<FlightRecorder> {
+        self.flight_recorder.clone()
+    }
+
+    /// Get flight recorder reference
+    #[cfg(feature = "local-flight-recorder")]
+    pub fn flight_recorder_ref(&self) -> &FlightRecorder {
+        self.flight_recorder.as_ref()
+    }
+
+    /// Set audit sink for recording closed flows
+    #[cfg(feature = "local-audit")]
+    pub fn set_audit_sink(&mut self, audit_sink: Arc<AuditSink>) {
+        self.audit_sink = Some(audit_sink);
+    }
+
+    /// Get audit sink reference
+    #[cfg(feature = "local-audit")]
+    pub fn audit_sink(&self) -> Option<&AuditSink> {
+        self.audit_sink.as_deref()
+    }
+
+    /// Send packets queued in UDP associations within `timeout` before they are evicted or closed
+    ///
+    /// Queued packets are discarded immediately if it is not set.
+    pub fn set_udp_drain_timeout(&mut self, timeout: Duration) {
+        self.udp_drain_timeout = Some(timeout);
+    }
+
+    /// Deadline of sending packets queued in UDP associations before they are torn down
+    pub fn udp_drain_timeout(&self) -> Option<Duration> {
+        self.udp_drain_timeout
+    }
+
+    /// Send proxied UDP packets to the two best servers at the same time, the first response is sent back to clients
+    ///
+    /// It lowers tail latency of UDP requests at the cost of doubling the upstream bandwidth.
+    pub fn set_udp_hedged_send(&mut self, hedged: bool) {
+        self.udp_hedged_send = hedged;
+    }
+
+    /// Check if proxied UDP packets are sent to the two best servers
+    pub fn udp_hedged_send(&self) -> bool {
+        self.udp_hedged_send
+    }
+
+    /// Delay reconnecting UDP associations to servers for `min_delay` after a failure, doubled on each consecutive
+    /// failure up to `max_delay`
+    pub fn set_udp_reconnect_backoff(&mut self, min_delay: Duration, max_delay: Duration) {
+        self.udp_reconnect_backoff = (min_delay, max_delay);
+    }
+
+    /// Backoff of reconnecting a UDP association to servers
+    pub fn udp_reconnect_backoff(&self) -> ConnectBackoff {
+        let (min_delay, max_delay) = self.udp_reconnect_backoff;
+        ConnectBackoff::new(min_delay, max_delay)
+    }
+
+    /// Pad proxied UDP packets of all relays with `padding`, and strip the padding of responses
+    ///
+    /// Servers must enable the same padding, they can't relay packets of clients not padding them.
+    pub fn set_udp_padding(&mut self, padding: Option<UdpPaddingPolicy>) {
+        self.udp_padding = padding;
+    }
+
+    /// Padding of proxied UDP packets
+    pub fn udp_padding(&self) -> Option<UdpPaddingPolicy> {
+        self.udp_padding
+    }
+
+    /// Log at most one connection error of each destination in `interval`, the others are logged as summaries
+    pub fn set_conn_error_log_interval(&mut self, interval: Duration) {
+        self.conn_error_log_throttle = ConnErrorLogThrottle::new(interval);
+    }
+
+    /// Throttle of connection error logs, keyed by destination
+    pub fn conn_error_log_throttle(&self) -> &ConnErrorLogThrottle {
+        &self.conn_error_log_throttle
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
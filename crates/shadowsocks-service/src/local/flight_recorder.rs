@@ -0,0 +1,209 @@
+//! Flight recorder for post-mortem debugging
+//!
+//! Keeps the most recent per-flow events in memory. Events could be retrieved on demand, or dumped into log when a
+//! flow fails, without enabling the noisy `trace` logging which changes timing of the whole process.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    net::SocketAddr,
+    time::SystemTime,
+};
+
+use log::error;
+use shadowsocks::{relay::socks5::Address, ServerAddr};
+use spin::Mutex as SpinMutex;
+
+/// Default number of events kept in the recorder
+pub const DEFAULT_FLIGHT_RECORDER_CAPACITY: usize = 4096;
+
+/// Protocol of a flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlightProtocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for FlightProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FlightProtocol::Tcp => f.write_str("tcp"),
+            FlightProtocol::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// Event happened on a flow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlightEventKind {
+    /// TCP SYN received from client
+    SynReceived,
+    /// UDP association created for client
+    AssociationCreated,
+    /// Connected to target, through `server` if proxied
+    Connected { server: Option<ServerAddr> },
+    /// Failed to connect to target
+    ConnectFailed { error: String },
+    /// Bytes relayed from client to target (`tx`) and from target to client (`rx`)
+    Relayed { tx: u64, rx: u64 },
+    /// Flow is closed
+    Closed,
+    /// Flow is aborted with error
+    Reset { error: String },
+}
+
+impl Display for FlightEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FlightEventKind::SynReceived => f.write_str("SYN received"),
+            FlightEventKind::AssociationCreated => f.write_str("association created"),
+            FlightEventKind::Connected {
+                server: Some(ref server),
+            } => write!(f, "connected through {}", server),
+            FlightEventKind::Connected { server: None } => f.write_str("connected bypassed"),
+            FlightEventKind::ConnectFailed { ref error } => write!(f, "connect failed, error: {}", error),
+            FlightEventKind::Relayed { tx, rx } => write!(f, "relayed tx {} bytes, rx {} bytes", tx, rx),
+            FlightEventKind::Closed => f.write_str("closed"),
+            FlightEventKind::Reset { ref error } => write!(f, "reset, error: {}", error),
+        }
+    }
+}
+
+/// A recorded event
+#[derive(Debug, Clone)]
+pub struct FlightEvent {
+    pub time: SystemTime,
+    pub protocol: FlightProtocol,
+    pub src_addr: SocketAddr,
+    pub dst_addr: Address,
+    pub kind: FlightEventKind,
+}
+
+impl Display for FlightEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        write!(
+            f,
+            "[{:.6}] {} {} <-> {} {}",
+            since_epoch, self.protocol, self.src_addr, self.dst_addr, self.kind
+        )
+    }
+}
+
+/// Ring buffer of the most recent flow events
+pub struct FlightRecorder {
+    capacity: usize,
+    events: SpinMutex<VecDeque<FlightEvent>>,
+}
+
+impl FlightRecorder {
+    /// Create a recorder keeping at most `capacity` events
+    pub fn new(capacity: usize) -> FlightRecorder {
+        FlightRecorder {
+            capacity,
+            events: SpinMutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Record an event, the oldest one will be discarded if the buffer is full
+    pub fn record(&self, protocol: FlightProtocol, src_addr: SocketAddr, dst_addr: &Address, kind: FlightEventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let event = FlightEvent {
+            time: SystemTime::now(),
+            protocol,
+            src_addr,
+            dst_addr: dst_addr.clone(),
+            kind,
+        };
+
+        let mut events = self.events.lock();
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Get all recorded events, from the oldest to the latest
+    pub fn events(&self) -> Vec<FlightEvent> {
+        self.events.lock().iter().cloned().collect()
+    }
+
+    /// Get recorded events of flows from `src_addr`
+    pub fn flow_events(&self, protocol: FlightProtocol, src_addr: SocketAddr) -> Vec<FlightEvent> {
+        self.events
+            .lock()
+            .iter()
+            .filter(|e| e.protocol == protocol && e.src_addr == src_addr)
+            .cloned()
+            .collect()
+    }
+
+    /// Dump all recorded events into log
+    pub fn dump(&self) {
+        for event in self.events() {
+            error!("flight recorder: {}", event);
+        }
+    }
+
+    /// Dump recorded events of flows from `src_addr` into log
+    pub fn dump_flow(&self, protocol: FlightProtocol, src_addr: SocketAddr) {
+        for event in self.flow_events(protocol, src_addr) {
+            error!("flight recorder: {}", event);
+        }
+    }
+}
+
+impl Default for FlightRecorder {
+    fn default() -> FlightRecorder {
+        FlightRecorder::new(DEFAULT_FLIGHT_RECORDER_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn ring_buffer_keeps_latest() {
+        let recorder = FlightRecorder::new(3);
+        let src_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 10000);
+        let other_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 20000);
+        let dst_addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+
+        recorder.record(FlightProtocol::Tcp, src_addr, &dst_addr, FlightEventKind::SynReceived);
+        recorder.record(
+            FlightProtocol::Tcp,
+            src_addr,
+            &dst_addr,
+            FlightEventKind::Connected { server: None },
+        );
+        recorder.record(
+            FlightProtocol::Udp,
+            other_addr,
+            &dst_addr,
+            FlightEventKind::AssociationCreated,
+        );
+        recorder.record(FlightProtocol::Tcp, src_addr, &dst_addr, FlightEventKind::Closed);
+
+        let kinds = recorder.events().into_iter().map(|e| e.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                FlightEventKind::Connected { server: None },
+                FlightEventKind::AssociationCreated,
+                FlightEventKind::Closed,
+            ]
+        );
+        assert_eq!(recorder.flow_events(FlightProtocol::Tcp, src_addr).len(), 2);
+        assert_eq!(recorder.flow_events(FlightProtocol::Udp, src_addr).len(), 0);
+    }
+}
@@ -12,9 +12,12 @@ use crate::local::{context::ServiceContext, loadbalancing::ServerIdent};
 use super::{connector::Connector, http_client::ProxyHttpClient};
 
 /// Cached HTTP client for remote servers
+///
+/// Clients are cached by server's address, along with the server that they are connecting with. Servers may share
+/// the same address but configured with different methods or passwords, for example, after reloading.
 pub struct ProxyClientCache {
     context: Arc<ServiceContext>,
-    cache: Mutex<LruCache<ServerAddr, ProxyHttpClient>>,
+    cache: Mutex<LruCache<ServerAddr, (Arc<ServerIdent>, ProxyHttpClient)>>,
 }
 
 impl ProxyClientCache {
@@ -29,8 +32,11 @@ impl ProxyClientCache {
         let server_config = server.server_config();
 
         let mut cache = self.cache.lock().await;
-        if let Some(client) = cache.get(server_config.addr()) {
-            return client.clone();
+        if let Some((cached_server, client)) = cache.get(server_config.addr()) {
+            let cached_config = cached_server.server_config();
+            if cached_config.method() == server_config.method() && cached_config.key() == server_config.key() {
+                return client.clone();
+            }
         }
 
         // Create a new client
@@ -38,7 +44,7 @@ impl ProxyClientCache {
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
             .build::<_, Body>(Connector::new(self.context.clone(), Some(server.clone())));
-        cache.insert(server_config.addr().clone(), client.clone());
+        cache.insert(server_config.addr().clone(), (server.clone(), client.clone()));
 
         client
     }
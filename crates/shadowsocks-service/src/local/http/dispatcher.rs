@@ -101,8 +101,15 @@ impl HttpDispatcher {
             // Connect to Shadowsocks' remote
             //
             // FIXME: What STATUS should I return for connection error?
-            let server = self.balancer.best_tcp_server();
-            let mut stream = AutoProxyClientStream::connect(self.context, server.as_ref(), &host).await?;
+            let context = self.context;
+            let (server, mut stream) = self
+                .balancer
+                .connect_with_retry(self.balancer.best_tcp_server(), |server| {
+                    let context = context.clone();
+                    let host = &host;
+                    async move { AutoProxyClientStream::connect(context, &server, host).await }
+                })
+                .await?;
 
             debug!("CONNECT relay connected {} <-> {}", self.client_addr, host);
 
@@ -118,14 +125,8 @@ impl HttpDispatcher {
                     Ok(mut upgraded) => {
                         trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
 
-                        let _ = establish_tcp_tunnel(
-                            server.server_config(),
-                            &mut upgraded,
-                            &mut stream,
-                            client_addr,
-                            &host,
-                        )
-                        .await;
+                        let _ =
+                            establish_tcp_tunnel(&server, &mut upgraded, &mut stream, client_addr, &host, None).await;
                     }
                     Err(e) => {
                         error!(
@@ -1,10 +1,32 @@
 //! Load balancer
 
 pub use self::{
-    ping_balancer::{PingBalancer, PingBalancerBuilder, ServerType},
-    server_data::{ServerIdent, ServerScore},
+    ping_balancer::{
+        BalancerStats,
+        PingBalancer,
+        PingBalancerBuilder,
+        PoolExhaustedError,
+        ServerType,
+        ServerUnavailablePolicy,
+        ServiceUnavailableError,
+    },
+    retry_budget::RetryBudget,
+    server_data::{
+        ServerFlowGuard,
+        ServerHealthEvent,
+        ServerHealthTransition,
+        ServerIdent,
+        ServerScore,
+        ServerScoreStats,
+        ServerStats,
+    },
+    server_pool::{ServerPoolClass, ServerPoolClassifier},
+    server_selector::{AvailableServerSelector, BestServerSelector, ServerSelectContext, ServerSelector},
 };
 
 pub mod ping_balancer;
+pub mod retry_budget;
 pub mod server_data;
+pub mod server_pool;
+pub mod server_selector;
 pub mod server_stat;
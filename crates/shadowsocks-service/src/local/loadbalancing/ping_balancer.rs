@@ -2,12 +2,15 @@
 
 use std::{
     cmp,
+    error::Error,
     fmt::{self, Debug, Display},
+    future::Future,
     io,
     iter::Iterator,
     net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -25,12 +28,13 @@ use shadowsocks::{
         tcprelay::proxy_stream::ProxyClientStream,
         udprelay::{proxy_socket::ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
     },
+    ServerAddr,
     ServerConfig,
 };
 use spin::Mutex as SpinMutex;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    sync::Notify,
+    sync::{broadcast, Notify},
     task::JoinHandle,
     time,
 };
@@ -38,12 +42,21 @@ use tokio::{
 use crate::local::context::ServiceContext;
 
 use super::{
-    server_data::ServerIdent,
+    retry_budget::{RetryBudget, DEFAULT_RETRY_BUDGET},
+    server_data::{ServerHealthEvent, ServerIdent, ServerStats},
+    server_pool::ServerPoolClassifier,
+    server_selector::{ServerSelectContext, ServerSelector},
     server_stat::{Score, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC},
 };
 
 const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
 
+/// Default duration for flows on removed servers to finish after reloading
+pub const DEFAULT_SERVER_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Health events kept for each subscriber, slower subscribers miss the oldest ones
+const SERVER_HEALTH_EVENT_CHANNEL_SIZE: usize = 64;
+
 /// Remote Server Type
 #[derive(Debug, Clone, Copy)]
 pub enum ServerType {
@@ -60,6 +73,121 @@ impl fmt::Display for ServerType {
     }
 }
 
+/// Policy for choosing servers when all servers are unavailable
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ServerUnavailablePolicy {
+    /// Use the server with the best score anyway
+    #[default]
+    UseBest,
+    /// Fail immediately with `ServiceUnavailableError`
+    Reject,
+    /// Connect directly to targets bypassed by ACL, fail the others with `ServiceUnavailableError`
+    Bypass,
+}
+
+impl Display for ServerUnavailablePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerUnavailablePolicy::UseBest => f.write_str("use_best"),
+            ServerUnavailablePolicy::Reject => f.write_str("reject"),
+            ServerUnavailablePolicy::Bypass => f.write_str("bypass"),
+        }
+    }
+}
+
+/// Error while parsing `ServerUnavailablePolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct ServerUnavailablePolicyError;
+
+impl Display for ServerUnavailablePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ServerUnavailablePolicy")
+    }
+}
+
+impl FromStr for ServerUnavailablePolicy {
+    type Err = ServerUnavailablePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "use_best" => Ok(ServerUnavailablePolicy::UseBest),
+            "reject" => Ok(ServerUnavailablePolicy::Reject),
+            "bypass" => Ok(ServerUnavailablePolicy::Bypass),
+            _ => Err(ServerUnavailablePolicyError),
+        }
+    }
+}
+
+/// Error returned when all servers are unavailable with `ServerUnavailablePolicy::Reject`
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceUnavailableError {
+    server_type: ServerType,
+}
+
+impl ServiceUnavailableError {
+    pub(crate) fn new(server_type: ServerType) -> ServiceUnavailableError {
+        ServiceUnavailableError { server_type }
+    }
+
+    /// Type of the servers that are unavailable
+    pub fn server_type(&self) -> ServerType {
+        self.server_type
+    }
+}
+
+impl Display for ServiceUnavailableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "service unavailable, all {} servers are down", self.server_type)
+    }
+}
+
+impl Error for ServiceUnavailableError {}
+
+impl From<ServiceUnavailableError> for io::Error {
+    fn from(err: ServiceUnavailableError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Error returned when all servers of the flow's pool are at their `ServerConfig::max_flows`
+///
+/// It is about capacity, not health, servers of the pool may all be available.
+#[derive(Debug, Clone)]
+pub struct PoolExhaustedError {
+    pool: String,
+    server_type: ServerType,
+}
+
+impl PoolExhaustedError {
+    /// Name of the exhausted pool
+    pub fn pool(&self) -> &str {
+        &self.pool
+    }
+
+    /// Type of the servers that are at capacity
+    pub fn server_type(&self) -> ServerType {
+        self.server_type
+    }
+}
+
+impl Display for PoolExhaustedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "server pool \"{}\" exhausted, all {} servers are at capacity",
+            self.pool, self.server_type
+        )
+    }
+}
+
+impl Error for PoolExhaustedError {}
+
+impl From<PoolExhaustedError> for io::Error {
+    fn from(err: PoolExhaustedError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
 /// Build a `PingBalancer`
 pub struct PingBalancerBuilder {
     servers: Vec<Arc<ServerIdent>>,
@@ -68,6 +196,13 @@ pub struct PingBalancerBuilder {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    retry_budget: u32,
+    unavailable_policy: ServerUnavailablePolicy,
+    drain_timeout: Duration,
+    server_selector: Option<Arc<dyn ServerSelector>>,
+    server_pools: ServerPoolClassifier,
+    health_tx: broadcast::Sender<ServerHealthEvent>,
+    recovery_ramp: Duration,
 }
 
 impl PingBalancerBuilder {
@@ -79,18 +214,32 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            retry_budget: DEFAULT_RETRY_BUDGET,
+            unavailable_policy: ServerUnavailablePolicy::default(),
+            drain_timeout: DEFAULT_SERVER_DRAIN_TIMEOUT,
+            server_selector: None,
+            server_pools: ServerPoolClassifier::new(),
+            health_tx: broadcast::channel(SERVER_HEALTH_EVENT_CHANNEL_SIZE).0,
+            recovery_ramp: Duration::ZERO,
         }
     }
 
     pub fn add_server(&mut self, server: ServerConfig) {
-        let ident = ServerIdent::new(
+        let mut ident = ServerIdent::new(
             server,
             self.max_server_rtt,
             self.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
         );
+        ident.set_health_events(self.health_tx.clone());
+        ident.set_recovery_ramp(self.recovery_ramp);
         self.servers.push(Arc::new(ident));
     }
 
+    /// Subscribe to transitions of servers between up and down, including those of the first check in `build`
+    pub fn subscribe_health_events(&self) -> broadcast::Receiver<ServerHealthEvent> {
+        self.health_tx.subscribe()
+    }
+
     pub fn max_server_rtt(&mut self, rtt: Duration) {
         self.max_server_rtt = rtt;
     }
@@ -103,6 +252,38 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    /// Total retries that could be made simultaneously by all connections
+    pub fn retry_budget(&mut self, budget: u32) {
+        self.retry_budget = budget;
+    }
+
+    /// Behavior when all servers are unavailable
+    pub fn unavailable_policy(&mut self, policy: ServerUnavailablePolicy) {
+        self.unavailable_policy = policy;
+    }
+
+    /// Deadline for flows on servers removed by `PingBalancer::reset_servers` to finish
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Ramp up flows sent to a recovered server in `ramp`, zero (default) sends all of them at once
+    ///
+    /// Have to be set before `add_server`.
+    pub fn recovery_ramp(&mut self, ramp: Duration) {
+        self.recovery_ramp = ramp;
+    }
+
+    /// Choose servers for new flows with `selector` instead of the built-in strategies
+    pub fn server_selector(&mut self, selector: Arc<dyn ServerSelector>) {
+        self.server_selector = Some(selector);
+    }
+
+    /// Send flows classified by `classifier` to servers of the named pools, see `ServerConfig::pool`
+    pub fn server_pool_classifier(&mut self, classifier: ServerPoolClassifier) {
+        self.server_pools = classifier;
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         let mut best_tcp_idx = 0;
         let mut best_udp_idx = 0;
@@ -168,6 +349,19 @@ impl PingBalancerBuilder {
             }
         }
 
+        for class in self.server_pools.classes() {
+            if !self
+                .servers
+                .iter()
+                .any(|s| s.server_config().pool() == Some(class.pool()))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("server pool \"{}\" doesn't have any servers", class.pool()),
+                ));
+            }
+        }
+
         let (shared_context, task_abortable) = PingBalancerContext::new(
             self.servers,
             self.context,
@@ -182,6 +376,16 @@ impl PingBalancerBuilder {
             inner: Arc::new(PingBalancerInner {
                 context: ArcSwap::new(shared_context),
                 task_abortable: SpinMutex::new(task_abortable),
+                retry_budget: RetryBudget::new(self.retry_budget),
+                unavailable_policy: self.unavailable_policy,
+                unavailable_count: AtomicU64::new(0),
+                pool_exhausted_count: AtomicU64::new(0),
+                drain_timeout: self.drain_timeout,
+                draining_servers: SpinMutex::new(Vec::new()),
+                server_selector: self.server_selector,
+                server_pools: self.server_pools,
+                health_tx: self.health_tx,
+                recovery_ramp: self.recovery_ramp,
             }),
         })
     }
@@ -245,6 +449,7 @@ impl PingBalancerContext {
                     // Start Plugin Process
                     let plugin = Plugin::start(p, svr_cfg.addr(), PluginMode::Client)?;
                     svr_cfg.set_plugin_addr(plugin.local_addr().into());
+                    server.set_plugin_flow_metadata(plugin.flow_metadata_sender());
                     plugins.push(plugin);
                 }
             }
@@ -656,6 +861,16 @@ impl PingBalancerContext {
 struct PingBalancerInner {
     context: ArcSwap<PingBalancerContext>,
     task_abortable: SpinMutex<PingBalancerContextTask>,
+    retry_budget: RetryBudget,
+    unavailable_policy: ServerUnavailablePolicy,
+    unavailable_count: AtomicU64,
+    pool_exhausted_count: AtomicU64,
+    drain_timeout: Duration,
+    draining_servers: SpinMutex<Vec<Arc<ServerIdent>>>,
+    server_selector: Option<Arc<dyn ServerSelector>>,
+    server_pools: ServerPoolClassifier,
+    health_tx: broadcast::Sender<ServerHealthEvent>,
+    recovery_ramp: Duration,
 }
 
 impl Drop for PingBalancerInner {
@@ -664,6 +879,20 @@ impl Drop for PingBalancerInner {
     }
 }
 
+/// Snapshot of a balancer's statistic, see `PingBalancer::stats`
+#[derive(Debug, Clone)]
+pub struct BalancerStats {
+    pub servers: Vec<ServerStats>,
+    /// Tokens left in the retry budget shared by all connections
+    pub retry_budget_available: u32,
+    /// Size of the retry budget
+    pub retry_budget_capacity: u32,
+    /// Number of times that all servers were found unavailable
+    pub unavailable_count: u64,
+    /// Number of flows rejected because all servers of their pools were at capacity
+    pub pool_exhausted_count: u64,
+}
+
 /// Balancer with active probing
 #[derive(Clone)]
 pub struct PingBalancer {
@@ -689,6 +918,292 @@ impl PingBalancer {
         context.best_udp_server()
     }
 
+    /// Pick the best TCP server with respect to `ServerUnavailablePolicy`
+    ///
+    /// Returns `Ok(None)` if connections should bypass the proxies.
+    pub fn best_available_tcp_server(&self) -> io::Result<Option<Arc<ServerIdent>>> {
+        let context = self.inner.context.load();
+
+        let is_available = |s: &ServerIdent| {
+            PingBalancerContext::check_server_tcp_enabled(s.server_config()) && s.tcp_score().is_available()
+        };
+
+        let server = context.best_tcp_server();
+        if server.tcp_score().is_available() && server.tcp_score().try_admit() {
+            return Ok(Some(server));
+        }
+
+        // Best server is down or ramping up after it recovered, but some others may still be alive
+        let available = context
+            .servers
+            .iter()
+            .find(|s| !Arc::ptr_eq(s, &server) && is_available(s) && s.tcp_score().try_admit());
+        if let Some(server) = available {
+            return Ok(Some(server.clone()));
+        }
+
+        // Only servers ramping up are available, they are used anyway
+        if server.tcp_score().is_available() {
+            return Ok(Some(server));
+        }
+        if let Some(server) = context.servers.iter().find(|s| is_available(s)) {
+            return Ok(Some(server.clone()));
+        }
+
+        self.apply_unavailable_policy(ServerType::Tcp, server)
+    }
+
+    /// Pick the best UDP server with respect to `ServerUnavailablePolicy`
+    ///
+    /// Returns `Ok(None)` if connections should bypass the proxies.
+    pub fn best_available_udp_server(&self) -> io::Result<Option<Arc<ServerIdent>>> {
+        let context = self.inner.context.load();
+
+        let is_available = |s: &ServerIdent| {
+            PingBalancerContext::check_server_udp_enabled(s.server_config()) && s.udp_score().is_available()
+        };
+
+        let server = context.best_udp_server();
+        if server.udp_score().is_available() && server.udp_score().try_admit() {
+            return Ok(Some(server));
+        }
+
+        // Best server is down or ramping up after it recovered, but some others may still be alive
+        let available = context
+            .servers
+            .iter()
+            .find(|s| !Arc::ptr_eq(s, &server) && is_available(s) && s.udp_score().try_admit());
+        if let Some(server) = available {
+            return Ok(Some(server.clone()));
+        }
+
+        // Only servers ramping up are available, they are used anyway
+        if server.udp_score().is_available() {
+            return Ok(Some(server));
+        }
+        if let Some(server) = context.servers.iter().find(|s| is_available(s)) {
+            return Ok(Some(server.clone()));
+        }
+
+        self.apply_unavailable_policy(ServerType::Udp, server)
+    }
+
+    /// Pick the best UDP server other than `primary`, for sending hedged copies of packets
+    ///
+    /// Available servers are preferred. Returns `None` if there are no other UDP servers.
+    pub fn hedge_udp_server(&self, primary: &ServerIdent) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context
+            .servers
+            .iter()
+            .filter(|s| {
+                s.server_config().addr() != primary.server_config().addr()
+                    && PingBalancerContext::check_server_udp_enabled(s.server_config())
+            })
+            .min_by_key(|s| (!s.udp_score().is_available(), s.udp_score().score()))
+            .cloned()
+    }
+
+    /// Pick the best server of the named pool, available servers are preferred
+    ///
+    /// Servers at their capacity are skipped. Returns `None` if there are no servers of `server_type` in the pool, or
+    /// all of them are at capacity.
+    pub fn best_pool_server(&self, server_type: ServerType, pool: &str) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context
+            .servers
+            .iter()
+            .filter(|s| PingBalancer::is_pool_server(s, server_type, pool) && !s.is_saturated())
+            .min_by_key(|s| {
+                let score = match server_type {
+                    ServerType::Tcp => s.tcp_score(),
+                    ServerType::Udp => s.udp_score(),
+                };
+                (!score.is_available(), score.score())
+            })
+            .cloned()
+    }
+
+    fn is_pool_server(server: &ServerIdent, server_type: ServerType, pool: &str) -> bool {
+        server.server_config().pool() == Some(pool)
+            && match server_type {
+                ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+            }
+    }
+
+    /// Choose the server for a new flow
+    ///
+    /// The customized `ServerSelector` is used if it was set. Otherwise flows classified into a server pool are
+    /// balanced within the pool, and `default` decides for the others. Returns `Ok(None)` if the flow should bypass
+    /// the proxies, or `PoolExhaustedError` if all servers of the flow's pool are at capacity.
+    pub fn select_server(
+        &self,
+        cx: &ServerSelectContext<'_>,
+        default: &dyn ServerSelector,
+    ) -> io::Result<Option<Arc<ServerIdent>>> {
+        if let Some(ref selector) = self.inner.server_selector {
+            return selector.select(self, cx);
+        }
+
+        if let Some(pool) = self.inner.server_pools.classify(cx.target_addr) {
+            match self.best_pool_server(cx.server_type, pool) {
+                Some(server) => {
+                    let available = match cx.server_type {
+                        ServerType::Tcp => server.tcp_score().is_available(),
+                        ServerType::Udp => server.udp_score().is_available(),
+                    };
+                    if available {
+                        return Ok(Some(server));
+                    }
+                    return self.apply_unavailable_policy(cx.server_type, server);
+                }
+                None => {
+                    let context = self.inner.context.load();
+                    if context
+                        .servers
+                        .iter()
+                        .any(|s| PingBalancer::is_pool_server(s, cx.server_type, pool))
+                    {
+                        self.inner.pool_exhausted_count.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "all {} servers of pool {} are at capacity, rejected",
+                            cx.server_type, pool
+                        );
+                        let err = PoolExhaustedError {
+                            pool: pool.to_owned(),
+                            server_type: cx.server_type,
+                        };
+                        return Err(err.into());
+                    }
+
+                    // Servers of the pool may be removed by reloading
+                    debug!(
+                        "server pool {} doesn't have any {} servers, choosing from all servers",
+                        pool, cx.server_type
+                    );
+                }
+            }
+        }
+
+        default.select(self, cx)
+    }
+
+    fn apply_unavailable_policy(
+        &self,
+        server_type: ServerType,
+        server: Arc<ServerIdent>,
+    ) -> io::Result<Option<Arc<ServerIdent>>> {
+        self.inner.unavailable_count.fetch_add(1, Ordering::Relaxed);
+
+        match self.inner.unavailable_policy {
+            ServerUnavailablePolicy::UseBest => {
+                debug!(
+                    "all {} servers are unavailable, using {} anyway",
+                    server_type,
+                    ServerConfigFormatter::new(server.server_config())
+                );
+                Ok(Some(server))
+            }
+            ServerUnavailablePolicy::Reject => {
+                warn!("all {} servers are unavailable, rejected", server_type);
+                Err(ServiceUnavailableError { server_type }.into())
+            }
+            ServerUnavailablePolicy::Bypass => {
+                warn!("all {} servers are unavailable, bypassing", server_type);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check if a flow to `addr` could connect directly, after choosing servers returned `Ok(None)`
+    ///
+    /// Only targets bypassed by ACL are connected directly, the others are rejected with `ServiceUnavailableError`,
+    /// traffic that should be proxied never leaks while servers are down.
+    pub async fn check_unavailable_bypass(
+        &self,
+        context: &ServiceContext,
+        server_type: ServerType,
+        addr: &Address,
+    ) -> io::Result<()> {
+        if context.check_target_bypassed(addr).await {
+            return Ok(());
+        }
+
+        warn!(
+            "all {} servers are unavailable, {} isn't bypassed by ACL, rejected",
+            server_type, addr
+        );
+        Err(ServiceUnavailableError { server_type }.into())
+    }
+
+    /// Policy when all servers are unavailable
+    pub fn unavailable_policy(&self) -> ServerUnavailablePolicy {
+        self.inner.unavailable_policy
+    }
+
+    /// Number of times that all servers were found unavailable while choosing servers
+    pub fn unavailable_count(&self) -> u64 {
+        self.inner.unavailable_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of flows rejected because all servers of their pools were at capacity
+    pub fn pool_exhausted_count(&self) -> u64 {
+        self.inner.pool_exhausted_count.load(Ordering::Relaxed)
+    }
+
+    /// Find the server configured with `addr`
+    pub fn find_server(&self, addr: &ServerAddr) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context
+            .servers
+            .iter()
+            .find(|s| s.server_config().addr() == addr)
+            .cloned()
+    }
+
+    /// Get the retry budget shared by all connections
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.inner.retry_budget
+    }
+
+    /// Connect by `connect` through `server`, then through the (maybe switched) best TCP server if it failed
+    ///
+    /// Failures are retried once, only if the retry budget is not exhausted. Returns the server that connected, relays
+    /// should all connect by it so that retries are bounded together.
+    pub async fn connect_with_retry<T, F, Fut>(
+        &self,
+        server: Arc<ServerIdent>,
+        mut connect: F,
+    ) -> io::Result<(Arc<ServerIdent>, T)>
+    where
+        F: FnMut(Arc<ServerIdent>) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        match connect(server.clone()).await {
+            Ok(stream) => Ok((server, stream)),
+            Err(err) => {
+                if !self.inner.retry_budget.try_acquire() {
+                    debug!(
+                        "connect through server {} failed, retry budget exhausted, error: {}",
+                        server.server_config().addr(),
+                        err
+                    );
+                    return Err(err);
+                }
+
+                let server = self.best_tcp_server();
+                trace!(
+                    "connect failed, retrying through server {}, error: {}",
+                    server.server_config().addr(),
+                    err
+                );
+                let stream = connect(server.clone()).await?;
+                Ok((server, stream))
+            }
+        }
+    }
+
     /// Get the server list
     pub fn servers(&self) -> PingServerIter<'_> {
         let context = self.inner.context.load();
@@ -699,18 +1214,56 @@ impl PingBalancer {
         }
     }
 
+    /// Get statistic of all servers, including connect error rates reported by relays
+    pub fn server_stats(&self) -> Vec<ServerStats> {
+        let context = self.inner.context.load();
+        context.servers.iter().map(|s| s.stats()).collect()
+    }
+
+    /// Get statistic of the balancer, with its servers' and the retry budget's
+    pub fn stats(&self) -> BalancerStats {
+        BalancerStats {
+            servers: self.server_stats(),
+            retry_budget_available: self.inner.retry_budget.available(),
+            retry_budget_capacity: self.inner.retry_budget.capacity(),
+            unavailable_count: self.unavailable_count(),
+            pool_exhausted_count: self.pool_exhausted_count(),
+        }
+    }
+
+    /// Subscribe to transitions of servers between up and down
+    ///
+    /// Events are sent by both active probing and failures reported by relays. Only the latest 64 events are kept for
+    /// each subscriber, older ones are skipped with `RecvError::Lagged` if it is too slow.
+    pub fn subscribe_health_events(&self) -> broadcast::Receiver<ServerHealthEvent> {
+        self.inner.health_tx.subscribe()
+    }
+
+    /// Number of flows still relaying through servers that were removed by `reset_servers`
+    pub fn draining_flows(&self) -> usize {
+        let mut draining_servers = self.inner.draining_servers.lock();
+        draining_servers.retain(|s| s.active_flows() > 0);
+        draining_servers.iter().map(|s| s.active_flows()).sum()
+    }
+
     /// Reset servers in load balancer. Designed for auto-reloading configuration file.
+    ///
+    /// New flows will only choose from the new servers. Existing flows on removed servers are drained, they could
+    /// keep relaying until they are finished or `drain_timeout` is reached.
     pub async fn reset_servers(&self, servers: Vec<ServerConfig>) -> io::Result<()> {
         let old_context = self.inner.context.load();
 
         let servers = servers
             .into_iter()
             .map(|s| {
-                Arc::new(ServerIdent::new(
+                let mut ident = ServerIdent::new(
                     s,
                     old_context.max_server_rtt,
                     old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-                ))
+                );
+                ident.set_health_events(self.inner.health_tx.clone());
+                ident.set_recovery_ramp(self.inner.recovery_ramp);
+                Arc::new(ident)
             })
             .collect::<Vec<Arc<ServerIdent>>>();
 
@@ -731,8 +1284,35 @@ impl PingBalancer {
         }
 
         // Replace with the new context
+        let is_removed = |server: &Arc<ServerIdent>| {
+            let addr = server.server_config().addr();
+            !shared_context.servers.iter().any(|s| s.server_config().addr() == addr)
+        };
+        let removed_servers = old_context
+            .servers
+            .iter()
+            .filter(|s| is_removed(s))
+            .cloned()
+            .collect::<Vec<_>>();
         self.inner.context.store(shared_context);
 
+        for server in removed_servers {
+            debug!(
+                "draining removed server {} with {} flows",
+                ServerConfigFormatter::new(server.server_config()),
+                server.active_flows()
+            );
+
+            server.start_draining();
+            self.inner.draining_servers.lock().push(server.clone());
+
+            let drain_timeout = self.inner.drain_timeout;
+            tokio::spawn(async move {
+                time::sleep(drain_timeout).await;
+                server.finish_draining();
+            });
+        }
+
         Ok(())
     }
 }
@@ -745,6 +1325,7 @@ impl Debug for PingBalancer {
             .field("servers", &context.servers)
             .field("best_tcp_idx", &context.best_tcp_idx.load(Ordering::Relaxed))
             .field("best_udp_idx", &context.best_udp_idx.load(Ordering::Relaxed))
+            .field("retry_budget", &self.inner.retry_budget.available())
             .finish()
     }
 }
@@ -996,3 +1577,210 @@ impl<'a> Iterator for PingServerIter<'a> {
         self.iter.next().map(AsRef::as_ref)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::crypto::v1::CipherKind;
+
+    use super::{super::ServerHealthTransition, *};
+
+    async fn build_balancer(policy: ServerUnavailablePolicy) -> PingBalancer {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.unavailable_policy(policy);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        builder.build().await.unwrap()
+    }
+
+    async fn mark_all_down(balancer: &PingBalancer) {
+        for server in balancer.inner.context.load().servers.iter() {
+            server.tcp_score().report_failure().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn health_transitions_sent_in_order() {
+        let balancer = build_balancer(ServerUnavailablePolicy::UseBest).await;
+        let mut events = balancer.subscribe_health_events();
+        let server = balancer.best_tcp_server();
+
+        // Server flaps, repeated results of the same direction are not transitions
+        server.tcp_score().push_score(Score::Latency(100)).await;
+        server.tcp_score().report_failure().await;
+        server.tcp_score().report_failure().await;
+        server.tcp_score().push_score(Score::Latency(200)).await;
+        server.tcp_score().push_score(Score::Latency(300)).await;
+        server.udp_score().report_failure().await;
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(&event.addr, server.server_config().addr());
+            received.push((
+                event.server_type.to_string(),
+                event.transition,
+                event.rtt,
+                event.failures,
+            ));
+        }
+        assert_eq!(
+            received,
+            vec![
+                ("TCP".to_owned(), ServerHealthTransition::Down, 100, 1),
+                ("TCP".to_owned(), ServerHealthTransition::Up, 150, 2),
+                ("UDP".to_owned(), ServerHealthTransition::Down, 5000, 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_retries_bounded_by_budget() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.retry_budget(1);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let attempts = AtomicUsize::new(0);
+        let connect = |_| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            future::ready(Err::<(), _>(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "refused",
+            )))
+        };
+
+        // Retried once while the budget lasts, then failures are returned right away
+        assert!(balancer
+            .connect_with_retry(balancer.best_tcp_server(), connect)
+            .await
+            .is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert!(balancer
+            .connect_with_retry(balancer.best_tcp_server(), connect)
+            .await
+            .is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        let stats = balancer.stats();
+        assert_eq!((stats.retry_budget_available, stats.retry_budget_capacity), (0, 1));
+        assert_eq!(stats.servers.len(), 1);
+
+        let (server, ()) = balancer
+            .connect_with_retry(balancer.best_tcp_server(), |_| future::ready(Ok(())))
+            .await
+            .unwrap();
+        assert_eq!(server.server_config().addr(), &stats.servers[0].addr);
+    }
+
+    #[tokio::test]
+    async fn all_servers_down_use_best() {
+        let balancer = build_balancer(ServerUnavailablePolicy::UseBest).await;
+        mark_all_down(&balancer).await;
+
+        assert!(balancer.best_available_tcp_server().unwrap().is_some());
+        assert_eq!(balancer.unavailable_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn all_servers_down_reject() {
+        let balancer = build_balancer(ServerUnavailablePolicy::Reject).await;
+        assert!(balancer.best_available_tcp_server().unwrap().is_some());
+
+        mark_all_down(&balancer).await;
+
+        let err = balancer.best_available_tcp_server().unwrap_err();
+        let err = err.get_ref().and_then(|e| e.downcast_ref::<ServiceUnavailableError>());
+        assert!(matches!(err.map(|e| e.server_type()), Some(ServerType::Tcp)));
+        assert_eq!(balancer.unavailable_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn all_servers_down_bypass() {
+        let balancer = build_balancer(ServerUnavailablePolicy::Bypass).await;
+        mark_all_down(&balancer).await;
+
+        assert!(balancer.best_available_tcp_server().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unavailable_bypass_checks_acl() {
+        use std::{env, fs, process};
+
+        use crate::acl::AccessControl;
+
+        let balancer = build_balancer(ServerUnavailablePolicy::Bypass).await;
+        let addr = Address::SocketAddress("127.0.0.1:80".parse().unwrap());
+
+        // Without ACL, every target should be proxied
+        let err = balancer
+            .check_unavailable_bypass(&ServiceContext::new(), ServerType::Tcp, &addr)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.get_ref(), Some(e) if e.is::<ServiceUnavailableError>()));
+
+        let path = env::temp_dir().join(format!("ss-balancer-bypass-test-{}.acl", process::id()));
+        fs::write(&path, "[proxy_all]\n[bypass_list]\n127.0.0.1/32\n").unwrap();
+        let acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+
+        assert!(balancer
+            .check_unavailable_bypass(&context, ServerType::Tcp, &addr)
+            .await
+            .is_ok());
+        let proxied = Address::SocketAddress("10.0.0.1:80".parse().unwrap());
+        assert!(balancer
+            .check_unavailable_bypass(&context, ServerType::Tcp, &proxied)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reset_servers_drain_removed() {
+        let removed_cfg = ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        let kept_cfg = ServerConfig::new(
+            "127.0.0.1:8389".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.drain_timeout(Duration::from_millis(200));
+        builder.add_server(removed_cfg.clone());
+        builder.add_server(kept_cfg.clone());
+        let balancer = builder.build().await.unwrap();
+
+        // Flows under load on the server which is going to be removed
+        let removed = balancer.find_server(removed_cfg.addr()).unwrap();
+        let finishing_flow = removed.start_flow();
+        let long_flow = removed.start_flow();
+
+        balancer.reset_servers(vec![kept_cfg.clone()]).await.unwrap();
+
+        // New flows avoid the removed server
+        assert!(balancer.find_server(removed_cfg.addr()).is_none());
+        assert_eq!(balancer.best_tcp_server().server_config().addr(), kept_cfg.addr());
+        assert!(removed.is_draining());
+        assert_eq!(balancer.draining_flows(), 2);
+
+        // Finished gracefully
+        drop(finishing_flow);
+        assert_eq!(balancer.draining_flows(), 1);
+
+        // The other one is terminated at the deadline
+        time::timeout(Duration::from_secs(5), removed.drained()).await.unwrap();
+        drop(long_flow);
+        assert_eq!(balancer.draining_flows(), 0);
+    }
+}
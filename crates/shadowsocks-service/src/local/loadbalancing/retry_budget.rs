@@ -0,0 +1,117 @@
+//! Retry budget shared by all connections of a client
+//!
+//! When every server is down at the same time, each flow retrying independently will produce a retry storm
+//! that hits the servers right when they are trying to recover. A shared token bucket bounds the aggregated
+//! retries, excessive retries are simply shed.
+
+use std::time::{Duration, Instant};
+
+use spin::Mutex as SpinMutex;
+
+/// Default size of the retry budget
+pub const DEFAULT_RETRY_BUDGET: u32 = 64;
+
+/// Duration for refilling an empty bucket to full
+const RETRY_BUDGET_REFILL_DURATION: Duration = Duration::from_secs(10);
+
+struct RetryBudgetState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket limiting the total number of retries
+pub struct RetryBudget {
+    capacity: u32,
+    state: SpinMutex<RetryBudgetState>,
+}
+
+impl RetryBudget {
+    /// Create a full bucket with `capacity` tokens
+    pub fn new(capacity: u32) -> RetryBudget {
+        RetryBudget {
+            capacity,
+            state: SpinMutex::new(RetryBudgetState {
+                tokens: capacity as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Size of the bucket
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Tokens currently available for retrying
+    pub fn available(&self) -> u32 {
+        self.available_at(Instant::now())
+    }
+
+    /// Try to take one token for a retry. Returns `false` if the budget is exhausted.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn available_at(&self, now: Instant) -> u32 {
+        let mut state = self.state.lock();
+        self.refill(&mut state, now);
+        state.tokens as u32
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state, now);
+
+        if state.tokens < 1.0 {
+            return false;
+        }
+        state.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, state: &mut RetryBudgetState, now: Instant) {
+        if now <= state.last_refill {
+            return;
+        }
+
+        let elapsed = now - state.last_refill;
+        let refilled = self.capacity as f64 * elapsed.as_secs_f64() / RETRY_BUDGET_REFILL_DURATION.as_secs_f64();
+        state.tokens = (state.tokens + refilled).min(self.capacity as f64);
+        state.last_refill = now;
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> RetryBudget {
+        RetryBudget::new(DEFAULT_RETRY_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mass_outage_retries_bounded() {
+        let budget = RetryBudget::new(16);
+        let start = Instant::now();
+
+        // 10000 flows failed at the same time, only the budget could be retried
+        let retried = (0..10000).filter(|_| budget.try_acquire_at(start)).count();
+        assert_eq!(retried, 16);
+        assert_eq!(budget.available_at(start), 0);
+
+        // Outage lasts for 5 seconds, every flow keeps retrying every 100ms
+        let mut retried = 0;
+        for step in 1..=50 {
+            let now = start + Duration::from_millis(step * 100);
+            retried += (0..10000).filter(|_| budget.try_acquire_at(now)).count();
+        }
+        // Refilled at most half of the bucket in 5 seconds
+        assert!(retried <= 8, "retried {}", retried);
+
+        // Servers recovered, bucket is refilled but never exceeds its capacity
+        let recovered = start + Duration::from_secs(60);
+        assert_eq!(budget.available_at(recovered), 16);
+    }
+}
@@ -2,19 +2,70 @@
 
 use std::{
     fmt::{self, Debug},
-    sync::atomic::{AtomicU32, Ordering},
-    time::Duration,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
-use shadowsocks::ServerConfig;
-use tokio::sync::Mutex;
+use futures::future;
+use shadowsocks::{plugin::PluginFlowMetadataSender, ServerAddr, ServerConfig};
+use spin::Mutex as SpinMutex;
+use tokio::sync::{broadcast, watch, Mutex};
 
-use super::server_stat::{Score, ServerStat};
+use super::{
+    ping_balancer::ServerType,
+    server_stat::{ConnectStat, Score, ServerStat, DEFAULT_CONNECT_STAT_WINDOW},
+};
+
+/// Direction of a server's health transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerHealthTransition {
+    /// The latest check or request succeeded after failures
+    Up,
+    /// The latest check or request failed
+    Down,
+}
+
+/// Transition of a server between up and down, see `PingBalancer::subscribe_health_events`
+#[derive(Debug, Clone)]
+pub struct ServerHealthEvent {
+    /// Address of the server
+    pub addr: ServerAddr,
+    /// TCP and UDP of a server are checked separately
+    pub server_type: ServerType,
+    pub transition: ServerHealthTransition,
+    /// Median RTT in the check window, in milliseconds
+    pub rtt: u32,
+    /// Failed checks and requests in the check window
+    pub failures: usize,
+}
+
+/// Admission weight of a server at the start of its recovery ramp
+const RECOVERY_RAMP_MIN_WEIGHT: f32 = 0.1;
+
+/// Slow-start of a server after it recovered
+#[derive(Default)]
+struct ServerRecovery {
+    recovered_at: Option<Instant>,
+    // Admission weights accumulated in thousandths, a flow is admitted once it reaches 1000
+    credit: u32,
+}
+
+/// Sender of a score's health transitions, with identity of its server
+struct ServerHealthSender {
+    tx: broadcast::Sender<ServerHealthEvent>,
+    addr: ServerAddr,
+    server_type: ServerType,
+}
 
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    available: AtomicBool,
+    connect_stat: SpinMutex<ConnectStat>,
+    health_sender: Option<ServerHealthSender>,
+    recovery_ramp: Duration,
+    recovery: SpinMutex<ServerRecovery>,
 }
 
 impl ServerScore {
@@ -26,6 +77,11 @@ impl ServerScore {
         ServerScore {
             stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
             score: AtomicU32::new(u32::MAX),
+            available: AtomicBool::new(true),
+            connect_stat: SpinMutex::new(ConnectStat::new(DEFAULT_CONNECT_STAT_WINDOW)),
+            health_sender: None,
+            recovery_ramp: Duration::ZERO,
+            recovery: SpinMutex::new(ServerRecovery::default()),
         }
     }
 
@@ -36,23 +92,150 @@ impl ServerScore {
 
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score(&self, score: Score) -> u32 {
-        let updated_score = {
+        let (updated_score, rtt, failures) = {
             let mut stat = self.stat_data.lock().await;
-            stat.push_score(score)
+            let updated_score = stat.push_score(score);
+            (updated_score, stat.rtt(), stat.failures())
         };
         self.score.store(updated_score, Ordering::Release);
+
+        let available = !matches!(score, Score::Errored);
+        let was_available = self.available.swap(available, Ordering::AcqRel);
+        if available != was_available {
+            if !self.recovery_ramp.is_zero() {
+                let mut recovery = self.recovery.lock();
+                recovery.recovered_at = if available { Some(Instant::now()) } else { None };
+                recovery.credit = 0;
+            }
+
+            if let Some(ref sender) = self.health_sender {
+                // Nobody is subscribing if it fails
+                let _ = sender.tx.send(ServerHealthEvent {
+                    addr: sender.addr.clone(),
+                    server_type: sender.server_type,
+                    transition: if available {
+                        ServerHealthTransition::Up
+                    } else {
+                        ServerHealthTransition::Down
+                    },
+                    rtt,
+                    failures,
+                });
+            }
+        }
+
         updated_score
     }
 
+    /// Check if the server is available, which means the latest check or request to this server was succeeded
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Acquire)
+    }
+
+    /// Weight of new flows admitted to the server, ramps up from 0.1 to 1.0 in the recovery ramp after it recovered
+    pub fn admission_weight(&self) -> f32 {
+        self.admission_weight_at(Instant::now())
+    }
+
+    fn admission_weight_at(&self, now: Instant) -> f32 {
+        let mut recovery = self.recovery.lock();
+        let recovered_at = match recovery.recovered_at {
+            Some(t) => t,
+            None => return 1.0,
+        };
+
+        let elapsed = now.saturating_duration_since(recovered_at);
+        if elapsed >= self.recovery_ramp {
+            recovery.recovered_at = None;
+            return 1.0;
+        }
+        (elapsed.as_secs_f32() / self.recovery_ramp.as_secs_f32()).max(RECOVERY_RAMP_MIN_WEIGHT)
+    }
+
+    /// Check if a new flow could be sent to the server, flows are admitted by the admission weight while ramping up
+    pub fn try_admit(&self) -> bool {
+        self.try_admit_at(Instant::now())
+    }
+
+    fn try_admit_at(&self, now: Instant) -> bool {
+        let weight = self.admission_weight_at(now);
+        if weight >= 1.0 {
+            return true;
+        }
+
+        let mut recovery = self.recovery.lock();
+        recovery.credit += (weight * 1000.0) as u32;
+        if recovery.credit >= 1000 {
+            recovery.credit -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+
     /// Report request failure of this server, which will eventually records an `Errored` score
     pub async fn report_failure(&self) -> u32 {
         self.push_score(Score::Errored).await
     }
+
+    /// Report a connect attempt made by relays to this server
+    pub fn report_connect(&self, succeeded: bool) {
+        self.connect_stat.lock().push(succeeded, Instant::now());
+    }
+
+    /// Get a snapshot of the statistic
+    pub fn stats(&self) -> ServerScoreStats {
+        let (connect_attempts, connect_failures) = self.connect_stat.lock().counts(Instant::now());
+        ServerScoreStats {
+            score: self.score(),
+            available: self.is_available(),
+            connect_attempts,
+            connect_failures,
+        }
+    }
+}
+
+/// Snapshot of `ServerScore`
+#[derive(Debug, Clone, Copy)]
+pub struct ServerScoreStats {
+    /// Statistic score, lower is better
+    pub score: u32,
+    /// Whether the latest check or request succeeded
+    pub available: bool,
+    /// Connect attempts made by relays in the recent window
+    pub connect_attempts: u64,
+    /// Failed connect attempts in the recent window
+    pub connect_failures: u64,
+}
+
+impl ServerScoreStats {
+    /// Connect failures / attempts in the recent window, `None` if there were no attempts
+    pub fn error_rate(&self) -> Option<f64> {
+        if self.connect_attempts == 0 {
+            None
+        } else {
+            Some(self.connect_failures as f64 / self.connect_attempts as f64)
+        }
+    }
+}
+
+/// Snapshot of a server's statistic, see `PingBalancer::server_stats`
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    /// Address of the server
+    pub addr: ServerAddr,
+    pub tcp: ServerScoreStats,
+    pub udp: ServerScoreStats,
+    /// Number of flows relaying through the server
+    pub active_flows: usize,
 }
 
 impl Debug for ServerScore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("ServerScore").field("score", &self.score()).finish()
+        f.debug_struct("ServerScore")
+            .field("score", &self.score())
+            .field("available", &self.is_available())
+            .finish()
     }
 }
 
@@ -62,15 +245,26 @@ pub struct ServerIdent {
     tcp_score: ServerScore,
     udp_score: ServerScore,
     svr_cfg: ServerConfig,
+    active_flows: AtomicUsize,
+    draining: AtomicBool,
+    drained_tx: watch::Sender<bool>,
+    drained_rx: watch::Receiver<bool>,
+    plugin_flow_metadata: Option<PluginFlowMetadataSender>,
 }
 
 impl ServerIdent {
     /// Create a `ServerIdent`
     pub fn new(svr_cfg: ServerConfig, max_server_rtt: Duration, check_window: Duration) -> ServerIdent {
+        let (drained_tx, drained_rx) = watch::channel(false);
         ServerIdent {
             tcp_score: ServerScore::new(svr_cfg.weight().tcp_weight(), max_server_rtt, check_window),
             udp_score: ServerScore::new(svr_cfg.weight().udp_weight(), max_server_rtt, check_window),
             svr_cfg,
+            active_flows: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            drained_tx,
+            drained_rx,
+            plugin_flow_metadata: None,
         }
     }
 
@@ -89,4 +283,127 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    /// Get sender of flow metadata to the server's plugin, if the plugin opted in
+    pub fn plugin_flow_metadata(&self) -> Option<&PluginFlowMetadataSender> {
+        self.plugin_flow_metadata.as_ref()
+    }
+
+    pub(crate) fn set_plugin_flow_metadata(&mut self, sender: Option<PluginFlowMetadataSender>) {
+        self.plugin_flow_metadata = sender;
+    }
+
+    pub(crate) fn set_health_events(&mut self, tx: broadcast::Sender<ServerHealthEvent>) {
+        let addr = self.svr_cfg.addr();
+        self.tcp_score.health_sender = Some(ServerHealthSender {
+            tx: tx.clone(),
+            addr: addr.clone(),
+            server_type: ServerType::Tcp,
+        });
+        self.udp_score.health_sender = Some(ServerHealthSender {
+            tx,
+            addr: addr.clone(),
+            server_type: ServerType::Udp,
+        });
+    }
+
+    /// Ramp up flows sent to the server in `ramp` after it recovered, instead of sending all of them at once
+    pub(crate) fn set_recovery_ramp(&mut self, ramp: Duration) {
+        self.tcp_score.recovery_ramp = ramp;
+        self.udp_score.recovery_ramp = ramp;
+    }
+
+    /// Get a snapshot of the statistic
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            addr: self.svr_cfg.addr().clone(),
+            tcp: self.tcp_score.stats(),
+            udp: self.udp_score.stats(),
+            active_flows: self.active_flows(),
+        }
+    }
+
+    /// Number of flows that are relaying through this server
+    pub fn active_flows(&self) -> usize {
+        self.active_flows.load(Ordering::Acquire)
+    }
+
+    /// Check if this server is relaying its `ServerConfig::max_flows`
+    pub fn is_saturated(&self) -> bool {
+        matches!(self.svr_cfg.max_flows(), Some(max_flows) if self.active_flows() >= max_flows)
+    }
+
+    /// Check if this server was removed from the balancer, existing flows are finishing
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Count a flow relaying through this server until the returned guard is dropped
+    pub fn start_flow(&self) -> ServerFlowGuard<'_> {
+        self.active_flows.fetch_add(1, Ordering::AcqRel);
+        ServerFlowGuard { server: self }
+    }
+
+    pub(crate) fn start_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn finish_draining(&self) {
+        let _ = self.drained_tx.send(true);
+    }
+
+    /// Wait until the draining deadline of this server, existing flows should be terminated then
+    ///
+    /// Never completes if the server is not draining.
+    pub async fn drained(&self) {
+        let mut drained_rx = self.drained_rx.clone();
+        while !*drained_rx.borrow() {
+            if drained_rx.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Guard of a flow relaying through a server, see `ServerIdent::start_flow`
+pub struct ServerFlowGuard<'a> {
+    server: &'a ServerIdent,
+}
+
+impl Drop for ServerFlowGuard<'_> {
+    fn drop(&mut self) {
+        self.server.active_flows.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn recovered_server_ramped_up() {
+        let mut score = ServerScore::new(1.0, Duration::from_secs(5), Duration::from_secs(60));
+        score.recovery_ramp = Duration::from_secs(10);
+        score.report_failure().await;
+        score.push_score(Score::Latency(100)).await;
+        let recovered_at = Instant::now();
+
+        // Admitted flows of every 100 new flows increase through the ramp
+        let admitted = |secs: u64| {
+            let now = recovered_at + Duration::from_secs(secs);
+            (0..100).filter(|_| score.try_admit_at(now)).count()
+        };
+        assert_eq!(admitted(0), 10);
+        assert_eq!(admitted(3), 30);
+        assert_eq!(admitted(6), 60);
+        assert_eq!(admitted(10), 100);
+
+        // Ramp is finished, it restarts only after the server is down and recovered again
+        assert_eq!(score.admission_weight(), 1.0);
+        score.push_score(Score::Latency(100)).await;
+        assert!(score.try_admit());
+        score.report_failure().await;
+        score.push_score(Score::Latency(100)).await;
+        assert!(score.admission_weight() < 1.0);
+    }
 }
@@ -0,0 +1,275 @@
+//! Named server pools, chosen by classifying flows
+
+use std::{
+    io::{self, ErrorKind},
+    ops::RangeInclusive,
+};
+
+use shadowsocks::relay::socks5::Address;
+
+use crate::acl::AddressRules;
+
+/// Class of flows that are sent to servers of a named pool
+///
+/// A flow matches if its target matches `targets` and its port is in one of `ports`, absent conditions match all.
+#[derive(Debug, Clone)]
+pub struct ServerPoolClass {
+    pool: String,
+    targets: Option<AddressRules>,
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl ServerPoolClass {
+    /// Create a class of flows sent to servers with `ServerConfig::pool` set to `pool`
+    pub fn new<S: Into<String>>(pool: S) -> ServerPoolClass {
+        ServerPoolClass {
+            pool: pool.into(),
+            targets: None,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Name of the server pool
+    pub fn pool(&self) -> &str {
+        &self.pool
+    }
+
+    /// Only match flows to targets that match `rules`, domain names are not resolved
+    pub fn set_targets(&mut self, rules: AddressRules) {
+        self.targets = Some(rules);
+    }
+
+    /// Match flows to ports in `ports`, could be called multiple times
+    pub fn add_ports(&mut self, ports: RangeInclusive<u16>) {
+        self.ports.push(ports);
+    }
+
+    /// Parse and add ports formatted as `PORT` or `START-END`
+    pub fn add_ports_str(&mut self, ports: &str) -> io::Result<()> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid server pool ports \"{}\"", ports),
+            )
+        };
+
+        let (start, end) = match ports.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (ports, ports),
+        };
+        let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+
+        self.add_ports(start..=end);
+        Ok(())
+    }
+
+    fn matches(&self, target_addr: &Address) -> bool {
+        if !self.ports.is_empty() {
+            let port = match *target_addr {
+                Address::SocketAddress(ref sa) => sa.port(),
+                Address::DomainNameAddress(_, port) => port,
+            };
+            if !self.ports.iter().any(|r| r.contains(&port)) {
+                return false;
+            }
+        }
+
+        match self.targets {
+            Some(ref rules) => rules.check_address_matched(target_addr),
+            None => true,
+        }
+    }
+}
+
+/// Classifies flows into server pools by their targets
+///
+/// Classes are checked in order, the first matched class decides. Flows that match none are balanced among all
+/// servers like there are no pools.
+#[derive(Debug, Clone, Default)]
+pub struct ServerPoolClassifier {
+    classes: Vec<ServerPoolClass>,
+}
+
+impl ServerPoolClassifier {
+    /// Create an empty classifier
+    pub fn new() -> ServerPoolClassifier {
+        ServerPoolClassifier::default()
+    }
+
+    /// Append a class
+    pub fn add_class(&mut self, class: ServerPoolClass) {
+        self.classes.push(class);
+    }
+
+    /// Check if there are no classes
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    /// Get all classes
+    pub fn classes(&self) -> &[ServerPoolClass] {
+        &self.classes
+    }
+
+    /// Name of the pool that flows to `target_addr` should be sent to
+    pub fn classify(&self, target_addr: &Address) -> Option<&str> {
+        self.classes
+            .iter()
+            .find(|c| c.matches(target_addr))
+            .map(|c| c.pool.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use shadowsocks::{config::Mode, crypto::v1::CipherKind, ServerConfig};
+
+    use crate::local::{
+        context::ServiceContext,
+        loadbalancing::{BestServerSelector, PingBalancerBuilder, PoolExhaustedError, ServerSelectContext, ServerType},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn classified_flows_use_pools() {
+        let servers = [
+            ("127.0.0.1:8388", None),
+            ("127.0.0.1:8389", Some("premium")),
+            ("127.0.0.1:8390", Some("bulk")),
+        ]
+        .iter()
+        .map(|(addr, pool)| {
+            let mut svr_cfg =
+                ServerConfig::new(addr.parse::<SocketAddr>().unwrap(), "password", CipherKind::AES_256_GCM);
+            if let Some(pool) = pool {
+                svr_cfg.set_pool(*pool);
+            }
+            svr_cfg
+        })
+        .collect::<Vec<_>>();
+
+        let mut classifier = ServerPoolClassifier::new();
+        let mut premium = ServerPoolClass::new("premium");
+        premium.add_ports_str("443").unwrap();
+        classifier.add_class(premium);
+        let mut bulk = ServerPoolClass::new("bulk");
+        bulk.set_targets(AddressRules::from_lines("bulk", ["203.0.113.0/24", "||example.org"]).unwrap());
+        classifier.add_class(bulk);
+
+        let context = Arc::new(ServiceContext::new());
+
+        // Pools must have servers
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(servers[0].clone());
+        builder.server_pool_classifier(classifier.clone());
+        assert!(builder.build().await.is_err());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        builder.max_server_rtt(Duration::from_millis(100));
+        for svr_cfg in servers.iter() {
+            builder.add_server(svr_cfg.clone());
+        }
+        builder.server_pool_classifier(classifier);
+        let balancer = builder.build().await.unwrap();
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let select = |target_addr: Address| {
+            let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, &target_addr);
+            balancer
+                .select_server(&cx, &BestServerSelector)
+                .unwrap()
+                .unwrap()
+                .server_config()
+                .addr()
+                .clone()
+        };
+
+        // Classes are checked in order
+        assert_eq!(
+            &select(Address::DomainNameAddress("example.org".to_owned(), 443)),
+            servers[1].addr()
+        );
+        assert_eq!(
+            &select(Address::DomainNameAddress("cdn.example.org".to_owned(), 80)),
+            servers[2].addr()
+        );
+        assert_eq!(
+            &select(Address::SocketAddress("203.0.113.7:8080".parse().unwrap())),
+            servers[2].addr()
+        );
+        assert_eq!(
+            select(Address::DomainNameAddress("example.com".to_owned(), 80)),
+            balancer.best_tcp_server().server_config().addr().clone()
+        );
+    }
+
+    #[tokio::test]
+    async fn saturated_pool_exhausted() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        for addr in ["127.0.0.1:8389", "127.0.0.1:8390"] {
+            let mut svr_cfg =
+                ServerConfig::new(addr.parse::<SocketAddr>().unwrap(), "password", CipherKind::AES_256_GCM);
+            svr_cfg.set_pool("premium");
+            svr_cfg.set_max_flows(1);
+            builder.add_server(svr_cfg);
+        }
+        let mut classifier = ServerPoolClassifier::new();
+        let mut premium = ServerPoolClass::new("premium");
+        premium.add_ports_str("443").unwrap();
+        classifier.add_class(premium);
+        builder.server_pool_classifier(classifier);
+        let balancer = builder.build().await.unwrap();
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let target_addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, &target_addr);
+
+        // Unhealthy servers of the pool are still chosen, as `ServerUnavailablePolicy::UseBest`
+        let first = balancer.select_server(&cx, &BestServerSelector).unwrap().unwrap();
+        first.tcp_score().report_failure().await;
+        let second = balancer.select_server(&cx, &BestServerSelector).unwrap().unwrap();
+        second.tcp_score().report_failure().await;
+        assert!(balancer.select_server(&cx, &BestServerSelector).unwrap().is_some());
+        assert_eq!(balancer.pool_exhausted_count(), 0);
+
+        // Saturate the total capacity of the pool
+        let first_flow = first.start_flow();
+        let second = balancer.select_server(&cx, &BestServerSelector).unwrap().unwrap();
+        assert_ne!(first.server_config().addr(), second.server_config().addr());
+        let _second_flow = second.start_flow();
+        assert!(first.is_saturated() && second.is_saturated());
+
+        let err = balancer.select_server(&cx, &BestServerSelector).unwrap_err();
+        let err = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<PoolExhaustedError>())
+            .unwrap();
+        assert_eq!(err.pool(), "premium");
+        assert!(matches!(err.server_type(), ServerType::Tcp));
+        assert_eq!(balancer.pool_exhausted_count(), 1);
+
+        // Flows outside of the pool are not affected
+        let other_addr = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let other_cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, &other_addr);
+        assert!(balancer
+            .select_server(&other_cx, &BestServerSelector)
+            .unwrap()
+            .is_some());
+
+        drop(first_flow);
+        let server = balancer.select_server(&cx, &BestServerSelector).unwrap().unwrap();
+        assert_eq!(server.server_config().addr(), first.server_config().addr());
+    }
+}
@@ -0,0 +1,139 @@
+//! Customizable policy of choosing servers for new flows
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use shadowsocks::relay::socks5::Address;
+
+use super::{
+    ping_balancer::{PingBalancer, ServerType},
+    server_data::ServerIdent,
+};
+
+/// Information of the flow that is choosing a server
+#[derive(Debug, Clone, Copy)]
+pub struct ServerSelectContext<'a> {
+    /// Type of server to be chosen
+    pub server_type: ServerType,
+    /// Address of the local client
+    pub peer_addr: SocketAddr,
+    /// Target address of the flow
+    pub target_addr: &'a Address,
+}
+
+impl<'a> ServerSelectContext<'a> {
+    /// Create a new context
+    pub fn new(server_type: ServerType, peer_addr: SocketAddr, target_addr: &'a Address) -> ServerSelectContext<'a> {
+        ServerSelectContext {
+            server_type,
+            peer_addr,
+            target_addr,
+        }
+    }
+}
+
+/// Chooses the server for a new flow
+///
+/// Set with `PingBalancerBuilder::server_selector` to replace the built-in strategies.
+pub trait ServerSelector: Send + Sync {
+    /// Choose a server from `balancer`
+    ///
+    /// Returns `Ok(None)` if the flow should bypass the proxies.
+    fn select(&self, balancer: &PingBalancer, cx: &ServerSelectContext<'_>) -> io::Result<Option<Arc<ServerIdent>>>;
+}
+
+/// Chooses the server with the best score, regardless of whether it is available
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BestServerSelector;
+
+impl ServerSelector for BestServerSelector {
+    fn select(&self, balancer: &PingBalancer, cx: &ServerSelectContext<'_>) -> io::Result<Option<Arc<ServerIdent>>> {
+        let server = match cx.server_type {
+            ServerType::Tcp => balancer.best_tcp_server(),
+            ServerType::Udp => balancer.best_udp_server(),
+        };
+        Ok(Some(server))
+    }
+}
+
+/// Chooses the best available server, with respect to `ServerUnavailablePolicy` if all servers are down
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AvailableServerSelector;
+
+impl ServerSelector for AvailableServerSelector {
+    fn select(&self, balancer: &PingBalancer, cx: &ServerSelectContext<'_>) -> io::Result<Option<Arc<ServerIdent>>> {
+        match cx.server_type {
+            ServerType::Tcp => balancer.best_available_tcp_server(),
+            ServerType::Udp => balancer.best_available_udp_server(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use shadowsocks::{config::Mode, crypto::v1::CipherKind, ServerAddr, ServerConfig};
+
+    use crate::local::{context::ServiceContext, loadbalancing::PingBalancerBuilder};
+
+    use super::*;
+
+    /// Sends HTTPS through the second server, the others through the best one
+    struct PortSelector {
+        https_server: ServerAddr,
+    }
+
+    impl ServerSelector for PortSelector {
+        fn select(
+            &self,
+            balancer: &PingBalancer,
+            cx: &ServerSelectContext<'_>,
+        ) -> io::Result<Option<Arc<ServerIdent>>> {
+            let port = match *cx.target_addr {
+                Address::SocketAddress(sa) => sa.port(),
+                Address::DomainNameAddress(_, port) => port,
+            };
+            match port {
+                443 => Ok(balancer.find_server(&self.https_server)),
+                53 => Ok(None),
+                _ => BestServerSelector.select(balancer, cx),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_selector_by_port() {
+        let svr_cfgs = ["127.0.0.1:8388", "127.0.0.1:8389"]
+            .iter()
+            .map(|addr| ServerConfig::new(addr.parse::<SocketAddr>().unwrap(), "password", CipherKind::AES_256_GCM))
+            .collect::<Vec<_>>();
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        for svr_cfg in svr_cfgs.iter() {
+            builder.add_server(svr_cfg.clone());
+        }
+        builder.server_selector(Arc::new(PortSelector {
+            https_server: svr_cfgs[1].addr().clone(),
+        }));
+        let balancer = builder.build().await.unwrap();
+
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let select = |target_addr: Address| {
+            let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, &target_addr);
+            balancer
+                .select_server(&cx, &AvailableServerSelector)
+                .unwrap()
+                .map(|s| s.server_config().addr().clone())
+        };
+
+        let https_addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let http_addr = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        assert_eq!(select(https_addr).as_ref(), Some(svr_cfgs[1].addr()));
+        assert_eq!(
+            select(http_addr),
+            Some(balancer.best_tcp_server().server_config().addr().clone())
+        );
+        assert_eq!(select(dns_addr), None);
+    }
+}
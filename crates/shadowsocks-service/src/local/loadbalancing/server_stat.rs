@@ -9,6 +9,11 @@ use std::{
 pub const DEFAULT_CHECK_INTERVAL_SEC: u64 = 10;
 /// Timeout of each check
 pub const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 5; // A common connection timeout of 5 seconds.
+/// Window of counting connect attempts for error rate
+pub const DEFAULT_CONNECT_STAT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of buckets that the connect statistic window is divided into
+const CONNECT_STAT_BUCKETS: u64 = 12;
 
 /// Statistic score
 #[derive(Debug, Copy, Clone)]
@@ -100,6 +105,19 @@ impl ServerStat {
         (score * 10000.0) as u32
     }
 
+    /// Median of latency in the check window, in milliseconds
+    pub fn rtt(&self) -> u32 {
+        self.rtt
+    }
+
+    /// Errored scores in the check window
+    pub fn failures(&self) -> usize {
+        self.latency_queue
+            .iter()
+            .filter(|(score, _)| matches!(score, Score::Errored))
+            .count()
+    }
+
     pub fn push_score(&mut self, score: Score) -> u32 {
         let now = Instant::now();
 
@@ -168,3 +186,97 @@ impl ServerStat {
         self.score()
     }
 }
+
+/// Connect attempts and failures made by relays in a rolling window
+///
+/// The window is divided into buckets, the oldest bucket expires as a whole.
+#[derive(Debug)]
+pub struct ConnectStat {
+    start: Instant,
+    bucket_duration: Duration,
+    /// (Bucket index, attempts, failures)
+    buckets: VecDeque<(u64, u64, u64)>,
+}
+
+impl ConnectStat {
+    pub fn new(window: Duration) -> ConnectStat {
+        ConnectStat {
+            start: Instant::now(),
+            bucket_duration: (window / CONNECT_STAT_BUCKETS as u32).max(Duration::from_millis(1)),
+            buckets: VecDeque::with_capacity(CONNECT_STAT_BUCKETS as usize),
+        }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_millis() / self.bucket_duration.as_millis()) as u64
+    }
+
+    fn expire(&mut self, index: u64) {
+        while let Some(&(i, ..)) = self.buckets.front() {
+            if i + CONNECT_STAT_BUCKETS <= index {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Count an attempt at `now`
+    pub fn push(&mut self, succeeded: bool, now: Instant) {
+        let index = self.bucket_index(now);
+        self.expire(index);
+
+        match self.buckets.back_mut() {
+            Some(&mut (i, ref mut attempts, ref mut failures)) if i == index => {
+                *attempts += 1;
+                if !succeeded {
+                    *failures += 1;
+                }
+            }
+            _ => self.buckets.push_back((index, 1, if succeeded { 0 } else { 1 })),
+        }
+    }
+
+    /// Attempts and failures in the window ending at `now`
+    pub fn counts(&mut self, now: Instant) -> (u64, u64) {
+        self.expire(self.bucket_index(now));
+        self.buckets
+            .iter()
+            .fold((0, 0), |(attempts, failures), &(_, a, f)| (attempts + a, failures + f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::local::loadbalancing::ServerScoreStats;
+
+    use super::*;
+
+    #[test]
+    fn connect_error_rate() {
+        let window = Duration::from_secs(60);
+        let mut stat = ConnectStat::new(window);
+        let now = Instant::now();
+
+        for i in 0..100 {
+            stat.push(i % 4 != 0, now + Duration::from_millis(i * 100));
+        }
+        let (connect_attempts, connect_failures) = stat.counts(now + Duration::from_secs(10));
+        let stats = ServerScoreStats {
+            score: 0,
+            available: true,
+            connect_attempts,
+            connect_failures,
+        };
+        assert_eq!((connect_attempts, connect_failures), (100, 25));
+        assert_eq!(stats.error_rate(), Some(0.25));
+
+        // Newer failures while the earlier attempts are expiring
+        for i in 0..10 {
+            stat.push(false, now + Duration::from_secs(50 + i));
+        }
+        assert_eq!(stat.counts(now + Duration::from_secs(59)), (110, 35));
+        assert_eq!(stat.counts(now + Duration::from_secs(75)), (10, 10));
+        assert_eq!(stat.counts(now + Duration::from_secs(120)), (0, 0));
+    }
+}
@@ -0,0 +1,154 @@
+//! Throttling of connection error logs by destination
+//!
+//! A dead destination fails every connection to it, logging each of the failures floods the log and buries failures
+//! of other destinations. Only the first error of a destination in each interval is logged, the following ones are
+//! counted and logged as a summary after the interval ended.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use shadowsocks::relay::socks5::Address;
+use spin::Mutex as SpinMutex;
+
+/// Maximum destinations throttled at the same time, errors of other destinations are always logged
+const MAX_THROTTLED_DESTINATIONS: usize = 1024;
+
+/// Errors of a destination since its first logged error
+struct ThrottledErrors {
+    since: Instant,
+    suppressed: u64,
+}
+
+/// Throttle of connection error logs keyed by destination, disabled by default
+///
+/// Summaries are checked whenever an error is reported, so the last summary of a destination is delayed until any
+/// connection fails after its interval ended.
+pub struct ConnErrorLogThrottle {
+    interval: Duration,
+    destinations: SpinMutex<HashMap<Address, ThrottledErrors>>,
+}
+
+impl Default for ConnErrorLogThrottle {
+    fn default() -> ConnErrorLogThrottle {
+        ConnErrorLogThrottle::new(Duration::ZERO)
+    }
+}
+
+impl ConnErrorLogThrottle {
+    /// Create a throttle logging at most one error of each destination in `interval`, zero disables throttling
+    pub fn new(interval: Duration) -> ConnErrorLogThrottle {
+        ConnErrorLogThrottle {
+            interval,
+            destinations: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Interval of logging errors of a destination
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Check whether a connection error of `addr` should be logged, it is counted in the destination's summary if not
+    pub fn should_log(&self, addr: &Address) -> bool {
+        let (should_log, summaries) = self.check_at(addr, Instant::now());
+        for (addr, suppressed) in summaries {
+            warn!(
+                "{} more connection errors of {} were not logged in {:?}",
+                suppressed, addr, self.interval
+            );
+        }
+        should_log
+    }
+
+    /// Returns whether the error should be logged, and summaries of destinations whose interval ended
+    fn check_at(&self, addr: &Address, now: Instant) -> (bool, Vec<(Address, u64)>) {
+        if self.interval.is_zero() {
+            return (true, Vec::new());
+        }
+
+        let mut summaries = Vec::new();
+        let mut destinations = self.destinations.lock();
+        destinations.retain(|addr, errors| {
+            if now.saturating_duration_since(errors.since) < self.interval {
+                return true;
+            }
+            if errors.suppressed > 0 {
+                summaries.push((addr.clone(), errors.suppressed));
+            }
+            false
+        });
+
+        let should_log = match destinations.get_mut(addr) {
+            Some(errors) => {
+                errors.suppressed += 1;
+                false
+            }
+            None => {
+                if destinations.len() < MAX_THROTTLED_DESTINATIONS {
+                    destinations.insert(
+                        addr.clone(),
+                        ThrottledErrors {
+                            since: now,
+                            suppressed: 0,
+                        },
+                    );
+                }
+                true
+            }
+        };
+
+        (should_log, summaries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn repeated_errors_collapsed() {
+        let throttle = ConnErrorLogThrottle::new(Duration::from_secs(10));
+        let dead = Address::DomainNameAddress("dead.example.com".to_owned(), 443);
+        let alive = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 80));
+        let start = Instant::now();
+
+        // 100 failures of the dead destination in 5 seconds, only the first one is logged
+        let mut logged = 0;
+        for i in 0..100u64 {
+            let (should_log, summaries) = throttle.check_at(&dead, start + Duration::from_millis(i * 50));
+            assert!(summaries.is_empty());
+            if should_log {
+                logged += 1;
+            }
+        }
+        assert_eq!(logged, 1);
+
+        // Other destinations are not affected
+        assert!(throttle.check_at(&alive, start + Duration::from_secs(5)).0);
+
+        // Repeats are summarized once the interval ended, and the next error is logged
+        let (should_log, summaries) = throttle.check_at(&dead, start + Duration::from_secs(11));
+        assert!(should_log);
+        assert_eq!(summaries, vec![(dead.clone(), 99)]);
+
+        // Destinations without repeats have nothing to summarize
+        let (should_log, summaries) = throttle.check_at(&dead, start + Duration::from_secs(30));
+        assert!(should_log);
+        assert!(summaries.is_empty());
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let throttle = ConnErrorLogThrottle::default();
+        let addr = Address::DomainNameAddress("dead.example.com".to_owned(), 443);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(throttle.check_at(&addr, now), (true, Vec::new()));
+        }
+    }
+}
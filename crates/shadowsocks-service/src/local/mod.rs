@@ -24,19 +24,27 @@ use crate::net::FlowStat;
 use crate::{
     config::{Config, ConfigType, ProtocolType},
     dns::build_dns_resolver,
+    net::{DEFAULT_UDP_RECONNECT_BACKOFF_MAX, DEFAULT_UDP_RECONNECT_BACKOFF_MIN},
 };
 
+#[cfg(feature = "local-flight-recorder")]
+use self::flight_recorder::FlightRecorder;
 use self::{
     context::ServiceContext,
     loadbalancing::{PingBalancer, PingBalancerBuilder},
 };
 
+#[cfg(feature = "local-audit")]
+pub mod audit;
 pub mod context;
 #[cfg(feature = "local-dns")]
 pub mod dns;
+#[cfg(feature = "local-flight-recorder")]
+pub mod flight_recorder;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
+pub mod log_throttle;
 pub mod net;
 #[cfg(feature = "local-redir")]
 pub mod redir;
@@ -77,6 +85,8 @@ impl Future for ServerHandle {
 pub struct Server {
     vfut: Vec<ServerHandle>,
     balancer: PingBalancer,
+    #[cfg(feature = "local-flight-recorder")]
+    flight_recorder: Arc<FlightRecorder>,
 }
 
 impl Server {
@@ -101,6 +111,12 @@ impl Server {
     pub fn server_balancer(&self) -> &PingBalancer {
         &self.balancer
     }
+
+    /// Get recent flow events recorded by all local servers
+    #[cfg(feature = "local-flight-recorder")]
+    pub fn flight_recorder(&self) -> &FlightRecorder {
+        &self.flight_recorder
+    }
 }
 
 /// Starts a shadowsocks local server
@@ -154,6 +170,7 @@ pub async fn create(config: Config) -> io::Result<Server> {
     };
     accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
     accept_opts.tcp.recv_buffer_size = config.inbound_recv_buffer_size;
+    accept_opts.tcp.initial_window = config.inbound_tcp_initial_window;
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -167,12 +184,42 @@ pub async fn create(config: Config) -> io::Result<Server> {
         context.set_ipv6_first(config.ipv6_first);
     }
 
+    #[cfg(feature = "acl-asn")]
+    if let Some(asn_database) = config.asn_database {
+        context.set_asn_database(asn_database);
+    }
+
     if let Some(acl) = config.acl {
         context.set_acl(acl);
     }
 
     context.set_security_config(&config.security);
 
+    if let Some(d) = config.udp_drain_timeout {
+        context.set_udp_drain_timeout(d);
+    }
+
+    if config.udp_hedged_send {
+        context.set_udp_hedged_send(true);
+    }
+
+    context.set_udp_padding(config.udp_padding);
+
+    if config.udp_reconnect_backoff_min.is_some() || config.udp_reconnect_backoff_max.is_some() {
+        context.set_udp_reconnect_backoff(
+            config
+                .udp_reconnect_backoff_min
+                .unwrap_or(DEFAULT_UDP_RECONNECT_BACKOFF_MIN),
+            config
+                .udp_reconnect_backoff_max
+                .unwrap_or(DEFAULT_UDP_RECONNECT_BACKOFF_MAX),
+        );
+    }
+
+    if let Some(d) = config.conn_error_log_interval {
+        context.set_conn_error_log_interval(d);
+    }
+
     assert!(!config.local.is_empty(), "no valid local server configuration");
 
     let context = Arc::new(context);
@@ -202,6 +249,18 @@ pub async fn create(config: Config) -> io::Result<Server> {
             balancer_builder.check_best_interval(intv);
         }
 
+        if let Some(budget) = config.balancer.retry_budget {
+            balancer_builder.retry_budget(budget);
+        }
+
+        // recovery_ramp have to be set before add_server
+        if let Some(ramp) = config.balancer.recovery_ramp {
+            balancer_builder.recovery_ramp(ramp);
+        }
+
+        balancer_builder.unavailable_policy(config.balancer.unavailable_policy);
+        balancer_builder.server_pool_classifier(config.balancer.server_pools);
+
         for server in config.server {
             balancer_builder.add_server(server);
         }
@@ -266,6 +325,26 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 if let Some(d) = config.udp_timeout {
                     server.set_udp_expiry_duration(d);
                 }
+                server.set_udp_capacity_mode(config.udp_capacity_mode);
+                server.set_udp_nat_mode(config.udp_nat_mode);
+                server.set_udp_outbound_pool_size(config.udp_outbound_pool_size);
+                #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+                if let Some(size) = config.udp_mmsg_batch_size {
+                    server.set_udp_mmsg_batch_size(size);
+                }
+                #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+                server.set_udp_hop_limit_mode(config.udp_hop_limit_mode);
+                if let Some(d) = config.tcp_first_byte_timeout {
+                    server.set_tcp_first_byte_timeout(d);
+                }
+                server.set_udp_channel_full_policy(config.udp_channel_full_policy);
+                if let Some(size) = config.udp_send_channel_size {
+                    server.set_udp_send_channel_size(size);
+                }
+                if let Some(size) = config.udp_max_payload_size {
+                    server.set_udp_max_payload_size(size);
+                }
+                server.set_udp_ttl_rules(config.udp_ttl_rules.clone());
                 server.set_mode(local_config.mode);
 
                 let udp_addr = local_config.udp_addr.unwrap_or_else(|| client_addr.clone());
@@ -347,6 +426,55 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 if let Some(name) = local_config.tun_interface_name {
                     builder = builder.name(&name);
                 }
+                if let Some(mtu) = local_config.tun_mtu {
+                    builder = builder.mtu(mtu);
+                }
+                builder = builder.auto_correct_mtu(local_config.tun_auto_correct_mtu);
+                builder = builder.clamp_mss_on_mtu_blackhole(local_config.tun_clamp_mss_on_mtu_blackhole);
+                if let Some(rate) = local_config.tun_tcp_conn_rate_limit {
+                    let burst = local_config.tun_tcp_conn_rate_burst.unwrap_or(rate);
+                    builder = builder.tcp_conn_rate_limit(rate, burst);
+                }
+                if let Some(rate) = local_config.tun_tcp_bandwidth_limit {
+                    let burst = local_config.tun_tcp_bandwidth_burst.unwrap_or(rate);
+                    builder = builder.tcp_bandwidth_limit(rate, burst);
+                }
+                if let Some(rate) = local_config.tun_tcp_global_bandwidth_limit {
+                    let burst = local_config.tun_tcp_global_bandwidth_burst.unwrap_or(rate);
+                    builder = builder.tcp_global_bandwidth_limit(rate, burst);
+                }
+                if let Some(mss) = local_config.tun_tcp_send_mss {
+                    builder = builder.tcp_send_mss(mss);
+                }
+                if let Some(mss) = local_config.tun_tcp_recv_mss {
+                    builder = builder.tcp_recv_mss(mss);
+                }
+                if let Some(overhead) = local_config.tun_tcp_mss_overhead {
+                    builder = builder.tcp_mss_overhead(overhead);
+                }
+                if let Some(d) = local_config.tun_tcp_idle_timeout {
+                    builder = builder.tcp_idle_timeout(d);
+                }
+                if let Some(d) = local_config.tun_tcp_connect_timeout {
+                    builder = builder.tcp_connect_timeout(d);
+                }
+                if let Some(max) = local_config.tun_tcp_recv_buffer_autotune_max {
+                    builder = builder.tcp_recv_buffer_autotune(max);
+                }
+                if let Some(budget) = local_config.tun_tcp_round_budget {
+                    builder = builder.tcp_round_budget(budget);
+                }
+                if let Some(limit) = local_config.tun_tcp_out_queue_limit {
+                    builder = builder.tcp_out_queue_limit(limit);
+                }
+                builder = builder.tcp_reset_on_remote_failure(local_config.tun_tcp_reset_on_remote_failure);
+                builder = builder.tcp_reset_on_server_down(local_config.tun_tcp_reset_on_server_down);
+                builder = builder.tcp_early_data_policy(local_config.tun_tcp_early_data_policy);
+                builder = builder.tcp_proxy_protocol(local_config.tun_tcp_proxy_protocol);
+                builder = builder.tcp_resolve_strategy(local_config.tun_tcp_resolve_strategy);
+                if let Some(d) = config.tcp_first_byte_timeout {
+                    builder = builder.tcp_first_byte_timeout(d);
+                }
                 if let Some(c) = config.udp_max_associations {
                     builder = builder.udp_capacity(c);
                 }
@@ -354,6 +482,14 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     builder = builder.udp_expiry_duration(d);
                 }
                 builder = builder.mode(local_config.mode);
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                if let Some(fwmark) = local_config.tun_outbound_fwmark {
+                    builder = builder.outbound_fwmark(fwmark);
+                }
+                #[cfg(feature = "local-tun-capture")]
+                if let Some(path) = local_config.tun_capture_path {
+                    builder = builder.tcp_frame_capture(path);
+                }
                 #[cfg(unix)]
                 if let Some(fd) = local_config.tun_device_fd {
                     builder = builder.file_descriptor(fd);
@@ -411,7 +547,12 @@ pub async fn create(config: Config) -> io::Result<Server> {
         }
     }
 
-    Ok(Server { vfut, balancer })
+    Ok(Server {
+        vfut,
+        balancer,
+        #[cfg(feature = "local-flight-recorder")]
+        flight_recorder: context.flight_recorder(),
+    })
 }
 
 #[cfg(feature = "local-flow-stat")]
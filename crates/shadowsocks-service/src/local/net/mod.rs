@@ -1,7 +1,14 @@
 //! Shadowsocks Local Network Utilities
 
 pub use self::{
-    tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream},
+    tcp::{
+        auto_proxy_io::AutoProxyIo,
+        auto_proxy_stream::AutoProxyClientStream,
+        sniff::{
+            parse_host, parse_http_host, parse_tls_sni, sniff_host, PrefixedStream, SniffConfig, SniffResult,
+            DEFAULT_SNIFF_TIMEOUT,
+        },
+    },
     udp::{UdpAssociationManager, UdpInboundWrite},
 };
 
@@ -10,7 +10,9 @@ use std::{
 
 use pin_project::pin_project;
 use shadowsocks::{
-    net::TcpStream,
+    config::{ResolveStrategy, ServerConfig},
+    dns_resolver::DnsResolver,
+    net::{ConnectOpts, TcpStream},
     relay::{
         socks5::Address,
         tcprelay::proxy_stream::{ProxyClientStream, ProxyClientStreamReadHalf, ProxyClientStreamWriteHalf},
@@ -19,8 +21,12 @@ use shadowsocks::{
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::ServerIdent},
-    net::MonProxyStream,
+    local::{
+        context::ServiceContext,
+        loadbalancing::{BestServerSelector, PingBalancer, ServerIdent, ServerSelectContext, ServerType},
+        utils::{resolve_address_with, resolve_server_config_with},
+    },
+    net::{FlowStat, MonProxyStream},
 };
 
 use super::auto_proxy_io::AutoProxyIo;
@@ -40,54 +46,205 @@ impl AutoProxyClientStream {
         server: &ServerIdent,
         addr: A,
     ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_with_opts(context.clone(), server, addr, context.connect_opts_ref()).await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`, with `opts` instead of the context's
+    pub async fn connect_with_opts<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_with_strategy(context, server, addr, None, opts).await
+    }
+
+    /// Connect to target `addr` like `connect_with_opts`, connecting directly with `strategy` if it's bypassed
+    ///
+    /// Servers are connected with their own `ServerConfig::resolve_strategy`.
+    pub async fn connect_with_strategy<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         let addr = addr.into();
         if context.check_target_bypassed(&addr).await {
-            AutoProxyClientStream::connect_bypassed(context, addr).await
+            AutoProxyClientStream::connect_bypassed_with_strategy(context, addr, strategy, opts).await
         } else {
-            AutoProxyClientStream::connect_proxied(context, server, addr).await
+            AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await
+        }
+    }
+
+    /// Connect to target `addr` like `connect_with_strategy`, resolving domain names with `resolver` instead of the
+    /// context's
+    ///
+    /// The server's name is resolved, and the target's if it's bypassed. Proxied targets are still resolved by the
+    /// server, they don't leak to `resolver`.
+    pub async fn connect_with_resolver<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
+        resolver: &DnsResolver,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let addr = addr.into();
+        if context.check_target_bypassed(&addr).await {
+            AutoProxyClientStream::connect_bypassed_with_resolver(context, addr, strategy, opts, resolver).await
+        } else {
+            let svr_cfg = resolve_server_config_with(resolver, server.server_config()).await?;
+            AutoProxyClientStream::connect_proxied_with_config(context, server, &svr_cfg, addr, opts).await
+        }
+    }
+
+    /// Connect to target `addr` via the server chosen by `balancer`
+    ///
+    /// The best server is chosen unless `balancer` has a customized `ServerSelector`. Returns the chosen server, which
+    /// is the best server if the selector bypassed the proxies. Failures are retried by
+    /// `PingBalancer::connect_with_retry`, returning the server that connected.
+    pub async fn connect_balanced(
+        context: Arc<ServiceContext>,
+        balancer: &PingBalancer,
+        peer_addr: SocketAddr,
+        addr: &Address,
+    ) -> io::Result<(Arc<ServerIdent>, AutoProxyClientStream)> {
+        let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, addr);
+        match balancer.select_server(&cx, &BestServerSelector)? {
+            Some(server) => {
+                balancer
+                    .connect_with_retry(server, |server| {
+                        let context = context.clone();
+                        async move { AutoProxyClientStream::connect(context, &server, addr).await }
+                    })
+                    .await
+            }
+            None => {
+                let stream = AutoProxyClientStream::connect_bypassed(context, addr).await?;
+                Ok((balancer.best_tcp_server(), stream))
+            }
         }
     }
 
     /// Connect directly to target `addr`
     pub async fn connect_bypassed<A>(context: Arc<ServiceContext>, addr: A) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_bypassed_with_opts(context.clone(), addr, context.connect_opts_ref()).await
+    }
+
+    /// Connect directly to target `addr`, with `opts` instead of the context's
+    pub async fn connect_bypassed_with_opts<A>(
+        context: Arc<ServiceContext>,
+        addr: A,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_bypassed_with_strategy(context, addr, None, opts).await
+    }
+
+    /// Connect directly to target `addr`, choosing its resolved addresses with `strategy`
+    ///
+    /// `None` races IPv4 and IPv6 addresses like `ResolveStrategy::Parallel`.
+    pub async fn connect_bypassed_with_strategy<A>(
+        context: Arc<ServiceContext>,
+        addr: A,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         // Connect directly.
         let addr = addr.into();
-        let stream =
-            TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()).await?;
+        let stream = TcpStream::connect_remote_with_strategy(context.context_ref(), &addr, strategy, opts).await?;
         Ok(AutoProxyClientStream::Bypassed(stream))
     }
 
+    /// Connect directly to target `addr` like `connect_bypassed_with_strategy`, its domain name is resolved with
+    /// `resolver` instead of the context's
+    pub async fn connect_bypassed_with_resolver<A>(
+        context: Arc<ServiceContext>,
+        addr: A,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
+        resolver: &DnsResolver,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let addr = resolve_address_with(resolver, &addr.into(), strategy).await?;
+        AutoProxyClientStream::connect_bypassed_with_strategy(context, addr, strategy, opts).await
+    }
+
     /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`
     pub async fn connect_proxied<A>(
         context: Arc<ServiceContext>,
         server: &ServerIdent,
         addr: A,
     ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_proxied_with_opts(context.clone(), server, addr, context.connect_opts_ref())
+            .await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`, with `opts` instead of the context's
+    pub async fn connect_proxied_with_opts<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_proxied_with_config(context, server, server.server_config(), addr, opts).await
+    }
+
+    /// Connect to target `addr` via `server` like `connect_proxied_with_opts`, configured by `svr_cfg` instead of its
+    /// own `ServerConfig`, e.g. with its address already resolved
+    pub async fn connect_proxied_with_config<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        svr_cfg: &ServerConfig,
+        addr: A,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         let flow_stat = context.flow_stat();
-        let stream = match ProxyClientStream::connect_with_opts_map(
-            context.context(),
-            server.server_config(),
-            addr,
-            context.connect_opts_ref(),
-            |stream| MonProxyStream::from_stream(stream, flow_stat),
-        )
+        let stream = match ProxyClientStream::connect_with_opts_map(context.context(), svr_cfg, addr, opts, |stream| {
+            MonProxyStream::from_stream(stream, flow_stat)
+        })
         .await
         {
             Ok(s) => s,
             Err(err) => {
+                server.tcp_score().report_connect(false);
                 server.tcp_score().report_failure().await;
                 return Err(err);
             }
         };
+        server.tcp_score().report_connect(true);
         Ok(AutoProxyClientStream::Proxied(stream))
     }
 
@@ -98,12 +255,36 @@ impl AutoProxyClientStream {
         }
     }
 
+    /// Remote address of the connection, it is the resolved server's address if proxied
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().peer_addr(),
+            AutoProxyClientStream::Bypassed(ref s) => s.peer_addr(),
+        }
+    }
+
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         match *self {
             AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
             AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
         }
     }
+
+    /// Flow statistic of the proxied connection, `None` if bypassed
+    pub fn flow_stat(&self) -> Option<&Arc<FlowStat>> {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s) => Some(s.get_ref().flow_stat()),
+            AutoProxyClientStream::Bypassed(..) => None,
+        }
+    }
+
+    /// Bytes transmitted and received on the wire of the proxied connection, `None` if bypassed
+    pub fn wire_bytes(&self) -> Option<(u64, u64)> {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s) => Some((s.get_ref().wire_tx(), s.get_ref().wire_rx())),
+            AutoProxyClientStream::Bypassed(..) => None,
+        }
+    }
 }
 
 impl AutoProxyIo for AutoProxyClientStream {
@@ -153,6 +334,13 @@ impl AsyncWrite for AutoProxyClientStream {
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
+
+    fn is_write_vectored(&self) -> bool {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s) => s.is_write_vectored(),
+            AutoProxyClientStream::Bypassed(ref s) => s.is_write_vectored(),
+        }
+    }
 }
 
 impl From<ProxyClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStream {
@@ -249,4 +437,90 @@ impl AsyncWrite for AutoProxyClientStreamWriteHalf {
             AutoProxyClientStreamWriteHalfProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
+
+    fn is_write_vectored(&self) -> bool {
+        match *self {
+            AutoProxyClientStreamWriteHalf::Proxied(ref s) => s.is_write_vectored(),
+            AutoProxyClientStreamWriteHalf::Bypassed(ref s) => s.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use std::time::Duration;
+
+    use shadowsocks::{
+        config::{Mode, ServerConfig},
+        crypto::v1::CipherKind,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time,
+    };
+
+    use crate::{
+        config::{Config, ConfigType},
+        local::loadbalancing::PingBalancerBuilder,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn servers_with_different_methods() {
+        // An echo server as target
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let svr_cfgs = vec![
+            ServerConfig::new(
+                "127.0.0.1:18510".parse::<SocketAddr>().unwrap(),
+                "password-gcm",
+                CipherKind::AES_256_GCM,
+            ),
+            ServerConfig::new(
+                "127.0.0.1:18520".parse::<SocketAddr>().unwrap(),
+                "password-chacha",
+                CipherKind::CHACHA20_POLY1305,
+            ),
+        ];
+
+        let mut server_config = Config::new(ConfigType::Server);
+        server_config.server = svr_cfgs.clone();
+        tokio::spawn(crate::run_server(server_config));
+        time::sleep(Duration::from_secs(1)).await;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        for svr_cfg in svr_cfgs.iter() {
+            builder.add_server(svr_cfg.clone());
+        }
+        let balancer = builder.build().await.unwrap();
+
+        for svr_cfg in svr_cfgs.iter() {
+            let server = balancer.find_server(svr_cfg.addr()).unwrap();
+            assert_eq!(server.server_config().method(), svr_cfg.method());
+
+            let mut stream = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr)
+                .await
+                .unwrap();
+
+            let payload = format!("hello through {}", svr_cfg.method());
+            stream.write_all(payload.as_bytes()).await.unwrap();
+
+            let mut buffer = vec![0u8; payload.len()];
+            stream.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(buffer, payload.as_bytes());
+        }
+    }
 }
@@ -0,0 +1,518 @@
+//! Sniffing hostname from the first bytes sent by client
+//!
+//! Flows captured by IP (like TUN without fake-IP) lose the hostname that clients intended to connect. TLS clients
+//! send the hostname in plaintext in the SNI extension of ClientHello, which is the first message of the connection.
+//! Plaintext HTTP clients send it in the `Host` header of the first request.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    time,
+};
+
+/// Default time waiting for the client's first message
+pub const DEFAULT_SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Maximum bytes buffered for sniffing TLS, a TLS record header and the largest TLS plaintext record
+const TLS_SNIFF_MAX_BUFFER_SIZE: usize = 5 + 16 * 1024;
+/// Maximum bytes buffered for sniffing HTTP, the first request's headers must fit in it
+const HTTP_SNIFF_MAX_BUFFER_SIZE: usize = 8 * 1024;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"TRACE ",
+];
+
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Protocols to be sniffed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SniffConfig {
+    /// SNI in TLS ClientHello
+    pub tls: bool,
+    /// `Host` header in plaintext HTTP request
+    pub http: bool,
+}
+
+impl SniffConfig {
+    /// Whether any protocol is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.tls || self.http
+    }
+
+    fn max_buffer_size(&self) -> usize {
+        if self.tls {
+            TLS_SNIFF_MAX_BUFFER_SIZE
+        } else {
+            HTTP_SNIFF_MAX_BUFFER_SIZE
+        }
+    }
+}
+
+/// Result of parsing the client's first bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniffResult {
+    /// Host name sent by client
+    Host(String),
+    /// Not the expected protocol, or no host name in it
+    NotFound,
+    /// More bytes are required
+    Incomplete,
+}
+
+/// Parse host name from the client's first bytes with protocols enabled in `config`
+pub fn parse_host(buf: &[u8], config: SniffConfig) -> SniffResult {
+    let mut incomplete = false;
+
+    if config.tls {
+        match parse_tls_sni(buf) {
+            SniffResult::Host(host) => return SniffResult::Host(host),
+            SniffResult::Incomplete => incomplete = true,
+            SniffResult::NotFound => {}
+        }
+    }
+
+    if config.http {
+        match parse_http_host(buf) {
+            SniffResult::Host(host) => return SniffResult::Host(host),
+            SniffResult::Incomplete => incomplete = true,
+            SniffResult::NotFound => {}
+        }
+    }
+
+    if incomplete {
+        SniffResult::Incomplete
+    } else {
+        SniffResult::NotFound
+    }
+}
+
+/// Parse SNI from the first TLS record sent by client
+pub fn parse_tls_sni(buf: &[u8]) -> SniffResult {
+    // TLSPlaintext { type, legacy_record_version, length }
+    if buf.is_empty() {
+        return SniffResult::Incomplete;
+    }
+    if buf[0] != TLS_CONTENT_TYPE_HANDSHAKE {
+        return SniffResult::NotFound;
+    }
+    if buf.len() < 5 {
+        return SniffResult::Incomplete;
+    }
+    if buf[1] != 0x03 {
+        return SniffResult::NotFound;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + record_len {
+        return SniffResult::Incomplete;
+    }
+
+    match parse_client_hello(&buf[5..5 + record_len]) {
+        Some(Some(name)) => SniffResult::Host(name),
+        _ => SniffResult::NotFound,
+    }
+}
+
+/// Cursor over a TLS message, all reads return `None` if out of bounds
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let (v, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(*v)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.bytes(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.bytes(3)?;
+        Some(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(v)
+    }
+
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.bytes(n)
+    }
+
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.bytes(n)
+    }
+}
+
+/// Returns `None` if the message is malformed, `Some(None)` if there is no SNI
+fn parse_client_hello(record: &[u8]) -> Option<Option<String>> {
+    let mut r = Reader { buf: record };
+    if r.u8()? != TLS_HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    // ClientHello split into multiple records is not supported, it only happens with huge extensions
+    let hello_len = r.u24()?;
+    let mut r = Reader {
+        buf: r.bytes(hello_len)?,
+    };
+
+    // legacy_version, random
+    r.bytes(2 + 32)?;
+    // legacy_session_id
+    r.vec_u8()?;
+    // cipher_suites
+    r.vec_u16()?;
+    // legacy_compression_methods
+    r.vec_u8()?;
+
+    if r.buf.is_empty() {
+        // No extensions
+        return Some(None);
+    }
+
+    let mut extensions = Reader { buf: r.vec_u16()? };
+    while !extensions.buf.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut ext = Reader {
+            buf: extensions.vec_u16()?,
+        };
+        if ext_type != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader { buf: ext.vec_u16()? };
+        while !names.buf.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec_u16()?;
+            if name_type == TLS_SERVER_NAME_TYPE_HOST_NAME {
+                return Some(validate_host_name(name));
+            }
+        }
+        return Some(None);
+    }
+
+    Some(None)
+}
+
+fn validate_host_name(name: &[u8]) -> Option<String> {
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+    if !name
+        .iter()
+        .all(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_')
+    {
+        return None;
+    }
+    // Literal IP addresses are not permitted in SNI
+    let name = std::str::from_utf8(name).ok()?;
+    if name.parse::<std::net::IpAddr>().is_ok() {
+        return None;
+    }
+    Some(name.to_ascii_lowercase())
+}
+
+/// Parse `Host` header from the first plaintext HTTP request sent by client
+///
+/// Only headers of the first request are parsed, pipelined requests after it are ignored.
+pub fn parse_http_host(buf: &[u8]) -> SniffResult {
+    let is_request = HTTP_METHODS.iter().any(|m| {
+        let n = m.len().min(buf.len());
+        buf[..n] == m[..n]
+    });
+    if !is_request {
+        return SniffResult::NotFound;
+    }
+
+    let headers_end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None if buf.len() >= HTTP_SNIFF_MAX_BUFFER_SIZE => return SniffResult::NotFound,
+        None => return SniffResult::Incomplete,
+    };
+
+    // Skip the request line
+    for line in buf[..headers_end].split(|&c| c == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = match line.iter().position(|&c| c == b':') {
+            Some(p) => p,
+            None => continue,
+        };
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if !name.eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+
+        let value = match std::str::from_utf8(value) {
+            Ok(v) => v.trim(),
+            Err(..) => return SniffResult::NotFound,
+        };
+        // Port in `Host` is ignored, the destination port of the flow is used
+        let host = match value.rsplit_once(':') {
+            Some((host, port)) if !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit()) => host,
+            _ => value,
+        };
+        return match validate_host_name(host.as_bytes()) {
+            Some(host) => SniffResult::Host(host),
+            None => SniffResult::NotFound,
+        };
+    }
+
+    SniffResult::NotFound
+}
+
+/// Read the client's first bytes into `buffer` and sniff host name from them
+///
+/// Stops when the enabled protocols are parsed, EOF, or `timeout`. Bytes read are kept in `buffer`, which must be
+/// relayed before the rest of the stream.
+pub async fn sniff_host<S>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    config: SniffConfig,
+    timeout: Duration,
+) -> io::Result<Option<String>>
+where
+    S: AsyncRead + Unpin,
+{
+    let sniff = async {
+        let mut read_buf = [0u8; 4096];
+        let read_buf_len = read_buf.len();
+        loop {
+            match parse_host(buffer, config) {
+                SniffResult::Host(name) => return Ok(Some(name)),
+                SniffResult::NotFound => return Ok(None),
+                SniffResult::Incomplete => {}
+            }
+
+            let remaining = config.max_buffer_size().saturating_sub(buffer.len());
+            if remaining == 0 {
+                return Ok(None);
+            }
+
+            let n = stream.read(&mut read_buf[..remaining.min(read_buf_len)]).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buffer.extend_from_slice(&read_buf[..n]);
+        }
+    };
+
+    match time::timeout(timeout, sniff).await {
+        Ok(r) => r,
+        Err(..) => Ok(None),
+    }
+}
+
+/// Stream that replays bytes already read from `stream` before reading from it
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    stream: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, stream: S) -> PrefixedStream<S> {
+        PrefixedStream { prefix, pos: 0, stream }
+    }
+}
+
+impl<S> AsyncRead for PrefixedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let remaining = &this.prefix[this.pos..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for PrefixedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// ClientHello sent by `openssl s_client -servername www.example.com -tls1_2`
+    const CLIENT_HELLO: &[u8] = &[
+        0x16, 0x03, 0x01, 0x00, 0xce, 0x01, 0x00, 0x00, 0xca, 0x03, 0x03, 0x34, 0x3f, 0x6d, 0x4f, 0x37, 0x77, 0x12,
+        0x0d, 0xf8, 0x69, 0x85, 0x19, 0xba, 0x47, 0xa6, 0xed, 0x48, 0xe2, 0x11, 0x68, 0x73, 0x4d, 0x4d, 0xd8, 0x7d,
+        0xf9, 0xb8, 0x19, 0xf5, 0x60, 0x7b, 0x31, 0x00, 0x00, 0x36, 0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9,
+        0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e, 0xc0, 0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23,
+        0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d,
+        0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x01, 0x00, 0x00, 0x6b, 0xff, 0x01, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x12, 0x00, 0x00, 0x0f, 0x77, 0x77, 0x77, 0x2e, 0x65, 0x78, 0x61, 0x6d,
+        0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x00, 0x0b, 0x00, 0x04, 0x03, 0x00, 0x01, 0x02, 0x00, 0x0a, 0x00,
+        0x0c, 0x00, 0x0a, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x1e, 0x00, 0x18, 0x00, 0x19, 0x00, 0x16, 0x00, 0x00, 0x00,
+        0x17, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x2a, 0x00, 0x28, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x07, 0x08,
+        0x08, 0x08, 0x09, 0x08, 0x0a, 0x08, 0x0b, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06,
+        0x01, 0x03, 0x03, 0x03, 0x01, 0x03, 0x02, 0x04, 0x02, 0x05, 0x02, 0x06, 0x02,
+    ];
+
+    const TLS_ONLY: SniffConfig = SniffConfig { tls: true, http: false };
+
+    #[test]
+    fn parse_captured_client_hello() {
+        assert_eq!(
+            parse_tls_sni(CLIENT_HELLO),
+            SniffResult::Host("www.example.com".to_owned())
+        );
+        assert_eq!(parse_tls_sni(&CLIENT_HELLO[..100]), SniffResult::Incomplete);
+        assert_eq!(parse_tls_sni(b"GET / HTTP/1.1\r\n"), SniffResult::NotFound);
+    }
+
+    #[tokio::test]
+    async fn sniff_tls_and_non_tls() {
+        // ClientHello arriving in segments
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(&CLIENT_HELLO[..50]).await.unwrap();
+        let writer = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(10)).await;
+            client.write_all(&CLIENT_HELLO[50..]).await.unwrap();
+            client
+        });
+        let mut buffer = Vec::new();
+        let sni = sniff_host(&mut server, &mut buffer, TLS_ONLY, DEFAULT_SNIFF_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(sni.as_deref(), Some("www.example.com"));
+        assert_eq!(buffer, CLIENT_HELLO);
+        drop(writer.await.unwrap());
+
+        // Non-TLS traffic passes through unchanged
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut server = server;
+        let mut buffer = Vec::new();
+        let sni = sniff_host(&mut server, &mut buffer, TLS_ONLY, DEFAULT_SNIFF_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(sni, None);
+
+        let mut stream = PrefixedStream::new(buffer, server);
+        let mut relayed = Vec::new();
+        stream.read_to_end(&mut relayed).await.unwrap();
+        assert_eq!(relayed, request);
+    }
+
+    #[test]
+    fn parse_http_requests() {
+        assert_eq!(
+            parse_http_host(b"GET /index.html HTTP/1.1\r\nUser-Agent: curl\r\nHOST: Example.COM:8080\r\n\r\n"),
+            SniffResult::Host("example.com".to_owned())
+        );
+        // Pipelined, only the first request is used
+        assert_eq!(
+            parse_http_host(
+                b"GET / HTTP/1.1\r\nHost: a.example.com\r\n\r\nGET / HTTP/1.1\r\nHost: b.example.com\r\n\r\n"
+            ),
+            SniffResult::Host("a.example.com".to_owned())
+        );
+        assert_eq!(
+            parse_http_host(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"),
+            SniffResult::NotFound
+        );
+        assert_eq!(parse_http_host(b"GE"), SniffResult::Incomplete);
+        assert_eq!(
+            parse_http_host(b"GET / HTTP/1.1\r\nHost: exam"),
+            SniffResult::Incomplete
+        );
+        assert_eq!(parse_http_host(b"SSH-2.0-OpenSSH_8.9\r\n"), SniffResult::NotFound);
+        assert_eq!(parse_http_host(CLIENT_HELLO), SniffResult::NotFound);
+
+        // Headers never end
+        let mut huge = b"GET / HTTP/1.1\r\n".to_vec();
+        huge.resize(HTTP_SNIFF_MAX_BUFFER_SIZE, b'a');
+        assert_eq!(parse_http_host(&huge), SniffResult::NotFound);
+    }
+
+    #[tokio::test]
+    async fn sniff_http_passes_through() {
+        let config = SniffConfig { tls: true, http: true };
+        let request = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n".to_vec();
+
+        // Partial request
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(&request[..20]).await.unwrap();
+        let remaining = request[20..].to_vec();
+        let writer = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(10)).await;
+            client.write_all(&remaining).await.unwrap();
+            client.shutdown().await.unwrap();
+            client
+        });
+
+        let mut buffer = Vec::new();
+        let host = sniff_host(&mut server, &mut buffer, config, DEFAULT_SNIFF_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(host.as_deref(), Some("www.example.com"));
+
+        let mut stream = PrefixedStream::new(buffer, server);
+        let mut relayed = Vec::new();
+        stream.read_to_end(&mut relayed).await.unwrap();
+        assert_eq!(relayed, request);
+        drop(writer.await.unwrap());
+
+        // Non-HTTP bytes
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(b"\x00\x01binary").await.unwrap();
+        let mut buffer = Vec::new();
+        let host = sniff_host(&mut server, &mut buffer, config, DEFAULT_SNIFF_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(host, None);
+        assert_eq!(buffer, b"\x00\x01binary");
+    }
+}
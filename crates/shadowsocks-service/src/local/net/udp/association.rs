@@ -1,7 +1,9 @@
 //! UDP Association Managing
 
 use std::{
-    io::{self, ErrorKind},
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    io,
     marker::PhantomData,
     net::SocketAddr,
     sync::Arc,
@@ -13,20 +15,39 @@ use bytes::Bytes;
 use futures::future;
 use log::{debug, error, trace, warn};
 use lru_time_cache::LruCache;
-use tokio::{sync::mpsc, task::JoinHandle, time};
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time,
+};
 
 use shadowsocks::{
     lookup_then,
-    net::UdpSocket as ShadowUdpSocket,
+    net::{ConnectOpts, UdpSocket as ShadowUdpSocket},
     relay::{
         udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
         Address,
     },
 };
 
+#[cfg(feature = "local-flight-recorder")]
+use crate::local::flight_recorder::{FlightEventKind, FlightProtocol};
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
-    net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE},
+    local::{
+        context::ServiceContext,
+        loadbalancing::{AvailableServerSelector, PingBalancer, ServerIdent, ServerSelectContext, ServerType},
+    },
+    net::{
+        strip_udp_padding,
+        ConnectBackoff,
+        KeepAliveThrottle,
+        MonProxySocket,
+        UdpPaddingPolicy,
+        UdpRelaySendError,
+        UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+        UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+    },
 };
 
 /// Writer for sending packets back to client
@@ -37,6 +58,13 @@ use crate::{
 pub trait UdpInboundWrite {
     /// Sends packet `data` received from `remote_addr` back to `peer_addr`
     async fn send_to(&self, peer_addr: SocketAddr, remote_addr: &Address, data: &[u8]) -> io::Result<()>;
+
+    /// Tells `peer_addr` that its packet `data` to `target_addr` couldn't be delivered
+    ///
+    /// Packets are dropped silently by default, writers that could signal errors to clients, like ICMP, override it.
+    async fn send_unreachable(&self, _peer_addr: SocketAddr, _target_addr: &Address, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 type AssociationMap<W> = LruCache<SocketAddr, UdpAssociation<W>>;
@@ -48,6 +76,7 @@ where
 {
     respond_writer: W,
     context: Arc<ServiceContext>,
+    connect_opts: Arc<ConnectOpts>,
     assoc_map: AssociationMap<W>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     balancer: PingBalancer,
@@ -74,11 +103,13 @@ where
         };
 
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let connect_opts = Arc::new(context.connect_opts_ref().clone());
 
         (
             UdpAssociationManager {
                 respond_writer,
                 context,
+                connect_opts,
                 assoc_map,
                 keepalive_tx,
                 balancer,
@@ -88,17 +119,36 @@ where
         )
     }
 
+    /// Set `SO_MARK` of outbound sockets of new associations, overriding the one in `ConnectOpts` of the context
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_outbound_fwmark(&mut self, fwmark: Option<u32>) {
+        let mut connect_opts = self.context.connect_opts_ref().clone();
+        if fwmark.is_some() {
+            connect_opts.fwmark = fwmark;
+        }
+        self.connect_opts = Arc::new(connect_opts);
+    }
+
     /// Sends `data` from `peer_addr` to `target_addr`
     pub async fn send_to(&mut self, peer_addr: SocketAddr, target_addr: Address, data: &[u8]) -> io::Result<()> {
         // Check or (re)create an association
 
         if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
+            if !assoc.is_closed() {
+                assoc.try_send((target_addr, Bytes::copy_from_slice(data)))?;
+                return Ok(());
+            }
+
+            // Association's task is dead, the flow continues in a new association
+            warn!("udp association for {} exited unexpectedly, recreating", peer_addr);
+            self.assoc_map.remove(&peer_addr);
         }
 
         let assoc = UdpAssociation::new(
             self.context.clone(),
+            self.connect_opts.clone(),
             peer_addr,
+            self.context.udp_drain_timeout(),
             self.keepalive_tx.clone(),
             self.balancer.clone(),
             self.respond_writer.clone(),
@@ -106,6 +156,14 @@ where
 
         debug!("created udp association for {}", peer_addr);
 
+        #[cfg(feature = "local-flight-recorder")]
+        self.context.flight_recorder_ref().record(
+            FlightProtocol::Udp,
+            peer_addr,
+            &target_addr,
+            FlightEventKind::AssociationCreated,
+        );
+
         assoc.try_send((target_addr, Bytes::copy_from_slice(data)))?;
         self.assoc_map.insert(peer_addr, assoc);
 
@@ -127,8 +185,9 @@ struct UdpAssociation<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
-    assoc_handle: JoinHandle<()>,
+    assoc_handle: Option<JoinHandle<()>>,
     sender: mpsc::Sender<(Address, Bytes)>,
+    drain_timeout: Option<Duration>,
     writer: PhantomData<W>,
 }
 
@@ -137,7 +196,22 @@ where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
     fn drop(&mut self) {
-        self.assoc_handle.abort();
+        let mut assoc_handle = match self.assoc_handle.take() {
+            Some(h) => h,
+            None => return,
+        };
+
+        // Dropping `sender` closes the channel, the association sends the queued packets and then exits by itself
+        if let (Some(timeout), Ok(runtime)) = (self.drain_timeout, Handle::try_current()) {
+            runtime.spawn(async move {
+                if time::timeout(timeout, &mut assoc_handle).await.is_err() {
+                    debug!("udp association draining timed out after {:?}", timeout);
+                    assoc_handle.abort();
+                }
+            });
+        } else {
+            assoc_handle.abort();
+        }
     }
 }
 
@@ -147,26 +221,72 @@ where
 {
     fn new(
         context: Arc<ServiceContext>,
+        connect_opts: Arc<ConnectOpts>,
         peer_addr: SocketAddr,
+        drain_timeout: Option<Duration>,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
         respond_writer: W,
     ) -> UdpAssociation<W> {
         let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, peer_addr, keepalive_tx, balancer, respond_writer);
+            UdpAssociationContext::create(context, connect_opts, peer_addr, keepalive_tx, balancer, respond_writer);
         UdpAssociation {
-            assoc_handle,
+            assoc_handle: Some(assoc_handle),
             sender,
+            drain_timeout,
             writer: PhantomData,
         }
     }
 
-    fn try_send(&self, data: (Address, Bytes)) -> io::Result<()> {
-        if let Err(..) = self.sender.try_send(data) {
-            let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
-            return Err(err);
+    /// Check if the association's task has exited
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    fn try_send(&self, data: (Address, Bytes)) -> Result<(), UdpRelaySendError> {
+        match self.sender.try_send(data) {
+            Ok(..) => Ok(()),
+            Err(TrySendError::Full(..)) => Err(UdpRelaySendError::ChannelFull),
+            Err(TrySendError::Closed(..)) => Err(UdpRelaySendError::ChannelClosed),
         }
-        Ok(())
+    }
+}
+
+/// Number of delivered responses remembered for dropping their duplicates from the other hedged server
+const HEDGED_RESPONSES_CAPACITY: usize = 64;
+
+/// Deduplicates responses of the same request received from both hedged servers
+#[derive(Default)]
+struct HedgedResponses {
+    // Responses delivered from one server, waiting for their duplicates from the other one
+    primary: VecDeque<u64>,
+    hedged: VecDeque<u64>,
+}
+
+impl HedgedResponses {
+    /// Returns `true` if the response is the first one and should be sent back to the client
+    fn deliver(&mut self, addr: &Address, data: &[u8], from_hedged: bool) -> bool {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let (delivered, other_delivered) = if from_hedged {
+            (&mut self.hedged, &mut self.primary)
+        } else {
+            (&mut self.primary, &mut self.hedged)
+        };
+
+        if let Some(pos) = other_delivered.iter().position(|h| *h == hash) {
+            other_delivered.remove(pos);
+            return false;
+        }
+
+        if delivered.len() >= HEDGED_RESPONSES_CAPACITY {
+            delivered.pop_front();
+        }
+        delivered.push_back(hash);
+        true
     }
 }
 
@@ -175,14 +295,26 @@ where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
     context: Arc<ServiceContext>,
+    // Options of outbound sockets, the context's may be overridden by the manager
+    connect_opts: Arc<ConnectOpts>,
     peer_addr: SocketAddr,
     bypassed_ipv4_socket: Option<ShadowUdpSocket>,
     bypassed_ipv6_socket: Option<ShadowUdpSocket>,
     proxied_socket: Option<MonProxySocket>,
+    // Suppresses reconnecting to servers after failures
+    proxied_backoff: ConnectBackoff,
+    // Socket to the second best server, only when sending hedged packets
+    hedged_socket: Option<MonProxySocket>,
+    hedged_responses: Option<HedgedResponses>,
+    // Proxied packets are padded, and so are their responses
+    padding: Option<UdpPaddingPolicy>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
-    keepalive_flag: bool,
+    keepalive_throttle: KeepAliveThrottle,
     balancer: PingBalancer,
     respond_writer: W,
+    // Latest target and bytes relayed from and to client
+    #[cfg(feature = "local-flight-recorder")]
+    relayed: Option<(Address, u64, u64)>,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -191,6 +323,23 @@ where
 {
     fn drop(&mut self) {
         debug!("udp association for {} is closed", self.peer_addr);
+
+        #[cfg(feature = "local-flight-recorder")]
+        if let Some((ref target_addr, tx, rx)) = self.relayed {
+            let recorder = self.context.flight_recorder_ref();
+            recorder.record(
+                FlightProtocol::Udp,
+                self.peer_addr,
+                target_addr,
+                FlightEventKind::Relayed { tx, rx },
+            );
+            recorder.record(
+                FlightProtocol::Udp,
+                self.peer_addr,
+                target_addr,
+                FlightEventKind::Closed,
+            );
+        }
     }
 }
 
@@ -200,6 +349,7 @@ where
 {
     fn create(
         context: Arc<ServiceContext>,
+        connect_opts: Arc<ConnectOpts>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
         balancer: PingBalancer,
@@ -210,16 +360,31 @@ where
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let hedged_responses = if context.udp_hedged_send() {
+            Some(HedgedResponses::default())
+        } else {
+            None
+        };
+
+        let proxied_backoff = context.udp_reconnect_backoff();
+        let padding = context.udp_padding();
         let mut assoc = UdpAssociationContext {
             context,
+            connect_opts,
             peer_addr,
             bypassed_ipv4_socket: None,
             bypassed_ipv6_socket: None,
             proxied_socket: None,
+            proxied_backoff,
+            hedged_socket: None,
+            hedged_responses,
+            padding,
             keepalive_tx,
-            keepalive_flag: false,
+            keepalive_throttle: KeepAliveThrottle::default(),
             balancer,
             respond_writer,
+            #[cfg(feature = "local-flight-recorder")]
+            relayed: None,
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
@@ -230,7 +395,7 @@ where
         let mut bypassed_ipv4_buffer = Vec::new();
         let mut bypassed_ipv6_buffer = Vec::new();
         let mut proxied_buffer = Vec::new();
-        let mut keepalive_interval = time::interval(Duration::from_secs(1));
+        let mut hedged_buffer = Vec::new();
 
         loop {
             tokio::select! {
@@ -287,16 +452,38 @@ where
                         }
                     };
 
-                    self.send_received_respond_packet(&addr, &proxied_buffer[..n], false).await;
+                    let data = match self.unpad_response(&proxied_buffer[..n]) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("udp relay {} <- {} (proxied) failed, error: {}", self.peer_addr, addr, err);
+                            continue;
+                        }
+                    };
+                    if self.check_hedged_response(&addr, data, false) {
+                        self.send_received_respond_packet(&addr, data, false).await;
+                    }
                 }
 
-                _ = keepalive_interval.tick() => {
-                    if self.keepalive_flag {
-                        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
-                            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
-                        } else {
-                            self.keepalive_flag = false;
+                received_opt = receive_from_proxied_opt(&self.hedged_socket, &mut hedged_buffer) => {
+                    let (n, addr) = match received_opt {
+                        Ok(r) => r,
+                        Err(err) => {
+                            error!("udp relay {} <- ... (hedged) failed, error: {}", self.peer_addr, err);
+                            // Socket failure. Only the primary server is used until it is recreated.
+                            self.hedged_socket = None;
+                            continue;
                         }
+                    };
+
+                    let data = match self.unpad_response(&hedged_buffer[..n]) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("udp relay {} <- {} (hedged) failed, error: {}", self.peer_addr, addr, err);
+                            continue;
+                        }
+                    };
+                    if self.check_hedged_response(&addr, data, true) {
+                        self.send_received_respond_packet(&addr, data, false).await;
                     }
                 }
             }
@@ -347,29 +534,76 @@ where
             data.len()
         );
 
+        #[cfg(feature = "local-flight-recorder")]
+        match self.relayed {
+            Some((ref mut addr, ref mut tx, ..)) => {
+                if addr != target_addr {
+                    *addr = target_addr.clone();
+                }
+                *tx += data.len() as u64;
+            }
+            None => self.relayed = Some((target_addr.clone(), data.len() as u64, 0)),
+        }
+
         if bypassed {
             if let Err(err) = self.dispatch_received_bypassed_packet(target_addr, data).await {
-                error!(
-                    "udp relay {} -> {} (bypassed) with {} bytes, error: {}",
-                    self.peer_addr,
-                    target_addr,
-                    data.len(),
-                    err
-                );
+                if self.context.conn_error_log_throttle().should_log(target_addr) {
+                    error!(
+                        "udp relay {} -> {} (bypassed) with {} bytes, error: {}",
+                        self.peer_addr,
+                        target_addr,
+                        data.len(),
+                        err
+                    );
+                }
+                #[cfg(feature = "local-flight-recorder")]
+                self.record_reset(target_addr, &err);
+                self.send_unreachable(target_addr, data).await;
             }
         } else {
             if let Err(err) = self.dispatch_received_proxied_packet(target_addr, data).await {
-                error!(
-                    "udp relay {} -> {} (proxied) with {} bytes, error: {}",
-                    self.peer_addr,
-                    target_addr,
-                    data.len(),
-                    err
-                );
+                if self.context.conn_error_log_throttle().should_log(target_addr) {
+                    error!(
+                        "udp relay {} -> {} (proxied) with {} bytes, error: {}",
+                        self.peer_addr,
+                        target_addr,
+                        data.len(),
+                        err
+                    );
+                }
+                #[cfg(feature = "local-flight-recorder")]
+                self.record_reset(target_addr, &err);
+                self.send_unreachable(target_addr, data).await;
             }
         }
     }
 
+    /// Tell client that `data` to `target_addr` was dropped because the target or server couldn't be reached
+    async fn send_unreachable(&self, target_addr: &Address, data: &[u8]) {
+        if let Err(err) = self
+            .respond_writer
+            .send_unreachable(self.peer_addr, target_addr, data)
+            .await
+        {
+            debug!(
+                "udp failed to send unreachable to client {}, target {}, error: {}",
+                self.peer_addr, target_addr, err
+            );
+        }
+    }
+
+    #[cfg(feature = "local-flight-recorder")]
+    fn record_reset(&self, target_addr: &Address, err: &io::Error) {
+        let recorder = self.context.flight_recorder_ref();
+        recorder.record(
+            FlightProtocol::Udp,
+            self.peer_addr,
+            target_addr,
+            FlightEventKind::Reset { error: err.to_string() },
+        );
+        recorder.dump_flow(FlightProtocol::Udp, self.peer_addr);
+    }
+
     async fn dispatch_received_bypassed_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
         match *target_addr {
             Address::SocketAddress(sa) => self.send_received_bypassed_packet(sa, data).await,
@@ -387,16 +621,14 @@ where
             SocketAddr::V4(..) => match self.bypassed_ipv4_socket {
                 Some(ref mut socket) => socket,
                 None => {
-                    let socket =
-                        ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    let socket = ShadowUdpSocket::connect_any_with_opts(&target_addr, &self.connect_opts).await?;
                     self.bypassed_ipv4_socket.insert(socket)
                 }
             },
             SocketAddr::V6(..) => match self.bypassed_ipv6_socket {
                 Some(ref mut socket) => socket,
                 None => {
-                    let socket =
-                        ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref()).await?;
+                    let socket = ShadowUdpSocket::connect_any_with_opts(&target_addr, &self.connect_opts).await?;
                     self.bypassed_ipv6_socket.insert(socket)
                 }
             },
@@ -420,20 +652,87 @@ where
         let socket = match self.proxied_socket {
             Some(ref mut socket) => socket,
             None => {
-                // Create a new connection to proxy server
+                // Create a new connection to proxy server, packets are dropped while backing off from failures
+                if !self.proxied_backoff.should_attempt() {
+                    trace!(
+                        "udp relay {} -> {} (proxied) dropped {} bytes, reconnecting in {:?}",
+                        self.peer_addr,
+                        target_addr,
+                        data.len(),
+                        self.proxied_backoff.delay().unwrap_or_default()
+                    );
+                    return Ok(());
+                }
 
-                let server = self.balancer.best_udp_server();
+                let cx = ServerSelectContext::new(ServerType::Udp, self.peer_addr, target_addr);
+                let server = match self.balancer.select_server(&cx, &AvailableServerSelector)? {
+                    Some(server) => server,
+                    None => {
+                        // All servers are unavailable, send to target directly if it's bypassed by ACL
+                        self.balancer
+                            .check_unavailable_bypass(&self.context, ServerType::Udp, target_addr)
+                            .await?;
+                        return self.dispatch_received_bypassed_packet(target_addr, data).await;
+                    }
+                };
                 let svr_cfg = server.server_config();
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+                let socket = ProxySocket::connect_with_opts(self.context.context(), svr_cfg, &self.connect_opts).await;
+                server.udp_score().report_connect(socket.is_ok());
+                let mut socket = match socket {
+                    Ok(socket) => {
+                        self.proxied_backoff.succeeded();
+                        MonProxySocket::from_socket(socket, self.context.flow_stat())
+                    }
+                    Err(err) => {
+                        self.proxied_backoff.failed();
+                        return Err(err);
+                    }
+                };
+
+                #[cfg(feature = "local-flight-recorder")]
+                self.context.flight_recorder_ref().record(
+                    FlightProtocol::Udp,
+                    self.peer_addr,
+                    target_addr,
+                    FlightEventKind::Connected {
+                        server: Some(svr_cfg.addr().clone()),
+                    },
+                );
+
+                socket.set_padded(self.padding.is_some());
+
+                if self.hedged_responses.is_some() {
+                    self.hedged_socket = self.connect_hedged_socket(&server).await;
+                }
 
                 self.proxied_socket.insert(socket)
             }
         };
 
+        let padded;
+        let data = match self.padding {
+            None => data,
+            Some(ref padding) => {
+                padded = padding.pad(data)?;
+                &padded[..]
+            }
+        };
+
+        // Hedged copy is only the best effort, failures are not reported to the client
+        if let Some(ref hedged_socket) = self.hedged_socket {
+            if let Err(err) = hedged_socket.send(target_addr, data).await {
+                debug!(
+                    "{} -> {} (hedged) sending {} bytes failed, error: {}",
+                    self.peer_addr,
+                    target_addr,
+                    data.len(),
+                    err
+                );
+                self.hedged_socket = None;
+            }
+        }
+
         match socket.send(target_addr, data).await {
             Ok(..) => return Ok(()),
             Err(err) => {
@@ -447,12 +746,77 @@ where
 
                 // Drop the socket and reconnect to another server.
                 self.proxied_socket = None;
+                self.hedged_socket = None;
             }
         }
 
         Ok(())
     }
 
+    async fn connect_hedged_socket(&self, primary: &ServerIdent) -> Option<MonProxySocket> {
+        let server = self.balancer.hedge_udp_server(primary)?;
+        let svr_cfg = server.server_config();
+
+        let socket = ProxySocket::connect_with_opts(self.context.context(), svr_cfg, &self.connect_opts).await;
+        server.udp_score().report_connect(socket.is_ok());
+        match socket {
+            Ok(socket) => {
+                let mut socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+                socket.set_padded(self.padding.is_some());
+                Some(socket)
+            }
+            Err(err) => {
+                warn!(
+                    "udp relay {} failed to connect hedged server {}, error: {}",
+                    self.peer_addr,
+                    svr_cfg.addr(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Strip padding of a proxied response if packets are padded
+    fn unpad_response<'a>(&self, data: &'a [u8]) -> io::Result<&'a [u8]> {
+        match self.padding {
+            Some(..) => strip_udp_padding(data),
+            None => Ok(data),
+        }
+    }
+
+    /// Check if a proxied response should be sent back to client, duplicates from the other hedged server are dropped
+    fn check_hedged_response(&mut self, addr: &Address, data: &[u8], from_hedged: bool) -> bool {
+        match self.hedged_responses {
+            None => true,
+            Some(ref mut responses) => {
+                let first = responses.deliver(addr, data, from_hedged);
+                if !first {
+                    trace!(
+                        "udp relay {} <- {} dropped duplicated response with {} bytes",
+                        self.peer_addr,
+                        addr,
+                        data.len()
+                    );
+                }
+                first
+            }
+        }
+    }
+
+    fn keep_alive(&mut self) {
+        // Keep association alive in map, refreshes are coalesced to avoid flooding the manager
+        if !self.keepalive_throttle.should_refresh() {
+            return;
+        }
+
+        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
+            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
+        } else {
+            self.keepalive_throttle.refreshed();
+        }
+    }
+
     async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8], bypassed: bool) {
         trace!(
             "udp relay {} <- {} ({}) received {} bytes",
@@ -462,8 +826,12 @@ where
             data.len(),
         );
 
-        // Keep association alive in map
-        self.keepalive_flag = true;
+        self.keep_alive();
+
+        #[cfg(feature = "local-flight-recorder")]
+        if let Some((.., ref mut rx)) = self.relayed {
+            *rx += data.len() as u64;
+        }
 
         // Send back to client
         if let Err(err) = self.respond_writer.send_to(self.peer_addr, addr, data).await {
@@ -486,3 +854,290 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use shadowsocks::{
+        config::Mode,
+        context::Context,
+        crypto::v1::CipherKind,
+        dns_resolver::{DnsResolve, DnsResolver},
+        net::AcceptOpts,
+        ServerAddr,
+        ServerConfig,
+    };
+    use tokio::net::UdpSocket;
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct DiscardWriter;
+
+    #[async_trait]
+    impl UdpInboundWrite for DiscardWriter {
+        async fn send_to(&self, _peer_addr: SocketAddr, _remote_addr: &Address, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Resolver of a flapping server, the first `failures` lookups fail
+    struct FlappingResolver {
+        addr: SocketAddr,
+        failures: usize,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl DnsResolve for FlappingResolver {
+        async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            if self.lookups.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(io::Error::new(io::ErrorKind::Other, "server is down"));
+            }
+            Ok(vec![self.addr])
+        }
+    }
+
+    #[derive(Clone)]
+    struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+    #[async_trait]
+    impl UdpInboundWrite for ChannelWriter {
+        async fn send_to(&self, _peer_addr: SocketAddr, _remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+            let _ = self.0.send(data.to_vec()).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn evicted_association_drains_queue() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_udp_drain_timeout(Duration::from_secs(1));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            server_socket.local_addr().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let (mut manager, ..) = UdpAssociationManager::new(context, DiscardWriter, None, Some(1), balancer);
+
+        // Packets are only queued, the association's task hasn't run yet
+        const PACKETS: usize = 5;
+        let target_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 53));
+        let first_peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        for _ in 0..PACKETS {
+            manager
+                .send_to(first_peer, target_addr.clone(), b"queued")
+                .await
+                .unwrap();
+        }
+
+        // Evicts the first association
+        let second_peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001);
+        manager.send_to(second_peer, target_addr, b"evict").await.unwrap();
+
+        let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for _ in 0..PACKETS + 1 {
+            time::timeout(Duration::from_secs(1), server_socket.recv_from(&mut buf))
+                .await
+                .expect("queued packets are not sent")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn hedged_send_uses_first_response() {
+        let target_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 53));
+
+        let mut context = ServiceContext::new();
+        context.set_udp_hedged_send(true);
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        // Probes of the balancer are never answered
+        builder.max_server_rtt(Duration::from_millis(100));
+
+        // Servers respond with the same payload, the slow one after the fast one
+        let (received_tx, mut received_rx) = mpsc::channel(2);
+        for delay in [Duration::ZERO, Duration::from_millis(300)] {
+            let svr_cfg = ServerConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                "password",
+                CipherKind::AES_256_GCM,
+            );
+            let server_context = Context::new_shared(shadowsocks::config::ServerType::Server);
+            let socket = ProxySocket::bind_with_opts(server_context, &svr_cfg, AcceptOpts::default())
+                .await
+                .unwrap();
+            builder.add_server(ServerConfig::new(
+                socket.local_addr().unwrap(),
+                "password",
+                CipherKind::AES_256_GCM,
+            ));
+
+            let received_tx = received_tx.clone();
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+                while let Ok((_, peer_addr, addr, _)) = socket.recv_from(&mut buf).await {
+                    // Ignore probes of the balancer
+                    if addr != target_addr {
+                        continue;
+                    }
+                    let _ = received_tx.send(delay).await;
+                    time::sleep(delay).await;
+                    let _ = socket.send_to(peer_addr, &addr, b"response").await;
+                }
+            });
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let (writer_tx, mut writer_rx) = mpsc::channel(2);
+        let (mut manager, ..) = UdpAssociationManager::new(context, ChannelWriter(writer_tx), None, None, balancer);
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        manager.send_to(peer_addr, target_addr, b"request").await.unwrap();
+
+        for _ in 0..2 {
+            time::timeout(Duration::from_secs(1), received_rx.recv())
+                .await
+                .expect("packet is not sent to both servers")
+                .unwrap();
+        }
+
+        let response = time::timeout(Duration::from_millis(200), writer_rx.recv())
+            .await
+            .expect("first response is not used")
+            .unwrap();
+        assert_eq!(response, b"response");
+
+        // Duplicate from the slow server is dropped
+        assert!(time::timeout(Duration::from_millis(500), writer_rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_backoff_after_failures() {
+        const FAILURES: usize = 3;
+
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = FlappingResolver {
+            addr: server_socket.local_addr().unwrap(),
+            failures: FAILURES,
+            lookups: lookups.clone(),
+        };
+
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(resolver)));
+        context.set_udp_reconnect_backoff(Duration::from_millis(20), Duration::from_millis(100));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("flapping.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let (mut manager, ..) = UdpAssociationManager::new(context, DiscardWriter, None, None, balancer);
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let target_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 53));
+
+        // Client keeps sending until the association recovers, retried at 20ms, 60ms and 140ms
+        let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut sent = 0;
+        let recovered = time::timeout(Duration::from_secs(1), async {
+            loop {
+                manager
+                    .send_to(peer_addr, target_addr.clone(), b"request")
+                    .await
+                    .unwrap();
+                sent += 1;
+                if let Ok(r) = time::timeout(Duration::from_millis(5), server_socket.recv_from(&mut buf)).await {
+                    break r;
+                }
+            }
+        })
+        .await
+        .expect("association is not recovered");
+        recovered.unwrap();
+
+        assert_eq!(lookups.load(Ordering::Relaxed), FAILURES + 1);
+        assert!(sent > 2 * (FAILURES + 1));
+    }
+
+    #[tokio::test]
+    async fn padded_round_trip() {
+        let target_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 53));
+
+        let mut context = ServiceContext::new();
+        context.set_udp_padding(Some(UdpPaddingPolicy::Fixed(256)));
+        let context = Arc::new(context);
+
+        // A server padding its responses too
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        let server_context = Context::new_shared(shadowsocks::config::ServerType::Server);
+        let socket = ProxySocket::bind_with_opts(server_context, &svr_cfg, AcceptOpts::default())
+            .await
+            .unwrap();
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            socket.local_addr().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let (received_tx, mut received_rx) = mpsc::channel(1);
+        {
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+                while let Ok((n, peer_addr, addr, _)) = socket.recv_from(&mut buf).await {
+                    // Ignore probes of the balancer
+                    if addr != target_addr {
+                        continue;
+                    }
+                    let _ = received_tx.send(buf[..n].to_vec()).await;
+                    let response = UdpPaddingPolicy::Fixed(256).pad(b"response").unwrap();
+                    let _ = socket.send_to(peer_addr, &addr, &response).await;
+                }
+            });
+        }
+
+        let (writer_tx, mut writer_rx) = mpsc::channel(1);
+        let (mut manager, ..) = UdpAssociationManager::new(context, ChannelWriter(writer_tx), None, None, balancer);
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        manager.send_to(peer_addr, target_addr, b"request").await.unwrap();
+
+        // Padded to the server, stripped before sending back to the client
+        let received = time::timeout(Duration::from_secs(1), received_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.len(), 256);
+        assert_eq!(strip_udp_padding(&received).unwrap(), b"request");
+        let response = time::timeout(Duration::from_secs(1), writer_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, b"response");
+    }
+}
@@ -21,7 +21,7 @@ use crate::{
         loadbalancing::PingBalancer,
         net::AutoProxyClientStream,
         redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
-        utils::{establish_tcp_tunnel, to_ipv4_mapped},
+        utils::{establish_tcp_tunnel, to_ipv4_mapped, TcpTunnelSummary},
     },
 };
 
@@ -36,13 +36,10 @@ async fn establish_client_tcp_redir<'a>(
     mut stream: TcpStream,
     peer_addr: SocketAddr,
     addr: &Address,
-) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
-
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
+) -> io::Result<TcpTunnelSummary> {
+    let (server, mut remote) = AutoProxyClientStream::connect_balanced(context, &balancer, peer_addr, addr).await?;
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    establish_tcp_tunnel(&server, &mut stream, &mut remote, peer_addr, addr, None).await
 }
 
 async fn handle_redir_client(
@@ -51,7 +48,7 @@ async fn handle_redir_client(
     s: TcpStream,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
-) -> io::Result<()> {
+) -> io::Result<TcpTunnelSummary> {
     // Get forward address from socket
     //
     // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
@@ -115,8 +112,24 @@ pub async fn run_tcp_redir(
                 }
             };
 
-            if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
-                debug!("TCP redirect client, error: {:?}", err);
+            match handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
+                Ok(summary) => {
+                    debug!(
+                        "TCP redirect {} <-> {} closed after {:?}, server: {:?} ({}), tx {} bytes ({} on wire), rx {} bytes ({} on wire)",
+                        peer_addr,
+                        dst_addr,
+                        summary.duration,
+                        summary.server,
+                        summary.remote_family(),
+                        summary.tx,
+                        summary.wire_tx,
+                        summary.rx,
+                        summary.wire_rx
+                    );
+                }
+                Err(err) => {
+                    debug!("TCP redirect client, error: {:?}", err);
+                }
             }
         });
     }
@@ -95,19 +95,24 @@ impl Socks4TcpHandler {
             return Ok(());
         }
 
-        let server = self.balancer.best_tcp_server();
-        let svr_cfg = server.server_config();
         let target_addr = target_addr.into();
 
-        let mut remote = match AutoProxyClientStream::connect(self.context, &server, &target_addr).await {
-            Ok(remote) => {
+        let (server, mut remote) = match AutoProxyClientStream::connect_balanced(
+            self.context,
+            &self.balancer,
+            peer_addr,
+            &target_addr,
+        )
+        .await
+        {
+            Ok((server, remote)) => {
                 // Tell the client that we are ready
                 let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
                 handshake_rsp.write_to(&mut stream).await?;
 
                 trace!("sent header: {:?}", handshake_rsp);
 
-                remote
+                (server, remote)
             }
             Err(err) => {
                 let result_code = match err.kind() {
@@ -132,6 +137,8 @@ impl Socks4TcpHandler {
         // UNWRAP.
         let mut stream = stream.into_inner();
 
-        establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+        establish_tcp_tunnel(&server, &mut stream, &mut remote, peer_addr, &target_addr, None)
+            .await
+            .map(|_| ())
     }
 }
@@ -101,7 +101,7 @@ impl Socks5TcpHandler {
 
         Err(Error::new(
             ErrorKind::Other,
-            "currently shadowsocks-rust does not support authentication",
+            "no acceptable socks5 authentication method",
         ))
     }
 
@@ -153,9 +153,8 @@ impl Socks5TcpHandler {
 
         if self.auth.passwd.check_user(user_name, password) {
             trace!(
-                "socks5 authenticated with Username/Password method, user: {}, password: {}",
-                user_name,
-                password
+                "socks5 authenticated with Username/Password method, user: {}",
+                user_name
             );
 
             let rsp = PasswdAuthResponse::new(0);
@@ -166,17 +165,12 @@ impl Socks5TcpHandler {
             let rsp = PasswdAuthResponse::new(PASSWORD_AUTH_STATUS_FAILURE);
             rsp.write_to(stream).await?;
 
-            error!(
-                "socks5 rejected Username/Password user: {}, password: {}",
-                user_name, password
-            );
+            // Passwords are never logged, they may be one typo away from the real ones
+            error!("socks5 rejected Username/Password user: {}", user_name);
 
             Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Username/Password Authentication failed, user: {}, password: {}",
-                    user_name, password
-                ),
+                ErrorKind::PermissionDenied,
+                format!("Username/Password Authentication failed, user: {}", user_name),
             ))
         }
     }
@@ -251,11 +245,15 @@ impl Socks5TcpHandler {
             return Ok(());
         }
 
-        let server = self.balancer.best_tcp_server();
-        let svr_cfg = server.server_config();
-
-        let mut remote = match AutoProxyClientStream::connect(self.context.clone(), &server, &target_addr).await {
-            Ok(remote) => {
+        let (server, mut remote) = match AutoProxyClientStream::connect_balanced(
+            self.context.clone(),
+            &self.balancer,
+            peer_addr,
+            &target_addr,
+        )
+        .await
+        {
+            Ok((server, remote)) => {
                 // Tell the client that we are ready
                 let header =
                     TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(remote.local_addr()?));
@@ -263,7 +261,7 @@ impl Socks5TcpHandler {
 
                 trace!("sent header: {:?}", header);
 
-                remote
+                (server, remote)
             }
             Err(err) => {
                 let reply = match err.kind() {
@@ -280,7 +278,9 @@ impl Socks5TcpHandler {
             }
         };
 
-        establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+        establish_tcp_tunnel(&server, &mut stream, &mut remote, peer_addr, &target_addr, None)
+            .await
+            .map(|_| ())
     }
 
     async fn handle_udp_associate(self, mut stream: TcpStream, client_addr: Address) -> io::Result<()> {
@@ -307,3 +307,75 @@ impl Socks5TcpHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{crypto::v1::CipherKind, ServerConfig};
+    use tokio::net::TcpListener;
+
+    use crate::local::{loadbalancing::PingBalancerBuilder, socks::config::Socks5AuthPasswdConfig};
+
+    use super::*;
+
+    async fn start_server() -> SocketAddr {
+        let context = Arc::new(ServiceContext::new());
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8388),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let mut passwd = Socks5AuthPasswdConfig::new();
+        passwd.add_user("alice", "correct horse");
+        let auth = Arc::new(Socks5AuthConfig { passwd });
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                let handler =
+                    Socks5TcpHandler::new(context.clone(), None, balancer.clone(), Mode::TcpOnly, auth.clone());
+                tokio::spawn(handler.handle_socks5_client(stream, peer_addr));
+            }
+        });
+        listen_addr
+    }
+
+    async fn password_auth(server_addr: SocketAddr, user_name: &str, password: &str) -> u8 {
+        let mut stream = TcpStream::connect(server_addr).await.unwrap();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_PASSWORD])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let resp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(resp.chosen_method, socks5::SOCKS5_AUTH_METHOD_PASSWORD);
+
+        PasswdAuthRequest::new(user_name, password)
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        PasswdAuthResponse::read_from(&mut stream).await.unwrap().status
+    }
+
+    #[tokio::test]
+    async fn password_auth_required() {
+        let server_addr = start_server().await;
+
+        assert_eq!(password_auth(server_addr, "alice", "correct horse").await, 0);
+        assert_ne!(password_auth(server_addr, "alice", "battery staple").await, 0);
+        assert_ne!(password_auth(server_addr, "bob", "correct horse").await, 0);
+
+        // Clients without credentials are rejected
+        let mut stream = TcpStream::connect(server_addr).await.unwrap();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let resp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(resp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+    }
+}
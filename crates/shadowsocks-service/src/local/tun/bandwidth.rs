@@ -0,0 +1,142 @@
+//! Bandwidth limits of TCP connections
+//!
+//! The manager moves data between smoltcp's sockets and connections' buffers at most as fast as token buckets of
+//! bytes refill. Throttled data waits in the buffers, so clients are slowed down by the windows advertised to them,
+//! and remotes by the relay's back-pressure.
+
+use std::time::{Duration, Instant};
+
+/// Smallest amount of bytes worth waking up the manager for, unless the burst is smaller
+const MIN_REFILL_BYTES: u64 = 1024;
+
+/// Direction of data limited by `TcpBandwidthLimit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthDirection {
+    /// From clients to remotes
+    Recv,
+    /// From remotes to clients
+    Send,
+}
+
+/// Token bucket of bytes
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `rate` bytes per second, with bursts of at most `burst` bytes. The bucket starts full
+    pub fn new(rate: u64, burst: u64) -> RateLimiter {
+        let burst = burst.max(1);
+        RateLimiter {
+            rate: rate.max(1),
+            burst,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Number of bytes that could be moved now
+    pub fn available(&mut self, now: Instant) -> usize {
+        if now > self.last_refill {
+            let elapsed = now - self.last_refill;
+            self.tokens = (self.tokens + self.rate as f64 * elapsed.as_secs_f64()).min(self.burst as f64);
+            self.last_refill = now;
+        }
+        self.tokens as usize
+    }
+
+    /// `n` bytes are moved, at most what was `available`
+    pub fn consume(&mut self, n: usize) {
+        self.tokens = (self.tokens - n as f64).max(0.0);
+    }
+
+    /// Time until enough bytes could be moved again
+    pub fn refill_delay(&self) -> Duration {
+        let wanted = self.burst.min(MIN_REFILL_BYTES) as f64;
+        if self.tokens >= wanted {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((wanted - self.tokens) / self.rate as f64)
+    }
+}
+
+/// Token buckets of both directions of TCP connections, of a connection or shared by all of them
+#[derive(Debug, Clone)]
+pub struct TcpBandwidthLimit {
+    recv: RateLimiter,
+    send: RateLimiter,
+}
+
+impl TcpBandwidthLimit {
+    /// Allow `rate` bytes per second in each direction, with bursts of at most `burst` bytes
+    pub fn new(rate: u64, burst: u64) -> TcpBandwidthLimit {
+        TcpBandwidthLimit {
+            recv: RateLimiter::new(rate, burst),
+            send: RateLimiter::new(rate, burst),
+        }
+    }
+
+    fn limiter(&self, direction: BandwidthDirection) -> &RateLimiter {
+        match direction {
+            BandwidthDirection::Recv => &self.recv,
+            BandwidthDirection::Send => &self.send,
+        }
+    }
+
+    fn limiter_mut(&mut self, direction: BandwidthDirection) -> &mut RateLimiter {
+        match direction {
+            BandwidthDirection::Recv => &mut self.recv,
+            BandwidthDirection::Send => &mut self.send,
+        }
+    }
+
+    /// Number of bytes that could be moved in `direction` now
+    pub fn available(&mut self, direction: BandwidthDirection, now: Instant) -> usize {
+        self.limiter_mut(direction).available(now)
+    }
+
+    /// `n` bytes are moved in `direction`
+    pub fn consume(&mut self, direction: BandwidthDirection, n: usize) {
+        self.limiter_mut(direction).consume(n);
+    }
+
+    /// Time until enough bytes could be moved in `direction` again
+    pub fn refill_delay(&self, direction: BandwidthDirection) -> Duration {
+        self.limiter(direction).refill_delay()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limited_to_rate() {
+        let mut limiter = RateLimiter::new(1000, 500);
+        let start = Instant::now();
+        assert_eq!(limiter.available(start), 500);
+        limiter.consume(500);
+        assert_eq!(limiter.available(start), 0);
+        assert_eq!(limiter.refill_delay(), Duration::from_millis(500));
+
+        // Refilled at the rate, up to the burst
+        assert_eq!(limiter.available(start + Duration::from_millis(200)), 200);
+        limiter.consume(150);
+        assert_eq!(limiter.available(start + Duration::from_secs(10)), 500);
+    }
+
+    #[test]
+    fn directions_limited_separately() {
+        let mut limit = TcpBandwidthLimit::new(1000, 1000);
+        let now = Instant::now();
+        limit.consume(BandwidthDirection::Recv, 1000);
+        assert_eq!(limit.available(BandwidthDirection::Recv, now), 0);
+        assert!(limit.refill_delay(BandwidthDirection::Recv) > Duration::ZERO);
+        assert_eq!(limit.available(BandwidthDirection::Send, now), 1000);
+        assert_eq!(limit.refill_delay(BandwidthDirection::Send), Duration::ZERO);
+    }
+}
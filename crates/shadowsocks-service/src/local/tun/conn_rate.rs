@@ -0,0 +1,154 @@
+//! Rate limit of new TCP connections by source IP
+//!
+//! Aggressive scanners behind the TUN open connections to every port they could reach, each of them creates a
+//! socket and a relay task. A token bucket of each source IP bounds how fast it could open new connections, SYNs
+//! over the rate are rejected with RST.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// Sources kept before full buckets are pruned, full buckets are the same as new ones
+const PRUNE_SOURCES_THRESHOLD: usize = 1024;
+
+struct SourceBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Counters of `TcpConnRateLimit`, could be read while `Tun` is running
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnRateStat {
+    rejected: Arc<AtomicU64>,
+}
+
+impl TcpConnRateStat {
+    /// Number of SYNs rejected because their sources were over the rate
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Token buckets of new connections of each source IP
+pub struct TcpConnRateLimit {
+    rate: u32,
+    burst: u32,
+    buckets: HashMap<IpAddr, SourceBucket>,
+    stat: TcpConnRateStat,
+}
+
+impl TcpConnRateLimit {
+    /// Allow `rate` new connections per second from each source IP, with bursts of at most `burst` connections
+    pub fn new(rate: u32, burst: u32) -> TcpConnRateLimit {
+        TcpConnRateLimit {
+            rate,
+            burst: burst.max(1),
+            buckets: HashMap::new(),
+            stat: TcpConnRateStat::default(),
+        }
+    }
+
+    /// Counters of rejected connections
+    pub fn stat(&self) -> TcpConnRateStat {
+        self.stat.clone()
+    }
+
+    /// Check if `source` could open a new connection now. Rejections are counted.
+    pub fn check(&mut self, source: IpAddr) -> bool {
+        self.check_at(source, Instant::now())
+    }
+
+    fn check_at(&mut self, source: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= PRUNE_SOURCES_THRESHOLD && !self.buckets.contains_key(&source) {
+            self.prune(now);
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(source).or_insert_with(|| SourceBucket {
+            tokens: burst as f64,
+            last_refill: now,
+        });
+        refill(bucket, rate, burst, now);
+
+        if bucket.tokens < 1.0 {
+            self.stat.rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Remove buckets that are refilled to full
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            refill(bucket, rate, burst, now);
+            bucket.tokens < burst as f64
+        });
+    }
+}
+
+fn refill(bucket: &mut SourceBucket, rate: u32, burst: u32, now: Instant) {
+    if now <= bucket.last_refill {
+        return;
+    }
+
+    let elapsed = now - bucket.last_refill;
+    bucket.tokens = (bucket.tokens + rate as f64 * elapsed.as_secs_f64()).min(burst as f64);
+    bucket.last_refill = now;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn over_rate_rejected() {
+        let mut limit = TcpConnRateLimit::new(10, 5);
+        let stat = limit.stat();
+        let scanner = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        let client = IpAddr::from(Ipv4Addr::new(10, 0, 0, 3));
+        let start = Instant::now();
+
+        // Burst is allowed, excess SYNs are rejected
+        let accepted = (0..100).filter(|_| limit.check_at(scanner, start)).count();
+        assert_eq!(accepted, 5);
+        assert_eq!(stat.rejected(), 95);
+
+        // Other sources are not affected
+        assert!(limit.check_at(client, start));
+
+        // Refilled by the rate
+        let later = start + Duration::from_millis(500);
+        let accepted = (0..100).filter(|_| limit.check_at(scanner, later)).count();
+        assert_eq!(accepted, 5);
+
+        let later = start + Duration::from_millis(600);
+        let accepted = (0..100).filter(|_| limit.check_at(scanner, later)).count();
+        assert_eq!(accepted, 1);
+        assert_eq!(stat.rejected(), 95 + 95 + 99);
+    }
+
+    #[test]
+    fn idle_sources_pruned() {
+        let mut limit = TcpConnRateLimit::new(10, 5);
+        let start = Instant::now();
+        for i in 0..PRUNE_SOURCES_THRESHOLD as u32 {
+            assert!(limit.check_at(IpAddr::from(Ipv4Addr::from(i)), start));
+        }
+        assert_eq!(limit.buckets.len(), PRUNE_SOURCES_THRESHOLD);
+
+        let later = start + Duration::from_secs(1);
+        let source = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(limit.check_at(source, later));
+        assert_eq!(limit.buckets.len(), 1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Handling data sent by TCP clients before their outbound connections are established
+//!
+//! The TCP stack completes handshakes with clients immediately, the outbound connection is only started afterwards.
+//! So clients may start sending before there is anywhere to relay to. Most protocols don't care, but some treat
+//! acknowledged data as delivered, or time out on a proxy that accepts their data without answering.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Default time waited for the outbound connection in `TcpEarlyDataPolicy::Reset`
+pub const DEFAULT_EARLY_DATA_RESET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Behavior for data sent by TCP clients before the outbound connection is established
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpEarlyDataPolicy {
+    /// Acknowledge and buffer the data, it is relayed after connected. Clients are only stopped by full buffers
+    Buffer,
+    /// Drop client's segments carrying data until connected, so nothing is acknowledged
+    ///
+    /// Clients retransmit the dropped segments, which delays their first data by a retransmission timeout. Data is
+    /// accepted while sniffing, which has to read it before connecting.
+    Delay,
+    /// Reset the connection if the client has sent data but the outbound connection isn't established in `timeout`
+    Reset { timeout: Duration },
+}
+
+impl Display for TcpEarlyDataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TcpEarlyDataPolicy::Buffer => f.write_str("buffer"),
+            TcpEarlyDataPolicy::Delay => f.write_str("delay"),
+            TcpEarlyDataPolicy::Reset { .. } => f.write_str("reset"),
+        }
+    }
+}
+
+/// Error while parsing `TcpEarlyDataPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct TcpEarlyDataPolicyError;
+
+impl Display for TcpEarlyDataPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid TcpEarlyDataPolicy")
+    }
+}
+
+impl FromStr for TcpEarlyDataPolicy {
+    type Err = TcpEarlyDataPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffer" => Ok(TcpEarlyDataPolicy::Buffer),
+            "delay" => Ok(TcpEarlyDataPolicy::Delay),
+            "reset" => Ok(TcpEarlyDataPolicy::Reset {
+                timeout: DEFAULT_EARLY_DATA_RESET_TIMEOUT,
+            }),
+            _ => Err(TcpEarlyDataPolicyError),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Lifecycle events of TCP connections relayed by the TCP stack
+//!
+//! Events are broadcast to subscribers of a `broadcast::Sender<TunEvent>`, e.g. for a live dashboard of connections.
+//! Nothing is built or sent unless a sender is set.
+
+use std::net::SocketAddr;
+
+use shadowsocks::{relay::socks5::Address, ServerAddr};
+use tokio::sync::broadcast;
+
+/// Stage of a connection's lifetime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunEventKind {
+    /// Client's SYN is accepted, its relay task starts connecting
+    Created,
+    /// Connected to `target`, which may be sniffed or rewritten, through `server` if proxied
+    Established {
+        target: Address,
+        server: Option<ServerAddr>,
+    },
+    /// Relay finished, with bytes from client (`tx`) and to client (`rx`). `error` is set if it failed
+    Closed { tx: u64, rx: u64, error: Option<String> },
+}
+
+/// Event of a connection between the client's `src_addr` and its original destination `dst_addr`
+#[derive(Debug, Clone)]
+pub struct TunEvent {
+    pub src_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+    pub kind: TunEventKind,
+}
+
+/// Sender of a connection's events
+#[derive(Clone)]
+pub(crate) struct TunEventSender {
+    tx: broadcast::Sender<TunEvent>,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+}
+
+impl TunEventSender {
+    pub(crate) fn new(tx: broadcast::Sender<TunEvent>, src_addr: SocketAddr, dst_addr: SocketAddr) -> TunEventSender {
+        TunEventSender { tx, src_addr, dst_addr }
+    }
+
+    /// Send `kind` to subscribers, dropped if there is none
+    pub(crate) fn send(&self, kind: TunEventKind) {
+        let _ = self.tx.send(TunEvent {
+            src_addr: self.src_addr,
+            dst_addr: self.dst_addr,
+            kind,
+        });
+    }
+}
@@ -0,0 +1,108 @@
+//! Server affinity of related TCP flows
+//!
+//! Protocols like FTP open a data connection shortly after the control connection. Flows from the same client IP
+//! within a short window are correlated and sent through the same server, so they leave from the same egress.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use shadowsocks::ServerAddr;
+use spin::Mutex as SpinMutex;
+
+use crate::local::loadbalancing::{PingBalancer, ServerIdent};
+
+/// Server chosen by the latest flow of a client
+struct AffinityEntry {
+    server_addr: ServerAddr,
+    updated: Instant,
+}
+
+/// Servers recently used by clients, shared by all flows' tasks
+#[derive(Clone)]
+pub struct FlowAffinity {
+    window: Duration,
+    entries: Arc<SpinMutex<HashMap<IpAddr, AffinityEntry>>>,
+}
+
+impl FlowAffinity {
+    /// Correlate flows started within `window` after the previous flow of the same client
+    pub fn new(window: Duration) -> FlowAffinity {
+        FlowAffinity {
+            window,
+            entries: Arc::new(SpinMutex::new(HashMap::new())),
+        }
+    }
+
+    /// Server of `client`'s related flow, if it is still in the balancer
+    pub fn server(&self, balancer: &PingBalancer, client: IpAddr) -> Option<Arc<ServerIdent>> {
+        let server_addr = {
+            let entries = self.entries.lock();
+            let entry = entries.get(&client)?;
+            if entry.updated.elapsed() > self.window {
+                return None;
+            }
+            entry.server_addr.clone()
+        };
+        balancer.find_server(&server_addr)
+    }
+
+    /// Record the server that `client`'s new flow is connected through
+    pub fn record(&self, client: IpAddr, server_addr: &ServerAddr) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        entries.retain(|_, entry| now.saturating_duration_since(entry.updated) <= self.window);
+        entries.insert(
+            client,
+            AffinityEntry {
+                server_addr: server_addr.clone(),
+                updated: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use shadowsocks::{config::Mode, crypto::v1::CipherKind, ServerConfig};
+
+    use crate::local::{context::ServiceContext, loadbalancing::PingBalancerBuilder};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn related_flows_share_server() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        for port in [8388, 8389] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                "password",
+                CipherKind::AES_256_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let client = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        let other_client = IpAddr::from(Ipv4Addr::new(10, 0, 0, 3));
+        let affinity = FlowAffinity::new(Duration::from_millis(100));
+        assert!(affinity.server(&balancer, client).is_none());
+
+        // Control flow is connected through the second server, which is not the best one
+        let control_server = ServerAddr::SocketAddr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8389));
+        affinity.record(client, &control_server);
+
+        let data_server = affinity.server(&balancer, client).expect("correlated server");
+        assert_eq!(data_server.server_config().addr(), &control_server);
+        assert!(affinity.server(&balancer, other_client).is_none());
+
+        // Flows after the window are not related
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(affinity.server(&balancer, client).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+//! Capturing raw IP frames of the TCP stack, and replaying them
+//!
+//! Frames entering the TCP stack (`TcpTun::drive_interface_state`) and leaving it (`TcpTun::recv_packet`) are
+//! appended to a file, so that bugs reported from the field could be reproduced by replaying the inbound frames
+//! through a `TcpTun` in tests.
+//!
+//! The file starts with `CAPTURE_MAGIC`, followed by records of
+//!
+//! ```plain
+//! +-----------+--------------+--------+-------+
+//! | DIRECTION | ELAPSED (us) | LENGTH | FRAME |
+//! +-----------+--------------+--------+-------+
+//! |     1     |      8       |   4    |  ...  |
+//! +-----------+--------------+--------+-------+
+//! ```
+//!
+//! Integers are in network order, `DIRECTION` is 0 for inbound and 1 for outbound frames, `ELAPSED` is the time since
+//! the capture started.
+
+#[cfg(test)]
+use std::net::SocketAddr;
+use std::{
+    fs::File,
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(test)]
+use log::{error, trace};
+#[cfg(test)]
+use smoltcp::wire::{IpProtocol, TcpPacket};
+#[cfg(test)]
+use tokio::time;
+
+#[cfg(test)]
+use super::{ip_packet::IpPacket, tcp::TcpTun};
+
+/// Leading bytes of a capture file
+pub const CAPTURE_MAGIC: &[u8; 8] = b"SSTUNCAP";
+
+const RECORD_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Frames larger than this are surely corrupted, TUN frames never exceed 64KiB
+const MAX_FRAME_LEN: usize = 65535;
+
+/// Direction of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// Sent by clients to the TCP stack
+    Inbound,
+    /// Sent by the TCP stack to clients
+    Outbound,
+}
+
+/// A frame read from a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub direction: FrameDirection,
+    /// Time since the capture started
+    pub elapsed: Duration,
+    pub frame: Vec<u8>,
+}
+
+/// Writer of a capture file
+///
+/// Records are buffered, they are flushed when the capture is dropped.
+pub struct FrameCapture {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl FrameCapture {
+    /// Create (or truncate) the capture file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FrameCapture> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CAPTURE_MAGIC)?;
+        Ok(FrameCapture {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Append a frame
+    pub fn record(&mut self, direction: FrameDirection, frame: &[u8]) -> io::Result<()> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0] = match direction {
+            FrameDirection::Inbound => 0,
+            FrameDirection::Outbound => 1,
+        };
+        BigEndian::write_u64(&mut header[1..9], self.start.elapsed().as_micros() as u64);
+        BigEndian::write_u32(&mut header[9..], frame.len() as u32);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(frame)
+    }
+
+    /// Flush buffered records to the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Read all frames of a capture file
+pub fn read_capture<R: Read>(mut reader: R) -> io::Result<Vec<CapturedFrame>> {
+    let mut magic = [0u8; CAPTURE_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != CAPTURE_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a TUN frame capture"));
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        // A truncated record is the last one written by an aborted process
+        match reader.read_exact(&mut header) {
+            Ok(..) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        let direction = match header[0] {
+            0 => FrameDirection::Inbound,
+            1 => FrameDirection::Outbound,
+            d => {
+                let err = io::Error::new(ErrorKind::InvalidData, format!("invalid frame direction {}", d));
+                return Err(err);
+            }
+        };
+        let elapsed = Duration::from_micros(BigEndian::read_u64(&header[1..9]));
+        let len = BigEndian::read_u32(&header[9..]) as usize;
+        if len > MAX_FRAME_LEN {
+            let err = io::Error::new(ErrorKind::InvalidData, format!("frame length {} too large", len));
+            return Err(err);
+        }
+
+        let mut frame = vec![0u8; len];
+        match reader.read_exact(&mut frame) {
+            Ok(..) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        frames.push(CapturedFrame {
+            direction,
+            elapsed,
+            frame,
+        });
+    }
+
+    Ok(frames)
+}
+
+/// Feed the inbound frames of a capture to `tcp` in order, as `Tun` does
+///
+/// Frames are sent with their captured spacing if `keep_timing`. Outbound frames are left to the caller, they are
+/// sent by `tcp` as it processes the inbound ones. Regression tests reproduce captures from the field with it.
+///
+/// Fails if the TCP stack of `tcp` is dead.
+#[cfg(test)]
+pub(crate) async fn replay_capture(tcp: &mut TcpTun, frames: &[CapturedFrame], keep_timing: bool) -> io::Result<()> {
+    let start = time::Instant::now();
+
+    for captured in frames.iter().filter(|f| f.direction == FrameDirection::Inbound) {
+        if keep_timing {
+            time::sleep_until(start + captured.elapsed).await;
+        }
+
+        let frame = &captured.frame[..];
+        if let Ok(Some(packet)) = IpPacket::new_checked(frame) {
+            if packet.protocol() == IpProtocol::Tcp {
+                if let Ok(tcp_packet) = TcpPacket::new_checked(packet.payload()) {
+                    let src_addr = SocketAddr::new(packet.src_addr(), tcp_packet.src_port());
+                    let dst_addr = SocketAddr::new(packet.dst_addr(), tcp_packet.dst_port());
+                    if let Err(err) = tcp
+                        .handle_packet(src_addr, dst_addr, packet.traffic_class(), &tcp_packet)
+                        .await
+                    {
+                        error!(
+                            "replay TCP packet failed, error: {}, {} <-> {}",
+                            err, src_addr, dst_addr
+                        );
+                    }
+                }
+            }
+        }
+
+        trace!("replaying captured frame of {} bytes", frame.len());
+        tcp.drive_interface_state(frame).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process, sync::Arc};
+
+    use shadowsocks::{config::Mode, crypto::v1::CipherKind, ServerConfig};
+
+    use crate::local::{context::ServiceContext, loadbalancing::PingBalancerBuilder};
+
+    use super::{
+        super::{scheduler::TcpSchedulerPolicy, test_frame::build_syn_frame},
+        *,
+    };
+
+    /// Stack relaying to `server_addr`, which should keep connections pending, otherwise clients are reset
+    async fn new_tcp_tun(server_addr: SocketAddr) -> TcpTun {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM));
+        let balancer = builder.build().await.unwrap();
+        TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None)
+    }
+
+    async fn recv_frames(tcp: &mut TcpTun, n: usize) -> Vec<Vec<u8>> {
+        let mut frames = Vec::with_capacity(n);
+        for _ in 0..n {
+            let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+                .await
+                .expect("frame not replied")
+                .unwrap();
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Replies with their random ISNs stripped
+    fn reply_summary(frame: &[u8]) -> (u16, u16, bool, bool, bool, u32) {
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        (
+            packet.src_port(),
+            packet.dst_port(),
+            packet.syn(),
+            packet.ack(),
+            packet.rst(),
+            packet.ack_number().0 as u32,
+        )
+    }
+
+    #[tokio::test]
+    async fn capture_round_trip() {
+        let path = env::temp_dir().join(format!("ss-tun-capture-test-{}.cap", process::id()));
+        // Server never responds, relays are kept waiting
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        // Every SYN is replied with a SYN-ACK by a listening socket
+        const FRAMES: u16 = 4;
+        let inbound = (0..FRAMES).map(|i| build_syn_frame(50000 + i)).collect::<Vec<_>>();
+        let mut tcp = new_tcp_tun(server_addr).await;
+        tcp.set_frame_capture(Some(FrameCapture::create(&path).unwrap()));
+        let frames = inbound
+            .iter()
+            .map(|frame| CapturedFrame {
+                direction: FrameDirection::Inbound,
+                elapsed: Duration::ZERO,
+                frame: frame.clone(),
+            })
+            .collect::<Vec<_>>();
+        replay_capture(&mut tcp, &frames, false).await.unwrap();
+        let outbound = recv_frames(&mut tcp, FRAMES as usize).await;
+        // Flushed on drop
+        tcp.set_frame_capture(None);
+
+        let frames = read_capture(fs::File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let captured = |direction| {
+            frames
+                .iter()
+                .filter(|f| f.direction == direction)
+                .map(|f| f.frame.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(frames.len(), 2 * FRAMES as usize);
+        assert_eq!(captured(FrameDirection::Inbound), inbound);
+        assert_eq!(captured(FrameDirection::Outbound), outbound);
+        assert!(frames.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+
+        // The replayed stack replies the same
+        let mut tcp = new_tcp_tun(server_addr).await;
+        replay_capture(&mut tcp, &frames, true).await.unwrap();
+        let replayed = recv_frames(&mut tcp, FRAMES as usize).await;
+        let expected = outbound.iter().map(|f| reply_summary(f)).collect::<Vec<_>>();
+        assert_eq!(replayed.iter().map(|f| reply_summary(f)).collect::<Vec<_>>(), expected);
+        assert!(expected.iter().all(|&(.., syn, ack, rst, _)| syn && ack && !rst));
+    }
+
+    #[test]
+    fn truncated_capture() {
+        let mut data = CAPTURE_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 3, 1, 2, 3]);
+        // Process aborted in the middle of the second record
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 3, 1]);
+
+        let frames = read_capture(&data[..]).unwrap();
+        assert_eq!(
+            frames,
+            vec![CapturedFrame {
+                direction: FrameDirection::Inbound,
+                elapsed: Duration::from_micros(1),
+                frame: vec![1, 2, 3],
+            }]
+        );
+
+        assert!(read_capture(&b"NOTACAPTURE"[..]).is_err());
+    }
+}
@@ -34,6 +34,14 @@ impl<T: AsRef<[u8]> + Copy> IpPacket<T> {
         }
     }
 
+    /// IPv4's TOS byte or IPv6's traffic class, DSCP in the high 6 bits and ECN in the low 2 bits
+    pub fn traffic_class(&self) -> u8 {
+        match *self {
+            IpPacket::Ipv4(ref packet) => (packet.dscp() << 2) | packet.ecn(),
+            IpPacket::Ipv6(ref packet) => packet.traffic_class(),
+        }
+    }
+
     pub fn protocol(&self) -> IpProtocol {
         match *self {
             IpPacket::Ipv4(ref packet) => packet.protocol(),
@@ -2,43 +2,206 @@
 
 #[cfg(unix)]
 use std::os::unix::io::RawFd;
+#[cfg(feature = "local-tun-capture")]
+use std::path::PathBuf;
 use std::{
+    fmt::{self, Display},
+    future::Future,
     io::{self, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
 
 use byte_string::ByteStr;
+use futures::{future, FutureExt};
 use ipnet::IpNet;
 use log::{debug, error, info, trace, warn};
-use shadowsocks::config::Mode;
-use smoltcp::wire::{IpProtocol, TcpPacket, UdpPacket};
-use tokio::{io::AsyncReadExt, sync::mpsc, time};
+use shadowsocks::{
+    config::{Mode, ResolveStrategy},
+    dns_resolver::DnsResolver,
+};
+use smoltcp::{
+    iface::Route,
+    wire::{IpAddress, IpCidr, IpProtocol, TcpPacket, UdpPacket},
+};
+use tokio::{
+    io::AsyncReadExt,
+    sync::{broadcast, mpsc},
+    time::{self, Instant},
+};
 use tun::{AsyncDevice, Configuration as TunConfiguration, Device as TunDevice, Error as TunError, Layer};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerSelector},
+        net::SniffConfig,
+        LOCAL_DEFAULT_KEEPALIVE_TIMEOUT,
+    },
+    net::clock::Clock,
+};
+
+pub use self::{
+    conn_rate::TcpConnRateStat,
+    mss_clamp::TcpMssClamp,
+    poll_stat::{PollMetrics, TcpPollStat},
+    target_rewriter::{IdentityTargetRewriter, TargetRewriter},
+    tcp::{
+        ConnEntry,
+        TcpAcceptor,
+        TcpBufferOccupancy,
+        TcpBufferProfile,
+        TcpConnTrack,
+        TcpConnection,
+        TcpConnectionState,
+        TcpRelayTasks,
+    },
+};
 
 use self::{
     ip_packet::IpPacket,
     sys::{write_packet_with_pi, IFF_PI_PREFIX_LEN},
-    tcp::TcpTun,
+    tcp::{TcpInterfaceConfig, TcpTun},
     udp::UdpTun,
+    unsupported_protocol::build_protocol_unreachable,
+};
+
+pub use self::proxy_protocol::{ProxyProtocolVersion, ProxyProtocolVersionError};
+pub use self::early_data::{TcpEarlyDataPolicy, TcpEarlyDataPolicyError, DEFAULT_EARLY_DATA_RESET_TIMEOUT};
+pub use self::scheduler::{TcpSchedulerPolicy, TcpSchedulerPolicyError, DEFAULT_SCHEDULER_QUANTUM};
+pub use self::unsupported_protocol::{
+    UnsupportedProtocolPolicy,
+    UnsupportedProtocolPolicyError,
+    UnsupportedProtocolStat,
 };
+pub use self::virt_device::{TunDeviceStat, DEFAULT_OUT_QUEUE_LIMIT};
+pub use self::events::{TunEvent, TunEventKind};
+#[cfg(feature = "local-tun-capture")]
+pub use self::frame_capture::{read_capture, CapturedFrame, FrameCapture, FrameDirection, CAPTURE_MAGIC};
 
+mod bandwidth;
+mod conn_rate;
+mod early_data;
+mod events;
+mod flow_affinity;
+#[cfg(feature = "local-tun-capture")]
+mod frame_capture;
 mod ip_packet;
+mod mss_clamp;
+mod mtu_blackhole;
+mod poll_stat;
+mod proxy_protocol;
+mod scheduler;
 mod sys;
+mod target_rewriter;
 mod tcp;
+#[cfg(test)]
+mod test_frame;
 mod udp;
+mod unsupported_protocol;
 mod virt_device;
 
+/// MTU of the TCP stack if it is neither configured nor could be queried from the device
+const DEFAULT_TUN_MTU: u32 = 1500;
+
+/// Default time waited for TCP connections to be closed gracefully in `Tun::run_until`
+pub const DEFAULT_TUN_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of checking if all TCP connections are closed while shutting down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum frames read from the device without waiting, before they are sent to the TCP stack together
+const TUN_READ_BATCH_SIZE: usize = 64;
+
+/// Smallest MTU accepted by `TunController::set_tcp_mtu`, the minimum MTU of IPv4
+const MIN_TUN_MTU: u32 = 576;
+
+/// Configured MTU differs from the device's real MTU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MtuMismatch {
+    configured: u32,
+    device: u32,
+}
+
+impl Display for MtuMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "configured mtu {} doesn't match tun device's mtu {}, packets may be fragmented or blackholed",
+            self.configured, self.device
+        )
+    }
+}
+
+/// Choose MTU of the TCP stack from the configured and the device's MTU
+///
+/// Returns the mismatch if they are different, the device's MTU is chosen if `auto_correct`.
+fn resolve_mtu(configured: Option<u32>, device: Option<u32>, auto_correct: bool) -> (u32, Option<MtuMismatch>) {
+    match (configured, device) {
+        (Some(configured), Some(device)) if configured != device => {
+            let mtu = if auto_correct { device } else { configured };
+            (mtu, Some(MtuMismatch { configured, device }))
+        }
+        (Some(mtu), ..) | (None, Some(mtu)) => (mtu, None),
+        (None, None) => (DEFAULT_TUN_MTU, None),
+    }
+}
+
 pub struct TunBuilder {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     tun_config: TunConfiguration,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_icmp_unreachable: bool,
     mode: Mode,
+    unsupported_protocol_policy: UnsupportedProtocolPolicy,
+    tcp_buffer_profiles: Vec<TcpBufferProfile>,
+    tcp_scheduler_policy: TcpSchedulerPolicy,
+    tcp_stall_timeout: Option<Duration>,
+    tcp_dead_peer_timeout: Option<Duration>,
+    tcp_read_timeout: Option<Duration>,
+    tcp_write_timeout: Option<Duration>,
+    tcp_idle_timeout: Option<Duration>,
+    tcp_connect_timeout: Option<Duration>,
+    tcp_first_byte_timeout: Option<Duration>,
+    tcp_reset_on_remote_failure: bool,
+    tcp_reset_on_server_down: bool,
+    tcp_early_data_policy: TcpEarlyDataPolicy,
+    tcp_proxy_protocol: Option<ProxyProtocolVersion>,
+    tcp_resolve_strategy: Option<ResolveStrategy>,
+    tcp_max_relay_tasks: Option<usize>,
+    tcp_max_connections: Option<usize>,
+    tcp_recv_buffer_autotune: Option<u32>,
+    tcp_round_budget: Option<usize>,
+    tcp_out_queue_limit: Option<usize>,
+    tcp_conn_rate_limit: Option<(u32, u32)>,
+    tcp_bandwidth_limit: Option<(u64, u64)>,
+    tcp_global_bandwidth_limit: Option<(u64, u64)>,
+    tcp_default_keepalive: Option<Duration>,
+    tcp_nodelay: bool,
+    tcp_flow_affinity: Option<Duration>,
+    tcp_server_selector: Option<Arc<dyn ServerSelector>>,
+    tcp_target_rewriter: Option<Arc<dyn TargetRewriter>>,
+    tcp_poll_metrics: Option<Arc<dyn PollMetrics>>,
+    tcp_clock: Option<Arc<dyn Clock>>,
+    tcp_events: Option<broadcast::Sender<TunEvent>>,
+    tcp_dns_resolver: Option<Arc<DnsResolver>>,
+    tcp_accept_cidrs: Option<Vec<IpNet>>,
+    tcp_accept_connections: bool,
+    tcp_interface_addrs: Option<Vec<IpNet>>,
+    tcp_interface_routes: Vec<(IpNet, IpAddr)>,
+    #[cfg(feature = "local-tun-capture")]
+    tcp_frame_capture_path: Option<PathBuf>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    outbound_fwmark: Option<u32>,
+    sniff_config: SniffConfig,
+    mtu: Option<u32>,
+    auto_correct_mtu: bool,
+    clamp_mss_on_mtu_blackhole: bool,
+    tcp_mss_clamp: TcpMssClamp,
+    shutdown_timeout: Duration,
 }
 
 impl TunBuilder {
@@ -49,7 +212,54 @@ impl TunBuilder {
             tun_config: TunConfiguration::default(),
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_icmp_unreachable: false,
             mode: Mode::TcpOnly,
+            unsupported_protocol_policy: UnsupportedProtocolPolicy::Drop,
+            tcp_buffer_profiles: Vec::new(),
+            tcp_scheduler_policy: TcpSchedulerPolicy::Unordered,
+            tcp_stall_timeout: None,
+            tcp_dead_peer_timeout: None,
+            tcp_read_timeout: None,
+            tcp_write_timeout: None,
+            tcp_idle_timeout: None,
+            tcp_connect_timeout: None,
+            tcp_first_byte_timeout: None,
+            tcp_reset_on_remote_failure: false,
+            tcp_reset_on_server_down: false,
+            tcp_early_data_policy: TcpEarlyDataPolicy::Buffer,
+            tcp_proxy_protocol: None,
+            tcp_resolve_strategy: None,
+            tcp_max_relay_tasks: None,
+            tcp_max_connections: None,
+            tcp_recv_buffer_autotune: None,
+            tcp_round_budget: None,
+            tcp_out_queue_limit: None,
+            tcp_conn_rate_limit: None,
+            tcp_bandwidth_limit: None,
+            tcp_global_bandwidth_limit: None,
+            tcp_default_keepalive: Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT),
+            tcp_nodelay: false,
+            tcp_flow_affinity: None,
+            tcp_server_selector: None,
+            tcp_target_rewriter: None,
+            tcp_poll_metrics: None,
+            tcp_clock: None,
+            tcp_events: None,
+            tcp_dns_resolver: None,
+            tcp_accept_cidrs: None,
+            tcp_accept_connections: false,
+            tcp_interface_addrs: None,
+            tcp_interface_routes: Vec::new(),
+            #[cfg(feature = "local-tun-capture")]
+            tcp_frame_capture_path: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            outbound_fwmark: None,
+            sniff_config: SniffConfig::default(),
+            mtu: None,
+            auto_correct_mtu: false,
+            clamp_mss_on_mtu_blackhole: false,
+            tcp_mss_clamp: TcpMssClamp::default(),
+            shutdown_timeout: DEFAULT_TUN_SHUTDOWN_TIMEOUT,
         }
     }
 
@@ -63,6 +273,52 @@ impl TunBuilder {
         self
     }
 
+    /// MTU of the tun device, it is checked against the device's real MTU after the device is created or opened
+    pub fn mtu(mut self, mtu: u32) -> TunBuilder {
+        self.tun_config.mtu(mtu as i32);
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Use the device's real MTU if it differs from the configured one, otherwise only a warning is logged
+    pub fn auto_correct_mtu(mut self, auto_correct: bool) -> TunBuilder {
+        self.auto_correct_mtu = auto_correct;
+        self
+    }
+
+    /// Clamp MSS of new TCP connections on paths where an MTU black hole was detected
+    ///
+    /// Black holes are always detected and logged, the MSS is left untouched by default.
+    pub fn clamp_mss_on_mtu_blackhole(mut self, clamp_mss: bool) -> TunBuilder {
+        self.clamp_mss_on_mtu_blackhole = clamp_mss;
+        self
+    }
+
+    /// Maximum size of TCP segments sent to clients, for paths with a smaller MTU towards them
+    ///
+    /// Derived from the MTU by default, as `tcp_recv_mss`.
+    pub fn tcp_send_mss(mut self, mss: u16) -> TunBuilder {
+        self.tcp_mss_clamp.send = Some(mss);
+        self
+    }
+
+    /// Maximum size of TCP segments advertised to clients, for paths with a smaller MTU from them
+    ///
+    /// Derived from the MTU by default, as `tcp_send_mss`.
+    pub fn tcp_recv_mss(mut self, mss: u16) -> TunBuilder {
+        self.tcp_mss_clamp.recv = Some(mss);
+        self
+    }
+
+    /// Overhead of the proxy's encapsulation subtracted from the MSS advertised to clients, which is derived from the
+    /// MTU
+    ///
+    /// Nothing is subtracted by default. The lower one is advertised if `tcp_recv_mss` is also set.
+    pub fn tcp_mss_overhead(mut self, overhead: u16) -> TunBuilder {
+        self.tcp_mss_clamp.recv_overhead = Some(overhead);
+        self
+    }
+
     #[cfg(unix)]
     pub fn file_descriptor(mut self, fd: RawFd) -> TunBuilder {
         self.tun_config.raw_fd(fd);
@@ -79,12 +335,341 @@ impl TunBuilder {
         self
     }
 
+    /// Answer UDP packets that couldn't be relayed, because the server or the target is unreachable, with ICMP host
+    /// unreachable, instead of dropping them silently. Disabled by default
+    pub fn udp_icmp_unreachable(mut self, enabled: bool) -> TunBuilder {
+        self.udp_icmp_unreachable = enabled;
+        self
+    }
+
     pub fn mode(mut self, mode: Mode) -> TunBuilder {
         self.mode = mode;
         self
     }
 
+    /// Behavior for IP packets with protocols that are not relayed
+    pub fn unsupported_protocol_policy(mut self, policy: UnsupportedProtocolPolicy) -> TunBuilder {
+        self.unsupported_protocol_policy = policy;
+        self
+    }
+
+    /// Add a buffer profile for TCP connections, profiles are matched in the order they were added
+    pub fn tcp_buffer_profile(mut self, profile: TcpBufferProfile) -> TunBuilder {
+        self.tcp_buffer_profiles.push(profile);
+        self
+    }
+
+    /// Policy of scheduling TCP connections' sends when the device is the bottleneck
+    pub fn tcp_scheduler_policy(mut self, policy: TcpSchedulerPolicy) -> TunBuilder {
+        self.tcp_scheduler_policy = policy;
+        self
+    }
+
+    /// Reset TCP connections making no progress for `timeout` while both the client and the remote stopped reading
+    ///
+    /// Disabled by default, these connections are kept until the keep-alive timeout.
+    pub fn tcp_stall_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Reset TCP connections making no progress for `timeout` while data is pending, that a peer stopped responding
+    ///
+    /// Disabled by default, these connections are kept until the keep-alive or idle timeout.
+    pub fn tcp_dead_peer_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_dead_peer_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail relay reads of TCP connections with `TimedOut` if no data comes from client for `timeout`
+    ///
+    /// Disabled by default, the relay waits for client until the idle timeout.
+    pub fn tcp_read_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail relay writes of TCP connections with `TimedOut` if client doesn't free room for them for `timeout`
+    ///
+    /// Disabled by default, the relay waits for client until the dead peer or idle timeout.
+    pub fn tcp_write_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_write_timeout = Some(timeout);
+        self
+    }
+
+    /// Reset TCP connections without data in both directions for `timeout`, 2 hours by default
+    pub fn tcp_idle_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Reset TCP connections whose remotes are not connected in `timeout`, instead of leaving clients hanging until
+    /// the system's connect timeout. Disabled by default.
+    pub fn tcp_connect_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Reset proxied TCP connections if the server sends nothing in `timeout` after the client's first payload
+    ///
+    /// The stall is reported to the balancer. Disabled by default.
+    pub fn tcp_first_byte_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.tcp_first_byte_timeout = Some(timeout);
+        self
+    }
+
+    /// Reset TCP connections of clients if the outbound connection fails in the middle of relaying
+    ///
+    /// By default they are closed gracefully, clients couldn't tell a truncated response from a complete one unless
+    /// the protocol has its own framing. Failures are always counted in `TcpRelayTasks`.
+    pub fn tcp_reset_on_remote_failure(mut self, reset: bool) -> TunBuilder {
+        self.tcp_reset_on_remote_failure = reset;
+        self
+    }
+
+    /// Reset TCP connections relayed through a server when the balancer marks it down, so clients reconnect through
+    /// healthy servers
+    ///
+    /// By default connections keep the server picked when they were established until their outbound connections fail.
+    pub fn tcp_reset_on_server_down(mut self, reset: bool) -> TunBuilder {
+        self.tcp_reset_on_server_down = reset;
+        self
+    }
+
+    /// Behavior for data sent by TCP clients before the outbound connection is established, buffered by default
+    pub fn tcp_early_data_policy(mut self, policy: TcpEarlyDataPolicy) -> TunBuilder {
+        self.tcp_early_data_policy = policy;
+        self
+    }
+
+    /// Write a PROXY protocol header with endpoints of TCP clients to upstreams before relaying, disabled by default
+    ///
+    /// For servers logging or authorizing by the client's address, which would see the outbound connection's instead.
+    pub fn tcp_proxy_protocol(mut self, version: Option<ProxyProtocolVersion>) -> TunBuilder {
+        self.tcp_proxy_protocol = version;
+        self
+    }
+
+    /// How domain name targets of TCP connections bypassing servers are resolved and connected
+    ///
+    /// IPv4 and IPv6 addresses are raced (happy eyeballs) by default, so a broken family doesn't stall connections.
+    pub fn tcp_resolve_strategy(mut self, strategy: Option<ResolveStrategy>) -> TunBuilder {
+        self.tcp_resolve_strategy = strategy;
+        self
+    }
+
+    /// Maximum number of live TCP relay tasks, new connections are reset while the limit is reached
+    pub fn tcp_max_relay_tasks(mut self, max: usize) -> TunBuilder {
+        self.tcp_max_relay_tasks = Some(max);
+        self
+    }
+
+    /// Maximum number of TCP sockets including half-open and closing ones, new connections are reset while the limit
+    /// is reached. Unlimited by default
+    pub fn tcp_max_connections(mut self, max: usize) -> TunBuilder {
+        self.tcp_max_connections = Some(max);
+        self
+    }
+
+    /// Grow receive buffers of TCP connections up to `max` bytes while clients send faster than they could hold.
+    /// Disabled by default
+    pub fn tcp_recv_buffer_autotune(mut self, max: u32) -> TunBuilder {
+        self.tcp_recv_buffer_autotune = Some(max);
+        self
+    }
+
+    /// Move at most `budget` bytes in each direction of a TCP connection in a round of the stack, unlimited by default
+    ///
+    /// A busy connection yields to the others after its budget, instead of delaying them and new SYNs.
+    pub fn tcp_round_budget(mut self, budget: usize) -> TunBuilder {
+        self.tcp_round_budget = Some(budget);
+        self
+    }
+
+    /// Maximum frames produced by the TCP stack waiting to be written to tun, `DEFAULT_OUT_QUEUE_LIMIT` by default
+    ///
+    /// Connections stop sending while tun is too slow to take them, instead of queuing frames without a bound.
+    pub fn tcp_out_queue_limit(mut self, limit: usize) -> TunBuilder {
+        self.tcp_out_queue_limit = Some(limit);
+        self
+    }
+
+    /// Connect TCP flows through the same server as the previous flow of the same client IP, if it started within `window`
+    ///
+    /// Related flows like FTP's control and data connections leave from the same egress. Disabled by default.
+    pub fn tcp_flow_affinity(mut self, window: Duration) -> TunBuilder {
+        self.tcp_flow_affinity = Some(window);
+        self
+    }
+
+    /// Choose servers of TCP connections with `selector` instead of the balancer's, for routing targets to specific
+    /// servers without affecting other local servers sharing the balancer
+    pub fn tcp_server_selector(mut self, selector: Arc<dyn ServerSelector>) -> TunBuilder {
+        self.tcp_server_selector = Some(selector);
+        self
+    }
+
+    /// Rewrite targets of TCP connections with `rewriter` before connecting, e.g. mapping fake IPs back to domain names
+    pub fn tcp_target_rewriter(mut self, rewriter: Arc<dyn TargetRewriter>) -> TunBuilder {
+        self.tcp_target_rewriter = Some(rewriter);
+        self
+    }
+
+    /// Report timing of every TCP stack's poll to `metrics`, for collecting poll latency into telemetry
+    pub fn tcp_poll_metrics(mut self, metrics: Arc<dyn PollMetrics>) -> TunBuilder {
+        self.tcp_poll_metrics = Some(metrics);
+        self
+    }
+
+    /// Check timeouts of TCP connections against `clock` instead of `SystemClock`
+    pub fn tcp_clock(mut self, clock: Arc<dyn Clock>) -> TunBuilder {
+        self.tcp_clock = Some(clock);
+        self
+    }
+
+    /// Broadcast lifecycle events of TCP connections to subscribers of `events`
+    pub fn tcp_events(mut self, events: broadcast::Sender<TunEvent>) -> TunBuilder {
+        self.tcp_events = Some(events);
+        self
+    }
+
+    /// Resolve domain names of TCP connections' targets and servers with `resolver` instead of the `ServiceContext`'s
+    pub fn tcp_dns_resolver(mut self, resolver: Arc<DnsResolver>) -> TunBuilder {
+        self.tcp_dns_resolver = Some(resolver);
+        self
+    }
+
+    /// Only accept TCP connections to destinations in `cidrs`, such as a fake-IP range, instead of all of them
+    ///
+    /// Frames to other destinations are dropped, so the host could route them normally.
+    pub fn tcp_accept_cidrs(mut self, cidrs: Vec<IpNet>) -> TunBuilder {
+        self.tcp_accept_cidrs = Some(cidrs);
+        self
+    }
+
+    /// Hand accepted TCP connections over to `Tun::tcp_acceptor` instead of tunneling them through the balancer
+    ///
+    /// Callers relay data of the connections themselves, such as bridging them to other upstreams.
+    pub fn tcp_accept_connections(mut self, enabled: bool) -> TunBuilder {
+        self.tcp_accept_connections = enabled;
+        self
+    }
+
+    /// Assign `addrs` to the TCP stack's interface and install `routes` of destinations via gateways, instead of
+    /// `0.0.0.1` and `::1` with default routes through them
+    ///
+    /// Address families without any address are disabled, every enabled family needs a default route through one of
+    /// `addrs`. Checked when the tun is built.
+    pub fn tcp_interface(mut self, addrs: Vec<IpNet>, routes: Vec<(IpNet, IpAddr)>) -> TunBuilder {
+        self.tcp_interface_addrs = Some(addrs);
+        self.tcp_interface_routes = routes;
+        self
+    }
+
+    /// Capture raw IP frames entering and leaving the TCP stack to the file at `path`, for replaying them in tests
+    ///
+    /// The file is truncated when the tun is built. Every frame is written, only enable it to reproduce bugs.
+    #[cfg(feature = "local-tun-capture")]
+    pub fn tcp_frame_capture(mut self, path: PathBuf) -> TunBuilder {
+        self.tcp_frame_capture_path = Some(path);
+        self
+    }
+
+    /// Set `SO_MARK` of all outbound sockets of TCP connections and UDP associations, instead of the global
+    /// `outbound_fwmark`
+    ///
+    /// Outbound traffic could be routed by the mark without going back into the tun, which would loop forever.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn outbound_fwmark(mut self, fwmark: u32) -> TunBuilder {
+        self.outbound_fwmark = Some(fwmark);
+        self
+    }
+
+    /// Limit new TCP connections of each source IP to `rate` per second with bursts of `burst`
+    ///
+    /// SYNs over the rate are replied with RST. Disabled by default.
+    pub fn tcp_conn_rate_limit(mut self, rate: u32, burst: u32) -> TunBuilder {
+        self.tcp_conn_rate_limit = Some((rate, burst));
+        self
+    }
+
+    /// Limit each TCP connection to `rate` bytes per second in each direction with bursts of `burst` bytes
+    ///
+    /// Throttled connections are slowed down by their windows and back-pressure. Disabled by default.
+    pub fn tcp_bandwidth_limit(mut self, rate: u64, burst: u64) -> TunBuilder {
+        self.tcp_bandwidth_limit = Some((rate, burst));
+        self
+    }
+
+    /// Limit all TCP connections together to `rate` bytes per second in each direction with bursts of `burst` bytes.
+    /// Disabled by default
+    pub fn tcp_global_bandwidth_limit(mut self, rate: u64, burst: u64) -> TunBuilder {
+        self.tcp_global_bandwidth_limit = Some((rate, burst));
+        self
+    }
+
+    /// Keep-alive of TCP connections if it isn't set by `AcceptOpts`, pass `None` to disable it
+    ///
+    /// Defaults to 15 seconds. Without keep-alive, connections of dead clients are not detected until smoltcp's 7200s
+    /// timeout.
+    pub fn tcp_default_keepalive(mut self, keepalive: Option<Duration>) -> TunBuilder {
+        self.tcp_default_keepalive = keepalive;
+        self
+    }
+
+    /// Send data of TCP connections to clients as soon as it is written, like `TCP_NODELAY`
+    ///
+    /// Disabled by default, small writes are coalesced by Nagle's algorithm while earlier data is unacknowledged.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> TunBuilder {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Recover hostname of TCP flows from TLS ClientHello's SNI, for ACL and connecting through servers
+    pub fn sniff_tls_sni(mut self, sniff_tls_sni: bool) -> TunBuilder {
+        self.sniff_config.tls = sniff_tls_sni;
+        self
+    }
+
+    /// Recover hostname of plaintext HTTP flows from the `Host` header, for ACL and connecting through servers
+    pub fn sniff_http_host(mut self, sniff_http_host: bool) -> TunBuilder {
+        self.sniff_config.http = sniff_http_host;
+        self
+    }
+
+    /// Maximum time waited for TCP connections to be closed gracefully after `Tun::run_until` is signaled
+    ///
+    /// Clients that never acknowledge FIN don't block shutting down, their connections are aborted after `timeout`.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> TunBuilder {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
     pub async fn build(mut self) -> io::Result<Tun> {
+        let tcp_iface_config = match self.tcp_interface_addrs.take() {
+            None => None,
+            Some(addrs) => {
+                let ip_addrs = addrs
+                    .iter()
+                    .map(|addr| IpCidr::new(IpAddress::from(addr.addr()), addr.prefix_len()))
+                    .collect();
+                let routes = self
+                    .tcp_interface_routes
+                    .iter()
+                    .map(|(cidr, gateway)| {
+                        let cidr = IpCidr::new(IpAddress::from(cidr.network()), cidr.prefix_len());
+                        let route = Route {
+                            via_router: IpAddress::from(*gateway),
+                            preferred_until: None,
+                            expires_at: None,
+                        };
+                        (cidr, route)
+                    })
+                    .collect();
+                Some(TcpInterfaceConfig::new(ip_addrs, routes)?)
+            }
+        };
+
         self.tun_config.layer(Layer::L3).up();
 
         #[cfg(any(target_os = "linux"))]
@@ -99,18 +684,115 @@ impl TunBuilder {
             Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
         };
 
-        let (udp, udp_cleanup_interval, udp_keepalive_rx) = UdpTun::new(
+        #[allow(unused_mut)]
+        let (mut udp, udp_cleanup_interval, udp_keepalive_rx) = UdpTun::new(
             self.context.clone(),
             self.balancer.clone(),
             self.udp_expiry_duration,
             self.udp_capacity,
+            self.udp_icmp_unreachable,
         );
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        udp.set_outbound_fwmark(self.outbound_fwmark);
 
-        let tcp = TcpTun::new(
-            self.context,
-            self.balancer,
-            device.get_ref().mtu().unwrap_or(1500) as u32,
-        );
+        // Devices opened from file descriptors are not configured by us, their MTU may be different
+        let device_mtu = match device.get_ref().mtu() {
+            Ok(mtu) => Some(mtu as u32),
+            Err(err) => {
+                warn!("failed to query tun device's mtu, error: {}", err);
+                None
+            }
+        };
+        let (mtu, mismatch) = resolve_mtu(self.mtu, device_mtu, self.auto_correct_mtu);
+        if let Some(mismatch) = mismatch {
+            if self.auto_correct_mtu {
+                warn!("{}, corrected to {}", mismatch, mtu);
+            } else {
+                warn!("{}", mismatch);
+            }
+        }
+
+        let accept_cidrs = self.tcp_accept_cidrs.map(|cidrs| {
+            cidrs
+                .iter()
+                .map(|cidr| IpCidr::new(IpAddress::from(cidr.network()), cidr.prefix_len()))
+                .collect()
+        });
+        let mut tcp = match tcp_iface_config {
+            None => TcpTun::new(
+                self.context,
+                self.balancer,
+                mtu,
+                self.tcp_scheduler_policy,
+                accept_cidrs,
+            ),
+            Some(iface_config) => TcpTun::with_interface(
+                self.context,
+                self.balancer,
+                mtu,
+                self.tcp_scheduler_policy,
+                accept_cidrs,
+                iface_config,
+            ),
+        };
+        tcp.set_buffer_profiles(self.tcp_buffer_profiles);
+        tcp.set_stall_timeout(self.tcp_stall_timeout);
+        tcp.set_dead_peer_timeout(self.tcp_dead_peer_timeout);
+        tcp.set_read_timeout(self.tcp_read_timeout);
+        tcp.set_write_timeout(self.tcp_write_timeout);
+        if let Some(idle_timeout) = self.tcp_idle_timeout {
+            tcp.set_idle_timeout(Some(idle_timeout));
+        }
+        tcp.set_connect_timeout(self.tcp_connect_timeout);
+        tcp.set_first_byte_timeout(self.tcp_first_byte_timeout);
+        tcp.set_reset_on_remote_failure(self.tcp_reset_on_remote_failure);
+        tcp.set_reset_on_server_down(self.tcp_reset_on_server_down);
+        tcp.set_early_data_policy(self.tcp_early_data_policy);
+        tcp.set_proxy_protocol(self.tcp_proxy_protocol);
+        tcp.set_resolve_strategy(self.tcp_resolve_strategy);
+        tcp.set_max_relay_tasks(self.tcp_max_relay_tasks);
+        tcp.set_max_connections(self.tcp_max_connections);
+        tcp.set_recv_buffer_autotune(self.tcp_recv_buffer_autotune);
+        tcp.set_round_budget(self.tcp_round_budget);
+        if let Some(limit) = self.tcp_out_queue_limit {
+            tcp.set_out_queue_limit(limit);
+        }
+        if let Some((rate, burst)) = self.tcp_conn_rate_limit {
+            tcp.set_conn_rate_limit(rate, burst);
+        }
+        if let Some((rate, burst)) = self.tcp_bandwidth_limit {
+            tcp.set_bandwidth_limit(rate, burst);
+        }
+        if let Some((rate, burst)) = self.tcp_global_bandwidth_limit {
+            tcp.set_global_bandwidth_limit(rate, burst);
+        }
+        tcp.set_default_keepalive(self.tcp_default_keepalive);
+        tcp.set_nodelay(self.tcp_nodelay);
+        tcp.set_flow_affinity(self.tcp_flow_affinity);
+        tcp.set_server_selector(self.tcp_server_selector);
+        tcp.set_poll_metrics(self.tcp_poll_metrics);
+        if let Some(clock) = self.tcp_clock {
+            tcp.set_clock(clock);
+        }
+        tcp.set_events(self.tcp_events);
+        tcp.set_dns_resolver(self.tcp_dns_resolver);
+        if let Some(rewriter) = self.tcp_target_rewriter {
+            tcp.set_target_rewriter(rewriter);
+        }
+        tcp.set_sniff_config(self.sniff_config);
+        tcp.set_accept_connections(self.tcp_accept_connections);
+        tcp.set_clamp_mss_on_mtu_blackhole(self.clamp_mss_on_mtu_blackhole);
+        tcp.set_mss_clamp(self.tcp_mss_clamp);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        tcp.set_outbound_fwmark(self.outbound_fwmark);
+        #[cfg(feature = "local-tun-capture")]
+        if let Some(path) = self.tcp_frame_capture_path {
+            let capture = FrameCapture::create(&path)?;
+            warn!("capturing TCP frames of tun to {}", path.display());
+            tcp.set_frame_capture(Some(capture));
+        }
+
+        let (tcp_mtu_tx, tcp_mtu_rx) = mpsc::unbounded_channel();
 
         Ok(Tun {
             device,
@@ -118,7 +800,12 @@ impl TunBuilder {
             udp,
             udp_cleanup_interval,
             udp_keepalive_rx,
+            controller: TunController { tcp_mtu_tx },
+            tcp_mtu_rx,
             mode: self.mode,
+            unsupported_protocol_policy: self.unsupported_protocol_policy,
+            unsupported_protocol_stat: UnsupportedProtocolStat::new(),
+            shutdown_timeout: self.shutdown_timeout,
         })
     }
 }
@@ -129,11 +816,112 @@ pub struct Tun {
     udp: UdpTun,
     udp_cleanup_interval: Duration,
     udp_keepalive_rx: mpsc::Receiver<SocketAddr>,
+    controller: TunController,
+    tcp_mtu_rx: mpsc::UnboundedReceiver<u32>,
     mode: Mode,
+    unsupported_protocol_policy: UnsupportedProtocolPolicy,
+    unsupported_protocol_stat: UnsupportedProtocolStat,
+    shutdown_timeout: Duration,
+}
+
+/// Handle for changing a running `Tun`, could be cloned and used while `Tun` is running
+#[derive(Debug, Clone)]
+pub struct TunController {
+    tcp_mtu_tx: mpsc::UnboundedSender<u32>,
+}
+
+impl TunController {
+    /// Change MTU of the TCP stack, e.g. after the tun device's MTU was changed
+    ///
+    /// Connections are kept, segments sent after it is applied fit in the new MTU. Fails with `InvalidInput` if `mtu`
+    /// is smaller than 576, or `BrokenPipe` if `Tun` is stopped.
+    pub fn set_tcp_mtu(&self, mtu: u32) -> io::Result<()> {
+        if mtu < MIN_TUN_MTU {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("mtu {} is smaller than {}", mtu, MIN_TUN_MTU),
+            ));
+        }
+
+        self.tcp_mtu_tx
+            .send(mtu)
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "tun stopped"))
+    }
 }
 
 impl Tun {
-    pub async fn run(mut self) -> io::Result<()> {
+    /// Handle for changing the `Tun` while it is running
+    pub fn controller(&self) -> TunController {
+        self.controller.clone()
+    }
+
+    /// Connection tracking of TCP connections, could be read while `Tun` is running
+    pub fn tcp_conntrack(&self) -> TcpConnTrack {
+        self.tcp.conntrack()
+    }
+
+    /// Occupancy of TCP connections' relay buffers, telling whether the client or the remote is the bottleneck
+    pub fn tcp_buffer_occupancy(&self) -> Vec<TcpBufferOccupancy> {
+        self.tcp.buffer_occupancy()
+    }
+
+    /// Counters of dropped packets with unsupported protocols, could be read while `Tun` is running
+    pub fn unsupported_protocol_stat(&self) -> UnsupportedProtocolStat {
+        self.unsupported_protocol_stat.clone()
+    }
+
+    /// Number of live TCP relay tasks, could be read while `Tun` is running
+    pub fn tcp_relay_tasks(&self) -> TcpRelayTasks {
+        self.tcp.relay_tasks()
+    }
+
+    /// Counters of TCP connections rejected by the rate limit, could be read while `Tun` is running
+    pub fn tcp_conn_rate_stat(&self) -> TcpConnRateStat {
+        self.tcp.conn_rate_stat()
+    }
+
+    /// Counters of frames passing through the TCP stack's device, could be read while `Tun` is running
+    pub fn tcp_device_stat(&self) -> TunDeviceStat {
+        self.tcp.device_stats()
+    }
+
+    /// Histogram of costs of the TCP stack's polls, could be read while `Tun` is running
+    pub fn tcp_poll_stat(&self) -> TcpPollStat {
+        self.tcp.poll_stat()
+    }
+
+    /// Export states of all active TCP connections, see `TcpConnectionState` for limitations
+    pub fn export_tcp_connections(&self) -> Vec<TcpConnectionState> {
+        self.tcp.export_connections()
+    }
+
+    /// Take the acceptor of TCP connections enabled by `TunBuilder::tcp_accept_connections`, used while `Tun` is running
+    ///
+    /// Returns `None` if accepting isn't enabled or the acceptor was taken already.
+    pub fn tcp_acceptor(&mut self) -> Option<TcpAcceptor> {
+        self.tcp.acceptor()
+    }
+
+    /// Import TCP connection states exported by another `Tun` instance
+    pub fn import_tcp_connections<I>(&mut self, states: I)
+    where
+        I: IntoIterator<Item = TcpConnectionState>,
+    {
+        self.tcp.import_connections(states)
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        self.run_until(future::pending()).await
+    }
+
+    /// Run until `shutdown` completes, then close TCP connections gracefully
+    ///
+    /// New connections are refused while closing. Data already received from remotes is sent to clients before FIN,
+    /// connections not closed in `shutdown_timeout` are aborted.
+    pub async fn run_until<F>(mut self, shutdown: F) -> io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
         let mtu = self.device.get_ref().mtu().expect("mtu");
         assert!(mtu > 0 && mtu as usize > IFF_PI_PREFIX_LEN);
 
@@ -147,25 +935,53 @@ impl Tun {
         let mut packet_buffer = vec![0u8; 65536 + IFF_PI_PREFIX_LEN].into_boxed_slice();
         let mut udp_cleanup_timer = time::interval(self.udp_cleanup_interval);
 
+        tokio::pin!(shutdown);
+        let mut shutdown_deadline = None;
+        let mut shutdown_check_timer = time::interval(SHUTDOWN_CHECK_INTERVAL);
+
         loop {
             tokio::select! {
+                _ = &mut shutdown, if shutdown_deadline.is_none() => {
+                    info!("shadowsocks tun device shutting down, closing {} TCP connections", self.tcp.connection_count());
+                    self.tcp.close();
+                    shutdown_deadline = Some(Instant::now() + self.shutdown_timeout);
+                }
+
+                _ = shutdown_check_timer.tick(), if shutdown_deadline.is_some() => {
+                    if self.tcp.is_closed() {
+                        debug!("shadowsocks tun device closed all TCP connections");
+                        return Ok(());
+                    }
+                }
+
+                _ = time::sleep_until(shutdown_deadline.unwrap_or_else(Instant::now)), if shutdown_deadline.is_some() => {
+                    warn!(
+                        "shadowsocks tun device didn't close TCP connections in {:?}, aborting",
+                        self.shutdown_timeout
+                    );
+                    return Ok(());
+                }
+
                 // tun device
                 n = self.device.read(&mut packet_buffer) => {
-                    let n = n?;
+                    // Frames already queued in the device are read without waiting, those for the TCP stack are sent
+                    // to it together, so a burst costs a single round of its manager
+                    let mut tcp_frames = Vec::new();
+                    let mut n = n?;
+                    for _ in 0..TUN_READ_BATCH_SIZE {
+                        self.handle_tun_read(&packet_buffer[..n], &mut tcp_frames).await;
 
-                    if n <= IFF_PI_PREFIX_LEN {
-                        error!(
-                            "[TUN] packet too short, packet: {:?}",
-                            ByteStr::new(&packet_buffer[..n])
-                        );
-                        continue;
+                        n = match self.device.read(&mut packet_buffer).now_or_never() {
+                            Some(n) => n?,
+                            None => break,
+                        };
                     }
 
-                    let packet = &mut packet_buffer[IFF_PI_PREFIX_LEN..n];
-                    trace!("[TUN] received IP packet {:?}", ByteStr::new(packet));
-
-                    if let Err(err) = self.handle_tun_frame(packet).await {
-                        error!("[TUN] handle IP frame failed, error: {}", err);
+                    // The TCP stack is dead if it fails
+                    match tcp_frames.len() {
+                        0 => {}
+                        1 => self.tcp.drive_interface_state(&tcp_frames[0]).await?,
+                        _ => self.tcp.drive_interface_state_batch(tcp_frames).await?,
                     }
                 }
 
@@ -189,8 +1005,15 @@ impl Tun {
                     self.udp.keep_alive(&peer_addr).await;
                 }
 
-                // TCP channel sent back
+                // MTU changed by `TunController`, `Tun` holds a sender itself
+                Some(mtu) = self.tcp_mtu_rx.recv() => {
+                    info!("shadowsocks tun device TCP mtu changed to {}", mtu);
+                    self.tcp.set_mtu(mtu);
+                }
+
+                // TCP channel sent back, the TCP stack is dead if it fails
                 packet = self.tcp.recv_packet() => {
+                    let packet = packet?;
                     if let Err(err) = write_packet_with_pi(&mut self.device, &packet).await {
                         error!("[TUN] failed to set packet information, error: {}, {:?}", err, ByteStr::new(&packet));
                     } else {
@@ -201,12 +1024,33 @@ impl Tun {
         }
     }
 
-    async fn handle_tun_frame(&mut self, frame: &[u8]) -> smoltcp::Result<()> {
-        let packet = match IpPacket::new_checked(frame)? {
-            Some(packet) => packet,
-            None => {
+    /// Handle `buf` read from the device, frames for the TCP stack are pushed to `tcp_frames`
+    async fn handle_tun_read(&mut self, buf: &[u8], tcp_frames: &mut Vec<Vec<u8>>) {
+        if buf.len() <= IFF_PI_PREFIX_LEN {
+            error!("[TUN] packet too short, packet: {:?}", ByteStr::new(buf));
+            return;
+        }
+
+        let packet = &buf[IFF_PI_PREFIX_LEN..];
+        trace!("[TUN] received IP packet {:?}", ByteStr::new(packet));
+
+        self.handle_tun_frame(packet, tcp_frames).await;
+    }
+
+    async fn handle_tun_frame(&mut self, frame: &[u8], tcp_frames: &mut Vec<Vec<u8>>) {
+        let packet = match IpPacket::new_checked(frame) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => {
                 warn!("unrecognized IP packet {:?}", ByteStr::new(frame));
-                return Ok(());
+                return;
+            }
+            Err(err) => {
+                error!(
+                    "[TUN] invalid IP packet err: {}, packet: {:?}",
+                    err,
+                    ByteStr::new(frame)
+                );
+                return;
             }
         };
 
@@ -214,7 +1058,8 @@ impl Tun {
             IpProtocol::Tcp => {
                 if !self.mode.enable_tcp() {
                     trace!("received TCP packet but mode is {}, throwing away", self.mode);
-                    return Ok(());
+                    self.handle_unsupported_protocol(frame, &packet).await;
+                    return;
                 }
 
                 let tcp_packet = match TcpPacket::new_checked(packet.payload()) {
@@ -227,7 +1072,7 @@ impl Tun {
                             packet.dst_addr(),
                             ByteStr::new(packet.payload())
                         );
-                        return Ok(());
+                        return;
                     }
                 };
 
@@ -240,19 +1085,24 @@ impl Tun {
                 trace!("[TUN] TCP packet {} -> {} {}", src_addr, dst_addr, tcp_packet);
 
                 // TCP first handshake packet.
-                if let Err(err) = self.tcp.handle_packet(src_addr, dst_addr, &tcp_packet).await {
+                if let Err(err) = self
+                    .tcp
+                    .handle_packet(src_addr, dst_addr, packet.traffic_class(), &tcp_packet)
+                    .await
+                {
                     error!(
                         "handle TCP packet failed, error: {}, {} <-> {}, packet: {:?}",
                         err, src_addr, dst_addr, tcp_packet
                     );
                 }
 
-                self.tcp.drive_interface_state(frame).await;
+                tcp_frames.push(frame.to_vec());
             }
             IpProtocol::Udp => {
                 if !self.mode.enable_udp() {
                     trace!("received UDP packet but mode is {}, throwing away", self.mode);
-                    return Ok(());
+                    self.handle_unsupported_protocol(frame, &packet).await;
+                    return;
                 }
 
                 let udp_packet = match UdpPacket::new_checked(packet.payload()) {
@@ -265,7 +1115,7 @@ impl Tun {
                             packet.dst_addr(),
                             ByteStr::new(packet.payload())
                         );
-                        return Ok(());
+                        return;
                     }
                 };
 
@@ -285,14 +1135,87 @@ impl Tun {
             IpProtocol::Icmp | IpProtocol::Icmpv6 => {
                 // ICMP is handled by TCP's Interface.
                 // smoltcp's interface will always send replies to EchoRequest
-                self.tcp.drive_interface_state(frame).await;
+                tcp_frames.push(frame.to_vec());
             }
             _ => {
                 debug!("IP packet ignored (protocol: {:?})", packet.protocol());
-                return Ok(());
+                self.handle_unsupported_protocol(frame, &packet).await;
+            }
+        }
+    }
+
+    async fn handle_unsupported_protocol(&mut self, frame: &[u8], packet: &IpPacket<&[u8]>) {
+        self.unsupported_protocol_stat.incr(packet.protocol());
+
+        match self.unsupported_protocol_policy {
+            UnsupportedProtocolPolicy::Drop => {}
+            UnsupportedProtocolPolicy::Log => {
+                warn!(
+                    "[TUN] dropped IP packet with unsupported protocol {}, {} -> {}, {} packets dropped",
+                    packet.protocol(),
+                    packet.src_addr(),
+                    packet.dst_addr(),
+                    self.unsupported_protocol_stat.total()
+                );
+            }
+            UnsupportedProtocolPolicy::Unreachable => {
+                let reply = match build_protocol_unreachable(frame) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        error!("[TUN] failed to build ICMP protocol unreachable, error: {}", err);
+                        return;
+                    }
+                };
+
+                if let Err(err) = write_packet_with_pi(&mut self.device, &reply).await {
+                    error!(
+                        "[TUN] failed to set packet information, error: {}, {:?}",
+                        err,
+                        ByteStr::new(&reply)
+                    );
+                } else {
+                    trace!("[TUN] sent IP packet (ICMP unreachable) {:?}", ByteStr::new(&reply));
+                }
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mtu_mismatch_reported() {
+        let mismatch = MtuMismatch {
+            configured: 9000,
+            device: 1500,
+        };
+        assert_eq!(resolve_mtu(Some(9000), Some(1500), false), (9000, Some(mismatch)));
+        assert_eq!(resolve_mtu(Some(9000), Some(1500), true), (1500, Some(mismatch)));
+        assert!(mismatch
+            .to_string()
+            .contains("configured mtu 9000 doesn't match tun device's mtu 1500"));
+
+        assert_eq!(resolve_mtu(Some(1400), Some(1400), false), (1400, None));
+        assert_eq!(resolve_mtu(None, Some(1400), false), (1400, None));
+        assert_eq!(resolve_mtu(Some(1400), None, false), (1400, None));
+        assert_eq!(resolve_mtu(None, None, false), (DEFAULT_TUN_MTU, None));
+    }
+
+    #[test]
+    fn controller_sends_tcp_mtu() {
+        let (tcp_mtu_tx, mut tcp_mtu_rx) = mpsc::unbounded_channel();
+        let controller = TunController { tcp_mtu_tx };
+
+        controller.set_tcp_mtu(1280).unwrap();
+        assert_eq!(tcp_mtu_rx.try_recv().unwrap(), 1280);
+        let err = controller.set_tcp_mtu(MIN_TUN_MTU - 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(tcp_mtu_rx.try_recv().is_err());
 
-        Ok(())
+        drop(tcp_mtu_rx);
+        let err = controller.set_tcp_mtu(1500).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
     }
 }
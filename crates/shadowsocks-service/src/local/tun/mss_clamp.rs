@@ -0,0 +1,284 @@
+//! Clamping MSS of TCP connections in each direction
+//!
+//! smoltcp derives both the MSS it advertises and the largest segment it sends from the interface's MTU. Paths
+//! with different overhead in each direction (tunnels over tunnels) need different values, so they are clamped by
+//! rewriting the MSS option of handshakes: the client's SYN limits segments sent by the TCP stack, the stack's
+//! SYN-ACK limits segments sent by the client.
+//!
+//! The MSS advertised to clients could also be derived from the MTU minus the overhead of the proxy's encapsulation,
+//! so clients never send segments that the path towards them fragments or drops when PMTUD fails.
+
+use byteorder::{BigEndian, ByteOrder};
+use smoltcp::wire::{IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket};
+
+/// TCP option kinds, RFC 793
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Length of TCP header without options
+const TCP_HEADER_LEN: usize = 20;
+/// MSS that every host accepts, RFC 879. Clamping by overhead never goes below it
+const TCP_DEFAULT_MSS: u16 = 536;
+
+/// Maximum segment sizes of the TCP stack in each direction
+///
+/// Both directions are derived from the MTU by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpMssClamp {
+    /// Maximum segment size sent by the TCP stack to clients
+    pub send: Option<u16>,
+    /// Maximum segment size advertised to clients, sent by them to the TCP stack
+    pub recv: Option<u16>,
+    /// Overhead subtracted from the MSS derived from the MTU advertised to clients, e.g. the proxy's encapsulation
+    pub recv_overhead: Option<u16>,
+}
+
+impl TcpMssClamp {
+    /// Clamp MSS of a client's SYN entering the TCP stack, returns the clamped MSS if it was rewritten
+    pub fn clamp_inbound(&self, frame: &mut [u8]) -> Option<u16> {
+        let max_mss = self.send?;
+        clamp_frame_mss(frame, max_mss, |tcp_packet| tcp_packet.syn() && !tcp_packet.ack())
+    }
+
+    /// Clamp MSS of a SYN-ACK sent by the TCP stack on an interface of `mtu`, returns the clamped MSS if it was
+    /// rewritten
+    pub fn clamp_outbound(&self, frame: &mut [u8], mtu: usize) -> Option<u16> {
+        let overhead_mss = self
+            .recv_overhead
+            .and_then(|overhead| mss_by_overhead(frame, mtu, overhead));
+        let max_mss = match (self.recv, overhead_mss) {
+            (Some(recv), Some(overhead_mss)) => recv.min(overhead_mss),
+            (recv, overhead_mss) => recv.or(overhead_mss)?,
+        };
+        clamp_frame_mss(frame, max_mss, |tcp_packet| tcp_packet.syn() && tcp_packet.ack())
+    }
+}
+
+/// MSS of `frame`'s IP version on an interface of `mtu`, minus `overhead`
+fn mss_by_overhead(frame: &[u8], mtu: usize, overhead: u16) -> Option<u16> {
+    let ip_header_len = match IpVersion::of_packet(frame).ok()? {
+        IpVersion::Ipv4 => 20,
+        IpVersion::Ipv6 => 40,
+        _ => return None,
+    };
+    let mss = mtu.saturating_sub(ip_header_len + TCP_HEADER_LEN + overhead as usize);
+    Some((mss.min(u16::MAX as usize) as u16).max(TCP_DEFAULT_MSS))
+}
+
+fn clamp_frame_mss<F>(frame: &mut [u8], max_mss: u16, is_handshake: F) -> Option<u16>
+where
+    F: Fn(&TcpPacket<&mut [u8]>) -> bool,
+{
+    let (segment, src_addr, dst_addr) = match IpVersion::of_packet(frame).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(&*frame).ok()?;
+            if packet.protocol() != IpProtocol::Tcp {
+                return None;
+            }
+            let header_len = packet.header_len() as usize;
+            let total_len = packet.total_len() as usize;
+            let src_addr = IpAddress::from(packet.src_addr());
+            let dst_addr = IpAddress::from(packet.dst_addr());
+            (&mut frame[header_len..total_len], src_addr, dst_addr)
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(&*frame).ok()?;
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            let header_len = packet.header_len();
+            let total_len = header_len + packet.payload_len() as usize;
+            let src_addr = IpAddress::from(packet.src_addr());
+            let dst_addr = IpAddress::from(packet.dst_addr());
+            (&mut frame[header_len..total_len], src_addr, dst_addr)
+        }
+        _ => return None,
+    };
+
+    let mut tcp_packet = TcpPacket::new_checked(segment).ok()?;
+    if !is_handshake(&tcp_packet) {
+        return None;
+    }
+    clamp_segment_mss(&mut tcp_packet, &src_addr, &dst_addr, max_mss)
+}
+
+/// Lower the MSS option of `tcp_packet` to `max_mss` and fix its checksum, returns `max_mss` if it was rewritten
+///
+/// Segments without an MSS option are left as is, peers assume the 536 bytes default.
+pub fn clamp_segment_mss(
+    tcp_packet: &mut TcpPacket<&mut [u8]>,
+    src_addr: &IpAddress,
+    dst_addr: &IpAddress,
+    max_mss: u16,
+) -> Option<u16> {
+    let options = tcp_packet.options_mut();
+    let mut offset = 0;
+    while offset < options.len() {
+        match options[offset] {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => offset += 1,
+            kind => {
+                let len = *options.get(offset + 1)? as usize;
+                if len < 2 || offset + len > options.len() {
+                    return None;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let value = &mut options[offset + 2..offset + 4];
+                    if BigEndian::read_u16(value) <= max_mss {
+                        return None;
+                    }
+                    BigEndian::write_u16(value, max_mss);
+                    tcp_packet.fill_checksum(src_addr, dst_addr);
+                    return Some(max_mss);
+                }
+                offset += len;
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use smoltcp::{
+        phy::ChecksumCapabilities,
+        wire::{TcpControl, TcpRepr, TcpSeqNumber},
+    };
+
+    use super::{super::test_frame::build_tcp_frame, *};
+
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000);
+    const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 80);
+
+    fn frame_mss(frame: &[u8]) -> Option<u16> {
+        let ip_packet = Ipv4Packet::new_checked(frame).unwrap();
+        let tcp_packet = TcpPacket::new_checked(ip_packet.payload()).unwrap();
+        let checksum_caps = ChecksumCapabilities::default();
+        let repr = TcpRepr::parse(
+            &tcp_packet,
+            &ip_packet.src_addr().into(),
+            &ip_packet.dst_addr().into(),
+            &checksum_caps,
+        )
+        .expect("invalid checksum");
+        repr.max_seg_size
+    }
+
+    #[test]
+    fn symmetric_by_default() {
+        let clamp = TcpMssClamp::default();
+        let mut syn = build_tcp_frame(CLIENT, SERVER, TcpControl::Syn, 1, None, Some(1460), &[]);
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_inbound(&mut syn), None);
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 1500), None);
+        assert_eq!(frame_mss(&syn), Some(1460));
+        assert_eq!(frame_mss(&syn_ack), Some(1460));
+    }
+
+    #[test]
+    fn directions_clamped_separately() {
+        let clamp = TcpMssClamp {
+            send: Some(1200),
+            recv: Some(1000),
+            recv_overhead: None,
+        };
+
+        // Client's SYN limits what the stack sends
+        let mut syn = build_tcp_frame(CLIENT, SERVER, TcpControl::Syn, 1, None, Some(1460), &[]);
+        assert_eq!(clamp.clamp_inbound(&mut syn), Some(1200));
+        assert_eq!(frame_mss(&syn), Some(1200));
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_inbound(&mut syn_ack), None);
+
+        // Stack's SYN-ACK limits what the client sends
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 1500), Some(1000));
+        assert_eq!(frame_mss(&syn_ack), Some(1000));
+        let mut syn = build_tcp_frame(CLIENT, SERVER, TcpControl::Syn, 1, None, Some(1460), &[]);
+        assert_eq!(clamp.clamp_outbound(&mut syn, 1500), None);
+
+        // Never raised
+        let mut syn = build_tcp_frame(CLIENT, SERVER, TcpControl::Syn, 1, None, Some(536), &[]);
+        assert_eq!(clamp.clamp_inbound(&mut syn), None);
+        assert_eq!(frame_mss(&syn), Some(536));
+        let mut syn = build_tcp_frame(CLIENT, SERVER, TcpControl::Syn, 1, None, None, &[]);
+        assert_eq!(clamp.clamp_inbound(&mut syn), None);
+    }
+
+    #[test]
+    fn recv_clamped_by_overhead() {
+        let mut clamp = TcpMssClamp {
+            recv_overhead: Some(60),
+            ..TcpMssClamp::default()
+        };
+
+        // 1500 bytes MTU minus IPv4 and TCP headers, and the overhead
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 1500), Some(1400));
+        assert_eq!(frame_mss(&syn_ack), Some(1400));
+
+        // The lower of both clamps
+        clamp.recv = Some(1300);
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 1500), Some(1300));
+        clamp.recv = Some(1450);
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 1500), Some(1400));
+
+        // Never below the default MSS
+        clamp.recv = None;
+        let mut syn_ack = build_tcp_frame(
+            SERVER,
+            CLIENT,
+            TcpControl::Syn,
+            1,
+            Some(TcpSeqNumber(1)),
+            Some(1460),
+            &[],
+        );
+        assert_eq!(clamp.clamp_outbound(&mut syn_ack, 576), Some(TCP_DEFAULT_MSS));
+    }
+}
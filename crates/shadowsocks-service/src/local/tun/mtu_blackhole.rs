@@ -0,0 +1,395 @@
+//! Detecting path MTU black holes between the TCP stack and clients
+//!
+//! A path silently dropping packets above some size lets the handshake and small segments through, but the
+//! connection hangs as soon as full-size segments are sent. Such a black hole is suspected when the TCP stack keeps
+//! retransmitting the same full-size segment while the client is still sending packets without acknowledging it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use smoltcp::wire::{IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, TcpSeqNumber};
+
+use super::{ip_packet::IpPacket, mss_clamp::clamp_segment_mss};
+
+/// Retransmissions of a full-size segment, while the client keeps sending, before a black hole is reported
+const BLACKHOLE_RETRANSMIT_THRESHOLD: u32 = 3;
+
+/// Segments in frames at most this many bytes smaller than the MTU are considered full-size
+const FULL_SIZE_SLACK: usize = 64;
+
+/// IPv6 minimum MTU, which is expected to pass through every path
+const SAFE_PATH_MTU: u16 = 1280;
+
+/// Flows idle for this long are forgotten once there are too many of them
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_TRACKED_FLOWS: usize = 4096;
+const MAX_CLAMPED_PATHS: usize = 1024;
+
+/// Diagnostic of a suspected MTU black hole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuBlackhole {
+    pub client: SocketAddr,
+    pub remote: SocketAddr,
+    /// Length of the lost frame
+    pub frame_len: usize,
+    /// Times the segment was retransmitted
+    pub retransmits: u32,
+}
+
+impl Display for MtuBlackhole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "possible MTU black hole between {} and {}, {}-byte segment retransmitted {} times while smaller packets \
+             pass, consider reducing tun_mtu (or the MTU of the path to the client)",
+            self.remote, self.client, self.frame_len, self.retransmits
+        )
+    }
+}
+
+#[derive(Debug)]
+struct FlowState {
+    /// Sequence number of the latest full-size segment that is not acknowledged yet
+    pending_seq: Option<TcpSeqNumber>,
+    retransmits: u32,
+    /// Client sent packets after the pending segment was retransmitted
+    client_active: bool,
+    reported: bool,
+    last_seen: Instant,
+}
+
+impl FlowState {
+    fn new(now: Instant) -> FlowState {
+        FlowState {
+            pending_seq: None,
+            retransmits: 0,
+            client_active: false,
+            reported: false,
+            last_seen: now,
+        }
+    }
+}
+
+/// Tracks segments sent to and received from clients, looking for MTU black holes
+#[derive(Debug)]
+pub struct MtuBlackholeDetector {
+    mtu: usize,
+    clamp_mss: bool,
+    flows: HashMap<(SocketAddr, SocketAddr), FlowState>,
+    clamped_paths: HashSet<(IpAddr, IpAddr)>,
+}
+
+impl MtuBlackholeDetector {
+    pub fn new(mtu: u32) -> MtuBlackholeDetector {
+        MtuBlackholeDetector {
+            mtu: mtu as usize,
+            clamp_mss: false,
+            flows: HashMap::new(),
+            clamped_paths: HashSet::new(),
+        }
+    }
+
+    /// Change the MTU that full-size segments are measured against
+    pub fn set_mtu(&mut self, mtu: u32) {
+        self.mtu = mtu as usize;
+    }
+
+    /// Clamp MSS advertised in SYNs of new connections between the same (client, remote) addresses after a black hole
+    /// was detected, so segments sent to the client fit in the IPv6 minimum MTU
+    pub fn set_clamp_mss(&mut self, clamp_mss: bool) {
+        self.clamp_mss = clamp_mss;
+    }
+
+    /// Observe a frame sent from the TCP stack to the client, returns the diagnostic once per suspected flow
+    pub fn outbound_frame(&mut self, frame: &[u8]) -> Option<MtuBlackhole> {
+        let packet = match IpPacket::new_checked(frame) {
+            Ok(Some(p)) if p.protocol() == IpProtocol::Tcp => p,
+            _ => return None,
+        };
+        let tcp_packet = TcpPacket::new_checked(packet.payload()).ok()?;
+
+        let client = SocketAddr::new(packet.dst_addr(), tcp_packet.dst_port());
+        let remote = SocketAddr::new(packet.src_addr(), tcp_packet.src_port());
+        self.outbound(client, remote, &tcp_packet, frame.len())
+    }
+
+    fn outbound(
+        &mut self,
+        client: SocketAddr,
+        remote: SocketAddr,
+        tcp_packet: &TcpPacket<&[u8]>,
+        frame_len: usize,
+    ) -> Option<MtuBlackhole> {
+        if tcp_packet.rst() || tcp_packet.fin() {
+            self.flows.remove(&(client, remote));
+            return None;
+        }
+        if tcp_packet.payload().is_empty() || frame_len + FULL_SIZE_SLACK < self.mtu {
+            return None;
+        }
+
+        let now = Instant::now();
+        self.expire_idle(now);
+
+        let state = self
+            .flows
+            .entry((client, remote))
+            .or_insert_with(|| FlowState::new(now));
+        state.last_seen = now;
+
+        let seq = tcp_packet.seq_number();
+        match state.pending_seq {
+            Some(pending_seq) if pending_seq == seq => state.retransmits += 1,
+            // Retransmission of an older segment, or a new segment while one is still pending
+            Some(..) => {}
+            None => {
+                state.pending_seq = Some(seq);
+                state.retransmits = 0;
+                state.client_active = false;
+            }
+        }
+
+        if state.reported || !state.client_active || state.retransmits < BLACKHOLE_RETRANSMIT_THRESHOLD {
+            return None;
+        }
+        state.reported = true;
+
+        if self.clamp_mss && self.clamped_paths.len() < MAX_CLAMPED_PATHS {
+            self.clamped_paths.insert((client.ip(), remote.ip()));
+        }
+
+        Some(MtuBlackhole {
+            client,
+            remote,
+            frame_len,
+            retransmits: state.retransmits,
+        })
+    }
+
+    /// Observe a segment received from the client
+    pub fn inbound(&mut self, client: SocketAddr, remote: SocketAddr, tcp_packet: &TcpPacket<&[u8]>) {
+        if tcp_packet.rst() || tcp_packet.fin() {
+            self.flows.remove(&(client, remote));
+            return;
+        }
+
+        let state = match self.flows.get_mut(&(client, remote)) {
+            Some(s) => s,
+            None => return,
+        };
+        state.last_seen = Instant::now();
+
+        let pending_seq = match state.pending_seq {
+            Some(s) => s,
+            None => return,
+        };
+
+        if tcp_packet.ack() && tcp_packet.ack_number() > pending_seq {
+            // Some of the pending segment went through
+            state.pending_seq = None;
+            state.retransmits = 0;
+            state.client_active = false;
+        } else if state.retransmits > 0 {
+            state.client_active = true;
+        }
+    }
+
+    /// Clamp the MSS option of a client's SYN if a black hole was detected on its path, returns the clamped MSS
+    pub fn clamp_syn(&self, frame: &mut [u8]) -> Option<u16> {
+        if self.clamped_paths.is_empty() {
+            return None;
+        }
+
+        match IpVersion::of_packet(frame).ok()? {
+            IpVersion::Ipv4 => {
+                let mut packet = Ipv4Packet::new_checked(frame).ok()?;
+                if packet.protocol() != IpProtocol::Tcp {
+                    return None;
+                }
+                let src_addr = IpAddress::from(packet.src_addr());
+                let dst_addr = IpAddress::from(packet.dst_addr());
+                self.clamp_syn_segment(packet.payload_mut(), src_addr, dst_addr, SAFE_PATH_MTU - 40)
+            }
+            IpVersion::Ipv6 => {
+                let mut packet = Ipv6Packet::new_checked(frame).ok()?;
+                if packet.next_header() != IpProtocol::Tcp {
+                    return None;
+                }
+                let src_addr = IpAddress::from(packet.src_addr());
+                let dst_addr = IpAddress::from(packet.dst_addr());
+                self.clamp_syn_segment(packet.payload_mut(), src_addr, dst_addr, SAFE_PATH_MTU - 60)
+            }
+            _ => None,
+        }
+    }
+
+    fn clamp_syn_segment(
+        &self,
+        segment: &mut [u8],
+        src_addr: IpAddress,
+        dst_addr: IpAddress,
+        max_mss: u16,
+    ) -> Option<u16> {
+        let mut tcp_packet = TcpPacket::new_checked(segment).ok()?;
+        if !tcp_packet.syn() || tcp_packet.ack() {
+            return None;
+        }
+
+        let path = (IpAddr::from(src_addr), IpAddr::from(dst_addr));
+        if !self.clamped_paths.contains(&path) {
+            return None;
+        }
+
+        clamp_segment_mss(&mut tcp_packet, &src_addr, &dst_addr, max_mss)
+    }
+
+    fn expire_idle(&mut self, now: Instant) {
+        if self.flows.len() < MAX_TRACKED_FLOWS {
+            return;
+        }
+        self.flows
+            .retain(|_, state| now.duration_since(state.last_seen) < FLOW_IDLE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use smoltcp::{
+        phy::ChecksumCapabilities,
+        wire::{Ipv4Address, TcpControl, TcpRepr},
+    };
+
+    use super::{super::test_frame::build_tcp_frame, *};
+
+    const MTU: usize = 1500;
+
+    #[test]
+    fn blackhole_detected_and_clamped() {
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 443);
+        let client_addr = SocketAddr::V4(client);
+        let remote_addr = SocketAddr::V4(remote);
+
+        let mut detector = MtuBlackholeDetector::new(MTU as u32);
+        detector.set_clamp_mss(true);
+
+        let full_payload = vec![0u8; MTU - 40];
+        let full_segment = build_tcp_frame(
+            remote,
+            client,
+            TcpControl::None,
+            1000,
+            Some(TcpSeqNumber(1)),
+            None,
+            &full_payload,
+        );
+        assert_eq!(full_segment.len(), MTU);
+        let client_ack = build_tcp_frame(client, remote, TcpControl::None, 1, Some(TcpSeqNumber(1000)), None, &[]);
+        let client_ack = TcpPacket::new_checked(&client_ack[20..]).unwrap();
+
+        // First transmission, then retransmissions while the client keeps sending duplicated ACKs
+        assert!(detector.outbound_frame(&full_segment).is_none());
+        for _ in 1..BLACKHOLE_RETRANSMIT_THRESHOLD {
+            assert!(detector.outbound_frame(&full_segment).is_none());
+            detector.inbound(client_addr, remote_addr, &client_ack);
+        }
+        let diagnostic = detector.outbound_frame(&full_segment).unwrap();
+        assert_eq!(diagnostic.client, client_addr);
+        assert_eq!(diagnostic.remote, remote_addr);
+        assert_eq!(diagnostic.frame_len, MTU);
+        assert_eq!(diagnostic.retransmits, BLACKHOLE_RETRANSMIT_THRESHOLD);
+        assert!(diagnostic.to_string().contains("possible MTU black hole"));
+
+        // Reported only once
+        assert!(detector.outbound_frame(&full_segment).is_none());
+
+        // New connections on the same path get a clamped MSS
+        let mut syn = build_tcp_frame(
+            SocketAddrV4::new(*client.ip(), 50001),
+            remote,
+            TcpControl::Syn,
+            1,
+            None,
+            Some(1460),
+            &[],
+        );
+        assert_eq!(detector.clamp_syn(&mut syn), Some(SAFE_PATH_MTU - 40));
+        let syn_packet = Ipv4Packet::new_checked(&syn).unwrap();
+        let tcp_packet = TcpPacket::new_checked(syn_packet.payload()).unwrap();
+        let (client_ip, remote_ip) = (Ipv4Address::from(*client.ip()), Ipv4Address::from(*remote.ip()));
+        assert!(tcp_packet.verify_checksum(&client_ip.into(), &remote_ip.into()));
+        let tcp_repr = TcpRepr::parse(
+            &tcp_packet,
+            &client_ip.into(),
+            &remote_ip.into(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        assert_eq!(tcp_repr.max_seg_size, Some(SAFE_PATH_MTU - 40));
+
+        // Other paths are untouched
+        let other = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 443);
+        let mut syn = build_tcp_frame(client, other, TcpControl::Syn, 1, None, Some(1460), &[]);
+        assert_eq!(detector.clamp_syn(&mut syn), None);
+    }
+
+    #[test]
+    fn acknowledged_retransmits_not_reported() {
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 443);
+        let client_addr = SocketAddr::V4(client);
+        let remote_addr = SocketAddr::V4(remote);
+
+        let mut detector = MtuBlackholeDetector::new(MTU as u32);
+
+        let full_payload = vec![0u8; MTU - 40];
+        let small_payload = [0u8; 100];
+        let full_segment = build_tcp_frame(
+            remote,
+            client,
+            TcpControl::None,
+            1000,
+            Some(TcpSeqNumber(1)),
+            None,
+            &full_payload,
+        );
+        let small_segment = build_tcp_frame(
+            remote,
+            client,
+            TcpControl::None,
+            1000,
+            Some(TcpSeqNumber(1)),
+            None,
+            &small_payload,
+        );
+        let client_ack = build_tcp_frame(client, remote, TcpControl::None, 1, Some(TcpSeqNumber(1000)), None, &[]);
+        let client_ack = TcpPacket::new_checked(&client_ack[20..]).unwrap();
+        let progress_ack = build_tcp_frame(client, remote, TcpControl::None, 1, Some(TcpSeqNumber(2000)), None, &[]);
+        let progress_ack = TcpPacket::new_checked(&progress_ack[20..]).unwrap();
+
+        // Small segments are never suspected
+        for _ in 0..=BLACKHOLE_RETRANSMIT_THRESHOLD * 2 {
+            assert!(detector.outbound_frame(&small_segment).is_none());
+            detector.inbound(client_addr, remote_addr, &client_ack);
+        }
+
+        // Ordinary losses are recovered before the threshold
+        for _ in 0..3 {
+            assert!(detector.outbound_frame(&full_segment).is_none());
+            assert!(detector.outbound_frame(&full_segment).is_none());
+            detector.inbound(client_addr, remote_addr, &client_ack);
+            detector.inbound(client_addr, remote_addr, &progress_ack);
+        }
+
+        // Silent client, the path may be completely broken rather than black holed
+        for _ in 0..=BLACKHOLE_RETRANSMIT_THRESHOLD * 2 {
+            assert!(detector.outbound_frame(&full_segment).is_none());
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Timing of the TCP stack's polls
+//!
+//! The manager thread polls the interface in a loop, cost of every poll is recorded in a histogram of power-of-two
+//! microseconds buckets. It shows how the cost of the loop changes with the number of sockets. Sockets checked after
+//! each poll are counted too, only the changed ones are checked except in the periodic full sweeps, which are spread
+//! across polls in chunks.
+//!
+//! Operators could also receive the timing of every poll with a `PollMetrics` sink, for their own telemetry.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use spin::Mutex as SpinMutex;
+
+/// Bucket `i` counts polls costing less than `2^i` microseconds, the last one counts all longer polls
+const POLL_COST_BUCKETS: usize = 24;
+
+#[derive(Debug, Default)]
+struct TcpPollStatInner {
+    buckets: [AtomicU64; POLL_COST_BUCKETS],
+    polls: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+    serviced: AtomicU64,
+    max_serviced: AtomicU64,
+    swept: AtomicU64,
+}
+
+/// Histogram of costs of the TCP stack's polls, could be read while `Tun` is running
+#[derive(Debug, Clone, Default)]
+pub struct TcpPollStat {
+    inner: Arc<TcpPollStatInner>,
+}
+
+impl TcpPollStat {
+    pub fn new() -> TcpPollStat {
+        TcpPollStat::default()
+    }
+
+    pub(crate) fn record(&self, cost: Duration) {
+        let micros = u64::try_from(cost.as_micros()).unwrap_or(u64::MAX);
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(POLL_COST_BUCKETS - 1);
+
+        self.inner.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.inner.polls.fetch_add(1, Ordering::Relaxed);
+        self.inner.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.inner.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_serviced(&self, sockets: usize, swept: usize) {
+        self.inner.serviced.fetch_add(sockets as u64, Ordering::Relaxed);
+        self.inner.max_serviced.fetch_max(sockets as u64, Ordering::Relaxed);
+        self.inner.swept.fetch_add(swept as u64, Ordering::Relaxed);
+    }
+
+    /// Total sockets checked after polls
+    pub fn sockets_serviced(&self) -> u64 {
+        self.inner.serviced.load(Ordering::Relaxed)
+    }
+
+    /// Most sockets checked after a single poll
+    pub fn max_sockets_serviced(&self) -> u64 {
+        self.inner.max_serviced.load(Ordering::Relaxed)
+    }
+
+    /// Sockets checked by the periodic full sweeps
+    pub fn sockets_swept(&self) -> u64 {
+        self.inner.swept.load(Ordering::Relaxed)
+    }
+
+    /// Total polls recorded
+    pub fn polls(&self) -> u64 {
+        self.inner.polls.load(Ordering::Relaxed)
+    }
+
+    /// Cost of the slowest poll
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.inner.max_micros.load(Ordering::Relaxed))
+    }
+
+    /// Average cost of polls
+    pub fn avg(&self) -> Duration {
+        match self.polls() {
+            0 => Duration::ZERO,
+            polls => Duration::from_micros(self.inner.total_micros.load(Ordering::Relaxed) / polls),
+        }
+    }
+
+    /// Cost that `quantile` (0.0 to 1.0) of polls are below, rounded up to the bucket's upper bound
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let buckets = self.buckets();
+        let polls = buckets.iter().map(|(_, n)| n).sum::<u64>();
+        if polls == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((polls as f64 * quantile).ceil() as u64).clamp(1, polls);
+        let mut seen = 0;
+        for (upper_bound, n) in buckets {
+            seen += n;
+            if seen >= rank {
+                // Bounds of long buckets are far above the actual costs
+                return upper_bound.min(self.max());
+            }
+        }
+        self.max()
+    }
+
+    /// Cost that 99% of polls are below
+    pub fn p99(&self) -> Duration {
+        self.percentile(0.99)
+    }
+
+    /// Upper bounds and counts of the histogram's buckets, the last bucket also counts all longer polls
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.inner
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (Duration::from_micros(1 << i), n.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Sink of every poll's timing, for wiring it into external telemetry
+///
+/// Called on the manager thread after every poll without holding any lock, a slow callback delays the TCP stack.
+pub trait PollMetrics: Send + Sync {
+    /// `duration` is the cost of the poll, `updated` is whether it changed any socket, `socket_count` is the number
+    /// of sockets managed by the stack
+    fn on_poll(&self, duration: Duration, updated: bool, socket_count: usize);
+}
+
+/// `PollMetrics` sink handed over to the manager thread, which picks it up after it is changed
+#[derive(Default)]
+pub(crate) struct SharedPollMetrics {
+    changed: AtomicBool,
+    metrics: SpinMutex<Option<Arc<dyn PollMetrics>>>,
+}
+
+impl SharedPollMetrics {
+    pub(crate) fn set(&self, metrics: Option<Arc<dyn PollMetrics>>) {
+        *self.metrics.lock() = metrics;
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// The new sink if it was changed since the last call, the lock is only taken then
+    pub(crate) fn take_changed(&self) -> Option<Option<Arc<dyn PollMetrics>>> {
+        if self.changed.load(Ordering::Relaxed) && self.changed.swap(false, Ordering::Acquire) {
+            Some(self.metrics.lock().clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_summary() {
+        let stat = TcpPollStat::new();
+        assert_eq!(stat.p99(), Duration::ZERO);
+        assert_eq!(stat.avg(), Duration::ZERO);
+
+        for _ in 0..98 {
+            stat.record(Duration::from_micros(3));
+        }
+        stat.record(Duration::from_micros(100));
+        stat.record(Duration::from_millis(10));
+
+        assert_eq!(stat.polls(), 100);
+        assert_eq!(stat.max(), Duration::from_millis(10));
+        assert_eq!(stat.avg(), Duration::from_micros((98 * 3 + 100 + 10_000) / 100));
+        // 3us is in [2us, 4us)
+        assert_eq!(stat.percentile(0.5), Duration::from_micros(4));
+        // 100us is in [64us, 128us)
+        assert_eq!(stat.p99(), Duration::from_micros(128));
+        assert_eq!(stat.percentile(1.0), Duration::from_millis(10));
+
+        let buckets = stat.buckets();
+        assert_eq!(buckets.len(), POLL_COST_BUCKETS);
+        assert_eq!(buckets[2], (Duration::from_micros(4), 98));
+        assert_eq!(buckets.iter().map(|(_, n)| n).sum::<u64>(), 100);
+    }
+}
@@ -0,0 +1,197 @@
+//! PROXY protocol headers carrying endpoints of clients to upstreams
+//!
+//! Upstreams only see the address of the outbound connection. Servers logging or authorizing by the client's address
+//! could read the client's endpoints from a header written before the client's data, which is defined in
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::local::utils::to_ipv4_mapped;
+
+/// Signature of v2 headers
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2 with the PROXY command, endpoints are carried
+const PROXY_V2_COMMAND_PROXY: u8 = 0x21;
+/// Version 2 with the LOCAL command, upstreams use endpoints of the connection itself
+const PROXY_V2_COMMAND_LOCAL: u8 = 0x20;
+
+/// Address family and transport protocol of v2 headers
+const PROXY_V2_UNSPEC: u8 = 0x00;
+const PROXY_V2_TCP4: u8 = 0x11;
+const PROXY_V2_TCP6: u8 = 0x21;
+
+/// Version of PROXY protocol headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// Human readable header, a line of text
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl Display for ProxyProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyProtocolVersion::V1 => f.write_str("v1"),
+            ProxyProtocolVersion::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+/// Error while parsing `ProxyProtocolVersion` from string
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyProtocolVersionError;
+
+impl Display for ProxyProtocolVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ProxyProtocolVersion")
+    }
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = ProxyProtocolVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(ProxyProtocolVersion::V1),
+            "v2" => Ok(ProxyProtocolVersion::V2),
+            _ => Err(ProxyProtocolVersionError),
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are converted to IPv4, so they match endpoints of the other side
+fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(ref a) => match to_ipv4_mapped(a.ip()) {
+            Some(v4) => SocketAddr::new(IpAddr::from(v4), a.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    }
+}
+
+/// Build the header of a TCP connection from `src_addr` to `dst_addr`
+///
+/// A header can't carry endpoints of different address families, it falls back to `UNKNOWN` in v1 and the LOCAL
+/// command in v2, so upstreams use endpoints of the outbound connection as if there were no header.
+pub fn build_proxy_protocol_header(
+    version: ProxyProtocolVersion,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+) -> Vec<u8> {
+    let src_addr = unmap_addr(src_addr);
+    let dst_addr = unmap_addr(dst_addr);
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let header = match (src_addr, dst_addr) {
+                (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                    format!("PROXY TCP4 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port())
+                }
+                (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+                    format!("PROXY TCP6 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port())
+                }
+                _ => "PROXY UNKNOWN\r\n".to_owned(),
+            };
+            header.into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = PROXY_V2_SIGNATURE.to_vec();
+            let mut addrs = Vec::with_capacity(36);
+            let (command, family) = match (src_addr, dst_addr) {
+                (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                    addrs.extend_from_slice(&src.ip().octets());
+                    addrs.extend_from_slice(&dst.ip().octets());
+                    (PROXY_V2_COMMAND_PROXY, PROXY_V2_TCP4)
+                }
+                (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+                    addrs.extend_from_slice(&src.ip().octets());
+                    addrs.extend_from_slice(&dst.ip().octets());
+                    (PROXY_V2_COMMAND_PROXY, PROXY_V2_TCP6)
+                }
+                _ => (PROXY_V2_COMMAND_LOCAL, PROXY_V2_UNSPEC),
+            };
+            if command == PROXY_V2_COMMAND_PROXY {
+                addrs.extend_from_slice(&src_addr.port().to_be_bytes());
+                addrs.extend_from_slice(&dst_addr.port().to_be_bytes());
+            }
+
+            header.push(command);
+            header.push(family);
+            header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            header
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_proxy_protocol_version() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            assert_eq!(version.to_string().parse::<ProxyProtocolVersion>().unwrap(), version);
+        }
+        assert!("v3".parse::<ProxyProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn ipv4_client_header() {
+        let src_addr = "192.0.2.10:40000".parse::<SocketAddr>().unwrap();
+        let dst_addr = "198.51.100.1:443".parse::<SocketAddr>().unwrap();
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V1, src_addr, dst_addr);
+        assert_eq!(header, b"PROXY TCP4 192.0.2.10 198.51.100.1 40000 443\r\n");
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V2, src_addr, dst_addr);
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        expected.extend_from_slice(&[192, 0, 2, 10, 198, 51, 100, 1]);
+        expected.extend_from_slice(&[0x9C, 0x40, 0x01, 0xBB]);
+        assert_eq!(header, expected);
+
+        // Mapped destination of a dual-stack client
+        let mapped_dst_addr = "[::ffff:198.51.100.1]:443".parse::<SocketAddr>().unwrap();
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V1, src_addr, mapped_dst_addr);
+        assert_eq!(header, b"PROXY TCP4 192.0.2.10 198.51.100.1 40000 443\r\n");
+    }
+
+    #[test]
+    fn ipv6_client_header() {
+        let src_addr = "[2001:db8::10]:40000".parse::<SocketAddr>().unwrap();
+        let dst_addr = "[2001:db8:1::1]:443".parse::<SocketAddr>().unwrap();
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V1, src_addr, dst_addr);
+        assert_eq!(header, b"PROXY TCP6 2001:db8::10 2001:db8:1::1 40000 443\r\n");
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V2, src_addr, dst_addr);
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0D, 0xB8, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        expected.extend_from_slice(&[0x9C, 0x40, 0x01, 0xBB]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn mixed_families_header() {
+        let src_addr = "[2001:db8::10]:40000".parse::<SocketAddr>().unwrap();
+        let dst_addr = "198.51.100.1:443".parse::<SocketAddr>().unwrap();
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V1, src_addr, dst_addr);
+        assert_eq!(header, b"PROXY UNKNOWN\r\n");
+
+        let header = build_proxy_protocol_header(ProxyProtocolVersion::V2, src_addr, dst_addr);
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(header, expected);
+    }
+}
@@ -0,0 +1,204 @@
+//! Scheduling sends of TCP connections in the TUN device's manager
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    hash::Hash,
+    str::FromStr,
+};
+
+/// Default quantum of `DeficitRoundRobin`, about one MTU
+pub const DEFAULT_SCHEDULER_QUANTUM: usize = 1500;
+
+/// Policy of serving TCP connections' pending data when the device is the bottleneck
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpSchedulerPolicy {
+    /// Serve connections in arbitrary order, each sends as much as possible
+    Unordered,
+    /// Deficit round-robin, each backlogged connection sends `quantum` bytes in its turn
+    DeficitRoundRobin { quantum: usize },
+}
+
+impl Display for TcpSchedulerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TcpSchedulerPolicy::Unordered => f.write_str("unordered"),
+            TcpSchedulerPolicy::DeficitRoundRobin { .. } => f.write_str("drr"),
+        }
+    }
+}
+
+/// Error while parsing `TcpSchedulerPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSchedulerPolicyError;
+
+impl Display for TcpSchedulerPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid TcpSchedulerPolicy")
+    }
+}
+
+impl FromStr for TcpSchedulerPolicy {
+    type Err = TcpSchedulerPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unordered" => Ok(TcpSchedulerPolicy::Unordered),
+            "drr" => Ok(TcpSchedulerPolicy::DeficitRoundRobin {
+                quantum: DEFAULT_SCHEDULER_QUANTUM,
+            }),
+            _ => Err(TcpSchedulerPolicyError),
+        }
+    }
+}
+
+/// Decides the order of serving connections and how many bytes each one could send
+pub(crate) enum SendScheduler<K> {
+    Unordered,
+    DeficitRoundRobin(DeficitRoundRobin<K>),
+}
+
+impl<K: Copy + Eq + Hash> SendScheduler<K> {
+    pub fn new(policy: TcpSchedulerPolicy) -> SendScheduler<K> {
+        match policy {
+            TcpSchedulerPolicy::Unordered => SendScheduler::Unordered,
+            TcpSchedulerPolicy::DeficitRoundRobin { quantum } => {
+                SendScheduler::DeficitRoundRobin(DeficitRoundRobin::new(quantum))
+            }
+        }
+    }
+
+    /// A new connection is created
+    pub fn add(&mut self, key: K) {
+        if let SendScheduler::DeficitRoundRobin(ref mut drr) = *self {
+            drr.add(key);
+        }
+    }
+
+    /// A connection is removed
+    pub fn remove(&mut self, key: &K) {
+        if let SendScheduler::DeficitRoundRobin(ref mut drr) = *self {
+            drr.remove(key);
+        }
+    }
+
+    /// Order of connections in `keys` to be served in this round
+    pub fn order(&self, keys: &HashSet<K>) -> Vec<K> {
+        match *self {
+            SendScheduler::Unordered => keys.iter().copied().collect(),
+            SendScheduler::DeficitRoundRobin(ref drr) => {
+                drr.queue.iter().filter(|k| keys.contains(*k)).copied().collect()
+            }
+        }
+    }
+
+    /// Number of bytes that `key` could send now
+    pub fn budget(&self, key: &K) -> usize {
+        match *self {
+            SendScheduler::Unordered => usize::MAX,
+            SendScheduler::DeficitRoundRobin(ref drr) => drr.budget(key),
+        }
+    }
+
+    /// `key` has sent `n` bytes, and whether it still has pending data
+    pub fn served(&mut self, key: K, n: usize, backlogged: bool) {
+        if let SendScheduler::DeficitRoundRobin(ref mut drr) = *self {
+            drr.served(key, n, backlogged);
+        }
+    }
+}
+
+/// Deficit round-robin
+///
+/// A connection keeps its place in the queue until it has used its deficit or has nothing more to send, so
+/// connections that were blocked by a full device will be served first in the next round.
+pub(crate) struct DeficitRoundRobin<K> {
+    quantum: usize,
+    queue: VecDeque<K>,
+    deficits: HashMap<K, usize>,
+}
+
+impl<K: Copy + Eq + Hash> DeficitRoundRobin<K> {
+    fn new(quantum: usize) -> DeficitRoundRobin<K> {
+        DeficitRoundRobin {
+            quantum: quantum.max(1),
+            queue: VecDeque::new(),
+            deficits: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, key: K) {
+        if self.deficits.insert(key, self.quantum).is_none() {
+            self.queue.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.deficits.remove(key).is_some() {
+            self.queue.retain(|k| k != key);
+        }
+    }
+
+    fn budget(&self, key: &K) -> usize {
+        self.deficits.get(key).copied().unwrap_or(0)
+    }
+
+    fn served(&mut self, key: K, n: usize, backlogged: bool) {
+        let deficit = match self.deficits.get_mut(&key) {
+            Some(d) => d,
+            None => return,
+        };
+        *deficit = deficit.saturating_sub(n);
+
+        // Turn ends, move to the back of the queue
+        if !backlogged || *deficit == 0 {
+            *deficit = if backlogged {
+                *deficit + self.quantum
+            } else {
+                self.quantum
+            };
+
+            if let Some(pos) = self.queue.iter().position(|k| *k == key) {
+                self.queue.remove(pos);
+            }
+            self.queue.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drr_fair_under_bottleneck() {
+        const FLOWS: usize = 50;
+        const QUANTUM: usize = 1500;
+        // Device could only take a little more than 8 flows' quantum in a round
+        const DEVICE_CAPACITY: usize = 12_345;
+
+        let mut scheduler = SendScheduler::new(TcpSchedulerPolicy::DeficitRoundRobin { quantum: QUANTUM });
+        let mut served = HashMap::new();
+        for key in 0..FLOWS {
+            scheduler.add(key);
+            served.insert(key, 0usize);
+        }
+
+        for _ in 0..1000 {
+            let mut capacity = DEVICE_CAPACITY;
+            for key in scheduler.order(&served.keys().copied().collect()) {
+                let n = scheduler.budget(&key).min(capacity);
+                capacity -= n;
+                *served.get_mut(&key).unwrap() += n;
+                scheduler.served(key, n, true);
+            }
+        }
+
+        let total = served.values().sum::<usize>();
+        assert_eq!(total, DEVICE_CAPACITY * 1000);
+
+        let max = *served.values().max().unwrap();
+        let min = *served.values().min().unwrap();
+        assert!(max - min <= QUANTUM, "max {} min {}", max, min);
+    }
+}
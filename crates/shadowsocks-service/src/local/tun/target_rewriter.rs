@@ -0,0 +1,24 @@
+//! Customizable rewriting of TCP connections' targets
+
+use std::net::SocketAddr;
+
+use shadowsocks::relay::socks5::Address;
+
+/// Rewrites the target of a TCP connection intercepted from tun, before connecting to it
+///
+/// Set with `TunBuilder::tcp_target_rewriter`, for example to map addresses of a fake-IP DNS pool back to their domain
+/// names. The rewritten target is still replaced by the sniffed hostname if sniffing is enabled.
+pub trait TargetRewriter: Send + Sync {
+    /// Rewrite `dst`, the destination address of the intercepted connection
+    fn rewrite(&self, dst: SocketAddr) -> Address;
+}
+
+/// Keeps the destination address as the target, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityTargetRewriter;
+
+impl TargetRewriter for IdentityTargetRewriter {
+    fn rewrite(&self, dst: SocketAddr) -> Address {
+        Address::from(dst)
+    }
+}
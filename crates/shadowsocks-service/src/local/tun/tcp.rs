@@ -1,505 +1,7957 @@
+#[cfg(feature = "local-audit")]
+use std::time::SystemTime;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
     io::{self, ErrorKind},
     mem,
     net::{IpAddr, SocketAddr},
+    ops::Range,
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     task::{Context, Poll, Waker},
     thread::{self, JoinHandle, Thread},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use log::{error, trace};
-use shadowsocks::{net::TcpSocketOpts, relay::socks5::Address};
+use futures::{future::poll_fn, ready};
+use log::{debug, error, trace, warn};
+use shadowsocks::{
+    config::ResolveStrategy,
+    dns_resolver::DnsResolver,
+    net::{ConnectOpts, TcpSocketOpts},
+    relay::socks5::Address,
+    ServerAddr,
+};
 use smoltcp::{
-    iface::{Interface, InterfaceBuilder, Routes, SocketHandle},
-    phy::{DeviceCapabilities, Medium},
-    socket::{TcpSocket, TcpSocketBuffer, TcpState},
+    iface::{Interface, InterfaceBuilder, Route, Routes, SocketHandle},
+    phy::{Device, DeviceCapabilities, Medium},
+    socket::{Socket, TcpSocket, TcpSocketBuffer, TcpState},
     storage::RingBuffer,
     time::{Duration as SmolDuration, Instant as SmolInstant},
-    wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address, TcpPacket},
+    wire::{IpAddress, IpCidr, IpEndpoint, IpProtocol, Ipv4Address, Ipv6Address, TcpPacket},
+    Error as SmolError,
 };
 use spin::Mutex as SpinMutex;
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
-    sync::mpsc,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{broadcast, mpsc},
+    task::JoinHandle as TaskJoinHandle,
+    time,
 };
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::{establish_tcp_tunnel, to_ipv4_mapped},
+#[cfg(feature = "local-audit")]
+use crate::local::audit::AuditRecord;
+#[cfg(feature = "local-flight-recorder")]
+use crate::local::flight_recorder::{FlightEventKind, FlightProtocol};
+use crate::{
+    acl::AddressRules,
+    local::{
+        context::ServiceContext,
+        loadbalancing::{
+            AvailableServerSelector,
+            PingBalancer,
+            ServerHealthEvent,
+            ServerHealthTransition,
+            ServerIdent,
+            ServerSelectContext,
+            ServerSelector,
+            ServerType,
+        },
+        net::{sniff_host, AutoProxyClientStream, AutoProxyIo, PrefixedStream, SniffConfig, DEFAULT_SNIFF_TIMEOUT},
+        utils::{
+            bypassed_tcp_tunnel_summary,
+            establish_tcp_tunnel,
+            is_first_byte_timeout,
+            to_ipv4_mapped,
+            TcpTunnelSummary,
+        },
+        LOCAL_DEFAULT_KEEPALIVE_TIMEOUT,
+    },
+    net::clock::{Clock, SharedClock, SystemClock},
 };
 
-use super::virt_device::VirtTunDevice;
+#[cfg(feature = "local-tun-capture")]
+use super::frame_capture::{FrameCapture, FrameDirection};
+use super::{
+    bandwidth::{BandwidthDirection, TcpBandwidthLimit},
+    conn_rate::{TcpConnRateLimit, TcpConnRateStat},
+    early_data::TcpEarlyDataPolicy,
+    events::{TunEvent, TunEventKind, TunEventSender},
+    flow_affinity::FlowAffinity,
+    ip_packet::IpPacket,
+    mss_clamp::TcpMssClamp,
+    mtu_blackhole::MtuBlackholeDetector,
+    poll_stat::{PollMetrics, SharedPollMetrics, TcpPollStat},
+    proxy_protocol::{build_proxy_protocol_header, ProxyProtocolVersion},
+    scheduler::{SendScheduler, TcpSchedulerPolicy},
+    target_rewriter::{IdentityTargetRewriter, TargetRewriter},
+    virt_device::{TunDeviceStat, VirtTunDevice},
+};
 
 // NOTE: Default buffer could contain 20 AEAD packets
 const DEFAULT_TCP_SEND_BUFFER_SIZE: u32 = 0x3FFF * 20;
 const DEFAULT_TCP_RECV_BUFFER_SIZE: u32 = 0x3FFF * 20;
 
-struct TcpSocketControl {
-    send_buffer: RingBuffer<'static, u8>,
-    send_waker: Option<Waker>,
-    recv_buffer: RingBuffer<'static, u8>,
-    recv_waker: Option<Waker>,
-    is_closed: bool,
-}
+// Segment size for sizing buffers of `TcpSocketOpts::initial_window`, TCP over Ethernet
+const TCP_INITIAL_WINDOW_SEGMENT_SIZE: u32 = 1460;
 
-struct ManagerNotify {
-    thread: Thread,
-}
+const TCP_LISTEN_MAX_ATTEMPTS: usize = 3;
 
-impl ManagerNotify {
-    fn new(thread: Thread) -> ManagerNotify {
-        ManagerNotify { thread }
-    }
+/// Default timeout of TCP connections without data in both directions, Linux's default keep-alive time
+pub const DEFAULT_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(7200);
 
-    fn notify(&self) {
-        self.thread.unpark();
-    }
-}
+// Interval of checking stalled connections, while they are waiting to be reset
+const TCP_STALL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
-struct TcpSocketManager {
-    iface: Interface<'static, VirtTunDevice>,
-    sockets: HashMap<SocketHandle, SharedTcpConnectionControl>,
-    socket_creation_rx: mpsc::UnboundedReceiver<TcpSocketCreation>,
-}
+// Interval of checking all sockets, for changes driven by smoltcp's timers instead of frames or relay tasks
+const TCP_FULL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
-type SharedTcpConnectionControl = Arc<SpinMutex<TcpSocketControl>>;
+/// Sockets serviced in each round at most, the others are deferred to the next round, which starts without waiting.
+/// A round under heavy load is bounded, instead of holding frames and other connections until all sockets are done.
+const TCP_MAX_SERVICED_SOCKETS: usize = 1024;
 
-struct TcpSocketCreation {
-    control: SharedTcpConnectionControl,
-    socket: TcpSocket<'static>,
+/// Sockets checked by a full sweep in each round. Sweeps of many sockets are spread across rounds, frames and changed
+/// sockets are handled between the chunks instead of waiting for the whole sweep.
+const TCP_SWEEP_CHUNK_SIZE: usize = 256;
+
+/// Free space of a send buffer that writes wait for, unless they are smaller or the buffer is
+const TCP_SEND_LOW_WATERMARK: usize = 4096;
+
+/// Buffer sizes of TCP connections to destinations matching `rules`
+///
+/// Sizes that are `None` fall back to the global `AcceptOpts`.
+#[derive(Debug, Clone)]
+pub struct TcpBufferProfile {
+    rules: AddressRules,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
 }
 
-struct TcpConnection {
-    control: SharedTcpConnectionControl,
-    manager_notify: Arc<ManagerNotify>,
+impl TcpBufferProfile {
+    pub fn new(rules: AddressRules, send_buffer_size: Option<u32>, recv_buffer_size: Option<u32>) -> TcpBufferProfile {
+        TcpBufferProfile {
+            rules,
+            send_buffer_size,
+            recv_buffer_size,
+        }
+    }
 }
 
-impl Drop for TcpConnection {
-    fn drop(&mut self) {
-        let mut control = self.control.lock();
-        control.is_closed = true;
+/// Socket options for a connection to `dst_addr`, overridden by the first matched profile
+fn profile_tcp_opts(profiles: &[TcpBufferProfile], tcp_opts: &TcpSocketOpts, dst_addr: SocketAddr) -> TcpSocketOpts {
+    let mut tcp_opts = tcp_opts.clone();
+    if let Some(profile) = profiles.iter().find(|p| p.rules.check_ip_matched(&dst_addr.ip())) {
+        if let Some(size) = profile.send_buffer_size {
+            tcp_opts.send_buffer_size = Some(size);
+        }
+        if let Some(size) = profile.recv_buffer_size {
+            tcp_opts.recv_buffer_size = Some(size);
+        }
     }
+    tcp_opts
 }
 
-impl TcpConnection {
-    fn new(
-        socket: TcpSocket<'static>,
-        socket_creation_tx: &mpsc::UnboundedSender<TcpSocketCreation>,
-        manager_notify: Arc<ManagerNotify>,
-        tcp_opts: &TcpSocketOpts,
-    ) -> TcpConnection {
-        let send_buffer_size = tcp_opts.send_buffer_size.unwrap_or(DEFAULT_TCP_SEND_BUFFER_SIZE);
-        let recv_buffer_size = tcp_opts.recv_buffer_size.unwrap_or(DEFAULT_TCP_RECV_BUFFER_SIZE);
+/// Keep-alive of connections, `default_keepalive` is used if `tcp_opts` doesn't set one
+///
+/// Without keep-alive, connections of dead clients are kept until smoltcp's 7200s timeout.
+fn resolve_keepalive(tcp_opts: &TcpSocketOpts, default_keepalive: Option<Duration>) -> Option<Duration> {
+    tcp_opts.keepalive.or(default_keepalive)
+}
+
+struct TcpSocketControl {
+    send_buffer: RingBuffer<'static, u8>,
+    send_waker: Option<Waker>,
+    recv_buffer: RingBuffer<'static, u8>,
+    recv_waker: Option<Waker>,
+    shutdown_waker: Option<Waker>,
+    flush_waker: Option<Waker>,
+    is_closed: bool,
+    // Tunnel failed, RST is sent to client instead of FIN after the connection is dropped
+    reset_on_close: bool,
+    // Remote finished sending, FIN is sent to client after `send_buffer` is flushed
+    send_shutdown: bool,
+    // FIN to client is queued in smoltcp's socket, data from client could still be received
+    fin_queued: bool,
+    // Client acknowledged all data from remote and the FIN, shutdown is finished
+    fin_acked: bool,
+    // Client finished sending, reads return EOF after `recv_buffer` is drained
+    recv_eof: bool,
+    socket_info: TcpSocketInfo,
+    // Reset the connection if both buffers are full without progress for this long
+    stall_timeout: Option<Duration>,
+    stalled_since: Option<Instant>,
+    // Reset the connection if no data is sent or received for this long
+    idle_timeout: Option<Duration>,
+    last_active: Instant,
+    // Reset the connection if data is pending without progress in both directions for this long
+    dead_peer_timeout: Option<Duration>,
+    dead_peer_since: Option<Instant>,
+    // Relay task moved data since the manager checked the last time
+    relay_progressed: bool,
+    // Connection is in the manager's dirty set since the manager checked it the last time
+    marked_dirty: bool,
+    // Wake the manager after every write to `send_buffer`, instead of after it is full
+    nodelay: bool,
+    // Bytes in smoltcp's send queue when the manager checked the last time, shrunk by client's acknowledgements
+    last_send_queue: usize,
+    // Reset because a peer stopped responding, pending I/Os fail instead of finishing
+    dead_peer: bool,
+    // Torn down abnormally, pending I/Os fail with this instead of finishing with EOF
+    close_error: Option<ErrorKind>,
+    // Pending reads and writes of the relay fail if they make no progress for this long
+    read_timeout: Option<Duration>,
+    read_timer: Option<Pin<Box<time::Sleep>>>,
+    write_timeout: Option<Duration>,
+    write_timer: Option<Pin<Box<time::Sleep>>>,
+    // Counts the socket in `TcpTun` until both the manager and the relay task dropped it
+    live_socket: Option<TcpLiveSocketGuard>,
+    // Grow `recv_buffer` up to this size while it is the bottleneck, disabled if not set
+    recv_buffer_max: Option<usize>,
+    // Relay read from `recv_buffer` since the manager found it full
+    recv_dequeued: bool,
+    // Bytes moved in each direction in a round of the manager, the rest is moved in the next rounds
+    round_budget: Option<usize>,
+    // Bandwidth of the connection, and of all connections
+    bandwidth_limit: Option<TcpBandwidthLimit>,
+    global_bandwidth_limit: Option<Arc<SpinMutex<TcpBandwidthLimit>>>,
+    // Bytes read from client and written to client
+    #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+    relayed_tx: u64,
+    #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+    relayed_rx: u64,
+}
 
-        let control = Arc::new(SpinMutex::new(TcpSocketControl {
+impl TcpSocketControl {
+    fn new(send_buffer_size: u32, recv_buffer_size: u32) -> TcpSocketControl {
+        TcpSocketControl {
             send_buffer: RingBuffer::new(vec![0u8; send_buffer_size as usize]),
             send_waker: None,
             recv_buffer: RingBuffer::new(vec![0u8; recv_buffer_size as usize]),
             recv_waker: None,
+            shutdown_waker: None,
+            flush_waker: None,
             is_closed: false,
-        }));
+            reset_on_close: false,
+            send_shutdown: false,
+            fin_queued: false,
+            fin_acked: false,
+            recv_eof: false,
+            socket_info: TcpSocketInfo::new(),
+            stall_timeout: None,
+            stalled_since: None,
+            idle_timeout: None,
+            last_active: Instant::now(),
+            dead_peer_timeout: None,
+            dead_peer_since: None,
+            relay_progressed: false,
+            marked_dirty: false,
+            nodelay: false,
+            last_send_queue: 0,
+            dead_peer: false,
+            close_error: None,
+            read_timeout: None,
+            read_timer: None,
+            write_timeout: None,
+            write_timer: None,
+            live_socket: None,
+            recv_buffer_max: None,
+            recv_dequeued: false,
+            round_budget: None,
+            bandwidth_limit: None,
+            global_bandwidth_limit: None,
+            #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+            relayed_tx: 0,
+            #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+            relayed_rx: 0,
+        }
+    }
 
-        let _ = socket_creation_tx.send(TcpSocketCreation {
-            control: control.clone(),
-            socket,
-        });
+    /// Resize the buffer of data from remote to client, queued data is kept
+    fn resize_send_buffer(&mut self, size: usize) -> bool {
+        if !resize_ring_buffer(&mut self.send_buffer, size) {
+            return false;
+        }
 
-        TcpConnection {
-            control,
-            manager_notify,
+        // Writer may be waiting for space
+        if let Some(waker) = self.send_waker.take() {
+            waker.wake();
         }
+        true
     }
-}
 
-impl AsyncRead for TcpConnection {
-    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        let mut control = self.control.lock();
+    /// Resize the buffer of data from client to remote, queued data is kept
+    fn resize_recv_buffer(&mut self, size: usize) -> bool {
+        if !resize_ring_buffer(&mut self.recv_buffer, size) {
+            return false;
+        }
 
-        // If socket is already closed, just return EOF directly.
-        if control.is_closed {
-            return Ok(()).into();
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Bytes that could be moved in `direction` now by the connection's and the global bandwidth limits
+    fn bandwidth_allowance(&mut self, direction: BandwidthDirection, now: Instant) -> usize {
+        let mut allowance = usize::MAX;
+        if let Some(ref mut limit) = self.bandwidth_limit {
+            allowance = allowance.min(limit.available(direction, now));
+        }
+        if let Some(ref limit) = self.global_bandwidth_limit {
+            allowance = allowance.min(limit.lock().available(direction, now));
         }
+        allowance
+    }
 
-        // Read from buffer
+    /// `n` bytes are moved in `direction`, taken from all bandwidth limits
+    fn consume_bandwidth(&mut self, direction: BandwidthDirection, n: usize) {
+        if let Some(ref mut limit) = self.bandwidth_limit {
+            limit.consume(direction, n);
+        }
+        if let Some(ref limit) = self.global_bandwidth_limit {
+            limit.lock().consume(direction, n);
+        }
+    }
 
-        if control.recv_buffer.is_empty() {
-            // Nothing could be read. Wait for notify.
-            if let Some(old_waker) = control.recv_waker.replace(cx.waker().clone()) {
-                if !old_waker.will_wake(cx.waker()) {
-                    old_waker.wake();
-                }
-            }
+    /// Time until all bandwidth limits of `direction` are refilled enough to move data again
+    fn bandwidth_refill_delay(&self, direction: BandwidthDirection) -> Duration {
+        let mut delay = Duration::ZERO;
+        if let Some(ref limit) = self.bandwidth_limit {
+            delay = delay.max(limit.refill_delay(direction));
+        }
+        if let Some(ref limit) = self.global_bandwidth_limit {
+            delay = delay.max(limit.lock().refill_delay(direction));
+        }
+        delay
+    }
 
-            return Poll::Pending;
+    /// Double the buffer of data from client up to `recv_buffer_max`, called when the manager found it full
+    ///
+    /// It only grows if the relay read from it since the last time, the client sends faster than the buffer could hold
+    /// between relay's reads. Otherwise the remote is the bottleneck, a larger buffer only holds more data. smoltcp's
+    /// buffer is fixed once created, but it is drained into this buffer, so the window advertised to the client is
+    /// kept open.
+    fn autotune_recv_buffer(&mut self) -> bool {
+        let recv_buffer_max = match self.recv_buffer_max {
+            Some(m) => m,
+            None => return false,
+        };
+        if !mem::take(&mut self.recv_dequeued) {
+            return false;
         }
 
-        let recv_buf = unsafe { mem::transmute::<_, &mut [u8]>(buf.unfilled_mut()) };
-        let n = control.recv_buffer.dequeue_slice(recv_buf);
-        buf.advance(n);
+        let capacity = self.recv_buffer.capacity();
+        let size = capacity.saturating_mul(2).min(recv_buffer_max);
+        size > capacity && self.resize_recv_buffer(size)
+    }
 
-        if control.recv_buffer.is_empty() {
-            self.manager_notify.notify();
+    /// Move data from client to `buf`, returns the bytes moved
+    ///
+    /// Copied directly from the ring buffer's contiguous parts, so `buf`'s uninitialized memory is never exposed.
+    fn dequeue_recv(&mut self, buf: &mut ReadBuf<'_>) -> usize {
+        let mut n = 0;
+        // At most twice, if the queued data wraps around the end
+        while buf.remaining() > 0 && !self.recv_buffer.is_empty() {
+            let (size, ()) = self.recv_buffer.dequeue_many_with(|data| {
+                let size = data.len().min(buf.remaining());
+                buf.put_slice(&data[..size]);
+                (size, ())
+            });
+            n += size;
         }
-        Ok(()).into()
+        self.record_recv_dequeued(n);
+        n
     }
-}
 
-impl AsyncWrite for TcpConnection {
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let mut control = self.control.lock();
-        if control.is_closed {
-            return Err(io::ErrorKind::BrokenPipe.into()).into();
+    /// Record `n` bytes from client moved out of the receive buffer
+    fn record_recv_dequeued(&mut self, n: usize) {
+        self.recv_dequeued |= n > 0;
+
+        #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+        {
+            self.relayed_tx += n as u64;
         }
+    }
 
-        // Write to buffer
+    /// Check whether there is data from client to move out, `Ok(false)` if it's EOF
+    ///
+    /// Waits for notify from the manager if nothing could be moved now.
+    fn poll_recv_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        // If socket is already closed, just return EOF directly.
+        if self.is_closed {
+            if let Some(err) = self.close_error() {
+                return Err(err).into();
+            }
+            return Ok(false).into();
+        }
 
-        if control.send_buffer.is_full() {
-            if let Some(old_waker) = control.send_waker.replace(cx.waker().clone()) {
-                if !old_waker.will_wake(cx.waker()) {
-                    old_waker.wake();
-                }
+        if self.recv_buffer.is_empty() {
+            // Client half-closed, EOF after all its data was read
+            if self.recv_eof {
+                return Ok(false).into();
             }
 
-            return Poll::Pending;
+            // Nothing could be read. Wait for notify.
+            register_waker(&mut self.recv_waker, cx);
+
+            return TcpSocketControl::poll_timeout(cx, self.read_timeout, &mut self.read_timer, "read");
         }
 
-        let n = control.send_buffer.enqueue_slice(buf);
+        self.read_timer = None;
+        Ok(true).into()
+    }
 
-        if control.send_buffer.is_full() {
-            self.manager_notify.notify();
+    /// Check whether there is space for `wanted` bytes of data to client
+    ///
+    /// Waits for notify from the manager if the send buffer has less free space.
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        if let Some(err) = self.close_error() {
+            return Err(err).into();
+        }
+        if self.is_closed || self.send_shutdown {
+            return Err(io::ErrorKind::BrokenPipe.into()).into();
+        }
+
+        if self.send_buffer.window() < wanted {
+            register_waker(&mut self.send_waker, cx);
+
+            return TcpSocketControl::poll_timeout(cx, self.write_timeout, &mut self.write_timer, "write");
         }
-        Ok(n).into()
-    }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write_timer = None;
         Ok(()).into()
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let mut control = self.control.lock();
+    /// Fail a pending I/O with `TimedOut` once it made no progress for `timeout`, otherwise it keeps waiting
+    ///
+    /// `timer` starts when the I/O first goes pending and wakes it up at the deadline, callers drop it on progress.
+    fn poll_timeout<T>(
+        cx: &mut Context<'_>,
+        timeout: Option<Duration>,
+        timer: &mut Option<Pin<Box<time::Sleep>>>,
+        op: &str,
+    ) -> Poll<io::Result<T>> {
+        let timeout = match timeout {
+            Some(t) => t,
+            None => return Poll::Pending,
+        };
 
-        if control.is_closed {
-            return Ok(()).into();
-        }
+        let sleep = timer.get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        ready!(sleep.as_mut().poll(cx));
+        *timer = None;
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("{} made no progress in {:?}", op, timeout),
+        ))
+        .into()
+    }
 
-        control.is_closed = true;
-        if let Some(old_waker) = control.send_waker.replace(cx.waker().clone()) {
-            if !old_waker.will_wake(cx.waker()) {
-                old_waker.wake();
-            }
+    /// Mark the socket closed by the manager, pending reads, writes and shutdown are woken up to finish
+    fn close(&mut self) {
+        self.is_closed = true;
+        self.wake_shutdown();
+        self.wake_flush();
+        if let Some(waker) = self.send_waker.take() {
+            waker.wake();
         }
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
 
-        Poll::Pending
+    /// Mark the socket torn down abnormally, pending and later I/Os fail with `kind` instead of EOF
+    fn close_with_error(&mut self, kind: ErrorKind) {
+        self.close_error = Some(kind);
+        self.close();
     }
-}
 
-pub struct TcpTun {
-    context: Arc<ServiceContext>,
-    manager_handle: Option<JoinHandle<()>>,
-    manager_notify: Arc<ManagerNotify>,
-    manager_socket_creation_tx: mpsc::UnboundedSender<TcpSocketCreation>,
-    manager_running: Arc<AtomicBool>,
-    balancer: PingBalancer,
-    iface_rx: mpsc::UnboundedReceiver<Vec<u8>>,
-    iface_tx: mpsc::UnboundedSender<Vec<u8>>,
-}
+    /// Error of I/Os on a connection that was torn down abnormally
+    fn close_error(&self) -> Option<io::Error> {
+        if self.dead_peer {
+            return Some(TcpSocketControl::dead_peer_error());
+        }
+        self.close_error.map(io::Error::from)
+    }
 
-impl Drop for TcpTun {
-    fn drop(&mut self) {
-        self.manager_running.store(false, Ordering::Relaxed);
-        let _ = self.manager_handle.take().unwrap().join();
+    fn wake_shutdown(&mut self) {
+        if let Some(waker) = self.shutdown_waker.take() {
+            waker.wake();
+        }
     }
-}
 
-impl TcpTun {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mtu: u32) -> TcpTun {
-        let mut capabilities = DeviceCapabilities::default();
-        capabilities.medium = Medium::Ip;
-        capabilities.max_transmission_unit = mtu as usize;
+    fn wake_flush(&mut self) {
+        if let Some(waker) = self.flush_waker.take() {
+            waker.wake();
+        }
+    }
 
-        let (virt, iface_rx, iface_tx) = VirtTunDevice::new(capabilities);
+    /// Check if all data from remote has been acknowledged by client, nothing is left in `send_buffer` or smoltcp's
+    /// socket
+    fn is_flushed(&self) -> bool {
+        self.send_buffer.is_empty() && self.socket_info.send_queue == 0
+    }
 
-        let iface_builder = InterfaceBuilder::new(virt, vec![]);
-        let iface_ipaddrs = [
-            IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0),
-            IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 0),
-        ];
-        let mut iface_routes = Routes::new(BTreeMap::new());
-        iface_routes
-            .add_default_ipv4_route(Ipv4Address::new(0, 0, 0, 1))
-            .expect("IPv4 route");
-        iface_routes
-            .add_default_ipv6_route(Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1))
-            .expect("IPv6 route");
-        let iface = iface_builder
-            .any_ip(true)
-            .ip_addrs(iface_ipaddrs)
-            .routes(iface_routes)
-            .finalize();
+    /// `send_queue` bytes are left in smoltcp's socket after the manager sent, pending flush finishes once it is 0
+    fn update_send_queue(&mut self, send_queue: usize) {
+        self.socket_info.send_queue = send_queue;
+        if self.is_flushed() {
+            self.wake_flush();
+        }
+    }
 
-        let (manager_socket_creation_tx, manager_socket_creation_rx) = mpsc::unbounded_channel();
-        let mut manager = TcpSocketManager {
-            iface,
-            sockets: HashMap::new(),
-            socket_creation_rx: manager_socket_creation_rx,
-        };
+    /// Check whether data written to client has been acknowledged
+    ///
+    /// Waits for notify from the manager until it is, like `poll_send_ready` waits for space.
+    fn poll_flushed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(err) = self.close_error() {
+            return Err(err).into();
+        }
+        if self.is_flushed() || self.fin_acked {
+            self.write_timer = None;
+            return Ok(()).into();
+        }
+        // Closed by the manager with data left, it would never be acknowledged
+        if self.is_closed {
+            return Err(io::ErrorKind::BrokenPipe.into()).into();
+        }
 
-        let manager_running = Arc::new(AtomicBool::new(true));
+        register_waker(&mut self.flush_waker, cx);
+        TcpSocketControl::poll_timeout(cx, self.write_timeout, &mut self.write_timer, "flush")
+    }
 
-        let manager_handle = {
-            let manager_running = manager_running.clone();
+    /// Check if the remote's half-close should be propagated to client, that all data from remote is flushed
+    fn should_queue_fin(&self) -> bool {
+        self.send_shutdown && !self.fin_queued && self.send_buffer.is_empty()
+    }
 
-            thread::spawn(move || {
-                let TcpSocketManager {
-                    ref mut iface,
-                    ref mut sockets,
-                    ref mut socket_creation_rx,
-                    ..
-                } = manager;
+    /// FIN is queued in smoltcp's socket after all data from remote
+    fn fin_queued(&mut self) {
+        self.fin_queued = true;
+    }
 
-                while manager_running.load(Ordering::Relaxed) {
-                    while let Ok(TcpSocketCreation { control, socket }) = socket_creation_rx.try_recv() {
-                        let handle = iface.add_socket(socket);
-                        sockets.insert(handle, control);
-                    }
+    /// Client acknowledged the FIN and all data before it, the pending shutdown is finished
+    fn fin_acked(&mut self) {
+        self.fin_acked = true;
+        self.wake_shutdown();
+    }
 
-                    let before_poll = SmolInstant::now();
-                    let updated_sockets = match iface.poll(before_poll) {
-                        Ok(u) => u,
-                        Err(err) => {
-                            error!("VirtDevice::poll error: {}", err);
-                            false
-                        }
-                    };
+    /// Client half-closed the connection, pending read is woken up to return EOF
+    fn recv_eof(&mut self) {
+        self.recv_eof = true;
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
 
-                    if updated_sockets {
-                        trace!("VirtDevice::poll costed {}", SmolInstant::now() - before_poll);
-                    }
+    /// Check if the connection is deadlocked and should be reset
+    ///
+    /// The client stopped reading so `send_buffer` is full, the remote stopped reading so `recv_buffer` is full.
+    /// Neither could make progress until one of them reads, which may never happen.
+    fn check_stalled(&mut self, progressed: bool, now: Instant) -> bool {
+        let stall_timeout = match self.stall_timeout {
+            Some(t) => t,
+            None => return false,
+        };
 
-                    // Check all the sockets' status
-                    let mut sockets_to_remove = Vec::new();
+        if progressed || !self.send_buffer.is_full() || !self.recv_buffer.is_full() {
+            self.stalled_since = None;
+            return false;
+        }
 
-                    for (socket_handle, control) in sockets.iter() {
-                        let socket_handle = socket_handle.clone();
-                        let socket = iface.get_socket::<TcpSocket>(socket_handle);
-                        let mut control = control.lock();
+        let stalled_since = *self.stalled_since.get_or_insert(now);
+        now - stalled_since >= stall_timeout
+    }
 
-                        #[inline]
-                        fn close_socket_control(control: &mut TcpSocketControl) {
-                            control.is_closed = true;
-                            if let Some(waker) = control.send_waker.take() {
-                                waker.wake();
-                            }
-                            if let Some(waker) = control.recv_waker.take() {
-                                waker.wake();
-                            }
-                        }
+    /// Check if a peer of the connection stopped responding and it should be reset
+    ///
+    /// Data is pending but nothing was moved in both directions: the relay isn't taking client's data because the
+    /// remote stalled, or the client isn't taking or acknowledging remote's data. Unlike stalls, buffers don't have to
+    /// be full.
+    fn check_dead_peer(&mut self, progressed: bool, send_queue: usize, now: Instant) -> bool {
+        let dead_peer_timeout = match self.dead_peer_timeout {
+            Some(t) => t,
+            None => return false,
+        };
 
-                        if !socket.is_open() || socket.state() == TcpState::Closed {
-                            sockets_to_remove.push(socket_handle);
-                            close_socket_control(&mut *control);
-                            continue;
-                        }
+        let acked = send_queue < self.last_send_queue;
+        self.last_send_queue = send_queue;
+        let relayed = mem::take(&mut self.relay_progressed);
+        let pending = !self.recv_buffer.is_empty() || !self.send_buffer.is_empty() || send_queue > 0;
+        if progressed || acked || relayed || !pending {
+            self.dead_peer_since = None;
+            return false;
+        }
 
-                        if control.is_closed {
-                            // Close the socket.
-                            socket.close();
-                            // sockets_to_remove.push(socket_handle);
-                            // close_socket_control(&mut *control);
-                            continue;
-                        }
+        let dead_peer_since = *self.dead_peer_since.get_or_insert(now);
+        now - dead_peer_since >= dead_peer_timeout
+    }
 
-                        // Check if readable
-                        let mut has_received = false;
-                        while socket.can_recv() && !control.recv_buffer.is_full() {
-                            let result = socket.recv(|buffer| {
-                                let n = control.recv_buffer.enqueue_slice(buffer);
-                                (n, ())
-                            });
+    /// Error of I/Os on a connection reset by `check_dead_peer`
+    fn dead_peer_error() -> io::Error {
+        io::Error::new(ErrorKind::TimedOut, "peer stopped responding with data pending")
+    }
 
-                            match result {
-                                Ok(..) => {
-                                    has_received = true;
-                                }
-                                Err(err) => {
-                                    error!("socket recv error: {}", err);
-                                    sockets_to_remove.push(socket_handle);
-                                    close_socket_control(&mut *control);
-                                    break;
-                                }
-                            }
-                        }
+    /// Check if the connection has been idle for `idle_timeout` and should be reaped
+    fn check_idle(&mut self, progressed: bool, now: Instant) -> bool {
+        if progressed {
+            self.last_active = now;
+            return false;
+        }
 
-                        if has_received && control.recv_waker.is_some() {
-                            if let Some(waker) = control.recv_waker.take() {
-                                waker.wake();
-                            }
-                        }
+        match self.idle_timeout {
+            Some(idle_timeout) => now.saturating_duration_since(self.last_active) >= idle_timeout,
+            None => false,
+        }
+    }
+}
 
-                        // Check if writable
-                        let mut has_sent = false;
-                        while socket.can_send() && !control.send_buffer.is_empty() {
-                            let result = socket.send(|buffer| {
-                                let n = control.send_buffer.dequeue_slice(buffer);
-                                (n, ())
-                            });
+/// Wake up the task waiting in `slot` next, the replaced one is woken up if it's another task
+fn register_waker(slot: &mut Option<Waker>, cx: &mut Context<'_>) {
+    if let Some(old_waker) = slot.replace(cx.waker().clone()) {
+        if !old_waker.will_wake(cx.waker()) {
+            old_waker.wake();
+        }
+    }
+}
 
-                            match result {
-                                Ok(..) => {
-                                    has_sent = true;
-                                }
-                                Err(err) => {
-                                    error!("socket send error: {}", err);
-                                    sockets_to_remove.push(socket_handle);
-                                    close_socket_control(&mut *control);
-                                    break;
-                                }
-                            }
-                        }
+/// Reallocate `buffer` with `size` bytes, queued data is moved into the new buffer in order
+///
+/// Returns `false` and keeps `buffer` unchanged if queued data couldn't fit in `size` bytes.
+fn resize_ring_buffer(buffer: &mut RingBuffer<'static, u8>, size: usize) -> bool {
+    if buffer.len() > size {
+        return false;
+    }
+    if buffer.capacity() == size {
+        return true;
+    }
 
-                        if has_sent && control.send_waker.is_some() {
-                            if let Some(waker) = control.send_waker.take() {
-                                waker.wake();
-                            }
-                        }
-                    }
+    let mut resized = RingBuffer::new(vec![0u8; size]);
+    // Queued data may wrap around the end, dequeue_many only returns the contiguous part
+    while !buffer.is_empty() {
+        let data = buffer.dequeue_many(usize::MAX);
+        let n = resized.enqueue_slice(data);
+        debug_assert_eq!(n, data.len());
+    }
+    *buffer = resized;
+    true
+}
 
-                    for socket_handle in sockets_to_remove {
-                        sockets.remove(&socket_handle);
-                        iface.remove_socket(socket_handle);
-                    }
+/// Metrics of smoltcp's `TcpSocket`, updated by the manager in every poll
+#[derive(Debug, Clone, Copy)]
+struct TcpSocketInfo {
+    state: TcpState,
+    send_queue: usize,
+    send_capacity: usize,
+    recv_queue: usize,
+    recv_capacity: usize,
+    local_addr: Option<SocketAddr>,
+    remote_addr: Option<SocketAddr>,
+}
 
-                    let next_duration = iface.poll_delay(before_poll).unwrap_or(SmolDuration::from_millis(5));
-                    if next_duration != SmolDuration::ZERO {
-                        thread::park_timeout(Duration::from(next_duration));
+impl TcpSocketInfo {
+    fn new() -> TcpSocketInfo {
+        TcpSocketInfo {
+            state: TcpState::Listen,
+            send_queue: 0,
+            send_capacity: 0,
+            recv_queue: 0,
+            recv_capacity: 0,
+            local_addr: None,
+            remote_addr: None,
+        }
+    }
+
+    fn update(&mut self, socket: &TcpSocket<'_>) {
+        self.state = socket.state();
+        self.send_queue = socket.send_queue();
+        self.send_capacity = socket.send_capacity();
+        self.recv_queue = socket.recv_queue();
+        self.recv_capacity = socket.recv_capacity();
+        self.local_addr = endpoint_addr(socket.local_endpoint());
+        self.remote_addr = endpoint_addr(socket.remote_endpoint());
+    }
+}
+
+/// Connections changed since the manager's last round, by frames from clients or by relay tasks
+type SharedDirtyConnections = Arc<SpinMutex<HashSet<TcpConnectionKey>>>;
+
+/// Wakes up the manager, coalescing marks and wakeups between its rounds
+///
+/// Relay tasks mark their connections on every read and write. Each connection takes the dirty set's lock once until
+/// the manager checks it, and the manager is unparked once until it starts the next round, instead of for every I/O.
+struct ManagerNotify {
+    thread: Thread,
+    dirty: SharedDirtyConnections,
+    // Unparked since the manager started its round, cleared by the manager before it reads anything
+    notified: Arc<AtomicBool>,
+    #[cfg(test)]
+    dirty_locks: AtomicUsize,
+    #[cfg(test)]
+    unparks: AtomicUsize,
+}
+
+impl ManagerNotify {
+    fn new(thread: Thread, dirty: SharedDirtyConnections, notified: Arc<AtomicBool>) -> ManagerNotify {
+        ManagerNotify {
+            thread,
+            dirty,
+            notified,
+            #[cfg(test)]
+            dirty_locks: AtomicUsize::new(0),
+            #[cfg(test)]
+            unparks: AtomicUsize::new(0),
+        }
+    }
+
+    fn notify(&self) {
+        // Changes made before this are seen by the round that clears the flag
+        if self.notified.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        #[cfg(test)]
+        self.unparks.fetch_add(1, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+
+    /// Connection `key` has to be checked in the manager's next round, without waking it up
+    fn mark_dirty(&self, key: TcpConnectionKey) {
+        #[cfg(test)]
+        self.dirty_locks.fetch_add(1, Ordering::Relaxed);
+        self.dirty.lock().insert(key);
+    }
+
+    /// `mark_dirty` for the relay of connection `key`, skipped if it's marked and not checked by the manager yet
+    fn mark_connection_dirty(&self, key: TcpConnectionKey, control: &mut TcpSocketControl) {
+        if !control.marked_dirty {
+            control.marked_dirty = true;
+            self.mark_dirty(key);
+        }
+    }
+
+    /// Connections of `keys` have to be checked in the manager's next round, without waking it up
+    fn mark_dirty_all(&self, keys: impl IntoIterator<Item = TcpConnectionKey>) {
+        #[cfg(test)]
+        self.dirty_locks.fetch_add(1, Ordering::Relaxed);
+        self.dirty.lock().extend(keys);
+    }
+}
+
+struct ManagedSocket {
+    // Connection that the socket was created for, marked by its relay task
+    key: TcpConnectionKey,
+    // Endpoints of the connection that the socket accepted, marked by frames from the client
+    connected_key: Option<TcpConnectionKey>,
+    control: SharedTcpConnectionControl,
+}
+
+struct TcpSocketManager {
+    iface: Interface<'static, VirtTunDevice>,
+    sockets: HashMap<SocketHandle, ManagedSocket>,
+    created_sockets: HashMap<TcpConnectionKey, SocketHandle>,
+    connected_sockets: HashMap<TcpConnectionKey, SocketHandle>,
+    socket_creation_rx: mpsc::UnboundedReceiver<TcpSocketCreation>,
+}
+
+impl Drop for TcpSocketManager {
+    fn drop(&mut self) {
+        // Nothing will be relayed anymore, release the pending I/Os. Also runs if the manager panicked.
+        for socket in self.sockets.values() {
+            socket.control.lock().close();
+        }
+    }
+}
+
+/// Addresses and routes of the TCP stack's interface
+///
+/// With any-IP, destinations are accepted if they are routed through one of the interface's own addresses, so the
+/// default route of every enabled address family goes through one of them.
+#[derive(Debug, Clone)]
+pub struct TcpInterfaceConfig {
+    ip_addrs: Vec<IpCidr>,
+    routes: Vec<(IpCidr, Route)>,
+}
+
+impl Default for TcpInterfaceConfig {
+    fn default() -> TcpInterfaceConfig {
+        let ipv4_gateway = Ipv4Address::new(0, 0, 0, 1);
+        let ipv6_gateway = Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1);
+        TcpInterfaceConfig {
+            ip_addrs: vec![IpCidr::new(ipv4_gateway.into(), 0), IpCidr::new(ipv6_gateway.into(), 0)],
+            routes: vec![
+                (
+                    IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+                    Route::new_ipv4_gateway(ipv4_gateway),
+                ),
+                (
+                    IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0),
+                    Route::new_ipv6_gateway(ipv6_gateway),
+                ),
+            ],
+        }
+    }
+}
+
+impl TcpInterfaceConfig {
+    /// Create with the interface's `ip_addrs` and `routes` installed instead of the default ones
+    ///
+    /// Address families without any address are disabled. Every enabled family must have a default route through
+    /// one of `ip_addrs`.
+    pub fn new(ip_addrs: Vec<IpCidr>, routes: Vec<(IpCidr, Route)>) -> io::Result<TcpInterfaceConfig> {
+        if ip_addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "TCP stack's interface requires at least one address",
+            ));
+        }
+        for (cidr, route) in routes.iter() {
+            if mem::discriminant(&cidr.address()) != mem::discriminant(&route.via_router) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "route to {} via {} of a different address family",
+                        cidr, route.via_router
+                    ),
+                ));
+            }
+        }
+
+        let config = TcpInterfaceConfig { ip_addrs, routes };
+        for ip_addr in config.ip_addrs.iter() {
+            let gateway = match config.default_gateway(&ip_addr.address()) {
+                Some(gateway) => gateway,
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("no default route of the address family of {}", ip_addr),
+                    ));
+                }
+            };
+            if !config.ip_addrs.iter().any(|cidr| cidr.address() == gateway) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("default route via {} is not an address of the interface", gateway),
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Gateway of the default route of `addr`'s address family
+    fn default_gateway(&self, addr: &IpAddress) -> Option<IpAddress> {
+        self.routes.iter().find_map(|(cidr, route)| {
+            let is_default = cidr.prefix_len() == 0 && mem::discriminant(&cidr.address()) == mem::discriminant(addr);
+            is_default.then_some(route.via_router)
+        })
+    }
+
+    /// Routes of destinations in `accept_cidrs` through the default gateways, or the configured routes if it's `None`
+    fn build_routes(&self, accept_cidrs: Option<&[IpCidr]>) -> Routes<'static> {
+        let mut iface_routes = Routes::new(BTreeMap::new());
+        iface_routes.update(|routes| match accept_cidrs {
+            None => {
+                for (cidr, route) in self.routes.iter() {
+                    routes.insert(*cidr, *route).expect("route");
+                }
+            }
+            // With any-IP, only destinations routed through our own addresses are accepted
+            Some(accept_cidrs) => {
+                for cidr in accept_cidrs {
+                    // Destinations of disabled address families are never accepted
+                    if let Some(gateway) = self.default_gateway(&cidr.address()) {
+                        let route = Route {
+                            via_router: gateway,
+                            preferred_until: None,
+                            expires_at: None,
+                        };
+                        routes.insert(*cidr, route).expect("route");
                     }
                 }
+            }
+        });
+        iface_routes
+    }
+}
 
-                trace!("VirtDevice::poll thread exited");
-            })
+/// Build the interface accepting any destination in `accept_cidrs`, or all destinations if it's `None`
+fn build_interface(
+    device: VirtTunDevice,
+    random_seed: u64,
+    iface_config: &TcpInterfaceConfig,
+    accept_cidrs: Option<&[IpCidr]>,
+) -> Interface<'static, VirtTunDevice> {
+    InterfaceBuilder::new(device, vec![])
+        .any_ip(true)
+        .ip_addrs(iface_config.ip_addrs.clone())
+        .routes(iface_config.build_routes(accept_cidrs))
+        .random_seed(random_seed)
+        .finalize()
+}
+
+/// Replace `iface` with one built for `mtu`, its sockets are moved with their handles, states and buffers
+///
+/// smoltcp cuts segments from send buffers at the MTU of the interface whenever it transmits, so segments sent
+/// after this, including retransmissions of unacknowledged data, fit in the new MTU. Frames already sent to tun
+/// with the old MTU are not touched, if the path drops them they are retransmitted in smaller segments. MSS
+/// advertised to clients during handshakes can't be revised, that only affects segments sent by clients.
+fn rebuild_interface(
+    iface: &mut Interface<'static, VirtTunDevice>,
+    mtu: usize,
+    iface_config: &TcpInterfaceConfig,
+    accept_cidrs: Option<&[IpCidr]>,
+) {
+    let mut device_capabilities = iface.device().capabilities();
+    device_capabilities.max_transmission_unit = mtu;
+    let (detached_device, ..) = VirtTunDevice::new(device_capabilities, TunDeviceStat::new());
+    let mut device = mem::replace(iface.device_mut(), detached_device);
+    device.set_mtu(mtu);
+
+    let mut rebuilt = build_interface(device, rand::random(), iface_config, accept_cidrs);
+
+    let mut socket_handles = iface.sockets().map(|(handle, _)| handle).collect::<Vec<_>>();
+    socket_handles.sort_unstable();
+
+    // Slots are taken in order, so free slots before each socket's are filled up to keep its handle
+    let mut placeholders = Vec::new();
+    for socket_handle in socket_handles {
+        loop {
+            let placeholder = rebuilt.add_socket(TcpSocket::new(
+                TcpSocketBuffer::new(Vec::new()),
+                TcpSocketBuffer::new(Vec::new()),
+            ));
+            if placeholder == socket_handle {
+                rebuilt.remove_socket(placeholder);
+                break;
+            }
+            placeholders.push(placeholder);
+        }
+
+        let socket = match iface.remove_socket(socket_handle) {
+            Socket::Tcp(socket) => socket,
+            _ => unreachable!("only TCP sockets are managed"),
         };
+        let handle = rebuilt.add_socket(socket);
+        debug_assert_eq!(handle, socket_handle);
+    }
+    for placeholder in placeholders {
+        rebuilt.remove_socket(placeholder);
+    }
 
-        let manager_notify = Arc::new(ManagerNotify::new(manager_handle.thread().clone()));
+    *iface = rebuilt;
+}
 
-        TcpTun {
-            context,
-            manager_handle: Some(manager_handle),
+type SharedTcpConnectionControl = Arc<SpinMutex<TcpSocketControl>>;
+
+struct TcpAcceptedConnection {
+    connection: TcpConnection,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+}
+
+/// Connections accepted by the TCP stack, handed over to the caller instead of being tunneled
+pub struct TcpAcceptor {
+    accepted_rx: mpsc::UnboundedReceiver<TcpAcceptedConnection>,
+}
+
+impl TcpAcceptor {
+    /// Wait for the next connection accepted by the stack, returns it with its (source, destination)
+    ///
+    /// The caller relays the connection's data, the stack keeps running while it is waiting. Fails with `BrokenPipe`
+    /// after the stack was dropped or accepting was disabled.
+    pub async fn accept(&mut self) -> io::Result<(TcpConnection, SocketAddr, SocketAddr)> {
+        match self.accepted_rx.recv().await {
+            Some(accepted) => Ok((accepted.connection, accepted.src_addr, accepted.dst_addr)),
+            None => Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "TCP stack stopped accepting connections",
+            )),
+        }
+    }
+}
+
+struct TcpSocketCreation {
+    key: TcpConnectionKey,
+    control: SharedTcpConnectionControl,
+    socket: TcpSocket<'static>,
+}
+
+/// Connection of a client accepted by the TCP stack, reads data from the client and writes data to it
+pub struct TcpConnection {
+    key: TcpConnectionKey,
+    control: SharedTcpConnectionControl,
+    manager_notify: Arc<ManagerNotify>,
+    traffic: Arc<TcpTrafficTotals>,
+}
+
+/// Buffer of `TcpConnection::poll_write_to` and `TcpConnection::poll_read_from`, which poll the remote without the
+/// connection locked
+#[derive(Default)]
+struct DirectRelayBuffer {
+    /// Data from client copied out of the receive buffer
+    to_remote: Vec<u8>,
+    /// Data to client read from the remote
+    to_client: Vec<u8>,
+    /// Part of `to_client` not moved into the send buffer yet
+    unqueued: Range<usize>,
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        let mut control = self.control.lock();
+        control.is_closed = true;
+        drop(control);
+
+        // Closed or reset by the manager, nothing else may wake it up for an idle client
+        self.manager_notify.mark_dirty(self.key);
+        self.manager_notify.notify();
+    }
+}
+
+impl TcpConnection {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        key: TcpConnectionKey,
+        socket: TcpSocket<'static>,
+        socket_creation_tx: &mpsc::UnboundedSender<TcpSocketCreation>,
+        manager_notify: Arc<ManagerNotify>,
+        traffic: Arc<TcpTrafficTotals>,
+        tcp_opts: &TcpSocketOpts,
+        stall_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> TcpConnection {
+        // Sized as the socket's buffers by `create_listen_socket`, so the window advertised by the socket never exceeds
+        // what `recv_buffer` could take from it
+        let send_buffer_size = socket.send_capacity() as u32;
+        let recv_buffer_size = socket.recv_capacity() as u32;
+
+        let mut control = TcpSocketControl::new(send_buffer_size, recv_buffer_size);
+        control.stall_timeout = stall_timeout;
+        control.idle_timeout = idle_timeout;
+        control.nodelay = tcp_opts.nodelay;
+        let control = Arc::new(SpinMutex::new(control));
+
+        let _ = socket_creation_tx.send(TcpSocketCreation {
+            key,
+            control: control.clone(),
+            socket,
+        });
+
+        TcpConnection {
+            key,
+            control,
             manager_notify,
-            manager_socket_creation_tx,
-            manager_running,
-            balancer,
-            iface_rx,
-            iface_tx,
+            traffic,
         }
     }
 
-    pub async fn handle_packet(
-        &mut self,
-        src_addr: SocketAddr,
-        dst_addr: SocketAddr,
-        tcp_packet: &TcpPacket<&[u8]>,
-    ) -> io::Result<()> {
-        // TCP first handshake packet, create a new Connection
-        if tcp_packet.syn() && !tcp_packet.ack() {
-            let accept_opts = self.context.accept_opts();
+    /// Fail reads with `TimedOut` if no data comes from client for `timeout`, waiting forever by default
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        let mut control = self.control.lock();
+        control.read_timeout = timeout;
+        control.read_timer = None;
+    }
 
-            let send_buffer_size = accept_opts.tcp.send_buffer_size.unwrap_or(DEFAULT_TCP_SEND_BUFFER_SIZE);
-            let recv_buffer_size = accept_opts.tcp.recv_buffer_size.unwrap_or(DEFAULT_TCP_RECV_BUFFER_SIZE);
+    /// Fail writes with `TimedOut` if the send buffer has no room for them for `timeout`, waiting forever by default
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        let mut control = self.control.lock();
+        control.write_timeout = timeout;
+        control.write_timer = None;
+    }
 
-            let mut socket = TcpSocket::new(
-                TcpSocketBuffer::new(vec![0u8; recv_buffer_size as usize]),
-                TcpSocketBuffer::new(vec![0u8; send_buffer_size as usize]),
-            );
-            socket.set_keep_alive(accept_opts.tcp.keepalive.map(From::from));
-            // FIXME: It should follow system's setting. 7200 is Linux's default.
-            socket.set_timeout(Some(SmolDuration::from_secs(7200)));
-            // NO ACK delay
-            // socket.set_ack_delay(None);
+    /// Free space of the send buffer, the most bytes that a write could take now, 0 if writing is closed
+    ///
+    /// Data in the buffer waits for room in the socket, which frees as client ACKs what was sent to it.
+    pub fn writable_len(&self) -> usize {
+        let control = self.control.lock();
+        if control.is_closed || control.send_shutdown {
+            return 0;
+        }
+        control.send_buffer.window()
+    }
+
+    /// Resize the send buffer to `size` bytes at runtime, data queued in it is kept
+    ///
+    /// Fails with `InvalidInput` if `size` is 0 or more than `size` bytes are queued.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        if size == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "send buffer size must not be 0",
+            ));
+        }
+
+        let mut control = self.control.lock();
+        if !control.resize_send_buffer(size) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "send buffer size {} is smaller than {} bytes queued",
+                    size,
+                    control.send_buffer.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
 
-            if let Err(err) = socket.listen(dst_addr) {
-                return Err(io::Error::new(ErrorKind::Other, err));
+    /// Reset the connection instead of closing it gracefully after it is dropped
+    fn set_reset_on_close(&self) {
+        self.control.lock().reset_on_close = true;
+    }
+
+    /// Wait until there is data from client to read, without reading it
+    async fn readable(&self) {
+        poll_fn(|cx| {
+            let mut control = self.control.lock();
+            if !control.recv_buffer.is_empty() {
+                return Poll::Ready(());
             }
 
-            trace!("created TCP connection for {} <-> {}", src_addr, dst_addr);
+            register_waker(&mut control.recv_waker, cx);
+            Poll::Pending
+        })
+        .await
+    }
 
-            let connection = TcpConnection::new(
-                socket,
-                &self.manager_socket_creation_tx,
-                self.manager_notify.clone(),
-                &accept_opts.tcp,
-            );
+    /// Write data from client to `writer` through `buffer`, returns the bytes written, 0 if it's EOF
+    ///
+    /// Queued data is copied to `buffer` and only moved out of the receive buffer after it was written, so nothing is
+    /// lost if `writer` is pending. The connection is not locked while polling `writer`, which may encrypt the data.
+    fn poll_write_to<W>(
+        &self,
+        cx: &mut Context<'_>,
+        mut writer: Pin<&mut W>,
+        buffer: &mut DirectRelayBuffer,
+    ) -> Poll<io::Result<usize>>
+    where
+        W: AsyncWrite + ?Sized,
+    {
+        {
+            let mut control = self.control.lock();
+            if !ready!(control.poll_recv_ready(cx))? {
+                return Ok(0).into();
+            }
 
-            // establish a tunnel
-            let context = self.context.clone();
-            let balancer = self.balancer.clone();
-            tokio::spawn(async move {
-                if let Err(err) = handle_redir_client(context, balancer, connection, src_addr, dst_addr).await {
-                    error!("TCP tunnel failure, {} <-> {}, error: {}", src_addr, dst_addr, err);
-                }
-            });
+            // Queued data may wrap around the end
+            let queued = &control.recv_buffer;
+            let first = queued.get_allocated(0, queued.len());
+            let second = queued.get_allocated(first.len(), queued.len() - first.len());
+            buffer.to_remote.clear();
+            buffer.to_remote.extend_from_slice(first);
+            buffer.to_remote.extend_from_slice(second);
         }
 
-        Ok(())
+        let n = ready!(writer.as_mut().poll_write(cx, &buffer.to_remote))?;
+        if n == 0 {
+            return Err(ErrorKind::WriteZero.into()).into();
+        }
+
+        // Only this connection moves data out, the copied data is still at the front
+        let mut control = self.control.lock();
+        control.recv_buffer.dequeue_allocated(n);
+        control.record_recv_dequeued(n);
+        self.recv_relayed(&mut control, n);
+        Ok(n).into()
+    }
+
+    /// Read data to client from `reader` through `buffer`, returns the bytes moved into the send buffer, 0 if it's EOF
+    ///
+    /// At most the send buffer's window is read, but the buffer may be shrunk before the data is queued, the rest is
+    /// kept in `buffer` and queued first in the next call. The connection is not locked while polling `reader`, which
+    /// may decrypt the data.
+    fn poll_read_from<R>(
+        &self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        buffer: &mut DirectRelayBuffer,
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + ?Sized,
+    {
+        loop {
+            let window = {
+                let mut control = self.control.lock();
+                ready!(control.poll_send_ready(cx, 1))?;
+
+                if !buffer.unqueued.is_empty() {
+                    let n = control
+                        .send_buffer
+                        .enqueue_slice(&buffer.to_client[buffer.unqueued.clone()]);
+                    buffer.unqueued.start += n;
+                    self.send_relayed(&mut control, n);
+                    return Ok(n).into();
+                }
+                control.send_buffer.window()
+            };
+
+            if buffer.to_client.len() < window {
+                buffer.to_client.resize(window, 0);
+            }
+            let n = {
+                let mut buf = ReadBuf::new(&mut buffer.to_client[..window]);
+                ready!(reader.as_mut().poll_read(cx, &mut buf))?;
+                buf.filled().len()
+            };
+            if n == 0 {
+                return Ok(0).into();
+            }
+
+            // Queued after locking the connection again
+            buffer.unqueued = 0..n;
+        }
     }
 
-    pub async fn drive_interface_state(&mut self, frame: &[u8]) {
-        if let Err(..) = self.iface_tx.send(frame.to_vec()) {
-            panic!("interface send channel closed unexpectly");
+    /// Account `n` bytes from client moved out of the receive buffer
+    fn recv_relayed(&self, control: &mut TcpSocketControl, n: usize) {
+        control.relay_progressed = true;
+        self.traffic.tx.fetch_add(n as u64, Ordering::Relaxed);
+
+        // Space is freed, the socket may have more data
+        self.manager_notify.mark_connection_dirty(self.key, control);
+        if control.recv_buffer.is_empty() {
+            self.manager_notify.notify();
         }
+    }
 
-        // Wake up and poll the interface.
+    /// Wake up the manager to check the connection in its next round
+    fn notify_manager(&self) {
+        self.manager_notify.mark_dirty(self.key);
         self.manager_notify.notify();
     }
 
-    pub async fn recv_packet(&mut self) -> Vec<u8> {
-        match self.iface_rx.recv().await {
-            Some(v) => v,
-            None => unreachable!("channel closed unexpectedly"),
+    /// Account `n` bytes to client moved into the send buffer
+    fn send_relayed(&self, control: &mut TcpSocketControl, n: usize) {
+        control.relay_progressed = true;
+        self.traffic.rx.fetch_add(n as u64, Ordering::Relaxed);
+
+        #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+        {
+            control.relayed_rx += n as u64;
+        }
+
+        self.manager_notify.mark_connection_dirty(self.key, control);
+        if control.nodelay || control.send_buffer.is_full() {
+            self.manager_notify.notify();
         }
     }
 }
 
-/// Established Client Transparent Proxy
-///
-/// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_redir<'a>(
-    context: Arc<ServiceContext>,
-    balancer: PingBalancer,
-    mut stream: TcpConnection,
-    peer_addr: SocketAddr,
-    addr: &Address,
-) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
-
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
+impl AsyncRead for TcpConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut control = self.control.lock();
+        if !ready!(control.poll_recv_ready(cx))? {
+            return Ok(()).into();
+        }
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+        let n = control.dequeue_recv(buf);
+        self.recv_relayed(&mut control, n);
+        Ok(()).into()
+    }
 }
 
-async fn handle_redir_client(
-    context: Arc<ServiceContext>,
-    balancer: PingBalancer,
-    s: TcpConnection,
-    peer_addr: SocketAddr,
-    mut daddr: SocketAddr,
-) -> io::Result<()> {
-    // Get forward address from socket
-    //
-    // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
-    if let SocketAddr::V6(ref a) = daddr {
-        if let Some(v4) = to_ipv4_mapped(a.ip()) {
-            daddr = SocketAddr::new(IpAddr::from(v4), a.port());
-        }
+impl AsyncWrite for TcpConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut control = self.control.lock();
+        // Wait for a worthwhile space instead of accepting a tiny partial write while the buffer is draining
+        let wanted = buf
+            .len()
+            .min(TCP_SEND_LOW_WATERMARK)
+            .min(control.send_buffer.capacity())
+            .max(1);
+        ready!(control.poll_send_ready(cx, wanted))?;
+
+        let n = control.send_buffer.enqueue_slice(buf);
+        self.send_relayed(&mut control, n);
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut control = self.control.lock();
+        let result = control.poll_flushed(cx);
+        drop(control);
+
+        // Data in `send_buffer` may be waiting for the manager's next round
+        if result.is_pending() {
+            self.notify_manager();
+        }
+        result
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut control = self.control.lock();
+
+        if let Some(err) = control.close_error() {
+            return Err(err).into();
+        }
+        // Closed by the manager, or client has already received the FIN requested below
+        if control.is_closed || control.fin_acked {
+            return Ok(()).into();
+        }
+
+        // Only the direction to client is closed, data from client is still relayed until it half-closes too.
+        //
+        // Registered with a dedicated waker under the same lock as the manager checks `send_shutdown`, so the wake
+        // couldn't be missed, or stolen by a pending `poll_write`
+        control.send_shutdown = true;
+        register_waker(&mut control.shutdown_waker, cx);
+        drop(control);
+
+        self.notify_manager();
+        Poll::Pending
+    }
+}
+
+/// State of an active TCP connection in `TcpTun`
+///
+/// This is designed for handing over connections to another process when upgrading. Only the connection's identity
+/// and the chosen server could be handed over, the TCP state machine and the in-flight data couldn't be preserved.
+/// So clients have to reconnect, but the new connections will go through the same servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpConnectionState {
+    /// Client's address
+    pub src_addr: SocketAddr,
+    /// Target's address
+    pub dst_addr: SocketAddr,
+    /// Server that connection was established through, `None` if the connection hasn't been established yet
+    pub server_addr: Option<ServerAddr>,
+}
+
+/// Snapshot of an active TCP connection in `TcpTun`
+///
+/// smoltcp doesn't expose its RTT estimation and it doesn't implement congestion control, so there is no RTT or
+/// congestion window could be reported. The closest indicator is `socket_send_queue`: a flow with a full send queue
+/// is limited by the client's receive window, otherwise it is waiting for the proxy.
+#[derive(Debug, Clone)]
+pub struct ConnEntry {
+    /// Client's address
+    pub src_addr: SocketAddr,
+    /// Target's address
+    pub dst_addr: SocketAddr,
+    /// Server that connection was established through, `None` if not established yet or bypassed
+    pub server_addr: Option<ServerAddr>,
+    /// Local address of the outbound socket, `None` if not established yet
+    ///
+    /// It is the actual source address chosen by the system, which could be used for confirming
+    /// `outbound_bind_addr` and policy routing are working as configured.
+    pub outbound_addr: Option<SocketAddr>,
+    /// Interface that the outbound socket was bound to, `None` if not established yet or not configured
+    pub outbound_interface: Option<String>,
+    /// Remote address of the outbound socket, the resolved server's address if proxied, `None` if not established yet
+    ///
+    /// Its family tells whether a domain-named server was reached over IPv4 or IPv6.
+    pub outbound_peer_addr: Option<SocketAddr>,
+    /// Local endpoint of smoltcp's socket, `None` until the manager has polled it
+    ///
+    /// The TCP stack accepts connections to any address, so it is expected to be `dst_addr`.
+    pub socket_local_addr: Option<SocketAddr>,
+    /// Remote endpoint of smoltcp's socket, `None` until the manager has polled it
+    ///
+    /// It is expected to be `src_addr`, a mismatch means the socket isn't the one the connection was tracked for.
+    pub socket_remote_addr: Option<SocketAddr>,
+    /// TCP state of smoltcp's socket
+    pub state: TcpState,
+    /// Bytes in smoltcp's socket waiting to be sent (or acknowledged) to client
+    pub socket_send_queue: usize,
+    /// Capacity of smoltcp's socket send buffer
+    pub socket_send_capacity: usize,
+    /// Bytes in smoltcp's socket received from client waiting to be read
+    pub socket_recv_queue: usize,
+    /// Capacity of smoltcp's socket receive buffer
+    pub socket_recv_capacity: usize,
+    /// Bytes received from remote waiting to be written into smoltcp's socket
+    pub relay_send_buffered: usize,
+    /// Bytes received from client waiting to be relayed to remote
+    pub relay_recv_buffered: usize,
+}
+
+/// Occupancy of the relay buffers of an active TCP connection in `TcpTun`
+///
+/// A full `send_buffered` means the client isn't reading fast enough, a full `recv_buffered` means the remote isn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpBufferOccupancy {
+    /// Client's address
+    pub src_addr: SocketAddr,
+    /// Target's address
+    pub dst_addr: SocketAddr,
+    /// Bytes received from remote waiting to be written into smoltcp's socket
+    pub send_buffered: usize,
+    /// Capacity of the buffer of bytes received from remote
+    pub send_capacity: usize,
+    /// Bytes received from client waiting to be relayed to remote
+    pub recv_buffered: usize,
+    /// Capacity of the buffer of bytes received from client
+    pub recv_capacity: usize,
+}
+
+type TcpConnectionKey = (SocketAddr, SocketAddr);
+
+struct TcpConnectionEntry {
+    server_addr: Option<ServerAddr>,
+    outbound_addr: Option<SocketAddr>,
+    outbound_interface: Option<String>,
+    outbound_peer_addr: Option<SocketAddr>,
+    // Client's segments carrying data are dropped until connected, in `TcpEarlyDataPolicy::Delay`
+    delay_data: bool,
+    control: SharedTcpConnectionControl,
+}
+
+type SharedTcpConnectionStates = Arc<SpinMutex<HashMap<TcpConnectionKey, TcpConnectionEntry>>>;
+
+/// Reset clients' connections relayed through `server_addr`, returns the number of them
+fn reset_server_connections(
+    states: &SharedTcpConnectionStates,
+    manager_notify: &ManagerNotify,
+    server_addr: &ServerAddr,
+) -> usize {
+    let mut reset = 0;
+    for (key, entry) in states.lock().iter() {
+        if entry.server_addr.as_ref() != Some(server_addr) {
+            continue;
+        }
+        let mut control = entry.control.lock();
+        if control.is_closed {
+            continue;
+        }
+        // Relay tasks see EOF and errors, the manager aborts the socket in its next round
+        control.reset_on_close = true;
+        control.close();
+        manager_notify.mark_connection_dirty(*key, &mut control);
+        reset += 1;
+    }
+    if reset > 0 {
+        manager_notify.notify();
+    }
+    reset
+}
+
+/// Bytes relayed by all connections, including the finished ones
+#[derive(Debug, Default)]
+struct TcpTrafficTotals {
+    // Read from clients
+    tx: AtomicU64,
+    // Written to clients
+    rx: AtomicU64,
+}
+
+/// Connection tracking of `TcpTun`, could be cloned and read while `TcpTun` is running
+#[derive(Clone)]
+pub struct TcpConnTrack {
+    states: SharedTcpConnectionStates,
+    traffic: Arc<TcpTrafficTotals>,
+    live_sockets: Arc<AtomicUsize>,
+}
+
+impl TcpConnTrack {
+    /// Number of active connections
+    pub fn len(&self) -> usize {
+        self.states.lock().len()
+    }
+
+    /// Check if there is no active connections
+    pub fn is_empty(&self) -> bool {
+        self.states.lock().is_empty()
+    }
+
+    /// Number of sockets holding buffers, including half-open and closing ones not tracked as connections
+    pub fn socket_count(&self) -> usize {
+        self.live_sockets.load(Ordering::Acquire)
+    }
+
+    /// Total bytes received from clients by all connections, including the finished ones
+    pub fn tx(&self) -> u64 {
+        self.traffic.tx.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes sent to clients by all connections, including the finished ones
+    pub fn rx(&self) -> u64 {
+        self.traffic.rx.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of all active connections
+    pub fn entries(&self) -> Vec<ConnEntry> {
+        let states = self.states.lock();
+        states
+            .iter()
+            .map(|(&(src_addr, dst_addr), entry)| {
+                let control = entry.control.lock();
+                let info = control.socket_info;
+                ConnEntry {
+                    src_addr,
+                    dst_addr,
+                    server_addr: entry.server_addr.clone(),
+                    outbound_addr: entry.outbound_addr,
+                    outbound_interface: entry.outbound_interface.clone(),
+                    outbound_peer_addr: entry.outbound_peer_addr,
+                    socket_local_addr: info.local_addr,
+                    socket_remote_addr: info.remote_addr,
+                    state: info.state,
+                    socket_send_queue: info.send_queue,
+                    socket_send_capacity: info.send_capacity,
+                    socket_recv_queue: info.recv_queue,
+                    socket_recv_capacity: info.recv_capacity,
+                    relay_send_buffered: control.send_buffer.len(),
+                    relay_recv_buffered: control.recv_buffer.len(),
+                }
+            })
+            .collect()
+    }
+
+    /// Occupancy of relay buffers of all active connections
+    ///
+    /// Lighter than `entries`, connections are locked one at a time after the tracking table is released, and only
+    /// their buffers' lengths are copied, so the manager is never held up for long.
+    pub fn buffer_occupancy(&self) -> Vec<TcpBufferOccupancy> {
+        let controls = self
+            .states
+            .lock()
+            .iter()
+            .map(|(&key, entry)| (key, entry.control.clone()))
+            .collect::<Vec<_>>();
+
+        controls
+            .into_iter()
+            .map(|((src_addr, dst_addr), control)| {
+                let control = control.lock();
+                TcpBufferOccupancy {
+                    src_addr,
+                    dst_addr,
+                    send_buffered: control.send_buffer.len(),
+                    send_capacity: control.send_buffer.capacity(),
+                    recv_buffered: control.recv_buffer.len(),
+                    recv_capacity: control.recv_buffer.capacity(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Keeps the connection's state in `TcpTun` until the connection is finished
+struct TcpConnectionTracker {
+    states: SharedTcpConnectionStates,
+    key: TcpConnectionKey,
+}
+
+impl TcpConnectionTracker {
+    fn new(
+        states: SharedTcpConnectionStates,
+        key: TcpConnectionKey,
+        control: SharedTcpConnectionControl,
+    ) -> TcpConnectionTracker {
+        let entry = TcpConnectionEntry {
+            server_addr: None,
+            outbound_addr: None,
+            outbound_interface: None,
+            outbound_peer_addr: None,
+            delay_data: false,
+            control,
+        };
+        states.lock().insert(key, entry);
+        TcpConnectionTracker { states, key }
+    }
+
+    fn set_delay_data(&self, delay_data: bool) {
+        if let Some(entry) = self.states.lock().get_mut(&self.key) {
+            entry.delay_data = delay_data;
+        }
+    }
+
+    fn set_server_addr(&self, server_addr: &ServerAddr) {
+        if let Some(entry) = self.states.lock().get_mut(&self.key) {
+            entry.server_addr = Some(server_addr.clone());
+        }
+    }
+
+    fn set_outbound(
+        &self,
+        outbound_addr: SocketAddr,
+        outbound_interface: Option<&str>,
+        outbound_peer_addr: Option<SocketAddr>,
+    ) {
+        if let Some(entry) = self.states.lock().get_mut(&self.key) {
+            entry.outbound_addr = Some(outbound_addr);
+            entry.outbound_interface = outbound_interface.map(ToOwned::to_owned);
+            entry.outbound_peer_addr = outbound_peer_addr;
+        }
+    }
+}
+
+impl Drop for TcpConnectionTracker {
+    fn drop(&mut self) {
+        self.states.lock().remove(&self.key);
+    }
+}
+
+/// Relay tasks spawned by `TcpTun` and how they ended, could be cloned and read while `TcpTun` is running
+#[derive(Debug, Clone, Default)]
+pub struct TcpRelayTasks {
+    count: Arc<AtomicUsize>,
+    remote_failures: Arc<AtomicU64>,
+}
+
+impl TcpRelayTasks {
+    /// Number of relay tasks that are not finished
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Relays ended by the outbound connection failing in the middle, like being reset by the server
+    pub fn remote_failures(&self) -> u64 {
+        self.remote_failures.load(Ordering::Relaxed)
+    }
+
+    fn record_remote_failure(&self) {
+        self.remote_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn start(&self) -> TcpRelayTaskGuard {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        TcpRelayTaskGuard {
+            count: self.count.clone(),
+        }
+    }
+}
+
+/// Counts a socket with its buffers until it is released
+struct TcpLiveSocketGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl TcpLiveSocketGuard {
+    fn new(count: Arc<AtomicUsize>) -> TcpLiveSocketGuard {
+        count.fetch_add(1, Ordering::AcqRel);
+        TcpLiveSocketGuard { count }
+    }
+}
+
+impl Drop for TcpLiveSocketGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Counts a relay task until it is finished
+struct TcpRelayTaskGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for TcpRelayTaskGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub struct TcpTun {
+    context: Arc<ServiceContext>,
+    manager_handle: Option<JoinHandle<()>>,
+    manager_notify: Arc<ManagerNotify>,
+    manager_socket_creation_tx: mpsc::UnboundedSender<TcpSocketCreation>,
+    manager_running: Arc<AtomicBool>,
+    manager_closing: Arc<AtomicBool>,
+    unfinished_sockets: Arc<AtomicUsize>,
+    balancer: PingBalancer,
+    iface_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    iface_tx: mpsc::UnboundedSender<Vec<u8>>,
+    device_stat: TunDeviceStat,
+    poll_stat: TcpPollStat,
+    shared_poll_metrics: Arc<SharedPollMetrics>,
+    shared_clock: Arc<SharedClock>,
+    connection_states: SharedTcpConnectionStates,
+    traffic: Arc<TcpTrafficTotals>,
+    imported_connections: HashMap<TcpConnectionKey, ServerAddr>,
+    accepted_tx: Option<mpsc::UnboundedSender<TcpAcceptedConnection>>,
+    accepted_rx: Option<mpsc::UnboundedReceiver<TcpAcceptedConnection>>,
+    buffer_profiles: Vec<TcpBufferProfile>,
+    sniff_config: SniffConfig,
+    stall_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    dead_peer_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+    reset_on_remote_failure: bool,
+    server_down_watcher: Option<TaskJoinHandle<()>>,
+    early_data_policy: TcpEarlyDataPolicy,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    outbound_fwmark: Option<u32>,
+    resolve_strategy: Option<ResolveStrategy>,
+    relay_tasks: TcpRelayTasks,
+    max_relay_tasks: Option<usize>,
+    live_sockets: Arc<AtomicUsize>,
+    max_connections: Option<usize>,
+    recv_buffer_autotune: Option<u32>,
+    round_budget: Option<usize>,
+    bandwidth_limit: Option<TcpBandwidthLimit>,
+    global_bandwidth_limit: Option<Arc<SpinMutex<TcpBandwidthLimit>>>,
+    conn_rate_limit: Option<TcpConnRateLimit>,
+    default_keepalive: Option<Duration>,
+    nodelay: bool,
+    shared_mtu: Arc<AtomicUsize>,
+    out_queue_limit: Arc<AtomicUsize>,
+    mtu_blackhole: MtuBlackholeDetector,
+    mss_clamp: TcpMssClamp,
+    flow_affinity: Option<FlowAffinity>,
+    server_selector: Option<Arc<dyn ServerSelector>>,
+    target_rewriter: Arc<dyn TargetRewriter>,
+    events: Option<broadcast::Sender<TunEvent>>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+    accept_cidrs: Option<Vec<IpCidr>>,
+    #[cfg(feature = "local-tun-capture")]
+    frame_capture: Option<FrameCapture>,
+}
+
+impl Drop for TcpTun {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.server_down_watcher.take() {
+            watcher.abort();
+        }
+        self.manager_running.store(false, Ordering::Relaxed);
+        self.manager_notify.notify();
+        if let Some(manager_handle) = self.manager_handle.take() {
+            let _ = manager_handle.join();
+        }
+    }
+}
+
+impl TcpTun {
+    /// Create the TCP stack accepting connections to destinations in `accept_cidrs`, or to all destinations if it's
+    /// `None`
+    ///
+    /// Frames to other destinations are dropped without a reply, so they could be routed by the host instead.
+    pub fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mtu: u32,
+        scheduler_policy: TcpSchedulerPolicy,
+        accept_cidrs: Option<Vec<IpCidr>>,
+    ) -> TcpTun {
+        TcpTun::with_interface(
+            context,
+            balancer,
+            mtu,
+            scheduler_policy,
+            accept_cidrs,
+            TcpInterfaceConfig::default(),
+        )
+    }
+
+    /// Create the TCP stack like `new`, with addresses and routes of `iface_config` on its interface
+    pub fn with_interface(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mtu: u32,
+        scheduler_policy: TcpSchedulerPolicy,
+        accept_cidrs: Option<Vec<IpCidr>>,
+        iface_config: TcpInterfaceConfig,
+    ) -> TcpTun {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = mtu as usize;
+
+        let device_stat = TunDeviceStat::new();
+        let (virt, iface_rx, iface_tx) = VirtTunDevice::new(capabilities, device_stat.clone());
+        let out_queue_limit = virt.out_queue_limit();
+
+        let iface = build_interface(virt, rand::random(), &iface_config, accept_cidrs.as_deref());
+
+        let (manager_socket_creation_tx, manager_socket_creation_rx) = mpsc::unbounded_channel();
+        let mut manager = TcpSocketManager {
+            iface,
+            sockets: HashMap::new(),
+            created_sockets: HashMap::new(),
+            connected_sockets: HashMap::new(),
+            socket_creation_rx: manager_socket_creation_rx,
+        };
+
+        let manager_running = Arc::new(AtomicBool::new(true));
+        let manager_notified = Arc::new(AtomicBool::new(false));
+        let manager_closing = Arc::new(AtomicBool::new(false));
+        // Only counted after closing
+        let unfinished_sockets = Arc::new(AtomicUsize::new(usize::MAX));
+        let poll_stat = TcpPollStat::new();
+        let shared_poll_metrics = Arc::new(SharedPollMetrics::default());
+        let shared_clock = Arc::new(SharedClock::default());
+        let dirty_connections = SharedDirtyConnections::default();
+        let shared_mtu = Arc::new(AtomicUsize::new(mtu as usize));
+
+        let manager_handle = {
+            let manager_running = manager_running.clone();
+            let manager_notified = manager_notified.clone();
+            let manager_closing = manager_closing.clone();
+            let unfinished_sockets = unfinished_sockets.clone();
+            let poll_stat = poll_stat.clone();
+            let shared_poll_metrics = shared_poll_metrics.clone();
+            let shared_clock = shared_clock.clone();
+            let dirty_connections = dirty_connections.clone();
+            let shared_mtu = shared_mtu.clone();
+            let manager_accept_cidrs = accept_cidrs.clone();
+
+            thread::spawn(move || {
+                let TcpSocketManager {
+                    ref mut iface,
+                    ref mut sockets,
+                    ref mut created_sockets,
+                    ref mut connected_sockets,
+                    ref mut socket_creation_rx,
+                } = manager;
+
+                let mut scheduler = SendScheduler::new(scheduler_policy);
+
+                // Sockets checked in this round. Idle sockets are left alone until a frame or their relay task
+                // changes them, so the cost of a round grows with the active connections instead of all of them.
+                let mut pending_sockets = HashSet::new();
+                // Timeouts are checked by the clock's time, smoltcp's time is moved forward with it
+                let mut clock: Arc<dyn Clock> = Arc::new(SystemClock);
+                let mut clock_epoch = clock.now();
+                let mut smol_epoch = SmolInstant::now();
+                let mut before_poll = smol_epoch;
+                let mut last_sweep = clock.now();
+                // Sockets left to check in the current sweep
+                let mut sweep_sockets = Vec::new();
+                // Listening sockets may accept SYNs of other connections to the same destination, they are checked
+                // in every round until connected, then also indexed by their real endpoints
+                let mut listening_sockets = HashSet::new();
+                let mut closing = false;
+                let mut poll_metrics = None;
+
+                // Deferred by the previous round, serviced first in this round
+                let mut deferred_sockets = HashSet::new();
+
+                loop {
+                    // Cleared before reading anything, including whether to exit, notifications after this unpark
+                    // the next round
+                    manager_notified.swap(false, Ordering::AcqRel);
+                    if !manager_running.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    // Sockets are closed as if their connections were dropped, FIN is sent after data from remotes
+                    if !closing && manager_closing.load(Ordering::Acquire) {
+                        closing = true;
+                        for (socket_handle, socket) in sockets.iter() {
+                            socket.control.lock().close();
+                            pending_sockets.insert(*socket_handle);
+                        }
+                    }
+
+                    let mtu = shared_mtu.load(Ordering::Acquire);
+                    if mtu != iface.device().capabilities().max_transmission_unit {
+                        rebuild_interface(iface, mtu, &iface_config, manager_accept_cidrs.as_deref());
+                        debug!("TCP stack's MTU changed to {}", mtu);
+                    }
+
+                    if shared_clock.update(&mut clock) {
+                        // smoltcp's time never goes backwards, it continues from the last poll
+                        clock_epoch = clock.now();
+                        smol_epoch = before_poll;
+                        last_sweep = clock_epoch;
+                        // Times of different clocks can't be compared, idle times start over
+                        for socket in sockets.values() {
+                            socket.control.lock().last_active = clock_epoch;
+                        }
+                    }
+
+                    while let Ok(TcpSocketCreation { key, control, socket }) = socket_creation_rx.try_recv() {
+                        {
+                            let mut control = control.lock();
+                            // Idle time is measured by the manager's clock
+                            control.last_active = clock.now();
+                            if closing {
+                                control.close();
+                            }
+                        }
+                        let handle = iface.add_socket(socket);
+                        sockets.insert(
+                            handle,
+                            ManagedSocket {
+                                key,
+                                connected_key: None,
+                                control,
+                            },
+                        );
+                        created_sockets.insert(key, handle);
+                        scheduler.add(handle);
+                        pending_sockets.insert(handle);
+                        listening_sockets.insert(handle);
+                    }
+
+                    if let Some(metrics) = shared_poll_metrics.take_changed() {
+                        poll_metrics = metrics;
+                    }
+                    // Taken before polling, frames are queued before their connections are marked
+                    let dirty = mem::take(&mut *dirty_connections.lock());
+
+                    before_poll = smol_epoch + SmolDuration::from(clock.now().saturating_duration_since(clock_epoch));
+                    let poll_start = Instant::now();
+                    let updated_sockets = match iface.poll(before_poll) {
+                        Ok(u) => u,
+                        Err(err) => {
+                            error!("VirtDevice::poll error: {}", err);
+                            false
+                        }
+                    };
+
+                    let poll_cost = poll_start.elapsed();
+                    poll_stat.record(poll_cost);
+                    if updated_sockets {
+                        trace!("VirtDevice::poll costed {:?}", poll_cost);
+                    }
+                    if let Some(ref metrics) = poll_metrics {
+                        metrics.on_poll(poll_cost, updated_sockets, sockets.len());
+                    }
+
+                    // Check the changed sockets' status
+                    let mut sockets_to_remove = Vec::new();
+                    let mut has_stalled = false;
+                    let mut has_exhausted = false;
+                    // Throttled sockets are checked again after the earliest refill
+                    let mut throttle_delay: Option<Duration> = None;
+                    let now = clock.now();
+
+                    if sweep_sockets.is_empty() && now.duration_since(last_sweep) >= TCP_FULL_SWEEP_INTERVAL {
+                        last_sweep = now;
+                        sweep_sockets.extend(sockets.keys().copied());
+                    }
+                    let swept = sweep_sockets.len().min(TCP_SWEEP_CHUNK_SIZE);
+                    pending_sockets.extend(sweep_sockets.drain(sweep_sockets.len() - swept..));
+
+                    // A listening socket may have accepted another connection to the same destination
+                    for key in &dirty {
+                        pending_sockets.extend(created_sockets.get(key));
+                        pending_sockets.extend(connected_sockets.get(key));
+                    }
+                    pending_sockets.extend(listening_sockets.iter().copied());
+
+                    let serving_sockets = mem::take(&mut pending_sockets);
+                    let (mut serving_order, rest): (Vec<_>, Vec<_>) = scheduler
+                        .order(&serving_sockets)
+                        .into_iter()
+                        .partition(|socket_handle| deferred_sockets.contains(socket_handle));
+                    serving_order.extend(rest);
+                    deferred_sockets.clear();
+                    if serving_order.len() > TCP_MAX_SERVICED_SOCKETS {
+                        deferred_sockets.extend(serving_order.drain(TCP_MAX_SERVICED_SOCKETS..));
+                        pending_sockets.extend(deferred_sockets.iter().copied());
+                    }
+                    poll_stat.record_serviced(serving_order.len(), swept);
+
+                    let mut accepted_sockets = Vec::new();
+                    for socket_handle in serving_order {
+                        let control = match sockets.get(&socket_handle) {
+                            Some(s) => &s.control,
+                            None => continue,
+                        };
+                        let socket = iface.get_socket::<TcpSocket>(socket_handle);
+                        let mut control = control.lock();
+                        control.marked_dirty = false;
+
+                        control.socket_info.update(socket);
+
+                        if !socket.is_listening() && listening_sockets.remove(&socket_handle) {
+                            if let (Some(client), Some(target)) = (
+                                endpoint_addr(socket.remote_endpoint()),
+                                endpoint_addr(socket.local_endpoint()),
+                            ) {
+                                accepted_sockets.push((socket_handle, (client, target)));
+                            }
+                        }
+
+                        if !socket.is_open() || socket.state() == TcpState::Closed {
+                            sockets_to_remove.push(socket_handle);
+                            // Both directions finished with FINs, otherwise the client reset it
+                            if control.is_closed || (control.recv_eof && control.fin_queued) {
+                                control.close();
+                            } else {
+                                control.close_with_error(ErrorKind::ConnectionReset);
+                            }
+                            continue;
+                        }
+
+                        // Connection is dropped, close the socket after data from remote is flushed. smoltcp's socket
+                        // sends its queued data before FIN, but data still in `send_buffer` would be lost.
+                        if control.is_closed && (control.reset_on_close || control.send_buffer.is_empty()) {
+                            if control.reset_on_close {
+                                socket.abort();
+                            } else {
+                                socket.close();
+                            }
+                            control.wake_shutdown();
+                            // Checked until smoltcp closes it
+                            pending_sockets.insert(socket_handle);
+                            continue;
+                        }
+
+                        // Check if readable, receiving at most the round's budget
+                        let mut has_received = false;
+                        let round_budget = control.round_budget.unwrap_or(usize::MAX);
+                        let recv_allowance = control.bandwidth_allowance(BandwidthDirection::Recv, now);
+                        let mut recv_budget = round_budget.min(recv_allowance);
+                        while socket.can_recv() && !control.recv_buffer.is_full() && recv_budget > 0 {
+                            let result = socket.recv(|buffer| {
+                                let len = buffer.len().min(recv_budget);
+                                let n = control.recv_buffer.enqueue_slice(&buffer[..len]);
+                                (n, n)
+                            });
+
+                            match result {
+                                Ok(n) => {
+                                    has_received = true;
+                                    recv_budget -= n;
+                                }
+                                Err(err) => {
+                                    error!("socket recv error: {}", err);
+                                    sockets_to_remove.push(socket_handle);
+                                    control.close_with_error(ErrorKind::ConnectionAborted);
+                                    break;
+                                }
+                            }
+                        }
+
+                        let recv_limited = recv_allowance < round_budget;
+                        if recv_allowance != usize::MAX {
+                            let received = round_budget.min(recv_allowance) - recv_budget;
+                            control.consume_bandwidth(BandwidthDirection::Recv, received);
+                        }
+
+                        // Client is faster than the relay could read between rounds
+                        if control.recv_buffer.is_full() && socket.can_recv() && control.autotune_recv_buffer() {
+                            trace!(
+                                "TCP connection {} <-> {} receive buffer grew to {} bytes",
+                                socket.remote_endpoint(),
+                                socket.local_endpoint(),
+                                control.recv_buffer.capacity()
+                            );
+                        }
+
+                        if has_received && control.recv_waker.is_some() {
+                            if let Some(waker) = control.recv_waker.take() {
+                                waker.wake();
+                            }
+                        }
+
+                        // Client sent FIN and all its data is received, the remote's direction is kept
+                        if !control.recv_eof
+                            && !socket.can_recv()
+                            && matches!(
+                                socket.state(),
+                                TcpState::CloseWait | TcpState::LastAck | TcpState::Closing | TcpState::TimeWait
+                            )
+                        {
+                            control.recv_eof();
+                        }
+
+                        // Check if writable, sending at most the scheduled budget and the round's budget
+                        let mut has_sent = false;
+                        let send_allowance = control.bandwidth_allowance(BandwidthDirection::Send, now);
+                        let mut budget = scheduler.budget(&socket_handle).min(round_budget).min(send_allowance);
+                        let mut sent = 0;
+                        while socket.can_send() && !control.send_buffer.is_empty() && budget > 0 {
+                            let result = socket.send(|buffer| {
+                                let len = buffer.len().min(budget);
+                                let n = control.send_buffer.dequeue_slice(&mut buffer[..len]);
+                                (n, n)
+                            });
+
+                            match result {
+                                Ok(n) => {
+                                    has_sent = true;
+                                    budget -= n;
+                                    sent += n;
+                                }
+                                Err(err) => {
+                                    error!("socket send error: {}", err);
+                                    sockets_to_remove.push(socket_handle);
+                                    control.close_with_error(ErrorKind::ConnectionAborted);
+                                    break;
+                                }
+                            }
+                        }
+
+                        scheduler.served(socket_handle, sent, !control.send_buffer.is_empty());
+                        if send_allowance != usize::MAX {
+                            control.consume_bandwidth(BandwidthDirection::Send, sent);
+                        }
+
+                        // Left for the next round, which starts right after polling the frames of others, or after
+                        // the bandwidth limits are refilled
+                        let recv_left = recv_budget == 0 && socket.can_recv() && !control.recv_buffer.is_full();
+                        let send_left = socket.can_send() && !control.send_buffer.is_empty();
+                        let mut throttled = None;
+                        if recv_left && recv_limited {
+                            throttled = Some(control.bandwidth_refill_delay(BandwidthDirection::Recv));
+                        }
+                        if send_left && sent == send_allowance {
+                            let delay = control.bandwidth_refill_delay(BandwidthDirection::Send);
+                            throttled = Some(throttled.map_or(delay, |d| d.min(delay)));
+                        }
+                        if let Some(delay) = throttled {
+                            // Not shorter than the manager could park
+                            let delay = delay.max(Duration::from_millis(1));
+                            throttle_delay = Some(throttle_delay.map_or(delay, |d| d.min(delay)));
+                            pending_sockets.insert(socket_handle);
+                        } else if (recv_left && recv_budget == 0) || (send_left && sent == round_budget) {
+                            has_exhausted = true;
+                        }
+
+                        // Remote half-closed, FIN is sent after all its data, client's direction is kept
+                        if control.should_queue_fin() {
+                            socket.close();
+                            control.fin_queued();
+                        }
+
+                        // Shutdown finishes after client received all the data, not when it is queued
+                        if control.fin_queued
+                            && !control.fin_acked
+                            && socket.send_queue() == 0
+                            && !matches!(
+                                socket.state(),
+                                TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck
+                            )
+                        {
+                            control.fin_acked();
+                        }
+
+                        control.update_send_queue(socket.send_queue());
+
+                        if has_sent && control.send_waker.is_some() {
+                            if let Some(waker) = control.send_waker.take() {
+                                waker.wake();
+                            }
+                        }
+
+                        if control.check_stalled(has_received || has_sent, now) {
+                            warn!(
+                                "TCP connection {} <-> {} reset, no progress in both directions with full buffers for {:?}",
+                                socket.remote_endpoint(),
+                                socket.local_endpoint(),
+                                control.stall_timeout.unwrap_or_default()
+                            );
+                            // RST is sent in the next poll, the socket is removed after that
+                            socket.abort();
+                            control.close();
+                        } else if control.stalled_since.is_some() {
+                            has_stalled = true;
+                        } else if control.check_dead_peer(has_received || has_sent, socket.send_queue(), now) {
+                            warn!(
+                                "TCP connection {} <-> {} reset, no progress in both directions with data pending for {:?}",
+                                socket.remote_endpoint(),
+                                socket.local_endpoint(),
+                                control.dead_peer_timeout.unwrap_or_default()
+                            );
+                            socket.abort();
+                            control.dead_peer = true;
+                            control.close();
+                        } else if control.dead_peer_since.is_some() {
+                            // Checked as often as stalled ones, so it is reset soon after the timeout
+                            has_stalled = true;
+                        } else if control.check_idle(has_received || has_sent, now) {
+                            debug!(
+                                "TCP connection {} <-> {} reset, idle for {:?}",
+                                socket.remote_endpoint(),
+                                socket.local_endpoint(),
+                                control.idle_timeout.unwrap_or_default()
+                            );
+                            socket.abort();
+                            control.close();
+                        }
+
+                        // Still has work to do without being changed by frames or the relay task
+                        if control.is_closed
+                            || (control.fin_queued && !control.fin_acked)
+                            || control.flush_waker.is_some()
+                            || has_received
+                            || has_sent
+                            || !control.send_buffer.is_empty()
+                            || control.stalled_since.is_some()
+                            || control.dead_peer_since.is_some()
+                        {
+                            pending_sockets.insert(socket_handle);
+                        }
+                    }
+
+                    for (socket_handle, connected_key) in accepted_sockets {
+                        if let Some(socket) = sockets.get_mut(&socket_handle) {
+                            socket.connected_key = Some(connected_key);
+                            connected_sockets.insert(connected_key, socket_handle);
+                        }
+                    }
+
+                    for socket_handle in sockets_to_remove {
+                        if let Some(socket) = sockets.remove(&socket_handle) {
+                            // A newer socket of the same connection may have replaced it
+                            if created_sockets.get(&socket.key) == Some(&socket_handle) {
+                                created_sockets.remove(&socket.key);
+                            }
+                            if let Some(ref key) = socket.connected_key {
+                                if connected_sockets.get(key) == Some(&socket_handle) {
+                                    connected_sockets.remove(key);
+                                }
+                            }
+                        }
+                        pending_sockets.remove(&socket_handle);
+                        listening_sockets.remove(&socket_handle);
+                        scheduler.remove(&socket_handle);
+                        iface.remove_socket(socket_handle);
+                    }
+
+                    if closing {
+                        // Finished once FIN is acknowledged, clients may not close their side at all
+                        let unfinished = sockets
+                            .keys()
+                            .filter(|&&socket_handle| {
+                                let socket = iface.get_socket::<TcpSocket>(socket_handle);
+                                !matches!(
+                                    socket.state(),
+                                    TcpState::Closed | TcpState::FinWait2 | TcpState::TimeWait
+                                )
+                            })
+                            .count();
+                        unfinished_sockets.store(unfinished, Ordering::Release);
+                    }
+
+                    let mut next_duration = iface.poll_delay(before_poll).unwrap_or(SmolDuration::from_millis(5));
+                    if has_stalled {
+                        next_duration = next_duration.min(SmolDuration::from(TCP_STALL_CHECK_INTERVAL));
+                    }
+                    if let Some(delay) = throttle_delay {
+                        next_duration = next_duration.min(SmolDuration::from(delay));
+                    }
+                    // Nothing could be sent until `recv_packet` drains the out queue and wakes us up
+                    let device_exhausted = iface.device_mut().take_exhausted();
+                    if !sweep_sockets.is_empty() || !deferred_sockets.is_empty() || has_exhausted {
+                        // Continue the sweep, the deferred sockets or the exhausted budgets after polling the new frames
+                        next_duration = SmolDuration::ZERO;
+                    }
+                    if device_exhausted {
+                        next_duration = next_duration.max(SmolDuration::from(TCP_STALL_CHECK_INTERVAL));
+                    }
+                    if next_duration != SmolDuration::ZERO {
+                        thread::park_timeout(Duration::from(next_duration));
+                    }
+                }
+
+                trace!("VirtDevice::poll thread exited");
+            })
+        };
+
+        let manager_notify = Arc::new(ManagerNotify::new(
+            manager_handle.thread().clone(),
+            dirty_connections,
+            manager_notified,
+        ));
+
+        TcpTun {
+            context,
+            manager_handle: Some(manager_handle),
+            manager_notify,
+            manager_socket_creation_tx,
+            manager_running,
+            manager_closing,
+            unfinished_sockets,
+            balancer,
+            iface_rx,
+            iface_tx,
+            device_stat,
+            poll_stat,
+            shared_poll_metrics,
+            shared_clock,
+            connection_states: Arc::new(SpinMutex::new(HashMap::new())),
+            traffic: Arc::new(TcpTrafficTotals::default()),
+            imported_connections: HashMap::new(),
+            accepted_tx: None,
+            accepted_rx: None,
+            buffer_profiles: Vec::new(),
+            sniff_config: SniffConfig::default(),
+            stall_timeout: None,
+            idle_timeout: Some(DEFAULT_TCP_IDLE_TIMEOUT),
+            dead_peer_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            connect_timeout: None,
+            first_byte_timeout: None,
+            reset_on_remote_failure: false,
+            server_down_watcher: None,
+            early_data_policy: TcpEarlyDataPolicy::Buffer,
+            proxy_protocol: None,
+            outbound_fwmark: None,
+            resolve_strategy: None,
+            relay_tasks: TcpRelayTasks::default(),
+            max_relay_tasks: None,
+            live_sockets: Arc::new(AtomicUsize::new(0)),
+            max_connections: None,
+            recv_buffer_autotune: None,
+            round_budget: None,
+            bandwidth_limit: None,
+            global_bandwidth_limit: None,
+            conn_rate_limit: None,
+            default_keepalive: Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT),
+            nodelay: false,
+            shared_mtu,
+            out_queue_limit,
+            mtu_blackhole: MtuBlackholeDetector::new(mtu),
+            mss_clamp: TcpMssClamp::default(),
+            flow_affinity: None,
+            server_selector: None,
+            target_rewriter: Arc::new(IdentityTargetRewriter),
+            events: None,
+            dns_resolver: None,
+            accept_cidrs,
+            #[cfg(feature = "local-tun-capture")]
+            frame_capture: None,
+        }
+    }
+
+    /// Set buffer profiles for connections to specific destinations, the first matched profile is used
+    pub fn set_buffer_profiles(&mut self, profiles: Vec<TcpBufferProfile>) {
+        self.buffer_profiles = profiles;
+    }
+
+    /// Reset connections deadlocked for `stall_timeout`, that both the client and the remote stopped reading
+    pub fn set_stall_timeout(&mut self, stall_timeout: Option<Duration>) {
+        self.stall_timeout = stall_timeout;
+    }
+
+    /// Reset connections making no progress in both directions for `dead_peer_timeout` while data is pending
+    ///
+    /// It detects a client or a remote that silently stopped responding in the middle of a transfer, which would be
+    /// kept until the keep-alive or idle timeout otherwise. Pending reads and writes of the relay fail with
+    /// `TimedOut`. Disabled by default, clients keeping a zero window for longer are also reset.
+    pub fn set_dead_peer_timeout(&mut self, dead_peer_timeout: Option<Duration>) {
+        self.dead_peer_timeout = dead_peer_timeout;
+    }
+
+    /// Default read timeout of new connections, see `TcpConnection::set_read_timeout`
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Default write timeout of new connections, see `TcpConnection::set_write_timeout`
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    /// Reset connections without data in both directions for `idle_timeout`, `DEFAULT_TCP_IDLE_TIMEOUT` by default
+    ///
+    /// It is also the timeout of clients acknowledging data sent to them.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Reset proxied connections if the server sends nothing in `first_byte_timeout` after the client's first payload
+    pub fn set_first_byte_timeout(&mut self, first_byte_timeout: Option<Duration>) {
+        self.first_byte_timeout = first_byte_timeout;
+    }
+
+    /// Reset connections whose remotes are not connected in `connect_timeout`, instead of waiting for the system's
+    /// connect timeout
+    pub fn set_connect_timeout(&mut self, connect_timeout: Option<Duration>) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    /// Reset clients' connections instead of closing them if the outbound connection fails in the middle of relaying
+    pub fn set_reset_on_remote_failure(&mut self, reset_on_remote_failure: bool) {
+        self.reset_on_remote_failure = reset_on_remote_failure;
+    }
+
+    /// Reset connections relayed through a server when the balancer marks its TCP down, so clients reconnect through
+    /// healthy servers. Disabled by default, connections keep their servers until the outbound connections fail
+    pub fn set_reset_on_server_down(&mut self, enabled: bool) {
+        if let Some(watcher) = self.server_down_watcher.take() {
+            watcher.abort();
+        }
+        if !enabled {
+            return;
+        }
+
+        let mut events = self.balancer.subscribe_health_events();
+        let connection_states = self.connection_states.clone();
+        let manager_notify = self.manager_notify.clone();
+        self.server_down_watcher = Some(tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(ServerHealthEvent {
+                        addr,
+                        server_type: ServerType::Tcp,
+                        transition: ServerHealthTransition::Down,
+                        ..
+                    }) => {
+                        let reset = reset_server_connections(&connection_states, &manager_notify, &addr);
+                        if reset > 0 {
+                            debug!("reset {} TCP connections of server {} marked down", reset, addr);
+                        }
+                    }
+                    Ok(..) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("missed {} server health events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }));
+    }
+
+    /// Set `SO_MARK` of outbound sockets, overriding the one in `ConnectOpts` of the context
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_outbound_fwmark(&mut self, fwmark: Option<u32>) {
+        self.outbound_fwmark = fwmark;
+    }
+
+    /// Set behavior for data sent by clients before the outbound connection is established, buffered by default
+    pub fn set_early_data_policy(&mut self, early_data_policy: TcpEarlyDataPolicy) {
+        self.early_data_policy = early_data_policy;
+    }
+
+    /// Write a PROXY protocol header of `version` with the client's endpoints to upstreams before relaying, `None`
+    /// by default
+    pub fn set_proxy_protocol(&mut self, version: Option<ProxyProtocolVersion>) {
+        self.proxy_protocol = version;
+    }
+
+    /// Set how domain name targets connected directly are resolved and connected, `None` races IPv4 and IPv6
+    /// addresses like `ResolveStrategy::Parallel`
+    pub fn set_resolve_strategy(&mut self, strategy: Option<ResolveStrategy>) {
+        self.resolve_strategy = strategy;
+    }
+
+    /// Limit the number of live relay tasks, new connections are reset while the limit is reached
+    pub fn set_max_relay_tasks(&mut self, max_relay_tasks: Option<usize>) {
+        self.max_relay_tasks = max_relay_tasks;
+    }
+
+    /// Limit the number of sockets, including half-open and closing ones, new connections are reset while the limit
+    /// is reached. Unlimited by default
+    ///
+    /// Every socket holds its buffers until it is removed by the manager, it bounds memory under SYN floods.
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) {
+        self.max_connections = max_connections;
+    }
+
+    /// Grow buffers of data from clients up to `max` bytes while they are the bottleneck of connections, like Linux's
+    /// receive buffer auto-tuning. Disabled by default
+    pub fn set_recv_buffer_autotune(&mut self, max: Option<u32>) {
+        self.recv_buffer_autotune = max;
+    }
+
+    /// Move at most `budget` bytes in each direction of a connection in a round of the manager, unlimited by default
+    ///
+    /// The rest is moved in the next rounds, so a busy connection doesn't delay others and new SYNs.
+    pub fn set_round_budget(&mut self, budget: Option<usize>) {
+        self.round_budget = budget.map(|budget| budget.max(1));
+    }
+
+    /// Limit each connection to `rate` bytes per second in each direction with bursts of `burst` bytes
+    ///
+    /// Throttled data waits in the connection's buffers, clients and remotes are slowed down by back-pressure.
+    pub fn set_bandwidth_limit(&mut self, rate: u64, burst: u64) {
+        self.bandwidth_limit = Some(TcpBandwidthLimit::new(rate, burst));
+    }
+
+    /// Limit all connections together to `rate` bytes per second in each direction with bursts of `burst` bytes
+    pub fn set_global_bandwidth_limit(&mut self, rate: u64, burst: u64) {
+        self.global_bandwidth_limit = Some(Arc::new(SpinMutex::new(TcpBandwidthLimit::new(rate, burst))));
+    }
+
+    /// Limit new connections of each source IP to `rate` per second with bursts of `burst`, excess SYNs are reset
+    pub fn set_conn_rate_limit(&mut self, rate: u32, burst: u32) {
+        self.conn_rate_limit = Some(TcpConnRateLimit::new(rate, burst));
+    }
+
+    /// Counters of connections rejected by the rate limit
+    pub fn conn_rate_stat(&self) -> TcpConnRateStat {
+        self.conn_rate_limit
+            .as_ref()
+            .map(TcpConnRateLimit::stat)
+            .unwrap_or_default()
+    }
+
+    /// Keep-alive of connections if it isn't set in `AcceptOpts`, `None` disables it
+    pub fn set_default_keepalive(&mut self, keepalive: Option<Duration>) {
+        self.default_keepalive = keepalive;
+    }
+
+    /// Disable Nagle's algorithm of connections, small writes are sent without waiting for earlier data's ACKs
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Change MTU of the TCP stack, e.g. after the path MTU of tun changed
+    ///
+    /// Connections are kept, segments sent after the manager's next round fit in the new MTU. See
+    /// `rebuild_interface` for segments already sent.
+    pub fn set_mtu(&mut self, mtu: u32) {
+        self.shared_mtu.store(mtu as usize, Ordering::Release);
+        self.mtu_blackhole.set_mtu(mtu);
+        self.manager_notify.notify();
+    }
+
+    /// Limit frames produced by the TCP stack waiting to be read by `recv_packet`, `DEFAULT_OUT_QUEUE_LIMIT` by default
+    ///
+    /// Sockets stop sending while the queue is full, until it is drained. Replies to received frames are dropped.
+    pub fn set_out_queue_limit(&mut self, limit: usize) {
+        self.out_queue_limit.store(limit.max(1), Ordering::Relaxed);
+        self.manager_notify.notify();
+    }
+
+    /// Clamp MSS of new connections on paths where an MTU black hole was detected
+    pub fn set_clamp_mss_on_mtu_blackhole(&mut self, clamp_mss: bool) {
+        self.mtu_blackhole.set_clamp_mss(clamp_mss);
+    }
+
+    /// Maximum segment sizes in each direction, for paths with different MTUs or encapsulation overhead
+    ///
+    /// Nothing is clamped by default, MSS is derived from the MTU.
+    pub fn set_mss_clamp(&mut self, mss_clamp: TcpMssClamp) {
+        self.mss_clamp = mss_clamp;
+    }
+
+    /// Connect flows started within `window` after another flow of the same client IP through the same server
+    ///
+    /// Disabled by default, every flow selects its server independently.
+    pub fn set_flow_affinity(&mut self, window: Option<Duration>) {
+        self.flow_affinity = window.map(FlowAffinity::new);
+    }
+
+    /// Choose servers of connections with `selector`, overriding the balancer's `ServerSelector`
+    ///
+    /// The selector sees the sniffed target address if sniffing is enabled. Connections preferring a server, handed
+    /// over or correlated by flow affinity, are not passed to it.
+    pub fn set_server_selector(&mut self, selector: Option<Arc<dyn ServerSelector>>) {
+        self.server_selector = selector;
+    }
+
+    /// Rewrite targets of connections with `rewriter` before connecting, `IdentityTargetRewriter` by default
+    pub fn set_target_rewriter(&mut self, rewriter: Arc<dyn TargetRewriter>) {
+        self.target_rewriter = rewriter;
+    }
+
+    /// Broadcast lifecycle events of connections to subscribers of `events`, nothing is sent by default
+    ///
+    /// Only connections relayed by the TCP stack are reported, not the ones handed over to the acceptor. Events are
+    /// dropped if there is no subscriber, lagging subscribers miss the oldest ones.
+    pub fn set_events(&mut self, events: Option<broadcast::Sender<TunEvent>>) {
+        self.events = events;
+    }
+
+    /// Resolve domain names of servers and bypassed targets with `resolver` instead of the context's, e.g. for
+    /// split-horizon DNS. `None` by default
+    ///
+    /// Targets connected through servers are still resolved by the servers.
+    pub fn set_dns_resolver(&mut self, resolver: Option<Arc<DnsResolver>>) {
+        self.dns_resolver = resolver;
+    }
+
+    /// Number of live relay tasks
+    pub fn relay_tasks(&self) -> TcpRelayTasks {
+        self.relay_tasks.clone()
+    }
+
+    /// Sniff hostname from the client's first bytes, then connect to the hostname instead of the IP destination
+    pub fn set_sniff_config(&mut self, sniff_config: SniffConfig) {
+        self.sniff_config = sniff_config;
+    }
+
+    /// Connection tracking of all active connections
+    pub fn conntrack(&self) -> TcpConnTrack {
+        TcpConnTrack {
+            states: self.connection_states.clone(),
+            traffic: self.traffic.clone(),
+            live_sockets: self.live_sockets.clone(),
+        }
+    }
+
+    /// Number of active connections
+    pub fn connection_count(&self) -> usize {
+        self.connection_states.lock().len()
+    }
+
+    /// Occupancy of relay buffers of all active connections, see `TcpConnTrack::buffer_occupancy`
+    pub fn buffer_occupancy(&self) -> Vec<TcpBufferOccupancy> {
+        self.conntrack().buffer_occupancy()
+    }
+
+    /// Export states of all active connections
+    pub fn export_connections(&self) -> Vec<TcpConnectionState> {
+        let states = self.connection_states.lock();
+        states
+            .iter()
+            .map(|(&(src_addr, dst_addr), entry)| TcpConnectionState {
+                src_addr,
+                dst_addr,
+                server_addr: entry.server_addr.clone(),
+            })
+            .collect()
+    }
+
+    /// Import connection states exported by `export_connections`
+    ///
+    /// Connections reconnecting with the same (source, destination) will be established through the same server
+    /// if it is still in the balancer. The in-flight data of these connections are lost.
+    pub fn import_connections<I>(&mut self, states: I)
+    where
+        I: IntoIterator<Item = TcpConnectionState>,
+    {
+        for state in states {
+            if let Some(server_addr) = state.server_addr {
+                self.imported_connections
+                    .insert((state.src_addr, state.dst_addr), server_addr);
+            }
+        }
+    }
+
+    /// Hand accepted connections over to the `TcpAcceptor` instead of tunneling them through the balancer, disabled by
+    /// default
+    ///
+    /// SYNs are handled and sockets are created the same way. Connections queued for the acceptor are dropped when
+    /// it's disabled. Accepted connections are not listed in `conntrack`.
+    pub fn set_accept_connections(&mut self, enabled: bool) {
+        if enabled {
+            if self.accepted_tx.is_none() {
+                let (accepted_tx, accepted_rx) = mpsc::unbounded_channel();
+                self.accepted_tx = Some(accepted_tx);
+                self.accepted_rx = Some(accepted_rx);
+            }
+        } else {
+            self.accepted_tx = None;
+            self.accepted_rx = None;
+        }
+    }
+
+    /// Take the acceptor of connections, after accepting was enabled by `set_accept_connections`
+    ///
+    /// Returns `None` if accepting isn't enabled or the acceptor was taken already.
+    pub fn acceptor(&mut self) -> Option<TcpAcceptor> {
+        self.accepted_rx.take().map(|accepted_rx| TcpAcceptor { accepted_rx })
+    }
+
+    /// Handle a TCP packet from client before it is fed to the stack, `traffic_class` is its IP header's TOS byte or
+    /// traffic class
+    ///
+    /// Connections are created on SYNs. The DSCP of the SYN is applied to the outbound connection, so QoS markings
+    /// of clients are kept through the tunnel.
+    pub async fn handle_packet(
+        &mut self,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        traffic_class: u8,
+        tcp_packet: &TcpPacket<&[u8]>,
+    ) -> io::Result<()> {
+        if !self.accepts_destination(dst_addr.ip()) {
+            // Its frames are dropped by `drive_interface_state` too, left for the host to route
+            trace!(
+                "TCP packet {} -> {} ignored, destination is not accepted",
+                src_addr,
+                dst_addr
+            );
+            return Ok(());
+        }
+
+        self.mtu_blackhole.inbound(src_addr, dst_addr, tcp_packet);
+
+        // TCP first handshake packet, create a new Connection
+        if tcp_packet.syn() && !tcp_packet.ack() {
+            if self.manager_closing.load(Ordering::Relaxed) {
+                // No socket is listening, the interface will reply with a RST
+                debug!("TCP connection {} <-> {} rejected, closing", src_addr, dst_addr);
+                return Ok(());
+            }
+
+            if let Some(max_relay_tasks) = self.max_relay_tasks {
+                if self.relay_tasks.count() >= max_relay_tasks {
+                    // No socket is listening, the interface will reply with a RST
+                    debug!(
+                        "TCP connection {} <-> {} rejected, reached {} relay tasks",
+                        src_addr, dst_addr, max_relay_tasks
+                    );
+                    return Ok(());
+                }
+            }
+
+            if let Some(max_connections) = self.max_connections {
+                if self.live_sockets.load(Ordering::Acquire) >= max_connections {
+                    // Same as above, replied with a RST
+                    debug!(
+                        "TCP connection {} <-> {} rejected, reached {} sockets",
+                        src_addr, dst_addr, max_connections
+                    );
+                    return Ok(());
+                }
+            }
+
+            if let Some(ref mut conn_rate_limit) = self.conn_rate_limit {
+                if !conn_rate_limit.check(src_addr.ip()) {
+                    // Same as above, replied with a RST
+                    debug!(
+                        "TCP connection {} <-> {} rejected, source is over the rate of new connections",
+                        src_addr, dst_addr
+                    );
+                    return Ok(());
+                }
+            }
+
+            let accept_opts = self.context.accept_opts();
+            let mut tcp_opts = profile_tcp_opts(&self.buffer_profiles, &accept_opts.tcp, dst_addr);
+            tcp_opts.keepalive = resolve_keepalive(&tcp_opts, self.default_keepalive);
+            tcp_opts.nodelay = self.nodelay;
+
+            // Socket and its buffers are dropped on failure, they were never handed to the manager
+            let live_socket = TcpLiveSocketGuard::new(self.live_sockets.clone());
+            let mut socket = create_listen_socket(dst_addr, &tcp_opts)?;
+            socket.set_timeout(self.idle_timeout.map(From::from));
+
+            trace!("created TCP connection for {} <-> {}", src_addr, dst_addr);
+
+            #[cfg(feature = "local-flight-recorder")]
+            self.context.flight_recorder_ref().record(
+                FlightProtocol::Tcp,
+                src_addr,
+                &Address::from(dst_addr),
+                FlightEventKind::SynReceived,
+            );
+
+            let connection = TcpConnection::new(
+                (src_addr, dst_addr),
+                socket,
+                &self.manager_socket_creation_tx,
+                self.manager_notify.clone(),
+                self.traffic.clone(),
+                &tcp_opts,
+                self.stall_timeout,
+                self.idle_timeout,
+            );
+            {
+                let mut control = connection.control.lock();
+                control.live_socket = Some(live_socket);
+                control.recv_buffer_max = self.recv_buffer_autotune.map(|max| max as usize);
+                control.dead_peer_timeout = self.dead_peer_timeout;
+                control.read_timeout = self.read_timeout;
+                control.write_timeout = self.write_timeout;
+                control.round_budget = self.round_budget;
+                control.bandwidth_limit = self.bandwidth_limit.clone();
+                control.global_bandwidth_limit = self.global_bandwidth_limit.clone();
+            }
+
+            if let Some(ref accepted_tx) = self.accepted_tx {
+                trace!("TCP connection {} <-> {} accepted", src_addr, dst_addr);
+                let _ = accepted_tx.send(TcpAcceptedConnection {
+                    connection,
+                    src_addr,
+                    dst_addr,
+                });
+                return Ok(());
+            }
+
+            let events = self
+                .events
+                .as_ref()
+                .map(|tx| TunEventSender::new(tx.clone(), src_addr, dst_addr));
+            if let Some(ref events) = events {
+                events.send(TunEventKind::Created);
+            }
+
+            // Connections handed over from the previous process prefer the same server
+            let mut preferred_server = self
+                .imported_connections
+                .remove(&(src_addr, dst_addr))
+                .and_then(|addr| self.balancer.find_server(&addr));
+
+            // Then the server of the client's related flow
+            if preferred_server.is_none() {
+                if let Some(ref flow_affinity) = self.flow_affinity {
+                    preferred_server = flow_affinity.server(&self.balancer, src_addr.ip());
+                    if let Some(ref server) = preferred_server {
+                        trace!(
+                            "TCP connection {} <-> {} correlated to server {}",
+                            src_addr,
+                            dst_addr,
+                            server.server_config().addr()
+                        );
+                    }
+                }
+            }
+
+            let tracker = TcpConnectionTracker::new(
+                self.connection_states.clone(),
+                (src_addr, dst_addr),
+                connection.control.clone(),
+            );
+            if self.early_data_policy == TcpEarlyDataPolicy::Delay {
+                tracker.set_delay_data(true);
+            }
+
+            // establish a tunnel
+            let context = self.context.clone();
+            let balancer = self.balancer.clone();
+            let sniff_config = self.sniff_config;
+            let flow_affinity = self.flow_affinity.clone();
+            let server_selector = self.server_selector.clone();
+            let target_rewriter = self.target_rewriter.clone();
+            let tos = outbound_tos(traffic_class);
+            let fwmark = self.outbound_fwmark;
+            let resolve_strategy = self.resolve_strategy;
+            let connect_timeout = self.connect_timeout;
+            let first_byte_timeout = self.first_byte_timeout;
+            let reset_on_remote_failure = self.reset_on_remote_failure;
+            let early_data_policy = self.early_data_policy;
+            let proxy_protocol = self.proxy_protocol;
+            let dns_resolver = self.dns_resolver.clone();
+            let relay_tasks = self.relay_tasks.clone();
+            let relay_task_guard = self.relay_tasks.start();
+            tokio::spawn(async move {
+                let _relay_task_guard = relay_task_guard;
+
+                // Result is logged with the sniffed destination
+                let result = handle_redir_client(
+                    context,
+                    balancer,
+                    connection,
+                    src_addr,
+                    dst_addr,
+                    preferred_server,
+                    server_selector,
+                    target_rewriter.as_ref(),
+                    tos,
+                    fwmark,
+                    resolve_strategy,
+                    tracker,
+                    sniff_config,
+                    flow_affinity,
+                    connect_timeout,
+                    first_byte_timeout,
+                    reset_on_remote_failure,
+                    early_data_policy,
+                    proxy_protocol,
+                    events.clone(),
+                    dns_resolver,
+                )
+                .await;
+
+                if let Some(events) = events {
+                    events.send(match result {
+                        Ok(ref summary) => TunEventKind::Closed {
+                            tx: summary.tx,
+                            rx: summary.rx,
+                            error: summary.remote_error.clone(),
+                        },
+                        Err(ref err) => TunEventKind::Closed {
+                            tx: 0,
+                            rx: 0,
+                            error: Some(err.to_string()),
+                        },
+                    });
+                }
+
+                if let Ok(TcpTunnelSummary {
+                    remote_error: Some(..), ..
+                }) = result
+                {
+                    relay_tasks.record_remote_failure();
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Counters of frames passing through the TCP stack's device
+    pub fn device_stats(&self) -> TunDeviceStat {
+        self.device_stat.clone()
+    }
+
+    /// Histogram of costs of the manager's polls
+    pub fn poll_stat(&self) -> TcpPollStat {
+        self.poll_stat.clone()
+    }
+
+    /// Report timing of every manager's poll to `metrics`, `None` by default
+    pub fn set_poll_metrics(&mut self, metrics: Option<Arc<dyn PollMetrics>>) {
+        self.shared_poll_metrics.set(metrics);
+    }
+
+    /// Check timeouts of connections by the time of `clock`, `SystemClock` by default
+    ///
+    /// The manager only wakes up to check them in the real time, a `ManualClock` moved forward is picked up by the
+    /// next round. Timeouts of relay tasks are driven by tokio's timer instead.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.shared_clock.set(clock);
+        self.manager_notify.notify();
+    }
+
+    /// Send `frame` to the interface and wake up the manager
+    ///
+    /// Fails with `BrokenPipe` if the manager thread exited, the TCP stack couldn't be recovered.
+    pub async fn drive_interface_state(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(key) = self.queue_frame(frame.to_vec())? {
+            if let Some(key) = key {
+                self.manager_notify.mark_dirty(key);
+            }
+
+            // Wake up and poll the interface.
+            self.manager_notify.notify();
+        }
+        Ok(())
+    }
+
+    /// Send all `frames` to the interface, then wake up the manager once
+    ///
+    /// The interface processes all queued frames in one poll, so bursts of frames cost a single round of the manager
+    /// instead of one for each frame. Fails like `drive_interface_state`, frames left are not sent.
+    pub async fn drive_interface_state_batch(&mut self, frames: impl IntoIterator<Item = Vec<u8>>) -> io::Result<()> {
+        let mut queued = false;
+        let mut keys = Vec::new();
+        for frame in frames {
+            if let Some(key) = self.queue_frame(frame)? {
+                queued = true;
+                keys.extend(key);
+            }
+        }
+
+        if queued {
+            self.manager_notify.mark_dirty_all(keys);
+            self.manager_notify.notify();
+        }
+        Ok(())
+    }
+
+    /// Queue `frame` for the interface without waking up the manager
+    ///
+    /// Returns `None` if the frame was dropped, or the connection that it belongs to. Fails if the manager exited.
+    fn queue_frame(&mut self, mut frame: Vec<u8>) -> io::Result<Option<Option<TcpConnectionKey>>> {
+        if self.accept_cidrs.is_some() {
+            if let Ok(Some(packet)) = IpPacket::new_checked(&frame) {
+                if !self.accepts_destination(packet.dst_addr()) {
+                    trace!("frame to {} dropped, destination is not accepted", packet.dst_addr());
+                    return Ok(None);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-tun-capture")]
+        self.capture_frame(FrameDirection::Inbound, &frame);
+
+        if self.early_data_policy == TcpEarlyDataPolicy::Delay && self.is_data_delayed(&frame) {
+            trace!("TCP segment with data dropped, connection is not established yet");
+            return Ok(None);
+        }
+
+        if let Some(mss) = self.mtu_blackhole.clamp_syn(&mut frame) {
+            debug!("TCP SYN's MSS clamped to {} on a path with MTU black hole", mss);
+        }
+        if let Some(mss) = self.mss_clamp.clamp_inbound(&mut frame) {
+            trace!("TCP SYN's MSS clamped to {} for sending", mss);
+        }
+
+        // Counted before sending, the manager may dequeue it immediately
+        self.device_stat.in_queued(frame.len());
+        let key = frame_connection_key(&frame);
+        if self.iface_tx.send(frame).is_err() {
+            return Err(self.manager_exited());
+        }
+        Ok(Some(key))
+    }
+
+    /// Check if connections to `dst` are accepted, by `accept_cidrs` of `TcpTun::new`
+    fn accepts_destination(&self, dst: IpAddr) -> bool {
+        match self.accept_cidrs {
+            None => true,
+            Some(ref cidrs) => {
+                let addr = IpAddress::from(dst);
+                cidrs.iter().any(|cidr| cidr.contains_addr(&addr))
+            }
+        }
+    }
+
+    /// Check if `frame` carries client's data of a connection that is still connecting
+    fn is_data_delayed(&self, frame: &[u8]) -> bool {
+        let (key, payload_len) = match frame_segment(frame) {
+            Some(s) => s,
+            None => return false,
+        };
+        payload_len > 0 && matches!(self.connection_states.lock().get(&key), Some(e) if e.delay_data)
+    }
+
+    /// Receive a frame sent by the TCP stack
+    ///
+    /// Fails with `BrokenPipe` if the manager thread exited unexpectedly, all connections were closed. It is fatal, the
+    /// TCP stack couldn't be recovered.
+    pub async fn recv_packet(&mut self) -> io::Result<Vec<u8>> {
+        match self.iface_rx.recv().await {
+            Some(mut v) => {
+                // Sockets stopped by the full queue could send again
+                if self.device_stat.out_dequeued(v.len()) >= self.out_queue_limit.load(Ordering::Relaxed) {
+                    self.manager_notify.notify();
+                }
+                let mtu = self.shared_mtu.load(Ordering::Relaxed);
+                if let Some(mss) = self.mss_clamp.clamp_outbound(&mut v, mtu) {
+                    trace!("TCP SYN-ACK's MSS clamped to {} for receiving", mss);
+                }
+                #[cfg(feature = "local-tun-capture")]
+                self.capture_frame(FrameDirection::Outbound, &v);
+                if let Some(blackhole) = self.mtu_blackhole.outbound_frame(&v) {
+                    warn!("{}", blackhole);
+                }
+                Ok(v)
+            }
+            None => Err(self.manager_exited()),
+        }
+    }
+
+    /// Capture frames entering and leaving the TCP stack, pass `None` to stop capturing
+    #[cfg(feature = "local-tun-capture")]
+    pub fn set_frame_capture(&mut self, capture: Option<FrameCapture>) {
+        self.frame_capture = capture;
+    }
+
+    /// Capturing stops on the first write error, it is not worth breaking the TCP stack
+    #[cfg(feature = "local-tun-capture")]
+    fn capture_frame(&mut self, direction: FrameDirection, frame: &[u8]) {
+        if let Some(ref mut capture) = self.frame_capture {
+            if let Err(err) = capture.record(direction, frame) {
+                error!("failed to write TUN frame capture, capturing stopped, error: {}", err);
+                self.frame_capture = None;
+            }
+        }
+    }
+
+    /// Close all connections gracefully, new connections are refused
+    ///
+    /// Data already received from remotes is sent to clients before FIN. Frames have to be exchanged by
+    /// `drive_interface_state` and `recv_packet` until `is_closed`, otherwise clients would never receive or
+    /// acknowledge the FINs. Connections left are aborted when `TcpTun` is dropped.
+    pub fn close(&mut self) {
+        self.manager_closing.store(true, Ordering::Release);
+        self.manager_notify.notify();
+    }
+
+    /// Check if FINs of all connections were acknowledged by clients after `close`
+    pub fn is_closed(&self) -> bool {
+        self.manager_closing.load(Ordering::Acquire) && self.unfinished_sockets.load(Ordering::Acquire) == 0
+    }
+
+    /// Error of the manager thread exited while `TcpTun` is still running
+    fn manager_exited(&mut self) -> io::Error {
+        let reason = match self.manager_handle.take().map(JoinHandle::join) {
+            Some(Err(payload)) => match payload.downcast::<String>() {
+                Ok(msg) => format!("panicked: {}", msg),
+                Err(payload) => match payload.downcast::<&'static str>() {
+                    Ok(msg) => format!("panicked: {}", msg),
+                    Err(..) => "panicked".to_owned(),
+                },
+            },
+            Some(Ok(..)) => "exited".to_owned(),
+            None => "exited previously".to_owned(),
+        };
+        error!("TCP stack manager {}, all TCP connections are closed", reason);
+        io::Error::new(ErrorKind::BrokenPipe, format!("TCP stack manager {}", reason))
+    }
+}
+
+fn endpoint_addr(endpoint: IpEndpoint) -> Option<SocketAddr> {
+    match endpoint.addr {
+        IpAddress::Ipv4(..) | IpAddress::Ipv6(..) => Some(SocketAddr::new(IpAddr::from(endpoint.addr), endpoint.port)),
+        _ => None,
+    }
+}
+
+/// Connection of a TCP frame sent by a client
+fn frame_connection_key(frame: &[u8]) -> Option<TcpConnectionKey> {
+    frame_segment(frame).map(|(key, ..)| key)
+}
+
+/// Connection of a TCP frame and the length of its payload
+fn frame_segment(frame: &[u8]) -> Option<(TcpConnectionKey, usize)> {
+    let packet = match IpPacket::new_checked(frame) {
+        Ok(Some(p)) if p.protocol() == IpProtocol::Tcp => p,
+        _ => return None,
+    };
+    let tcp_packet = TcpPacket::new_checked(packet.payload()).ok()?;
+    let key = (
+        SocketAddr::new(packet.src_addr(), tcp_packet.src_port()),
+        SocketAddr::new(packet.dst_addr(), tcp_packet.dst_port()),
+    );
+    Some((key, tcp_packet.payload().len()))
+}
+
+/// Create a smoltcp socket listening on `dst_addr`, waiting for the client's SYN to be processed by the interface
+///
+/// Listening on a fresh socket only fails if the destination is unaddressable (port 0), which is permanent. Other
+/// failures are unexpected, the socket will be recreated and retried for `TCP_LISTEN_MAX_ATTEMPTS` times.
+/// Create a socket listening on `dst_addr`
+///
+/// smoltcp doesn't implement congestion control, it sends as much as the client's window permits, so there is no
+/// initial window of our own to enlarge. `TcpSocketOpts::initial_window` is applied to the client side instead:
+///
+/// - The receive buffer is enlarged to hold the initial window, our advertised window won't clamp client's first flight
+/// - Delayed ACK is disabled, client's slow start grows with every segment
+///
+/// Other congestion knobs, such as the slow start threshold or the congestion algorithm, are not supported by smoltcp.
+fn create_listen_socket(dst_addr: SocketAddr, tcp_opts: &TcpSocketOpts) -> io::Result<TcpSocket<'static>> {
+    let send_buffer_size = tcp_opts.send_buffer_size.unwrap_or(DEFAULT_TCP_SEND_BUFFER_SIZE);
+    let mut recv_buffer_size = tcp_opts.recv_buffer_size.unwrap_or(DEFAULT_TCP_RECV_BUFFER_SIZE);
+    if let Some(initial_window) = tcp_opts.initial_window {
+        let initial_window_size = initial_window.saturating_mul(TCP_INITIAL_WINDOW_SEGMENT_SIZE);
+        recv_buffer_size = recv_buffer_size.max(initial_window_size);
+    }
+
+    let mut last_err = SmolError::Illegal;
+    for attempt in 1..=TCP_LISTEN_MAX_ATTEMPTS {
+        let mut socket = TcpSocket::new(
+            TcpSocketBuffer::new(vec![0u8; recv_buffer_size as usize]),
+            TcpSocketBuffer::new(vec![0u8; send_buffer_size as usize]),
+        );
+
+        match socket.listen(dst_addr) {
+            Ok(..) => {
+                // Options must be set after `listen`, which resets them to defaults
+                socket.set_keep_alive(tcp_opts.keepalive.map(From::from));
+                socket.set_nagle_enabled(!tcp_opts.nodelay);
+                // NO ACK delay
+                if tcp_opts.initial_window.is_some() {
+                    socket.set_ack_delay(None);
+                }
+                return Ok(socket);
+            }
+            Err(SmolError::Unaddressable) => {
+                let err = io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("TCP listen {} failed permanently, address is unaddressable", dst_addr),
+                );
+                return Err(err);
+            }
+            Err(err) => {
+                debug!(
+                    "TCP listen {} failed (attempt {}/{}), error: {}",
+                    dst_addr, attempt, TCP_LISTEN_MAX_ATTEMPTS, err
+                );
+                last_err = err;
+            }
+        }
+    }
+
+    let err = io::Error::new(
+        ErrorKind::Other,
+        format!(
+            "TCP listen {} failed after {} attempts, error: {}",
+            dst_addr, TCP_LISTEN_MAX_ATTEMPTS, last_err
+        ),
+    );
+    Err(err)
+}
+
+/// Established Client Transparent Proxy
+///
+/// This method must be called after handshaking with client (for example, socks5 handshaking)
+#[allow(clippy::too_many_arguments)]
+async fn establish_client_tcp_redir(
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    mut stream: TcpConnection,
+    peer_addr: SocketAddr,
+    addr: &Address,
+    preferred_server: Option<Arc<ServerIdent>>,
+    server_selector: Option<Arc<dyn ServerSelector>>,
+    tos: Option<u8>,
+    fwmark: Option<u32>,
+    resolve_strategy: Option<ResolveStrategy>,
+    tracker: TcpConnectionTracker,
+    sniff_config: SniffConfig,
+    flow_affinity: Option<FlowAffinity>,
+    connect_timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+    reset_on_remote_failure: bool,
+    early_data_policy: TcpEarlyDataPolicy,
+    proxy_header: Option<Vec<u8>>,
+    events: Option<TunEventSender>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+) -> io::Result<TcpTunnelSummary> {
+    #[cfg(feature = "local-audit")]
+    let start_time = SystemTime::now();
+
+    // Sniffing has to read the client's data before connecting
+    if sniff_config.is_enabled() {
+        tracker.set_delay_data(false);
+    }
+
+    // Bytes read while sniffing will be relayed first
+    let mut sniffed = Vec::new();
+    let addr = match sniff_target_addr(&mut stream, &mut sniffed, peer_addr, addr, sniff_config).await {
+        Ok(addr) => addr,
+        Err(err) => {
+            let result = Err(err);
+            log_tcp_tunnel_result(&context, peer_addr, addr, &result);
+            return result;
+        }
+    };
+    let addr = &addr;
+
+    let connect = connect_remote(
+        &context,
+        &balancer,
+        peer_addr,
+        addr,
+        preferred_server,
+        server_selector.as_deref(),
+        tos,
+        fwmark,
+        resolve_strategy,
+        dns_resolver.as_deref(),
+    );
+    // Timed out like other failures of connecting, so the client is reset promptly
+    let connect = async {
+        match connect_timeout {
+            Some(timeout) => match time::timeout(timeout, connect).await {
+                Ok(r) => r,
+                Err(..) => Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("connecting took longer than {:?}", timeout),
+                )),
+            },
+            None => connect.await,
+        }
+    };
+    let connected = match early_data_policy {
+        TcpEarlyDataPolicy::Reset { timeout } => {
+            let early_data = async {
+                time::sleep(timeout).await;
+                if sniffed.is_empty() {
+                    stream.readable().await;
+                }
+            };
+            tokio::select! {
+                biased;
+                r = connect => r,
+                _ = early_data => {
+                    // Client is waiting for the response of data that couldn't be sent in time
+                    stream.set_reset_on_close();
+                    Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("client sent data but connecting took longer than {:?}", timeout),
+                    ))
+                }
+            }
+        }
+        TcpEarlyDataPolicy::Buffer | TcpEarlyDataPolicy::Delay => connect.await,
+    };
+    let (server, mut remote) = match connected {
+        Ok(r) => r,
+        Err(err) => {
+            // Client is refused promptly, instead of being accepted and closed, or waiting for its handshake to time out
+            stream.set_reset_on_close();
+
+            #[cfg(feature = "local-flight-recorder")]
+            context.flight_recorder_ref().record(
+                FlightProtocol::Tcp,
+                peer_addr,
+                addr,
+                FlightEventKind::ConnectFailed { error: err.to_string() },
+            );
+
+            #[cfg(feature = "local-audit")]
+            if let Some(audit_sink) = context.audit_sink() {
+                let mut record = AuditRecord::tcp(start_time, peer_addr, addr, None);
+                record.error = Some(err.to_string());
+                audit_sink.record(record);
+            }
+
+            let result = Err(err);
+            log_tcp_tunnel_result(&context, peer_addr, addr, &result);
+            return result;
+        }
+    };
+    let svr_cfg = server.server_config();
+    tracker.set_delay_data(false);
+
+    if remote.is_proxied() {
+        tracker.set_server_addr(svr_cfg.addr());
+        if let Some(flow_affinity) = flow_affinity {
+            flow_affinity.record(peer_addr.ip(), svr_cfg.addr());
+        }
+    }
+    record_outbound(&context, &tracker, &remote);
+
+    if let Some(events) = events {
+        events.send(TunEventKind::Established {
+            target: addr.clone(),
+            server: if remote.is_proxied() {
+                Some(svr_cfg.addr().clone())
+            } else {
+                None
+            },
+        });
+    }
+
+    #[cfg(feature = "local-flight-recorder")]
+    context.flight_recorder_ref().record(
+        FlightProtocol::Tcp,
+        peer_addr,
+        addr,
+        FlightEventKind::Connected {
+            server: if remote.is_proxied() {
+                Some(svr_cfg.addr().clone())
+            } else {
+                None
+            },
+        },
+    );
+
+    // Upstream reads the header before any of client's data
+    if let Some(ref proxy_header) = proxy_header {
+        if let Err(err) = remote.write_all(proxy_header).await {
+            stream.set_reset_on_close();
+            let result = Err(err);
+            log_tcp_tunnel_result(&context, peer_addr, addr, &result);
+            return result;
+        }
+    }
+
+    let result = if !remote.is_proxied() && sniffed.is_empty() {
+        establish_tcp_tunnel_direct(&mut stream, &mut remote, peer_addr, addr).await
+    } else {
+        let mut client = PrefixedStream::new(sniffed, &mut stream);
+        let result = establish_tcp_tunnel(&server, &mut client, &mut remote, peer_addr, addr, first_byte_timeout).await;
+        drop(client);
+        result
+    };
+
+    match result {
+        Err(ref err) if is_first_byte_timeout(err) => {
+            // Client is waiting for the response of a stalled server
+            stream.set_reset_on_close();
+        }
+        Ok(TcpTunnelSummary {
+            remote_error: Some(..), ..
+        }) if reset_on_remote_failure => {
+            // Client may have received a part of the response, it shouldn't be taken as complete
+            stream.set_reset_on_close();
+        }
+        _ => {}
+    }
+
+    #[cfg(any(feature = "local-flight-recorder", feature = "local-audit"))]
+    let (tx, rx) = {
+        let control = stream.control.lock();
+        (control.relayed_tx, control.relayed_rx)
+    };
+
+    #[cfg(feature = "local-flight-recorder")]
+    {
+        let recorder = context.flight_recorder_ref();
+        recorder.record(
+            FlightProtocol::Tcp,
+            peer_addr,
+            addr,
+            FlightEventKind::Relayed { tx, rx },
+        );
+        recorder.record(FlightProtocol::Tcp, peer_addr, addr, FlightEventKind::Closed);
+    }
+
+    #[cfg(feature = "local-audit")]
+    if let Some(audit_sink) = context.audit_sink() {
+        let server_addr = if remote.is_proxied() {
+            Some(svr_cfg.addr())
+        } else {
+            None
+        };
+        let mut record = AuditRecord::tcp(start_time, peer_addr, addr, server_addr);
+        record.tx = tx;
+        record.rx = rx;
+        record.error = match result {
+            Ok(ref summary) => summary.remote_error.clone(),
+            Err(ref err) => Some(err.to_string()),
+        };
+        audit_sink.record(record);
+    }
+
+    log_tcp_tunnel_result(&context, peer_addr, addr, &result);
+    result
+}
+
+/// Relay a bypassed connection between the client's buffers and `remote` directly
+///
+/// Data is moved by `TcpConnection::poll_write_to` and `TcpConnection::poll_read_from`, which don't lock the
+/// connection while polling `remote`.
+async fn establish_tcp_tunnel_direct(
+    stream: &mut TcpConnection,
+    remote: &mut AutoProxyClientStream,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<TcpTunnelSummary> {
+    let start = Instant::now();
+    let remote_addr = remote.peer_addr().ok();
+    debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
+
+    let copy_result = copy_bidirectional_direct(stream, remote).await;
+    let client_failed = {
+        let control = stream.control.lock();
+        control.is_closed || control.dead_peer
+    };
+
+    Ok(bypassed_tcp_tunnel_summary(
+        copy_result,
+        client_failed,
+        peer_addr,
+        target_addr,
+        start,
+        remote_addr,
+    ))
+}
+
+/// Progress of one direction of `copy_bidirectional_direct`
+enum DirectTransfer {
+    Running(u64),
+    ShuttingDown(u64),
+    Done(u64),
+}
+
+/// Copy data between `stream` and `remote` in both directions until both of them reach EOF, returns `(L2R, R2L)`
+///
+/// Each side is shut down after the other side reaches EOF, same as `tokio::io::copy_bidirectional`.
+async fn copy_bidirectional_direct<S>(stream: &mut TcpConnection, remote: &mut S) -> io::Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut l2r = DirectTransfer::Running(0);
+    let mut r2l = DirectTransfer::Running(0);
+    let mut buffer = DirectRelayBuffer::default();
+
+    poll_fn(|cx| {
+        let l2r = poll_direct_transfer(cx, &mut l2r, stream, remote, &mut buffer, true)?;
+        let r2l = poll_direct_transfer(cx, &mut r2l, stream, remote, &mut buffer, false)?;
+        let l2r = ready!(l2r);
+        let r2l = ready!(r2l);
+        Ok((l2r, r2l)).into()
+    })
+    .await
+}
+
+fn poll_direct_transfer<S>(
+    cx: &mut Context<'_>,
+    state: &mut DirectTransfer,
+    stream: &mut TcpConnection,
+    remote: &mut S,
+    buffer: &mut DirectRelayBuffer,
+    to_remote: bool,
+) -> Poll<io::Result<u64>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match *state {
+            DirectTransfer::Running(ref mut total) => {
+                let n = if to_remote {
+                    ready!(stream.poll_write_to(cx, Pin::new(&mut *remote), buffer))?
+                } else {
+                    ready!(stream.poll_read_from(cx, Pin::new(&mut *remote), buffer))?
+                };
+                if n == 0 {
+                    *state = DirectTransfer::ShuttingDown(*total);
+                } else {
+                    *total += n as u64;
+                }
+            }
+            DirectTransfer::ShuttingDown(total) => {
+                if to_remote {
+                    ready!(Pin::new(&mut *remote).poll_shutdown(cx))?;
+                } else {
+                    ready!(Pin::new(&mut *stream).poll_shutdown(cx))?;
+                }
+                *state = DirectTransfer::Done(total);
+            }
+            DirectTransfer::Done(total) => return Ok(total).into(),
+        }
+    }
+}
+
+/// Destination of the connection, it is the sniffed host if found, so it is logged and connected by the domain name
+async fn sniff_target_addr<S>(
+    stream: &mut S,
+    sniffed: &mut Vec<u8>,
+    peer_addr: SocketAddr,
+    addr: &Address,
+    sniff_config: SniffConfig,
+) -> io::Result<Address>
+where
+    S: AsyncRead + Unpin,
+{
+    if !sniff_config.is_enabled() {
+        return Ok(addr.clone());
+    }
+
+    match sniff_host(stream, sniffed, sniff_config, DEFAULT_SNIFF_TIMEOUT).await? {
+        Some(host) => {
+            trace!("TCP tunnel {} <-> {} sniffed host {}", peer_addr, addr, host);
+            Ok(Address::DomainNameAddress(host, addr.port()))
+        }
+        None => Ok(addr.clone()),
+    }
+}
+
+/// Log the result of a TCP tunnel, `addr` is the destination with the sniffed host
+fn log_tcp_tunnel_result(
+    context: &ServiceContext,
+    peer_addr: SocketAddr,
+    addr: &Address,
+    result: &io::Result<TcpTunnelSummary>,
+) {
+    match *result {
+        Ok(TcpTunnelSummary {
+            remote_error: Some(ref err),
+            ref server,
+            ..
+        }) => {
+            if context.conn_error_log_throttle().should_log(addr) {
+                warn!(
+                    "TCP tunnel {} <-> {} failed by remote in the middle of relaying, server: {:?}, error: {}",
+                    peer_addr, addr, server, err
+                );
+            }
+
+            #[cfg(feature = "local-flight-recorder")]
+            {
+                let recorder = context.flight_recorder_ref();
+                recorder.record(
+                    FlightProtocol::Tcp,
+                    peer_addr,
+                    addr,
+                    FlightEventKind::Reset { error: err.clone() },
+                );
+                recorder.dump_flow(FlightProtocol::Tcp, peer_addr);
+            }
+        }
+        Ok(ref summary) => {
+            debug!(
+                "TCP tunnel {} <-> {} closed after {:?}, server: {:?} ({}), tx {} bytes ({} on wire), rx {} bytes ({} on wire)",
+                peer_addr,
+                addr,
+                summary.duration,
+                summary.server,
+                summary.remote_family(),
+                summary.tx,
+                summary.wire_tx,
+                summary.rx,
+                summary.wire_rx
+            );
+        }
+        Err(ref err) => {
+            if context.conn_error_log_throttle().should_log(addr) {
+                error!("TCP tunnel failure, {} <-> {}, error: {}", peer_addr, addr, err);
+            }
+
+            #[cfg(feature = "local-flight-recorder")]
+            {
+                let recorder = context.flight_recorder_ref();
+                recorder.record(
+                    FlightProtocol::Tcp,
+                    peer_addr,
+                    addr,
+                    FlightEventKind::Reset { error: err.to_string() },
+                );
+                recorder.dump_flow(FlightProtocol::Tcp, peer_addr);
+            }
+        }
+    }
+}
+
+/// Record the actual local address, interface and remote address of the outbound socket
+fn record_outbound(context: &ServiceContext, tracker: &TcpConnectionTracker, remote: &AutoProxyClientStream) {
+    match remote.local_addr() {
+        Ok(outbound_addr) => {
+            let outbound_interface = context.connect_opts_ref().bind_interface.as_deref();
+            tracker.set_outbound(outbound_addr, outbound_interface, remote.peer_addr().ok());
+        }
+        Err(err) => {
+            debug!("TCP tunnel failed to get outbound local address, error: {}", err);
+        }
+    }
+}
+
+/// TOS of the outbound connection of a client's traffic class, `None` if it is not marked
+///
+/// Only DSCP is kept, ECN is negotiated by the outbound connection itself.
+fn outbound_tos(traffic_class: u8) -> Option<u8> {
+    match traffic_class & !0x03 {
+        0 => None,
+        dscp => Some(dscp),
+    }
+}
+
+/// Connect to the remote, returns the chosen server and the connected stream
+#[allow(clippy::too_many_arguments)]
+async fn connect_remote(
+    context: &Arc<ServiceContext>,
+    balancer: &PingBalancer,
+    peer_addr: SocketAddr,
+    addr: &Address,
+    preferred_server: Option<Arc<ServerIdent>>,
+    server_selector: Option<&dyn ServerSelector>,
+    tos: Option<u8>,
+    fwmark: Option<u32>,
+    resolve_strategy: Option<ResolveStrategy>,
+    dns_resolver: Option<&DnsResolver>,
+) -> io::Result<(Arc<ServerIdent>, AutoProxyClientStream)> {
+    // Options are only copied if the connection has its own traffic class or mark
+    let mut own_connect_opts;
+    let connect_opts = if tos.is_some() || fwmark.is_some() {
+        own_connect_opts = context.connect_opts_ref().clone();
+        if tos.is_some() {
+            own_connect_opts.tcp.tos = tos;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if fwmark.is_some() {
+            own_connect_opts.fwmark = fwmark;
+        }
+        &own_connect_opts
+    } else {
+        context.connect_opts_ref()
+    };
+
+    let server = match preferred_server {
+        Some(server) => server,
+        None => {
+            let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, addr);
+            let selected = match server_selector {
+                Some(selector) => selector.select(balancer, &cx)?,
+                None => {
+                    let selected = balancer.select_server(&cx, &AvailableServerSelector)?;
+                    if selected.is_none() {
+                        balancer
+                            .check_unavailable_bypass(context, ServerType::Tcp, addr)
+                            .await?;
+                    }
+                    selected
+                }
+            };
+            match selected {
+                Some(server) => server,
+                None => {
+                    // Bypassed by the selector or all servers are unavailable, connect to target directly
+                    let server = balancer.best_tcp_server();
+                    let remote = match dns_resolver {
+                        Some(resolver) => {
+                            AutoProxyClientStream::connect_bypassed_with_resolver(
+                                context.clone(),
+                                addr,
+                                resolve_strategy,
+                                connect_opts,
+                                resolver,
+                            )
+                            .await?
+                        }
+                        None => {
+                            AutoProxyClientStream::connect_bypassed_with_strategy(
+                                context.clone(),
+                                addr,
+                                resolve_strategy,
+                                connect_opts,
+                            )
+                            .await?
+                        }
+                    };
+                    return Ok((server, remote));
+                }
+            }
+        }
+    };
+
+    balancer
+        .connect_with_retry(server, |server| async move {
+            connect_server(context, &server, addr, resolve_strategy, connect_opts, dns_resolver).await
+        })
+        .await
+}
+
+/// Connect to `addr` through `server`, or directly if it's bypassed, with names resolved by `dns_resolver` if it's set
+async fn connect_server(
+    context: &Arc<ServiceContext>,
+    server: &ServerIdent,
+    addr: &Address,
+    resolve_strategy: Option<ResolveStrategy>,
+    connect_opts: &ConnectOpts,
+    dns_resolver: Option<&DnsResolver>,
+) -> io::Result<AutoProxyClientStream> {
+    match dns_resolver {
+        Some(resolver) => {
+            AutoProxyClientStream::connect_with_resolver(
+                context.clone(),
+                server,
+                addr,
+                resolve_strategy,
+                connect_opts,
+                resolver,
+            )
+            .await
+        }
+        None => {
+            AutoProxyClientStream::connect_with_strategy(context.clone(), server, addr, resolve_strategy, connect_opts)
+                .await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_redir_client(
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    s: TcpConnection,
+    peer_addr: SocketAddr,
+    mut daddr: SocketAddr,
+    preferred_server: Option<Arc<ServerIdent>>,
+    server_selector: Option<Arc<dyn ServerSelector>>,
+    target_rewriter: &dyn TargetRewriter,
+    tos: Option<u8>,
+    fwmark: Option<u32>,
+    resolve_strategy: Option<ResolveStrategy>,
+    tracker: TcpConnectionTracker,
+    sniff_config: SniffConfig,
+    flow_affinity: Option<FlowAffinity>,
+    connect_timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+    reset_on_remote_failure: bool,
+    early_data_policy: TcpEarlyDataPolicy,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    events: Option<TunEventSender>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+) -> io::Result<TcpTunnelSummary> {
+    // Get forward address from socket
+    //
+    // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
+    if let SocketAddr::V6(ref a) = daddr {
+        if let Some(v4) = to_ipv4_mapped(a.ip()) {
+            daddr = SocketAddr::new(IpAddr::from(v4), a.port());
+        }
+    }
+    let target_addr = target_rewriter.rewrite(daddr);
+    // Endpoints the client connected to, not the rewritten target
+    let proxy_header = proxy_protocol.map(|version| build_proxy_protocol_header(version, peer_addr, daddr));
+    establish_client_tcp_redir(
+        context,
+        balancer,
+        s,
+        peer_addr,
+        &target_addr,
+        preferred_server,
+        server_selector,
+        tos,
+        fwmark,
+        resolve_strategy,
+        tracker,
+        sniff_config,
+        flow_affinity,
+        connect_timeout,
+        first_byte_timeout,
+        reset_on_remote_failure,
+        early_data_policy,
+        proxy_header,
+        events,
+        dns_resolver,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use async_trait::async_trait;
+    use shadowsocks::{
+        config::Mode,
+        crypto::v1::CipherKind,
+        dns_resolver::{DnsResolve, DnsResolver},
+        net::ConnectOpts,
+        ServerConfig,
+    };
+    use smoltcp::{
+        phy::ChecksumCapabilities,
+        wire::{Ipv4Packet, TcpControl, TcpRepr, TcpSeqNumber},
+    };
+    use tokio::{net::TcpListener, time};
+
+    use crate::{local::loadbalancing::PingBalancerBuilder, net::clock::ManualClock};
+
+    use super::{
+        super::test_frame::{build_syn_frame, build_tcp_frame},
+        *,
+    };
+
+    /// Resolver of a server that never answers in time
+    struct SlowResolver;
+
+    #[async_trait]
+    impl DnsResolve for SlowResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            time::sleep(Duration::from_secs(5)).await;
+            Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+        }
+    }
+
+    /// Frame of a segment from 10.0.0.2 at `src_port` to 10.0.0.3 at `dst_port`
+    fn build_client_frame(
+        src_port: u16,
+        dst_port: u16,
+        control: TcpControl,
+        seq: i32,
+        ack: Option<TcpSeqNumber>,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        build_client_frame_with_mss(src_port, dst_port, control, seq, ack, None, payload)
+    }
+
+    fn build_client_frame_with_mss(
+        src_port: u16,
+        dst_port: u16,
+        control: TcpControl,
+        seq: i32,
+        ack: Option<TcpSeqNumber>,
+        max_seg_size: Option<u16>,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        build_tcp_frame(
+            SocketAddrV4::new([10, 0, 0, 2].into(), src_port),
+            SocketAddrV4::new([10, 0, 0, 3].into(), dst_port),
+            control,
+            seq,
+            ack,
+            max_seg_size,
+            payload,
+        )
+    }
+
+    /// Establish connections from 10.0.0.2:40000 to 10.0.0.3 at `10000 + port` of each of `ports` with buffers of
+    /// `buffer_size` bytes, returns their controls and the stack's next sequence numbers by destination port
+    async fn establish_connections(
+        tcp: &mut TcpTun,
+        ports: Range<u16>,
+        buffer_size: u32,
+    ) -> (Vec<SharedTcpConnectionControl>, HashMap<u16, TcpSeqNumber>) {
+        establish_connections_with_mss(tcp, ports, buffer_size, None).await
+    }
+
+    /// `establish_connections` with clients advertising `client_mss` in their SYNs, smoltcp assumes 536 without it
+    async fn establish_connections_with_mss(
+        tcp: &mut TcpTun,
+        ports: Range<u16>,
+        buffer_size: u32,
+        client_mss: Option<u16>,
+    ) -> (Vec<SharedTcpConnectionControl>, HashMap<u16, TcpSeqNumber>) {
+        let tcp_opts = TcpSocketOpts {
+            send_buffer_size: Some(buffer_size),
+            recv_buffer_size: Some(buffer_size),
+            ..Default::default()
+        };
+        establish_connections_with_opts(tcp, ports, &tcp_opts, client_mss).await
+    }
+
+    /// `establish_connections_with_mss` with sockets created by `tcp_opts`, which must set both buffer sizes
+    async fn establish_connections_with_opts(
+        tcp: &mut TcpTun,
+        ports: Range<u16>,
+        tcp_opts: &TcpSocketOpts,
+        client_mss: Option<u16>,
+    ) -> (Vec<SharedTcpConnectionControl>, HashMap<u16, TcpSeqNumber>) {
+        const SRC_PORT: u16 = 40000;
+
+        let buffer_size = tcp_opts.send_buffer_size.unwrap();
+        assert_eq!(tcp_opts.recv_buffer_size, Some(buffer_size));
+
+        // Connections to different ports, so each SYN is accepted by its own socket
+        let mut controls = Vec::new();
+        for port in ports.clone() {
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), SRC_PORT);
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000 + port);
+            let socket = create_listen_socket(dst_addr, tcp_opts).unwrap();
+            let mut control = TcpSocketControl::new(buffer_size, buffer_size);
+            control.nodelay = tcp_opts.nodelay;
+            let control = Arc::new(SpinMutex::new(control));
+            let creation = TcpSocketCreation {
+                key: (src_addr, dst_addr),
+                control: control.clone(),
+                socket,
+            };
+            assert!(tcp.manager_socket_creation_tx.send(creation).is_ok());
+            controls.push(control);
+        }
+
+        for port in ports.clone() {
+            let frame = build_client_frame_with_mss(SRC_PORT, 10000 + port, TcpControl::Syn, 1, None, client_mss, &[]);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        let mut server_seqs = HashMap::new();
+        for _ in ports.clone() {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("SYN not replied")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(packet.syn() && packet.ack());
+            server_seqs.insert(packet.src_port(), packet.seq_number() + 1);
+        }
+        for port in ports {
+            let ack = Some(server_seqs[&(10000 + port)]);
+            let frame = build_client_frame(SRC_PORT, 10000 + port, TcpControl::None, 2, ack, &[]);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        time::timeout(Duration::from_secs(10), async {
+            while !controls
+                .iter()
+                .all(|c| c.lock().socket_info.state == TcpState::Established)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("handshakes not finished");
+
+        (controls, server_seqs)
+    }
+
+    #[test]
+    fn resize_buffer_keeps_queued_data() {
+        let mut control = TcpSocketControl::new(16, 16);
+
+        // Half-full and wrapped around the end
+        assert_eq!(control.send_buffer.enqueue_slice(&[0u8; 12]), 12);
+        assert_eq!(control.send_buffer.dequeue_slice(&mut [0u8; 12]), 12);
+        let queued = (0..8).collect::<Vec<u8>>();
+        assert_eq!(control.send_buffer.enqueue_slice(&queued), 8);
+
+        // Too small for the queued data
+        assert!(!control.resize_send_buffer(4));
+        assert_eq!(control.send_buffer.capacity(), 16);
+
+        assert!(control.resize_send_buffer(64));
+        assert_eq!(control.send_buffer.capacity(), 64);
+        assert!(control.resize_send_buffer(8));
+        assert!(control.send_buffer.is_full());
+
+        let mut data = [0u8; 16];
+        assert_eq!(control.send_buffer.dequeue_slice(&mut data), 8);
+        assert_eq!(&data[..8], &queued[..]);
+    }
+
+    #[test]
+    fn stalled_connection_reset() {
+        let mut control = TcpSocketControl::new(16, 16);
+        control.stall_timeout = Some(Duration::from_secs(5));
+        let start = Instant::now();
+
+        // Client stopped reading, but the remote is still reading
+        assert_eq!(control.send_buffer.enqueue_slice(&[0u8; 16]), 16);
+        assert!(!control.check_stalled(false, start));
+        assert!(control.stalled_since.is_none());
+
+        // Remote stopped reading too
+        assert_eq!(control.recv_buffer.enqueue_slice(&[0u8; 16]), 16);
+        assert!(!control.check_stalled(false, start));
+        assert!(!control.check_stalled(false, start + Duration::from_secs(4)));
+
+        // Any progress restarts the interval
+        assert!(!control.check_stalled(true, start + Duration::from_secs(4)));
+        assert!(!control.check_stalled(false, start + Duration::from_secs(5)));
+        assert!(control.check_stalled(false, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn read_into_uninit_buffer() {
+        use std::mem::MaybeUninit;
+
+        use futures::task::noop_waker_ref;
+
+        // No runtime involved, so that it could be checked by `cargo miri test read_into_uninit_buffer`
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+            ),
+            control: control.clone(),
+            manager_notify: Arc::new(ManagerNotify::new(
+                thread::current(),
+                SharedDirtyConnections::default(),
+                Arc::default(),
+            )),
+            traffic: Arc::default(),
+        };
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Wrapped around the end
+        {
+            let mut control = control.lock();
+            assert_eq!(control.recv_buffer.enqueue_slice(&[0u8; 12]), 12);
+            assert_eq!(control.recv_buffer.dequeue_slice(&mut [0u8; 12]), 12);
+            let queued = (0..10).collect::<Vec<u8>>();
+            assert_eq!(control.recv_buffer.enqueue_slice(&queued), 10);
+        }
+
+        // Smaller than the queued data
+        let mut storage = [MaybeUninit::<u8>::uninit(); 6];
+        let mut buf = ReadBuf::uninit(&mut storage);
+        assert!(Pin::new(&mut connection).poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled(), &[0, 1, 2, 3, 4, 5]);
+
+        let mut storage = [MaybeUninit::<u8>::uninit(); 64];
+        let mut buf = ReadBuf::uninit(&mut storage);
+        assert!(Pin::new(&mut connection).poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled(), &[6, 7, 8, 9]);
+        assert!(control.lock().recv_buffer.is_empty());
+
+        // Nothing queued
+        assert!(Pin::new(&mut connection).poll_read(&mut cx, &mut buf).is_pending());
+    }
+
+    #[test]
+    fn direct_relay_wraps_around() {
+        use std::io::Cursor;
+
+        use futures::task::noop_waker_ref;
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let connection = TcpConnection {
+            key: (
+                "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+            ),
+            control: control.clone(),
+            manager_notify: Arc::new(ManagerNotify::new(
+                thread::current(),
+                SharedDirtyConnections::default(),
+                Arc::default(),
+            )),
+            traffic: Arc::default(),
+        };
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buffer = DirectRelayBuffer::default();
+        let queued = (0..10).collect::<Vec<u8>>();
+
+        // Data from client wrapped around the end, written with both parts at once
+        {
+            let mut control = control.lock();
+            assert_eq!(control.recv_buffer.enqueue_slice(&[0u8; 12]), 12);
+            assert_eq!(control.recv_buffer.dequeue_slice(&mut [0u8; 12]), 12);
+            assert_eq!(control.recv_buffer.enqueue_slice(&queued), 10);
+        }
+        let mut writer = Vec::new();
+        match connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 10),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(writer, queued);
+        assert!(connection
+            .poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer)
+            .is_pending());
+
+        // Partially written across the end, the rest is kept
+        assert_eq!(control.lock().recv_buffer.enqueue_slice(&queued), 10);
+        let mut storage = [0u8; 7];
+        let mut writer = Cursor::new(&mut storage[..]);
+        match connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 7),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(&storage[..], &queued[..7]);
+        assert_eq!(control.lock().recv_buffer.len(), 3);
+
+        let mut writer = Cursor::new(&mut storage[..]);
+        writer.set_position(7);
+        match connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer) {
+            Poll::Ready(Err(err)) => assert_eq!(err.kind(), ErrorKind::WriteZero),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut data = [0u8; 16];
+        assert_eq!(control.lock().recv_buffer.dequeue_slice(&mut data), 3);
+        assert_eq!(&data[..3], &queued[7..]);
+
+        // Client half-closed
+        control.lock().recv_eof = true;
+        let mut writer = Vec::new();
+        assert!(matches!(
+            connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer),
+            Poll::Ready(Ok(0))
+        ));
+
+        // Data to client read into the free parts before and after the end
+        {
+            let mut control = control.lock();
+            assert_eq!(control.send_buffer.enqueue_slice(&[0u8; 12]), 12);
+            assert_eq!(control.send_buffer.dequeue_slice(&mut [0u8; 12]), 12);
+        }
+        let data = (0..20).collect::<Vec<u8>>();
+        let mut reader = &data[..];
+        match connection.poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 16),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(connection
+            .poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer)
+            .is_pending());
+
+        let mut sent = [0u8; 16];
+        assert_eq!(control.lock().send_buffer.dequeue_slice(&mut sent), 16);
+        assert_eq!(&sent[..], &data[..16]);
+
+        // Remote closed after the rest is read
+        match connection.poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 4),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            connection.poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer),
+            Poll::Ready(Ok(0))
+        ));
+
+        let traffic = connection.traffic.clone();
+        assert_eq!(traffic.tx.load(Ordering::Relaxed), 17);
+        assert_eq!(traffic.rx.load(Ordering::Relaxed), 20);
+    }
+
+    #[test]
+    fn direct_relay_unlocked_while_polling_remote() {
+        use futures::task::noop_waker_ref;
+
+        /// Remote writing data to client, shrinks the connection's send buffer while it's read
+        struct ShrinkingReader {
+            control: SharedTcpConnectionControl,
+            data: &'static [u8],
+        }
+
+        impl AsyncRead for ShrinkingReader {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                let mut control = self
+                    .control
+                    .try_lock()
+                    .expect("connection locked while reading from remote");
+                assert!(control.resize_send_buffer(4));
+                drop(control);
+
+                buf.put_slice(mem::take(&mut self.data));
+                Ok(()).into()
+            }
+        }
+
+        /// Remote receiving data from client
+        struct CheckingWriter {
+            control: SharedTcpConnectionControl,
+            written: Vec<u8>,
+        }
+
+        impl AsyncWrite for CheckingWriter {
+            fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                assert!(
+                    self.control.try_lock().is_some(),
+                    "connection locked while writing to remote"
+                );
+                self.written.extend_from_slice(buf);
+                Ok(buf.len()).into()
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+        }
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let connection = TcpConnection {
+            key: (
+                "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+            ),
+            control: control.clone(),
+            manager_notify: Arc::new(ManagerNotify::new(
+                thread::current(),
+                SharedDirtyConnections::default(),
+                Arc::default(),
+            )),
+            traffic: Arc::default(),
+        };
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buffer = DirectRelayBuffer::default();
+
+        assert_eq!(control.lock().recv_buffer.enqueue_slice(b"request"), 7);
+        let mut writer = CheckingWriter {
+            control: control.clone(),
+            written: Vec::new(),
+        };
+        match connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 7),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(writer.written, b"request");
+        assert!(control.lock().recv_buffer.is_empty());
+
+        // Read with the whole window, but only 4 bytes fit after it was shrunk, the rest is queued as space is freed
+        let mut reader = ShrinkingReader {
+            control: control.clone(),
+            data: b"0123456789",
+        };
+        let mut received = Vec::new();
+        for expected in [4, 4, 2] {
+            match connection.poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer) {
+                Poll::Ready(Ok(n)) => assert_eq!(n, expected),
+                r => panic!("unexpected {:?}", r),
+            }
+            if expected == 4 {
+                assert!(connection
+                    .poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer)
+                    .is_pending());
+            }
+
+            let mut data = [0u8; 4];
+            let n = control.lock().send_buffer.dequeue_slice(&mut data);
+            received.extend_from_slice(&data[..n]);
+        }
+        assert_eq!(received, b"0123456789");
+        assert!(matches!(
+            connection.poll_read_from(&mut cx, Pin::new(&mut reader), &mut buffer),
+            Poll::Ready(Ok(0))
+        ));
+        assert_eq!(connection.traffic.rx.load(Ordering::Relaxed), 10);
+    }
+
+    #[tokio::test]
+    async fn slow_remote_writer_not_blocking_manager() {
+        use std::sync::mpsc;
+
+        use futures::task::noop_waker_ref;
+
+        /// Remote blocked in writing, until it is released
+        struct SlowWriter {
+            started: mpsc::Sender<()>,
+            release: mpsc::Receiver<()>,
+            written: Vec<u8>,
+        }
+
+        impl AsyncWrite for SlowWriter {
+            fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                self.started.send(()).unwrap();
+                self.release.recv().unwrap();
+                self.written.extend_from_slice(buf);
+                Ok(buf.len()).into()
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+        }
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..2, 1024).await;
+        let client_frame = |port: u16, seq: i32, payload: &[u8]| {
+            let ack = Some(server_seqs[&(10000 + port)]);
+            build_client_frame(40000, 10000 + port, TcpControl::Psh, seq, ack, payload)
+        };
+        // Checked without spinning on the lock, it may be held by the relay
+        let received = |port: usize, len: usize| {
+            controls[port]
+                .try_lock()
+                .is_some_and(|control| control.recv_buffer.len() == len)
+        };
+
+        tcp.drive_interface_state(&client_frame(0, 2, b"hello")).await.unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while !received(0, 5) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("data not received");
+
+        let connection = TcpConnection {
+            key: (
+                SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+                SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: tcp.traffic.clone(),
+        };
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let relay = thread::spawn(move || {
+            let mut writer = SlowWriter {
+                started: started_tx,
+                release: release_rx,
+                written: Vec::new(),
+            };
+            let mut cx = Context::from_waker(noop_waker_ref());
+            let mut buffer = DirectRelayBuffer::default();
+            let n = match connection.poll_write_to(&mut cx, Pin::new(&mut writer), &mut buffer) {
+                Poll::Ready(Ok(n)) => n,
+                r => panic!("unexpected {:?}", r),
+            };
+            (n, writer.written)
+        });
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Both the stalled connection and the other one are still read from their sockets
+        tcp.drive_interface_state(&client_frame(0, 7, b" world")).await.unwrap();
+        tcp.drive_interface_state(&client_frame(1, 2, b"ping")).await.unwrap();
+        let serviced = time::timeout(Duration::from_secs(5), async {
+            while !received(0, 11) || !received(1, 4) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+
+        release_tx.send(()).unwrap();
+        let (n, written) = relay.join().unwrap();
+        assert!(serviced.is_ok(), "manager blocked by the writing relay");
+        assert_eq!(n, 5);
+        assert_eq!(written, b"hello");
+        assert_eq!(controls[0].lock().recv_buffer.len(), 6);
+    }
+
+    /// Throughput of relaying 1 GiB from client to remote, by `tokio::io::copy` or by `TcpConnection::poll_write_to`
+    ///
+    /// `cargo test --release -p shadowsocks-service --features local-tun direct_relay_bench -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn direct_relay_bench() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        const TOTAL: u64 = 1 << 30;
+
+        async fn relay(direct: bool) -> Duration {
+            let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap();
+            let mut remote = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (mut sink, _) = listener.accept().await.unwrap();
+            let sink = tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                let mut total = 0;
+                loop {
+                    match sink.read(&mut buf).await.unwrap() {
+                        0 => return total,
+                        n => total += n as u64,
+                    }
+                }
+            });
+
+            // Fills the receive buffer as the manager does, in the same thread as the relay
+            let control = Arc::new(SpinMutex::new(TcpSocketControl::new(
+                DEFAULT_TCP_SEND_BUFFER_SIZE,
+                DEFAULT_TCP_RECV_BUFFER_SIZE,
+            )));
+            let feeder = {
+                let control = control.clone();
+                tokio::spawn(async move {
+                    let chunk = vec![0u8; 65536];
+                    let mut fed = 0;
+                    while fed < TOTAL {
+                        let n = {
+                            let mut control = control.lock();
+                            let size = (TOTAL - fed).min(chunk.len() as u64) as usize;
+                            let n = control.recv_buffer.enqueue_slice(&chunk[..size]);
+                            if let Some(waker) = control.recv_waker.take() {
+                                waker.wake();
+                            }
+                            n
+                        };
+                        fed += n as u64;
+
+                        // Buffer is full, until the relay takes some of it
+                        if n == 0 {
+                            tokio::task::yield_now().await;
+                        }
+                    }
+
+                    let mut control = control.lock();
+                    control.recv_eof = true;
+                    if let Some(waker) = control.recv_waker.take() {
+                        waker.wake();
+                    }
+                })
+            };
+            let mut connection = TcpConnection {
+                key: (
+                    "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                    "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+                ),
+                control,
+                manager_notify: Arc::new(ManagerNotify::new(
+                    thread::current(),
+                    SharedDirtyConnections::default(),
+                    Arc::default(),
+                )),
+                traffic: Arc::default(),
+            };
+
+            let start = Instant::now();
+            let relayed = if direct {
+                let mut relayed = 0;
+                let mut buffer = DirectRelayBuffer::default();
+                loop {
+                    match poll_fn(|cx| connection.poll_write_to(cx, Pin::new(&mut remote), &mut buffer))
+                        .await
+                        .unwrap()
+                    {
+                        0 => break relayed,
+                        n => relayed += n as u64,
+                    }
+                }
+            } else {
+                tokio::io::copy(&mut connection, &mut remote).await.unwrap()
+            };
+            remote.shutdown().await.unwrap();
+            assert_eq!(sink.await.unwrap(), relayed);
+            let elapsed = start.elapsed();
+
+            feeder.await.unwrap();
+            assert_eq!(relayed, TOTAL);
+            elapsed
+        }
+
+        let copied = relay(false).await;
+        let direct = relay(true).await;
+        let throughput = |elapsed: Duration| TOTAL as f64 / elapsed.as_secs_f64() / (1 << 20) as f64;
+        println!("tokio::io::copy: {:.0} MiB/s", throughput(copied));
+        println!(
+            "poll_write_to: {:.0} MiB/s ({:.2}x)",
+            throughput(direct),
+            copied.as_secs_f64() / direct.as_secs_f64()
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_races_with_manager_close() {
+        use tokio::io::AsyncWriteExt;
+
+        for i in 0..100 {
+            let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+            let mut connection = TcpConnection {
+                key: (
+                    "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                    "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+                ),
+                control: control.clone(),
+                manager_notify: Arc::new(ManagerNotify::new(
+                    thread::current(),
+                    SharedDirtyConnections::default(),
+                    Arc::default(),
+                )),
+                traffic: Arc::default(),
+            };
+
+            let manager = thread::spawn(move || {
+                if i % 2 == 0 {
+                    // Manager closes the socket as if smoltcp failed, before, while or after the client shuts down
+                    for _ in 0..i {
+                        std::hint::spin_loop();
+                    }
+                    control.lock().close();
+                } else {
+                    // Manager processes the shutdown requested by the relay, then client acknowledges the FIN
+                    loop {
+                        let mut control = control.lock();
+                        if control.should_queue_fin() {
+                            control.fin_queued();
+                            control.fin_acked();
+                            break;
+                        }
+                    }
+                }
+            });
+
+            time::timeout(Duration::from_secs(5), connection.shutdown())
+                .await
+                .expect("shutdown never finished")
+                .unwrap();
+            manager.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_data_received() {
+        use tokio::io::AsyncWriteExt;
+
+        const DATA_SIZE: usize = 1024 * 1024;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 0xFFFF).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+
+        // Remote sends its last data and shuts down right away
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut relay = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                connection.write_all(&data).await?;
+                connection.shutdown().await?;
+                Ok::<_, io::Error>(connection)
+            })
+        };
+
+        // Client receives all the data before FIN
+        let mut received = Vec::with_capacity(DATA_SIZE);
+        let mut next_seq = server_seqs[&10000];
+        loop {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("data not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.seq_number() != next_seq {
+                continue;
+            }
+
+            received.extend_from_slice(packet.payload());
+            next_seq += packet.payload().len();
+            if packet.fin() {
+                next_seq += 1;
+                break;
+            }
+
+            let frame = build_client_frame(40000, 10000, TcpControl::None, 2, Some(next_seq), &[]);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        assert_eq!(received.len(), DATA_SIZE);
+        assert!(received == data);
+
+        // Shutdown finishes after client acknowledged the FIN
+        assert!(time::timeout(Duration::from_millis(100), &mut relay).await.is_err());
+        let frame = build_client_frame(40000, 10000, TcpControl::None, 2, Some(next_seq), &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let connection = time::timeout(Duration::from_secs(10), relay)
+            .await
+            .expect("shutdown never finished")
+            .unwrap()
+            .unwrap();
+        assert!(connection.control.lock().fin_acked);
+    }
+
+    #[tokio::test]
+    async fn remote_failure_resets_client() {
+        use std::{env, fs, process};
+
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        use crate::acl::AccessControl;
+
+        // Targets are connected directly
+        let path = env::temp_dir().join(format!("ss-tun-remote-failure-test-{}.acl", process::id()));
+        fs::write(&path, "[bypass_all]\n").unwrap();
+        let acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+        let context = Arc::new(context);
+
+        for reset_on_remote_failure in [false, true] {
+            // Target resets the connection in the middle of its response, after the client received the first part
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target_addr = Address::from(listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 5];
+                stream.read_exact(&mut request).await.unwrap();
+                stream.write_all(b"partial").await.unwrap();
+                stream.read_exact(&mut request).await.unwrap();
+                stream.set_linger(Some(Duration::ZERO)).unwrap();
+            });
+
+            let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+            builder.add_server(ServerConfig::new(
+                "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+                "password",
+                CipherKind::AES_256_GCM,
+            ));
+            let balancer = builder.build().await.unwrap();
+            let mut tcp = TcpTun::new(
+                context.clone(),
+                balancer.clone(),
+                1500,
+                TcpSchedulerPolicy::Unordered,
+                None,
+            );
+
+            let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+            let key = (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            );
+            let connection = TcpConnection {
+                key,
+                control: controls[0].clone(),
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            };
+            let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, controls[0].clone());
+            let relay = {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    establish_client_tcp_redir(
+                        context,
+                        balancer,
+                        connection,
+                        key.0,
+                        &target_addr,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        tracker,
+                        SniffConfig::default(),
+                        None,
+                        None,
+                        None,
+                        reset_on_remote_failure,
+                        TcpEarlyDataPolicy::Buffer,
+                        None,
+                        None,
+                        None,
+                    )
+                    .await
+                })
+            };
+
+            let ack = Some(server_seqs[&10000]);
+            let frame = build_client_frame(40000, 10000, TcpControl::Psh, 2, ack, b"hello");
+            tcp.drive_interface_state(&frame).await.unwrap();
+
+            let mut received = Vec::new();
+            while received.len() < 7 {
+                let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                    .await
+                    .expect("response not received")
+                    .unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                received.extend_from_slice(packet.payload());
+            }
+            assert_eq!(received, b"partial");
+            let ack = Some(server_seqs[&10000] + received.len());
+            let frame = build_client_frame(40000, 10000, TcpControl::Psh, 7, ack, b"again");
+            tcp.drive_interface_state(&frame).await.unwrap();
+
+            // Then the connection is closed or reset
+            let (fin, rst) = loop {
+                let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                    .await
+                    .expect("connection not closed")
+                    .unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                if packet.fin() || packet.rst() {
+                    break (packet.fin(), packet.rst());
+                }
+            };
+            assert_eq!(rst, reset_on_remote_failure);
+            assert_eq!(fin, !reset_on_remote_failure);
+
+            let summary = relay.await.unwrap().unwrap();
+            assert!(summary.remote_error.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_failure_resets_client() {
+        // Server refuses connections
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(
+            context.clone(),
+            balancer.clone(),
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            None,
+        );
+
+        // Connecting fails after the handshake at 10000, in the middle of it at 10001, and before the SYN at 10002
+        let (mut controls, _) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        for port in [10001, 10002] {
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port);
+            let socket = create_listen_socket(dst_addr, &TcpSocketOpts::default()).unwrap();
+            let control = Arc::new(SpinMutex::new(TcpSocketControl::new(1024, 1024)));
+            let creation = TcpSocketCreation {
+                key: (src_addr, dst_addr),
+                control: control.clone(),
+                socket,
+            };
+            assert!(tcp.manager_socket_creation_tx.send(creation).is_ok());
+            controls.push(control);
+        }
+        let frame = build_client_frame(40000, 10001, TcpControl::Syn, 1, None, &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        assert!(packet.syn() && packet.ack());
+
+        for (port, control) in (10000..).zip(controls) {
+            let key = (src_addr, SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port));
+            let connection = TcpConnection {
+                key,
+                control: control.clone(),
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            };
+            let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, control);
+            let target_addr = Address::from(key.1);
+            let result = time::timeout(
+                Duration::from_secs(10),
+                establish_client_tcp_redir(
+                    context.clone(),
+                    balancer.clone(),
+                    connection,
+                    key.0,
+                    &target_addr,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    tracker,
+                    SniffConfig::default(),
+                    None,
+                    None,
+                    None,
+                    false,
+                    TcpEarlyDataPolicy::Buffer,
+                    None,
+                    None,
+                    None,
+                ),
+            )
+            .await
+            .expect("connect not failed");
+            assert!(result.is_err());
+        }
+
+        let frame = build_client_frame(40000, 10002, TcpControl::Syn, 1, None, &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        // Every client is reset instead of closed or left waiting
+        let mut reset_ports = HashSet::new();
+        while reset_ports.len() < 3 {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("RST not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(!packet.fin());
+            if packet.rst() {
+                reset_ports.insert(packet.src_port());
+            }
+        }
+        assert_eq!(reset_ports, HashSet::from([10000, 10001, 10002]));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_resets_client() {
+        const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+        // Server is never reachable in time
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("slow.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(
+            context.clone(),
+            balancer.clone(),
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            None,
+        );
+
+        let (controls, _) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let key = (
+            "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+            "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+        );
+        let connection = TcpConnection {
+            key,
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, controls[0].clone());
+
+        let start = Instant::now();
+        let err = establish_client_tcp_redir(
+            context,
+            balancer,
+            connection,
+            key.0,
+            &Address::from(key.1),
+            None,
+            None,
+            None,
+            None,
+            None,
+            tracker,
+            SniffConfig::default(),
+            None,
+            Some(CONNECT_TIMEOUT),
+            None,
+            false,
+            TcpEarlyDataPolicy::Buffer,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        // Client is reset at the deadline instead of waiting for the server
+        let rst = loop {
+            let frame = time::timeout(Duration::from_secs(1), tcp.recv_packet())
+                .await
+                .expect("RST not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.rst() {
+                break start.elapsed();
+            }
+        };
+        assert!(rst >= CONNECT_TIMEOUT);
+        assert!(rst < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn server_down_resets_connections() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer.clone(), 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_reset_on_server_down(true);
+
+        // Connection to 10000 is relayed through server A, the one to 10001 through another server
+        let (controls, _) = establish_connections(&mut tcp, 0..2, 1024).await;
+        let src_addr = "10.0.0.2:40000".parse::<SocketAddr>().unwrap();
+        let trackers = [
+            ("10.0.0.3:10000", "127.0.0.1:8388"),
+            ("10.0.0.3:10001", "127.0.0.1:8389"),
+        ]
+        .iter()
+        .zip(controls.iter())
+        .map(|(&(dst_addr, server_addr), control)| {
+            let key = (src_addr, dst_addr.parse::<SocketAddr>().unwrap());
+            let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, control.clone());
+            tracker.set_server_addr(&ServerAddr::from(server_addr.parse::<SocketAddr>().unwrap()));
+            tracker
+        })
+        .collect::<Vec<_>>();
+
+        balancer.best_tcp_server().tcp_score().report_failure().await;
+
+        let mut reset_ports = HashSet::new();
+        while let Ok(frame) = time::timeout(Duration::from_millis(500), tcp.recv_packet()).await {
+            let frame = frame.unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.rst() {
+                reset_ports.insert(packet.src_port());
+            }
+        }
+        assert_eq!(reset_ports, HashSet::from([10000]));
+        assert!(controls[0].lock().is_closed);
+        assert!(!controls[1].lock().is_closed);
+
+        drop(trackers);
+    }
+
+    fn standalone_connection(control: SharedTcpConnectionControl) -> TcpConnection {
+        TcpConnection {
+            key: (
+                "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+            ),
+            control,
+            manager_notify: Arc::new(ManagerNotify::new(
+                thread::current(),
+                SharedDirtyConnections::default(),
+                Arc::default(),
+            )),
+            traffic: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_timeout_without_data() {
+        use tokio::io::AsyncReadExt;
+
+        const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let mut connection = standalone_connection(control.clone());
+        connection.set_read_timeout(Some(READ_TIMEOUT));
+
+        // Data arriving within the timeout is read, the window restarts after it
+        let feeder = {
+            let control = control.clone();
+            tokio::spawn(async move {
+                time::sleep(READ_TIMEOUT / 2).await;
+                let mut control = control.lock();
+                assert_eq!(control.recv_buffer.enqueue_slice(b"data"), 4);
+                if let Some(waker) = control.recv_waker.take() {
+                    waker.wake();
+                }
+            })
+        };
+        let mut buf = [0u8; 16];
+        let n = connection.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"data");
+        feeder.await.unwrap();
+
+        let start = Instant::now();
+        let err = time::timeout(Duration::from_secs(1), connection.read(&mut buf))
+            .await
+            .expect("read never timed out")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= READ_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn write_timeout_with_full_buffer() {
+        use tokio::io::AsyncWriteExt;
+
+        const WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let mut connection = standalone_connection(control.clone());
+        connection.set_write_timeout(Some(WRITE_TIMEOUT));
+
+        // Buffer is filled without waiting, then nothing is flushed to client
+        connection.write_all(&[0u8; 16]).await.unwrap();
+        let start = Instant::now();
+        let err = time::timeout(Duration::from_secs(1), connection.write(b"more"))
+            .await
+            .expect("write never timed out")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= WRITE_TIMEOUT);
+
+        // Writes succeed again once the buffer has space
+        control.lock().send_buffer.dequeue_allocated(4);
+        assert_eq!(connection.write(b"more").await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn remote_half_close_keeps_client_direction() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:50000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:80".parse::<SocketAddr>().unwrap(),
+            ),
+            control: control.clone(),
+            manager_notify: Arc::new(ManagerNotify::new(
+                thread::current(),
+                SharedDirtyConnections::default(),
+                Arc::default(),
+            )),
+            traffic: Arc::default(),
+        };
+
+        // Remote's last bytes are queued, then the remote half-closes
+        connection.write_all(b"bye").await.unwrap();
+        let manager = {
+            let control = control.clone();
+            thread::spawn(move || loop {
+                let mut control = control.lock();
+                if control.send_shutdown {
+                    // FIN is only sent after the remote's data is flushed to client
+                    assert!(!control.should_queue_fin());
+                    let mut flushed = [0u8; 3];
+                    assert_eq!(control.send_buffer.dequeue_slice(&mut flushed), 3);
+                    assert_eq!(&flushed, b"bye");
+                    assert!(control.should_queue_fin());
+                    control.fin_queued();
+                    control.fin_acked();
+                    break;
+                }
+            })
+        };
+        time::timeout(Duration::from_secs(5), connection.shutdown())
+            .await
+            .expect("shutdown never finished")
+            .unwrap();
+        manager.join().unwrap();
+        assert!(!control.lock().is_closed);
+        assert_eq!(
+            connection.write(b"more").await.unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+
+        // Client keeps sending, its data is still relayed until it half-closes too
+        for data in [b"hello".as_slice(), b"world".as_slice()] {
+            assert_eq!(control.lock().recv_buffer.enqueue_slice(data), data.len());
+            let mut buf = [0u8; 16];
+            let n = connection.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], data);
+        }
+
+        assert_eq!(control.lock().recv_buffer.enqueue_slice(b"last"), 4);
+        control.lock().recv_eof();
+        let mut buf = [0u8; 16];
+        let n = connection.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"last");
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn client_half_close_receives_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const RESPONSE_SIZE: usize = 256 * 1024;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 0xFFFF).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+
+        // Remote answers after the whole request, which ends with client's FIN
+        let request = b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n";
+        let response = (0..RESPONSE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut relay = {
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                connection.read_to_end(&mut received).await?;
+                connection.write_all(&response).await?;
+                connection.shutdown().await?;
+                Ok::<_, io::Error>((received, connection))
+            })
+        };
+
+        let mut next_seq = server_seqs[&10000];
+        let frame = build_client_frame(40000, 10000, TcpControl::Fin, 2, Some(next_seq), request);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let client_seq = 2 + request.len() as i32 + 1;
+
+        // Client still receives the full response after its FIN
+        let mut received = Vec::with_capacity(RESPONSE_SIZE);
+        loop {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("response not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(!packet.rst());
+            if packet.seq_number() != next_seq {
+                continue;
+            }
+
+            received.extend_from_slice(packet.payload());
+            next_seq += packet.payload().len();
+            if packet.fin() {
+                next_seq += 1;
+                break;
+            }
+
+            let frame = build_client_frame(40000, 10000, TcpControl::None, client_seq, Some(next_seq), &[]);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        assert_eq!(received.len(), RESPONSE_SIZE);
+        assert!(received == response);
+
+        // Both directions are finished after client acknowledged the remote's FIN
+        assert!(time::timeout(Duration::from_millis(100), &mut relay).await.is_err());
+        let frame = build_client_frame(40000, 10000, TcpControl::None, client_seq, Some(next_seq), &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let (request_received, mut connection) = time::timeout(Duration::from_secs(10), relay)
+            .await
+            .expect("shutdown never finished")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request_received, request);
+        assert!(connection.control.lock().is_closed);
+
+        // Closed with FINs in both directions is a clean EOF
+        assert_eq!(connection.read(&mut [0u8; 16]).await.unwrap(), 0);
+        connection.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_reaped_without_traffic() {
+        // Sockets are only swept without being marked dirty every `TCP_FULL_SWEEP_INTERVAL`
+        const REAP_DEADLINE: Duration = Duration::from_millis(200);
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        // Connections hold the only references besides the manager, sockets are counted until both dropped them
+        let (controls, _) = establish_connections(&mut tcp, 0..2, 4096).await;
+        let mut connections = controls.into_iter().zip(10000..).map(|(control, port)| {
+            control.lock().live_socket = Some(TcpLiveSocketGuard::new(tcp.live_sockets.clone()));
+            TcpConnection {
+                key: (
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port),
+                ),
+                control,
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            }
+        });
+        let (reset, graceful) = (connections.next().unwrap(), connections.next().unwrap());
+        assert_eq!(tcp.conntrack().socket_count(), 2);
+
+        // Reset right away, the socket is removed without waiting for the client
+        reset.set_reset_on_close();
+        let start = Instant::now();
+        drop(reset);
+        while tcp.conntrack().socket_count() > 1 {
+            assert!(start.elapsed() < REAP_DEADLINE, "reset socket not removed");
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let frame = time::timeout(REAP_DEADLINE, tcp.recv_packet()).await.unwrap().unwrap();
+        assert!(TcpPacket::new_checked(&frame[20..]).unwrap().rst());
+
+        // Closed gracefully, FIN is sent right away
+        let start = Instant::now();
+        drop(graceful);
+        let frame = time::timeout(REAP_DEADLINE, tcp.recv_packet())
+            .await
+            .expect("FIN not sent")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        assert_eq!(packet.src_port(), 10001);
+        assert!(packet.fin());
+        assert!(start.elapsed() < REAP_DEADLINE);
+    }
+
+    #[tokio::test]
+    async fn client_reset_fails_io() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let relay = tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let result = connection.read(&mut buf).await;
+            (result, connection)
+        });
+
+        let frame = build_client_frame(40000, 10000, TcpControl::Rst, 2, Some(server_seqs[&10000]), &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        // Pending read fails instead of seeing EOF, so does everything after it
+        let (result, mut connection) = time::timeout(Duration::from_secs(5), relay)
+            .await
+            .expect("read not woken up")
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionReset);
+        assert_eq!(
+            connection.read(&mut [0u8; 16]).await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+        assert_eq!(
+            connection.write(b"late").await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+        assert_eq!(
+            connection.shutdown().await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn sniffed_domain_as_destination() {
+        use tokio::io::AsyncWriteExt;
+
+        let peer_addr = "10.0.0.2:50000".parse::<SocketAddr>().unwrap();
+        let ip_addr = Address::from("93.184.216.34:80".parse::<SocketAddr>().unwrap());
+        let request = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n";
+        let sniff_config = SniffConfig { tls: true, http: true };
+
+        let (mut client, mut stream) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+        let mut sniffed = Vec::new();
+        let addr = sniff_target_addr(&mut stream, &mut sniffed, peer_addr, &ip_addr, sniff_config)
+            .await
+            .unwrap();
+        assert_eq!(addr, Address::DomainNameAddress("www.example.com".to_owned(), 80));
+        assert_eq!(addr.to_string(), "www.example.com:80");
+        assert_eq!(sniffed, request);
+
+        // No host found, or sniffing is disabled
+        let (mut client, mut stream) = tokio::io::duplex(4096);
+        client.write_all(b"\x00\x01binary").await.unwrap();
+        let addr = sniff_target_addr(&mut stream, &mut Vec::new(), peer_addr, &ip_addr, sniff_config)
+            .await
+            .unwrap();
+        assert_eq!(addr, ip_addr);
+
+        let addr = sniff_target_addr(
+            &mut stream,
+            &mut Vec::new(),
+            peer_addr,
+            &ip_addr,
+            SniffConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, ip_addr);
+    }
+
+    #[tokio::test]
+    async fn fake_ip_target_rewritten() {
+        use std::{env, fs, process};
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::acl::AccessControl;
+
+        /// Maps addresses of a fake-IP DNS pool back to their domain names
+        struct FakeIpRewriter(HashMap<IpAddr, String>);
+
+        impl TargetRewriter for FakeIpRewriter {
+            fn rewrite(&self, dst: SocketAddr) -> Address {
+                match self.0.get(&dst.ip()) {
+                    Some(domain) => Address::DomainNameAddress(domain.clone(), dst.port()),
+                    None => Address::from(dst),
+                }
+            }
+        }
+
+        /// Resolves every name to the target, recording names resolved
+        struct RecordingResolver {
+            target: SocketAddr,
+            names: Arc<SpinMutex<Vec<String>>>,
+        }
+
+        #[async_trait]
+        impl DnsResolve for RecordingResolver {
+            async fn resolve(&self, addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+                self.names.lock().push(addr.to_owned());
+                Ok(vec![self.target])
+            }
+        }
+
+        let fake_ip = IpAddr::from(Ipv4Addr::new(10, 0, 0, 3));
+        let rewriter = FakeIpRewriter([(fake_ip, "www.example.com".to_owned())].into_iter().collect());
+        let dst_addr = SocketAddr::new(fake_ip, 10000);
+        assert_eq!(
+            rewriter.rewrite(dst_addr),
+            Address::DomainNameAddress("www.example.com".to_owned(), 10000)
+        );
+        let real_addr = "10.0.0.4:443".parse::<SocketAddr>().unwrap();
+        assert_eq!(rewriter.rewrite(real_addr), Address::from(real_addr));
+        assert_eq!(IdentityTargetRewriter.rewrite(dst_addr), Address::from(dst_addr));
+
+        // Targets are connected directly, so the domain name is resolved by the context's resolver
+        let path = env::temp_dir().join(format!("ss-tun-target-rewriter-test-{}.acl", process::id()));
+        fs::write(&path, "[bypass_all]\n").unwrap();
+        let acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let names = Arc::new(SpinMutex::new(Vec::new()));
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(RecordingResolver {
+            target: listener.local_addr().unwrap(),
+            names: names.clone(),
+        })));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(
+            context.clone(),
+            balancer.clone(),
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            None,
+        );
+
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let key = ("10.0.0.2:40000".parse::<SocketAddr>().unwrap(), dst_addr);
+        let connection = TcpConnection {
+            key,
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, controls[0].clone());
+        let relay = tokio::spawn(async move {
+            handle_redir_client(
+                context,
+                balancer,
+                connection,
+                key.0,
+                key.1,
+                None,
+                None,
+                &rewriter,
+                None,
+                None,
+                None,
+                tracker,
+                SniffConfig::default(),
+                None,
+                None,
+                None,
+                false,
+                TcpEarlyDataPolicy::Buffer,
+                Some(ProxyProtocolVersion::V1),
+                None,
+                None,
+            )
+            .await
+        });
+
+        // Connected to the domain name instead of the fake IP
+        let (mut target, ..) = time::timeout(Duration::from_secs(10), listener.accept())
+            .await
+            .expect("target not connected")
+            .unwrap();
+        assert_eq!(*names.lock(), ["www.example.com"]);
+
+        // Header carries the fake IP that the client connected to
+        let expected = b"PROXY TCP4 10.0.0.2 10.0.0.3 40000 10000\r\n";
+        let mut header = [0u8; 42];
+        time::timeout(Duration::from_secs(10), target.read_exact(&mut header))
+            .await
+            .expect("PROXY header not written")
+            .unwrap();
+        assert_eq!(&header, expected);
+        relay.abort();
+    }
+
+    #[tokio::test]
+    async fn device_stat_counts_frames() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let stat = tcp.device_stats();
+
+        // SYNs are not handled by `handle_packet`, no sockets are listening, so each of them is replied with a RST
+        const FRAMES: u64 = 5;
+        let mut bytes_in = 0;
+        for i in 0..FRAMES {
+            let frame = build_syn_frame(50000 + i as u16);
+            bytes_in += frame.len() as u64;
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        assert_eq!(stat.frames_in(), FRAMES);
+        assert_eq!(stat.bytes_in(), bytes_in);
+
+        let mut bytes_out = 0;
+        for _ in 0..FRAMES {
+            let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(packet.rst());
+            bytes_out += frame.len() as u64;
+        }
+        assert_eq!(stat.frames_out(), FRAMES);
+        assert_eq!(stat.bytes_out(), bytes_out);
+        assert_eq!(stat.in_queue_len(), 0);
+        assert_eq!(stat.out_queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn batched_frames_polled_together() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let poll_stat = tcp.poll_stat();
+        let device_stat = tcp.device_stats();
+
+        // No sockets are listening, so each SYN is replied with a RST
+        const FRAMES: u16 = 64;
+        let start = Instant::now();
+        let polls = poll_stat.polls();
+        tcp.drive_interface_state_batch((0..FRAMES).map(|i| build_syn_frame(50000 + i)))
+            .await
+            .unwrap();
+        for _ in 0..FRAMES {
+            let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(packet.rst());
+        }
+        assert_eq!(device_stat.frames_in(), FRAMES as u64);
+        assert_eq!(device_stat.in_queue_len(), 0);
+
+        // Woken up once for the batch, the others are the manager's periodic polls of at least 5ms
+        let periodic = start.elapsed().as_millis() as u64 / 5;
+        assert!(poll_stat.polls() - polls <= 2 + periodic);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_sent() {
+        // Nothing listens on the server's port, connecting is refused
+        let server_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        tcp.set_events(Some(events_tx));
+
+        let frame = build_syn_frame(50000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        let event = time::timeout(Duration::from_secs(5), events_rx.recv())
+            .await
+            .expect("created event not sent")
+            .unwrap();
+        assert_eq!((event.src_addr, event.dst_addr), (src_addr, dst_addr));
+        assert_eq!(event.kind, TunEventKind::Created);
+
+        // Closed with the failure, nothing was relayed
+        let event = time::timeout(Duration::from_secs(5), events_rx.recv())
+            .await
+            .expect("closed event not sent")
+            .unwrap();
+        assert_eq!((event.src_addr, event.dst_addr), (src_addr, dst_addr));
+        match event.kind {
+            TunEventKind::Closed { tx, rx, error } => {
+                assert_eq!((tx, rx), (0, 0));
+                assert!(error.is_some());
+            }
+            kind => panic!("unexpected event {:?}", kind),
+        }
+    }
+
+    #[tokio::test]
+    async fn syn_ack_mss_clamped() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_mss_clamp(TcpMssClamp {
+            send: Some(1200),
+            recv: Some(1000),
+            recv_overhead: None,
+        });
+
+        let frame = build_syn_frame(50000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        // Advertised the receiving clamp instead of the MTU's 1460
+        let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let ip_packet = Ipv4Packet::new_checked(&frame[..]).unwrap();
+        let packet = TcpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(packet.syn() && packet.ack());
+        let repr = TcpRepr::parse(
+            &packet,
+            &ip_packet.src_addr().into(),
+            &ip_packet.dst_addr().into(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        assert_eq!(repr.max_seg_size, Some(1000));
+    }
+
+    #[tokio::test]
+    async fn syn_ack_mss_clamped_by_overhead() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_mss_clamp(TcpMssClamp {
+            recv_overhead: Some(100),
+            ..TcpMssClamp::default()
+        });
+
+        let frame = build_syn_frame(50000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        // MTU's 1460 minus the overhead
+        let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let ip_packet = Ipv4Packet::new_checked(&frame[..]).unwrap();
+        let packet = TcpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(packet.syn() && packet.ack());
+        let repr = TcpRepr::parse(
+            &packet,
+            &ip_packet.src_addr().into(),
+            &ip_packet.dst_addr().into(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        assert_eq!(repr.max_seg_size, Some(1360));
+    }
+
+    #[tokio::test]
+    async fn poll_costs_recorded() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let stat = tcp.poll_stat();
+
+        // Listening sockets keep the manager busy
+        for i in 0..50 {
+            let frame = build_syn_frame(50000 + i);
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+            tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        }
+
+        // Every SYN is replied before the next one is sent, so each of them takes at least one poll
+        const FRAMES: u64 = 20;
+        for i in 0..FRAMES {
+            tcp.drive_interface_state(&build_syn_frame(50000 + i as u16))
+                .await
+                .unwrap();
+            time::timeout(Duration::from_secs(5), tcp.recv_packet())
+                .await
+                .expect("SYN not replied")
+                .unwrap();
+        }
+
+        let polls = stat.polls();
+        assert!(polls >= FRAMES);
+        assert!(stat.buckets().iter().map(|(_, n)| n).sum::<u64>() >= polls);
+        assert!(stat.avg() <= stat.max());
+        assert!(stat.p99() <= stat.max());
+    }
+
+    #[tokio::test]
+    async fn poll_metrics_reported() {
+        /// Records every sample
+        #[derive(Default)]
+        struct RecordingMetrics(SpinMutex<Vec<(Duration, bool, usize)>>);
+
+        impl PollMetrics for RecordingMetrics {
+            fn on_poll(&self, duration: Duration, updated: bool, socket_count: usize) {
+                self.0.lock().push((duration, updated, socket_count));
+            }
+        }
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let metrics = Arc::new(RecordingMetrics::default());
+        tcp.set_poll_metrics(Some(metrics.clone()));
+
+        establish_connections(&mut tcp, 0..3, 1024).await;
+        {
+            let samples = metrics.0.lock();
+            let stat = tcp.poll_stat();
+            assert!(!samples.is_empty());
+            assert!(samples.len() as u64 <= stat.polls());
+            assert!(samples
+                .iter()
+                .all(|&(duration, ..)| duration.as_micros() <= stat.max().as_micros()));
+            // Handshakes changed the sockets
+            assert!(samples
+                .iter()
+                .any(|&(_, updated, socket_count)| updated && socket_count == 3));
+        }
+
+        // Not reported after it is removed
+        tcp.set_poll_metrics(None);
+        tcp.drive_interface_state(&build_syn_frame(50000)).await.unwrap();
+        time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let reported = metrics.0.lock().len();
+        tcp.drive_interface_state(&build_syn_frame(50001)).await.unwrap();
+        time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        assert_eq!(metrics.0.lock().len(), reported);
+    }
+
+    #[tokio::test]
+    async fn idle_sockets_not_serviced() {
+        const IDLE: u16 = 1000;
+        const ACTIVE: u16 = 20;
+        const TOTAL: u16 = IDLE + ACTIVE;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let stat = tcp.poll_stat();
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..TOTAL, 1024).await;
+
+        // Rounds following the handshakes may still be servicing all sockets
+        let mut serviced = stat.sockets_serviced();
+        loop {
+            time::sleep(Duration::from_millis(50)).await;
+            let settled = stat.sockets_serviced();
+            if settled == serviced {
+                break;
+            }
+            serviced = settled;
+        }
+        let swept = stat.sockets_swept();
+        let polls = stat.polls();
+
+        // Only a few connections are sending
+        const SEGMENTS: i32 = 10;
+        for i in 0..SEGMENTS {
+            for port in 0..ACTIVE {
+                let ack = Some(server_seqs[&(10000 + port)]);
+                let frame = build_client_frame(40000, 10000 + port, TcpControl::Psh, 2 + i * 4, ack, b"data");
+                tcp.drive_interface_state(&frame).await.unwrap();
+            }
+        }
+        time::timeout(Duration::from_secs(10), async {
+            while !controls[..ACTIVE as usize]
+                .iter()
+                .all(|c| c.lock().recv_buffer.len() == SEGMENTS as usize * 4)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("data not received");
+
+        // Except the periodic sweeps, rounds only checked the active sockets
+        let serviced = stat.sockets_serviced() - serviced;
+        let swept = stat.sockets_swept() - swept;
+        let polls = stat.polls() - polls;
+        assert!(polls > 0);
+        assert!(
+            serviced - swept <= polls * ACTIVE as u64,
+            "serviced {} sockets in {} polls, {} swept",
+            serviced,
+            polls,
+            swept
+        );
+        assert!(controls[ACTIVE as usize..]
+            .iter()
+            .all(|c| c.lock().recv_buffer.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn sweep_spread_across_rounds() {
+        const IDLE: u16 = 3000;
+        const BATCH: u16 = 100;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let stat = tcp.poll_stat();
+
+        // Handshakes in batches, so listening sockets don't make rounds larger than a chunk of a sweep
+        let (active, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let ack = Some(server_seqs[&10000]);
+        for start in (1..=IDLE).step_by(BATCH as usize) {
+            establish_connections(&mut tcp, start..(start + BATCH).min(IDLE + 1), 1024).await;
+        }
+
+        // Relay of the active connection keeps going while all the sockets are swept
+        let swept = stat.sockets_swept();
+        let mut max_latency = Duration::ZERO;
+        let mut seq = 2;
+        while stat.sockets_swept() - swept < IDLE as u64 + 1 {
+            let frame = build_client_frame(40000, 10000, TcpControl::Psh, seq, ack, b"data");
+            seq += 4;
+
+            let start = Instant::now();
+            tcp.drive_interface_state(&frame).await.unwrap();
+            time::timeout(Duration::from_secs(10), async {
+                while active[0].lock().recv_buffer.is_empty() {
+                    time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("data not received");
+            max_latency = max_latency.max(start.elapsed());
+            active[0].lock().recv_buffer.clear();
+        }
+
+        // A chunk of a sweep, a batch being established and marks left by the previous batch
+        assert!(
+            stat.max_sockets_serviced() <= (TCP_SWEEP_CHUNK_SIZE + 2 * BATCH as usize + 1) as u64,
+            "serviced {} sockets in a round",
+            stat.max_sockets_serviced()
+        );
+        assert!(
+            max_latency < Duration::from_millis(500),
+            "data delayed {:?}",
+            max_latency
+        );
+    }
+
+    #[tokio::test]
+    async fn manager_panic_reported() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+
+        // The manager reads the time as soon as it switches to the clock
+        struct PanickingClock;
+        impl Clock for PanickingClock {
+            fn now(&self) -> Instant {
+                panic!("manager panic induced by test");
+            }
+        }
+        tcp.set_clock(Arc::new(PanickingClock));
+        tcp.manager_notify.notify();
+
+        let err = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("manager exit not detected")
+            .unwrap_err();
+        assert!(err.to_string().contains("manager panic induced by test"), "{}", err);
+        // Pending I/Os of connections are released
+        assert!(controls[0].lock().is_closed);
+
+        // Frames are refused instead of panicking
+        assert!(tcp.drive_interface_state(&build_syn_frame(50000)).await.is_err());
+        assert!(tcp.recv_packet().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn manager_stopped_reported() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        // Stop the manager as `TcpTun` is dropped, the interface and its channels are dropped with it
+        tcp.manager_running.store(false, Ordering::Relaxed);
+        tcp.manager_notify.notify();
+
+        let err = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("manager exit not detected")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        let err = tcp.drive_interface_state(&build_syn_frame(50000)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        let err = tcp
+            .drive_interface_state_batch((0..4).map(|i| build_syn_frame(50000 + i)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn relay_tasks_counted_and_limited() {
+        // Connections to the server are kept in the backlog until the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            listener.local_addr().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_max_relay_tasks(Some(2));
+        let relay_tasks = tcp.relay_tasks();
+
+        for i in 0..3 {
+            let frame = build_syn_frame(50000 + i);
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+            tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        }
+        assert_eq!(relay_tasks.count(), 2);
+
+        // Sockets are closed after the manager exits, then the tasks finish
+        drop(tcp);
+        drop(listener);
+        time::timeout(Duration::from_secs(5), async {
+            while relay_tasks.count() != 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("relay tasks never finished");
+    }
+
+    #[tokio::test]
+    async fn syn_over_max_connections_reset() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_max_connections(Some(2));
+
+        for i in 0..3 {
+            let frame = build_syn_frame(50000 + i);
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+            tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        assert_eq!(tcp.conntrack().socket_count(), 2);
+
+        // Only the third SYN is refused
+        let mut replies = HashMap::new();
+        for _ in 0..3 {
+            let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+                .await
+                .expect("SYN not replied")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            replies.insert(packet.dst_port(), packet.rst());
+        }
+        assert_eq!(replies, HashMap::from([(50000, false), (50001, false), (50002, true)]));
+    }
+
+    #[tokio::test]
+    async fn accepted_connection_echoed() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        assert!(tcp.acceptor().is_none());
+        tcp.set_accept_connections(true);
+        let mut acceptor = tcp.acceptor().unwrap();
+        assert!(tcp.acceptor().is_none());
+
+        let frame = build_syn_frame(40000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 80);
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        let (mut connection, accepted_src, accepted_dst) = time::timeout(Duration::from_secs(5), acceptor.accept())
+            .await
+            .expect("connection not accepted")
+            .unwrap();
+        assert_eq!((accepted_src, accepted_dst), (src_addr, dst_addr));
+        // Handed over instead of being tunneled
+        assert_eq!(tcp.relay_tasks().count(), 0);
+
+        let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        assert!(packet.syn() && packet.ack());
+        let server_seq = packet.seq_number() + 1;
+
+        let frame = build_client_frame(40000, 80, TcpControl::Psh, 2, Some(server_seq), b"hello");
+        tcp.drive_interface_state(&frame).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        time::timeout(Duration::from_secs(5), connection.read_exact(&mut buf))
+            .await
+            .expect("data not received")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        connection.write_all(&buf).await.unwrap();
+
+        let echoed = time::timeout(Duration::from_secs(5), async {
+            loop {
+                let frame = tcp.recv_packet().await.unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                if packet.seq_number() == server_seq && !packet.payload().is_empty() {
+                    return packet.payload().to_vec();
+                }
+            }
+        })
+        .await
+        .expect("data not echoed");
+        assert_eq!(echoed, b"hello");
+
+        // Acceptor is closed after accepting is disabled
+        tcp.set_accept_connections(false);
+        let err = acceptor.accept().await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn buffer_sizes_follow_socket() {
+        use tokio::io::AsyncReadExt;
+
+        const SEGMENT_SIZE: usize = TCP_INITIAL_WINDOW_SEGMENT_SIZE as usize;
+        const INITIAL_WINDOW: u32 = 10;
+
+        // Receive buffer is enlarged for the initial window beyond the configured size
+        let mut context = ServiceContext::new();
+        let mut accept_opts = context.accept_opts();
+        accept_opts.tcp.send_buffer_size = Some(4096);
+        accept_opts.tcp.recv_buffer_size = Some(1024);
+        accept_opts.tcp.initial_window = Some(INITIAL_WINDOW);
+        context.set_accept_opts(accept_opts);
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_accept_connections(true);
+        let mut acceptor = tcp.acceptor().unwrap();
+
+        let frame = build_syn_frame(40000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 80);
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let (mut connection, ..) = time::timeout(Duration::from_secs(5), acceptor.accept())
+            .await
+            .expect("connection not accepted")
+            .unwrap();
+
+        let initial_window_size = INITIAL_WINDOW as usize * SEGMENT_SIZE;
+        {
+            let control = connection.control.lock();
+            assert_eq!(control.recv_buffer.capacity(), initial_window_size);
+            assert_eq!(control.send_buffer.capacity(), 4096);
+        }
+
+        let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        assert!(packet.syn() && packet.ack());
+        let server_seq = packet.seq_number() + 1;
+
+        // Client's whole initial window arrives before the relay reads anything
+        let data = (0..initial_window_size).map(|i| i as u8).collect::<Vec<_>>();
+        let ack = build_client_frame(40000, 80, TcpControl::None, 2, Some(server_seq), &[]);
+        tcp.drive_interface_state(&ack).await.unwrap();
+        for (i, segment) in data.chunks(SEGMENT_SIZE).enumerate() {
+            let seq = 2 + (i * SEGMENT_SIZE) as i32;
+            let frame = build_client_frame(40000, 80, TcpControl::None, seq, Some(server_seq), segment);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+
+        let mut received = vec![0u8; initial_window_size];
+        time::timeout(Duration::from_secs(5), connection.read_exact(&mut received))
+            .await
+            .expect("data not received")
+            .unwrap();
+        assert!(received == data);
+    }
+
+    #[tokio::test]
+    async fn accepted_connection_default_timeouts() {
+        const READ_TIMEOUT: Duration = Duration::from_secs(3);
+        const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_read_timeout(Some(READ_TIMEOUT));
+        tcp.set_write_timeout(Some(WRITE_TIMEOUT));
+        tcp.set_accept_connections(true);
+        let mut acceptor = tcp.acceptor().unwrap();
+
+        let frame = build_syn_frame(40000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 80);
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        let (connection, ..) = time::timeout(Duration::from_secs(5), acceptor.accept())
+            .await
+            .expect("connection not accepted")
+            .unwrap();
+
+        // New connections take the stack's timeouts, which they could still override
+        {
+            let control = connection.control.lock();
+            assert_eq!(control.read_timeout, Some(READ_TIMEOUT));
+            assert_eq!(control.write_timeout, Some(WRITE_TIMEOUT));
+        }
+        connection.set_read_timeout(None);
+        assert_eq!(connection.control.lock().read_timeout, None);
+    }
+
+    #[tokio::test]
+    async fn syn_outside_accept_cidrs_ignored() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let accept_cidrs = vec![IpCidr::new(IpAddress::v4(10, 0, 0, 0), 30)];
+        let mut tcp = TcpTun::new(
+            context,
+            balancer,
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            Some(accept_cidrs),
+        );
+
+        // 10.0.0.3 is in the range, 10.0.0.4 is not
+        for (src_port, dst_ip) in [(50000, Ipv4Addr::new(10, 0, 0, 3)), (50001, Ipv4Addr::new(10, 0, 0, 4))] {
+            let mut frame = build_syn_frame(src_port);
+            {
+                let mut ip_packet = Ipv4Packet::new_unchecked(&mut frame[..]);
+                ip_packet.set_dst_addr(dst_ip.into());
+                ip_packet.fill_checksum();
+                let src_ip = ip_packet.src_addr();
+                let mut tcp_packet = TcpPacket::new_unchecked(ip_packet.payload_mut());
+                tcp_packet.fill_checksum(&src_ip.into(), &Ipv4Address::from(dst_ip).into());
+            }
+
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+            let dst_addr = SocketAddr::new(dst_ip.into(), packet.dst_port());
+            tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        assert_eq!(tcp.conntrack().socket_count(), 1);
+
+        // SYN out of the range is neither accepted nor reset, by the interface either
+        let frame = time::timeout(Duration::from_secs(5), tcp.recv_packet())
+            .await
+            .expect("SYN not replied")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        assert!(packet.syn() && packet.ack());
+        assert_eq!(packet.dst_port(), 50000);
+        // Connection in the range is reset later, its server isn't running
+        while let Ok(frame) = time::timeout(Duration::from_millis(500), tcp.recv_packet()).await {
+            let frame = frame.unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert_eq!(packet.dst_port(), 50000);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_interface_establishes_connections() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let iface_address = Ipv4Address::new(10, 0, 0, 1);
+        let iface_config = TcpInterfaceConfig::new(
+            vec![IpCidr::new(iface_address.into(), 24)],
+            vec![(
+                IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+                Route::new_ipv4_gateway(iface_address),
+            )],
+        )
+        .unwrap();
+        let mut tcp = TcpTun::with_interface(
+            context,
+            balancer,
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            None,
+            iface_config,
+        );
+
+        // Destinations are accepted through the default route via the interface's own address
+        let (controls, _) = establish_connections(&mut tcp, 0..2, 4096).await;
+        for control in controls.iter() {
+            assert_eq!(control.lock().socket_info.state, TcpState::Established);
+        }
+    }
+
+    #[test]
+    fn custom_interface_validated() {
+        let ipv4_address = Ipv4Address::new(10, 0, 0, 1);
+        let ipv6_address = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let ipv4_default = IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0);
+        let ipv6_default = IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0);
+
+        assert!(TcpInterfaceConfig::new(Vec::new(), Vec::new()).is_err());
+        // IPv6 is enabled without its default route
+        assert!(TcpInterfaceConfig::new(
+            vec![
+                IpCidr::new(ipv4_address.into(), 24),
+                IpCidr::new(ipv6_address.into(), 64)
+            ],
+            vec![(ipv4_default, Route::new_ipv4_gateway(ipv4_address))],
+        )
+        .is_err());
+        // Gateway isn't an address of the interface, nothing would be accepted
+        assert!(TcpInterfaceConfig::new(
+            vec![IpCidr::new(ipv4_address.into(), 24)],
+            vec![(ipv4_default, Route::new_ipv4_gateway(Ipv4Address::new(10, 0, 0, 254)))],
+        )
+        .is_err());
+        // Route via a gateway of the other family
+        assert!(TcpInterfaceConfig::new(
+            vec![IpCidr::new(ipv4_address.into(), 24)],
+            vec![
+                (ipv4_default, Route::new_ipv4_gateway(ipv4_address)),
+                (ipv6_default, Route::new_ipv4_gateway(ipv4_address)),
+            ],
+        )
+        .is_err());
+        assert!(TcpInterfaceConfig::new(
+            vec![
+                IpCidr::new(ipv4_address.into(), 24),
+                IpCidr::new(ipv6_address.into(), 64)
+            ],
+            vec![
+                (ipv4_default, Route::new_ipv4_gateway(ipv4_address)),
+                (ipv6_default, Route::new_ipv6_gateway(ipv6_address)),
+            ],
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn syn_over_rate_reset() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_conn_rate_limit(1, 2);
+        let stat = tcp.conn_rate_stat();
+        let relay_tasks = tcp.relay_tasks();
+
+        for i in 0..5 {
+            let frame = build_syn_frame(50000 + i);
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), packet.src_port());
+            let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), packet.dst_port());
+            tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        }
+        assert_eq!(relay_tasks.count(), 2);
+        assert_eq!(stat.rejected(), 3);
+    }
+
+    #[tokio::test]
+    async fn conntrack_outbound_bind_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+
+        let bind_addr = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let mut context = ServiceContext::new();
+        context.set_connect_opts(ConnectOpts {
+            bind_local_addr: Some(bind_addr),
+            ..Default::default()
+        });
+        let context = Arc::new(context);
+
+        let states = SharedTcpConnectionStates::default();
+        let conntrack = TcpConnTrack {
+            states: states.clone(),
+            traffic: Arc::default(),
+            live_sockets: Arc::default(),
+        };
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 50000);
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(1024, 1024)));
+        let tracker = TcpConnectionTracker::new(states, (src_addr, target_addr), control);
+
+        let remote = AutoProxyClientStream::connect_bypassed(context.clone(), target_addr)
+            .await
+            .unwrap();
+        record_outbound(&context, &tracker, &remote);
+
+        let entries = conntrack.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outbound_addr.map(|a| a.ip()), Some(bind_addr));
+        assert_eq!(entries[0].outbound_addr, Some(remote.local_addr().unwrap()));
+        assert_eq!(entries[0].outbound_peer_addr, Some(target_addr));
+
+        drop(tracker);
+        assert!(conntrack.is_empty());
+    }
+
+    #[tokio::test]
+    async fn conntrack_socket_endpoints() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000);
+        let _tracker =
+            TcpConnectionTracker::new(tcp.connection_states.clone(), (src_addr, dst_addr), controls[0].clone());
+
+        let entries = tcp.conntrack().entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, TcpState::Established);
+        assert_eq!(entries[0].socket_local_addr, Some(dst_addr));
+        assert_eq!(entries[0].socket_remote_addr, Some(src_addr));
+        assert_eq!(entries[0].outbound_addr, None);
+    }
+
+    #[tokio::test]
+    async fn buffer_occupancy_of_stalled_client() {
+        use tokio::io::AsyncWriteExt;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let key = (
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000),
+        );
+        let _tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, controls[0].clone());
+        let mut connection = TcpConnection {
+            key,
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: tcp.traffic.clone(),
+        };
+
+        // The client never acknowledges, remote's data piles up once the socket's buffer is full too
+        let data = [0xAAu8; 512];
+        while time::timeout(Duration::from_millis(100), connection.write(&data))
+            .await
+            .is_ok()
+        {}
+
+        let occupancy = tcp.buffer_occupancy();
+        assert_eq!(occupancy.len(), 1);
+        let occupancy = occupancy[0];
+        assert_eq!((occupancy.src_addr, occupancy.dst_addr), key);
+        assert_eq!(occupancy.send_capacity, 1024);
+        assert!(occupancy.send_buffered > occupancy.send_capacity - data.len());
+        assert_eq!(occupancy.recv_buffered, 0);
+        assert_eq!(occupancy.recv_capacity, 1024);
+    }
+
+    #[test]
+    fn manager_wakeups_coalesced() {
+        let key = (
+            "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+            "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+        );
+        let dirty = SharedDirtyConnections::default();
+        let notified = Arc::new(AtomicBool::new(false));
+        let notify = ManagerNotify::new(thread::current(), dirty.clone(), notified.clone());
+        let mut control = TcpSocketControl::new(16, 16);
+
+        // Relay's I/Os between two rounds
+        for _ in 0..100 {
+            notify.mark_connection_dirty(key, &mut control);
+            notify.notify();
+        }
+        assert_eq!(notify.dirty_locks.load(Ordering::Relaxed), 1);
+        assert_eq!(notify.unparks.load(Ordering::Relaxed), 1);
+
+        // Manager started a round and checked the connection
+        notified.swap(false, Ordering::AcqRel);
+        assert_eq!(mem::take(&mut *dirty.lock()), HashSet::from([key]));
+        control.marked_dirty = false;
+
+        for _ in 0..100 {
+            notify.mark_connection_dirty(key, &mut control);
+            notify.notify();
+        }
+        assert_eq!(notify.dirty_locks.load(Ordering::Relaxed), 2);
+        assert_eq!(notify.unparks.load(Ordering::Relaxed), 2);
+        assert_eq!(mem::take(&mut *dirty.lock()), HashSet::from([key]));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_fully_relayed() {
+        use tokio::io::AsyncWriteExt;
+
+        const CONNECTIONS: u16 = 32;
+        const DATA_SIZE: usize = 256 * 1024;
+        const CHUNK_SIZE: usize = 333;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, mut next_seqs) = establish_connections(&mut tcp, 0..CONNECTIONS, 4096).await;
+
+        // Writers fill their small buffers faster than the manager drains them
+        let mut writers = Vec::new();
+        for (port, control) in (0..CONNECTIONS).zip(controls) {
+            let mut connection = TcpConnection {
+                key: (
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000 + port),
+                ),
+                control,
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            };
+            writers.push(tokio::spawn(async move {
+                let data = vec![port as u8; DATA_SIZE];
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    connection.write_all(chunk).await.unwrap();
+                }
+                connection
+            }));
+        }
+
+        // Acknowledge everything in order, until all data of every connection arrived
+        let mut received = HashMap::new();
+        time::timeout(Duration::from_secs(30), async {
+            while received.len() < CONNECTIONS as usize || received.values().any(|&n| n < DATA_SIZE) {
+                let frame = tcp.recv_packet().await.unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                assert!(!packet.rst());
+                let port = packet.src_port();
+                let next_seq = next_seqs.get_mut(&port).unwrap();
+                if packet.seq_number() != *next_seq || packet.payload().is_empty() {
+                    continue;
+                }
+                assert!(packet.payload().iter().all(|&b| b == (port - 10000) as u8));
+                *next_seq += packet.payload().len();
+                *received.entry(port).or_insert(0) += packet.payload().len();
+
+                let frame = build_client_frame(40000, port, TcpControl::None, 2, Some(*next_seq), &[]);
+                tcp.drive_interface_state(&frame).await.unwrap();
+            }
+        })
+        .await
+        .expect("data not fully relayed, wakeups lost");
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+    }
+
+    /// Write 3 single bytes to a connection established with `nodelay`, returns how many of them were sent to the
+    /// client, which never acknowledges, within 500ms of their writes
+    async fn unacknowledged_writes_sent(nodelay: bool) -> usize {
+        use tokio::io::AsyncWriteExt;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let tcp_opts = TcpSocketOpts {
+            send_buffer_size: Some(1024),
+            recv_buffer_size: Some(1024),
+            nodelay,
+            ..Default::default()
+        };
+        let (controls, server_seqs) = establish_connections_with_opts(&mut tcp, 0..1, &tcp_opts, None).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let mut next_seq = server_seqs[&10000];
+
+        let mut sent = 0;
+        for byte in 1..=3u8 {
+            connection.write_all(&[byte]).await.unwrap();
+
+            let deadline = Instant::now() + Duration::from_millis(500);
+            while let Ok(frame) = time::timeout_at(deadline.into(), tcp.recv_packet()).await {
+                let frame = frame.unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                // Retransmissions of bytes already counted are skipped
+                if packet.seq_number() == next_seq && !packet.payload().is_empty() {
+                    assert_eq!(packet.payload(), &[byte]);
+                    assert!(packet.psh());
+                    next_seq += 1;
+                    sent += 1;
+                    break;
+                }
+            }
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn nodelay_sends_small_writes() {
+        // Each byte is sent in its own segment promptly
+        assert_eq!(unacknowledged_writes_sent(true).await, 3);
+        // Nagle's algorithm holds the latter ones until the first is acknowledged
+        assert_eq!(unacknowledged_writes_sent(false).await, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_connection_reset() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        controls[0].lock().idle_timeout = Some(Duration::from_secs(1));
+
+        // Reaped by the full sweep shortly after the timeout
+        let start = Instant::now();
+        loop {
+            let frame = time::timeout(Duration::from_secs(3), tcp.recv_packet())
+                .await
+                .expect("idle connection not reset")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.src_port() == 10000 && packet.rst() {
+                break;
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(controls[0].lock().is_closed);
+    }
+
+    #[tokio::test]
+    async fn idle_reset_by_manual_clock() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let idle_timeout = Duration::from_secs(600);
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        controls[0].lock().idle_timeout = Some(idle_timeout);
+
+        let clock = ManualClock::new();
+        tcp.set_clock(Arc::new(clock.clone()));
+
+        // Not reaped before its timeout passes on the clock
+        clock.advance(idle_timeout / 2);
+        tcp.manager_notify.notify();
+        assert!(time::timeout(Duration::from_millis(300), tcp.recv_packet())
+            .await
+            .is_err());
+        assert!(!controls[0].lock().is_closed);
+
+        // Reaped by the next full sweep, without waiting for the timeout
+        clock.advance(idle_timeout);
+        tcp.manager_notify.notify();
+        loop {
+            let frame = time::timeout(Duration::from_millis(500), tcp.recv_packet())
+                .await
+                .expect("idle connection not reset")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.src_port() == 10000 && packet.rst() {
+                break;
+            }
+        }
+        assert!(controls[0].lock().is_closed);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_counted_by_manual_clock_from_creation() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        // Connection is created after the clock is set, while the system's time passes
+        let clock = ManualClock::new();
+        tcp.set_clock(Arc::new(clock.clone()));
+        time::sleep(Duration::from_millis(100)).await;
+
+        let idle_timeout = Duration::from_secs(600);
+        let (controls, ..) = establish_connections(&mut tcp, 0..1, 1024).await;
+        controls[0].lock().idle_timeout = Some(idle_timeout);
+
+        clock.advance(idle_timeout - Duration::from_secs(1));
+        tcp.manager_notify.notify();
+        assert!(time::timeout(Duration::from_millis(300), tcp.recv_packet())
+            .await
+            .is_err());
+        assert!(!controls[0].lock().is_closed);
+
+        // Reaped once exactly `idle_timeout` passed on the clock
+        clock.advance(Duration::from_secs(1));
+        tcp.manager_notify.notify();
+        loop {
+            let frame = time::timeout(Duration::from_millis(500), tcp.recv_packet())
+                .await
+                .expect("idle connection not reset")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.src_port() == 10000 && packet.rst() {
+                break;
+            }
+        }
+        assert!(controls[0].lock().is_closed);
+    }
+
+    #[tokio::test]
+    async fn dead_upstream_reset() {
+        use tokio::{io::AsyncReadExt, sync::oneshot};
+
+        const DEAD_PEER_TIMEOUT: Duration = Duration::from_millis(300);
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+        controls[0].lock().dead_peer_timeout = Some(DEAD_PEER_TIMEOUT);
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+
+        // Relay takes the first chunk, then its remote stops responding
+        let (first_read_tx, first_read_rx) = oneshot::channel();
+        let (resume_tx, resume_rx) = oneshot::channel::<()>();
+        let relay = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let n = connection.read(&mut buf).await.unwrap();
+            let _ = first_read_tx.send(n);
+            let _ = resume_rx.await;
+            connection.read(&mut buf).await
+        });
+
+        let ack = Some(server_seqs[&10000]);
+        let frame = build_client_frame(40000, 10000, TcpControl::Psh, 2, ack, b"first");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        assert_eq!(first_read_rx.await.unwrap(), 5);
+
+        // Client keeps sending, but nothing is taken anymore
+        let frame = build_client_frame(40000, 10000, TcpControl::Psh, 7, ack, b"second");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let start = Instant::now();
+
+        loop {
+            let frame = time::timeout(Duration::from_secs(3), tcp.recv_packet())
+                .await
+                .expect("connection with a dead remote not reset")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.rst() {
+                break;
+            }
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= DEAD_PEER_TIMEOUT, "reset after {:?}", elapsed);
+        assert!(
+            elapsed < DEAD_PEER_TIMEOUT + Duration::from_millis(500),
+            "reset after {:?}",
+            elapsed
+        );
+        assert!(controls[0].lock().is_closed);
+
+        // Relay's I/Os fail instead of taking it as EOF
+        resume_tx.send(()).unwrap();
+        let err = relay.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn early_data_reset_on_slow_connect() {
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("slow.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(
+            context.clone(),
+            balancer.clone(),
+            1500,
+            TcpSchedulerPolicy::Unordered,
+            None,
+        );
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..2, 1024).await;
+        let policy = TcpEarlyDataPolicy::Reset {
+            timeout: Duration::from_millis(100),
+        };
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let mut relays = Vec::new();
+        for (port, control) in (10000..).zip(controls) {
+            let key = (src_addr, SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port));
+            let connection = TcpConnection {
+                key,
+                control: control.clone(),
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            };
+            let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, control);
+            let context = context.clone();
+            let balancer = balancer.clone();
+            relays.push(tokio::spawn(async move {
+                let target_addr = Address::from(key.1);
+                establish_client_tcp_redir(
+                    context,
+                    balancer,
+                    connection,
+                    key.0,
+                    &target_addr,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    tracker,
+                    SniffConfig::default(),
+                    None,
+                    None,
+                    None,
+                    false,
+                    policy,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+            }));
+        }
+
+        // Client sending data while connecting is reset
+        let frame = build_client_frame(40000, 10000, TcpControl::Psh, 2, Some(server_seqs[&10000]), b"hello");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let result = time::timeout(Duration::from_secs(2), &mut relays[0])
+            .await
+            .expect("connection not reset")
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        loop {
+            let frame = time::timeout(Duration::from_secs(2), tcp.recv_packet())
+                .await
+                .expect("RST not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.src_port() == 10000 && packet.rst() {
+                break;
+            }
+            assert!(!packet.fin());
+        }
+
+        // Idle client keeps waiting for the connection
+        assert!(time::timeout(Duration::from_millis(200), &mut relays[1]).await.is_err());
+        relays[1].abort();
+    }
+
+    #[tokio::test]
+    async fn early_data_delayed_until_connected() {
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        tcp.set_early_data_policy(TcpEarlyDataPolicy::Delay);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let key = (
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000),
+        );
+        let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, controls[0].clone());
+        tracker.set_delay_data(true);
+
+        // Neither acknowledged nor buffered while connecting
+        let frame = build_client_frame(40000, 10000, TcpControl::Psh, 2, Some(server_seqs[&10000]), b"hello");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(200), tcp.recv_packet())
+            .await
+            .is_err());
+        assert!(controls[0].lock().recv_buffer.is_empty());
+        assert_eq!(controls[0].lock().socket_info.recv_queue, 0);
+
+        // Client's retransmission is accepted after connected
+        tracker.set_delay_data(false);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let ack = time::timeout(Duration::from_secs(2), tcp.recv_packet())
+            .await
+            .expect("data not acknowledged")
+            .unwrap();
+        let packet = TcpPacket::new_checked(&ack[20..]).unwrap();
+        assert_eq!(packet.ack_number(), TcpSeqNumber(2 + 5));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while controls[0].lock().recv_buffer.len() != 5 {
+            assert!(Instant::now() < deadline, "data not buffered");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn conntrack_counts_and_traffic() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let conntrack = tcp.conntrack();
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..2, 1024).await;
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let mut connections = Vec::new();
+        for (port, control) in (10000..).zip(controls) {
+            let key = (src_addr, SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port));
+            let tracker = TcpConnectionTracker::new(tcp.connection_states.clone(), key, control.clone());
+            let connection = TcpConnection {
+                key,
+                control,
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: tcp.traffic.clone(),
+            };
+            connections.push((connection, tracker));
+            assert_eq!(tcp.connection_count(), connections.len());
+        }
+        assert_eq!(conntrack.len(), 2);
+
+        let (connection, _) = &mut connections[0];
+        connection.write_all(b"hello").await.unwrap();
+        let frame = build_client_frame(40000, 10000, TcpControl::Psh, 2, Some(server_seqs[&10000]), b"hi");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        let mut request = [0u8; 2];
+        time::timeout(Duration::from_secs(5), connection.read_exact(&mut request))
+            .await
+            .expect("request not received")
+            .unwrap();
+        assert_eq!(&request, b"hi");
+        assert_eq!(conntrack.tx(), 2);
+        assert_eq!(conntrack.rx(), 5);
+
+        // Totals are kept after connections are finished
+        connections.remove(0);
+        assert_eq!(tcp.connection_count(), 1);
+        connections.clear();
+        assert_eq!(tcp.connection_count(), 0);
+        assert!(conntrack.is_empty());
+        assert_eq!(conntrack.tx(), 2);
+        assert_eq!(conntrack.rx(), 5);
+    }
+
+    #[tokio::test]
+    async fn close_flushes_and_fins_connections() {
+        use tokio::io::AsyncWriteExt;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let relay_tasks = tcp.relay_tasks();
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 1024).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+
+        // Data from the remote is still queued while closing
+        connection.write_all(b"bye").await.unwrap();
+        tcp.close();
+        assert!(!tcp.is_closed());
+
+        // New connections are refused
+        let frame = build_syn_frame(50000);
+        let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+        let src_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 50000);
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 80);
+        tcp.handle_packet(src_addr, dst_addr, 0, &packet).await.unwrap();
+        tcp.drive_interface_state(&frame).await.unwrap();
+        assert_eq!(relay_tasks.count(), 0);
+
+        let mut received = Vec::new();
+        let mut next_seq = server_seqs[&10000];
+        let (mut refused, mut fin_received) = (false, false);
+        while !(refused && fin_received) {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("RST or FIN not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            if packet.dst_port() == 50000 {
+                assert!(packet.rst());
+                refused = true;
+                continue;
+            }
+            if fin_received || packet.seq_number() != next_seq {
+                continue;
+            }
+            received.extend_from_slice(packet.payload());
+            next_seq += packet.payload().len();
+            if packet.fin() {
+                next_seq += 1;
+                fin_received = true;
+            }
+        }
+        assert_eq!(received, b"bye");
+
+        // Closed once the client acknowledged the FIN
+        assert!(!tcp.is_closed());
+        let frame = build_client_frame(40000, 10000, TcpControl::None, 2, Some(next_seq), &[]);
+        tcp.drive_interface_state(&frame).await.unwrap();
+        time::timeout(Duration::from_secs(10), async {
+            while !tcp.is_closed() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connections not closed");
+        assert!(connection.write_all(b"late").await.is_err());
+    }
+
+    #[test]
+    fn listen_unaddressable_released() {
+        let tcp_opts = TcpSocketOpts::default();
+
+        let err = create_listen_socket(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 0), &tcp_opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let socket = create_listen_socket(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443), &tcp_opts).unwrap();
+        assert_eq!(socket.state(), TcpState::Listen);
+        assert_eq!(socket.recv_capacity(), DEFAULT_TCP_RECV_BUFFER_SIZE as usize);
+    }
+
+    #[test]
+    fn initial_window_applied() {
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443);
+
+        let socket = create_listen_socket(dst_addr, &TcpSocketOpts::default()).unwrap();
+        assert!(socket.ack_delay().is_some());
+
+        let tcp_opts = TcpSocketOpts {
+            recv_buffer_size: Some(0x3FFF),
+            initial_window: Some(64),
+            ..Default::default()
+        };
+        let socket = create_listen_socket(dst_addr, &tcp_opts).unwrap();
+        assert_eq!(socket.recv_capacity(), 64 * TCP_INITIAL_WINDOW_SEGMENT_SIZE as usize);
+        assert!(socket.ack_delay().is_none());
+
+        // Buffer larger than the initial window is kept
+        let tcp_opts = TcpSocketOpts {
+            initial_window: Some(10),
+            keepalive: Some(Duration::from_secs(15)),
+            ..Default::default()
+        };
+        let socket = create_listen_socket(dst_addr, &tcp_opts).unwrap();
+        assert_eq!(socket.recv_capacity(), DEFAULT_TCP_RECV_BUFFER_SIZE as usize);
+        // Not reset by `listen`
+        assert_eq!(socket.keep_alive(), Some(SmolDuration::from_secs(15)));
+    }
+
+    #[test]
+    fn default_keepalive_applied() {
+        let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443);
+
+        // Unset
+        let mut tcp_opts = TcpSocketOpts::default();
+        tcp_opts.keepalive = resolve_keepalive(&tcp_opts, Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
+        let socket = create_listen_socket(dst_addr, &tcp_opts).unwrap();
+        assert_eq!(socket.keep_alive(), Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT.into()));
+
+        // Set by the user
+        let mut tcp_opts = TcpSocketOpts {
+            keepalive: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        tcp_opts.keepalive = resolve_keepalive(&tcp_opts, Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
+        let socket = create_listen_socket(dst_addr, &tcp_opts).unwrap();
+        assert_eq!(socket.keep_alive(), Some(SmolDuration::from_secs(60)));
+
+        // Explicitly disabled
+        let mut tcp_opts = TcpSocketOpts::default();
+        tcp_opts.keepalive = resolve_keepalive(&tcp_opts, None);
+        let socket = create_listen_socket(dst_addr, &tcp_opts).unwrap();
+        assert_eq!(socket.keep_alive(), None);
+    }
+
+    #[test]
+    fn buffer_profile_matched_destination() {
+        let bulk = AddressRules::from_lines("bulk", ["203.0.113.0/24"]).unwrap();
+        let profiles = vec![TcpBufferProfile::new(bulk, Some(0x3FFF * 64), Some(0x3FFF * 128))];
+        let tcp_opts = TcpSocketOpts {
+            send_buffer_size: Some(0x3FFF),
+            ..Default::default()
+        };
+
+        let matched_addr = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 7).into(), 443);
+        let opts = profile_tcp_opts(&profiles, &tcp_opts, matched_addr);
+        let socket = create_listen_socket(matched_addr, &opts).unwrap();
+        assert_eq!(socket.send_capacity(), 0x3FFF * 64);
+        assert_eq!(socket.recv_capacity(), 0x3FFF * 128);
+
+        let other_addr = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 7).into(), 443);
+        let opts = profile_tcp_opts(&profiles, &tcp_opts, other_addr);
+        let socket = create_listen_socket(other_addr, &opts).unwrap();
+        assert_eq!(socket.send_capacity(), 0x3FFF);
+        assert_eq!(socket.recv_capacity(), DEFAULT_TCP_RECV_BUFFER_SIZE as usize);
+    }
+
+    /// Send data from client to the stack within its advertised window, starting from `window` bytes, for `duration`,
+    /// while the relay reads every `read_interval` like a remote with a long round trip, returns the bytes read by the
+    /// relay
+    async fn measure_goodput(
+        tcp: &mut TcpTun,
+        connection: TcpConnection,
+        server_seq: TcpSeqNumber,
+        window: usize,
+        read_interval: Duration,
+        duration: Duration,
+    ) -> usize {
+        use tokio::io::AsyncReadExt;
+
+        const SEGMENT_SIZE: usize = 1400;
+
+        let dst_port = connection.key.1.port();
+        let received = Arc::new(AtomicUsize::new(0));
+        let relay = {
+            let received = received.clone();
+            let mut connection = connection;
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; 1024 * 1024];
+                loop {
+                    time::sleep(read_interval).await;
+                    let n = connection.read(&mut buffer).await.unwrap();
+                    received.fetch_add(n, Ordering::Relaxed);
+                }
+            })
+        };
+
+        let payload = [0u8; SEGMENT_SIZE];
+        let mut next_seq = TcpSeqNumber(2);
+        let mut window_end = next_seq + window;
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            if next_seq + SEGMENT_SIZE <= window_end {
+                let frame = build_client_frame(
+                    40000,
+                    dst_port,
+                    TcpControl::None,
+                    next_seq.0,
+                    Some(server_seq),
+                    &payload,
+                );
+                tcp.drive_interface_state(&frame).await.unwrap();
+                next_seq += SEGMENT_SIZE;
+            }
+
+            // Wait for a window update if the window is exhausted
+            let wait = if next_seq + SEGMENT_SIZE <= window_end {
+                Duration::ZERO
+            } else {
+                Duration::from_millis(1)
+            };
+            while let Ok(frame) = time::timeout(wait, tcp.recv_packet()).await {
+                let frame = frame.unwrap();
+                let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+                if packet.src_port() == dst_port && packet.ack() {
+                    let end = packet.ack_number() + packet.window_len() as usize;
+                    if end > window_end {
+                        window_end = end;
+                    }
+                }
+                if next_seq + SEGMENT_SIZE <= window_end {
+                    break;
+                }
+            }
+        }
+
+        relay.abort();
+        received.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_autotune_goodput() {
+        const BUFFER_SIZE: u32 = 16 * 1024;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..2, BUFFER_SIZE).await;
+        controls[1].lock().recv_buffer_max = Some(256 * 1024);
+
+        let mut goodputs = Vec::new();
+        for (port, control) in (10000..).zip(controls.iter()) {
+            let connection = TcpConnection {
+                key: (
+                    "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port),
+                ),
+                control: control.clone(),
+                manager_notify: tcp.manager_notify.clone(),
+                traffic: Arc::default(),
+            };
+            let goodput = measure_goodput(
+                &mut tcp,
+                connection,
+                server_seqs[&port],
+                BUFFER_SIZE as usize,
+                Duration::from_millis(50),
+                Duration::from_millis(500),
+            )
+            .await;
+            goodputs.push(goodput);
+        }
+
+        // Bound by the fixed buffers between reads without auto-tuning
+        assert!(goodputs[0] <= 2 * BUFFER_SIZE as usize * (500 / 50 + 1));
+        assert_eq!(controls[0].lock().recv_buffer.capacity(), BUFFER_SIZE as usize);
+        assert!(controls[1].lock().recv_buffer.capacity() > BUFFER_SIZE as usize);
+        assert!(goodputs[1] > 2 * goodputs[0], "goodputs {:?}", goodputs);
+    }
+
+    #[tokio::test]
+    async fn round_budget_keeps_latency_bounded() {
+        const BUFFER_SIZE: u32 = 64 * 1024;
+        const ROUND_BUDGET: usize = 1024;
+        const SEGMENT_SIZE: usize = 512;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..2, BUFFER_SIZE).await;
+        for control in controls.iter() {
+            control.lock().round_budget = Some(ROUND_BUDGET);
+        }
+
+        // Bulk transfer fills the window in one burst, the latency-sensitive request follows it
+        let bulk_size = BUFFER_SIZE as usize / 2;
+        let mut frames = (0..bulk_size / SEGMENT_SIZE)
+            .map(|i| {
+                let seq = 2 + (i * SEGMENT_SIZE) as i32;
+                let payload = [0xAB; SEGMENT_SIZE];
+                build_client_frame(40000, 10000, TcpControl::Psh, seq, Some(server_seqs[&10000]), &payload)
+            })
+            .collect::<Vec<_>>();
+        frames.push(build_client_frame(
+            40000,
+            10001,
+            TcpControl::Psh,
+            2,
+            Some(server_seqs[&10001]),
+            b"ping",
+        ));
+        tcp.drive_interface_state_batch(frames).await.unwrap();
+
+        time::timeout(Duration::from_secs(1), async {
+            while controls[1].lock().recv_buffer.len() < 4 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("request delayed by the bulk transfer");
+
+        // Bulk transfer still proceeds in the following rounds
+        time::timeout(Duration::from_secs(5), async {
+            while controls[0].lock().recv_buffer.len() < bulk_size {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("bulk transfer stopped");
+    }
+
+    #[tokio::test]
+    async fn bandwidth_limit_throttles_transfer() {
+        const BUFFER_SIZE: u32 = 64 * 1024;
+        const RATE: u64 = 32 * 1024;
+        const BURST: u64 = 4 * 1024;
+        const TRANSFER_SIZE: usize = 20 * 1024;
+        const SEGMENT_SIZE: usize = 512;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, BUFFER_SIZE).await;
+        controls[0].lock().bandwidth_limit = Some(TcpBandwidthLimit::new(RATE, BURST));
+
+        let frames = (0..TRANSFER_SIZE / SEGMENT_SIZE)
+            .map(|i| {
+                let seq = 2 + (i * SEGMENT_SIZE) as i32;
+                let payload = [0xAB; SEGMENT_SIZE];
+                build_client_frame(40000, 10000, TcpControl::Psh, seq, Some(server_seqs[&10000]), &payload)
+            })
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+        tcp.drive_interface_state_batch(frames).await.unwrap();
+
+        time::timeout(Duration::from_secs(5), async {
+            while controls[0].lock().recv_buffer.len() < TRANSFER_SIZE {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("throttled transfer stopped");
+
+        // The burst is moved right away, the rest at the rate
+        let expected = Duration::from_secs_f64((TRANSFER_SIZE as u64 - BURST) as f64 / RATE as f64);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= expected.mul_f64(0.8),
+            "elapsed {:?}, expected {:?}",
+            elapsed,
+            expected
+        );
+        assert!(elapsed < expected * 3, "elapsed {:?}, expected {:?}", elapsed, expected);
+    }
+
+    #[tokio::test]
+    async fn out_queue_bounded_while_not_read() {
+        const BUFFER_SIZE: u32 = 64 * 1024;
+        const OUT_QUEUE_LIMIT: usize = 8;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+        let stat = tcp.device_stats();
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, BUFFER_SIZE).await;
+        tcp.set_out_queue_limit(OUT_QUEUE_LIMIT);
+
+        // Remote sends far more segments than the queue could hold, frames are not read from the stack
+        let data = vec![0xAB; BUFFER_SIZE as usize / 2];
+        assert_eq!(controls[0].lock().send_buffer.enqueue_slice(&data), data.len());
+        let key = (
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000),
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000),
+        );
+        tcp.manager_notify.mark_dirty(key);
+        tcp.manager_notify.notify();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(stat.out_queue_len(), OUT_QUEUE_LIMIT);
+
+        // Reset replied to a segment of an unknown connection is dropped
+        let frame = build_client_frame(40001, 20000, TcpControl::None, 1, Some(TcpSeqNumber(1)), b"data");
+        tcp.drive_interface_state(&frame).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(stat.out_queue_len(), OUT_QUEUE_LIMIT);
+        assert_eq!(stat.out_queue_dropped(), 1);
+
+        // Sending resumes as the queue is drained, all data is sent in bounded batches
+        let mut sent_end: usize = 0;
+        while sent_end < data.len() {
+            let frame = time::timeout(Duration::from_secs(1), tcp.recv_packet())
+                .await
+                .expect("sending not resumed")
+                .unwrap();
+            // The manager may have refilled the queue already after receiving the frame
+            assert!(stat.out_queue_len() <= OUT_QUEUE_LIMIT);
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            let end = packet.seq_number() + packet.payload().len() - server_seqs[&10000];
+            if end > sent_end {
+                // Acknowledged, so the client's window keeps open
+                sent_end = end;
+                let ack = Some(server_seqs[&10000] + sent_end);
+                let frame = build_client_frame(40000, 10000, TcpControl::None, 2, ack, &[]);
+                tcp.drive_interface_state(&frame).await.unwrap();
+            }
+        }
+        assert_eq!(sent_end, data.len());
+    }
+
+    /// Sends HTTPS through `https_server`, the others through `other_server`
+    struct HttpsSelector {
+        https_server: ServerAddr,
+        other_server: ServerAddr,
+    }
+
+    impl ServerSelector for HttpsSelector {
+        fn select(
+            &self,
+            balancer: &PingBalancer,
+            cx: &ServerSelectContext<'_>,
+        ) -> io::Result<Option<Arc<ServerIdent>>> {
+            let server = match *cx.target_addr {
+                Address::SocketAddress(sa) if sa.port() == 443 => &self.https_server,
+                _ => &self.other_server,
+            };
+            Ok(balancer.find_server(server))
+        }
+    }
+
+    #[tokio::test]
+    async fn server_selector_routes_by_port() {
+        let listeners = [
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let svr_addrs = listeners
+            .iter()
+            .map(|l| ServerAddr::from(l.local_addr().unwrap()))
+            .collect::<Vec<_>>();
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        // Listeners never answer the first check
+        builder.max_server_rtt(Duration::from_millis(100));
+        for svr_addr in svr_addrs.iter() {
+            builder.add_server(ServerConfig::new(svr_addr.clone(), "password", CipherKind::AES_256_GCM));
+        }
+        let balancer = builder.build().await.unwrap();
+        for listener in listeners.iter() {
+            while time::timeout(Duration::from_millis(100), listener.accept())
+                .await
+                .is_ok()
+            {}
+        }
+        let selector = HttpsSelector {
+            https_server: svr_addrs[0].clone(),
+            other_server: svr_addrs[1].clone(),
+        };
+
+        let peer_addr = "10.0.0.2:40000".parse::<SocketAddr>().unwrap();
+        for (port, index) in [(443, 0), (80, 1), (8443, 1)] {
+            let addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), port));
+            let (server, _remote) = connect_remote(
+                &context,
+                &balancer,
+                peer_addr,
+                &addr,
+                None,
+                Some(&selector),
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(server.server_config().addr(), &svr_addrs[index]);
+
+            // Connected to the chosen upstream only
+            time::timeout(Duration::from_secs(5), listeners[index].accept())
+                .await
+                .expect("chosen server not connected")
+                .unwrap();
+            assert!(time::timeout(Duration::from_millis(10), listeners[1 - index].accept())
+                .await
+                .is_err());
+        }
+    }
+
+    /// Resolves to an unreachable IPv6 address before a listening IPv4 address
+    struct DualStackResolver {
+        ipv4_addr: SocketAddr,
+    }
+
+    #[async_trait]
+    impl DnsResolve for DualStackResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            let ipv6_addr = SocketAddr::new("2001:db8::1".parse::<IpAddr>().unwrap(), port);
+            Ok(vec![ipv6_addr, self.ipv4_addr])
+        }
+    }
+
+    /// Connects every flow directly
+    struct BypassSelector;
+
+    impl ServerSelector for BypassSelector {
+        fn select(&self, _: &PingBalancer, _: &ServerSelectContext<'_>) -> io::Result<Option<Arc<ServerIdent>>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn bypassed_target_races_address_families() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipv4_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_ipv6_first(true);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(DualStackResolver { ipv4_addr })));
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        // The preferred IPv6 address never answers, IPv4 wins the race
+        let peer_addr = "10.0.0.2:50000".parse::<SocketAddr>().unwrap();
+        let addr = Address::DomainNameAddress("dual-stack.example".to_owned(), ipv4_addr.port());
+        let (_, remote) = time::timeout(
+            Duration::from_secs(2),
+            connect_remote(
+                &context,
+                &balancer,
+                peer_addr,
+                &addr,
+                None,
+                Some(&BypassSelector),
+                None,
+                None,
+                None,
+                None,
+            ),
+        )
+        .await
+        .expect("connect stalled on the IPv6 address")
+        .unwrap();
+        assert_eq!(remote.peer_addr().unwrap(), ipv4_addr);
+    }
+
+    /// Resolver of every name to the loopback address
+    struct LoopbackResolver;
+
+    #[async_trait]
+    impl DnsResolve for LoopbackResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+        }
+    }
+
+    #[tokio::test]
+    async fn names_resolved_by_tun_resolver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        // The context's resolver never answers in time, names have to be resolved by the stack's
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("server.invalid".to_owned(), listen_addr.port()),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let resolver = DnsResolver::custom_resolver(LoopbackResolver);
+
+        let peer_addr = "10.0.0.2:50000".parse::<SocketAddr>().unwrap();
+        let addr = Address::DomainNameAddress("target.invalid".to_owned(), listen_addr.port());
+        for selector in [None, Some(&BypassSelector as &dyn ServerSelector)] {
+            let (_, remote) = time::timeout(
+                Duration::from_secs(1),
+                connect_remote(
+                    &context,
+                    &balancer,
+                    peer_addr,
+                    &addr,
+                    None,
+                    selector,
+                    None,
+                    None,
+                    None,
+                    Some(&resolver),
+                ),
+            )
+            .await
+            .expect("name not resolved")
+            .unwrap();
+            assert_eq!(remote.peer_addr().unwrap(), listen_addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_dscp_applied_to_outbound() {
+        use socket2::SockRef;
+
+        // Expedited Forwarding with ECN capable transport
+        let mut frame = build_syn_frame(50000);
+        {
+            let mut packet = Ipv4Packet::new_unchecked(&mut frame[..]);
+            packet.set_dscp(46);
+            packet.set_ecn(0b10);
+            packet.fill_checksum();
+        }
+        let packet = IpPacket::new_checked(&frame[..]).unwrap().unwrap();
+        assert_eq!(packet.traffic_class(), 46 << 2 | 0b10);
+        let tos = outbound_tos(packet.traffic_class());
+        assert_eq!(tos, Some(46 << 2));
+        assert_eq!(outbound_tos(0b01), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let svr_addr = ServerAddr::from(listener.local_addr().unwrap());
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(svr_addr, "password", CipherKind::AES_256_GCM));
+        let balancer = builder.build().await.unwrap();
+
+        let peer_addr = "10.0.0.2:50000".parse::<SocketAddr>().unwrap();
+        let addr = Address::SocketAddress("10.0.0.3:80".parse::<SocketAddr>().unwrap());
+        for (tos, expected) in [(tos, 46 << 2), (None, 0)] {
+            let (_, remote) = connect_remote(&context, &balancer, peer_addr, &addr, None, None, tos, None, None, None)
+                .await
+                .unwrap();
+            let outbound_tos = match remote {
+                AutoProxyClientStream::Proxied(ref s) => SockRef::from(s.get_ref().get_ref()).tos().unwrap(),
+                AutoProxyClientStream::Bypassed(ref s) => SockRef::from(s).tos().unwrap(),
+            };
+            assert_eq!(outbound_tos, expected);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn outbound_fwmark_applied() {
+        use socket2::SockRef;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let svr_addr = ServerAddr::from(listener.local_addr().unwrap());
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(svr_addr, "password", CipherKind::AES_256_GCM));
+        let balancer = builder.build().await.unwrap();
+
+        let peer_addr = "10.0.0.2:50000".parse::<SocketAddr>().unwrap();
+        let addr = Address::SocketAddress("10.0.0.3:80".parse::<SocketAddr>().unwrap());
+        for (fwmark, expected) in [(Some(0x1234), 0x1234), (None, 0)] {
+            let remote = match connect_remote(
+                &context, &balancer, peer_addr, &addr, None, None, None, fwmark, None, None,
+            )
+            .await
+            {
+                Ok((_, remote)) => remote,
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                    // Setting SO_MARK requires CAP_NET_ADMIN
+                    return;
+                }
+                Err(err) => panic!("connect failed, error: {}", err),
+            };
+            let outbound_mark = match remote {
+                AutoProxyClientStream::Proxied(ref s) => SockRef::from(s.get_ref().get_ref()).mark().unwrap(),
+                AutoProxyClientStream::Bypassed(ref s) => SockRef::from(s).mark().unwrap(),
+            };
+            assert_eq!(outbound_mark, expected);
+        }
+    }
+
+    #[test]
+    fn rebuilt_interface_keeps_socket_handles() {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = 1500;
+        let (virt, ..) = VirtTunDevice::new(capabilities, TunDeviceStat::new());
+        let iface_config = TcpInterfaceConfig::default();
+        let mut iface = build_interface(virt, 0, &iface_config, None);
+
+        let tcp_opts = TcpSocketOpts::default();
+        let handles = (0..5)
+            .map(|port| {
+                let dst_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 10000 + port);
+                iface.add_socket(create_listen_socket(dst_addr, &tcp_opts).unwrap())
+            })
+            .collect::<Vec<_>>();
+        // Leave free slots before the remaining sockets
+        iface.remove_socket(handles[0]);
+        iface.remove_socket(handles[2]);
+
+        rebuild_interface(&mut iface, 1000, &iface_config, None);
+        assert_eq!(iface.device().capabilities().max_transmission_unit, 1000);
+        assert_eq!(iface.sockets().count(), 3);
+        for (index, &handle) in handles.iter().enumerate() {
+            if index == 0 || index == 2 {
+                continue;
+            }
+            let socket = iface.get_socket::<TcpSocket>(handle);
+            assert_eq!(socket.local_endpoint().port, 10000 + index as u16);
+        }
+    }
+
+    /// Receive `len` bytes sent by the stack on the connection to 10.0.0.3:10000, acknowledging them, returns lengths
+    /// of the frames carrying them
+    async fn receive_segments(tcp: &mut TcpTun, next_seq: &mut TcpSeqNumber, len: usize) -> Vec<usize> {
+        let mut frame_lens = Vec::new();
+        let end_seq = *next_seq + len;
+        while *next_seq < end_seq {
+            let frame = time::timeout(Duration::from_secs(10), tcp.recv_packet())
+                .await
+                .expect("data not sent")
+                .unwrap();
+            let packet = TcpPacket::new_checked(&frame[20..]).unwrap();
+            assert!(!packet.rst());
+            if packet.seq_number() != *next_seq || packet.payload().is_empty() {
+                continue;
+            }
+            frame_lens.push(frame.len());
+            *next_seq += packet.payload().len();
+
+            let frame = build_client_frame(40000, 10000, TcpControl::None, 2, Some(*next_seq), &[]);
+            tcp.drive_interface_state(&frame).await.unwrap();
+        }
+        frame_lens
+    }
+
+    #[tokio::test]
+    async fn mtu_changed_mid_connection() {
+        use tokio::io::AsyncWriteExt;
+
+        const DATA_SIZE: usize = 32 * 1024;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        // Client's MSS is larger than both MTUs, so only the MTU limits segments
+        let (controls, server_seqs) = establish_connections_with_mss(&mut tcp, 0..1, 0xFFFF, Some(8960)).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let mut next_seq = server_seqs[&10000];
+
+        connection.write_all(&[1u8; DATA_SIZE]).await.unwrap();
+        let frame_lens = receive_segments(&mut tcp, &mut next_seq, DATA_SIZE).await;
+        assert_eq!(frame_lens.iter().max(), Some(&1500));
+
+        tcp.set_mtu(1000);
+        connection.write_all(&[2u8; DATA_SIZE]).await.unwrap();
+        let frame_lens = receive_segments(&mut tcp, &mut next_seq, DATA_SIZE).await;
+        assert_eq!(frame_lens.iter().max(), Some(&1000));
+
+        // Connection is kept with its buffers
+        let control = controls[0].lock();
+        assert_eq!(control.socket_info.state, TcpState::Established);
+        assert_eq!(control.send_buffer.capacity(), 0xFFFF);
+    }
+
+    #[tokio::test]
+    async fn writable_len_tracks_send_buffer() {
+        use tokio::io::AsyncWriteExt;
+
+        const BUFFER_SIZE: usize = 4096;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, BUFFER_SIZE as u32).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let mut next_seq = server_seqs[&10000];
+        assert_eq!(connection.writable_len(), BUFFER_SIZE);
+
+        async fn wait_writable_len(connection: &TcpConnection, len: usize) {
+            time::timeout(Duration::from_secs(5), async {
+                while connection.writable_len() != len {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("send buffer not drained");
+        }
+
+        // Moved into the socket, where it stays until client acknowledges it
+        connection.write_all(&[1u8; BUFFER_SIZE]).await.unwrap();
+        wait_writable_len(&connection, BUFFER_SIZE).await;
+
+        assert_eq!(connection.write(&[2u8; 1000]).await.unwrap(), 1000);
+        assert_eq!(connection.writable_len(), BUFFER_SIZE - 1000);
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(connection.writable_len(), BUFFER_SIZE - 1000);
+
+        // Nearly full, a larger write waits instead of taking the few bytes left
+        assert_eq!(connection.write(&[3u8; 3000]).await.unwrap(), 3000);
+        assert_eq!(connection.writable_len(), BUFFER_SIZE - 4000);
+        assert!(
+            time::timeout(Duration::from_millis(100), connection.write(&[4u8; 1000]))
+                .await
+                .is_err()
+        );
+
+        receive_segments(&mut tcp, &mut next_seq, BUFFER_SIZE).await;
+        wait_writable_len(&connection, BUFFER_SIZE).await;
+        assert_eq!(connection.write(&[4u8; 1000]).await.unwrap(), 1000);
+    }
+
+    #[tokio::test]
+    async fn send_buffer_resized_at_runtime() {
+        use tokio::io::AsyncWriteExt;
+
+        let control = Arc::new(SpinMutex::new(TcpSocketControl::new(16, 16)));
+        let mut connection = standalone_connection(control.clone());
+        connection.write_all(&[1u8; 16]).await.unwrap();
+        assert_eq!(connection.writable_len(), 0);
+
+        // Can't drop queued data
+        assert_eq!(
+            connection.set_send_buffer_size(0).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            connection.set_send_buffer_size(8).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(control.lock().send_buffer.capacity(), 16);
+
+        // Queued data is kept, more could be written after enlarging it
+        connection.set_send_buffer_size(64).unwrap();
+        assert_eq!(control.lock().send_buffer.len(), 16);
+        assert_eq!(connection.writable_len(), 64 - 16);
+        connection.write_all(&[2u8; 8]).await.unwrap();
+        assert_eq!(connection.writable_len(), 64 - 24);
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_client_acknowledgement() {
+        use tokio::io::AsyncWriteExt;
+
+        const DATA_SIZE: usize = 3000;
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        let mut tcp = TcpTun::new(context, balancer, 1500, TcpSchedulerPolicy::Unordered, None);
+
+        let (controls, server_seqs) = establish_connections(&mut tcp, 0..1, 4096).await;
+        let mut connection = TcpConnection {
+            key: (
+                "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.3:10000".parse::<SocketAddr>().unwrap(),
+            ),
+            control: controls[0].clone(),
+            manager_notify: tcp.manager_notify.clone(),
+            traffic: Arc::default(),
+        };
+        let mut next_seq = server_seqs[&10000];
+
+        // Nothing written, nothing to wait for
+        time::timeout(Duration::from_secs(1), connection.flush())
+            .await
+            .expect("flush of nothing stalled")
+            .unwrap();
+
+        connection.write_all(&[1u8; DATA_SIZE]).await.unwrap();
+        let mut flush = tokio::spawn(async move {
+            connection.flush().await.unwrap();
+            connection
+        });
+        assert!(time::timeout(Duration::from_millis(100), &mut flush).await.is_err());
+
+        // Finished only after client received and acknowledged all of it
+        receive_segments(&mut tcp, &mut next_seq, DATA_SIZE).await;
+        let connection = time::timeout(Duration::from_secs(1), flush)
+            .await
+            .expect("flush not finished after acknowledgement")
+            .unwrap();
+        assert!(connection.control.lock().is_flushed());
     }
-    let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
 }
@@ -0,0 +1,64 @@
+//! IPv4 frames of TCP segments sent to and from the TCP stack in tests
+
+use std::net::SocketAddrV4;
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber},
+};
+
+/// Frame of a segment from `src` to `dst`, advertising the largest window without scaling
+pub fn build_tcp_frame(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    control: TcpControl,
+    seq: i32,
+    ack: Option<TcpSeqNumber>,
+    max_seg_size: Option<u16>,
+    payload: &[u8],
+) -> Vec<u8> {
+    let src_addr = Ipv4Address::from(*src.ip());
+    let dst_addr = Ipv4Address::from(*dst.ip());
+    let checksum_caps = ChecksumCapabilities::default();
+
+    let tcp_repr = TcpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+        control,
+        seq_number: TcpSeqNumber(seq),
+        ack_number: ack,
+        window_len: u16::MAX,
+        window_scale: None,
+        max_seg_size,
+        sack_permitted: false,
+        sack_ranges: [None, None, None],
+        payload,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        protocol: IpProtocol::Tcp,
+        payload_len: tcp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+
+    let mut buffer = vec![0u8; ip_repr.buffer_len() + tcp_repr.buffer_len()];
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut buffer);
+    ip_repr.emit(&mut ip_packet, &checksum_caps);
+    let mut tcp_packet = TcpPacket::new_unchecked(ip_packet.payload_mut());
+    tcp_repr.emit(&mut tcp_packet, &src_addr.into(), &dst_addr.into(), &checksum_caps);
+    buffer
+}
+
+/// SYN of a client connecting from 10.0.0.2 at `src_port` to 10.0.0.3:80
+pub fn build_syn_frame(src_port: u16) -> Vec<u8> {
+    build_tcp_frame(
+        SocketAddrV4::new([10, 0, 0, 2].into(), src_port),
+        SocketAddrV4::new([10, 0, 0, 3].into(), 80),
+        TcpControl::Syn,
+        1,
+        None,
+        None,
+        &[],
+    )
+}
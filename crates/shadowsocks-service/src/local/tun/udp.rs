@@ -8,7 +8,7 @@ use std::{
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
 use etherparse::PacketBuilder;
-use log::trace;
+use log::{debug, trace};
 use shadowsocks::relay::socks5::Address;
 use tokio::sync::mpsc;
 
@@ -19,22 +19,27 @@ use crate::local::{
     utils::to_ipv4_mapped,
 };
 
+use super::unsupported_protocol::build_host_unreachable;
+
 pub struct UdpTun {
     tun_rx: mpsc::Receiver<BytesMut>,
     manager: UdpAssociationManager<UdpTunInboundWriter>,
 }
 
 impl UdpTun {
+    /// Create a new UDP stack, packets that couldn't be relayed are answered with ICMP host unreachable if
+    /// `icmp_unreachable` is set
     pub fn new(
         context: Arc<ServiceContext>,
         balancer: PingBalancer,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        icmp_unreachable: bool,
     ) -> (UdpTun, Duration, mpsc::Receiver<SocketAddr>) {
         let (tun_tx, tun_rx) = mpsc::channel(64);
         let (manager, cleanup_interval, keepalive_rx) = UdpAssociationManager::new(
             context,
-            UdpTunInboundWriter::new(tun_tx),
+            UdpTunInboundWriter::new(tun_tx, icmp_unreachable),
             time_to_live,
             capacity,
             balancer,
@@ -43,6 +48,12 @@ impl UdpTun {
         (UdpTun { tun_rx, manager }, cleanup_interval, keepalive_rx)
     }
 
+    /// Set `SO_MARK` of outbound sockets, overriding the one in `ConnectOpts` of the context
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_outbound_fwmark(&mut self, fwmark: Option<u32>) {
+        self.manager.set_outbound_fwmark(fwmark);
+    }
+
     pub async fn handle_packet(
         &mut self,
         src_addr: SocketAddr,
@@ -74,11 +85,15 @@ impl UdpTun {
 #[derive(Clone)]
 struct UdpTunInboundWriter {
     tun_tx: mpsc::Sender<BytesMut>,
+    icmp_unreachable: bool,
 }
 
 impl UdpTunInboundWriter {
-    fn new(tun_tx: mpsc::Sender<BytesMut>) -> UdpTunInboundWriter {
-        UdpTunInboundWriter { tun_tx }
+    fn new(tun_tx: mpsc::Sender<BytesMut>, icmp_unreachable: bool) -> UdpTunInboundWriter {
+        UdpTunInboundWriter {
+            tun_tx,
+            icmp_unreachable,
+        }
     }
 }
 
@@ -105,36 +120,156 @@ impl UdpInboundWrite for UdpTunInboundWriter {
             }
         };
 
-        let packet = match (peer_addr, addr) {
-            (SocketAddr::V4(peer), SocketAddr::V4(remote)) => {
-                let builder =
-                    PacketBuilder::ipv4(remote.ip().octets(), peer.ip().octets(), 20).udp(remote.port(), peer.port());
-
-                let packet = BytesMut::with_capacity(builder.size(data.len()));
-                let mut packet_writer = packet.writer();
-                builder.write(&mut packet_writer, data).expect("PacketBuilder::write");
-
-                packet_writer.into_inner()
-            }
-            (SocketAddr::V6(peer), SocketAddr::V6(remote)) => {
-                let builder =
-                    PacketBuilder::ipv6(remote.ip().octets(), peer.ip().octets(), 20).udp(remote.port(), peer.port());
+        let packet = build_udp_packet(addr, peer_addr, data)?;
+        self.tun_tx.send(packet).await.expect("tun_tx::send");
+        Ok(())
+    }
 
-                let packet = BytesMut::with_capacity(builder.size(data.len()));
-                let mut packet_writer = packet.writer();
-                builder.write(&mut packet_writer, data).expect("PacketBuilder::write");
+    async fn send_unreachable(&self, peer_addr: SocketAddr, target_addr: &Address, data: &[u8]) -> io::Result<()> {
+        if !self.icmp_unreachable {
+            return Ok(());
+        }
 
-                packet_writer.into_inner()
-            }
-            _ => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "source and destination type unmatch",
-                ));
+        let target_addr = match *target_addr {
+            Address::SocketAddress(sa) => sa,
+            Address::DomainNameAddress(..) => {
+                let err = io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "tun destination must not be an domain name address",
+                );
+                return Err(err);
             }
         };
 
-        self.tun_tx.send(packet).await.expect("tun_tx::send");
+        // ICMP messages quote the invoking packet, which is the client's packet built again
+        let frame = build_udp_packet(peer_addr, target_addr, data)?;
+        let reply = build_host_unreachable(&frame).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        debug!(
+            "udp {} -> {} unreachable, sending ICMP to client",
+            peer_addr, target_addr
+        );
+
+        self.tun_tx
+            .send(BytesMut::from(&reply[..]))
+            .await
+            .expect("tun_tx::send");
         Ok(())
     }
 }
+
+/// Build an IP packet of UDP datagram `data` from `src_addr` to `dst_addr`
+fn build_udp_packet(src_addr: SocketAddr, dst_addr: SocketAddr, data: &[u8]) -> io::Result<BytesMut> {
+    let packet = match (src_addr, dst_addr) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            let builder = PacketBuilder::ipv4(src.ip().octets(), dst.ip().octets(), 20).udp(src.port(), dst.port());
+
+            let packet = BytesMut::with_capacity(builder.size(data.len()));
+            let mut packet_writer = packet.writer();
+            builder.write(&mut packet_writer, data).expect("PacketBuilder::write");
+
+            packet_writer.into_inner()
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            let builder = PacketBuilder::ipv6(src.ip().octets(), dst.ip().octets(), 20).udp(src.port(), dst.port());
+
+            let packet = BytesMut::with_capacity(builder.size(data.len()));
+            let mut packet_writer = packet.writer();
+            builder.write(&mut packet_writer, data).expect("PacketBuilder::write");
+
+            packet_writer.into_inner()
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "source and destination type unmatch",
+            ));
+        }
+    };
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use shadowsocks::{
+        config::{Mode, ServerConfig},
+        crypto::v1::CipherKind,
+        dns_resolver::{DnsResolve, DnsResolver},
+        ServerAddr,
+    };
+    use smoltcp::wire::{
+        Icmpv4DstUnreachable,
+        Icmpv4Message,
+        Icmpv4Packet,
+        IpProtocol,
+        Ipv4Address,
+        Ipv4Packet,
+        UdpPacket,
+    };
+    use tokio::time;
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    /// Resolver of a server that is down
+    struct DownResolver;
+
+    #[async_trait]
+    impl DnsResolve for DownResolver {
+        async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Err(io::Error::new(ErrorKind::NotFound, "server is down"))
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_answered_with_icmp() {
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(DownResolver)));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("down.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let (src_ip, dst_ip) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 0, 2, 1));
+        let src_addr = SocketAddr::new(src_ip.into(), 50000);
+        let dst_addr = SocketAddr::new(dst_ip.into(), 53);
+
+        let (mut udp, ..) = UdpTun::new(context.clone(), balancer.clone(), None, None, true);
+        udp.handle_packet(src_addr, dst_addr, b"request").await.unwrap();
+
+        let reply = time::timeout(Duration::from_secs(1), udp.recv_packet())
+            .await
+            .expect("unreachable server is not signaled");
+        let reply = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(reply.src_addr(), Ipv4Address::from(dst_ip));
+        assert_eq!(reply.dst_addr(), Ipv4Address::from(src_ip));
+        assert_eq!(reply.protocol(), IpProtocol::Icmp);
+
+        let icmp = Icmpv4Packet::new_checked(reply.payload()).unwrap();
+        assert!(icmp.verify_checksum());
+        assert_eq!(icmp.msg_type(), Icmpv4Message::DstUnreachable);
+        assert_eq!(icmp.msg_code(), u8::from(Icmpv4DstUnreachable::HostUnreachable));
+
+        // Quotes the client's packet, so the client could match it with its socket
+        let quoted = Ipv4Packet::new_unchecked(icmp.data());
+        assert_eq!(quoted.dst_addr(), Ipv4Address::from(dst_ip));
+        // Truncated after the first 64 bits of the payload
+        let quoted = UdpPacket::new_unchecked(&icmp.data()[quoted.header_len() as usize..]);
+        assert_eq!(quoted.src_port(), src_addr.port());
+        assert_eq!(quoted.dst_port(), dst_addr.port());
+
+        // Dropped silently if disabled
+        let (mut udp, ..) = UdpTun::new(context, balancer, None, None, false);
+        udp.handle_packet(src_addr, dst_addr, b"request").await.unwrap();
+        assert!(time::timeout(Duration::from_millis(200), udp.recv_packet())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Handling IP packets with protocols that are not supported by the TUN device
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        Icmpv4DstUnreachable,
+        Icmpv4Packet,
+        Icmpv4Repr,
+        Icmpv6DstUnreachable,
+        Icmpv6Packet,
+        Icmpv6ParamProblem,
+        Icmpv6Repr,
+        IpAddress,
+        IpProtocol,
+        IpVersion,
+        Ipv4Packet,
+        Ipv4Repr,
+        Ipv6Packet,
+        Ipv6Repr,
+    },
+};
+
+/// IPv6 minimum MTU, ICMPv6 error messages must not exceed it
+const IPV6_MIN_MTU: usize = 1280;
+
+/// Offset of the Next Header field in IPv6 header
+const IPV6_NEXT_HEADER_OFFSET: u32 = 6;
+
+/// Hop limit of the generated ICMP messages
+const ICMP_HOP_LIMIT: u8 = 64;
+
+/// Behavior for IP packets with protocols that are not supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedProtocolPolicy {
+    /// Drop silently
+    Drop,
+    /// Drop and reply ICMP protocol unreachable (ICMPv6 parameter problem for IPv6)
+    Unreachable,
+    /// Drop and log every packet
+    Log,
+}
+
+impl Display for UnsupportedProtocolPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnsupportedProtocolPolicy::Drop => f.write_str("drop"),
+            UnsupportedProtocolPolicy::Unreachable => f.write_str("unreachable"),
+            UnsupportedProtocolPolicy::Log => f.write_str("log"),
+        }
+    }
+}
+
+/// Error while parsing `UnsupportedProtocolPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UnsupportedProtocolPolicyError;
+
+impl Display for UnsupportedProtocolPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UnsupportedProtocolPolicy")
+    }
+}
+
+impl FromStr for UnsupportedProtocolPolicy {
+    type Err = UnsupportedProtocolPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(UnsupportedProtocolPolicy::Drop),
+            "unreachable" => Ok(UnsupportedProtocolPolicy::Unreachable),
+            "log" => Ok(UnsupportedProtocolPolicy::Log),
+            _ => Err(UnsupportedProtocolPolicyError),
+        }
+    }
+}
+
+/// Counters of packets with unsupported protocols, by IP protocol number
+#[derive(Clone)]
+pub struct UnsupportedProtocolStat {
+    counters: Arc<[AtomicU64]>,
+}
+
+impl UnsupportedProtocolStat {
+    pub fn new() -> UnsupportedProtocolStat {
+        UnsupportedProtocolStat {
+            counters: (0..=u8::MAX).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn incr(&self, protocol: IpProtocol) {
+        self.counters[u8::from(protocol) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of packets seen with IP protocol number `protocol`
+    pub fn count(&self, protocol: u8) -> u64 {
+        self.counters[protocol as usize].load(Ordering::Relaxed)
+    }
+
+    /// Total number of packets with unsupported protocols
+    pub fn total(&self) -> u64 {
+        self.counters.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Non-zero counters, as (IP protocol number, packets)
+    pub fn counts(&self) -> Vec<(u8, u64)> {
+        self.counters
+            .iter()
+            .enumerate()
+            .map(|(protocol, c)| (protocol as u8, c.load(Ordering::Relaxed)))
+            .filter(|&(_, n)| n > 0)
+            .collect()
+    }
+}
+
+impl Default for UnsupportedProtocolStat {
+    fn default() -> UnsupportedProtocolStat {
+        UnsupportedProtocolStat::new()
+    }
+}
+
+/// Build an ICMP message telling the sender of `frame` that its protocol is unreachable
+pub fn build_protocol_unreachable(frame: &[u8]) -> smoltcp::Result<Vec<u8>> {
+    build_icmp_error(frame, Icmpv4DstUnreachable::ProtoUnreachable, |header, data| {
+        Icmpv6Repr::ParamProblem {
+            reason: Icmpv6ParamProblem::UnrecognizedNxtHdr,
+            pointer: IPV6_NEXT_HEADER_OFFSET,
+            header,
+            data,
+        }
+    })
+}
+
+/// Build an ICMP message telling the sender of `frame` that its destination host is unreachable
+pub fn build_host_unreachable(frame: &[u8]) -> smoltcp::Result<Vec<u8>> {
+    build_icmp_error(frame, Icmpv4DstUnreachable::HostUnreachable, |header, data| {
+        Icmpv6Repr::DstUnreachable {
+            reason: Icmpv6DstUnreachable::AddrUnreachable,
+            header,
+            data,
+        }
+    })
+}
+
+/// Build an ICMP error message about `frame` back to its sender, `icmpv6_repr` builds the ICMPv6 message from the
+/// invoking packet's header and payload
+fn build_icmp_error<F>(frame: &[u8], icmpv4_reason: Icmpv4DstUnreachable, icmpv6_repr: F) -> smoltcp::Result<Vec<u8>>
+where
+    F: for<'a> FnOnce(Ipv6Repr, &'a [u8]) -> Icmpv6Repr<'a>,
+{
+    let checksum_caps = ChecksumCapabilities::default();
+
+    match IpVersion::of_packet(frame)? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame)?;
+            let header = Ipv4Repr::parse(&packet, &checksum_caps)?;
+
+            // Original IP header and the first 64 bits of its payload
+            let payload = packet.payload();
+            let icmp_repr = Icmpv4Repr::DstUnreachable {
+                reason: icmpv4_reason,
+                header,
+                data: &payload[..payload.len().min(8)],
+            };
+            let ip_repr = Ipv4Repr {
+                src_addr: header.dst_addr,
+                dst_addr: header.src_addr,
+                protocol: IpProtocol::Icmp,
+                payload_len: icmp_repr.buffer_len(),
+                hop_limit: ICMP_HOP_LIMIT,
+            };
+
+            let mut buffer = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+            let mut ip_packet = Ipv4Packet::new_unchecked(&mut buffer);
+            ip_repr.emit(&mut ip_packet, &checksum_caps);
+            let mut icmp_packet = Icmpv4Packet::new_unchecked(ip_packet.payload_mut());
+            icmp_repr.emit(&mut icmp_packet, &checksum_caps);
+
+            Ok(buffer)
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame)?;
+            let header = Ipv6Repr::parse(&packet)?;
+
+            // As much of the invoking packet as possible without exceeding the minimum IPv6 MTU
+            let payload = packet.payload();
+            let max_data_len = IPV6_MIN_MTU - 2 * header.buffer_len() - 8;
+            let icmp_repr = icmpv6_repr(header, &payload[..payload.len().min(max_data_len)]);
+            let ip_repr = Ipv6Repr {
+                src_addr: header.dst_addr,
+                dst_addr: header.src_addr,
+                next_header: IpProtocol::Icmpv6,
+                payload_len: icmp_repr.buffer_len(),
+                hop_limit: ICMP_HOP_LIMIT,
+            };
+
+            let mut buffer = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+            let mut ip_packet = Ipv6Packet::new_unchecked(&mut buffer);
+            ip_repr.emit(&mut ip_packet);
+            let mut icmp_packet = Icmpv6Packet::new_unchecked(ip_packet.payload_mut());
+            icmp_repr.emit(
+                &IpAddress::Ipv6(ip_repr.src_addr),
+                &IpAddress::Ipv6(ip_repr.dst_addr),
+                &mut icmp_packet,
+                &checksum_caps,
+            );
+
+            Ok(buffer)
+        }
+        _ => Err(smoltcp::Error::Unrecognized),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use smoltcp::wire::{Icmpv4Message, Ipv4Address};
+
+    use super::*;
+
+    #[test]
+    fn ipv4_protocol_unreachable() {
+        // An IPv4 packet with protocol SCTP (132)
+        let payload = [0xAAu8; 16];
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 0, 2),
+            dst_addr: Ipv4Address::new(1, 1, 1, 1),
+            protocol: IpProtocol::Unknown(132),
+            payload_len: payload.len(),
+            hop_limit: 64,
+        };
+        let mut frame = vec![0u8; repr.buffer_len() + payload.len()];
+        let mut packet = Ipv4Packet::new_unchecked(&mut frame);
+        repr.emit(&mut packet, &ChecksumCapabilities::default());
+        packet.payload_mut().copy_from_slice(&payload);
+
+        let reply = build_protocol_unreachable(&frame).unwrap();
+        let reply = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(reply.src_addr(), repr.dst_addr);
+        assert_eq!(reply.dst_addr(), repr.src_addr);
+        assert_eq!(reply.protocol(), IpProtocol::Icmp);
+
+        let icmp = Icmpv4Packet::new_checked(reply.payload()).unwrap();
+        assert!(icmp.verify_checksum());
+        assert_eq!(icmp.msg_type(), Icmpv4Message::DstUnreachable);
+        assert_eq!(icmp.msg_code(), u8::from(Icmpv4DstUnreachable::ProtoUnreachable));
+    }
+
+    #[test]
+    fn stat_by_protocol() {
+        let stat = UnsupportedProtocolStat::new();
+        stat.incr(IpProtocol::Unknown(132));
+        stat.incr(IpProtocol::Unknown(132));
+        stat.incr(IpProtocol::Unknown(47));
+
+        assert_eq!(stat.count(132), 2);
+        assert_eq!(stat.total(), 3);
+        assert_eq!(stat.counts(), vec![(47, 1), (132, 2)]);
+    }
+}
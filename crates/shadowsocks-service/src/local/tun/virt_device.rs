@@ -1,20 +1,121 @@
 //! Virtual Device for receiving packets from tun
 
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
 use smoltcp::{
     phy::{self, Device, DeviceCapabilities},
     time::Instant,
 };
 use tokio::sync::mpsc;
 
+/// Default number of frames produced by the TCP stack that could wait to be written to tun
+pub const DEFAULT_OUT_QUEUE_LIMIT: usize = 4096;
+
+#[derive(Debug, Default)]
+struct TunDeviceStatInner {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    in_queue_len: AtomicUsize,
+    out_queue_len: AtomicUsize,
+    out_dropped: AtomicU64,
+}
+
+/// Counters of frames passing through the virtual device of TCP stack, could be read while `Tun` is running
+///
+/// "In" frames are sent from tun to the TCP stack, "out" frames are sent from the TCP stack to tun.
+#[derive(Debug, Clone, Default)]
+pub struct TunDeviceStat {
+    inner: Arc<TunDeviceStatInner>,
+}
+
+impl TunDeviceStat {
+    pub fn new() -> TunDeviceStat {
+        TunDeviceStat::default()
+    }
+
+    pub(crate) fn in_queued(&self, len: usize) {
+        self.inner.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.inner.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+        self.inner.in_queue_len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn in_dequeued(&self) {
+        self.inner.in_queue_len.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn out_queued(&self) {
+        self.inner.out_queue_len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame of `len` bytes is taken from the out queue, returns the length of the queue before it
+    pub(crate) fn out_dequeued(&self, len: usize) -> usize {
+        self.inner.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.inner.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+        self.inner.out_queue_len.fetch_sub(1, Ordering::Relaxed)
+    }
+
+    fn out_dropped(&self) {
+        self.inner.out_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total frames sent to the TCP stack
+    pub fn frames_in(&self) -> u64 {
+        self.inner.frames_in.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes sent to the TCP stack
+    pub fn bytes_in(&self) -> u64 {
+        self.inner.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Total frames received from the TCP stack
+    pub fn frames_out(&self) -> u64 {
+        self.inner.frames_out.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes received from the TCP stack
+    pub fn bytes_out(&self) -> u64 {
+        self.inner.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Frames waiting to be processed by the TCP stack
+    pub fn in_queue_len(&self) -> usize {
+        self.inner.in_queue_len.load(Ordering::Relaxed)
+    }
+
+    /// Frames produced by the TCP stack, waiting to be written to tun
+    pub fn out_queue_len(&self) -> usize {
+        self.inner.out_queue_len.load(Ordering::Relaxed)
+    }
+
+    /// Total replies of the TCP stack dropped because the out queue was full
+    ///
+    /// Other frames are kept in sockets until the queue is drained, they are never dropped.
+    pub fn out_queue_dropped(&self) -> u64 {
+        self.inner.out_dropped.load(Ordering::Relaxed)
+    }
+}
+
 pub struct VirtTunDevice {
     capabilities: DeviceCapabilities,
     in_buf: mpsc::UnboundedReceiver<Vec<u8>>,
     out_buf: mpsc::UnboundedSender<Vec<u8>>,
+    stat: TunDeviceStat,
+    // Frames in `out_buf` are bounded by this, shared with the reader of `out_buf`
+    out_queue_limit: Arc<AtomicUsize>,
+    // Transmitting was refused since the last `take_exhausted`
+    exhausted: bool,
 }
 
 impl VirtTunDevice {
     pub fn new(
         capabilities: DeviceCapabilities,
+        stat: TunDeviceStat,
     ) -> (Self, mpsc::UnboundedReceiver<Vec<u8>>, mpsc::UnboundedSender<Vec<u8>>) {
         let (iface_tx, iface_output) = mpsc::unbounded_channel();
         let (iface_input, iface_rx) = mpsc::unbounded_channel();
@@ -24,11 +125,35 @@ impl VirtTunDevice {
                 capabilities,
                 in_buf: iface_rx,
                 out_buf: iface_tx,
+                stat,
+                out_queue_limit: Arc::new(AtomicUsize::new(DEFAULT_OUT_QUEUE_LIMIT)),
+                exhausted: false,
             },
             iface_output,
             iface_input,
         )
     }
+
+    /// Change MTU of the device
+    ///
+    /// `Interface` copies capabilities of its device when it is built, so it has to be rebuilt to pick up the new MTU.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.capabilities.max_transmission_unit = mtu;
+    }
+
+    /// Maximum frames waiting to be written to tun, could be changed while the device is running
+    pub fn out_queue_limit(&self) -> Arc<AtomicUsize> {
+        self.out_queue_limit.clone()
+    }
+
+    /// Check if transmitting was refused by the full out queue since the last check
+    pub fn take_exhausted(&mut self) -> bool {
+        std::mem::take(&mut self.exhausted)
+    }
+
+    fn is_out_queue_full(&self) -> bool {
+        self.stat.out_queue_len() >= self.out_queue_limit.load(Ordering::Relaxed)
+    }
 }
 
 impl<'a> Device<'a> for VirtTunDevice {
@@ -37,6 +162,7 @@ impl<'a> Device<'a> for VirtTunDevice {
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         if let Ok(buffer) = self.in_buf.try_recv() {
+            self.stat.in_dequeued();
             let rx = Self::RxToken { buffer };
             let tx = VirtTxToken(self);
             return Some((rx, tx));
@@ -45,7 +171,12 @@ impl<'a> Device<'a> for VirtTunDevice {
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        return Some(VirtTxToken(self));
+        // Sockets keep their data until the out queue is drained
+        if self.is_out_queue_full() {
+            self.exhausted = true;
+            return None;
+        }
+        Some(VirtTxToken(self))
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
@@ -73,8 +204,16 @@ impl<'a> phy::TxToken for VirtTxToken<'a> {
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
+        // Replies to received frames, they are dropped, peers will retransmit
+        if self.0.is_out_queue_full() {
+            self.0.exhausted = true;
+            self.0.stat.out_dropped();
+            return Err(smoltcp::Error::Exhausted);
+        }
+
         let mut buffer = vec![0u8; len];
         let result = f(&mut buffer);
+        self.0.stat.out_queued();
         self.0.out_buf.send(buffer).expect("channel closed unexpectly");
         result
     }
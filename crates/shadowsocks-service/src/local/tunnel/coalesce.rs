@@ -0,0 +1,65 @@
+//! Coalescing of small UDP responses
+//!
+//! UDP tunnel sends every response back to the client with its own `send_to`. When a target answers with bursts of
+//! tiny datagrams, merging them saves syscalls, but it changes datagram boundaries that UDP applications usually rely
+//! on. So it is strictly opt-in: nothing is coalesced unless a rule matches the association's forward address, and the
+//! merging itself is left to a `UdpResponseCoalesce` hook that knows how the protocol frames its messages.
+
+use std::{fmt, sync::Arc};
+
+use bytes::Bytes;
+use shadowsocks::relay::socks5::Address;
+
+use crate::acl::AddressRules;
+
+/// Maximum responses passed to the hook at once
+pub(crate) const MAX_COALESCE_BATCH: usize = 32;
+
+/// Hook merging small responses of an association
+///
+/// Only implement it for protocols that could split the merged datagrams back into messages.
+pub trait UdpResponseCoalesce: Send + Sync {
+    /// Merge `datagrams` received back to back from the target, in their order, into datagrams sent to the client
+    fn coalesce(&self, datagrams: Vec<Bytes>) -> Vec<Bytes>;
+}
+
+/// Coalescing of an association, chosen by `UdpCoalesceRules`
+#[derive(Clone)]
+pub(crate) struct UdpCoalesce {
+    pub max_size: usize,
+    pub hook: Arc<dyn UdpResponseCoalesce>,
+}
+
+impl fmt::Debug for UdpCoalesce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpCoalesce").field("max_size", &self.max_size).finish()
+    }
+}
+
+/// Destinations whose small responses are coalesced, nothing is coalesced by default
+#[derive(Debug, Clone, Default)]
+pub struct UdpCoalesceRules {
+    rules: Vec<(AddressRules, UdpCoalesce)>,
+}
+
+impl UdpCoalesceRules {
+    /// Create an empty rule table
+    pub fn new() -> UdpCoalesceRules {
+        UdpCoalesceRules::default()
+    }
+
+    /// Responses of associations forwarding to destinations matching `rules` are passed to `hook` if they are not
+    /// larger than `max_size`
+    ///
+    /// Rules are matched in the order they were added.
+    pub fn add_rule(&mut self, rules: AddressRules, max_size: usize, hook: Arc<dyn UdpResponseCoalesce>) {
+        self.rules.push((rules, UdpCoalesce { max_size, hook }));
+    }
+
+    pub(crate) fn coalesce(&self, forward_addr: &Address) -> Option<UdpCoalesce> {
+        self.rules
+            .iter()
+            .find(|(rules, ..)| rules.check_address_matched(forward_addr))
+            .map(|(_, coalesce)| coalesce.clone())
+    }
+}
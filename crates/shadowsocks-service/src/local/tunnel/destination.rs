@@ -0,0 +1,290 @@
+//! Destination addresses of UDP packets received from clients with `IP_RECVORIGDSTADDR` / `IPV6_RECVORIGDSTADDR`,
+//! and responses sent back from them with `IP_PKTINFO` / `IPV6_PKTINFO`
+//!
+//! Transparent sockets (`IP_TRANSPARENT` / `IPV6_TRANSPARENT`) receive packets redirected by TPROXY, whose destinations
+//! are the ones that clients intended to reach.
+
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::unix::io::AsRawFd,
+    ptr,
+};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Ancillary data of a packet received from a client, fields are `None` unless the socket was set receiving them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpRecvMeta {
+    /// Address that the client sent the packet to
+    pub destination: Option<SocketAddr>,
+    /// Hop limit that the packet arrived with
+    pub hop_limit: Option<u8>,
+}
+
+pub fn set_int_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const _,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive destination addresses of packets in their ancillary data
+///
+/// IPv6 sockets receive both, IPv4 packets received by dual-stack sockets carry IPv4 destinations.
+pub fn set_recv_destination(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(..) => set_int_option(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1),
+        SocketAddr::V6(..) => {
+            set_int_option(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
+            // Not supported by IPv6-only sockets, which never receive IPv4 packets
+            let _ = set_int_option(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1);
+            Ok(())
+        }
+    }
+}
+
+/// Receive packets redirected by TPROXY to `socket`, requires `CAP_NET_ADMIN`
+pub fn set_transparent(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(..) => set_int_option(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1),
+        SocketAddr::V6(..) => set_int_option(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1),
+    }
+}
+
+/// Bind a transparent socket on `addr`, which may be an address of another host, for sending responses from it
+///
+/// Sockets of several clients sending to the same destination are bound on the same address.
+pub fn bind_transparent(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    match addr {
+        SocketAddr::V4(..) => set_int_option(socket.as_raw_fd(), libc::SOL_IP, libc::IP_TRANSPARENT, 1)?,
+        SocketAddr::V6(..) => {
+            set_int_option(socket.as_raw_fd(), libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+            socket.set_only_v6(true)?;
+        }
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Receive a packet into `buf`, with the ancillary data that the socket was set receiving
+///
+/// Packets larger than `buf` are truncated, like `UdpSocket::recv_from`.
+pub async fn recv_from_with_meta(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, UdpRecvMeta)> {
+    loop {
+        socket.readable().await?;
+
+        match socket.try_io(Interest::READABLE, || recv_msg(socket, buf)) {
+            // Readiness was cleared by `try_io`, wait for the next one
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            x => return x,
+        }
+    }
+}
+
+fn recv_msg(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, UdpRecvMeta)> {
+    unsafe {
+        // Aligned for `cmsghdr`, large enough for a destination and a hop limit
+        let mut control_buf = [0u64; 16];
+        let mut src_addr: libc::sockaddr_storage = mem::zeroed();
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut src_addr as *mut _ as *mut _;
+        msg.msg_namelen = mem::size_of_val(&src_addr) as libc::socklen_t;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = mem::size_of_val(&control_buf) as _;
+
+        let ret = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut meta = UdpRecvMeta::default();
+        let mut cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let rcmsg = &*cmsg;
+            match (rcmsg.cmsg_level, rcmsg.cmsg_type) {
+                (libc::SOL_IP, libc::IP_TTL) | (libc::SOL_IPV6, libc::IPV6_HOPLIMIT) => {
+                    let value = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    meta.hop_limit = u8::try_from(value).ok();
+                }
+                (libc::SOL_IP, libc::IP_RECVORIGDSTADDR) => {
+                    let addr = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in);
+                    meta.destination = Some(SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                        u16::from_be(addr.sin_port),
+                    )));
+                }
+                (libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR) => {
+                    let addr = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in6);
+                    meta.destination = Some(SocketAddr::V6(SocketAddrV6::new(
+                        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                        u16::from_be(addr.sin6_port),
+                        addr.sin6_flowinfo,
+                        addr.sin6_scope_id,
+                    )));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        let src_addr = SockAddr::new(src_addr, msg.msg_namelen);
+        Ok((ret as usize, src_addr.as_socket().expect("SocketAddr"), meta))
+    }
+}
+
+/// Send `buf` to `target` from the local address `source`, instead of the one chosen by routing
+///
+/// Responses to clients come from the address that they sent their packets to, even if `socket` listens on an
+/// unspecified address of a host with several addresses.
+pub async fn send_to_from(socket: &UdpSocket, buf: &[u8], target: SocketAddr, source: IpAddr) -> io::Result<usize> {
+    loop {
+        socket.writable().await?;
+
+        match socket.try_io(Interest::WRITABLE, || send_msg(socket, buf, target, source)) {
+            // Readiness was cleared by `try_io`, wait for the next one
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            x => return x,
+        }
+    }
+}
+
+fn send_msg(socket: &UdpSocket, buf: &[u8], target: SocketAddr, source: IpAddr) -> io::Result<usize> {
+    unsafe {
+        // Aligned for `cmsghdr`
+        let mut control_buf = [0u64; 8];
+        let target_addr = SockAddr::from(target);
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = target_addr.as_ptr() as *mut _;
+        msg.msg_namelen = target_addr.len();
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = mem::size_of_val(&control_buf) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        // Targets of IPv6 sockets are IPv6 addresses, IPv4 ones are mapped. So are their sources
+        let control_len = match (target, source) {
+            (SocketAddr::V4(..), IpAddr::V4(source)) => {
+                let pktinfo = libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from(source).to_be(),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                (*cmsg).cmsg_level = libc::SOL_IP;
+                (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(&pktinfo) as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, pktinfo);
+                libc::CMSG_SPACE(mem::size_of_val(&pktinfo) as u32)
+            }
+            (SocketAddr::V6(..), source) => {
+                let source = match source {
+                    IpAddr::V4(source) => source.to_ipv6_mapped(),
+                    IpAddr::V6(source) => source,
+                };
+                let pktinfo = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: source.octets(),
+                    },
+                    ipi6_ifindex: 0,
+                };
+                (*cmsg).cmsg_level = libc::SOL_IPV6;
+                (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(&pktinfo) as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo, pktinfo);
+                libc::CMSG_SPACE(mem::size_of_val(&pktinfo) as u32)
+            }
+            (SocketAddr::V4(..), IpAddr::V6(..)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "IPv6 source address for an IPv4 target",
+                ));
+            }
+        };
+        msg.msg_controllen = control_len as _;
+
+        let ret = libc::sendmsg(socket.as_raw_fd(), &msg, 0);
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn destination_received_and_replied_from() {
+        // Listening on all addresses, 127.0.0.2 is a loopback address too
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+            .await
+            .unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+
+        // Not received unless it is enabled
+        let mut buf = [0u8; 16];
+        client
+            .send_to(b"default", SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+            .await
+            .unwrap();
+        let (n, peer_addr, meta) = recv_from_with_meta(&socket, &mut buf).await.unwrap();
+        assert_eq!(
+            (&buf[..n], peer_addr, meta),
+            (&b"default"[..], client.local_addr().unwrap(), UdpRecvMeta::default())
+        );
+
+        set_recv_destination(&socket).unwrap();
+        for ip in [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)] {
+            let destination = SocketAddr::new(ip.into(), port);
+            client.send_to(b"request", destination).await.unwrap();
+            let (n, peer_addr, meta) = recv_from_with_meta(&socket, &mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"request");
+            assert_eq!(meta.destination, Some(destination));
+
+            // Replied from the address that the client sent to
+            send_to_from(&socket, b"response", peer_addr, ip.into()).await.unwrap();
+            let (n, src_addr) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..n], src_addr), (&b"response"[..], destination));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Hop limits (TTL) of UDP packets, received from clients with `IP_RECVTTL` / `IPV6_RECVHOPLIMIT` and set on
+//! sockets relaying them to servers
+
+use std::{
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+    str::FromStr,
+};
+
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+
+use super::destination::set_int_option;
+
+/// Hop limit of packets relayed to servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpHopLimitMode {
+    /// The system's default, clients' hop limits are not received. The default
+    Default,
+    /// The hop limit that the client's packet arrived with
+    Copy,
+    /// The hop limit that the client's packet arrived with, decremented like by a router. Packets that reach 0 are
+    /// dropped
+    Decrement,
+}
+
+impl UdpHopLimitMode {
+    /// Check if hop limits of clients' packets have to be received
+    pub fn is_relayed(self) -> bool {
+        self != UdpHopLimitMode::Default
+    }
+
+    /// Hop limit of a packet that arrived with `hop_limit` relayed to servers, `None` if it is dropped
+    pub fn outbound(self, hop_limit: u8) -> Option<u8> {
+        match self {
+            UdpHopLimitMode::Default | UdpHopLimitMode::Copy => Some(hop_limit),
+            UdpHopLimitMode::Decrement => match hop_limit.saturating_sub(1) {
+                0 => None,
+                n => Some(n),
+            },
+        }
+    }
+}
+
+impl Display for UdpHopLimitMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpHopLimitMode::Default => f.write_str("default"),
+            UdpHopLimitMode::Copy => f.write_str("copy"),
+            UdpHopLimitMode::Decrement => f.write_str("decrement"),
+        }
+    }
+}
+
+/// Error while parsing `UdpHopLimitMode` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpHopLimitModeError;
+
+impl Display for UdpHopLimitModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpHopLimitMode, expecting \"default\", \"copy\" or \"decrement\"")
+    }
+}
+
+impl FromStr for UdpHopLimitMode {
+    type Err = UdpHopLimitModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(UdpHopLimitMode::Default),
+            "copy" => Ok(UdpHopLimitMode::Copy),
+            "decrement" => Ok(UdpHopLimitMode::Decrement),
+            _ => Err(UdpHopLimitModeError),
+        }
+    }
+}
+
+/// Receive hop limits of packets in their ancillary data
+///
+/// IPv6 sockets receive both, IPv4 packets received by dual-stack sockets carry their TTL.
+pub fn set_recv_hop_limit(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(..) => set_int_option(fd, libc::SOL_IP, libc::IP_RECVTTL, 1),
+        SocketAddr::V6(..) => {
+            set_int_option(fd, libc::SOL_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+            // Not supported by IPv6-only sockets, which never receive IPv4 packets
+            let _ = set_int_option(fd, libc::SOL_IP, libc::IP_RECVTTL, 1);
+            Ok(())
+        }
+    }
+}
+
+/// Set hop limit of packets sent by `socket`
+///
+/// IPv6 sockets set both, IPv4 packets sent by dual-stack sockets to IPv4-mapped addresses take the TTL.
+pub fn set_hop_limit<S: AsRawFd>(socket: &S, hop_limit: u8) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V6(..)) => {
+            socket.set_unicast_hops_v6(hop_limit as u32)?;
+            let _ = socket.set_ttl(hop_limit as u32);
+            Ok(())
+        }
+        _ => socket.set_ttl(hop_limit as u32),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::{super::destination::recv_from_with_meta, *};
+
+    #[test]
+    fn parse_hop_limit_mode() {
+        for mode in [
+            UdpHopLimitMode::Default,
+            UdpHopLimitMode::Copy,
+            UdpHopLimitMode::Decrement,
+        ] {
+            assert_eq!(mode.to_string().parse::<UdpHopLimitMode>().unwrap(), mode);
+        }
+        assert!("ttl".parse::<UdpHopLimitMode>().is_err());
+    }
+
+    #[test]
+    fn decremented_hop_limit_expires() {
+        assert_eq!(UdpHopLimitMode::Copy.outbound(1), Some(1));
+        assert_eq!(UdpHopLimitMode::Decrement.outbound(64), Some(63));
+        assert_eq!(UdpHopLimitMode::Decrement.outbound(1), None);
+        assert_eq!(UdpHopLimitMode::Decrement.outbound(0), None);
+    }
+
+    #[tokio::test]
+    async fn hop_limit_received_as_sent() {
+        for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+            let client = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+
+            // Not received unless it is enabled
+            let mut buf = [0u8; 16];
+            client.send_to(b"default", addr).await.unwrap();
+            let (n, peer_addr, meta) = recv_from_with_meta(&socket, &mut buf).await.unwrap();
+            assert_eq!(
+                (&buf[..n], peer_addr, meta.hop_limit),
+                (&b"default"[..], client.local_addr().unwrap(), None)
+            );
+
+            set_recv_hop_limit(&socket).unwrap();
+            for sent in [7, 64, 255] {
+                set_hop_limit(&client, sent).unwrap();
+                client.send_to(b"limited", addr).await.unwrap();
+                let (n, _, meta) = recv_from_with_meta(&socket, &mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"limited");
+                assert_eq!(meta.hop_limit, Some(sent));
+            }
+        }
+    }
+}
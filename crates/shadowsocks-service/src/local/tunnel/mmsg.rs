@@ -0,0 +1,222 @@
+//! Batched UDP syscalls, `recvmmsg` and `sendmmsg` move many datagrams with one syscall
+
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+    ptr,
+};
+
+use bytes::Bytes;
+use shadowsocks::relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE;
+use socket2::SockAddr;
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Default number of datagrams moved by one `recvmmsg` or `sendmmsg`
+pub const DEFAULT_UDP_MMSG_BATCH_SIZE: usize = 32;
+
+/// Maximum number of datagrams moved by one `recvmmsg` or `sendmmsg`
+pub const MAX_UDP_MMSG_BATCH_SIZE: usize = 64;
+
+/// Buffers of datagrams received together by `recvmmsg`, each of them could hold a datagram of the maximum size
+///
+/// The buffer is allocated zeroed, pages not touched by small datagrams may not be backed by memory at all.
+pub struct UdpRecvBatch {
+    buffer: Vec<u8>,
+    // Length and source address of datagrams received by the last `recv_from`
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl UdpRecvBatch {
+    /// Create buffers of receiving `batch_size` datagrams at most, up to `MAX_UDP_MMSG_BATCH_SIZE`
+    pub fn new(batch_size: usize) -> UdpRecvBatch {
+        let batch_size = batch_size.clamp(1, MAX_UDP_MMSG_BATCH_SIZE);
+        UdpRecvBatch {
+            buffer: vec![0u8; batch_size * MAXIMUM_UDP_PAYLOAD_SIZE],
+            received: Vec::with_capacity(batch_size),
+        }
+    }
+
+    /// Receive datagrams already queued in `socket`, waits for at least one of them
+    pub async fn recv_from(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        loop {
+            socket.readable().await?;
+
+            match socket.try_io(Interest::READABLE, || {
+                recv_mmsg(socket, &mut self.buffer, &mut self.received)
+            }) {
+                // Readiness was cleared by `try_io`, wait for the next one
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+                x => return x,
+            }
+        }
+    }
+
+    /// Datagrams received by the last `recv_from` in order
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+        self.received.iter().enumerate().map(|(i, &(n, addr))| {
+            let start = i * MAXIMUM_UDP_PAYLOAD_SIZE;
+            (&self.buffer[start..start + n], addr)
+        })
+    }
+}
+
+/// Send `datagrams` to `target` in order, returns number of datagrams sent
+///
+/// It may send less than `datagrams`, up to `MAX_UDP_MMSG_BATCH_SIZE` of them at once. An error is returned only if
+/// the first one couldn't be sent.
+pub async fn send_to_batch(socket: &UdpSocket, datagrams: &[Bytes], target: SocketAddr) -> io::Result<usize> {
+    let target = SockAddr::from(target);
+    loop {
+        socket.writable().await?;
+
+        match socket.try_io(Interest::WRITABLE, || send_mmsg(socket, datagrams, &target)) {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            x => return x,
+        }
+    }
+}
+
+fn recv_mmsg(socket: &UdpSocket, buffer: &mut [u8], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<usize> {
+    let batch_size = buffer.len() / MAXIMUM_UDP_PAYLOAD_SIZE;
+    received.clear();
+
+    unsafe {
+        let mut addrs: [libc::sockaddr_storage; MAX_UDP_MMSG_BATCH_SIZE] = mem::zeroed();
+        let mut iovs: [libc::iovec; MAX_UDP_MMSG_BATCH_SIZE] = mem::zeroed();
+        let mut msgs: [libc::mmsghdr; MAX_UDP_MMSG_BATCH_SIZE] = mem::zeroed();
+
+        for (i, chunk) in buffer.chunks_exact_mut(MAXIMUM_UDP_PAYLOAD_SIZE).enumerate() {
+            iovs[i] = libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut _,
+                iov_len: chunk.len() as libc::size_t,
+            };
+
+            let msg = &mut msgs[i].msg_hdr;
+            msg.msg_name = &mut addrs[i] as *mut _ as *mut _;
+            msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_iov = &mut iovs[i];
+            msg.msg_iovlen = 1;
+        }
+
+        let ret = libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            batch_size as libc::c_uint,
+            0,
+            ptr::null_mut(),
+        );
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        for (msg, addr) in msgs.iter().zip(addrs).take(ret as usize) {
+            let addr = SockAddr::new(addr, msg.msg_hdr.msg_namelen);
+            received.push((msg.msg_len as usize, addr.as_socket().expect("SocketAddr")));
+        }
+    }
+
+    Ok(received.len())
+}
+
+fn send_mmsg(socket: &UdpSocket, datagrams: &[Bytes], target: &SockAddr) -> io::Result<usize> {
+    let datagrams = &datagrams[..datagrams.len().min(MAX_UDP_MMSG_BATCH_SIZE)];
+
+    unsafe {
+        let mut iovs: [libc::iovec; MAX_UDP_MMSG_BATCH_SIZE] = mem::zeroed();
+        let mut msgs: [libc::mmsghdr; MAX_UDP_MMSG_BATCH_SIZE] = mem::zeroed();
+
+        for (i, data) in datagrams.iter().enumerate() {
+            iovs[i] = libc::iovec {
+                iov_base: data.as_ptr() as *mut _,
+                iov_len: data.len() as libc::size_t,
+            };
+
+            let msg = &mut msgs[i].msg_hdr;
+            msg.msg_name = target.as_ptr() as *mut _;
+            msg.msg_namelen = target.len();
+            msg.msg_iov = &mut iovs[i];
+            msg.msg_iovlen = 1;
+        }
+
+        let ret = libc::sendmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            datagrams.len() as libc::c_uint,
+            0,
+        );
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn recv_batch_keeps_order() {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        // 65507 is the largest payload of UDP over IPv4
+        let sizes = [1, 1200, 65507, 0, 512];
+        for (i, size) in sizes.into_iter().enumerate() {
+            client.send_to(&vec![i as u8; size], addr).await.unwrap();
+        }
+
+        // All of them are queued, received by one syscall
+        let mut batch = UdpRecvBatch::new(8);
+        assert_eq!(batch.recv_from(&socket).await.unwrap(), sizes.len());
+        for (i, ((data, peer_addr), size)) in batch.datagrams().zip(sizes).enumerate() {
+            assert_eq!(peer_addr, client.local_addr().unwrap());
+            assert_eq!(data.len(), size);
+            assert!(data.iter().all(|b| *b == i as u8));
+        }
+
+        // Limited by the batch size
+        let mut batch = UdpRecvBatch::new(2);
+        for _ in 0..3 {
+            client.send_to(b"queued", addr).await.unwrap();
+        }
+        assert_eq!(batch.recv_from(&socket).await.unwrap(), 2);
+        assert_eq!(batch.recv_from(&socket).await.unwrap(), 1);
+        assert_eq!(batch.datagrams().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn send_batch_keeps_order() {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+
+        let datagrams = (0..MAX_UDP_MMSG_BATCH_SIZE + 3)
+            .map(|i| Bytes::from(vec![i as u8; i + 1]))
+            .collect::<Vec<_>>();
+        let sent = send_to_batch(&socket, &datagrams, client.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(sent, MAX_UDP_MMSG_BATCH_SIZE);
+
+        let mut buf = [0u8; 1024];
+        for data in &datagrams[..sent] {
+            let (n, peer_addr) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(peer_addr, socket.local_addr().unwrap());
+            assert_eq!(&buf[..n], &data[..]);
+        }
+    }
+}
@@ -1,7 +1,38 @@
 //! Shadowsocks Local Tunnel Server
 
-pub use self::server::Tunnel;
+#[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+pub use self::mmsg::{DEFAULT_UDP_MMSG_BATCH_SIZE, MAX_UDP_MMSG_BATCH_SIZE};
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+pub use self::hop_limit::{UdpHopLimitMode, UdpHopLimitModeError};
+pub use self::{
+    coalesce::{UdpCoalesceRules, UdpResponseCoalesce},
+    proxied::{DefaultProxySocketFactory, ProxiedSocket, ProxySocketFactory},
+    server::Tunnel,
+    udprelay::{
+        AssocEntry,
+        PeerTraffic,
+        UdpAssocTrack,
+        UdpCapacityMode,
+        UdpCapacityModeError,
+        UdpEvictionHook,
+        UdpForwardRules,
+        UdpNatMode,
+        UdpNatModeError,
+        UdpTtlRules,
+        UdpTunnel,
+        UdpTunnelBuilder,
+        UdpUpstreamState,
+    },
+};
 
+mod coalesce;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod destination;
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+mod hop_limit;
+#[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+mod mmsg;
+mod proxied;
 pub mod server;
 mod tcprelay;
 mod udprelay;
@@ -0,0 +1,107 @@
+//! Sockets relaying UDP associations' packets through servers
+//!
+//! Associations connect their sockets with a `ProxySocketFactory`. The default one connects `MonProxySocket`s to real
+//! servers, others could relay packets in memory, for example to exercise reconnecting deterministically in tests.
+
+use std::io;
+
+use async_trait::async_trait;
+#[cfg(feature = "local-udp-quic")]
+use shadowsocks::relay::udprelay::QuicProxySocket;
+use shadowsocks::{
+    relay::{socks5::Address, udprelay::ProxySocket},
+    ServerConfig,
+};
+
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+use super::hop_limit::set_hop_limit;
+use crate::{
+    local::context::ServiceContext,
+    net::{LastActive, MonProxySocket},
+};
+
+/// Socket of an association, connected to one server
+#[async_trait]
+pub trait ProxiedSocket: Send + Sync {
+    /// Send `payload` to `addr` through the server
+    async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<()>;
+
+    /// Receive a response into `buf`, returns its length and the address it came from
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, Address)>;
+
+    /// Record time of the last packet sent or received in `last_active`, ignored by default
+    fn set_last_active(&mut self, _last_active: LastActive) {}
+
+    /// Payloads are framed by `UdpPaddingPolicy`, ignored by default
+    fn set_padded(&mut self, _padded: bool) {}
+
+    /// Send packets with `hop_limit`, ignored by default
+    fn set_hop_limit(&self, _hop_limit: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Largest payload to `addr` that is sent in one packet, `None` if it is unknown, by default
+    fn max_payload_size(&self, _addr: &Address) -> Option<usize> {
+        None
+    }
+}
+
+/// Connects sockets of associations to servers
+#[async_trait]
+pub trait ProxySocketFactory: Send + Sync {
+    /// Connect a socket to the server `svr_cfg`
+    async fn connect(&self, context: &ServiceContext, svr_cfg: &ServerConfig) -> io::Result<Box<dyn ProxiedSocket>>;
+}
+
+/// Connects `MonProxySocket`s with the server's UDP transport, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultProxySocketFactory;
+
+#[async_trait]
+impl ProxySocketFactory for DefaultProxySocketFactory {
+    async fn connect(&self, context: &ServiceContext, svr_cfg: &ServerConfig) -> io::Result<Box<dyn ProxiedSocket>> {
+        #[cfg(feature = "local-udp-quic")]
+        if svr_cfg.quic_transport().is_some() {
+            let socket =
+                QuicProxySocket::connect_with_opts(context.context(), svr_cfg, context.connect_opts_ref()).await?;
+            return Ok(Box::new(MonProxySocket::from_quic_socket(socket, context.flow_stat())));
+        }
+
+        let socket = ProxySocket::connect_with_opts(context.context(), svr_cfg, context.connect_opts_ref()).await?;
+        Ok(Box::new(MonProxySocket::from_socket(socket, context.flow_stat())))
+    }
+}
+
+#[async_trait]
+impl ProxiedSocket for MonProxySocket {
+    async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<()> {
+        MonProxySocket::send(self, addr, payload).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, Address)> {
+        MonProxySocket::recv(self, buf).await
+    }
+
+    fn set_last_active(&mut self, last_active: LastActive) {
+        MonProxySocket::set_last_active(self, last_active)
+    }
+
+    fn set_padded(&mut self, padded: bool) {
+        MonProxySocket::set_padded(self, padded)
+    }
+
+    fn max_payload_size(&self, addr: &Address) -> Option<usize> {
+        MonProxySocket::max_payload_size(self, addr)
+    }
+
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    fn set_hop_limit(&self, hop_limit: u8) -> io::Result<()> {
+        match self.get_ref() {
+            Some(socket) => set_hop_limit(socket.get_ref(), hop_limit),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "hop limit of packets sent through QUIC couldn't be set",
+            )),
+        }
+    }
+}
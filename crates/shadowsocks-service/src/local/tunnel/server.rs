@@ -1,21 +1,61 @@
 //! Shadowsocks Local Tunnel Server
 
-use std::{io, sync::Arc, time::Duration};
+use std::{io, slice, sync::Arc, time::Duration};
 
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, relay::socks5::Address, ServerAddr};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    net::{UdpChannelFullPolicy, UdpPaddingPolicy},
+};
 
-use super::{tcprelay::run_tcp_tunnel, udprelay::UdpTunnel};
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+use super::hop_limit::UdpHopLimitMode;
+use super::{
+    coalesce::UdpCoalesceRules,
+    tcprelay::run_tcp_tunnel,
+    udprelay::{
+        UdpAssocTrack,
+        UdpCapacityMode,
+        UdpEvictionHook,
+        UdpForwardRules,
+        UdpNatMode,
+        UdpTtlRules,
+        UdpTunnelBuilder,
+    },
+};
 
 /// Tunnel Server
 pub struct Tunnel {
     context: Arc<ServiceContext>,
     forward_addr: Address,
     mode: Mode,
+    tcp_first_byte_timeout: Option<Duration>,
     udp_expiry_duration: Option<Duration>,
+    udp_cleanup_interval: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_capacity_mode: UdpCapacityMode,
+    udp_nat_mode: UdpNatMode,
+    udp_eviction_hook: Option<Arc<dyn UdpEvictionHook>>,
+    udp_forward_addrs: Vec<Address>,
+    udp_bind_addrs: Vec<ServerAddr>,
+    udp_forward_rules: UdpForwardRules,
+    udp_ttl_rules: UdpTtlRules,
+    udp_coalesce_rules: UdpCoalesceRules,
+    udp_conntrack: UdpAssocTrack,
+    udp_connect_timeout: Option<Duration>,
+    udp_padding: Option<UdpPaddingPolicy>,
+    udp_channel_full_policy: UdpChannelFullPolicy,
+    udp_send_channel_size: Option<usize>,
+    udp_max_payload_size: Option<usize>,
+    udp_outbound_pool_size: usize,
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    udp_mmsg_batch_size: Option<usize>,
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    udp_hop_limit_mode: UdpHopLimitMode,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    udp_transparent: bool,
 }
 
 impl Tunnel {
@@ -27,25 +67,156 @@ impl Tunnel {
 
     /// Create a new Tunnel server with context
     pub fn with_context(context: Arc<ServiceContext>, forward_addr: Address) -> Tunnel {
+        let udp_padding = context.udp_padding();
         Tunnel {
             context,
             forward_addr,
             mode: Mode::TcpOnly,
+            tcp_first_byte_timeout: None,
             udp_expiry_duration: None,
+            udp_cleanup_interval: None,
             udp_capacity: None,
+            udp_capacity_mode: UdpCapacityMode::Evict,
+            udp_nat_mode: UdpNatMode::Peer,
+            udp_eviction_hook: None,
+            udp_forward_addrs: Vec::new(),
+            udp_bind_addrs: Vec::new(),
+            udp_forward_rules: UdpForwardRules::new(),
+            udp_ttl_rules: UdpTtlRules::new(),
+            udp_coalesce_rules: UdpCoalesceRules::new(),
+            udp_conntrack: UdpAssocTrack::new(),
+            udp_connect_timeout: None,
+            udp_padding,
+            udp_channel_full_policy: UdpChannelFullPolicy::Drop,
+            udp_send_channel_size: None,
+            udp_max_payload_size: None,
+            udp_outbound_pool_size: 1,
+            #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+            udp_mmsg_batch_size: None,
+            #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+            udp_hop_limit_mode: UdpHopLimitMode::Default,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            udp_transparent: false,
         }
     }
 
+    /// Set timeout of the server's first byte after the client's first payload, stalled connections are reset
+    pub fn set_tcp_first_byte_timeout(&mut self, d: Duration) {
+        self.tcp_first_byte_timeout = Some(d);
+    }
+
     /// Set UDP association's expiry duration
     pub fn set_udp_expiry_duration(&mut self, d: Duration) {
         self.udp_expiry_duration = Some(d);
     }
 
+    /// Set interval of sweeping expired UDP associations, see `UdpTunnel::set_cleanup_interval`
+    pub fn set_udp_cleanup_interval(&mut self, d: Duration) {
+        self.udp_cleanup_interval = Some(d);
+    }
+
     /// Set total UDP association to be kept simultaneously in server
     pub fn set_udp_capacity(&mut self, c: usize) {
         self.udp_capacity = Some(c);
     }
 
+    /// Set behavior when new UDP clients come while the capacity is reached, the most idle association is evicted by default
+    pub fn set_udp_capacity_mode(&mut self, mode: UdpCapacityMode) {
+        self.udp_capacity_mode = mode;
+    }
+
+    /// Report UDP associations evicted because of the capacity limit to `hook`
+    pub fn set_udp_eviction_hook(&mut self, hook: Arc<dyn UdpEvictionHook>) {
+        self.udp_eviction_hook = Some(hook);
+    }
+
+    /// Set how UDP associations are kept for clients, see `UdpTunnel::set_nat_mode`
+    pub fn set_udp_nat_mode(&mut self, mode: UdpNatMode) {
+        self.udp_nat_mode = mode;
+    }
+
+    /// Distribute UDP associations across `addrs` instead of forwarding all of them to the forward address, see
+    /// `UdpTunnel::run_forward_addrs`
+    pub fn set_udp_forward_addrs(&mut self, addrs: Vec<Address>) {
+        self.udp_forward_addrs = addrs;
+    }
+
+    /// Listen for UDP clients on `addrs` in addition to the UDP address, sharing associations, see
+    /// `UdpTunnel::run_multi`
+    pub fn set_udp_bind_addrs(&mut self, addrs: Vec<ServerAddr>) {
+        self.udp_bind_addrs = addrs;
+    }
+
+    /// Set UDP routing rules for choosing forward address by packets' destination port, see `UdpForwardRules`
+    pub fn set_udp_forward_rules(&mut self, rules: UdpForwardRules) {
+        self.udp_forward_rules = rules;
+    }
+
+    /// Set UDP associations' expiry durations by their forward addresses, overriding `set_udp_expiry_duration`
+    pub fn set_udp_ttl_rules(&mut self, rules: UdpTtlRules) {
+        self.udp_ttl_rules = rules;
+    }
+
+    /// Set destinations whose small UDP responses are coalesced, see `UdpTunnel::set_coalesce_rules`
+    pub fn set_udp_coalesce_rules(&mut self, rules: UdpCoalesceRules) {
+        self.udp_coalesce_rules = rules;
+    }
+
+    /// Set timeout of connecting to servers for UDP associations, packets are dropped while connecting timed out
+    pub fn set_udp_connect_timeout(&mut self, d: Duration) {
+        self.udp_connect_timeout = Some(d);
+    }
+
+    /// Set padding of UDP packets sent to servers, servers must enable padding too
+    ///
+    /// The context's `ServiceContext::udp_padding` by default.
+    pub fn set_udp_padding(&mut self, padding: UdpPaddingPolicy) {
+        self.udp_padding = Some(padding);
+    }
+
+    /// Set behavior when a UDP association's send channel is full, packets are dropped by default
+    pub fn set_udp_channel_full_policy(&mut self, policy: UdpChannelFullPolicy) {
+        self.udp_channel_full_policy = policy;
+    }
+
+    /// Set number of packets queued in each UDP association's send channel, see `UdpTunnel::set_send_channel_size`
+    pub fn set_udp_send_channel_size(&mut self, size: usize) {
+        self.udp_send_channel_size = Some(size);
+    }
+
+    /// Set size of the largest UDP packet accepted from clients, see `UdpTunnel::set_max_payload_size`
+    pub fn set_udp_max_payload_size(&mut self, size: usize) {
+        self.udp_max_payload_size = Some(size);
+    }
+
+    /// Set number of outbound sockets that each UDP association is striped across, see `UdpTunnel::set_outbound_pool_size`
+    pub fn set_udp_outbound_pool_size(&mut self, size: usize) {
+        self.udp_outbound_pool_size = size;
+    }
+
+    /// Set number of datagrams received or sent together by `recvmmsg` / `sendmmsg`, see `UdpTunnel::set_mmsg_batch_size`
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    pub fn set_udp_mmsg_batch_size(&mut self, size: usize) {
+        self.udp_mmsg_batch_size = Some(size);
+    }
+
+    /// Set hop limit of UDP packets relayed to servers, see `UdpTunnel::set_hop_limit_mode`
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    pub fn set_udp_hop_limit_mode(&mut self, mode: UdpHopLimitMode) {
+        self.udp_hop_limit_mode = mode;
+    }
+
+    /// Receive UDP packets redirected by TPROXY, see `UdpTunnel::set_transparent`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_udp_transparent(&mut self, transparent: bool) {
+        self.udp_transparent = transparent;
+    }
+
+    /// Tracking of active UDP associations, could be read while the server is running
+    pub fn udp_conntrack(&self) -> UdpAssocTrack {
+        self.udp_conntrack.clone()
+    }
+
     /// Set server mode
     pub fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
@@ -68,11 +239,51 @@ impl Tunnel {
     }
 
     async fn run_tcp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        run_tcp_tunnel(self.context.clone(), client_config, balancer, &self.forward_addr).await
+        run_tcp_tunnel(
+            self.context.clone(),
+            client_config,
+            balancer,
+            &self.forward_addr,
+            self.tcp_first_byte_timeout,
+        )
+        .await
     }
 
     async fn run_udp_tunnel(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let mut server = UdpTunnel::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
-        server.run(client_config, balancer, &self.forward_addr).await
+        let builder = UdpTunnelBuilder::new()
+            .time_to_live(self.udp_expiry_duration)
+            .cleanup_interval(self.udp_cleanup_interval)
+            .capacity(self.udp_capacity)
+            .capacity_mode(self.udp_capacity_mode)
+            .nat_mode(self.udp_nat_mode)
+            .eviction_hook(self.udp_eviction_hook.clone())
+            .forward_rules(self.udp_forward_rules.clone())
+            .ttl_rules(self.udp_ttl_rules.clone())
+            .coalesce_rules(self.udp_coalesce_rules.clone())
+            .conntrack(self.udp_conntrack.clone())
+            .connect_timeout(self.udp_connect_timeout)
+            .padding(self.udp_padding)
+            .channel_full_policy(self.udp_channel_full_policy)
+            .send_channel_size(self.udp_send_channel_size)
+            .max_payload_size(self.udp_max_payload_size)
+            .outbound_pool_size(self.udp_outbound_pool_size);
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        let builder = builder.mmsg_batch_size(self.udp_mmsg_batch_size);
+        #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+        let builder = builder.hop_limit_mode(self.udp_hop_limit_mode);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let builder = builder.transparent(self.udp_transparent);
+        let mut server = builder.build(self.context.clone());
+
+        let mut client_configs = Vec::with_capacity(1 + self.udp_bind_addrs.len());
+        client_configs.push(client_config.clone());
+        client_configs.extend(self.udp_bind_addrs.iter().cloned());
+
+        let forward_addrs = if self.udp_forward_addrs.is_empty() {
+            slice::from_ref(&self.forward_addr)
+        } else {
+            &self.udp_forward_addrs
+        };
+        server.run_multi(&client_configs, balancer, forward_addrs).await
     }
 }
@@ -8,9 +8,9 @@ use tokio::{net::TcpStream, time};
 
 use crate::local::{
     context::ServiceContext,
-    loadbalancing::PingBalancer,
+    loadbalancing::{AvailableServerSelector, PingBalancer, ServerSelectContext, ServerType},
     net::AutoProxyClientStream,
-    utils::establish_tcp_tunnel,
+    utils::{establish_tcp_tunnel, is_first_byte_timeout},
 };
 
 pub async fn run_tcp_tunnel(
@@ -18,6 +18,7 @@ pub async fn run_tcp_tunnel(
     client_config: &ServerAddr,
     balancer: PingBalancer,
     forward_addr: &Address,
+    first_byte_timeout: Option<Duration>,
 ) -> io::Result<()> {
     let listener = match *client_config {
         ServerAddr::SocketAddr(ref saddr) => ShadowTcpListener::bind_with_opts(saddr, context.accept_opts()).await?,
@@ -41,16 +42,26 @@ pub async fn run_tcp_tunnel(
             }
         };
 
+        let context = context.clone();
         let balancer = balancer.clone();
         let forward_addr = forward_addr.clone();
 
-        tokio::spawn(handle_tcp_client(
-            context.clone(),
-            stream,
-            balancer,
-            peer_addr,
-            forward_addr,
-        ));
+        tokio::spawn(async move {
+            if let Err(err) = handle_tcp_client(
+                context.clone(),
+                stream,
+                balancer,
+                peer_addr,
+                forward_addr.clone(),
+                first_byte_timeout,
+            )
+            .await
+            {
+                if context.conn_error_log_throttle().should_log(&forward_addr) {
+                    error!("tcp tunnel {} <-> {} failed, error: {}", peer_addr, forward_addr, err);
+                }
+            }
+        });
     }
 }
 
@@ -60,18 +71,56 @@ async fn handle_tcp_client(
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     forward_addr: Address,
+    first_byte_timeout: Option<Duration>,
 ) -> io::Result<()> {
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
+    let cx = ServerSelectContext::new(ServerType::Tcp, peer_addr, &forward_addr);
+    let server = match balancer.select_server(&cx, &AvailableServerSelector)? {
+        Some(server) => server,
+        None => {
+            // All servers are unavailable, connect to target directly if it's bypassed by ACL
+            balancer
+                .check_unavailable_bypass(&context, ServerType::Tcp, &forward_addr)
+                .await?;
+            let server = balancer.best_tcp_server();
+            let mut remote = AutoProxyClientStream::connect_bypassed(context, &forward_addr).await?;
+            return establish_tcp_tunnel(&server, &mut stream, &mut remote, peer_addr, &forward_addr, None)
+                .await
+                .map(|_| ());
+        }
+    };
     trace!(
         "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
         peer_addr,
         forward_addr,
-        svr_cfg.external_addr(),
-        svr_cfg.addr(),
+        server.server_config().external_addr(),
+        server.server_config().addr(),
     );
 
-    let mut remote = AutoProxyClientStream::connect_proxied(context, &server, &forward_addr).await?;
+    let (server, mut remote) = balancer
+        .connect_with_retry(server, |server| {
+            let context = context.clone();
+            let forward_addr = &forward_addr;
+            async move { AutoProxyClientStream::connect_proxied(context, &server, forward_addr).await }
+        })
+        .await?;
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &forward_addr).await
+    match establish_tcp_tunnel(
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        &forward_addr,
+        first_byte_timeout,
+    )
+    .await
+    {
+        Ok(..) => Ok(()),
+        Err(err) => {
+            if is_first_byte_timeout(&err) {
+                // Reset the client, so it won't wait for the response of a stalled server
+                let _ = stream.set_linger(Some(Duration::ZERO));
+            }
+            Err(err)
+        }
+    }
 }
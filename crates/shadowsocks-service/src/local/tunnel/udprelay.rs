@@ -1,382 +1,4999 @@
 //! UDP Tunnel server
 
 use std::{
+    collections::HashMap,
+    fmt::{self, Display},
     io::{self, ErrorKind},
-    net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    mem,
+    net::{IpAddr, SocketAddr},
+    slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use bytes::Bytes;
-use futures::future;
+use bytes::{Bytes, BytesMut};
+use futures::{future, FutureExt};
 use log::{debug, error, info, trace, warn};
 use lru_time_cache::LruCache;
 use shadowsocks::{
+    dns_resolver::DnsResolver,
     lookup_then,
     net::UdpSocket as ShadowUdpSocket,
-    relay::{
-        socks5::Address,
-        udprelay::{ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
-    },
+    relay::{socks5::Address, udprelay::MAXIMUM_UDP_PAYLOAD_SIZE},
     ServerAddr,
 };
+use spin::Mutex as SpinMutex;
 use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::destination::{bind_transparent, recv_from_with_meta, send_to_from, set_recv_destination, set_transparent};
+#[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+use super::hop_limit::{set_recv_hop_limit, UdpHopLimitMode};
+#[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+use super::mmsg::{send_to_batch, UdpRecvBatch, DEFAULT_UDP_MMSG_BATCH_SIZE, MAX_UDP_MMSG_BATCH_SIZE};
+use super::{
+    coalesce::{UdpCoalesce, UdpCoalesceRules, MAX_COALESCE_BATCH},
+    proxied::{DefaultProxySocketFactory, ProxiedSocket, ProxySocketFactory},
+};
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
-    net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE},
+    acl::AddressRules,
+    local::{
+        context::ServiceContext,
+        loadbalancing::{
+            AvailableServerSelector,
+            PingBalancer,
+            ServerSelectContext,
+            ServerType,
+            ServiceUnavailableError,
+        },
+        utils::{resolve_server_config_with, resolve_with},
+    },
+    net::{
+        activity::evict_least_active,
+        clock::{Clock, SystemClock},
+        send_to_channel,
+        strip_udp_padding,
+        udp_channel,
+        KeepAliveThrottle,
+        LastActive,
+        UdpChannelFullPolicy,
+        UdpChannelReceiver,
+        UdpChannelSender,
+        UdpPaddingPolicy,
+        UdpRelaySendError,
+        UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+        UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+    },
 };
 
-type AssociationMap = LruCache<SocketAddr, UdpAssociation>;
+type AssociationMap = LruCache<AssocKey, UdpAssociation>;
 
-pub struct UdpTunnel {
-    context: Arc<ServiceContext>,
-    assoc_map: AssociationMap,
-    keepalive_tx: mpsc::Sender<SocketAddr>,
-    keepalive_rx: mpsc::Receiver<SocketAddr>,
-    time_to_live: Duration,
-}
+/// Packet queued in an association's channel, with the hop limit that it is relayed with if it is set
+type QueuedPacket = (Bytes, Option<u8>);
 
-impl UdpTunnel {
-    pub fn new(context: Arc<ServiceContext>, time_to_live: Option<Duration>, capacity: Option<usize>) -> UdpTunnel {
-        let time_to_live = time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
-        let assoc_map = match capacity {
-            Some(capacity) => LruCache::with_expiry_duration_and_capacity(time_to_live, capacity),
-            None => LruCache::with_expiry_duration(time_to_live),
-        };
+/// Server that an association is pinned to, all of its stripes are connected to it
+///
+/// Protocols like QUIC keep states per path, so associations reconnect to the same server after transient errors.
+/// The server is only chosen again if it was removed from the balancer, or connecting to it failed.
+type PinnedServer = Arc<SpinMutex<Option<ServerAddr>>>;
 
-        let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+/// Default timeout of connecting to a server for an association
+pub const DEFAULT_UDP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-        UdpTunnel {
-            context,
-            assoc_map,
-            keepalive_tx,
-            keepalive_rx,
-            time_to_live,
+/// Default interval of sweeping expired associations, shorter if associations expire sooner
+pub const DEFAULT_UDP_CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval of binding client sockets that failed fatally again, until they are bound
+const INBOUND_REBIND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeout of connecting to servers, shared by all associations with the counter of packets dropped by it
+#[derive(Debug, Clone)]
+struct ConnectTimeout {
+    timeout: Duration,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ConnectTimeout {
+    fn new(timeout: Duration) -> ConnectTimeout {
+        ConnectTimeout {
+            timeout,
+            dropped: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub async fn run(
-        &mut self,
-        client_config: &ServerAddr,
-        balancer: PingBalancer,
-        forward_addr: &Address,
-    ) -> io::Result<()> {
-        let socket = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => {
-                ShadowUdpSocket::listen_with_opts(saddr, self.context.accept_opts()).await?
-            }
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
-                    ShadowUdpSocket::listen_with_opts(&addr, self.context.accept_opts()).await
-                })?
-                .1
-            }
+    /// Count a dropped packet, returns the total number
+    fn drop_packet(&self) -> u64 {
+        self.dropped.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Size of the arena that packets from clients are received into, it holds at least a packet of the maximum size
+const UDP_RECV_ARENA_SIZE: usize = (MAXIMUM_UDP_PAYLOAD_SIZE + 1) * 4;
+
+/// Reusable buffer that packets from clients are received into, they are split off as `Bytes` without copying
+///
+/// The arena is refilled once the rest of it couldn't hold a packet of the maximum size. Its allocation is reused if
+/// all packets received into it were dropped, otherwise a new one is allocated and the old one is freed with its last
+/// packet. So a packet queued in an association's channel keeps the whole arena, associations copy packets queued
+/// behind others out of it.
+struct UdpRecvArena {
+    buffer: BytesMut,
+    // Start of the current allocation, for telling whether it was reused
+    base: usize,
+    allocations: u64,
+}
+
+impl UdpRecvArena {
+    fn new() -> UdpRecvArena {
+        let mut arena = UdpRecvArena {
+            buffer: BytesMut::new(),
+            base: 0,
+            allocations: 0,
         };
-        let socket: UdpSocket = socket.into();
+        arena.refill();
+        arena
+    }
 
-        info!("shadowsocks UDP tunnel listening on {}", socket.local_addr()?);
+    fn refill(&mut self) {
+        self.buffer.clear();
+        self.buffer.reserve(UDP_RECV_ARENA_SIZE);
+        let base = self.buffer.as_ptr() as usize;
+        if base != self.base {
+            self.base = base;
+            self.allocations += 1;
+        }
+        // Zeroed once for all packets received into it
+        self.buffer.resize(UDP_RECV_ARENA_SIZE, 0);
+    }
 
-        let listener = Arc::new(socket);
+    /// Receive a packet of `max_payload_size` at most, larger ones are truncated to `max_payload_size + 1` bytes
+    ///
+    /// The extra byte tells an oversized packet from one of exactly `max_payload_size`, it must be checked by callers.
+    async fn recv_from(&mut self, socket: &UdpSocket, max_payload_size: usize) -> io::Result<(Bytes, SocketAddr)> {
+        let recv_size = max_payload_size.min(MAXIMUM_UDP_PAYLOAD_SIZE) + 1;
+        if self.buffer.len() < recv_size {
+            self.refill();
+        }
+        let (n, peer_addr) = socket.recv_from(&mut self.buffer[..recv_size]).await?;
+        Ok((self.buffer.split_to(n).freeze(), peer_addr))
+    }
 
-        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
-        let mut cleanup_timer = time::interval(self.time_to_live);
+    /// `recv_from` with the packet's destination and hop limit, if the socket receives them
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn recv_from_with_meta(&mut self, socket: &UdpSocket, max_payload_size: usize) -> io::Result<ReceivedPacket> {
+        let recv_size = max_payload_size.min(MAXIMUM_UDP_PAYLOAD_SIZE) + 1;
+        if self.buffer.len() < recv_size {
+            self.refill();
+        }
+        let (n, peer_addr, meta) = recv_from_with_meta(socket, &mut self.buffer[..recv_size]).await?;
+        Ok((
+            self.buffer.split_to(n).freeze(),
+            peer_addr,
+            meta.destination,
+            meta.hop_limit,
+        ))
+    }
 
-        loop {
-            tokio::select! {
-                _ = cleanup_timer.tick() => {
-                    // cleanup expired associations. iter() will remove expired elements
-                    let _ = self.assoc_map.iter();
-                }
+    /// Copy a packet received elsewhere into the arena, it is split off like packets received by `recv_from`
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    fn copy_packet(&mut self, data: &[u8]) -> Bytes {
+        if self.buffer.len() < data.len() {
+            self.refill();
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffer.split_to(data.len()).freeze()
+    }
 
-                peer_addr_opt = self.keepalive_rx.recv() => {
-                    let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
-                    self.assoc_map.get(&peer_addr);
-                }
+    /// Number of allocations, including the first one
+    #[allow(dead_code)]
+    fn allocations(&self) -> u64 {
+        self.allocations
+    }
+}
 
-                recv_result = listener.recv_from(&mut buffer) => {
-                    let (n, peer_addr) = match recv_result {
-                        Ok(s) => s,
-                        Err(err) => {
-                            error!("udp server recv_from failed with error: {}", err);
-                            time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    };
+/// Packet received from a client with its source, and its destination and hop limit if they are received
+type ReceivedPacket = (Bytes, SocketAddr, Option<SocketAddr>, Option<u8>);
 
-                    if n == 0 {
-                        // For windows, it will generate a ICMP Port Unreachable Message
-                        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
-                        // Which will result in recv_from return 0.
-                        //
-                        // It cannot be solved here, because `WSAGetLastError` is already set.
-                        //
-                        // See `relay::udprelay::utils::create_socket` for more detail.
-                        continue;
-                    }
+/// Receiver of packets from clients, they are split off from the arena
+struct UdpInboundReceiver {
+    arena: UdpRecvArena,
+    max_payload_size: usize,
+    // Packets already queued in the socket are received together, then copied into the arena
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    batch: Option<UdpRecvBatch>,
+    // Packets are received one by one with their ancillary data, instead of in batches
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    recv_meta: bool,
+}
 
-                    let data = &buffer[..n];
-                    if let Err(err) = self
-                        .send_packet(&listener, peer_addr, &balancer, forward_addr, data)
-                        .await
-                    {
-                        error!(
-                            "udp packet relay {} -> {} with {} bytes failed, error: {}",
-                            peer_addr,
-                            forward_addr,
-                            data.len(),
-                            err
-                        );
-                    }
-                }
-            }
+impl UdpInboundReceiver {
+    fn new(max_payload_size: usize) -> UdpInboundReceiver {
+        UdpInboundReceiver {
+            arena: UdpRecvArena::new(),
+            max_payload_size,
+            #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+            batch: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            recv_meta: false,
         }
     }
 
-    async fn send_packet(
-        &mut self,
-        listener: &Arc<UdpSocket>,
-        peer_addr: SocketAddr,
-        balancer: &PingBalancer,
-        forward_addr: &Address,
-        data: &[u8],
-    ) -> io::Result<()> {
-        if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send(Bytes::copy_from_slice(data));
-        }
+    /// Receive packets together by `recvmmsg` if `batch_size` is larger than 1
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch = if batch_size > 1 {
+            Some(UdpRecvBatch::new(batch_size))
+        } else {
+            None
+        };
+    }
 
-        let assoc = UdpAssociation::new(
-            self.context.clone(),
-            listener.clone(),
-            peer_addr,
-            forward_addr.clone(),
-            self.keepalive_tx.clone(),
-            balancer.clone(),
-        );
+    /// Receive packets with their destinations and hop limits, `socket` must have been set receiving those needed
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_recv_meta(&mut self, enabled: bool) {
+        self.recv_meta = enabled;
+    }
 
-        debug!("created udp association for {}", peer_addr);
+    /// Receive packets into `received` in order with their destinations and hop limits, waits for at least one of them
+    ///
+    /// Packets larger than `max_payload_size` are received with more than `max_payload_size` bytes, but may be
+    /// truncated. Destinations and hop limits are `None` unless they are received.
+    async fn recv_from(&mut self, socket: &UdpSocket, received: &mut Vec<ReceivedPacket>) -> io::Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.recv_meta {
+            received.push(self.arena.recv_from_with_meta(socket, self.max_payload_size).await?);
+            return Ok(());
+        }
 
-        assoc.try_send(Bytes::copy_from_slice(data))?;
-        self.assoc_map.insert(peer_addr, assoc);
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        if let Some(ref mut batch) = self.batch {
+            batch.recv_from(socket).await?;
+            let arena = &mut self.arena;
+            received.extend(
+                batch
+                    .datagrams()
+                    .map(|(data, peer_addr)| (arena.copy_packet(data), peer_addr, None, None)),
+            );
+            return Ok(());
+        }
 
+        let (data, peer_addr) = self.arena.recv_from(socket, self.max_payload_size).await?;
+        received.push((data, peer_addr, None, None));
         Ok(())
     }
 }
 
-struct UdpAssociation {
-    assoc_handle: JoinHandle<()>,
-    sender: mpsc::Sender<Bytes>,
+/// Client socket of the tunnel, receiving packets into its own buffer
+struct UdpInbound {
+    socket: Arc<UdpSocket>,
+    // Bound again from it if the socket fails fatally, domain names are resolved again
+    client_config: ServerAddr,
+    // Address that the socket listens on, destination of packets received without their own
+    local_addr: SocketAddr,
+    receiver: UdpInboundReceiver,
+    received: Vec<ReceivedPacket>,
 }
 
-impl Drop for UdpAssociation {
-    fn drop(&mut self) {
-        self.assoc_handle.abort();
+impl UdpInbound {
+    async fn recv(&mut self) -> io::Result<()> {
+        self.receiver.recv_from(&self.socket, &mut self.received).await
     }
 }
 
-impl UdpAssociation {
-    fn new(
-        context: Arc<ServiceContext>,
-        inbound: Arc<UdpSocket>,
-        peer_addr: SocketAddr,
-        forward_addr: Address,
-        keepalive_tx: mpsc::Sender<SocketAddr>,
-        balancer: PingBalancer,
-    ) -> UdpAssociation {
-        let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, inbound, peer_addr, forward_addr, keepalive_tx, balancer);
-        UdpAssociation { assoc_handle, sender }
+/// Receive packets by any of `inbounds`, returns the index of the one that received them
+///
+/// Waits forever if there is none, while all sockets are being bound again.
+async fn recv_any_inbound(inbounds: &mut [UdpInbound]) -> (usize, io::Result<()>) {
+    match inbounds {
+        [] => return future::pending().await,
+        [inbound] => return (0, inbound.recv().await),
+        _ => {}
     }
 
-    fn try_send(&self, data: Bytes) -> io::Result<()> {
-        if let Err(..) = self.sender.try_send(data) {
-            let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
-            return Err(err);
-        }
-        Ok(())
+    let recvs = inbounds
+        .iter_mut()
+        .enumerate()
+        .map(|(idx, inbound)| Box::pin(async move { (idx, inbound.recv().await) }));
+    let (received, ..) = future::select_all(recvs).await;
+    received
+}
+
+/// Check if receiving from a client socket failed with an error that it never recovers from
+///
+/// The socket was closed under the tunnel, or its network went away, every later receive fails the same.
+fn is_fatal_recv_error(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EBADF | libc::ENOTSOCK | libc::ENETDOWN | libc::ENODEV | libc::ENXIO)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = err;
+        false
     }
 }
 
-struct UdpAssociationContext {
-    context: Arc<ServiceContext>,
-    peer_addr: SocketAddr,
-    forward_addr: Address,
-    proxied_socket: Option<MonProxySocket>,
-    keepalive_tx: mpsc::Sender<SocketAddr>,
-    keepalive_flag: bool,
-    balancer: PingBalancer,
-    inbound: Arc<UdpSocket>,
+/// Check if sending a packet failed because it is too large, other packets could still be sent
+fn is_oversized_send_error(err: &io::Error) -> bool {
+    if err.kind() == ErrorKind::InvalidInput {
+        return true;
+    }
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EMSGSIZE) {
+        return true;
+    }
+    false
 }
 
-impl Drop for UdpAssociationContext {
-    fn drop(&mut self) {
-        debug!("udp association for {} is closed", self.peer_addr);
+/// Client socket that an association replies on, switched to the one that received the client's latest packet
+#[derive(Clone)]
+struct AssocInbound {
+    socket: Arc<SpinMutex<Arc<UdpSocket>>>,
+}
+
+impl AssocInbound {
+    fn get(&self) -> Arc<UdpSocket> {
+        self.socket.lock().clone()
+    }
+
+    /// Reply on `socket` instead, returns `true` if it was another socket
+    fn switch(&self, socket: &Arc<UdpSocket>) -> bool {
+        let mut current = self.socket.lock();
+        if Arc::ptr_eq(&current, socket) {
+            return false;
+        }
+        *current = socket.clone();
+        true
     }
 }
 
-impl UdpAssociationContext {
-    fn create(
-        context: Arc<ServiceContext>,
-        inbound: Arc<UdpSocket>,
-        peer_addr: SocketAddr,
-        forward_addr: Address,
-        keepalive_tx: mpsc::Sender<SocketAddr>,
-        balancer: PingBalancer,
-    ) -> (JoinHandle<()>, mpsc::Sender<Bytes>) {
-        // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
-        // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
-        // being OOM.
-        let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+impl From<Arc<UdpSocket>> for AssocInbound {
+    fn from(socket: Arc<UdpSocket>) -> AssocInbound {
+        AssocInbound {
+            socket: Arc::new(SpinMutex::new(socket)),
+        }
+    }
+}
 
-        let mut assoc = UdpAssociationContext {
-            context,
-            peer_addr,
-            forward_addr,
-            proxied_socket: None,
-            keepalive_tx,
-            keepalive_flag: false,
-            balancer,
-            inbound,
-        };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+/// Commands queued in each association's control channel, the association is busy if it is full
+const UDP_ASSOCIATION_COMMAND_CHANNEL_SIZE: usize = 4;
+
+/// Commands controlling running associations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UdpAssocCommand {
+    /// Drop the outbound socket, the next packet connects to a newly chosen server
+    RebindServer,
+    /// Stop relaying after packets already queued, the association's task exits
+    Close,
+}
 
-        (handle, sender)
+/// Behavior when new clients come while the tunnel has reached its capacity of associations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpCapacityMode {
+    /// Evict the most idle association to make room for the new client
+    Evict,
+    /// Reject the new client by dropping its packets, existing associations are kept
+    Reject,
+}
+
+impl Display for UdpCapacityMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpCapacityMode::Evict => f.write_str("evict"),
+            UdpCapacityMode::Reject => f.write_str("reject"),
+        }
     }
+}
 
-    async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<Bytes>) {
-        let mut proxied_buffer = Vec::new();
-        let mut keepalive_interval = time::interval(Duration::from_secs(1));
+/// Error while parsing `UdpCapacityMode` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpCapacityModeError;
 
-        loop {
-            tokio::select! {
-                packet_received_opt = receiver.recv() => {
-                    let data = match packet_received_opt {
-                        Some(d) => d,
-                        None => {
-                            trace!("udp association for {} -> ... channel closed", self.peer_addr);
-                            break;
-                        }
-                    };
+impl Display for UdpCapacityModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpCapacityMode, expecting \"evict\" or \"reject\"")
+    }
+}
 
-                    self.dispatch_received_packet(&data).await;
-                }
+impl FromStr for UdpCapacityMode {
+    type Err = UdpCapacityModeError;
 
-                received_opt = receive_from_proxied_opt(&self.proxied_socket, &mut proxied_buffer) => {
-                    let (n, addr) = match received_opt {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
-                            // Socket failure. Reset for recreation.
-                            self.proxied_socket = None;
-                            continue;
-                        }
-                    };
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "evict" => Ok(UdpCapacityMode::Evict),
+            "reject" => Ok(UdpCapacityMode::Reject),
+            _ => Err(UdpCapacityModeError),
+        }
+    }
+}
 
-                    self.send_received_respond_packet(&addr, &proxied_buffer[..n]).await;
-                }
+/// How associations are kept for clients, and which responses they send back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpNatMode {
+    /// One association for each client, responses from any source are sent back. The default
+    Peer,
+    /// One association for each client and target, only responses from the target are sent back
+    Symmetric,
+    /// One association for each client and target, responses from any source are sent back, including unsolicited
+    /// ones sent to the association's port on the server
+    FullCone,
+}
 
-                _ = keepalive_interval.tick() => {
-                    if self.keepalive_flag {
-                        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
-                            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
-                        } else {
-                            self.keepalive_flag = false;
-                        }
-                    }
-                }
-            }
+impl UdpNatMode {
+    /// Check if a response from `source` is sent back by an association forwarding to `target`
+    fn accepts_response(self, target: &Address, source: &Address) -> bool {
+        match (self, target) {
+            (UdpNatMode::Symmetric, Address::SocketAddress(..)) => source == target,
+            // Resolved by the server, only the port could be checked
+            (UdpNatMode::Symmetric, Address::DomainNameAddress(_, port)) => source.port() == *port,
+            (UdpNatMode::Peer, ..) | (UdpNatMode::FullCone, ..) => true,
         }
+    }
+}
 
-        #[inline]
-        async fn receive_from_proxied_opt(
-            socket: &Option<MonProxySocket>,
-            buf: &mut Vec<u8>,
-        ) -> io::Result<(usize, Address)> {
-            match *socket {
-                None => future::pending().await,
-                Some(ref s) => {
-                    if buf.is_empty() {
-                        buf.resize(MAXIMUM_UDP_PAYLOAD_SIZE, 0);
-                    }
-                    s.recv(buf).await
-                }
-            }
+impl Display for UdpNatMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpNatMode::Peer => f.write_str("peer"),
+            UdpNatMode::Symmetric => f.write_str("symmetric"),
+            UdpNatMode::FullCone => f.write_str("fullcone"),
         }
     }
+}
 
-    async fn dispatch_received_packet(&mut self, data: &[u8]) {
-        trace!(
-            "udp relay {} -> {} with {} bytes",
-            self.peer_addr,
-            self.forward_addr,
-            data.len()
-        );
+/// Error while parsing `UdpNatMode` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpNatModeError;
 
-        if let Err(err) = self.dispatch_received_proxied_packet(data).await {
-            error!(
-                "udp relay {} -> {} with {} bytes, error: {}",
-                self.peer_addr,
-                self.forward_addr,
-                data.len(),
-                err
-            );
+impl Display for UdpNatModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpNatMode, expecting \"peer\", \"symmetric\" or \"fullcone\"")
+    }
+}
+
+impl FromStr for UdpNatMode {
+    type Err = UdpNatModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peer" => Ok(UdpNatMode::Peer),
+            "symmetric" => Ok(UdpNatMode::Symmetric),
+            "fullcone" => Ok(UdpNatMode::FullCone),
+            _ => Err(UdpNatModeError),
         }
     }
+}
 
-    async fn dispatch_received_proxied_packet(&mut self, data: &[u8]) -> io::Result<()> {
-        let socket = match self.proxied_socket {
-            Some(ref mut socket) => socket,
-            None => {
-                // Create a new connection to proxy server
+/// Health of an association's connection to its server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpUpstreamState {
+    /// Not connected yet, the first packet is connecting
+    Connecting,
+    /// Holds a live socket to the server
+    Connected,
+    /// Socket is dropped after an error, the next packet reconnects
+    Reconnecting,
+    /// Connecting failed, packets are dropped until the next packet connects successfully
+    Failed,
+}
 
-                let server = self.balancer.best_udp_server();
-                let svr_cfg = server.server_config();
+impl UdpUpstreamState {
+    fn from_u8(state: u8) -> UdpUpstreamState {
+        match state {
+            1 => UdpUpstreamState::Connected,
+            2 => UdpUpstreamState::Reconnecting,
+            3 => UdpUpstreamState::Failed,
+            _ => UdpUpstreamState::Connecting,
+        }
+    }
+}
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+impl Display for UdpUpstreamState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpUpstreamState::Connecting => f.write_str("connecting"),
+            UdpUpstreamState::Connected => f.write_str("connected"),
+            UdpUpstreamState::Reconnecting => f.write_str("reconnecting"),
+            UdpUpstreamState::Failed => f.write_str("failed"),
+        }
+    }
+}
 
-                self.proxied_socket.insert(socket)
-            }
-        };
+/// Key of an association, with the destination of its client's packets only if associations are kept for each
+/// target by `UdpNatMode`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct AssocKey {
+    peer_addr: SocketAddr,
+    target: Option<SocketAddr>,
+}
 
-        match socket.send(&self.forward_addr, data).await {
-            Ok(..) => return Ok(()),
-            Err(err) => {
-                debug!(
-                    "{} -> {} (proxied) sending {} bytes failed, error: {}",
-                    self.peer_addr,
-                    self.forward_addr,
-                    data.len(),
-                    err
-                );
+impl AssocKey {
+    fn new(nat_mode: UdpNatMode, peer_addr: SocketAddr, dst_addr: SocketAddr) -> AssocKey {
+        let target = match nat_mode {
+            UdpNatMode::Peer => None,
+            UdpNatMode::Symmetric | UdpNatMode::FullCone => Some(dst_addr),
+        };
+        AssocKey { peer_addr, target }
+    }
+}
 
-                // Drop the socket and reconnect to another server.
-                self.proxied_socket = None;
-            }
+impl From<SocketAddr> for AssocKey {
+    fn from(peer_addr: SocketAddr) -> AssocKey {
+        AssocKey {
+            peer_addr,
+            target: None,
         }
+    }
+}
 
-        Ok(())
+fn new_association_map(
+    time_to_live: Duration,
+    capacity: Option<usize>,
+    capacity_mode: UdpCapacityMode,
+) -> AssociationMap {
+    match (capacity, capacity_mode) {
+        // Rejecting mode checks capacity by itself, LRU must not evict anything
+        (Some(capacity), UdpCapacityMode::Evict) => LruCache::with_expiry_duration_and_capacity(time_to_live, capacity),
+        _ => LruCache::with_expiry_duration(time_to_live),
     }
+}
 
-    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) {
-        trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+/// Hook notified of associations evicted because of the capacity limit in `UdpCapacityMode::Evict`
+///
+/// Associations expired by their TTL are not reported. Called in the tunnel's task, it shouldn't block.
+pub trait UdpEvictionHook: Send + Sync {
+    /// Association of client `peer_addr` was evicted to make room for a new client
+    fn evicted(&self, peer_addr: SocketAddr);
+}
 
-        // Keep association alive in map
-        self.keepalive_flag = true;
+/// Routing rules choosing the forward address by destination port of packets
+///
+/// Destinations are the ones that clients intended to reach if the tunnel is transparent, see
+/// `UdpTunnel::set_transparent`. Otherwise they are the listening addresses, listen on several ports with
+/// `UdpTunnel::run_multi` to route each of them to its own forward address.
+#[derive(Debug, Clone, Default)]
+pub struct UdpForwardRules {
+    port_rules: HashMap<u16, Address>,
+}
 
-        // Send back to client
-        if let Err(err) = self.inbound.send_to(data, self.peer_addr).await {
-            warn!(
-                "udp failed to send back {} bytes to client {}, from target {}, error: {}",
-                data.len(),
-                self.peer_addr,
-                addr,
-                err
-            );
-        } else {
-            trace!("udp relay {} <- {} with {} bytes", self.peer_addr, addr, data.len());
-        }
+impl UdpForwardRules {
+    /// Create an empty rule table, all packets will be forwarded to the default address
+    pub fn new() -> UdpForwardRules {
+        UdpForwardRules::default()
+    }
+
+    /// Forward packets sent to `port` to `forward_addr`
+    pub fn add_port_rule(&mut self, port: u16, forward_addr: Address) {
+        self.port_rules.insert(port, forward_addr);
+    }
+
+    /// Choose the forward address for packet sent to `dst_port`, falling back to `default_addr`
+    pub fn forward_addr<'a>(&'a self, dst_port: u16, default_addr: &'a Address) -> &'a Address {
+        self.port_rules.get(&dst_port).unwrap_or(default_addr)
+    }
+}
+
+/// Snapshot of an active association in `UdpTunnel`
+#[derive(Debug, Clone)]
+pub struct AssocEntry {
+    /// Client's address
+    pub peer_addr: SocketAddr,
+    /// Address that the client's packets are forwarded to
+    pub forward_addr: Address,
+    /// Bytes sent from the client to the forward address
+    pub tx: u64,
+    /// Bytes sent back to the client
+    pub rx: u64,
+    /// Time since the association was created
+    pub age: Duration,
+    /// Time of the last packet sent or received
+    pub last_active: Instant,
+    /// Time from the client's first packet to the first response sent back, including connecting to the server
+    pub first_response_latency: Option<Duration>,
+    /// Health of the connection to the server, of the stripe that changed it the last time
+    pub upstream_state: UdpUpstreamState,
+}
+
+/// Traffic relayed by an association of `UdpTunnel`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerTraffic {
+    /// Bytes sent from the client to the forward address
+    pub tx_bytes: u64,
+    /// Bytes sent back to the client
+    pub rx_bytes: u64,
+    /// Packets sent from the client to the forward address
+    pub tx_packets: u64,
+    /// Packets sent back to the client
+    pub rx_packets: u64,
+}
+
+/// Statistics of an association, shared between the association's task and the tracker
+#[derive(Debug, Default)]
+struct AssocTraffic {
+    tx: AtomicU64,
+    rx: AtomicU64,
+    tx_packets: AtomicU64,
+    rx_packets: AtomicU64,
+    first_response_latency: SpinMutex<Option<Duration>>,
+    upstream_state: AtomicU8,
+    totals: Arc<UdpTrafficTotals>,
+}
+
+impl AssocTraffic {
+    fn new(totals: Arc<UdpTrafficTotals>) -> AssocTraffic {
+        AssocTraffic {
+            totals,
+            ..Default::default()
+        }
+    }
+
+    fn add_tx(&self, n: usize) {
+        self.tx.fetch_add(n as u64, Ordering::Relaxed);
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.totals.tx.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_rx(&self, n: usize) {
+        self.rx.fetch_add(n as u64, Ordering::Relaxed);
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.totals.rx.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn set_upstream_state(&self, state: UdpUpstreamState) {
+        self.upstream_state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn upstream_state(&self) -> UdpUpstreamState {
+        UdpUpstreamState::from_u8(self.upstream_state.load(Ordering::Relaxed))
+    }
+
+    fn snapshot(&self) -> PeerTraffic {
+        PeerTraffic {
+            tx_bytes: self.tx.load(Ordering::Relaxed),
+            rx_bytes: self.rx.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes relayed by all associations, including the dropped ones
+#[derive(Debug, Default)]
+struct UdpTrafficTotals {
+    tx: AtomicU64,
+    rx: AtomicU64,
+}
+
+struct AssocState {
+    forward_addr: Address,
+    created: Instant,
+    last_active: LastActive,
+    traffic: Arc<AssocTraffic>,
+}
+
+type SharedAssocStates = Arc<SpinMutex<HashMap<AssocKey, AssocState>>>;
+
+/// Association tracking of `UdpTunnel`, could be cloned and read while `UdpTunnel` is running
+#[derive(Clone, Default)]
+pub struct UdpAssocTrack {
+    states: SharedAssocStates,
+    traffic: Arc<UdpTrafficTotals>,
+}
+
+impl UdpAssocTrack {
+    /// Create an empty tracking table, to be shared with a `UdpTunnel` before it runs
+    pub fn new() -> UdpAssocTrack {
+        UdpAssocTrack::default()
+    }
+
+    /// Number of active associations
+    pub fn len(&self) -> usize {
+        self.states.lock().len()
+    }
+
+    /// Check if there is no active associations
+    pub fn is_empty(&self) -> bool {
+        self.states.lock().is_empty()
+    }
+
+    /// Total bytes sent from clients to forward addresses by all associations, including the dropped ones
+    pub fn tx(&self) -> u64 {
+        self.traffic.tx.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes sent back to clients by all associations, including the dropped ones
+    pub fn rx(&self) -> u64 {
+        self.traffic.rx.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of all active associations
+    pub fn entries(&self) -> Vec<AssocEntry> {
+        let now = Instant::now();
+        let states = self.states.lock();
+        states
+            .iter()
+            .map(|(key, state)| AssocEntry {
+                peer_addr: key.peer_addr,
+                forward_addr: state.forward_addr.clone(),
+                tx: state.traffic.tx.load(Ordering::Relaxed),
+                rx: state.traffic.rx.load(Ordering::Relaxed),
+                age: now.saturating_duration_since(state.created),
+                last_active: state.last_active.get(),
+                first_response_latency: *state.traffic.first_response_latency.lock(),
+                upstream_state: state.traffic.upstream_state(),
+            })
+            .collect()
+    }
+
+    /// Traffic relayed by active associations, summed by client's address
+    pub fn traffic_by_peer(&self) -> HashMap<SocketAddr, PeerTraffic> {
+        // Counters are read after releasing the lock, associations' tasks never wait for it
+        let traffics = self
+            .states
+            .lock()
+            .iter()
+            .map(|(key, state)| (key.peer_addr, state.traffic.clone()))
+            .collect::<Vec<_>>();
+
+        let mut traffic_by_peer = HashMap::<SocketAddr, PeerTraffic>::new();
+        for (peer_addr, traffic) in traffics {
+            let snapshot = traffic.snapshot();
+            let total = traffic_by_peer.entry(peer_addr).or_default();
+            total.tx_bytes += snapshot.tx_bytes;
+            total.rx_bytes += snapshot.rx_bytes;
+            total.tx_packets += snapshot.tx_packets;
+            total.rx_packets += snapshot.rx_packets;
+        }
+        traffic_by_peer
+    }
+}
+
+/// Keeps the association's state in `UdpTunnel` until the association is dropped
+struct UdpAssocTracker {
+    states: SharedAssocStates,
+    key: AssocKey,
+}
+
+impl UdpAssocTracker {
+    fn new(states: SharedAssocStates, key: AssocKey, state: AssocState) -> UdpAssocTracker {
+        states.lock().insert(key.clone(), state);
+        UdpAssocTracker { states, key }
+    }
+}
+
+impl Drop for UdpAssocTracker {
+    fn drop(&mut self) {
+        self.states.lock().remove(&self.key);
+    }
+}
+
+/// Expiry durations of associations forwarding to specific destinations
+#[derive(Debug, Clone, Default)]
+pub struct UdpTtlRules {
+    rules: Vec<(AddressRules, Duration)>,
+}
+
+impl UdpTtlRules {
+    /// Create an empty rule table, all associations expire on the global TTL
+    pub fn new() -> UdpTtlRules {
+        UdpTtlRules::default()
+    }
+
+    /// Associations forwarding to destinations matching `rules` expire after idle for `ttl`
+    ///
+    /// Rules are matched in the order they were added.
+    pub fn add_rule(&mut self, rules: AddressRules, ttl: Duration) {
+        self.rules.push((rules, ttl));
+    }
+
+    /// Choose the TTL of association forwarding to `forward_addr`, falling back to `default_ttl`
+    pub fn ttl(&self, forward_addr: &Address, default_ttl: Duration) -> Duration {
+        self.rules
+            .iter()
+            .find(|(rules, ..)| rules.check_address_matched(forward_addr))
+            .map_or(default_ttl, |(_, ttl)| *ttl)
+    }
+
+    fn ttls(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.rules.iter().map(|(_, ttl)| *ttl)
+    }
+}
+
+/// Builder of `UdpTunnel`, options not set are the defaults of `UdpTunnel::new`
+#[derive(Clone)]
+pub struct UdpTunnelBuilder {
+    time_to_live: Option<Duration>,
+    cleanup_interval: Option<Duration>,
+    capacity: Option<usize>,
+    capacity_mode: UdpCapacityMode,
+    max_payload_size: Option<usize>,
+    eviction_hook: Option<Arc<dyn UdpEvictionHook>>,
+    forward_rules: UdpForwardRules,
+    ttl_rules: UdpTtlRules,
+    coalesce_rules: UdpCoalesceRules,
+    conntrack: Option<UdpAssocTrack>,
+    connect_timeout: Option<Duration>,
+    padding: Option<UdpPaddingPolicy>,
+    channel_full_policy: UdpChannelFullPolicy,
+    send_channel_size: Option<usize>,
+    outbound_pool_size: usize,
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    mmsg_batch_size: Option<usize>,
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    hop_limit_mode: UdpHopLimitMode,
+    nat_mode: UdpNatMode,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    transparent: bool,
+    socket_factory: Option<Arc<dyn ProxySocketFactory>>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Default for UdpTunnelBuilder {
+    fn default() -> UdpTunnelBuilder {
+        UdpTunnelBuilder {
+            time_to_live: None,
+            cleanup_interval: None,
+            capacity: None,
+            capacity_mode: UdpCapacityMode::Evict,
+            max_payload_size: None,
+            eviction_hook: None,
+            forward_rules: UdpForwardRules::new(),
+            ttl_rules: UdpTtlRules::new(),
+            coalesce_rules: UdpCoalesceRules::new(),
+            conntrack: None,
+            connect_timeout: None,
+            padding: None,
+            channel_full_policy: UdpChannelFullPolicy::Drop,
+            send_channel_size: None,
+            outbound_pool_size: 1,
+            #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+            mmsg_batch_size: None,
+            #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+            hop_limit_mode: UdpHopLimitMode::Default,
+            nat_mode: UdpNatMode::Peer,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            transparent: false,
+            socket_factory: None,
+            dns_resolver: None,
+            clock: None,
+        }
+    }
+}
+
+impl UdpTunnelBuilder {
+    pub fn new() -> UdpTunnelBuilder {
+        UdpTunnelBuilder::default()
+    }
+
+    /// Expiry duration of idle associations, `DEFAULT_UDP_EXPIRY_DURATION` if not set
+    pub fn time_to_live(mut self, time_to_live: Option<Duration>) -> UdpTunnelBuilder {
+        self.time_to_live = time_to_live;
+        self
+    }
+
+    /// See `UdpTunnel::set_cleanup_interval`
+    pub fn cleanup_interval(mut self, interval: Option<Duration>) -> UdpTunnelBuilder {
+        self.cleanup_interval = interval;
+        self
+    }
+
+    /// Maximum number of associations, unlimited if not set
+    pub fn capacity(mut self, capacity: Option<usize>) -> UdpTunnelBuilder {
+        self.capacity = capacity;
+        self
+    }
+
+    /// How new clients are handled once `capacity` is reached, `UdpCapacityMode::Evict` by default
+    pub fn capacity_mode(mut self, capacity_mode: UdpCapacityMode) -> UdpTunnelBuilder {
+        self.capacity_mode = capacity_mode;
+        self
+    }
+
+    /// See `UdpTunnel::set_max_payload_size`
+    pub fn max_payload_size(mut self, size: Option<usize>) -> UdpTunnelBuilder {
+        self.max_payload_size = size;
+        self
+    }
+
+    /// See `UdpTunnel::set_eviction_hook`
+    pub fn eviction_hook(mut self, hook: Option<Arc<dyn UdpEvictionHook>>) -> UdpTunnelBuilder {
+        self.eviction_hook = hook;
+        self
+    }
+
+    /// See `UdpTunnel::set_forward_rules`
+    pub fn forward_rules(mut self, forward_rules: UdpForwardRules) -> UdpTunnelBuilder {
+        self.forward_rules = forward_rules;
+        self
+    }
+
+    /// See `UdpTunnel::set_ttl_rules`
+    pub fn ttl_rules(mut self, ttl_rules: UdpTtlRules) -> UdpTunnelBuilder {
+        self.ttl_rules = ttl_rules;
+        self
+    }
+
+    /// See `UdpTunnel::set_coalesce_rules`
+    pub fn coalesce_rules(mut self, coalesce_rules: UdpCoalesceRules) -> UdpTunnelBuilder {
+        self.coalesce_rules = coalesce_rules;
+        self
+    }
+
+    /// Track associations in `conntrack`, a new one is created if not set
+    pub fn conntrack(mut self, conntrack: UdpAssocTrack) -> UdpTunnelBuilder {
+        self.conntrack = Some(conntrack);
+        self
+    }
+
+    /// Timeout of connecting to a server, `DEFAULT_UDP_CONNECT_TIMEOUT` if not set
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> UdpTunnelBuilder {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// See `UdpTunnel::set_padding`
+    pub fn padding(mut self, padding: Option<UdpPaddingPolicy>) -> UdpTunnelBuilder {
+        self.padding = padding;
+        self
+    }
+
+    /// See `UdpTunnel::set_channel_full_policy`
+    pub fn channel_full_policy(mut self, policy: UdpChannelFullPolicy) -> UdpTunnelBuilder {
+        self.channel_full_policy = policy;
+        self
+    }
+
+    /// Packets queued in each association's send channel, `UDP_ASSOCIATION_SEND_CHANNEL_SIZE` if not set
+    pub fn send_channel_size(mut self, size: Option<usize>) -> UdpTunnelBuilder {
+        self.send_channel_size = size;
+        self
+    }
+
+    /// See `UdpTunnel::set_outbound_pool_size`
+    pub fn outbound_pool_size(mut self, size: usize) -> UdpTunnelBuilder {
+        self.outbound_pool_size = size;
+        self
+    }
+
+    /// Datagrams batched by `recvmmsg` and `sendmmsg`, `DEFAULT_UDP_MMSG_BATCH_SIZE` if not set
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    pub fn mmsg_batch_size(mut self, size: Option<usize>) -> UdpTunnelBuilder {
+        self.mmsg_batch_size = size;
+        self
+    }
+
+    /// See `UdpTunnel::set_hop_limit_mode`
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    pub fn hop_limit_mode(mut self, hop_limit_mode: UdpHopLimitMode) -> UdpTunnelBuilder {
+        self.hop_limit_mode = hop_limit_mode;
+        self
+    }
+
+    /// See `UdpTunnel::set_nat_mode`
+    pub fn nat_mode(mut self, nat_mode: UdpNatMode) -> UdpTunnelBuilder {
+        self.nat_mode = nat_mode;
+        self
+    }
+
+    /// See `UdpTunnel::set_transparent`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn transparent(mut self, transparent: bool) -> UdpTunnelBuilder {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Connect associations' sockets with `socket_factory`, `DefaultProxySocketFactory` if not set
+    pub fn socket_factory(mut self, socket_factory: Arc<dyn ProxySocketFactory>) -> UdpTunnelBuilder {
+        self.socket_factory = Some(socket_factory);
+        self
+    }
+
+    /// See `UdpTunnel::set_dns_resolver`
+    pub fn dns_resolver(mut self, dns_resolver: Option<Arc<DnsResolver>>) -> UdpTunnelBuilder {
+        self.dns_resolver = dns_resolver;
+        self
+    }
+
+    /// See `UdpTunnel::set_clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> UdpTunnelBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build(self, context: Arc<ServiceContext>) -> UdpTunnel {
+        let time_to_live = self.time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
+        // Associations are kept in LRU until the longest TTL and removed by their own TTL
+        let max_ttl = self.ttl_rules.ttls().fold(time_to_live, Duration::max);
+        let assoc_map = new_association_map(max_ttl, self.capacity, self.capacity_mode);
+
+        let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+
+        UdpTunnel {
+            context,
+            assoc_map,
+            keepalive_tx,
+            keepalive_rx,
+            time_to_live,
+            cleanup_interval: self.cleanup_interval,
+            capacity: self.capacity,
+            capacity_mode: self.capacity_mode,
+            rejected: Arc::new(AtomicU64::new(0)),
+            evicted: Arc::new(AtomicU64::new(0)),
+            max_payload_size: self
+                .max_payload_size
+                .unwrap_or(MAXIMUM_UDP_PAYLOAD_SIZE)
+                .clamp(1, MAXIMUM_UDP_PAYLOAD_SIZE),
+            oversized: Arc::new(AtomicU64::new(0)),
+            eviction_hook: self.eviction_hook,
+            forward_rules: self.forward_rules,
+            ttl_rules: self.ttl_rules,
+            coalesce_rules: self.coalesce_rules,
+            coalesced: Arc::new(AtomicU64::new(0)),
+            conntrack: self.conntrack.unwrap_or_default(),
+            connect_timeout: ConnectTimeout::new(self.connect_timeout.unwrap_or(DEFAULT_UDP_CONNECT_TIMEOUT)),
+            padding: self.padding,
+            channel_full_policy: self.channel_full_policy,
+            send_channel_size: self
+                .send_channel_size
+                .unwrap_or(UDP_ASSOCIATION_SEND_CHANNEL_SIZE)
+                .max(1),
+            outbound_pool_size: self.outbound_pool_size.max(1),
+            #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+            mmsg_batch_size: self
+                .mmsg_batch_size
+                .unwrap_or(DEFAULT_UDP_MMSG_BATCH_SIZE)
+                .clamp(1, MAX_UDP_MMSG_BATCH_SIZE),
+            #[cfg(not(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android"))))]
+            mmsg_batch_size: 1,
+            #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+            hop_limit_mode: self.hop_limit_mode,
+            nat_mode: self.nat_mode,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            transparent: self.transparent,
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            transparent: false,
+            socket_factory: self
+                .socket_factory
+                .unwrap_or_else(|| Arc::new(DefaultProxySocketFactory)),
+            dns_resolver: self.dns_resolver,
+            clock: self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            next_forward_idx: 0,
+        }
+    }
+}
+
+pub struct UdpTunnel {
+    context: Arc<ServiceContext>,
+    assoc_map: AssociationMap,
+    keepalive_tx: mpsc::Sender<AssocKey>,
+    keepalive_rx: mpsc::Receiver<AssocKey>,
+    time_to_live: Duration,
+    cleanup_interval: Option<Duration>,
+    capacity: Option<usize>,
+    capacity_mode: UdpCapacityMode,
+    rejected: Arc<AtomicU64>,
+    evicted: Arc<AtomicU64>,
+    max_payload_size: usize,
+    oversized: Arc<AtomicU64>,
+    eviction_hook: Option<Arc<dyn UdpEvictionHook>>,
+    forward_rules: UdpForwardRules,
+    ttl_rules: UdpTtlRules,
+    coalesce_rules: UdpCoalesceRules,
+    coalesced: Arc<AtomicU64>,
+    conntrack: UdpAssocTrack,
+    connect_timeout: ConnectTimeout,
+    padding: Option<UdpPaddingPolicy>,
+    channel_full_policy: UdpChannelFullPolicy,
+    send_channel_size: usize,
+    outbound_pool_size: usize,
+    mmsg_batch_size: usize,
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    hop_limit_mode: UdpHopLimitMode,
+    nat_mode: UdpNatMode,
+    // Listening sockets receive packets redirected by TPROXY
+    transparent: bool,
+    socket_factory: Arc<dyn ProxySocketFactory>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+    clock: Arc<dyn Clock>,
+    next_forward_idx: usize,
+}
+
+impl UdpTunnel {
+    /// Create a new UDP tunnel, `capacity_mode` decides how new clients are handled once `capacity` is reached
+    ///
+    /// Other options are set by `UdpTunnelBuilder` or setters of the tunnel.
+    pub fn new(
+        context: Arc<ServiceContext>,
+        time_to_live: Option<Duration>,
+        capacity: Option<usize>,
+        capacity_mode: UdpCapacityMode,
+    ) -> UdpTunnel {
+        UdpTunnelBuilder::new()
+            .time_to_live(time_to_live)
+            .capacity(capacity)
+            .capacity_mode(capacity_mode)
+            .build(context)
+    }
+
+    /// Association tracking of all active associations
+    pub fn conntrack(&self) -> UdpAssocTrack {
+        self.conntrack.clone()
+    }
+
+    /// Track associations in `conntrack` instead, must be set before running
+    pub fn set_conntrack(&mut self, conntrack: UdpAssocTrack) {
+        self.conntrack = conntrack;
+    }
+
+    /// Snapshot of all active associations
+    pub fn associations(&self) -> Vec<AssocEntry> {
+        self.conntrack.entries()
+    }
+
+    /// Number of active associations
+    pub fn association_count(&self) -> usize {
+        self.assoc_map.len()
+    }
+
+    /// Move all associations off their current servers, their next packets connect to newly chosen servers
+    ///
+    /// Useful after the balancer's servers were updated, associations are kept on their servers until they fail
+    /// otherwise. Returns the number of associations notified.
+    pub fn rebind_servers(&self) -> usize {
+        self.assoc_map
+            .peek_iter()
+            .filter(|(_, assoc)| assoc.command(UdpAssocCommand::RebindServer))
+            .count()
+    }
+
+    /// Close associations of `peer_addr` after relaying packets already queued, returns `false` if none exists
+    ///
+    /// Responses arriving while closing are not sent back, the client's next packet creates a new association.
+    pub fn close_association(&mut self, peer_addr: &SocketAddr) -> bool {
+        let keys = self
+            .assoc_map
+            .peek_iter()
+            .filter(|(key, _)| key.peer_addr == *peer_addr)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let mut closed = false;
+        for key in keys {
+            if let Some(assoc) = self.assoc_map.remove(&key) {
+                assoc.command(UdpAssocCommand::Close);
+                // Detached, tasks exit by themselves
+                assoc.close();
+                closed = true;
+            }
+        }
+        closed
+    }
+
+    /// Traffic relayed by each active association, keyed by client's address
+    pub fn traffic_by_peer(&self) -> HashMap<SocketAddr, PeerTraffic> {
+        self.conntrack.traffic_by_peer()
+    }
+
+    /// Number of clients rejected because of the capacity limit in `UdpCapacityMode::Reject`
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of associations evicted because of the capacity limit in `UdpCapacityMode::Evict`
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Set size of the largest packet accepted from clients, must be set before running
+    ///
+    /// Larger packets are dropped instead of being forwarded truncated. `MAXIMUM_UDP_PAYLOAD_SIZE` by default, which
+    /// is larger than any UDP datagram, so nothing is dropped unless it's lowered.
+    pub fn set_max_payload_size(&mut self, size: usize) {
+        self.max_payload_size = size.clamp(1, MAXIMUM_UDP_PAYLOAD_SIZE);
+    }
+
+    /// Number of packets from clients dropped because they were larger than the maximum payload size
+    pub fn oversized_count(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    /// Report associations evicted because of the capacity limit to `hook`
+    pub fn set_eviction_hook(&mut self, hook: Option<Arc<dyn UdpEvictionHook>>) {
+        self.eviction_hook = hook;
+    }
+
+    /// Set behavior when an association's send channel is full, packets are dropped by default
+    pub fn set_channel_full_policy(&mut self, policy: UdpChannelFullPolicy) {
+        self.channel_full_policy = policy;
+    }
+
+    /// Set number of packets queued in each association's send channel, `UDP_ASSOCIATION_SEND_CHANNEL_SIZE` by default
+    ///
+    /// Packets coming while it is full are handled by the channel full policy. Every stripe of an association has its
+    /// own channel.
+    pub fn set_send_channel_size(&mut self, size: usize) {
+        self.send_channel_size = size.max(1);
+    }
+
+    /// Set routing rules choosing forward address by the listening port that received packets
+    pub fn set_forward_rules(&mut self, forward_rules: UdpForwardRules) {
+        self.forward_rules = forward_rules;
+    }
+
+    /// Set expiry durations of associations by their forward addresses, overriding the global TTL
+    ///
+    /// Must be set before running, associations are kept in LRU until the longest TTL and removed by their own TTL.
+    pub fn set_ttl_rules(&mut self, ttl_rules: UdpTtlRules) {
+        let max_ttl = ttl_rules.ttls().fold(self.time_to_live, Duration::max);
+        self.assoc_map = new_association_map(max_ttl, self.capacity, self.capacity_mode);
+        self.ttl_rules = ttl_rules;
+    }
+
+    /// Set interval of sweeping expired associations, independent of their TTLs
+    ///
+    /// Expired associations keep their sockets until the next sweep. By default it is the shortest TTL, but at most
+    /// `DEFAULT_UDP_CLEANUP_INTERVAL`, so associations with long TTLs are still removed soon after they expire.
+    pub fn set_cleanup_interval(&mut self, interval: Duration) {
+        self.cleanup_interval = Some(interval);
+    }
+
+    fn cleanup_interval(&self) -> Duration {
+        self.cleanup_interval.unwrap_or_else(|| {
+            // Associations with the shortest TTL are checked in time
+            self.ttl_rules
+                .ttls()
+                .fold(self.time_to_live, Duration::min)
+                .min(DEFAULT_UDP_CLEANUP_INTERVAL)
+        })
+    }
+
+    /// Coalesce small responses of associations by their forward addresses, nothing is coalesced by default
+    ///
+    /// Merging datagrams breaks applications relying on datagram boundaries, only add rules for destinations whose
+    /// protocol tolerates it, with hooks that know how the protocol frames its messages.
+    pub fn set_coalesce_rules(&mut self, coalesce_rules: UdpCoalesceRules) {
+        self.coalesce_rules = coalesce_rules;
+    }
+
+    /// Number of responses saved by coalescing, which is responses passed to hooks minus datagrams they returned
+    pub fn coalesced_count(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Set timeout of connecting to a server, packets are dropped if it takes longer
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = ConnectTimeout::new(connect_timeout);
+    }
+
+    /// Set padding of packets sent to servers, servers must enable the same padding
+    pub fn set_padding(&mut self, padding: Option<UdpPaddingPolicy>) {
+        self.padding = padding;
+    }
+
+    /// Stripe each association across `size` outbound sockets to the same server, 1 (default) disables striping
+    ///
+    /// Packets of an association are sent round-robin through the sockets, each of them is encrypted, sent and received
+    /// by its own task, and responses of all sockets are sent back to the client. It raises the packet rate of a single
+    /// busy association, but:
+    ///
+    /// - Packets may be reordered between sockets, in both directions
+    /// - Server relays each socket as a separate association, so the target sees `size` different source ports
+    ///
+    /// Only enable it for protocols that tolerate both, like stateless request-response or bulk datagram streams.
+    pub fn set_outbound_pool_size(&mut self, size: usize) {
+        self.outbound_pool_size = size.max(1);
+    }
+
+    /// Set number of datagrams received from clients or sent back to them together by `recvmmsg` and `sendmmsg`
+    ///
+    /// 1 disables batching, up to `MAX_UDP_MMSG_BATCH_SIZE`. Packets are still relayed one by one in order, a batch
+    /// only takes packets already queued in the socket. `DEFAULT_UDP_MMSG_BATCH_SIZE` by default.
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    pub fn set_mmsg_batch_size(&mut self, size: usize) {
+        self.mmsg_batch_size = size.clamp(1, MAX_UDP_MMSG_BATCH_SIZE);
+    }
+
+    /// Set hop limit of packets relayed to servers, `UdpHopLimitMode::Default` by default
+    ///
+    /// Clients' hop limits are received by `recvmsg` one packet at a time if they are relayed, packets aren't
+    /// batched by `recvmmsg`. Sockets not supporting it keep the system's default, like the ones relaying through
+    /// QUIC.
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    pub fn set_hop_limit_mode(&mut self, hop_limit_mode: UdpHopLimitMode) {
+        self.hop_limit_mode = hop_limit_mode;
+    }
+
+    /// Set how associations are kept for clients, `UdpNatMode::Peer` by default
+    ///
+    /// Targets are the addresses that clients sent their packets to, received by `IP_RECVORIGDSTADDR` on Linux and
+    /// Android. A client sending to several addresses of a listener on an unspecified address gets an association for
+    /// each of them, with responses sent back from that address. On other platforms targets are listening addresses.
+    pub fn set_nat_mode(&mut self, nat_mode: UdpNatMode) {
+        self.nat_mode = nat_mode;
+    }
+
+    /// Receive packets redirected by TPROXY on listening sockets, like `redir` does, requires `CAP_NET_ADMIN`
+    ///
+    /// Packets are routed by the ports of the destinations that clients intended to reach, see `UdpForwardRules`.
+    /// Every destination has an association of its own whatever the NAT mode is, responses are sent back from it.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.transparent = transparent;
+    }
+
+    /// Connect associations' sockets with `socket_factory` instead of `DefaultProxySocketFactory`
+    pub fn set_socket_factory(&mut self, socket_factory: Arc<dyn ProxySocketFactory>) {
+        self.socket_factory = socket_factory;
+    }
+
+    /// Resolve domain names of listening addresses and servers with `dns_resolver` instead of the `ServiceContext`'s
+    ///
+    /// Names are resolved when sockets are bound or connected, the first address is taken.
+    pub fn set_dns_resolver(&mut self, dns_resolver: Option<Arc<DnsResolver>>) {
+        self.dns_resolver = dns_resolver;
+    }
+
+    /// Check idle associations against `clock` instead of `SystemClock`
+    ///
+    /// Only the sweeps read it, they still run on tokio's timer, and associations are marked active at the system's
+    /// time. Tests could expire associations by advancing a `ManualClock` and sweeping, without waiting for their TTLs.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub async fn run(
+        &mut self,
+        client_config: &ServerAddr,
+        balancer: PingBalancer,
+        forward_addr: &Address,
+    ) -> io::Result<()> {
+        self.run_forward_addrs(client_config, balancer, slice::from_ref(forward_addr))
+            .await
+    }
+
+    /// Run the tunnel distributing new associations across `forward_addrs` in round-robin
+    ///
+    /// Each association keeps forwarding to the address chosen when it was created, so a client talks to the same
+    /// target until its association expires. Forward rules still take precedence.
+    pub async fn run_forward_addrs(
+        &mut self,
+        client_config: &ServerAddr,
+        balancer: PingBalancer,
+        forward_addrs: &[Address],
+    ) -> io::Result<()> {
+        self.run_multi(slice::from_ref(client_config), balancer, forward_addrs)
+            .await
+    }
+
+    /// Run the tunnel listening on all of `client_configs`, like `run_forward_addrs`
+    ///
+    /// Associations are shared by all sockets, a client reaching the tunnel through several of them keeps one
+    /// association. Responses are sent back by the socket that received the client's latest packet.
+    pub async fn run_multi(
+        &mut self,
+        client_configs: &[ServerAddr],
+        balancer: PingBalancer,
+        forward_addrs: &[Address],
+    ) -> io::Result<()> {
+        if client_configs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "UDP tunnel requires at least one listen address",
+            ));
+        }
+        if forward_addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "UDP tunnel requires at least one forward address",
+            ));
+        }
+
+        let mut inbounds = Vec::with_capacity(client_configs.len());
+        for client_config in client_configs {
+            inbounds.push(self.bind_inbound(client_config).await?);
+        }
+        // Client sockets that failed fatally, waiting to be bound again
+        let mut unbound = Vec::new();
+
+        let mut cleanup_timer = time::interval(self.cleanup_interval());
+        let mut rebind_timer = time::interval(INBOUND_REBIND_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = cleanup_timer.tick() => {
+                    // cleanup expired associations. iter() will remove expired elements
+                    let _ = self.assoc_map.iter();
+                    self.cleanup_idle();
+                }
+
+                key_opt = self.keepalive_rx.recv() => {
+                    let key = key_opt.expect("keep-alive channel closed unexpectly");
+                    self.assoc_map.get(&key);
+                }
+
+                _ = rebind_timer.tick(), if !unbound.is_empty() => {
+                    self.rebind_inbounds(&mut inbounds, &mut unbound).await;
+                }
+
+                (idx, recv_result) = recv_any_inbound(&mut inbounds) => {
+                    if let Err(err) = recv_result {
+                        if is_fatal_recv_error(&err) {
+                            error!("udp server recv_from failed with error: {}, binding it again", err);
+                            unbound.push(self.close_inbound(&mut inbounds, idx));
+                            self.rebind_inbounds(&mut inbounds, &mut unbound).await;
+                        } else {
+                            error!("udp server recv_from failed with error: {}", err);
+                            time::sleep(Duration::from_secs(1)).await;
+                        }
+                        continue;
+                    }
+
+                    let inbound = &mut inbounds[idx];
+                    // Packets received together are relayed in order, as if they were received one by one
+                    for (data, peer_addr, dst_addr, hop_limit) in inbound.received.drain(..) {
+                        if data.is_empty() {
+                            // For windows, it will generate a ICMP Port Unreachable Message
+                            // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-recvfrom
+                            // Which will result in recv_from return 0.
+                            //
+                            // It cannot be solved here, because `WSAGetLastError` is already set.
+                            //
+                            // See `relay::udprelay::utils::create_socket` for more detail.
+                            continue;
+                        }
+
+                        let n = data.len();
+                        if n > self.max_payload_size {
+                            // Truncated packets are never forwarded, they would be garbage to the target's protocol
+                            let oversized = self.oversized.fetch_add(1, Ordering::Relaxed) + 1;
+                            warn!(
+                                "udp packet from {} dropped, larger than {} bytes, {} dropped in total",
+                                peer_addr, self.max_payload_size, oversized
+                            );
+                            continue;
+                        }
+
+                        #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+                        let hop_limit = match hop_limit.map(|h| self.hop_limit_mode.outbound(h)) {
+                            Some(None) => {
+                                trace!("udp packet from {} dropped, hop limit exceeded", peer_addr);
+                                continue;
+                            }
+                            Some(hop_limit) => hop_limit,
+                            None => None,
+                        };
+
+                        if let Err(err) = self
+                            .send_packet(
+                                &inbound.socket,
+                                peer_addr,
+                                dst_addr.unwrap_or(inbound.local_addr),
+                                &balancer,
+                                forward_addrs,
+                                data,
+                                hop_limit,
+                            )
+                            .await
+                        {
+                            error!(
+                                "udp packet relay from {} with {} bytes failed, error: {}",
+                                peer_addr,
+                                n,
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn bind_inbound(&self, client_config: &ServerAddr) -> io::Result<UdpInbound> {
+        let socket = match *client_config {
+            ServerAddr::SocketAddr(ref saddr) => {
+                ShadowUdpSocket::listen_with_opts(saddr, self.context.accept_opts()).await?
+            }
+            ServerAddr::DomainName(ref dname, port) => match self.dns_resolver {
+                Some(ref resolver) => {
+                    let addr = resolve_with(resolver, dname, port, None).await?;
+                    ShadowUdpSocket::listen_with_opts(&addr, self.context.accept_opts()).await?
+                }
+                None => {
+                    lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                        ShadowUdpSocket::listen_with_opts(&addr, self.context.accept_opts()).await
+                    })?
+                    .1
+                }
+            },
+        };
+        let socket: UdpSocket = socket.into();
+
+        let local_addr = socket.local_addr()?;
+        info!("shadowsocks UDP tunnel listening on {}", local_addr);
+
+        #[allow(unused_mut)]
+        let mut receiver = UdpInboundReceiver::new(self.max_payload_size);
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        receiver.set_batch_size(self.mmsg_batch_size);
+        #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+        if self.hop_limit_mode.is_relayed() {
+            set_recv_hop_limit(&socket)?;
+            receiver.set_recv_meta(true);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.transparent {
+            set_transparent(&socket)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.nat_mode != UdpNatMode::Peer || self.transparent {
+            set_recv_destination(&socket)?;
+            receiver.set_recv_meta(true);
+        }
+
+        Ok(UdpInbound {
+            socket: Arc::new(socket),
+            client_config: client_config.clone(),
+            local_addr,
+            receiver,
+            received: Vec::new(),
+        })
+    }
+
+    /// Remove the fatally failed `inbounds[idx]` with associations replying on it, returns its listen address
+    ///
+    /// The associations could never reply again, clients' next packets create new ones on the socket bound again.
+    /// Dropping them releases the address.
+    fn close_inbound(&mut self, inbounds: &mut Vec<UdpInbound>, idx: usize) -> ServerAddr {
+        let inbound = inbounds.swap_remove(idx);
+        let keys = self
+            .assoc_map
+            .peek_iter()
+            .filter(|(_, assoc)| Arc::ptr_eq(&assoc.inbound.get(), &inbound.socket))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.assoc_map.remove(&key);
+        }
+        inbound.client_config
+    }
+
+    /// Bind client sockets of `unbound` again, those failing are kept for the next try
+    async fn rebind_inbounds(&self, inbounds: &mut Vec<UdpInbound>, unbound: &mut Vec<ServerAddr>) {
+        let mut failed = Vec::new();
+        for client_config in unbound.drain(..) {
+            match self.bind_inbound(&client_config).await {
+                Ok(inbound) => inbounds.push(inbound),
+                Err(err) => {
+                    error!(
+                        "udp tunnel failed to listen on {} again, retrying in {:?}, error: {}",
+                        client_config, INBOUND_REBIND_INTERVAL, err
+                    );
+                    failed.push(client_config);
+                }
+            }
+        }
+        *unbound = failed;
+    }
+
+    /// Relay `data` sent from `peer_addr` to `dst_addr` through its association, sent with `hop_limit` if it is set
+    #[allow(clippy::too_many_arguments)]
+    async fn send_packet(
+        &mut self,
+        listener: &Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        balancer: &PingBalancer,
+        forward_addrs: &[Address],
+        data: Bytes,
+        hop_limit: Option<u8>,
+    ) -> io::Result<()> {
+        let key = if self.transparent {
+            AssocKey {
+                peer_addr,
+                target: Some(dst_addr),
+            }
+        } else {
+            AssocKey::new(self.nat_mode, peer_addr, dst_addr)
+        };
+        // Redirected to the listener from another port, responses can't be sent back from the listener
+        let redirected = self.transparent && listener.local_addr()?.port() != dst_addr.port();
+
+        if let Some(assoc) = self.assoc_map.get(&key) {
+            if !redirected && assoc.inbound.switch(listener) {
+                debug!("udp association for {} replies on another socket", peer_addr);
+            }
+            match assoc.send(data.clone(), hop_limit, self.channel_full_policy).await {
+                Ok(..) => return Ok(()),
+                Err(UdpRelaySendError::ChannelClosed) => {
+                    // Association's task is dead, the flow continues in a new association
+                    warn!("udp association for {} exited unexpectedly, recreating", peer_addr);
+                    self.assoc_map.remove(&key);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if let Some(capacity) = self.capacity {
+            if self.assoc_map.len() >= capacity {
+                match self.capacity_mode {
+                    UdpCapacityMode::Evict => {
+                        // Make room for the new association by evicting the most idle one, instead of the LRU one
+                        let evicted = evict_least_active(&mut self.assoc_map, |assoc| assoc.last_active.get());
+                        if let Some((key, ..)) = evicted {
+                            let evicted = self.evicted.fetch_add(1, Ordering::Relaxed) + 1;
+                            debug!(
+                                "udp association for {} is evicted because of capacity limit, {} evicted in total",
+                                key.peer_addr, evicted
+                            );
+                            if let Some(ref hook) = self.eviction_hook {
+                                hook.evicted(key.peer_addr);
+                            }
+                        }
+                    }
+                    UdpCapacityMode::Reject => {
+                        // Idle associations don't count
+                        self.cleanup_idle();
+                        if self.assoc_map.len() >= capacity {
+                            let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                            trace!(
+                                "udp packet from {} is dropped because of capacity limit, {} rejected in total",
+                                peer_addr,
+                                rejected
+                            );
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        let default_addr = &forward_addrs[self.next_forward_idx % forward_addrs.len()];
+        self.next_forward_idx = self.next_forward_idx.wrapping_add(1);
+        // Destinations have ports other than the listening port only if they are redirected
+        let forward_addr = self.forward_rules.forward_addr(dst_addr.port(), default_addr);
+        let ttl = self.ttl_rules.ttl(forward_addr, self.time_to_live);
+        let coalesce = self.coalesce_rules.coalesce(forward_addr);
+
+        let opts = UdpAssociationOptions {
+            context: self.context.clone(),
+            balancer: balancer.clone(),
+            keepalive_tx: self.keepalive_tx.clone(),
+            connect_timeout: self.connect_timeout.clone(),
+            padding: self.padding,
+            ttl,
+            channel_size: self.send_channel_size,
+            pool_size: self.outbound_pool_size,
+            coalesce: coalesce.map(|c| (c, self.coalesced.clone())),
+            mmsg_batch_size: self.mmsg_batch_size,
+            nat_mode: self.nat_mode,
+            // Listeners on unspecified addresses would send responses from the addresses chosen by routing
+            reply_addr: key
+                .target
+                .map(|target| target.ip())
+                .filter(|ip| !redirected && !ip.is_unspecified()),
+            socket_factory: self.socket_factory.clone(),
+            dns_resolver: self.dns_resolver.clone(),
+        };
+        // Responses to redirected packets are sent back from a socket bound on their destination
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let inbound = if redirected {
+            Arc::new(bind_transparent(dst_addr)?)
+        } else {
+            listener.clone()
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let inbound = listener.clone();
+        let assoc = UdpAssociation::new(&opts, inbound, key.clone(), forward_addr.clone(), &self.conntrack);
+
+        debug!(
+            "created udp association for {} -> {}, ttl {:?}",
+            peer_addr, forward_addr, ttl
+        );
+
+        assoc.send(data, hop_limit, self.channel_full_policy).await?;
+        self.assoc_map.insert(key, assoc);
+
+        Ok(())
+    }
+
+    /// Close all associations after relaying packets already queued in their channels
+    ///
+    /// Call it after `run` is stopped. Associations' tasks not finished in `timeout` are aborted, like dropped
+    /// associations. Responses arriving while closing are not sent back.
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        let keys = self
+            .assoc_map
+            .peek_iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let mut assoc_handles = Vec::new();
+        for key in keys {
+            if let Some(assoc) = self.assoc_map.remove(&key) {
+                assoc_handles.extend(assoc.close());
+            }
+        }
+        // Expired ones are not iterated, nothing to relay for them anyway
+        self.assoc_map.clear();
+
+        if assoc_handles.is_empty() {
+            return;
+        }
+
+        debug!("udp tunnel closing {} association tasks", assoc_handles.len());
+        if time::timeout(timeout, future::join_all(assoc_handles.iter_mut()))
+            .await
+            .is_err()
+        {
+            warn!("udp tunnel associations are not closed in {:?}, aborting", timeout);
+            for assoc_handle in &assoc_handles {
+                assoc_handle.abort();
+            }
+        }
+    }
+
+    fn cleanup_idle(&mut self) {
+        let now = self.clock.now();
+        let idle_keys = self
+            .assoc_map
+            .peek_iter()
+            .filter(|(_, assoc)| now.saturating_duration_since(assoc.last_active.get()) > assoc.ttl)
+            .map(|(key, assoc)| (key.clone(), assoc.ttl))
+            .collect::<Vec<_>>();
+
+        for (key, ttl) in idle_keys {
+            trace!("udp association for {} is idle for {:?}", key.peer_addr, ttl);
+            self.assoc_map.remove(&key);
+        }
+    }
+}
+
+/// Options of a new association, taken from the `UdpTunnel` and the rules matching its target
+struct UdpAssociationOptions {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    keepalive_tx: mpsc::Sender<AssocKey>,
+    connect_timeout: ConnectTimeout,
+    padding: Option<UdpPaddingPolicy>,
+    ttl: Duration,
+    // Packets queued for each stripe
+    channel_size: usize,
+    // Stripes, each with its own outbound socket
+    pool_size: usize,
+    coalesce: Option<(UdpCoalesce, Arc<AtomicU64>)>,
+    mmsg_batch_size: usize,
+    nat_mode: UdpNatMode,
+    // Local address that responses are sent back from, the target of the client's packets
+    reply_addr: Option<IpAddr>,
+    socket_factory: Arc<dyn ProxySocketFactory>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+}
+
+struct UdpAssociation {
+    assoc_handles: Vec<JoinHandle<()>>,
+    senders: Vec<UdpChannelSender<QueuedPacket>>,
+    command_senders: Vec<mpsc::Sender<UdpAssocCommand>>,
+    inbound: AssocInbound,
+    next_stripe: AtomicUsize,
+    channel_size: usize,
+    last_active: LastActive,
+    ttl: Duration,
+    _tracker: UdpAssocTracker,
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        for assoc_handle in &self.assoc_handles {
+            assoc_handle.abort();
+        }
+    }
+}
+
+impl UdpAssociation {
+    /// Stop accepting packets, returns handles of tasks relaying the packets already queued
+    fn close(mut self) -> Vec<JoinHandle<()>> {
+        mem::take(&mut self.assoc_handles)
+    }
+
+    fn new(
+        opts: &UdpAssociationOptions,
+        inbound: Arc<UdpSocket>,
+        key: AssocKey,
+        forward_addr: Address,
+        conntrack: &UdpAssocTrack,
+    ) -> UdpAssociation {
+        let last_active = LastActive::new();
+        let traffic = Arc::new(AssocTraffic::new(conntrack.traffic.clone()));
+        let tracker = UdpAssocTracker::new(
+            conntrack.states.clone(),
+            key.clone(),
+            AssocState {
+                forward_addr: forward_addr.clone(),
+                created: Instant::now(),
+                last_active: last_active.clone(),
+                traffic: traffic.clone(),
+            },
+        );
+
+        // Every stripe is a task with its own outbound socket, sharing the association's states
+        let inbound = AssocInbound::from(inbound);
+        let pinned_server = PinnedServer::default();
+        let mut assoc_handles = Vec::with_capacity(opts.pool_size);
+        let mut senders = Vec::with_capacity(opts.pool_size);
+        let mut command_senders = Vec::with_capacity(opts.pool_size);
+        for _ in 0..opts.pool_size {
+            let (assoc_handle, sender, command_sender) = UdpAssociationContext::create(
+                opts,
+                inbound.clone(),
+                key.clone(),
+                forward_addr.clone(),
+                last_active.clone(),
+                traffic.clone(),
+                pinned_server.clone(),
+            );
+            assoc_handles.push(assoc_handle);
+            senders.push(sender);
+            command_senders.push(command_sender);
+        }
+        UdpAssociation {
+            assoc_handles,
+            senders,
+            command_senders,
+            inbound,
+            next_stripe: AtomicUsize::new(0),
+            channel_size: opts.channel_size,
+            last_active,
+            ttl: opts.ttl,
+            _tracker: tracker,
+        }
+    }
+
+    async fn send(
+        &self,
+        data: Bytes,
+        hop_limit: Option<u8>,
+        policy: UdpChannelFullPolicy,
+    ) -> Result<(), UdpRelaySendError> {
+        let stripe = self.next_stripe.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        let sender = &self.senders[stripe];
+
+        // Only packets taken right away keep sharing the receiving arena, backlogged ones would keep arenas alive
+        let data = if sender.capacity() < self.channel_size {
+            Bytes::copy_from_slice(&data)
+        } else {
+            data
+        };
+        send_to_channel(sender, (data, hop_limit), policy).await
+    }
+
+    /// Send `command` to all stripes, returns `false` if none of them accepted it
+    fn command(&self, command: UdpAssocCommand) -> bool {
+        let mut accepted = false;
+        for command_sender in &self.command_senders {
+            match command_sender.try_send(command) {
+                Ok(..) => accepted = true,
+                Err(err) => trace!("udp association command {:?} is not sent, error: {}", command, err),
+            }
+        }
+        accepted
+    }
+}
+
+struct UdpAssociationContext {
+    context: Arc<ServiceContext>,
+    key: AssocKey,
+    peer_addr: SocketAddr,
+    forward_addr: Address,
+    socket_factory: Arc<dyn ProxySocketFactory>,
+    dns_resolver: Option<Arc<DnsResolver>>,
+    proxied_socket: Option<Box<dyn ProxiedSocket>>,
+    keepalive_tx: mpsc::Sender<AssocKey>,
+    keepalive_throttle: KeepAliveThrottle,
+    last_active: LastActive,
+    traffic: Arc<AssocTraffic>,
+    first_packet_time: Option<Instant>,
+    first_response_time: Option<Instant>,
+    balancer: PingBalancer,
+    inbound: AssocInbound,
+    connect_timeout: ConnectTimeout,
+    padding: Option<UdpPaddingPolicy>,
+    pinned_server: PinnedServer,
+    coalesce: Option<(UdpCoalesce, Arc<AtomicU64>)>,
+    // Responses already received are sent back together if it is larger than 1
+    #[cfg_attr(
+        not(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android"))),
+        allow(dead_code)
+    )]
+    mmsg_batch_size: usize,
+    nat_mode: UdpNatMode,
+    // Responses are sent back from it if it is set, instead of the address chosen by routing
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+    reply_addr: Option<IpAddr>,
+    // Hop limit set on the proxied socket, the system's default if it is not set
+    socket_hop_limit: Option<u8>,
+}
+
+impl Drop for UdpAssociationContext {
+    fn drop(&mut self) {
+        debug!("udp association for {} is closed", self.peer_addr);
+    }
+}
+
+impl UdpAssociationContext {
+    fn create(
+        opts: &UdpAssociationOptions,
+        inbound: AssocInbound,
+        key: AssocKey,
+        forward_addr: Address,
+        last_active: LastActive,
+        traffic: Arc<AssocTraffic>,
+        pinned_server: PinnedServer,
+    ) -> (
+        JoinHandle<()>,
+        UdpChannelSender<QueuedPacket>,
+        mpsc::Sender<UdpAssocCommand>,
+    ) {
+        // Pending packets `channel_size` for each association, UDP_ASSOCIATION_SEND_CHANNEL_SIZE by default.
+        // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
+        // being OOM.
+        let (sender, receiver) = udp_channel::channel(opts.channel_size);
+        let (command_sender, command_receiver) = mpsc::channel(UDP_ASSOCIATION_COMMAND_CHANNEL_SIZE);
+
+        let mut assoc = UdpAssociationContext {
+            context: opts.context.clone(),
+            peer_addr: key.peer_addr,
+            key,
+            forward_addr,
+            socket_factory: opts.socket_factory.clone(),
+            dns_resolver: opts.dns_resolver.clone(),
+            proxied_socket: None,
+            keepalive_tx: opts.keepalive_tx.clone(),
+            keepalive_throttle: KeepAliveThrottle::default(),
+            last_active,
+            traffic,
+            first_packet_time: None,
+            first_response_time: None,
+            balancer: opts.balancer.clone(),
+            inbound,
+            connect_timeout: opts.connect_timeout.clone(),
+            padding: opts.padding,
+            pinned_server,
+            coalesce: opts.coalesce.clone(),
+            mmsg_batch_size: opts.mmsg_batch_size,
+            nat_mode: opts.nat_mode,
+            reply_addr: opts.reply_addr,
+            socket_hop_limit: None,
+        };
+        // Zeroed allocation, instead of zeroing it by `resize`, pages not used by responses may not be touched at all.
+        // It has to be initialized, responses are decrypted in place.
+        let proxied_buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let handle =
+            tokio::spawn(async move { assoc.dispatch_packet(receiver, command_receiver, proxied_buffer).await });
+
+        (handle, sender, command_sender)
+    }
+
+    async fn dispatch_packet(
+        &mut self,
+        mut receiver: UdpChannelReceiver<QueuedPacket>,
+        mut command_receiver: mpsc::Receiver<UdpAssocCommand>,
+        mut proxied_buffer: Vec<u8>,
+    ) {
+        loop {
+            tokio::select! {
+                // Commands are sent before the packets that they apply to
+                biased;
+
+                Some(command) = command_receiver.recv() => {
+                    match command {
+                        UdpAssocCommand::RebindServer => {
+                            debug!("udp association for {} -> {} rebinding server", self.peer_addr, self.forward_addr);
+                            self.unpin_server();
+                            self.traffic.set_upstream_state(UdpUpstreamState::Reconnecting);
+                        }
+                        UdpAssocCommand::Close => {
+                            // Senders are dropped with the association, the channel ends after the queued packets
+                            receiver.close();
+                        }
+                    }
+                }
+
+                packet_received_opt = receiver.recv() => {
+                    let (data, hop_limit) = match packet_received_opt {
+                        Some(d) => d,
+                        None => {
+                            trace!("udp association for {} -> ... channel closed", self.peer_addr);
+                            break;
+                        }
+                    };
+
+                    self.dispatch_received_packet(&data, hop_limit).await;
+                }
+
+                received_opt = receive_from_proxied_opt(&self.proxied_socket, &mut proxied_buffer) => {
+                    let (n, addr) = match received_opt {
+                        Ok(r) => r,
+                        Err(err) => {
+                            error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                            // Socket failure. Reset for recreation.
+                            self.reset_proxied_socket();
+                            continue;
+                        }
+                    };
+
+                    if !self.accepts_response(&addr) {
+                        continue;
+                    }
+
+                    let data = match unpad_response(self.padding.is_some(), &proxied_buffer[..n]) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("udp relay {} <- {} failed, error: {}", self.peer_addr, addr, err);
+                            continue;
+                        }
+                    };
+
+                    match self.coalesce {
+                        Some((ref coalesce, ..)) if data.len() <= coalesce.max_size => {
+                            let max_size = coalesce.max_size;
+                            let mut batch = vec![Bytes::copy_from_slice(data)];
+                            let (large, failed) =
+                                self.take_ready_responses(&mut proxied_buffer, &mut batch, MAX_COALESCE_BATCH, max_size);
+
+                            let batch = self.coalesce_responses(&addr, batch);
+                            self.send_received_respond_packets(&addr, &batch).await;
+                            if let Some(data) = large {
+                                self.send_received_respond_packet(&addr, &data).await;
+                            }
+                            if let Some(err) = failed {
+                                error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                                self.reset_proxied_socket();
+                            }
+                        }
+                        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+                        _ if self.mmsg_batch_size > 1 => {
+                            let mut batch = vec![Bytes::copy_from_slice(data)];
+                            let (_, failed) = self.take_ready_responses(
+                                &mut proxied_buffer,
+                                &mut batch,
+                                self.mmsg_batch_size,
+                                usize::MAX,
+                            );
+
+                            self.send_received_respond_packets(&addr, &batch).await;
+                            if let Some(err) = failed {
+                                error!("udp relay {} <- ... failed, error: {}", self.peer_addr, err);
+                                self.reset_proxied_socket();
+                            }
+                        }
+                        _ => self.send_received_respond_packet(&addr, data).await,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take responses already received by the proxied socket into `batch`, without waiting for more
+    ///
+    /// Taking stops once `batch` has `batch_size` responses, or at a response larger than `max_size`, which is
+    /// returned. An error of the socket is returned after the responses taken before it.
+    fn take_ready_responses(
+        &self,
+        proxied_buffer: &mut [u8],
+        batch: &mut Vec<Bytes>,
+        batch_size: usize,
+        max_size: usize,
+    ) -> (Option<Bytes>, Option<io::Error>) {
+        while batch.len() < batch_size {
+            let received = receive_from_proxied_opt(&self.proxied_socket, proxied_buffer);
+            let (n, addr) = match received.now_or_never() {
+                Some(Ok(r)) => r,
+                Some(Err(err)) => return (None, Some(err)),
+                None => break,
+            };
+            if !self.accepts_response(&addr) {
+                continue;
+            }
+            let data = match unpad_response(self.padding.is_some(), &proxied_buffer[..n]) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("udp relay {} <- {} failed, error: {}", self.peer_addr, addr, err);
+                    continue;
+                }
+            };
+            if data.len() > max_size {
+                return (Some(Bytes::copy_from_slice(data)), None);
+            }
+            batch.push(Bytes::copy_from_slice(data));
+        }
+        (None, None)
+    }
+
+    /// Check if a response from `addr` is sent back to client, by `UdpNatMode`
+    fn accepts_response(&self, addr: &Address) -> bool {
+        if self.nat_mode.accepts_response(&self.forward_addr, addr) {
+            return true;
+        }
+
+        trace!(
+            "udp relay {} <- {} dropped, not from the target {} in {} mode",
+            self.peer_addr,
+            addr,
+            self.forward_addr,
+            self.nat_mode
+        );
+        false
+    }
+
+    async fn dispatch_received_packet(&mut self, data: &[u8], hop_limit: Option<u8>) {
+        trace!(
+            "udp relay {} -> {} with {} bytes",
+            self.peer_addr,
+            self.forward_addr,
+            data.len()
+        );
+
+        if self.first_packet_time.is_none() {
+            self.first_packet_time = Some(Instant::now());
+        }
+
+        if let Err(err) = self.dispatch_received_proxied_packet(data, hop_limit).await {
+            if self.context.conn_error_log_throttle().should_log(&self.forward_addr) {
+                error!(
+                    "udp relay {} -> {} with {} bytes, error: {}",
+                    self.peer_addr,
+                    self.forward_addr,
+                    data.len(),
+                    err
+                );
+            }
+        }
+    }
+
+    async fn dispatch_received_proxied_packet(&mut self, data: &[u8], hop_limit: Option<u8>) -> io::Result<()> {
+        let socket = match self.proxied_socket {
+            Some(ref mut socket) => socket,
+            None => {
+                // Create a new connection to proxy server
+
+                // Reconnect to the pinned server, unless it was removed
+                let pinned_server = self
+                    .pinned_server
+                    .lock()
+                    .clone()
+                    .and_then(|addr| self.balancer.find_server(&addr));
+                let server = match pinned_server {
+                    Some(server) => server,
+                    None => {
+                        // UDP tunnel doesn't support sending packets directly, so they are rejected instead of bypassed
+                        let cx = ServerSelectContext::new(ServerType::Udp, self.peer_addr, &self.forward_addr);
+                        match self.balancer.select_server(&cx, &AvailableServerSelector)? {
+                            Some(server) => server,
+                            None => return Err(ServiceUnavailableError::new(ServerType::Udp).into()),
+                        }
+                    }
+                };
+                let svr_cfg = server.server_config();
+
+                // A slow server shouldn't stall the association, packets are dropped until it is connected
+                let connect = async {
+                    match self.dns_resolver {
+                        Some(ref resolver) => {
+                            let svr_cfg = resolve_server_config_with(resolver, svr_cfg).await?;
+                            self.socket_factory.connect(&self.context, &svr_cfg).await
+                        }
+                        None => self.socket_factory.connect(&self.context, svr_cfg).await,
+                    }
+                };
+                let mut socket = match time::timeout(self.connect_timeout.timeout, connect).await {
+                    Ok(Ok(socket)) => {
+                        server.udp_score().report_connect(true);
+                        self.traffic.set_upstream_state(UdpUpstreamState::Connected);
+                        socket
+                    }
+                    Ok(Err(err)) => {
+                        server.udp_score().report_connect(false);
+                        self.unpin_server();
+                        self.traffic.set_upstream_state(UdpUpstreamState::Failed);
+                        return Err(err);
+                    }
+                    Err(..) => {
+                        server.udp_score().report_connect(false);
+                        self.unpin_server();
+                        self.traffic.set_upstream_state(UdpUpstreamState::Failed);
+                        let dropped = self.connect_timeout.drop_packet();
+                        let err = io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!(
+                                "connect server {} timed out after {:?}, packet dropped ({} dropped by connect timeout)",
+                                svr_cfg.addr(),
+                                self.connect_timeout.timeout,
+                                dropped
+                            ),
+                        );
+                        return Err(err);
+                    }
+                };
+                socket.set_last_active(self.last_active.clone());
+                socket.set_padded(self.padding.is_some());
+
+                *self.pinned_server.lock() = Some(svr_cfg.addr().clone());
+
+                self.socket_hop_limit = None;
+                self.proxied_socket.insert(socket)
+            }
+        };
+
+        // Sockets keep the hop limit, it is only set again if it changes
+        if let Some(hop_limit) = hop_limit {
+            if self.socket_hop_limit != Some(hop_limit) {
+                match socket.set_hop_limit(hop_limit) {
+                    Ok(..) => self.socket_hop_limit = Some(hop_limit),
+                    Err(err) => trace!(
+                        "udp relay {} -> {} couldn't set hop limit {}, error: {}",
+                        self.peer_addr,
+                        self.forward_addr,
+                        hop_limit,
+                        err
+                    ),
+                }
+            }
+        }
+
+        let payload_len = data.len();
+        let padded;
+        let data = match self.padding {
+            None => data,
+            Some(ref padding) => {
+                padded = padding.pad(data)?;
+                &padded
+            }
+        };
+
+        // A packet maps to exactly one packet to the server, it is never split
+        if let Some(max_size) = socket.max_payload_size(&self.forward_addr) {
+            if data.len() > max_size {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "udp packet with {} bytes dropped, larger than {} bytes that the server's protocol carries in one packet",
+                        data.len(),
+                        max_size
+                    ),
+                ));
+            }
+        }
+
+        match socket.send(&self.forward_addr, data).await {
+            Ok(..) => {
+                self.traffic.add_tx(payload_len);
+                return Ok(());
+            }
+            // Only this packet couldn't be sent, the socket is kept
+            Err(err) if is_oversized_send_error(&err) => return Err(err),
+            Err(err) => {
+                debug!(
+                    "{} -> {} (proxied) sending {} bytes failed, error: {}",
+                    self.peer_addr,
+                    self.forward_addr,
+                    data.len(),
+                    err
+                );
+
+                // Drop the socket and reconnect to the pinned server.
+                self.reset_proxied_socket();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reset_proxied_socket(&mut self) {
+        self.proxied_socket = None;
+        self.traffic.set_upstream_state(UdpUpstreamState::Reconnecting);
+    }
+
+    fn unpin_server(&mut self) {
+        self.proxied_socket = None;
+
+        // Stripes choose a new server together
+        *self.pinned_server.lock() = None;
+    }
+
+    fn keep_alive(&mut self) {
+        // Keep association alive in map, refreshes are coalesced to avoid flooding the manager
+        if !self.keepalive_throttle.should_refresh() {
+            return;
+        }
+
+        if let Err(..) = self.keepalive_tx.try_send(self.key.clone()) {
+            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
+        } else {
+            self.keepalive_throttle.refreshed();
+        }
+    }
+
+    fn coalesce_responses(&self, addr: &Address, batch: Vec<Bytes>) -> Vec<Bytes> {
+        let (coalesce, coalesced) = match self.coalesce {
+            Some(ref c) => c,
+            None => return batch,
+        };
+
+        let received = batch.len();
+        let merged = coalesce.hook.coalesce(batch);
+        if merged.len() < received {
+            coalesced.fetch_add((received - merged.len()) as u64, Ordering::Relaxed);
+            trace!(
+                "udp relay {} <- {} coalesced {} responses into {}",
+                self.peer_addr,
+                addr,
+                received,
+                merged.len()
+            );
+        }
+        merged
+    }
+
+    async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) {
+        trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
+
+        self.keep_alive();
+
+        // Send back to client, a response maps to exactly one packet
+        match self.send_to_peer(data).await {
+            Ok(n) if n == data.len() => self.respond_packet_sent(addr, n),
+            Ok(n) => warn!(
+                "udp sent back only {} of {} bytes to client {}, from target {}",
+                n,
+                data.len(),
+                self.peer_addr,
+                addr
+            ),
+            Err(err) => warn!(
+                "udp failed to send back {} bytes to client {}, from target {}, error: {}",
+                data.len(),
+                self.peer_addr,
+                addr,
+                err
+            ),
+        }
+    }
+
+    /// Send `data` back to client, from `reply_addr` if it is set
+    async fn send_to_peer(&self, data: &[u8]) -> io::Result<usize> {
+        let inbound = self.inbound.get();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(reply_addr) = self.reply_addr {
+            return send_to_from(&inbound, data, self.peer_addr, reply_addr).await;
+        }
+        inbound.send_to(data, self.peer_addr).await
+    }
+
+    /// Send `batch` back to client in order, together by `sendmmsg` if batching is enabled
+    ///
+    /// Responses sent from `reply_addr` are sent one by one.
+    async fn send_received_respond_packets(&mut self, addr: &Address, batch: &[Bytes]) {
+        #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+        if batch.len() > 1 && self.mmsg_batch_size > 1 && self.reply_addr.is_none() {
+            trace!(
+                "udp relay {} <- {} received {} packets",
+                self.peer_addr,
+                addr,
+                batch.len()
+            );
+
+            self.keep_alive();
+
+            let inbound = self.inbound.get();
+            let mut rest = batch;
+            while !rest.is_empty() {
+                let chunk = &rest[..rest.len().min(self.mmsg_batch_size)];
+                match send_to_batch(&inbound, chunk, self.peer_addr).await {
+                    Ok(n) => {
+                        for data in &chunk[..n] {
+                            self.respond_packet_sent(addr, data.len());
+                        }
+                        rest = &rest[n.max(1)..];
+                    }
+                    Err(err) => {
+                        // Only the first one failed, the rest are retried
+                        warn!(
+                            "udp failed to send back {} bytes to client {}, from target {}, error: {}",
+                            rest[0].len(),
+                            self.peer_addr,
+                            addr,
+                            err
+                        );
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            return;
+        }
+
+        for data in batch {
+            self.send_received_respond_packet(addr, data).await;
+        }
+    }
+
+    fn respond_packet_sent(&mut self, addr: &Address, n: usize) {
+        self.traffic.add_rx(n);
+        trace!("udp relay {} <- {} with {} bytes", self.peer_addr, addr, n);
+
+        if self.first_response_time.is_none() {
+            self.record_first_response();
+        }
+    }
+
+    fn record_first_response(&mut self) {
+        let now = Instant::now();
+        self.first_response_time = Some(now);
+
+        if let Some(first_packet_time) = self.first_packet_time {
+            // Stripes of the association start later than the first one
+            let mut first_response_latency = self.traffic.first_response_latency.lock();
+            if first_response_latency.is_some() {
+                return;
+            }
+
+            let latency = now.saturating_duration_since(first_packet_time);
+            *first_response_latency = Some(latency);
+            debug!(
+                "udp association for {} -> {} got its first response in {:?}",
+                self.peer_addr, self.forward_addr, latency
+            );
+        }
+    }
+}
+
+#[inline]
+async fn receive_from_proxied_opt(
+    socket: &Option<Box<dyn ProxiedSocket>>,
+    buf: &mut [u8],
+) -> io::Result<(usize, Address)> {
+    match *socket {
+        None => future::pending().await,
+        Some(ref s) => s.recv(buf).await,
+    }
+}
+
+/// Strip padding of a response if the association pads its packets
+fn unpad_response(padded: bool, data: &[u8]) -> io::Result<&[u8]> {
+    if padded {
+        strip_udp_padding(data)
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use async_trait::async_trait;
+    use shadowsocks::{
+        config::{Mode, ServerConfig},
+        crypto::v1::CipherKind,
+        dns_resolver::DnsResolve,
+        relay::udprelay::{ProxySocket, MAXIMUM_UDP_DATAGRAM_SIZE_V4},
+    };
+    use tokio::time::Instant;
+
+    use crate::{
+        local::{
+            loadbalancing::{PingBalancerBuilder, ServerIdent, ServerSelector},
+            tunnel::UdpResponseCoalesce,
+        },
+        net::clock::ManualClock,
+    };
+
+    use super::*;
+
+    /// Resolver of a server that never answers in time
+    struct SlowResolver;
+
+    #[async_trait]
+    impl DnsResolve for SlowResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            time::sleep(Duration::from_secs(5)).await;
+            Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+        }
+    }
+
+    /// Resolver of every name to the loopback address
+    struct LoopbackResolver;
+
+    #[async_trait]
+    impl DnsResolve for LoopbackResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+        }
+    }
+
+    #[test]
+    fn forward_rules_by_port() {
+        let default_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let quic_addr = Address::DomainNameAddress("quic.example.com".to_owned(), 443);
+
+        let mut rules = UdpForwardRules::new();
+        rules.add_port_rule(53, dns_addr.clone());
+        rules.add_port_rule(443, quic_addr.clone());
+
+        assert_eq!(rules.forward_addr(53, &default_addr), &dns_addr);
+        assert_eq!(rules.forward_addr(443, &default_addr), &quic_addr);
+        assert_eq!(rules.forward_addr(8080, &default_addr), &default_addr);
+        assert_eq!(UdpForwardRules::new().forward_addr(53, &default_addr), &default_addr);
+    }
+
+    /// Association of `peer_addr` to 8.8.8.8:53 with default options, tests override the fields they need
+    fn test_assoc_context(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        inbound: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        keepalive_tx: mpsc::Sender<AssocKey>,
+    ) -> UdpAssociationContext {
+        UdpAssociationContext {
+            context,
+            key: AssocKey::from(peer_addr),
+            peer_addr,
+            forward_addr: Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53)),
+            socket_factory: Arc::new(DefaultProxySocketFactory),
+            dns_resolver: None,
+            proxied_socket: None,
+            keepalive_tx,
+            keepalive_throttle: KeepAliveThrottle::default(),
+            last_active: LastActive::new(),
+            traffic: Arc::new(AssocTraffic::default()),
+            first_packet_time: None,
+            first_response_time: None,
+            balancer,
+            inbound: inbound.into(),
+            connect_timeout: ConnectTimeout::new(DEFAULT_UDP_CONNECT_TIMEOUT),
+            padding: None,
+            pinned_server: PinnedServer::default(),
+            coalesce: None,
+            mmsg_batch_size: 1,
+            nat_mode: UdpNatMode::Peer,
+            reply_addr: None,
+            socket_hop_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_rules_route_by_listener() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let mut listeners = Vec::new();
+        for _ in 0..2 {
+            listeners.push(Arc::new(
+                UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                    .await
+                    .unwrap(),
+            ));
+        }
+        let dns_port = listeners[0].local_addr().unwrap().port();
+        let other_port = listeners[1].local_addr().unwrap().port();
+
+        // Only the first listener has a rule
+        let default_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5353));
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 53));
+        let mut forward_rules = UdpForwardRules::new();
+        forward_rules.add_port_rule(dns_port, dns_addr.clone());
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        tunnel.set_forward_rules(forward_rules);
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let routes = [
+            (&listeners[0], dns_port, 50000, &dns_addr),
+            (&listeners[1], other_port, 50001, &default_addr),
+        ];
+        for (listener, listen_port, peer_port, target) in routes {
+            tunnel
+                .send_packet(
+                    listener,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), peer_port),
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port),
+                    &balancer,
+                    slice::from_ref(&default_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+                .unwrap();
+
+            let (n, _, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .expect("packet not relayed")
+                .unwrap();
+            assert_eq!((&buffer[..n], &addr), (&b"payload"[..], target));
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn transparent_forward_rules_route_by_original_destination() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks, reporting targets of packets
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+        let (target_tx, mut target_rx) = mpsc::unbounded_channel();
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let (n, src_addr, addr, ..) = server.recv_from(&mut buffer).await.unwrap();
+                server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+                let _ = target_tx.send(addr);
+            }
+        });
+
+        let listener = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        set_transparent(&listener).unwrap();
+        let listener = Arc::new(listener);
+
+        // Original destinations redirected by TPROXY, 127.0.0.2 and 127.0.0.3 are loopback addresses too
+        let free_port = |ip: Ipv4Addr| {
+            std::net::UdpSocket::bind(SocketAddr::new(ip.into(), 0))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let dns_dst = free_port(Ipv4Addr::new(127, 0, 0, 2));
+        let other_dst = free_port(Ipv4Addr::new(127, 0, 0, 3));
+
+        let default_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5353));
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 53));
+        let mut forward_rules = UdpForwardRules::new();
+        forward_rules.add_port_rule(dns_dst.port(), dns_addr.clone());
+        let mut tunnel = UdpTunnelBuilder::new()
+            .forward_rules(forward_rules)
+            .transparent(true)
+            .build(context);
+
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        for (dst_addr, target) in [(dns_dst, &dns_addr), (other_dst, &default_addr)] {
+            tunnel
+                .send_packet(
+                    &listener,
+                    client.local_addr().unwrap(),
+                    dst_addr,
+                    &balancer,
+                    slice::from_ref(&default_addr),
+                    Bytes::from_static(b"request"),
+                    None,
+                )
+                .await
+                .unwrap();
+
+            let forwarded = time::timeout(Duration::from_secs(1), target_rx.recv())
+                .await
+                .expect("packet not relayed")
+                .unwrap();
+            assert_eq!(&forwarded, target);
+
+            // Replied from the destination that the client intended to reach
+            let (n, src_addr) = time::timeout(Duration::from_secs(1), client.recv_from(&mut buffer))
+                .await
+                .expect("response not sent back")
+                .unwrap();
+            assert_eq!((&buffer[..n], src_addr), (&b"request"[..], dst_addr));
+        }
+
+        echo.abort();
+    }
+
+    async fn local_balancer(context: Arc<ServiceContext>) -> PingBalancer {
+        let mut builder = PingBalancerBuilder::new(context, Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8388),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        ));
+        builder.build().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn ttl_rules_override_expiry() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let default_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+
+        let mut forward_rules = UdpForwardRules::new();
+        forward_rules.add_port_rule(53, dns_addr.clone());
+
+        let short_ttl = Duration::from_millis(100);
+        let mut ttl_rules = UdpTtlRules::new();
+        ttl_rules.add_rule(AddressRules::from_lines("test", ["8.8.8.8"]).unwrap(), short_ttl);
+        assert_eq!(ttl_rules.ttl(&dns_addr, Duration::from_secs(10)), short_ttl);
+        assert_eq!(
+            ttl_rules.ttl(&default_addr, Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+
+        let mut tunnel = UdpTunnel::new(context, Some(Duration::from_secs(10)), None, UdpCapacityMode::Evict);
+        tunnel.set_forward_rules(forward_rules);
+        tunnel.set_ttl_rules(ttl_rules);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let dns_peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let other_peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001);
+        tunnel
+            .send_packet(
+                &listener,
+                dns_peer,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53),
+                &balancer,
+                slice::from_ref(&default_addr),
+                Bytes::from_static(b"query"),
+                None,
+            )
+            .await
+            .unwrap();
+        tunnel
+            .send_packet(
+                &listener,
+                other_peer,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&default_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        tunnel.cleanup_idle();
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(dns_peer)).is_some());
+
+        time::sleep(short_ttl * 2).await;
+        tunnel.cleanup_idle();
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(dns_peer)).is_none());
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(other_peer)).is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_expired_by_manual_clock() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+
+        let ttl = Duration::from_secs(600);
+        let clock = ManualClock::new();
+        let mut tunnel = UdpTunnelBuilder::new()
+            .time_to_live(Some(ttl))
+            .clock(Arc::new(clock.clone()))
+            .build(context);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        tunnel
+            .send_packet(
+                &listener,
+                peer_addr,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        clock.advance(ttl / 2);
+        tunnel.cleanup_idle();
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peer_addr)).is_some());
+
+        clock.advance(ttl);
+        tunnel.cleanup_idle();
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peer_addr)).is_none());
+    }
+
+    #[tokio::test]
+    async fn cleanup_interval_independent_of_ttl() {
+        // Scaled down from a 10s TTL swept every second
+        let ttl = Duration::from_secs(2);
+        let cleanup_interval = Duration::from_millis(200);
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let mut tunnel = UdpTunnel::new(context, Some(ttl), None, UdpCapacityMode::Evict);
+        assert_eq!(tunnel.cleanup_interval(), ttl);
+        tunnel.set_cleanup_interval(cleanup_interval);
+        assert_eq!(tunnel.cleanup_interval(), cleanup_interval);
+        let conntrack = tunnel.conntrack();
+
+        let listen_addr = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353));
+        let server = tokio::spawn(async move {
+            tunnel
+                .run(&ServerAddr::SocketAddr(listen_addr), balancer, &forward_addr)
+                .await
+        });
+
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while conntrack.entries().is_empty() {
+            assert!(Instant::now() < deadline, "association not created");
+            client.send_to(b"payload", listen_addr).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let last_active = Instant::now();
+
+        // Dropped within a few sweeps after expiry, instead of up to another TTL later
+        while !conntrack.entries().is_empty() {
+            assert!(
+                last_active.elapsed() < ttl + cleanup_interval * 3,
+                "idle association not dropped"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(last_active.elapsed() >= ttl - cleanup_interval);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn replies_on_receiving_socket() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let (n, src_addr, addr, ..) = server.recv_from(&mut buffer).await.unwrap();
+                server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        let conntrack = tunnel.conntrack();
+
+        let listen_addrs = (0..2)
+            .map(|_| {
+                std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let client_configs = listen_addrs
+            .iter()
+            .map(|addr| ServerAddr::SocketAddr(*addr))
+            .collect::<Vec<_>>();
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353));
+        let relay = tokio::spawn(async move {
+            tunnel
+                .run_multi(&client_configs, balancer, slice::from_ref(&forward_addr))
+                .await
+        });
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            clients.push(
+                UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        async fn echoed(client: &UdpSocket, listen_addr: SocketAddr, payload: &[u8]) -> SocketAddr {
+            let mut buffer = [0u8; 64];
+            let deadline = Instant::now() + Duration::from_secs(1);
+            loop {
+                assert!(Instant::now() < deadline, "packet not echoed");
+                client.send_to(payload, listen_addr).await.unwrap();
+                if let Ok(result) = time::timeout(Duration::from_millis(50), client.recv_from(&mut buffer)).await {
+                    let (n, src_addr) = result.unwrap();
+                    assert_eq!(&buffer[..n], payload);
+                    return src_addr;
+                }
+            }
+        }
+
+        // Every client is replied by the socket that it sent to
+        assert_eq!(echoed(&clients[0], listen_addrs[0], b"first").await, listen_addrs[0]);
+        assert_eq!(echoed(&clients[1], listen_addrs[1], b"second").await, listen_addrs[1]);
+        assert_eq!(conntrack.entries().len(), 2);
+
+        // A client moving to another socket keeps its association, replied by the new socket
+        assert_eq!(echoed(&clients[0], listen_addrs[1], b"moved").await, listen_addrs[1]);
+        assert_eq!(conntrack.entries().len(), 2);
+
+        relay.abort();
+        echo.abort();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn symmetric_keyed_by_destination() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let (n, src_addr, addr, ..) = server.recv_from(&mut buffer).await.unwrap();
+                server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        tunnel.set_nat_mode(UdpNatMode::Symmetric);
+        let conntrack = tunnel.conntrack();
+
+        // Listening on all addresses, 127.0.0.2 is a loopback address too
+        let port = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client_config = ServerAddr::SocketAddr(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port));
+        let forward_addrs = [
+            Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353)),
+            Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5354)),
+        ];
+        let relay = {
+            let forward_addrs = forward_addrs.clone();
+            tokio::spawn(async move {
+                tunnel
+                    .run_multi(slice::from_ref(&client_config), balancer, &forward_addrs)
+                    .await
+            })
+        };
+
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        for ip in [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)] {
+            let dst_addr = SocketAddr::new(ip.into(), port);
+            let deadline = Instant::now() + Duration::from_secs(1);
+            let src_addr = loop {
+                assert!(Instant::now() < deadline, "packet not echoed");
+                client.send_to(b"request", dst_addr).await.unwrap();
+                if let Ok(result) = time::timeout(Duration::from_millis(50), client.recv_from(&mut buffer)).await {
+                    let (n, src_addr) = result.unwrap();
+                    assert_eq!(&buffer[..n], b"request");
+                    break src_addr;
+                }
+            };
+            // Replied from the destination that the client sent to
+            assert_eq!(src_addr, dst_addr);
+        }
+
+        // An association for each destination, distributed across forward addresses in round-robin
+        let mut forwarded = conntrack
+            .entries()
+            .into_iter()
+            .map(|entry| entry.forward_addr)
+            .collect::<Vec<_>>();
+        forwarded.sort_by_key(|addr| addr.port());
+        assert_eq!(forwarded, forward_addrs);
+
+        relay.abort();
+        echo.abort();
+    }
+
+    #[tokio::test]
+    async fn oversized_packets_dropped() {
+        const MAX_PAYLOAD_SIZE: usize = 1024;
+
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let mut tunnel = UdpTunnelBuilder::new()
+            .max_payload_size(Some(MAX_PAYLOAD_SIZE))
+            .build(context);
+        let oversized = tunnel.oversized.clone();
+
+        let listen_addr = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353));
+        let relay = tokio::spawn(async move {
+            tunnel
+                .run(&ServerAddr::SocketAddr(listen_addr), balancer, &forward_addr)
+                .await
+        });
+
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let n = loop {
+            assert!(Instant::now() < deadline, "packet not relayed");
+            client
+                .send_to(&[0xCD; MAX_PAYLOAD_SIZE + 1], listen_addr)
+                .await
+                .unwrap();
+            client.send_to(&[0xAB; MAX_PAYLOAD_SIZE], listen_addr).await.unwrap();
+            if let Ok(result) = time::timeout(Duration::from_millis(50), server.recv_from(&mut buffer)).await {
+                break result.unwrap().0;
+            }
+        };
+
+        // Only the packet of exactly the maximum size is forwarded, never a truncated one
+        assert_eq!(n, MAX_PAYLOAD_SIZE);
+        assert!(buffer[..n].iter().all(|&b| b == 0xAB));
+        assert!(oversized.load(Ordering::Relaxed) >= 1);
+        while let Ok(result) = time::timeout(Duration::from_millis(50), server.recv_from(&mut buffer)).await {
+            let n = result.unwrap().0;
+            assert_eq!(n, MAX_PAYLOAD_SIZE);
+            assert!(buffer[..n].iter().all(|&b| b == 0xAB));
+        }
+
+        relay.abort();
+    }
+
+    #[cfg(all(feature = "local-udp-hop-limit", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn hop_limit_decremented() {
+        use super::super::hop_limit::set_hop_limit;
+
+        let context = Arc::new(ServiceContext::new());
+
+        // Packets are only checked for their hop limits, they aren't decrypted
+        let server = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        set_recv_hop_limit(&server).unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let mut tunnel = UdpTunnelBuilder::new()
+            .hop_limit_mode(UdpHopLimitMode::Decrement)
+            .build(context);
+
+        let listen_addr = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353));
+        let relay = tokio::spawn(async move {
+            tunnel
+                .run(&ServerAddr::SocketAddr(listen_addr), balancer, &forward_addr)
+                .await
+        });
+
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let deadline = Instant::now() + Duration::from_secs(1);
+        set_hop_limit(&client, 10).unwrap();
+        let hop_limit = loop {
+            assert!(Instant::now() < deadline, "packet not relayed");
+            client.send_to(b"query", listen_addr).await.unwrap();
+            let recv = recv_from_with_meta(&server, &mut buffer);
+            if let Ok(result) = time::timeout(Duration::from_millis(50), recv).await {
+                break result.unwrap().2.hop_limit;
+            }
+        };
+        assert_eq!(hop_limit, Some(9));
+        while time::timeout(Duration::from_millis(50), recv_from_with_meta(&server, &mut buffer))
+            .await
+            .is_ok()
+        {}
+
+        // Expired packets are dropped, the following ones are relayed with their own hop limits
+        set_hop_limit(&client, 1).unwrap();
+        client.send_to(b"expired", listen_addr).await.unwrap();
+        set_hop_limit(&client, 20).unwrap();
+        client.send_to(b"query", listen_addr).await.unwrap();
+        let (_, _, meta) = time::timeout(Duration::from_secs(1), recv_from_with_meta(&server, &mut buffer))
+            .await
+            .expect("packet not relayed")
+            .unwrap();
+        assert_eq!(meta.hop_limit, Some(19));
+
+        relay.abort();
+    }
+
+    #[tokio::test]
+    async fn associations_snapshot() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        let conntrack = tunnel.conntrack();
+        assert!(tunnel.associations().is_empty());
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peers = [
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001),
+        ];
+        for peer_addr in peers {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut entries = tunnel.associations();
+        entries.sort_by_key(|e| e.peer_addr);
+        assert_eq!(entries.len(), 2);
+        for (entry, peer_addr) in entries.iter().zip(peers) {
+            assert_eq!(entry.peer_addr, peer_addr);
+            assert_eq!(entry.forward_addr, forward_addr);
+            assert_eq!(entry.rx, 0);
+            assert!(entry.last_active <= Instant::now().into_std());
+        }
+
+        // Sent to the server by the association's task
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while conntrack.entries().iter().any(|e| e.tx != b"payload".len() as u64) {
+            assert!(Instant::now() < deadline, "association's sent bytes are not counted");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Removed associations are removed from the snapshot
+        tunnel.assoc_map.remove(&AssocKey::from(peers[0]));
+        let entries = conntrack.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].peer_addr, peers[1]);
+    }
+
+    #[tokio::test]
+    async fn association_counts_and_traffic() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        let conntrack = tunnel.conntrack();
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let clients = [
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        ];
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for (i, client) in clients.iter().enumerate() {
+            tunnel
+                .send_packet(
+                    &listener,
+                    client.local_addr().unwrap(),
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"request"),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(tunnel.association_count(), i + 1);
+
+            // Every client gets a response through its own association
+            let (_, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            server.send_to(src_addr, &addr, b"response").await.unwrap();
+            let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buffer[..n], b"response");
+        }
+        assert_eq!(conntrack.len(), 2);
+
+        // Counted by associations' tasks after sending
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while conntrack.tx() != 2 * b"request".len() as u64 || conntrack.rx() != 2 * b"response".len() as u64 {
+            assert!(Instant::now() < deadline, "relayed bytes are not counted");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Totals are kept after associations are dropped
+        tunnel
+            .assoc_map
+            .remove(&AssocKey::from(clients[0].local_addr().unwrap()));
+        assert_eq!(tunnel.association_count(), 1);
+        assert_eq!(conntrack.len(), 1);
+        tunnel.assoc_map.clear();
+        assert_eq!(tunnel.association_count(), 0);
+        assert!(conntrack.is_empty());
+        assert_eq!(conntrack.tx(), 2 * b"request".len() as u64);
+        assert_eq!(conntrack.rx(), 2 * b"response".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn traffic_counted_by_peer() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let clients = [
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        ];
+
+        // Client i sends i + 1 requests of 10 * (i + 1) bytes, each of them is answered with 100 bytes
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for (i, client) in clients.iter().enumerate() {
+            let request = vec![0u8; 10 * (i + 1)];
+            for _ in 0..=i {
+                tunnel
+                    .send_packet(
+                        &listener,
+                        client.local_addr().unwrap(),
+                        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                        &balancer,
+                        slice::from_ref(&forward_addr),
+                        Bytes::copy_from_slice(&request),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+
+                let (n, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(n, request.len());
+                server.send_to(src_addr, &addr, &[0u8; 100]).await.unwrap();
+                time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+        }
+
+        let expected = clients
+            .iter()
+            .enumerate()
+            .map(|(i, client)| {
+                let packets = i as u64 + 1;
+                let traffic = PeerTraffic {
+                    tx_bytes: 10 * packets * packets,
+                    rx_bytes: 100 * packets,
+                    tx_packets: packets,
+                    rx_packets: packets,
+                };
+                (client.local_addr().unwrap(), traffic)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // Counted by associations' tasks after sending
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while tunnel.traffic_by_peer() != expected {
+            assert!(Instant::now() < deadline, "relayed traffic is not counted by peer");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Only active associations are reported
+        tunnel
+            .assoc_map
+            .remove(&AssocKey::from(clients[0].local_addr().unwrap()));
+        let traffic = tunnel.traffic_by_peer();
+        assert_eq!(traffic.len(), 1);
+        assert_eq!(
+            traffic[&clients[1].local_addr().unwrap()],
+            expected[&clients[1].local_addr().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn rebind_server_reconnects() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut outbound_addrs = Vec::new();
+        for rebind in [false, false, true] {
+            if rebind {
+                assert_eq!(tunnel.rebind_servers(), 1);
+            }
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"request"),
+                    None,
+                )
+                .await
+                .unwrap();
+            let (_, src_addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            outbound_addrs.push(src_addr);
+        }
+
+        // Same socket until the rebinding, which opens a new one
+        assert_eq!(outbound_addrs[0], outbound_addrs[1]);
+        assert_ne!(outbound_addrs[1], outbound_addrs[2]);
+
+        assert!(tunnel.close_association(&peer_addr));
+        assert!(!tunnel.close_association(&peer_addr));
+        assert_eq!(tunnel.association_count(), 0);
+        assert_eq!(tunnel.rebind_servers(), 0);
+    }
+
+    #[tokio::test]
+    async fn dead_association_recreated() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for died in [false, true] {
+            if died {
+                let assoc = tunnel.assoc_map.get(&AssocKey::from(peer_addr)).unwrap();
+                assoc.assoc_handles[0].abort();
+                time::timeout(Duration::from_secs(1), assoc.senders[0].closed())
+                    .await
+                    .expect("association's task is not aborted");
+            }
+
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"request"),
+                    None,
+                )
+                .await
+                .unwrap();
+            time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .expect("packet is not relayed")
+                .unwrap();
+            assert_eq!(tunnel.association_count(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn capacity_rejects_new_clients() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, Some(2), UdpCapacityMode::Reject);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peers = [
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50002),
+        ];
+        for peer_addr in peers {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(tunnel.rejected_count(), 1);
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[0])).is_some());
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[1])).is_some());
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[2])).is_none());
+
+        // Existing clients are still relayed at capacity
+        tunnel
+            .send_packet(
+                &listener,
+                peers[0],
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(tunnel.rejected_count(), 1);
+
+        tunnel.assoc_map.remove(&AssocKey::from(peers[1]));
+        tunnel
+            .send_packet(
+                &listener,
+                peers[2],
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[2])).is_some());
+        assert_eq!(tunnel.rejected_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn builder_options_applied() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let conntrack = UdpAssocTrack::new();
+        let mut tunnel = UdpTunnelBuilder::new()
+            .time_to_live(Some(Duration::from_secs(30)))
+            .cleanup_interval(Some(Duration::from_secs(3)))
+            .capacity(Some(1))
+            .capacity_mode(UdpCapacityMode::Reject)
+            .conntrack(conntrack.clone())
+            .connect_timeout(Some(Duration::from_millis(500)))
+            .send_channel_size(Some(4))
+            .outbound_pool_size(2)
+            .nat_mode(UdpNatMode::Symmetric)
+            .build(context);
+
+        assert_eq!(tunnel.time_to_live, Duration::from_secs(30));
+        assert_eq!(tunnel.cleanup_interval(), Duration::from_secs(3));
+        assert_eq!(tunnel.connect_timeout.timeout, Duration::from_millis(500));
+        assert_eq!(tunnel.send_channel_size, 4);
+        assert_eq!(tunnel.outbound_pool_size, 2);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peers = [
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001),
+        ];
+        for peer_addr in peers {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // Second client is over capacity, the first one is tracked by the given conntrack and keyed by its target
+        assert_eq!(tunnel.rejected_count(), 1);
+        assert_eq!(tunnel.association_count(), 1);
+        let entries = conntrack.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].peer_addr, peers[0]);
+        assert!(tunnel
+            .assoc_map
+            .peek(&AssocKey::new(
+                UdpNatMode::Symmetric,
+                peers[0],
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353)
+            ))
+            .is_some());
+    }
+
+    /// Records clients of evicted associations
+    #[derive(Default)]
+    struct RecordEviction {
+        evicted: SpinMutex<Vec<SocketAddr>>,
+    }
+
+    impl UdpEvictionHook for RecordEviction {
+        fn evicted(&self, peer_addr: SocketAddr) {
+            self.evicted.lock().push(peer_addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn capacity_eviction_reported() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, Some(1), UdpCapacityMode::Evict);
+        let hook = Arc::new(RecordEviction::default());
+        tunnel.set_eviction_hook(Some(hook.clone()));
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peers = [
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50001),
+        ];
+        for peer_addr in peers {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(*hook.evicted.lock(), vec![peers[0]]);
+        assert_eq!(tunnel.evicted_count(), 1);
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[0])).is_none());
+        assert!(tunnel.assoc_map.peek(&AssocKey::from(peers[1])).is_some());
+
+        // Packets of existing clients don't evict anything
+        tunnel
+            .send_packet(
+                &listener,
+                peers[1],
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(tunnel.evicted_count(), 1);
+        assert_eq!(hook.evicted.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn association_striped_across_pool() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        const POOL_SIZE: usize = 3;
+        const PACKETS: usize = 6;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        tunnel.set_outbound_pool_size(POOL_SIZE);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let peer_addr = client.local_addr().unwrap();
+        for i in 0..PACKETS {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::copy_from_slice(&[i as u8]),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(tunnel.associations().len(), 1);
+
+        let mut stripes = HashMap::new();
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for _ in 0..PACKETS {
+            let (n, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(addr, forward_addr);
+            *stripes.entry(src_addr).or_insert(0) += 1;
+            server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+        }
+
+        // Sent round-robin through every socket
+        assert_eq!(stripes.len(), POOL_SIZE);
+        assert!(stripes.values().all(|&n| n == PACKETS / POOL_SIZE));
+
+        // Responses of all sockets are sent back to the client, possibly reordered
+        let mut responses = Vec::new();
+        for _ in 0..PACKETS {
+            let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(n, 1);
+            responses.push(buffer[0]);
+        }
+        responses.sort_unstable();
+        assert_eq!(responses, (0..PACKETS as u8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn associations_distributed_across_forward_addrs() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addrs = [
+            Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5353)),
+            Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 5353)),
+        ];
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(
+                UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        // Every client sends twice, its second packet goes to the same target
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for round in 0..2u8 {
+            for (i, client) in clients.iter().enumerate() {
+                let peer_addr = client.local_addr().unwrap();
+                tunnel
+                    .send_packet(
+                        &listener,
+                        peer_addr,
+                        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                        &balancer,
+                        &forward_addrs,
+                        Bytes::copy_from_slice(&[i as u8, round]),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+
+                let (n, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buffer[..n], &[i as u8, round]);
+                assert_eq!(addr, forward_addrs[i % forward_addrs.len()]);
+                server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+            }
+        }
+
+        let mut entries = tunnel.associations();
+        entries.sort_by_key(|e| clients.iter().position(|c| c.local_addr().unwrap() == e.peer_addr));
+        let forwarded = entries.iter().map(|e| e.forward_addr.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            forwarded,
+            vec![
+                forward_addrs[0].clone(),
+                forward_addrs[1].clone(),
+                forward_addrs[0].clone()
+            ]
+        );
+
+        // Responses of every target are sent back to the client that sent the request
+        for (i, client) in clients.iter().enumerate() {
+            for round in 0..2u8 {
+                let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buffer[..n], &[i as u8, round]);
+            }
+        }
+    }
+
+    /// Concatenates all responses into one datagram
+    struct ConcatCoalesce {
+        calls: AtomicUsize,
+    }
+
+    impl UdpResponseCoalesce for ConcatCoalesce {
+        fn coalesce(&self, datagrams: Vec<Bytes>) -> Vec<Bytes> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            vec![datagrams.concat().into()]
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce_hook_merges_small_responses() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        const SMALL_RESPONSES: usize = 5;
+        const MAX_SIZE: usize = 16;
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let other_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let hook = Arc::new(ConcatCoalesce {
+            calls: AtomicUsize::new(0),
+        });
+        let mut coalesce_rules = UdpCoalesceRules::new();
+        coalesce_rules.add_rule(
+            AddressRules::from_lines("test", ["127.0.0.1"]).unwrap(),
+            MAX_SIZE,
+            hook.clone(),
+        );
+        assert!(coalesce_rules.coalesce(&forward_addr).is_some());
+        assert!(coalesce_rules.coalesce(&other_addr).is_none());
+
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        tunnel.set_coalesce_rules(coalesce_rules);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let peer_addr = client.local_addr().unwrap();
+        tunnel
+            .send_packet(
+                &listener,
+                peer_addr,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"request"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let (_, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A burst of small responses, followed by a large one that is never coalesced
+        let mut expected = Vec::new();
+        for i in 0..SMALL_RESPONSES {
+            let response = [i as u8; 4];
+            server.send_to(src_addr, &addr, &response).await.unwrap();
+            expected.extend_from_slice(&response);
+        }
+        let large = [0xffu8; MAX_SIZE + 1];
+        server.send_to(src_addr, &addr, &large).await.unwrap();
+        expected.extend_from_slice(&large);
+
+        let mut received = Vec::new();
+        let mut datagrams = 0;
+        while received.len() < expected.len() {
+            let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&buffer[..n]);
+            datagrams += 1;
+        }
+
+        assert_eq!(received, expected);
+        assert!(hook.calls.load(Ordering::Relaxed) >= 1);
+        assert!(tunnel.coalesced_count() > 0);
+        assert_eq!(datagrams as u64 + tunnel.coalesced_count(), SMALL_RESPONSES as u64 + 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_associations() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        let conntrack = tunnel.conntrack();
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        for i in 0..3u8 {
+            tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::copy_from_slice(&[i]),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // Queued packets are relayed before the association's task exits
+        time::timeout(Duration::from_secs(5), tunnel.shutdown(Duration::from_secs(1)))
+            .await
+            .expect("shutdown not bounded");
+        assert_eq!(tunnel.association_count(), 0);
+        assert!(conntrack.is_empty());
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        for i in 0..3u8 {
+            let (n, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                .await
+                .expect("queued packet not relayed")
+                .unwrap();
+            assert_eq!(&buffer[..n], &[i]);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_stuck_associations() {
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("slow.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        tunnel
+            .send_packet(
+                &listener,
+                peer_addr,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"payload"),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Association's task is stuck connecting to the server
+        let start = Instant::now();
+        tunnel.shutdown(Duration::from_millis(100)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(tunnel.association_count(), 0);
+    }
+
+    #[test]
+    fn parse_capacity_mode() {
+        for mode in [UdpCapacityMode::Evict, UdpCapacityMode::Reject] {
+            assert_eq!(mode.to_string().parse::<UdpCapacityMode>().unwrap(), mode);
+        }
+        assert!("drop".parse::<UdpCapacityMode>().is_err());
+    }
+
+    #[test]
+    fn parse_nat_mode() {
+        for mode in [UdpNatMode::Peer, UdpNatMode::Symmetric, UdpNatMode::FullCone] {
+            assert_eq!(mode.to_string().parse::<UdpNatMode>().unwrap(), mode);
+        }
+        assert!("cone".parse::<UdpNatMode>().is_err());
+    }
+
+    #[test]
+    fn nat_mode_filters_responses() {
+        let target = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let other = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(1, 1, 1, 1).into(), 53));
+        let domain = Address::DomainNameAddress("dns.example.com".to_owned(), 53);
+
+        assert!(UdpNatMode::Symmetric.accepts_response(&target, &target));
+        assert!(!UdpNatMode::Symmetric.accepts_response(&target, &other));
+        // Resolved by the server, any address of the port is accepted
+        assert!(UdpNatMode::Symmetric.accepts_response(&domain, &other));
+        assert!(!UdpNatMode::Symmetric.accepts_response(
+            &domain,
+            &Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(1, 1, 1, 1).into(), 5353))
+        ));
+
+        for mode in [UdpNatMode::Peer, UdpNatMode::FullCone] {
+            assert!(mode.accepts_response(&target, &other));
+            assert!(mode.accepts_response(&domain, &other));
+        }
+    }
+
+    #[tokio::test]
+    async fn nat_mode_keys_associations() {
+        let context = Arc::new(ServiceContext::new());
+
+        // An echo server speaking shadowsocks, it also sends a response from another source first
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let default_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5353));
+        let dns_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 53));
+        let unsolicited_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 53));
+        let forward_addrs = [default_addr.clone()];
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let peer_addr = client.local_addr().unwrap();
+
+        for (nat_mode, associations, unsolicited_received) in [
+            (UdpNatMode::Peer, 1, true),
+            (UdpNatMode::Symmetric, 2, false),
+            (UdpNatMode::FullCone, 2, true),
+        ] {
+            let mut forward_rules = UdpForwardRules::new();
+            forward_rules.add_port_rule(53, dns_addr.clone());
+            let mut tunnel = UdpTunnel::new(context.clone(), None, None, UdpCapacityMode::Evict);
+            tunnel.set_forward_rules(forward_rules);
+            tunnel.set_nat_mode(nat_mode);
+
+            let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            for (dst_port, target) in [(5353, &default_addr), (53, &dns_addr)] {
+                tunnel
+                    .send_packet(
+                        &listener,
+                        peer_addr,
+                        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), dst_port),
+                        &balancer,
+                        &forward_addrs,
+                        Bytes::from_static(b"request"),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+
+                let (n, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                // Associations of peers keep the target they were created with
+                if nat_mode != UdpNatMode::Peer {
+                    assert_eq!(&addr, target);
+                }
+                server
+                    .send_to(src_addr, &unsolicited_addr, b"unsolicited")
+                    .await
+                    .unwrap();
+                server.send_to(src_addr, &addr, &buffer[..n]).await.unwrap();
+
+                if unsolicited_received {
+                    let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(&buffer[..n], b"unsolicited");
+                }
+                let n = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buffer[..n], b"request");
+            }
+
+            let entries = tunnel.associations();
+            assert_eq!(entries.len(), associations, "{}", nat_mode);
+            assert!(entries.iter().all(|e| e.peer_addr == peer_addr));
+
+            // Closing the peer closes all of its associations
+            assert!(tunnel.close_association(&peer_addr));
+            assert_eq!(tunnel.assoc_map.len(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_channel_size_limits_queue() {
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(ServerConfig::new(
+            ServerAddr::DomainName("slow.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5353));
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+        tunnel.set_send_channel_size(1);
+
+        let listener = Arc::new(
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+                .await
+                .unwrap(),
+        );
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+
+        // Association's task takes the first packet and is stuck connecting to the server
+        tunnel
+            .send_packet(
+                &listener,
+                peer_addr,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                &balancer,
+                slice::from_ref(&forward_addr),
+                Bytes::from_static(b"first"),
+                None,
+            )
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut dropped = 0;
+        for _ in 0..2 {
+            if let Err(err) = tunnel
+                .send_packet(
+                    &listener,
+                    peer_addr,
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353),
+                    &balancer,
+                    slice::from_ref(&forward_addr),
+                    Bytes::from_static(b"payload"),
+                    None,
+                )
+                .await
+            {
+                assert!(matches!(
+                    UdpRelaySendError::from_io_error(&err),
+                    Some(UdpRelaySendError::ChannelFull)
+                ));
+                dropped += 1;
+            }
+        }
+        assert_eq!(dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_drops_packet() {
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+
+        let svr_cfg = ServerConfig::new(
+            ServerAddr::DomainName("slow.example.com".to_owned(), 8388),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let connect_timeout = ConnectTimeout::new(Duration::from_millis(100));
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            keepalive_tx,
+        );
+        assoc.connect_timeout = connect_timeout.clone();
+
+        for dropped in 1..=2 {
+            let start = Instant::now();
+            let err = assoc
+                .dispatch_received_proxied_packet(b"payload", None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() < Duration::from_secs(1));
+            // Association is kept and retries connecting on the next packet
+            assert!(assoc.proxied_socket.is_none());
+            assert_eq!(connect_timeout.dropped.load(Ordering::Relaxed), dropped);
+        }
+    }
+
+    /// Packets sent by mock sockets, with the socket's connection number
+    type MockSentPackets = Arc<SpinMutex<Vec<(usize, Address, Vec<u8>)>>>;
+
+    /// Socket relaying in memory, its sends fail if it is broken
+    struct MockProxiedSocket {
+        id: usize,
+        broken: bool,
+        sent: MockSentPackets,
+    }
+
+    #[async_trait]
+    impl ProxiedSocket for MockProxiedSocket {
+        async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<()> {
+            if self.broken {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "broken mock socket"));
+            }
+            self.sent.lock().push((self.id, addr.clone(), payload.to_vec()));
+            Ok(())
+        }
+
+        async fn recv(&self, _buf: &mut [u8]) -> io::Result<(usize, Address)> {
+            future::pending().await
+        }
+    }
+
+    /// Connects `MockProxiedSocket`s, the first `broken` of them are broken
+    struct MockProxySocketFactory {
+        broken: usize,
+        connected: Arc<SpinMutex<Vec<ServerAddr>>>,
+        sent: MockSentPackets,
+    }
+
+    #[async_trait]
+    impl ProxySocketFactory for MockProxySocketFactory {
+        async fn connect(
+            &self,
+            _context: &ServiceContext,
+            svr_cfg: &ServerConfig,
+        ) -> io::Result<Box<dyn ProxiedSocket>> {
+            let mut connected = self.connected.lock();
+            connected.push(svr_cfg.addr().clone());
+            Ok(Box::new(MockProxiedSocket {
+                id: connected.len(),
+                broken: connected.len() <= self.broken,
+                sent: self.sent.clone(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn send_failure_reconnects() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+        let server_addr = balancer.best_udp_server().server_config().addr().clone();
+
+        let factory = Arc::new(MockProxySocketFactory {
+            broken: 1,
+            connected: Arc::new(SpinMutex::new(Vec::new())),
+            sent: Arc::new(SpinMutex::new(Vec::new())),
+        });
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let traffic = Arc::new(AssocTraffic::default());
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            keepalive_tx,
+        );
+        assoc.socket_factory = factory.clone();
+        assoc.traffic = traffic.clone();
+
+        // Sending through the broken socket drops the packet and the socket, the server is kept
+        assoc.dispatch_received_proxied_packet(b"lost", None).await.unwrap();
+        assert!(assoc.proxied_socket.is_none());
+        assert_eq!(*assoc.pinned_server.lock(), Some(server_addr.clone()));
+        assert!(factory.sent.lock().is_empty());
+        assert_eq!(traffic.tx_packets.load(Ordering::Relaxed), 0);
+
+        // The next packets reconnect to the same server once, and go through the new socket
+        for payload in [&b"first"[..], b"second"] {
+            assoc.dispatch_received_proxied_packet(payload, None).await.unwrap();
+        }
+        assert!(assoc.proxied_socket.is_some());
+        assert_eq!(*factory.connected.lock(), vec![server_addr.clone(), server_addr]);
+        assert_eq!(
+            *factory.sent.lock(),
+            vec![
+                (2, forward_addr.clone(), b"first".to_vec()),
+                (2, forward_addr, b"second".to_vec()),
+            ]
+        );
+        assert_eq!(traffic.tx_packets.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn packet_boundaries_kept() {
+        let context = Arc::new(ServiceContext::new());
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(server.local_addr().unwrap(), "password", CipherKind::CHACHA20_POLY1305);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let traffic = Arc::new(AssocTraffic::default());
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            client.local_addr().unwrap(),
+            keepalive_tx,
+        );
+        assoc.traffic = traffic.clone();
+
+        // One IPv4 packet carries the salt, the target address, the payload and the tag
+        let max_size = MAXIMUM_UDP_DATAGRAM_SIZE_V4 - 32 - forward_addr.serialized_len() - 16;
+        let mut server_buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut proxied_buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut client_buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        for size in [0, 1, 1500, max_size] {
+            let payload = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+
+            // Client's packet reaches the server as one packet
+            assoc.dispatch_received_proxied_packet(&payload, None).await.unwrap();
+            let (n, src_addr, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut server_buffer))
+                .await
+                .expect("packet not relayed")
+                .unwrap();
+            assert_eq!((&server_buffer[..n], &addr), (&payload[..], &forward_addr));
+            server.send_to(src_addr, &addr, &server_buffer[..n]).await.unwrap();
+
+            // Its response reaches the client as one packet
+            let (n, addr) = time::timeout(
+                Duration::from_secs(1),
+                receive_from_proxied_opt(&assoc.proxied_socket, &mut proxied_buffer),
+            )
+            .await
+            .expect("response not received")
+            .unwrap();
+            assoc.send_received_respond_packet(&addr, &proxied_buffer[..n]).await;
+            let (n, _) = time::timeout(Duration::from_secs(1), client.recv_from(&mut client_buffer))
+                .await
+                .expect("response not sent back")
+                .unwrap();
+            assert_eq!(&client_buffer[..n], &payload[..]);
+        }
+        let socket = assoc.proxied_socket.as_ref().unwrap();
+        assert_eq!(socket.max_payload_size(&forward_addr), Some(max_size));
+
+        // Larger packets are rejected instead of being split, the socket is kept
+        for size in [max_size + 1, MAXIMUM_UDP_PAYLOAD_SIZE] {
+            let err = assoc
+                .dispatch_received_proxied_packet(&vec![0u8; size], None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert!(assoc.proxied_socket.is_some());
+        assert!(
+            time::timeout(Duration::from_millis(100), server.recv_from(&mut server_buffer))
+                .await
+                .is_err()
+        );
+        assert_eq!(traffic.tx_packets.load(Ordering::Relaxed), 4);
+        assert_eq!(traffic.rx_packets.load(Ordering::Relaxed), 4);
+    }
+
+    /// Connects nothing, every attempt is refused
+    struct RefusingProxySocketFactory;
+
+    #[async_trait]
+    impl ProxySocketFactory for RefusingProxySocketFactory {
+        async fn connect(
+            &self,
+            _context: &ServiceContext,
+            _svr_cfg: &ServerConfig,
+        ) -> io::Result<Box<dyn ProxiedSocket>> {
+            Err(io::Error::new(ErrorKind::ConnectionRefused, "refused by mock factory"))
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_state_reported() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let conntrack = UdpAssocTrack::new();
+        let traffic = Arc::new(AssocTraffic::new(conntrack.traffic.clone()));
+        let _tracker = UdpAssocTracker::new(
+            conntrack.states.clone(),
+            AssocKey::from(peer_addr),
+            AssocState {
+                forward_addr: forward_addr.clone(),
+                created: std::time::Instant::now(),
+                last_active: LastActive::new(),
+                traffic: traffic.clone(),
+            },
+        );
+        let upstream_state = || conntrack.entries()[0].upstream_state;
+        assert_eq!(upstream_state(), UdpUpstreamState::Connecting);
+
+        let mut assoc = test_assoc_context(context, balancer, Arc::new(inbound), peer_addr, keepalive_tx);
+        assoc.socket_factory = Arc::new(RefusingProxySocketFactory);
+        assoc.traffic = traffic;
+
+        // Every packet tries connecting again, and fails
+        for _ in 0..3 {
+            let err = assoc
+                .dispatch_received_proxied_packet(b"payload", None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+            assert_eq!(upstream_state(), UdpUpstreamState::Failed);
+        }
+
+        // Recovered once the server could be connected, the broken socket is dropped and reconnected
+        assoc.socket_factory = Arc::new(MockProxySocketFactory {
+            broken: 1,
+            connected: Arc::new(SpinMutex::new(Vec::new())),
+            sent: Arc::new(SpinMutex::new(Vec::new())),
+        });
+        assoc.dispatch_received_proxied_packet(b"lost", None).await.unwrap();
+        assert_eq!(upstream_state(), UdpUpstreamState::Reconnecting);
+        assoc.dispatch_received_proxied_packet(b"payload", None).await.unwrap();
+        assert_eq!(upstream_state(), UdpUpstreamState::Connected);
+    }
+
+    #[tokio::test]
+    async fn association_pinned_to_server() {
+        /// Chooses servers in turn, so every choice is a different server
+        struct RoundRobinSelector(AtomicUsize);
+
+        impl ServerSelector for RoundRobinSelector {
+            fn select(
+                &self,
+                balancer: &PingBalancer,
+                _cx: &ServerSelectContext<'_>,
+            ) -> io::Result<Option<Arc<ServerIdent>>> {
+                let servers = balancer.servers().count();
+                let index = self.0.fetch_add(1, Ordering::Relaxed) % servers;
+                let addr = balancer.servers().nth(index).unwrap().server_config().addr().clone();
+                Ok(balancer.find_server(&addr))
+            }
+        }
+
+        let context = Arc::new(ServiceContext::new());
+
+        let mut servers = Vec::new();
+        let mut svr_cfgs = Vec::new();
+        for _ in 0..2 {
+            let svr_cfg = ServerConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                "password",
+                CipherKind::CHACHA20_POLY1305,
+            );
+            let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+            svr_cfgs.push(ServerConfig::new(
+                server.local_addr().unwrap(),
+                "password",
+                CipherKind::CHACHA20_POLY1305,
+            ));
+            servers.push(server);
+        }
+        // Servers are checked with TCP, so checkers' packets don't reach them
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        for svr_cfg in &svr_cfgs {
+            builder.add_server(svr_cfg.clone());
+        }
+        builder.server_selector(Arc::new(RoundRobinSelector(AtomicUsize::new(0))));
+        let balancer = builder.build().await.unwrap();
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let mut assoc = test_assoc_context(
+            context,
+            balancer.clone(),
+            Arc::new(inbound),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000),
+            keepalive_tx,
+        );
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        // Server 0 is chosen first, the association keeps using it after transient socket errors
+        for i in 0..3u8 {
+            assoc.dispatch_received_proxied_packet(&[i], None).await.unwrap();
+            let (n, ..) = time::timeout(Duration::from_secs(1), servers[0].recv_from(&mut buffer))
+                .await
+                .expect("association not pinned")
+                .unwrap();
+            assert_eq!(&buffer[..n], &[i]);
+            assoc.reset_proxied_socket();
+        }
+
+        // Another server is chosen after the pinned one is removed
+        balancer.reset_servers(vec![svr_cfgs[1].clone()]).await.unwrap();
+        assoc.dispatch_received_proxied_packet(b"moved", None).await.unwrap();
+        let (n, ..) = time::timeout(Duration::from_secs(1), servers[1].recv_from(&mut buffer))
+            .await
+            .expect("association not moved")
+            .unwrap();
+        assert_eq!(&buffer[..n], b"moved");
+        assert_eq!(assoc.pinned_server.lock().as_ref(), Some(svr_cfgs[1].addr()));
+    }
+
+    #[tokio::test]
+    async fn first_response_latency_recorded() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let traffic = Arc::new(AssocTraffic::default());
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            client.local_addr().unwrap(),
+            keepalive_tx,
+        );
+        assoc.traffic = traffic.clone();
+
+        let start = Instant::now();
+        assoc.dispatch_received_packet(b"query", None).await;
+        assert!(traffic.first_response_latency.lock().is_none());
+
+        time::sleep(Duration::from_millis(50)).await;
+        let server_addr = assoc.forward_addr.clone();
+        assoc.send_received_respond_packet(&server_addr, b"answer").await;
+
+        let latency = traffic.first_response_latency.lock().expect("first response latency");
+        assert!(latency >= Duration::from_millis(50));
+        assert!(latency <= start.elapsed());
+
+        // Only the first response is measured
+        time::sleep(Duration::from_millis(10)).await;
+        assoc.send_received_respond_packet(&server_addr, b"answer").await;
+        assert_eq!(*traffic.first_response_latency.lock(), Some(latency));
+    }
+
+    #[tokio::test]
+    async fn full_keepalive_channel_not_delaying_responses() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+
+        // The manager is too busy to take refreshes
+        let (keepalive_tx, mut keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let other_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50000);
+        while keepalive_tx.try_send(AssocKey::from(other_addr)).is_ok() {}
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            client.local_addr().unwrap(),
+            keepalive_tx,
+        );
+
+        let server_addr = assoc.forward_addr.clone();
+        let mut buf = [0u8; 64];
+        for _ in 0..10 {
+            let start = Instant::now();
+            assoc.send_received_respond_packet(&server_addr, b"answer").await;
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"answer");
+            assert!(start.elapsed() < Duration::from_millis(100));
+        }
+
+        // Refresh is skipped, then retried by the next response once the channel has room
+        let mut queued = 0;
+        while let Ok(addr) = keepalive_rx.try_recv() {
+            assert_eq!(addr, AssocKey::from(other_addr));
+            queued += 1;
+        }
+        assert_eq!(queued, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        assoc.send_received_respond_packet(&server_addr, b"answer").await;
+        assert_eq!(keepalive_rx.try_recv().unwrap(), AssocKey::from(assoc.peer_addr));
+    }
+
+    #[cfg(all(feature = "local-udp-mmsg", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn batched_responses_sent_in_order() {
+        let context = Arc::new(ServiceContext::new());
+        let balancer = local_balancer(context.clone()).await;
+
+        let inbound = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let traffic = Arc::new(AssocTraffic::default());
+
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            Arc::new(inbound),
+            client.local_addr().unwrap(),
+            keepalive_tx,
+        );
+        assoc.traffic = traffic.clone();
+        assoc.mmsg_batch_size = 8;
+
+        // Split into several `sendmmsg` by the batch size
+        let batch = (0..20u8)
+            .map(|i| Bytes::from(vec![i; i as usize + 1]))
+            .collect::<Vec<_>>();
+        let server_addr = assoc.forward_addr.clone();
+        assoc.send_received_respond_packets(&server_addr, &batch).await;
+
+        let mut buf = [0u8; 1024];
+        for data in &batch {
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &data[..]);
+        }
+        assert_eq!(traffic.snapshot().rx_packets, batch.len() as u64);
+        assert_eq!(traffic.snapshot().rx_bytes, (1..=20).sum::<u64>());
+        assert!(assoc.first_response_time.is_some());
+    }
+
+    #[tokio::test]
+    async fn recv_arena_reused_at_high_rate() {
+        const PACKETS: usize = 100_000;
+        const BATCH: usize = 64;
+
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        // Packets are relayed and dropped before the arena is refilled, like associations waiting for them
+        let mut arena = UdpRecvArena::new();
+        let payload = [0x5Au8; 1200];
+        for _ in 0..PACKETS / BATCH {
+            for _ in 0..BATCH {
+                client.send_to(&payload, addr).await.unwrap();
+            }
+            for _ in 0..BATCH {
+                let (data, peer_addr) = arena.recv_from(&socket, MAXIMUM_UDP_PAYLOAD_SIZE).await.unwrap();
+                assert_eq!(peer_addr, client.local_addr().unwrap());
+                assert_eq!(&data[..], &payload[..]);
+            }
+        }
+
+        // Instead of an allocation for every packet
+        assert_eq!(arena.allocations(), 1);
+    }
+
+    #[tokio::test]
+    async fn recv_arena_max_size_payloads() {
+        // Maximum UDP payload over IPv4
+        const MAX_PAYLOAD_SIZE: usize = 65507;
+
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        // Kept across refills, they must not be overwritten by later packets
+        let sizes = [
+            MAX_PAYLOAD_SIZE,
+            MAX_PAYLOAD_SIZE - 1,
+            1,
+            MAX_PAYLOAD_SIZE,
+            1500,
+            MAX_PAYLOAD_SIZE,
+            MAX_PAYLOAD_SIZE,
+        ];
+        let mut arena = UdpRecvArena::new();
+        let mut received = Vec::new();
+        for (i, size) in sizes.into_iter().enumerate() {
+            let payload = vec![i as u8; size];
+            client.send_to(&payload, addr).await.unwrap();
+            let (data, _) = arena.recv_from(&socket, MAXIMUM_UDP_PAYLOAD_SIZE).await.unwrap();
+            received.push(data);
+        }
+        for (i, (data, size)) in received.iter().zip(sizes).enumerate() {
+            assert_eq!(data.len(), size);
+            assert!(data.iter().all(|b| *b == i as u8));
+        }
+
+        // Arenas still held by packets are not reused
+        assert!(arena.allocations() > 1);
+        let allocations = arena.allocations();
+        drop(received);
+        for _ in 0..UDP_RECV_ARENA_SIZE / MAX_PAYLOAD_SIZE + 1 {
+            client.send_to(&[0u8; MAX_PAYLOAD_SIZE], addr).await.unwrap();
+            let (data, _) = arena.recv_from(&socket, MAXIMUM_UDP_PAYLOAD_SIZE).await.unwrap();
+            assert_eq!(data.len(), MAX_PAYLOAD_SIZE);
+        }
+        assert_eq!(arena.allocations(), allocations);
+    }
+
+    #[tokio::test]
+    async fn inbound_rebound_after_fatal_error() {
+        assert!(is_fatal_recv_error(&io::Error::from_raw_os_error(libc::ENETDOWN)));
+        assert!(!is_fatal_recv_error(&io::Error::from_raw_os_error(libc::ECONNREFUSED)));
+
+        let context = Arc::new(ServiceContext::new());
+        let mut tunnel = UdpTunnel::new(context, None, None, UdpCapacityMode::Evict);
+
+        let listen_addr = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut inbounds = vec![tunnel.bind_inbound(&ServerAddr::SocketAddr(listen_addr)).await.unwrap()];
+        let failed_socket = Arc::downgrade(&inbounds[0].socket);
+
+        // Like the run loop after a fatal error, the failed socket is closed
+        let mut unbound = vec![tunnel.close_inbound(&mut inbounds, 0)];
+        assert!(inbounds.is_empty());
+        assert!(failed_socket.upgrade().is_none());
+        assert!(
+            time::timeout(Duration::from_millis(50), recv_any_inbound(&mut inbounds))
+                .await
+                .is_err()
+        );
+
+        // Kept for the next try while the address is taken
+        let blocker = std::net::UdpSocket::bind(listen_addr).unwrap();
+        tunnel.rebind_inbounds(&mut inbounds, &mut unbound).await;
+        assert!(inbounds.is_empty());
+        assert_eq!(unbound.len(), 1);
+
+        drop(blocker);
+        tunnel.rebind_inbounds(&mut inbounds, &mut unbound).await;
+        assert!(unbound.is_empty());
+        assert_eq!(inbounds[0].socket.local_addr().unwrap(), listen_addr);
+
+        // Serving again on the same address
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        client.send_to(b"resumed", listen_addr).await.unwrap();
+        let (idx, result) = time::timeout(Duration::from_secs(1), recv_any_inbound(&mut inbounds))
+            .await
+            .expect("packet not received");
+        result.unwrap();
+        assert_eq!(idx, 0);
+        let (data, peer_addr, ..) = &inbounds[0].received[0];
+        assert_eq!((&data[..], *peer_addr), (&b"resumed"[..], client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn names_resolved_by_tunnel_resolver() {
+        // The context's resolver never answers in time, names have to be resolved by the tunnel's
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(SlowResolver)));
+        let context = Arc::new(context);
+        let resolver = Arc::new(DnsResolver::custom_resolver(LoopbackResolver));
+
+        let mut tunnel = UdpTunnel::new(context.clone(), None, None, UdpCapacityMode::Evict);
+        tunnel.set_dns_resolver(Some(resolver.clone()));
+        let listen_port = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client_config = ServerAddr::DomainName("tunnel.invalid".to_owned(), listen_port);
+        let inbound = time::timeout(Duration::from_secs(1), tunnel.bind_inbound(&client_config))
+            .await
+            .expect("listening address not resolved")
+            .unwrap();
+        assert_eq!(
+            inbound.socket.local_addr().unwrap(),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port)
+        );
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let server = ProxySocket::bind(context.context(), &svr_cfg).await.unwrap();
+        let svr_cfg = ServerConfig::new(
+            ServerAddr::DomainName("server.invalid".to_owned(), server.local_addr().unwrap().port()),
+            "password",
+            CipherKind::CHACHA20_POLY1305,
+        );
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly);
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+        let forward_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let mut assoc = test_assoc_context(
+            context,
+            balancer,
+            inbound.socket,
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 10000),
+            keepalive_tx,
+        );
+        assoc.dns_resolver = Some(resolver);
+        assoc.connect_timeout = ConnectTimeout::new(Duration::from_secs(1));
+
+        assoc.dispatch_received_proxied_packet(b"resolved", None).await.unwrap();
+        let mut buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let (n, _, addr, ..) = time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer))
+            .await
+            .expect("packet not relayed")
+            .unwrap();
+        assert_eq!((&buffer[..n], &addr), (&b"resolved"[..], &forward_addr));
+
+        // Pinned by its configured name, not the resolved address
+        assert_eq!(
+            *assoc.pinned_server.lock(),
+            Some(ServerAddr::DomainName(
+                "server.invalid".to_owned(),
+                server.local_addr().unwrap().port()
+            ))
+        );
     }
 }
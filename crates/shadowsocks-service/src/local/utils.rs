@@ -1,62 +1,294 @@
 //! Shadowsocks Local Utilities
 
 use std::{
-    io,
+    io::{self, ErrorKind},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
-    time::Duration,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use log::{debug, trace};
+use futures::{future, ready};
+use log::{debug, trace, warn};
 use shadowsocks::{
-    config::ServerConfig,
+    config::ResolveStrategy,
+    dns_resolver::DnsResolver,
     relay::{socks5::Address, tcprelay::utils::copy_encrypted_bidirectional},
+    ServerAddr,
+    ServerConfig,
 };
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::oneshot,
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::{
+    local::{
+        loadbalancing::ServerIdent,
+        net::{AutoProxyClientStream, AutoProxyIo},
+    },
+    net::MonPlainStream,
+};
 
-pub(crate) async fn establish_tcp_tunnel<P, S>(
-    svr_cfg: &ServerConfig,
+/// Summary of a finished TCP tunnel
+///
+/// Bytes are not available if the tunnel was closed with an error, they are reported as 0.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub(crate) struct TcpTunnelSummary {
+    /// Bytes from client to remote
+    pub tx: u64,
+    /// Bytes from remote to client
+    pub rx: u64,
+    /// Bytes sent to the server on the wire, including the cipher's overhead. Same as `tx` if bypassed
+    pub wire_tx: u64,
+    /// Bytes received from the server on the wire, including the cipher's overhead. Same as `rx` if bypassed
+    pub wire_rx: u64,
+    /// Time from establishing to closing
+    pub duration: Duration,
+    /// Server relayed through, `None` if bypassed
+    pub server: Option<ServerAddr>,
+    /// Remote address of the outbound connection, the resolved server's address if proxied, `None` if unknown
+    ///
+    /// It tells which address family was actually used for reaching a domain-named server.
+    pub remote_addr: Option<SocketAddr>,
+    /// Error of the outbound connection if it failed in the middle of relaying, like being reset by the server
+    ///
+    /// Data of both directions may be incomplete. Errors of the client's connection are not recorded here.
+    pub remote_error: Option<String>,
+}
+
+impl TcpTunnelSummary {
+    fn new(start: Instant, server: Option<&ServerAddr>, remote_addr: Option<SocketAddr>) -> TcpTunnelSummary {
+        TcpTunnelSummary {
+            tx: 0,
+            rx: 0,
+            wire_tx: 0,
+            wire_rx: 0,
+            duration: start.elapsed(),
+            server: server.cloned(),
+            remote_addr,
+            remote_error: None,
+        }
+    }
+
+    /// Address family of the outbound connection, `"-"` if unknown
+    #[cfg(any(feature = "local-tun", feature = "local-redir"))]
+    pub fn remote_family(&self) -> &'static str {
+        self.remote_addr.as_ref().map_or("-", socket_addr_family)
+    }
+}
+
+/// Name of the address family of `addr`, IPv4-mapped IPv6 addresses are IPv4
+#[cfg(any(feature = "local-tun", feature = "local-redir"))]
+pub(crate) fn socket_addr_family(addr: &SocketAddr) -> &'static str {
+    match *addr {
+        SocketAddr::V4(..) => "IPv4",
+        SocketAddr::V6(ref a) if to_ipv4_mapped(a.ip()).is_some() => "IPv4",
+        SocketAddr::V6(..) => "IPv6",
+    }
+}
+
+/// Server accepted the connection but sent nothing back in time after the client's first payload
+#[derive(Debug, thiserror::Error)]
+#[error("server sent nothing in {0:?} after the request")]
+pub(crate) struct FirstByteTimeoutError(Duration);
+
+/// Check if the tunnel was closed by the first byte timeout, clients' connections should be reset
+#[cfg(any(feature = "local-tun", feature = "local-tunnel"))]
+pub(crate) fn is_first_byte_timeout(err: &io::Error) -> bool {
+    matches!(err.get_ref(), Some(e) if e.is::<FirstByteTimeoutError>())
+}
+
+/// Client's stream of a tunnel, remembers if it failed, so errors of relaying are attributed to the right side
+struct PlainErrorWatch<S> {
+    stream: S,
+    failed: bool,
+}
+
+impl<S> PlainErrorWatch<S> {
+    fn new(stream: S) -> PlainErrorWatch<S> {
+        PlainErrorWatch { stream, failed: false }
+    }
+
+    fn watch<T>(&mut self, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(..)) = poll {
+            self.failed = true;
+        }
+        poll
+    }
+}
+
+impl<S> AsyncRead for PlainErrorWatch<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.watch(poll)
+    }
+}
+
+impl<S> AsyncWrite for PlainErrorWatch<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.watch(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_flush(cx);
+        self.watch(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_shutdown(cx);
+        self.watch(poll)
+    }
+}
+
+/// Client's stream of a tunnel, signals the client's first payload and the first byte sent back to it
+struct FirstByteWatch<S> {
+    stream: S,
+    request_tx: Option<oneshot::Sender<()>>,
+    response_tx: Option<oneshot::Sender<()>>,
+}
+
+impl<S> AsyncRead for FirstByteWatch<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            if let Some(request_tx) = self.request_tx.take() {
+                let _ = request_tx.send(());
+            }
+        }
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for FirstByteWatch<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+        if n > 0 {
+            if let Some(response_tx) = self.response_tx.take() {
+                let _ = response_tx.send(());
+            }
+        }
+        Ok(n).into()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Completes if the server sends nothing within `timeout` after the client's first payload
+async fn first_byte_stalled(
+    timeout: Option<Duration>,
+    request_rx: oneshot::Receiver<()>,
+    response_rx: oneshot::Receiver<()>,
+) -> Duration {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return future::pending().await,
+    };
+
+    // Clients that haven't sent anything, like preconnected ones, are allowed to idle
+    if request_rx.await.is_err() {
+        return future::pending().await;
+    }
+    if time::timeout(timeout, response_rx).await.is_ok() {
+        return future::pending().await;
+    }
+    timeout
+}
+
+/// Relay between the client and the remote
+///
+/// If `first_byte_timeout` is set, proxied tunnels are closed with `FirstByteTimeoutError` if the server sends nothing
+/// in time after the client's first payload, and the stall is reported to the balancer.
+pub(crate) async fn establish_tcp_tunnel<P>(
+    server: &ServerIdent,
     plain: &mut P,
-    shadow: &mut S,
+    shadow: &mut AutoProxyClientStream,
     peer_addr: SocketAddr,
     target_addr: &Address,
-) -> io::Result<()>
+    first_byte_timeout: Option<Duration>,
+) -> io::Result<TcpTunnelSummary>
 where
     P: AsyncRead + AsyncWrite + Unpin,
-    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
 {
+    let svr_cfg = server.server_config();
+    let start = Instant::now();
+    let remote_addr = shadow.peer_addr().ok();
+
     if shadow.is_proxied() {
         debug!(
-            "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
+            "established tcp tunnel {} <-> {} through sever {} (outbound: {}, remote: {:?})",
             peer_addr,
             target_addr,
             svr_cfg.external_addr(),
             svr_cfg.addr(),
+            remote_addr,
         );
+
+        if let Some(flow_metadata) = server.plugin_flow_metadata() {
+            match shadow.local_addr() {
+                Ok(conn_addr) => flow_metadata.send(conn_addr, peer_addr, target_addr),
+                Err(err) => debug!(
+                    "tcp tunnel {} <-> {} failed to get local address for plugin flow metadata, error: {}",
+                    peer_addr, target_addr, err
+                ),
+            }
+        }
     } else {
         debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr, start, remote_addr).await;
     }
 
+    // Flow is counted for draining the server when it is removed
+    let _flow_guard = server.start_flow();
+
+    // Payload relayed from and to the client is counted as goodput
+    let flow_stat = shadow.flow_stat().cloned().unwrap_or_default();
+    let (request_tx, request_rx) = oneshot::channel();
+    let (response_tx, response_rx) = oneshot::channel();
+    let mut plain = PlainErrorWatch::new(FirstByteWatch {
+        stream: MonPlainStream::from_stream(plain, flow_stat),
+        request_tx: Some(request_tx),
+        response_tx: Some(response_tx),
+    });
+
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
     //
     // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
     //
     // Wait at most 500ms, and then sends handshake packet to remote servers.
-    {
+    let first_packet_len = {
         let mut buffer = [0u8; 8192];
         match time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await {
             Ok(Ok(0)) => {
                 // EOF. Just terminate right here.
-                return Ok(());
+                return Ok(TcpTunnelSummary::new(start, Some(svr_cfg.addr()), remote_addr));
             }
             Ok(Ok(n)) => {
                 // Send the first packet.
                 shadow.write_all(&buffer[..n]).await?;
+                n as u64
             }
             Ok(Err(err)) => return Err(err),
             Err(..) => {
@@ -68,11 +300,42 @@ where
                     peer_addr,
                     target_addr
                 );
+                0
             }
         }
-    }
+    };
+
+    let copy_result = tokio::select! {
+        r = copy_encrypted_bidirectional(svr_cfg.method(), shadow, &mut plain) => r,
+        _ = server.drained() => {
+            debug!(
+                "tcp tunnel {} <-> {} (proxied) terminated, server {} was removed and drained",
+                peer_addr,
+                target_addr,
+                svr_cfg.addr()
+            );
+            return Ok(TcpTunnelSummary::new(start, Some(svr_cfg.addr()), remote_addr));
+        }
+        timeout = first_byte_stalled(first_byte_timeout, request_rx, response_rx) => {
+            warn!(
+                "tcp tunnel {} <-> {} (proxied) reset, server {} sent nothing in {:?} after the request",
+                peer_addr,
+                target_addr,
+                svr_cfg.addr(),
+                timeout
+            );
+            server.tcp_score().report_failure().await;
+            return Err(io::Error::new(ErrorKind::TimedOut, FirstByteTimeoutError(timeout)));
+        }
+    };
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
+    let mut summary = TcpTunnelSummary::new(start, Some(svr_cfg.addr()), remote_addr);
+    summary.tx = first_packet_len;
+    if let Some((wire_tx, wire_rx)) = shadow.wire_bytes() {
+        summary.wire_tx = wire_tx;
+        summary.wire_rx = wire_rx;
+    }
+    match copy_result {
         Ok((wn, rn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
@@ -81,6 +344,8 @@ where
                 rn,
                 wn
             );
+            summary.tx += rn;
+            summary.rx = wn;
         }
         Err(err) => {
             trace!(
@@ -89,10 +354,13 @@ where
                 target_addr,
                 err
             );
+            if !plain.failed {
+                summary.remote_error = Some(err.to_string());
+            }
         }
     }
 
-    Ok(())
+    Ok(summary)
 }
 
 async fn establish_tcp_tunnel_bypassed<P, S>(
@@ -100,12 +368,37 @@ async fn establish_tcp_tunnel_bypassed<P, S>(
     shadow: &mut S,
     peer_addr: SocketAddr,
     target_addr: &Address,
-) -> io::Result<()>
+    start: Instant,
+    remote_addr: Option<SocketAddr>,
+) -> io::Result<TcpTunnelSummary>
 where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    match copy_bidirectional(plain, shadow).await {
+    let mut plain = PlainErrorWatch::new(plain);
+    let copy_result = copy_bidirectional(&mut plain, shadow).await;
+
+    Ok(bypassed_tcp_tunnel_summary(
+        copy_result,
+        plain.failed,
+        peer_addr,
+        target_addr,
+        start,
+        remote_addr,
+    ))
+}
+
+/// Summary of a bypassed tunnel from its result of copying `(L2R, R2L)`, `plain_failed` if it failed on the client
+pub(crate) fn bypassed_tcp_tunnel_summary(
+    copy_result: io::Result<(u64, u64)>,
+    plain_failed: bool,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+    start: Instant,
+    remote_addr: Option<SocketAddr>,
+) -> TcpTunnelSummary {
+    let mut summary = TcpTunnelSummary::new(start, None, remote_addr);
+    match copy_result {
         Ok((rn, wn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
@@ -114,6 +407,10 @@ where
                 rn,
                 wn
             );
+            summary.tx = rn;
+            summary.rx = wn;
+            summary.wire_tx = rn;
+            summary.wire_rx = wn;
         }
         Err(err) => {
             trace!(
@@ -122,19 +419,282 @@ where
                 target_addr,
                 err
             );
+            if !plain_failed {
+                summary.remote_error = Some(err.to_string());
+            }
         }
     }
 
-    Ok(())
+    summary
 }
 
-/// Helper function for converting IPv4 mapped IPv6 address
+/// Resolve `dname` with `resolver` instead of the context's, to the first address of the family preferred by `strategy`
 ///
-/// This is the same as `Ipv6Addr::to_ipv4_mapped`, but it is still unstable in the current libstd
-#[allow(unused)]
+/// IPv4 is preferred unless `strategy` is `ResolveStrategy::Ipv6First`.
+pub(crate) async fn resolve_with(
+    resolver: &DnsResolver,
+    dname: &str,
+    port: u16,
+    strategy: Option<ResolveStrategy>,
+) -> io::Result<SocketAddr> {
+    let ipv6_first = matches!(strategy, Some(ResolveStrategy::Ipv6First));
+    let mut resolved = resolver.resolve(dname, port).await?.collect::<Vec<_>>();
+    resolved.sort_by_key(|addr| addr.is_ipv6() != ipv6_first);
+    resolved.into_iter().next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::NotFound,
+            format!("domain name {} resolved to no address", dname),
+        )
+    })
+}
+
+/// `addr` with its domain name resolved by `resolver`, socket addresses are kept
+pub(crate) async fn resolve_address_with(
+    resolver: &DnsResolver,
+    addr: &Address,
+    strategy: Option<ResolveStrategy>,
+) -> io::Result<Address> {
+    match *addr {
+        Address::SocketAddress(..) => Ok(addr.clone()),
+        Address::DomainNameAddress(ref dname, port) => resolve_with(resolver, dname, port, strategy)
+            .await
+            .map(Address::SocketAddress),
+    }
+}
+
+/// `svr_cfg` with its domain name resolved by `resolver` with its own `ResolveStrategy`
+pub(crate) async fn resolve_server_config_with(
+    resolver: &DnsResolver,
+    svr_cfg: &ServerConfig,
+) -> io::Result<ServerConfig> {
+    let mut svr_cfg = svr_cfg.clone();
+    if let ServerAddr::DomainName(ref dname, port) = *svr_cfg.addr() {
+        let addr = resolve_with(resolver, dname, port, svr_cfg.resolve_strategy()).await?;
+        svr_cfg.set_addr(addr);
+    }
+    Ok(svr_cfg)
+}
+
 pub(crate) fn to_ipv4_mapped(ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
     match ipv6.octets() {
         [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use shadowsocks::{crypto::v1::CipherKind, ServerConfig};
+    use tokio::net::TcpListener;
+
+    use crate::local::context::ServiceContext;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tcp_tunnel_summary_totals() {
+        const REQUEST_LEN: usize = 12345;
+        const RESPONSE_LEN: usize = 54321;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request.len(), REQUEST_LEN);
+            stream.write_all(&[0u8; RESPONSE_LEN]).await.unwrap();
+        });
+
+        let server = ServerIdent::new(
+            ServerConfig::new(
+                "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+                "password",
+                CipherKind::AES_256_GCM,
+            ),
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        );
+        let mut remote = AutoProxyClientStream::connect_bypassed(Arc::new(ServiceContext::new()), target_addr)
+            .await
+            .unwrap();
+
+        let (mut client, mut plain) = tokio::io::duplex(4096);
+        let client_task = tokio::spawn(async move {
+            client.write_all(&[0u8; REQUEST_LEN]).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response.len()
+        });
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let summary = establish_tcp_tunnel(
+            &server,
+            &mut plain,
+            &mut remote,
+            peer_addr,
+            &Address::from(target_addr),
+            None,
+        )
+        .await
+        .unwrap();
+        drop(plain);
+
+        assert_eq!(client_task.await.unwrap(), RESPONSE_LEN);
+        assert_eq!(summary.tx, REQUEST_LEN as u64);
+        assert_eq!(summary.rx, RESPONSE_LEN as u64);
+        assert!(summary.server.is_none());
+        assert!(summary.remote_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn tcp_tunnel_remote_reset() {
+        // Target resets the connection in the middle of its response
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"partial").await.unwrap();
+            stream.set_linger(Some(Duration::ZERO)).unwrap();
+        });
+
+        let server = ServerIdent::new(
+            ServerConfig::new(
+                "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+                "password",
+                CipherKind::AES_256_GCM,
+            ),
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        );
+        let mut remote = AutoProxyClientStream::connect_bypassed(Arc::new(ServiceContext::new()), target_addr)
+            .await
+            .unwrap();
+
+        // Client keeps its connection open, waiting for the rest of the response
+        let (mut client, mut plain) = tokio::io::duplex(4096);
+        client.write_all(b"hello").await.unwrap();
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let summary = time::timeout(
+            Duration::from_secs(5),
+            establish_tcp_tunnel(
+                &server,
+                &mut plain,
+                &mut remote,
+                peer_addr,
+                &Address::from(target_addr),
+                None,
+            ),
+        )
+        .await
+        .expect("reset not detected")
+        .unwrap();
+
+        let err = summary.remote_error.expect("reset as a clean close");
+        assert!(!err.is_empty());
+    }
+
+    #[cfg(any(feature = "local-tun", feature = "local-redir"))]
+    #[tokio::test]
+    async fn tcp_tunnel_remote_family() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let accept_task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let local_addr = stream.local_addr().unwrap();
+            let mut request = Vec::new();
+            let _ = stream.read_to_end(&mut request).await;
+            local_addr
+        });
+
+        // Domain-named server, the family is decided by resolving
+        let server = ServerIdent::new(
+            ServerConfig::new(
+                ServerAddr::DomainName("localhost".to_owned(), server_port),
+                "password",
+                CipherKind::AES_256_GCM,
+            ),
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        );
+        let target_addr = Address::from("127.0.0.1:80".parse::<SocketAddr>().unwrap());
+        let mut remote =
+            AutoProxyClientStream::connect_proxied(Arc::new(ServiceContext::new()), &server, target_addr.clone())
+                .await
+                .unwrap();
+
+        // Client closes without sending anything
+        let (client, mut plain) = tokio::io::duplex(4096);
+        drop(client);
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let summary = establish_tcp_tunnel(&server, &mut plain, &mut remote, peer_addr, &target_addr, None)
+            .await
+            .unwrap();
+        drop(remote);
+
+        let accepted_addr = accept_task.await.unwrap();
+        assert_eq!(summary.remote_addr, Some(accepted_addr));
+        assert_eq!(summary.remote_family(), socket_addr_family(&accepted_addr));
+    }
+
+    #[cfg(any(feature = "local-tun", feature = "local-redir"))]
+    #[test]
+    fn addr_family_of_mapped_ipv6() {
+        let mapped = "[::ffff:127.0.0.1]:8388".parse::<SocketAddr>().unwrap();
+        assert_eq!(socket_addr_family(&mapped), "IPv4");
+        let v6 = "[::1]:8388".parse::<SocketAddr>().unwrap();
+        assert_eq!(socket_addr_family(&v6), "IPv6");
+    }
+
+    #[tokio::test]
+    async fn tcp_tunnel_first_byte_timeout() {
+        // Server accepts the connection but never sends anything back
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let _ = stream.read_to_end(&mut request).await;
+        });
+
+        let server = ServerIdent::new(
+            ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM),
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        );
+        let target_addr = Address::from("127.0.0.1:80".parse::<SocketAddr>().unwrap());
+        let mut remote =
+            AutoProxyClientStream::connect_proxied(Arc::new(ServiceContext::new()), &server, target_addr.clone())
+                .await
+                .unwrap();
+
+        let (mut client, mut plain) = tokio::io::duplex(4096);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let peer_addr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap();
+        let start = Instant::now();
+        let err = establish_tcp_tunnel(
+            &server,
+            &mut plain,
+            &mut remote,
+            peer_addr,
+            &target_addr,
+            Some(Duration::from_millis(200)),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        #[cfg(any(feature = "local-tun", feature = "local-tunnel"))]
+        assert!(is_first_byte_timeout(&err));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!server.tcp_score().is_available());
+    }
+}
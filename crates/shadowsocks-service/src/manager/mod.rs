@@ -77,7 +77,12 @@ pub async fn run(config: Config) -> io::Result<()> {
         manager.set_udp_expiry_duration(d);
     }
 
-    if let Some(acl) = config.acl {
+    #[allow(unused_mut)]
+    if let Some(mut acl) = config.acl {
+        #[cfg(feature = "acl-asn")]
+        if let Some(asn_database) = config.asn_database {
+            acl.set_asn_database(asn_database);
+        }
         manager.set_acl(Arc::new(acl));
     }
 
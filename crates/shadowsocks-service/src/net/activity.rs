@@ -0,0 +1,94 @@
+//! Activity tracking for UDP associations
+
+use std::{sync::Arc, time::Instant};
+
+use lru_time_cache::LruCache;
+use spin::Mutex as SpinMutex;
+
+/// Timestamp of the last packet sent or received by an association
+///
+/// Shared between the association's task and its manager, so the manager could tell which associations are
+/// genuinely idle. Position in LRU cache isn't accurate because keep-alive refreshes are coalesced.
+#[derive(Debug, Clone)]
+pub struct LastActive {
+    inner: Arc<SpinMutex<Instant>>,
+}
+
+impl LastActive {
+    /// Create a tracker that is active now
+    pub fn new() -> LastActive {
+        LastActive::new_at(Instant::now())
+    }
+
+    fn new_at(now: Instant) -> LastActive {
+        LastActive {
+            inner: Arc::new(SpinMutex::new(now)),
+        }
+    }
+
+    /// Mark as active now
+    pub fn touch(&self) {
+        self.touch_at(Instant::now())
+    }
+
+    fn touch_at(&self, now: Instant) {
+        let mut last = self.inner.lock();
+        if now > *last {
+            *last = now;
+        }
+    }
+
+    /// Time of the last activity
+    pub fn get(&self) -> Instant {
+        *self.inner.lock()
+    }
+}
+
+impl Default for LastActive {
+    fn default() -> LastActive {
+        LastActive::new()
+    }
+}
+
+/// Remove the association that has been idle for the longest time
+pub fn evict_least_active<K, V, F>(map: &mut LruCache<K, V>, last_active: F) -> Option<(K, V)>
+where
+    K: Ord + Clone,
+    F: Fn(&V) -> Instant,
+{
+    let key = map
+        .peek_iter()
+        .min_by_key(|(_, v)| last_active(v))
+        .map(|(k, _)| k.clone())?;
+    let value = map.remove(&key)?;
+    Some((key, value))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn evict_idle_under_capacity_pressure() {
+        let start = Instant::now();
+        let mut map = LruCache::with_capacity(3);
+
+        // The oldest association keeps relaying, but it is never touched in the map
+        let busy = LastActive::new_at(start);
+        map.insert(1, busy.clone());
+        map.insert(2, LastActive::new_at(start + Duration::from_secs(1)));
+        map.insert(3, LastActive::new_at(start + Duration::from_secs(2)));
+        busy.touch_at(start + Duration::from_secs(10));
+
+        let (key, _) = evict_least_active(&mut map, LastActive::get).unwrap();
+        assert_eq!(key, 2);
+        map.insert(4, LastActive::new_at(start + Duration::from_secs(11)));
+
+        let (key, _) = evict_least_active(&mut map, LastActive::get).unwrap();
+        assert_eq!(key, 3);
+        assert!(map.contains_key(&1));
+        assert!(map.contains_key(&4));
+    }
+}
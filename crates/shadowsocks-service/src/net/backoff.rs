@@ -0,0 +1,106 @@
+//! Reconnecting backoff for UDP associations
+
+use std::time::{Duration, Instant};
+
+/// Default delay of reconnecting after the first failure
+pub const DEFAULT_UDP_RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(50);
+
+/// Default maximum delay of reconnecting after consecutive failures
+pub const DEFAULT_UDP_RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Exponential backoff of an association's reconnecting attempts
+///
+/// Every packet of an association retries connecting when its server is down, which hammers the server and wastes
+/// resources while it is flapping. Attempts are suppressed for a delay after failures, which is doubled on every
+/// consecutive failure up to the maximum and reset after a success.
+#[derive(Debug, Clone)]
+pub struct ConnectBackoff {
+    min_delay: Duration,
+    max_delay: Duration,
+    delay: Option<Duration>,
+    retry_at: Option<Instant>,
+}
+
+impl ConnectBackoff {
+    /// Create a backoff delaying from `min_delay` to `max_delay`
+    pub fn new(min_delay: Duration, max_delay: Duration) -> ConnectBackoff {
+        ConnectBackoff {
+            min_delay,
+            max_delay: max_delay.max(min_delay),
+            delay: None,
+            retry_at: None,
+        }
+    }
+
+    /// Check whether connecting should be attempted now
+    pub fn should_attempt(&self) -> bool {
+        self.should_attempt_at(Instant::now())
+    }
+
+    /// Record a failed attempt, the next one is delayed
+    pub fn failed(&mut self) {
+        self.failed_at(Instant::now())
+    }
+
+    /// Record a successful attempt, the delay is reset
+    pub fn succeeded(&mut self) {
+        self.delay = None;
+        self.retry_at = None;
+    }
+
+    /// Current delay of the next attempt, `None` if not backing off
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
+
+    fn should_attempt_at(&self, now: Instant) -> bool {
+        match self.retry_at {
+            None => true,
+            Some(retry_at) => now >= retry_at,
+        }
+    }
+
+    fn failed_at(&mut self, now: Instant) {
+        let delay = match self.delay {
+            None => self.min_delay,
+            Some(delay) => delay.saturating_mul(2).min(self.max_delay),
+        };
+        self.delay = Some(delay);
+        self.retry_at = Some(now + delay);
+    }
+}
+
+impl Default for ConnectBackoff {
+    fn default() -> ConnectBackoff {
+        ConnectBackoff::new(DEFAULT_UDP_RECONNECT_BACKOFF_MIN, DEFAULT_UDP_RECONNECT_BACKOFF_MAX)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay_doubled_until_max() {
+        let mut backoff = ConnectBackoff::new(Duration::from_millis(50), Duration::from_millis(300));
+        let start = Instant::now();
+        assert!(backoff.should_attempt_at(start));
+
+        backoff.failed_at(start);
+        assert!(!backoff.should_attempt_at(start + Duration::from_millis(49)));
+        assert!(backoff.should_attempt_at(start + Duration::from_millis(50)));
+
+        let delays = (0..4)
+            .map(|_| {
+                backoff.failed_at(start);
+                backoff.delay().unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 300, 300].map(Duration::from_millis).to_vec());
+
+        backoff.succeeded();
+        assert!(backoff.should_attempt_at(start));
+        backoff.failed_at(start);
+        assert_eq!(backoff.delay(), Some(Duration::from_millis(50)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Clocks of time-dependent behaviors, like idle timeouts
+//!
+//! Services read the time from a `Clock`, `SystemClock` by default. Tests could drive them with a `ManualClock`
+//! instead of sleeping until timeouts expire.
+
+#[cfg(feature = "local-tun")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use spin::Mutex as SpinMutex;
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Current time, never goes backwards
+    fn now(&self) -> Instant;
+}
+
+/// Time of the system's monotonic clock, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Time only moved forward by `advance`, starting at the time it was created
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<SpinMutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Create a clock stopped at the current time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Arc::new(SpinMutex::new(Duration::ZERO)),
+        }
+    }
+
+    /// Move the time forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+
+    /// Time moved forward since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+/// `Clock` handed over to another thread, which picks it up after it is changed
+#[cfg(feature = "local-tun")]
+pub(crate) struct SharedClock {
+    changed: AtomicBool,
+    clock: SpinMutex<Arc<dyn Clock>>,
+}
+
+#[cfg(feature = "local-tun")]
+impl SharedClock {
+    pub(crate) fn set(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock() = clock;
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// Replace `current` if the clock was changed since the last call, the lock is only taken then
+    pub(crate) fn update(&self, current: &mut Arc<dyn Clock>) -> bool {
+        if self.changed.load(Ordering::Relaxed) && self.changed.swap(false, Ordering::Acquire) {
+            *current = self.clock.lock().clone();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(feature = "local-tun")]
+impl Default for SharedClock {
+    fn default() -> SharedClock {
+        SharedClock {
+            changed: AtomicBool::new(false),
+            clock: SpinMutex::new(Arc::new(SystemClock)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock_advanced_by_clones() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let cloned = clock.clone();
+        cloned.advance(Duration::from_secs(30));
+        assert_eq!(clock.now(), start + Duration::from_secs(30));
+        assert_eq!(clock.elapsed(), Duration::from_secs(30));
+    }
+
+    #[cfg(feature = "local-tun")]
+    #[test]
+    fn shared_clock_picked_up_once() {
+        let shared = SharedClock::default();
+        let mut current: Arc<dyn Clock> = Arc::new(SystemClock);
+
+        let clock = ManualClock::new();
+        shared.set(Arc::new(clock.clone()));
+        assert!(shared.update(&mut current));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(current.now(), clock.now());
+
+        // Not replaced again unless it is changed
+        current = Arc::new(SystemClock);
+        assert!(!shared.update(&mut current));
+        assert_ne!(current.now(), clock.now());
+    }
+}
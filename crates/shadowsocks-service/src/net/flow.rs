@@ -8,9 +8,14 @@ type FlowCounter = std::sync::atomic::AtomicU64;
 type FlowCounter = std::sync::atomic::AtomicU32;
 
 /// Connection flow statistic
+///
+/// `tx` and `rx` are bytes on the wire, including overhead of the cipher and the protocol. `goodput_tx` and
+/// `goodput_rx` are the relayed application payload.
 pub struct FlowStat {
     tx: FlowCounter,
     rx: FlowCounter,
+    goodput_tx: FlowCounter,
+    goodput_rx: FlowCounter,
 }
 
 impl Default for FlowStat {
@@ -18,6 +23,8 @@ impl Default for FlowStat {
         FlowStat {
             tx: FlowCounter::new(0),
             rx: FlowCounter::new(0),
+            goodput_tx: FlowCounter::new(0),
+            goodput_rx: FlowCounter::new(0),
         }
     }
 }
@@ -47,4 +54,24 @@ impl FlowStat {
     pub fn incr_rx(&self, n: u64) {
         self.rx.fetch_add(n as _, Ordering::AcqRel);
     }
+
+    /// Transmitted payload bytes count
+    pub fn goodput_tx(&self) -> u64 {
+        self.goodput_tx.load(Ordering::Relaxed) as _
+    }
+
+    /// Increase transmitted payload bytes
+    pub fn incr_goodput_tx(&self, n: u64) {
+        self.goodput_tx.fetch_add(n as _, Ordering::AcqRel);
+    }
+
+    /// Received payload bytes count
+    pub fn goodput_rx(&self) -> u64 {
+        self.goodput_rx.load(Ordering::Relaxed) as _
+    }
+
+    /// Increase received payload bytes
+    pub fn incr_goodput_rx(&self, n: u64) {
+        self.goodput_rx.fetch_add(n as _, Ordering::AcqRel);
+    }
 }
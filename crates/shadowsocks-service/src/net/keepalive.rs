@@ -0,0 +1,85 @@
+//! Keep-alive refreshing for UDP associations
+
+use std::time::{Duration, Instant};
+
+/// Minimum interval between two keep-alive refreshes of an association
+pub const UDP_ASSOCIATION_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Coalesces keep-alive refreshes of an association
+///
+/// Every response packet keeps the association alive, but notifying the associations' manager for each of them
+/// floods the keep-alive channel under heavy traffic. Refreshes are coalesced to at most one per interval, which is
+/// far shorter than associations' time-to-live.
+#[derive(Debug, Clone)]
+pub struct KeepAliveThrottle {
+    interval: Duration,
+    last_refresh: Option<Instant>,
+}
+
+impl KeepAliveThrottle {
+    /// Create a throttle allowing at most one refresh in `interval`
+    pub fn new(interval: Duration) -> KeepAliveThrottle {
+        KeepAliveThrottle {
+            interval,
+            last_refresh: None,
+        }
+    }
+
+    /// Check whether the association should be refreshed now
+    ///
+    /// Caller should call `refreshed` after the refresh was actually sent
+    pub fn should_refresh(&self) -> bool {
+        self.should_refresh_at(Instant::now())
+    }
+
+    /// Record a successful refresh
+    pub fn refreshed(&mut self) {
+        self.refreshed_at(Instant::now())
+    }
+
+    fn should_refresh_at(&self, now: Instant) -> bool {
+        match self.last_refresh {
+            None => true,
+            Some(last) => now.saturating_duration_since(last) >= self.interval,
+        }
+    }
+
+    fn refreshed_at(&mut self, now: Instant) {
+        self.last_refresh = Some(now);
+    }
+}
+
+impl Default for KeepAliveThrottle {
+    fn default() -> KeepAliveThrottle {
+        KeepAliveThrottle::new(UDP_ASSOCIATION_KEEP_ALIVE_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn high_rate_refresh_bounded() {
+        let mut throttle = KeepAliveThrottle::new(Duration::from_secs(1));
+        let start = Instant::now();
+
+        // 10 seconds of responses, one every 100us
+        let mut refreshes = 0;
+        for step in 0..100_000u64 {
+            let now = start + Duration::from_micros(step * 100);
+            if throttle.should_refresh_at(now) {
+                throttle.refreshed_at(now);
+                refreshes += 1;
+            }
+        }
+        assert_eq!(refreshes, 10);
+
+        // Failed refreshes will be retried by the next packet
+        let mut throttle = KeepAliveThrottle::new(Duration::from_secs(1));
+        assert!(throttle.should_refresh_at(start));
+        assert!(throttle.should_refresh_at(start + Duration::from_millis(1)));
+        throttle.refreshed_at(start + Duration::from_millis(1));
+        assert!(!throttle.should_refresh_at(start + Duration::from_millis(500)));
+    }
+}
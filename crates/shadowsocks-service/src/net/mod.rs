@@ -1,10 +1,26 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    activity::LastActive,
+    backoff::{ConnectBackoff, DEFAULT_UDP_RECONNECT_BACKOFF_MAX, DEFAULT_UDP_RECONNECT_BACKOFF_MIN},
+    clock::{Clock, ManualClock, SystemClock},
+    flow::FlowStat,
+    keepalive::{KeepAliveThrottle, UDP_ASSOCIATION_KEEP_ALIVE_INTERVAL},
+    mon_socket::MonProxySocket,
+    mon_stream::{MonPlainStream, MonProxyStream},
+    udp_channel::{send_to_channel, UdpChannelFullPolicy, UdpChannelReceiver, UdpChannelSender, UdpRelaySendError},
+    udp_padding::{strip_udp_padding, UdpPaddingPolicy},
+};
 
+pub mod activity;
+pub mod backoff;
+pub mod clock;
 pub mod flow;
+pub mod keepalive;
 pub mod mon_socket;
 pub mod mon_stream;
+pub mod udp_channel;
+pub mod udp_padding;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
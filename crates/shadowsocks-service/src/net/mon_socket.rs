@@ -2,28 +2,88 @@
 
 use std::{io, net::SocketAddr, sync::Arc};
 
+#[cfg(feature = "local-udp-quic")]
+use shadowsocks::relay::udprelay::QuicProxySocket;
 use shadowsocks::{relay::socks5::Address, ProxySocket};
 use tokio::net::ToSocketAddrs;
 
-use super::flow::FlowStat;
+use super::{activity::LastActive, flow::FlowStat, udp_padding::strip_udp_padding};
+
+enum ProxySocketTransport {
+    Udp(ProxySocket),
+    #[cfg(feature = "local-udp-quic")]
+    Quic(QuicProxySocket),
+}
 
 /// Monitored `ProxySocket`
 pub struct MonProxySocket {
-    socket: ProxySocket,
+    socket: ProxySocketTransport,
     flow_stat: Arc<FlowStat>,
+    last_active: Option<LastActive>,
+    padded: bool,
 }
 
 impl MonProxySocket {
     /// Create a new socket with flow monitor
     pub fn from_socket(socket: ProxySocket, flow_stat: Arc<FlowStat>) -> MonProxySocket {
-        MonProxySocket { socket, flow_stat }
+        MonProxySocket {
+            socket: ProxySocketTransport::Udp(socket),
+            flow_stat,
+            last_active: None,
+            padded: false,
+        }
+    }
+
+    /// Create a new socket with flow monitor, sending packets inside a QUIC connection
+    ///
+    /// Only `send` and `recv` are supported, the connection is to a single server.
+    #[cfg(feature = "local-udp-quic")]
+    pub fn from_quic_socket(socket: QuicProxySocket, flow_stat: Arc<FlowStat>) -> MonProxySocket {
+        MonProxySocket {
+            socket: ProxySocketTransport::Quic(socket),
+            flow_stat,
+            last_active: None,
+            padded: false,
+        }
+    }
+
+    /// Payloads are framed by `UdpPaddingPolicy`, only the original payloads are counted as goodput
+    pub fn set_padded(&mut self, padded: bool) {
+        self.padded = padded;
+    }
+
+    #[inline]
+    fn goodput_len(&self, payload: &[u8]) -> u64 {
+        if self.padded {
+            strip_udp_padding(payload).map(|p| p.len()).unwrap_or(0) as u64
+        } else {
+            payload.len() as u64
+        }
+    }
+
+    /// Record time of the last packet sent or received in `last_active`
+    pub fn set_last_active(&mut self, last_active: LastActive) {
+        self.last_active = Some(last_active);
+    }
+
+    #[inline]
+    fn touch(&self) {
+        if let Some(ref last_active) = self.last_active {
+            last_active.touch();
+        }
     }
 
     /// Send a UDP packet to addr through proxy
     #[inline]
     pub async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<()> {
-        let n = self.socket.send(addr, payload).await?;
+        let n = match self.socket {
+            ProxySocketTransport::Udp(ref s) => s.send(addr, payload).await?,
+            #[cfg(feature = "local-udp-quic")]
+            ProxySocketTransport::Quic(ref s) => s.send(addr, payload).await?,
+        };
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.incr_goodput_tx(self.goodput_len(payload));
+        self.touch();
 
         Ok(())
     }
@@ -31,8 +91,10 @@ impl MonProxySocket {
     /// Send a UDP packet to target from proxy
     #[inline]
     pub async fn send_to<A: ToSocketAddrs>(&self, target: A, addr: &Address, payload: &[u8]) -> io::Result<()> {
-        let n = self.socket.send_to(target, addr, payload).await?;
+        let n = self.get_udp_socket()?.send_to(target, addr, payload).await?;
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.incr_goodput_tx(self.goodput_len(payload));
+        self.touch();
 
         Ok(())
     }
@@ -44,8 +106,14 @@ impl MonProxySocket {
     /// It is recommended to allocate a buffer to have at least 65536 bytes.
     #[inline]
     pub async fn recv(&self, recv_buf: &mut [u8]) -> io::Result<(usize, Address)> {
-        let (n, addr, recv_n) = self.socket.recv(recv_buf).await?;
+        let (n, addr, recv_n) = match self.socket {
+            ProxySocketTransport::Udp(ref s) => s.recv(recv_buf).await?,
+            #[cfg(feature = "local-udp-quic")]
+            ProxySocketTransport::Quic(ref s) => s.recv(recv_buf).await?,
+        };
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.incr_goodput_rx(self.goodput_len(&recv_buf[..n]));
+        self.touch();
 
         Ok((n, addr))
     }
@@ -57,14 +125,89 @@ impl MonProxySocket {
     /// It is recommended to allocate a buffer to have at least 65536 bytes.
     #[inline]
     pub async fn recv_from(&self, recv_buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Address)> {
-        let (n, peer_addr, addr, recv_n) = self.socket.recv_from(recv_buf).await?;
+        let (n, peer_addr, addr, recv_n) = self.get_udp_socket()?.recv_from(recv_buf).await?;
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.incr_goodput_rx(self.goodput_len(&recv_buf[..n]));
+        self.touch();
 
         Ok((n, peer_addr, addr))
     }
 
+    /// Largest payload to `addr` that is carried in one packet, `None` if the transport doesn't carry any
     #[inline]
-    pub fn get_ref(&self) -> &ProxySocket {
-        &self.socket
+    pub fn max_payload_size(&self, addr: &Address) -> Option<usize> {
+        match self.socket {
+            ProxySocketTransport::Udp(ref s) => Some(s.max_payload_size(addr)),
+            #[cfg(feature = "local-udp-quic")]
+            ProxySocketTransport::Quic(ref s) => s.max_payload_size(addr),
+        }
+    }
+
+    /// Get the `ProxySocket`, `None` if packets are sent through QUIC
+    #[inline]
+    pub fn get_ref(&self) -> Option<&ProxySocket> {
+        match self.socket {
+            ProxySocketTransport::Udp(ref s) => Some(s),
+            #[cfg(feature = "local-udp-quic")]
+            ProxySocketTransport::Quic(..) => None,
+        }
+    }
+
+    #[inline]
+    fn get_udp_socket(&self) -> io::Result<&ProxySocket> {
+        self.get_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "QUIC transport doesn't support sending to or receiving from specific addresses",
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::ServerType, context::Context, crypto::v1::CipherKind, ServerConfig};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn wire_bytes_include_cipher_overhead() {
+        const PAYLOAD_LEN: usize = 1000;
+        // Salt and AEAD tag of AES-256-GCM
+        const CIPHER_OVERHEAD: u64 = 32 + 16;
+
+        let server_stat = Arc::new(FlowStat::new());
+        let svr_cfg = ServerConfig::new(
+            "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        let server = ProxySocket::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let server = MonProxySocket::from_socket(server, server_stat.clone());
+
+        let local_stat = Arc::new(FlowStat::new());
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM);
+        let local = ProxySocket::connect(Context::new_shared(ServerType::Local), &svr_cfg)
+            .await
+            .unwrap();
+        let local = MonProxySocket::from_socket(local, local_stat.clone());
+
+        let target_addr = Address::SocketAddress("127.0.0.1:80".parse().unwrap());
+        local.send(&target_addr, &[0xa5u8; PAYLOAD_LEN]).await.unwrap();
+
+        let mut buffer = [0u8; 2048];
+        let (n, _, addr) = server.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(n, PAYLOAD_LEN);
+        assert_eq!(addr, target_addr);
+
+        // Address is encrypted together with the payload
+        let wire_len = CIPHER_OVERHEAD + target_addr.serialized_len() as u64 + PAYLOAD_LEN as u64;
+        assert_eq!(local_stat.tx(), wire_len);
+        assert_eq!(local_stat.goodput_tx(), PAYLOAD_LEN as u64);
+        assert_eq!(server_stat.rx(), wire_len);
+        assert_eq!(server_stat.goodput_rx(), PAYLOAD_LEN as u64);
     }
 }
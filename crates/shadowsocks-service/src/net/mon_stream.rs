@@ -13,17 +13,26 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use super::flow::FlowStat;
 
 /// Monitored `ProxyStream`
+///
+/// Counts bytes on the wire, it should be wrapped by the encrypted stream.
 #[pin_project]
 pub struct MonProxyStream<S> {
     #[pin]
     stream: S,
     flow_stat: Arc<FlowStat>,
+    tx: u64,
+    rx: u64,
 }
 
 impl<S> MonProxyStream<S> {
     #[inline]
     pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonProxyStream<S> {
-        MonProxyStream { stream, flow_stat }
+        MonProxyStream {
+            stream,
+            flow_stat,
+            tx: 0,
+            rx: 0,
+        }
     }
 
     #[inline]
@@ -35,6 +44,24 @@ impl<S> MonProxyStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         &mut self.stream
     }
+
+    /// Get the flow statistic that this stream reports to
+    #[inline]
+    pub fn flow_stat(&self) -> &Arc<FlowStat> {
+        &self.flow_stat
+    }
+
+    /// Bytes transmitted on the wire by this stream
+    #[inline]
+    pub fn wire_tx(&self) -> u64 {
+        self.tx
+    }
+
+    /// Bytes received on the wire by this stream
+    #[inline]
+    pub fn wire_rx(&self) -> u64 {
+        self.rx
+    }
 }
 
 impl<S> AsyncRead for MonProxyStream<S>
@@ -44,11 +71,13 @@ where
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let this = self.project();
+        let filled = buf.filled().len();
         match this.stream.poll_read(cx, buf) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(())) => {
-                let n = buf.filled().len();
-                this.flow_stat.incr_rx(n as u64);
+                let n = (buf.filled().len() - filled) as u64;
+                this.flow_stat.incr_rx(n);
+                *this.rx += n;
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -67,6 +96,7 @@ where
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(n)) => {
                 this.flow_stat.incr_tx(n as u64);
+                *this.tx += n as u64;
                 Poll::Ready(Ok(n))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -89,6 +119,90 @@ where
         cx: &mut Context<'_>,
         bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
-        self.project().stream.poll_write_vectored(cx, bufs)
+        let this = self.project();
+        match this.stream.poll_write_vectored(cx, bufs) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(n)) => {
+                this.flow_stat.incr_tx(n as u64);
+                *this.tx += n as u64;
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+/// Monitored plain side of a relayed stream, the client's stream in local or the target's stream in server
+///
+/// Bytes read from it are going to be sent through the proxy, they are counted as transmitted goodput. Bytes written
+/// to it were received from the proxy.
+#[pin_project]
+pub struct MonPlainStream<S> {
+    #[pin]
+    stream: S,
+    flow_stat: Arc<FlowStat>,
+}
+
+impl<S> MonPlainStream<S> {
+    #[inline]
+    pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonPlainStream<S> {
+        MonPlainStream { stream, flow_stat }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S> AsyncRead for MonPlainStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        match this.stream.poll_read(cx, buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => {
+                this.flow_stat.incr_goodput_tx((buf.filled().len() - filled) as u64);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MonPlainStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        match this.stream.poll_write(cx, buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(n)) => {
+                this.flow_stat.incr_goodput_rx(n as u64);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
     }
 }
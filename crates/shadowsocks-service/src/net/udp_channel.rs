@@ -0,0 +1,408 @@
+//! Sending packets to UDP associations' send channels
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+use tokio::sync::{
+    mpsc::error::{TryRecvError, TrySendError},
+    Notify,
+};
+
+/// Behavior when an association's send channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpChannelFullPolicy {
+    /// Drop the packet, sending fails with `UdpRelaySendError::ChannelFull`
+    Drop,
+    /// Wait until the association has room for the packet, receiving from clients is paused meanwhile
+    Backpressure,
+    /// Drop the oldest queued packet to make room for the packet, for protocols that stale packets are useless
+    DropOldest,
+}
+
+impl Display for UdpChannelFullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpChannelFullPolicy::Drop => f.write_str("drop"),
+            UdpChannelFullPolicy::Backpressure => f.write_str("backpressure"),
+            UdpChannelFullPolicy::DropOldest => f.write_str("drop_oldest"),
+        }
+    }
+}
+
+/// Error while parsing `UdpChannelFullPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpChannelFullPolicyError;
+
+impl Display for UdpChannelFullPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpChannelFullPolicy, expecting \"drop\", \"backpressure\" or \"drop_oldest\"")
+    }
+}
+
+impl FromStr for UdpChannelFullPolicy {
+    type Err = UdpChannelFullPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(UdpChannelFullPolicy::Drop),
+            "backpressure" => Ok(UdpChannelFullPolicy::Backpressure),
+            "drop_oldest" => Ok(UdpChannelFullPolicy::DropOldest),
+            _ => Err(UdpChannelFullPolicyError),
+        }
+    }
+}
+
+/// Error of sending a packet to an association
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpRelaySendError {
+    #[error("udp relay channel full")]
+    ChannelFull,
+    #[error("udp relay channel closed")]
+    ChannelClosed,
+}
+
+impl From<UdpRelaySendError> for io::Error {
+    fn from(err: UdpRelaySendError) -> io::Error {
+        let kind = match err {
+            UdpRelaySendError::ChannelFull => ErrorKind::WouldBlock,
+            UdpRelaySendError::ChannelClosed => ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl UdpRelaySendError {
+    /// Get the `UdpRelaySendError` wrapped in an `io::Error`
+    pub fn from_io_error(err: &io::Error) -> Option<UdpRelaySendError> {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<UdpRelaySendError>())
+            .copied()
+    }
+}
+
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    closed: bool,
+}
+
+struct Channel<T> {
+    state: Mutex<ChannelState<T>>,
+    size: usize,
+    /// Receiver waits for packets, or all senders being dropped
+    recv_notify: Notify,
+    /// Senders wait for room, or the receiver being closed
+    send_notify: Notify,
+    closed_notify: Notify,
+}
+
+/// Create a bounded channel of an association's packets
+///
+/// It works like `mpsc::channel`, except that senders could also evict the oldest queued packet to make room, which
+/// `mpsc` doesn't support.
+pub fn channel<T>(size: usize) -> (UdpChannelSender<T>, UdpChannelReceiver<T>) {
+    let channel = Arc::new(Channel {
+        state: Mutex::new(ChannelState {
+            queue: VecDeque::new(),
+            senders: 1,
+            closed: false,
+        }),
+        size: size.max(1),
+        recv_notify: Notify::new(),
+        send_notify: Notify::new(),
+        closed_notify: Notify::new(),
+    });
+    (
+        UdpChannelSender {
+            channel: channel.clone(),
+        },
+        UdpChannelReceiver { channel },
+    )
+}
+
+/// Sending half of an association's channel
+pub struct UdpChannelSender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> UdpChannelSender<T> {
+    /// Send `data` if there is room
+    pub fn try_send(&self, data: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.channel.state.lock().unwrap();
+        if state.closed {
+            return Err(TrySendError::Closed(data));
+        }
+        if state.queue.len() >= self.channel.size {
+            return Err(TrySendError::Full(data));
+        }
+        state.queue.push_back(data);
+        drop(state);
+        self.channel.recv_notify.notify_one();
+        Ok(())
+    }
+
+    /// Send `data`, waiting until there is room
+    pub async fn send(&self, mut data: T) -> Result<(), T> {
+        loop {
+            match self.try_send(data) {
+                Ok(..) => return Ok(()),
+                Err(TrySendError::Closed(d)) => return Err(d),
+                Err(TrySendError::Full(d)) => data = d,
+            }
+            // Room made after `try_send` is kept as a permit, so it won't be missed
+            self.channel.send_notify.notified().await;
+        }
+    }
+
+    /// Send `data`, dropping the oldest queued packet if it is full, returns the dropped packet
+    pub fn send_evicting(&self, data: T) -> Result<Option<T>, T> {
+        let mut state = self.channel.state.lock().unwrap();
+        if state.closed {
+            return Err(data);
+        }
+        let evicted = if state.queue.len() >= self.channel.size {
+            state.queue.pop_front()
+        } else {
+            None
+        };
+        state.queue.push_back(data);
+        drop(state);
+        self.channel.recv_notify.notify_one();
+        Ok(evicted)
+    }
+
+    /// Number of packets that could be sent without waiting
+    pub fn capacity(&self) -> usize {
+        let state = self.channel.state.lock().unwrap();
+        self.channel.size.saturating_sub(state.queue.len())
+    }
+
+    /// Check if the receiver was closed or dropped
+    pub fn is_closed(&self) -> bool {
+        self.channel.state.lock().unwrap().closed
+    }
+
+    /// Wait until the receiver is closed or dropped
+    pub async fn closed(&self) {
+        while !self.is_closed() {
+            self.channel.closed_notify.notified().await;
+        }
+    }
+}
+
+impl<T> Clone for UdpChannelSender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().unwrap().senders += 1;
+        UdpChannelSender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UdpChannelSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.channel.recv_notify.notify_one();
+        }
+    }
+}
+
+/// Receiving half of an association's channel
+pub struct UdpChannelReceiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> UdpChannelReceiver<T> {
+    /// Receive the oldest queued packet, `None` after all senders are dropped or it is closed, and the queue is drained
+    ///
+    /// It is cancel safe, packets are only taken when it returns.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.try_recv() {
+                Ok(data) => return Some(data),
+                Err(TryRecvError::Disconnected) => return None,
+                // Packets sent after `try_recv` are kept as a permit, so they won't be missed
+                Err(TryRecvError::Empty) => self.channel.recv_notify.notified().await,
+            }
+        }
+    }
+
+    /// Receive the oldest queued packet without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.channel.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(data) => {
+                drop(state);
+                self.channel.send_notify.notify_one();
+                Ok(data)
+            }
+            None if state.closed || state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Stop accepting packets, the queued ones could still be received
+    pub fn close(&mut self) {
+        self.channel.state.lock().unwrap().closed = true;
+        // Senders waiting for room or closing have to know it. Permits are left for those about to wait.
+        for notify in [&self.channel.send_notify, &self.channel.closed_notify] {
+            notify.notify_waiters();
+            notify.notify_one();
+        }
+    }
+}
+
+impl<T> Drop for UdpChannelReceiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        self.channel.state.lock().unwrap().queue.clear();
+    }
+}
+
+/// Send `data` to an association's channel, handling a full channel by the policy
+pub async fn send_to_channel<T>(
+    sender: &UdpChannelSender<T>,
+    data: T,
+    policy: UdpChannelFullPolicy,
+) -> Result<(), UdpRelaySendError> {
+    match sender.try_send(data) {
+        Ok(..) => Ok(()),
+        Err(TrySendError::Closed(..)) => Err(UdpRelaySendError::ChannelClosed),
+        Err(TrySendError::Full(data)) => match policy {
+            UdpChannelFullPolicy::Drop => Err(UdpRelaySendError::ChannelFull),
+            UdpChannelFullPolicy::Backpressure => sender.send(data).await.map_err(|_| UdpRelaySendError::ChannelClosed),
+            UdpChannelFullPolicy::DropOldest => sender
+                .send_evicting(data)
+                .map(|_| ())
+                .map_err(|_| UdpRelaySendError::ChannelClosed),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn channel_full_dropped() {
+        let (tx, mut rx) = channel(2);
+        send_to_channel(&tx, 1, UdpChannelFullPolicy::Drop).await.unwrap();
+        send_to_channel(&tx, 2, UdpChannelFullPolicy::Drop).await.unwrap();
+
+        let err = send_to_channel(&tx, 3, UdpChannelFullPolicy::Drop).await.unwrap_err();
+        assert_eq!(err, UdpRelaySendError::ChannelFull);
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(UdpRelaySendError::from_io_error(&err), Some(UdpRelaySendError::ChannelFull));
+
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        let err = send_to_channel(&tx, 4, UdpChannelFullPolicy::Drop).await.unwrap_err();
+        assert_eq!(err, UdpRelaySendError::ChannelClosed);
+    }
+
+    #[tokio::test]
+    async fn channel_full_backpressure() {
+        let (tx, mut rx) = channel(2);
+        send_to_channel(&tx, 1, UdpChannelFullPolicy::Backpressure).await.unwrap();
+        send_to_channel(&tx, 2, UdpChannelFullPolicy::Backpressure).await.unwrap();
+
+        // Blocked until the association takes a packet
+        let blocked = send_to_channel(&tx, 3, UdpChannelFullPolicy::Backpressure);
+        tokio::pin!(blocked);
+        assert!(time::timeout(Duration::from_millis(50), &mut blocked).await.is_err());
+
+        assert_eq!(rx.recv().await, Some(1));
+        blocked.await.unwrap();
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn channel_full_drop_oldest() {
+        let (tx, mut rx) = channel(2);
+        for data in 1..=4 {
+            send_to_channel(&tx, data, UdpChannelFullPolicy::DropOldest)
+                .await
+                .unwrap();
+        }
+
+        // The newest packets survive
+        assert_eq!(tx.capacity(), 0);
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, Some(4));
+
+        // Queued packets are received after closing, then it ends
+        send_to_channel(&tx, 5, UdpChannelFullPolicy::DropOldest).await.unwrap();
+        rx.close();
+        assert!(tx.is_closed());
+        let err = send_to_channel(&tx, 6, UdpChannelFullPolicy::DropOldest)
+            .await
+            .unwrap_err();
+        assert_eq!(err, UdpRelaySendError::ChannelClosed);
+        assert_eq!(rx.recv().await, Some(5));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn channel_ends_after_senders_dropped() {
+        let (tx, mut rx) = channel(4);
+        let tx2 = tx.clone();
+        tx.try_send(1).unwrap();
+        drop(tx);
+        tx2.try_send(2).unwrap();
+
+        let receiving = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(data) = rx.recv().await {
+                received.push(data);
+            }
+            received
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        drop(tx2);
+        let received = time::timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn backpressure_released_by_close() {
+        let (tx, mut rx) = channel(1);
+        tx.try_send(1).unwrap();
+
+        let blocked = tokio::spawn(async move { tx.send(2).await });
+        time::sleep(Duration::from_millis(20)).await;
+        rx.close();
+        let result = time::timeout(Duration::from_secs(5), blocked).await.unwrap().unwrap();
+        assert_eq!(result, Err(2));
+    }
+
+    #[test]
+    fn parse_channel_full_policy() {
+        for policy in [
+            UdpChannelFullPolicy::Drop,
+            UdpChannelFullPolicy::Backpressure,
+            UdpChannelFullPolicy::DropOldest,
+        ] {
+            assert_eq!(policy.to_string().parse::<UdpChannelFullPolicy>().unwrap(), policy);
+        }
+        assert!("block".parse::<UdpChannelFullPolicy>().is_err());
+    }
+}
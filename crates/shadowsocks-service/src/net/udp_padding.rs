@@ -0,0 +1,213 @@
+//! Padding of UDP payloads relayed between local and server, obscuring packet sizes from observers
+//!
+//! A padded payload is framed as
+//!
+//! ```plain
+//! +----------------+---------+---------+
+//! | PAYLOAD LENGTH | PAYLOAD | PADDING |
+//! +----------------+---------+---------+
+//! |  u16 (BE)      |  N      |  M      |
+//! +----------------+---------+---------+
+//! ```
+//!
+//! Every packet carries 2 extra bytes of length besides the padding itself. The framing is not compatible with plain
+//! shadowsocks UDP, both local and server must enable padding.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    str::FromStr,
+};
+
+use shadowsocks::{crypto::v1::random_iv_or_salt, relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE};
+
+/// Length of the header of padded payloads
+pub const UDP_PADDING_HEADER_LEN: usize = 2;
+
+// Same as `Address::max_serialized_len()`, a domain name with its type, length and port
+const MAX_ADDRESS_LEN: usize = 1 + 1 + u8::MAX as usize + 2;
+// Largest salt and tag added by ciphers to every packet
+const MAX_CIPHER_OVERHEAD: usize = 32 + 16;
+
+/// Maximum length of padded payloads, packets fit in `MAXIMUM_UDP_PAYLOAD_SIZE` with any address and cipher
+const MAX_PADDED_LEN: usize = MAXIMUM_UDP_PAYLOAD_SIZE - MAX_ADDRESS_LEN - MAX_CIPHER_OVERHEAD;
+
+/// Maximum size of `UdpPaddingPolicy`
+pub const MAX_UDP_PADDING_SIZE: usize = MAX_PADDED_LEN - UDP_PADDING_HEADER_LEN;
+
+/// Policy of padding UDP payloads, sizes are at most `MAX_UDP_PADDING_SIZE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpPaddingPolicy {
+    /// Pad packets to at least `size` bytes including the header, longer packets are only framed
+    Fixed(usize),
+    /// Pad payloads with random 0 to `max` bytes
+    Random(usize),
+}
+
+impl Display for UdpPaddingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpPaddingPolicy::Fixed(size) => write!(f, "fixed:{}", size),
+            UdpPaddingPolicy::Random(max) => write!(f, "random:{}", max),
+        }
+    }
+}
+
+/// Error while parsing `UdpPaddingPolicy` from string
+#[derive(Debug, Clone, Copy)]
+pub struct UdpPaddingPolicyError;
+
+impl Display for UdpPaddingPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid UdpPaddingPolicy, expecting \"fixed:<size>\" or \"random:<max>\" up to {}",
+            MAX_UDP_PADDING_SIZE
+        )
+    }
+}
+
+impl FromStr for UdpPaddingPolicy {
+    type Err = UdpPaddingPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, size) = s.split_once(':').ok_or(UdpPaddingPolicyError)?;
+        let size = size.parse::<usize>().map_err(|_| UdpPaddingPolicyError)?;
+        if size > MAX_UDP_PADDING_SIZE {
+            return Err(UdpPaddingPolicyError);
+        }
+        match kind {
+            "fixed" => Ok(UdpPaddingPolicy::Fixed(size)),
+            "random" => Ok(UdpPaddingPolicy::Random(size)),
+            _ => Err(UdpPaddingPolicyError),
+        }
+    }
+}
+
+impl UdpPaddingPolicy {
+    fn padding_len(&self, payload_len: usize) -> usize {
+        match *self {
+            UdpPaddingPolicy::Fixed(size) => size.saturating_sub(UDP_PADDING_HEADER_LEN + payload_len),
+            UdpPaddingPolicy::Random(0) => 0,
+            UdpPaddingPolicy::Random(max) => {
+                let mut rnd = [0u8; 4];
+                random_iv_or_salt(&mut rnd);
+                u32::from_ne_bytes(rnd) as usize % (max + 1)
+            }
+        }
+    }
+
+    /// Frame `payload` and append the padding
+    ///
+    /// Padded payloads are at most `MAX_UDP_PADDING_SIZE` plus the header, encrypted packets must fit in UDP.
+    pub fn pad(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if UDP_PADDING_HEADER_LEN + payload.len() > MAX_PADDED_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("udp payload with {} bytes is too large to be padded", payload.len()),
+            ));
+        }
+
+        let max_padding_len = MAX_PADDED_LEN - UDP_PADDING_HEADER_LEN - payload.len();
+        let padding_len = self.padding_len(payload.len()).min(max_padding_len);
+
+        let mut buf = Vec::with_capacity(UDP_PADDING_HEADER_LEN + payload.len() + padding_len);
+        buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf.resize(buf.len() + padding_len, 0);
+        Ok(buf)
+    }
+}
+
+/// Strip the padding, returns the original payload
+pub fn strip_udp_padding(data: &[u8]) -> io::Result<&[u8]> {
+    if data.len() < UDP_PADDING_HEADER_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "padded udp payload too short"));
+    }
+
+    let payload_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    match data.get(UDP_PADDING_HEADER_LEN..UDP_PADDING_HEADER_LEN + payload_len) {
+        Some(payload) => Ok(payload),
+        None => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "padded udp payload length {} exceeds packet length {}, is padding enabled on both ends?",
+                payload_len,
+                data.len()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn padding_round_trip() {
+        let payload = b"hello padding";
+
+        let padded = UdpPaddingPolicy::Fixed(1200).pad(payload).unwrap();
+        assert_eq!(padded.len(), 1200);
+        assert_eq!(strip_udp_padding(&padded).unwrap(), payload);
+
+        // Payload longer than the fixed size is only framed
+        let long_payload = vec![0xa5u8; 1500];
+        let padded = UdpPaddingPolicy::Fixed(1200).pad(&long_payload).unwrap();
+        assert_eq!(padded.len(), UDP_PADDING_HEADER_LEN + long_payload.len());
+        assert_eq!(strip_udp_padding(&padded).unwrap(), &long_payload[..]);
+
+        for _ in 0..100 {
+            let padded = UdpPaddingPolicy::Random(64).pad(payload).unwrap();
+            assert!(padded.len() >= UDP_PADDING_HEADER_LEN + payload.len());
+            assert!(padded.len() <= UDP_PADDING_HEADER_LEN + payload.len() + 64);
+            assert_eq!(strip_udp_padding(&padded).unwrap(), payload);
+        }
+
+        // Unpadded packets from a peer without padding enabled
+        assert!(strip_udp_padding(payload).is_err());
+
+        assert_eq!(
+            "random:64".parse::<UdpPaddingPolicy>().unwrap(),
+            UdpPaddingPolicy::Random(64)
+        );
+        assert!("fixed".parse::<UdpPaddingPolicy>().is_err());
+    }
+
+    #[test]
+    fn padding_size_limited() {
+        use shadowsocks::{
+            crypto::v1::CipherKind,
+            relay::{socks5::Address, CipherOverhead},
+        };
+
+        assert_eq!(MAX_ADDRESS_LEN, Address::max_serialized_len());
+        for method in [
+            CipherKind::AES_128_GCM,
+            CipherKind::AES_256_GCM,
+            CipherKind::CHACHA20_POLY1305,
+        ] {
+            assert!(CipherOverhead::new(method).udp_packet_overhead() <= MAX_CIPHER_OVERHEAD);
+        }
+
+        let max = format!("fixed:{}", MAX_UDP_PADDING_SIZE);
+        assert_eq!(
+            max.parse::<UdpPaddingPolicy>().unwrap(),
+            UdpPaddingPolicy::Fixed(MAX_UDP_PADDING_SIZE)
+        );
+        for policy in ["fixed:65535", "random:65535", "random:65536"] {
+            assert!(policy.parse::<UdpPaddingPolicy>().is_err(), "{}", policy);
+        }
+
+        // Padded packets with the largest address and cipher overhead fit in UDP
+        let payload = vec![0xa5u8; MAX_UDP_PADDING_SIZE - 100];
+        for _ in 0..100 {
+            let padded = UdpPaddingPolicy::Random(MAX_UDP_PADDING_SIZE).pad(&payload).unwrap();
+            assert!(padded.len() + MAX_ADDRESS_LEN + MAX_CIPHER_OVERHEAD <= MAXIMUM_UDP_PAYLOAD_SIZE);
+            assert_eq!(strip_udp_padding(&padded).unwrap(), &payload[..]);
+        }
+        assert!(UdpPaddingPolicy::Fixed(64)
+            .pad(&vec![0u8; MAX_UDP_PADDING_SIZE + 1])
+            .is_err());
+    }
+}
@@ -12,6 +12,8 @@ use shadowsocks::{
 
 use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
 
+use super::udprelay::UdpAssociationCloseStat;
+
 /// Server Service Context
 pub struct ServiceContext {
     context: SharedContext,
@@ -22,6 +24,9 @@ pub struct ServiceContext {
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Closed UDP associations by reasons
+    udp_close_stat: Arc<UdpAssociationCloseStat>,
 }
 
 impl Default for ServiceContext {
@@ -31,6 +36,7 @@ impl Default for ServiceContext {
             connect_opts: ConnectOpts::default(),
             acl: None,
             flow_stat: Arc::new(FlowStat::new()),
+            udp_close_stat: Arc::new(UdpAssociationCloseStat::default()),
         }
     }
 }
@@ -81,6 +87,16 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get closed UDP associations statistic
+    pub fn udp_close_stat(&self) -> Arc<UdpAssociationCloseStat> {
+        self.udp_close_stat.clone()
+    }
+
+    /// Get closed UDP associations statistic reference
+    pub fn udp_close_stat_ref(&self) -> &UdpAssociationCloseStat {
+        self.udp_close_stat.as_ref()
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
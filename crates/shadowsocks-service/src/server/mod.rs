@@ -19,7 +19,10 @@ use crate::{
     dns::build_dns_resolver,
 };
 
-pub use self::server::Server;
+pub use self::{
+    server::Server,
+    udprelay::{UdpAssociationCloseReason, UdpAssociationCloseStat},
+};
 
 pub mod context;
 #[allow(clippy::module_inception)]
@@ -91,7 +94,14 @@ pub async fn run(config: Config) -> io::Result<()> {
         .await
         .map(Arc::new);
 
-    let acl = config.acl.map(Arc::new);
+    #[allow(unused_mut)]
+    let acl = config.acl.map(|mut acl| {
+        #[cfg(feature = "acl-asn")]
+        if let Some(ref asn_database) = config.asn_database {
+            acl.set_asn_database(asn_database.clone());
+        }
+        Arc::new(acl)
+    });
 
     for svr_cfg in config.server {
         let mut server = Server::new(svr_cfg);
@@ -109,6 +119,10 @@ pub async fn run(config: Config) -> io::Result<()> {
         if let Some(d) = config.udp_timeout {
             server.set_udp_expiry_duration(d);
         }
+        if let Some(p) = config.udp_padding {
+            server.set_udp_padding(p);
+        }
+        server.set_udp_channel_full_policy(config.udp_channel_full_policy);
         if let Some(ref m) = config.manager {
             server.set_manager_addr(m.addr.clone());
         }
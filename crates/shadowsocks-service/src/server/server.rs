@@ -18,9 +18,17 @@ use shadowsocks::{
 };
 use tokio::time;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    net::{FlowStat, UdpChannelFullPolicy, UdpPaddingPolicy},
+};
 
-use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
+use super::{
+    context::ServiceContext,
+    tcprelay::TcpServer,
+    udprelay::{UdpAssociationCloseStat, UdpDestinationLimit, UdpServer},
+};
 
 /// Shadowsocks Server
 pub struct Server {
@@ -28,6 +36,10 @@ pub struct Server {
     svr_cfg: ServerConfig,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_destination_limit: Option<UdpDestinationLimit>,
+    udp_padding: Option<UdpPaddingPolicy>,
+    udp_max_association_bytes: Option<u64>,
+    udp_channel_full_policy: UdpChannelFullPolicy,
     manager_addr: Option<ManagerAddr>,
     accept_opts: AcceptOpts,
 }
@@ -45,6 +57,10 @@ impl Server {
             svr_cfg,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_destination_limit: None,
+            udp_padding: None,
+            udp_max_association_bytes: None,
+            udp_channel_full_policy: UdpChannelFullPolicy::Drop,
             manager_addr: None,
             accept_opts: AcceptOpts::default(),
         }
@@ -60,6 +76,11 @@ impl Server {
         self.context.flow_stat_ref()
     }
 
+    /// Get closed UDP associations statistic
+    pub fn udp_close_stat(&self) -> Arc<UdpAssociationCloseStat> {
+        self.context.udp_close_stat()
+    }
+
     /// Set `ConnectOpts`
     pub fn set_connect_opts(&mut self, opts: ConnectOpts) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ConnectOpts on a shared context");
@@ -76,6 +97,35 @@ impl Server {
         self.udp_capacity = Some(c);
     }
 
+    /// Set limit of distinct destinations that one UDP association could send to within `window`
+    ///
+    /// Packets to new destinations beyond the limit are dropped. Unlimited by default.
+    pub fn set_udp_max_destinations(&mut self, max_destinations: usize, window: Duration) {
+        self.udp_destination_limit = Some(UdpDestinationLimit {
+            max_destinations,
+            window,
+        });
+    }
+
+    /// Set total bytes that one UDP association could relay, it is closed after exceeding the quota
+    ///
+    /// The next packet from the same client creates a new association. Unlimited by default.
+    pub fn set_udp_max_association_bytes(&mut self, max_bytes: u64) {
+        self.udp_max_association_bytes = Some(max_bytes);
+    }
+
+    /// Set padding of UDP packets relayed with clients, clients must enable padding too
+    ///
+    /// Packets of clients not padding them can't be relayed, serve those clients with another server without padding.
+    pub fn set_udp_padding(&mut self, padding: UdpPaddingPolicy) {
+        self.udp_padding = Some(padding);
+    }
+
+    /// Set behavior when a UDP association's send channel is full, packets are dropped by default
+    pub fn set_udp_channel_full_policy(&mut self, policy: UdpChannelFullPolicy) {
+        self.udp_channel_full_policy = policy;
+    }
+
     /// Set manager's address to report `stat`
     pub fn set_manager_addr(&mut self, manager_addr: ManagerAddr) {
         self.manager_addr = Some(manager_addr);
@@ -169,12 +219,16 @@ impl Server {
     }
 
     async fn run_udp_server(&self) -> io::Result<()> {
-        let server = UdpServer::new(
+        let mut server = UdpServer::new(
             self.context.clone(),
             self.udp_expiry_duration,
             self.udp_capacity,
             self.accept_opts.clone(),
         );
+        server.set_destination_limit(self.udp_destination_limit);
+        server.set_padding(self.udp_padding);
+        server.set_max_association_bytes(self.udp_max_association_bytes);
+        server.set_channel_full_policy(self.udp_channel_full_policy);
         server.run(&self.svr_cfg).await
     }
 
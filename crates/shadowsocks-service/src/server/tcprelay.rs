@@ -25,7 +25,7 @@ use tokio::{
     time,
 };
 
-use crate::net::{utils::ignore_until_end, MonProxyStream};
+use crate::net::{utils::ignore_until_end, MonPlainStream, MonProxyStream};
 
 use super::context::ServiceContext;
 
@@ -157,7 +157,7 @@ impl TcpServerClient {
             return Ok(());
         }
 
-        let mut remote_stream = match timeout_fut(
+        let remote_stream = match timeout_fut(
             self.timeout,
             OutboundTcpStream::connect_remote_with_opts(
                 self.context.context_ref(),
@@ -176,6 +176,7 @@ impl TcpServerClient {
                 return Err(err);
             }
         };
+        let mut remote_stream = MonPlainStream::from_stream(remote_stream, self.context.flow_stat());
 
         // https://github.com/shadowsocks/shadowsocks-rust/issues/232
         //
@@ -1,10 +1,15 @@
 //! Shadowsocks UDP server
 
 use std::{
-    io::{self, ErrorKind},
+    collections::HashSet,
+    fmt::{self, Display},
+    io,
     net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -20,21 +25,158 @@ use shadowsocks::{
     },
     ServerConfig,
 };
+use spin::Mutex as SpinMutex;
 use tokio::{sync::mpsc, task::JoinHandle, time};
 
-use crate::net::{MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE};
+use crate::net::{
+    send_to_channel,
+    strip_udp_padding,
+    udp_channel,
+    KeepAliveThrottle,
+    MonProxySocket,
+    UdpChannelFullPolicy,
+    UdpChannelReceiver,
+    UdpChannelSender,
+    UdpPaddingPolicy,
+    UdpRelaySendError,
+    UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+    UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+};
 
 use super::context::ServiceContext;
 
 type AssociationMap = LruCache<SocketAddr, UdpAssociation>;
 
+/// Limit of distinct destinations that one association could send to within a window
+#[derive(Debug, Clone, Copy)]
+pub struct UdpDestinationLimit {
+    /// Maximum number of distinct destinations
+    pub max_destinations: usize,
+    /// Window of counting destinations, restarts after it elapsed
+    pub window: Duration,
+}
+
+/// Distinct destinations seen by an association in the current window
+struct DestinationTracker {
+    limit: UdpDestinationLimit,
+    window_start: Instant,
+    destinations: HashSet<Address>,
+    dropped: u64,
+}
+
+impl DestinationTracker {
+    fn new(limit: UdpDestinationLimit) -> DestinationTracker {
+        DestinationTracker {
+            limit,
+            window_start: Instant::now(),
+            destinations: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Check if packets could be sent to `target_addr` at `now`
+    fn allow(&mut self, target_addr: &Address, now: Instant) -> bool {
+        if now.saturating_duration_since(self.window_start) >= self.limit.window {
+            self.window_start = now;
+            self.destinations.clear();
+        }
+
+        if self.destinations.contains(target_addr) {
+            return true;
+        }
+
+        if self.destinations.len() >= self.limit.max_destinations {
+            self.dropped += 1;
+            return false;
+        }
+
+        self.destinations.insert(target_addr.clone());
+        true
+    }
+}
+
+/// Reason of closing an UDP association
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpAssociationCloseReason {
+    /// No packets were relayed within the expiry duration
+    IdleTimeout,
+    /// Evicted by a new association while the server reached its capacity
+    Evicted,
+    /// Relayed more bytes than its quota
+    QuotaExceeded,
+    /// Server was shut down
+    Shutdown,
+}
+
+impl Display for UdpAssociationCloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            UdpAssociationCloseReason::IdleTimeout => f.write_str("idle timeout"),
+            UdpAssociationCloseReason::Evicted => f.write_str("evicted"),
+            UdpAssociationCloseReason::QuotaExceeded => f.write_str("quota exceeded"),
+            UdpAssociationCloseReason::Shutdown => f.write_str("shutdown"),
+        }
+    }
+}
+
+/// Number of closed UDP associations by reasons
+#[derive(Debug, Default)]
+pub struct UdpAssociationCloseStat {
+    idle_timeout: AtomicUsize,
+    evicted: AtomicUsize,
+    quota_exceeded: AtomicUsize,
+    shutdown: AtomicUsize,
+}
+
+impl UdpAssociationCloseStat {
+    /// Number of associations closed because of `reason`
+    pub fn closed(&self, reason: UdpAssociationCloseReason) -> usize {
+        self.counter(reason).load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, reason: UdpAssociationCloseReason) {
+        self.counter(reason).fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn counter(&self, reason: UdpAssociationCloseReason) -> &AtomicUsize {
+        match reason {
+            UdpAssociationCloseReason::IdleTimeout => &self.idle_timeout,
+            UdpAssociationCloseReason::Evicted => &self.evicted,
+            UdpAssociationCloseReason::QuotaExceeded => &self.quota_exceeded,
+            UdpAssociationCloseReason::Shutdown => &self.shutdown,
+        }
+    }
+}
+
+/// Close reason shared by an association and its task, the first reason set is kept
+#[derive(Clone, Default)]
+struct CloseReasonCell(Arc<SpinMutex<Option<UdpAssociationCloseReason>>>);
+
+impl CloseReasonCell {
+    fn set(&self, reason: UdpAssociationCloseReason) {
+        let mut current = self.0.lock();
+        if current.is_none() {
+            *current = Some(reason);
+        }
+    }
+
+    fn get(&self) -> Option<UdpAssociationCloseReason> {
+        *self.0.lock()
+    }
+}
+
 pub struct UdpServer {
     context: Arc<ServiceContext>,
     assoc_map: AssociationMap,
+    capacity: Option<usize>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_rx: mpsc::Receiver<SocketAddr>,
     time_to_live: Duration,
     accept_opts: AcceptOpts,
+    destination_limit: Option<UdpDestinationLimit>,
+    padding: Option<UdpPaddingPolicy>,
+    max_association_bytes: Option<u64>,
+    channel_full_policy: UdpChannelFullPolicy,
 }
 
 impl UdpServer {
@@ -55,13 +197,40 @@ impl UdpServer {
         UdpServer {
             context,
             assoc_map,
+            capacity,
             keepalive_tx,
             keepalive_rx,
             time_to_live,
             accept_opts,
+            destination_limit: None,
+            padding: None,
+            max_association_bytes: None,
+            channel_full_policy: UdpChannelFullPolicy::Drop,
         }
     }
 
+    /// Set limit of distinct destinations per association, unlimited by default
+    pub fn set_destination_limit(&mut self, limit: Option<UdpDestinationLimit>) {
+        self.destination_limit = limit;
+    }
+
+    /// Set padding of packets relayed with clients, clients must enable the same padding
+    pub fn set_padding(&mut self, padding: Option<UdpPaddingPolicy>) {
+        self.padding = padding;
+    }
+
+    /// Set total bytes that one association could relay before it is closed, unlimited by default
+    ///
+    /// The next packet from the client creates a new association.
+    pub fn set_max_association_bytes(&mut self, max_bytes: Option<u64>) {
+        self.max_association_bytes = max_bytes;
+    }
+
+    /// Set behavior when an association's send channel is full, packets are dropped by default
+    pub fn set_channel_full_policy(&mut self, policy: UdpChannelFullPolicy) {
+        self.channel_full_policy = policy;
+    }
+
     pub async fn run(mut self, svr_cfg: &ServerConfig) -> io::Result<()> {
         let socket = ProxySocket::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts.clone()).await?;
 
@@ -70,7 +239,8 @@ impl UdpServer {
             socket.local_addr().expect("listener.local_addr"),
         );
 
-        let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+        let mut socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+        socket.set_padded(self.padding.is_some());
         let listener = Arc::new(socket);
 
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
@@ -121,7 +291,16 @@ impl UdpServer {
                         continue;
                     }
 
-                    let data = &buffer[..n];
+                    let data = match self.padding {
+                        None => &buffer[..n],
+                        Some(..) => match strip_udp_padding(&buffer[..n]) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                error!("udp client {} outbound {} failed, error: {}", peer_addr, target_addr, err);
+                                continue;
+                            }
+                        },
+                    };
                     if let Err(err) = self.send_packet(&listener, peer_addr, target_addr, data).await {
                         error!(
                             "udp packet relay {} with {} bytes failed, error: {}",
@@ -143,7 +322,23 @@ impl UdpServer {
         data: &[u8],
     ) -> io::Result<()> {
         if let Some(assoc) = self.assoc_map.get(&peer_addr) {
-            return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
+            if !assoc.is_closed() {
+                let data = (target_addr, Bytes::copy_from_slice(data));
+                assoc.send(data, self.channel_full_policy).await?;
+                return Ok(());
+            }
+
+            // Closed by itself, for example, exceeded its quota
+            self.assoc_map.remove(&peer_addr);
+        }
+
+        // Expired associations were removed by `get`, the least recently used one will be evicted by `insert`
+        if let Some(capacity) = self.capacity {
+            if self.assoc_map.len() >= capacity {
+                if let Some((_, assoc)) = self.assoc_map.peek_iter().last() {
+                    assoc.set_close_reason(UdpAssociationCloseReason::Evicted);
+                }
+            }
         }
 
         let assoc = UdpAssociation::new(
@@ -151,24 +346,39 @@ impl UdpServer {
             listener.clone(),
             peer_addr,
             self.keepalive_tx.clone(),
+            self.destination_limit,
+            self.padding,
+            self.max_association_bytes,
         );
 
         debug!("created udp association for {}", peer_addr);
 
-        assoc.try_send((target_addr, Bytes::copy_from_slice(data)))?;
+        let data = (target_addr, Bytes::copy_from_slice(data));
+        assoc.send(data, self.channel_full_policy).await?;
         self.assoc_map.insert(peer_addr, assoc);
 
         Ok(())
     }
 }
 
+impl Drop for UdpServer {
+    fn drop(&mut self) {
+        for (_, assoc) in self.assoc_map.peek_iter() {
+            assoc.set_close_reason(UdpAssociationCloseReason::Shutdown);
+        }
+    }
+}
+
 struct UdpAssociation {
     assoc_handle: JoinHandle<()>,
-    sender: mpsc::Sender<(Address, Bytes)>,
+    sender: UdpChannelSender<(Address, Bytes)>,
+    close_reason: CloseReasonCell,
 }
 
 impl Drop for UdpAssociation {
     fn drop(&mut self) {
+        // Associations dropped by the map without a reason are the expired ones
+        self.close_reason.set(UdpAssociationCloseReason::IdleTimeout);
         self.assoc_handle.abort();
     }
 }
@@ -179,17 +389,36 @@ impl UdpAssociation {
         inbound: Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
+        destination_limit: Option<UdpDestinationLimit>,
+        padding: Option<UdpPaddingPolicy>,
+        max_bytes: Option<u64>,
     ) -> UdpAssociation {
-        let (assoc_handle, sender) = UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx);
-        UdpAssociation { assoc_handle, sender }
+        let (assoc_handle, sender, close_reason) = UdpAssociationContext::create(
+            context,
+            inbound,
+            peer_addr,
+            keepalive_tx,
+            destination_limit,
+            padding,
+            max_bytes,
+        );
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            close_reason,
+        }
     }
 
-    fn try_send(&self, data: (Address, Bytes)) -> io::Result<()> {
-        if let Err(..) = self.sender.try_send(data) {
-            let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
-            return Err(err);
-        }
-        Ok(())
+    fn set_close_reason(&self, reason: UdpAssociationCloseReason) {
+        self.close_reason.set(reason);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    async fn send(&self, data: (Address, Bytes), policy: UdpChannelFullPolicy) -> Result<(), UdpRelaySendError> {
+        send_to_channel(&self.sender, data, policy).await
     }
 }
 
@@ -199,13 +428,21 @@ struct UdpAssociationContext {
     outbound_ipv4_socket: Option<OutboundUdpSocket>,
     outbound_ipv6_socket: Option<OutboundUdpSocket>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
-    keepalive_flag: bool,
+    keepalive_throttle: KeepAliveThrottle,
     inbound: Arc<MonProxySocket>,
+    destination_tracker: Option<DestinationTracker>,
+    padding: Option<UdpPaddingPolicy>,
+    max_bytes: Option<u64>,
+    relayed_bytes: u64,
+    close_reason: CloseReasonCell,
 }
 
 impl Drop for UdpAssociationContext {
     fn drop(&mut self) {
-        debug!("udp association for {} is closed", self.peer_addr);
+        // Task is dropped by the runtime without being aborted
+        let reason = self.close_reason.get().unwrap_or(UdpAssociationCloseReason::Shutdown);
+        debug!("udp association for {} is closed, reason: {}", self.peer_addr, reason);
+        self.context.udp_close_stat_ref().record(reason);
     }
 }
 
@@ -215,11 +452,15 @@ impl UdpAssociationContext {
         inbound: Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<SocketAddr>,
-    ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
+        destination_limit: Option<UdpDestinationLimit>,
+        padding: Option<UdpPaddingPolicy>,
+        max_bytes: Option<u64>,
+    ) -> (JoinHandle<()>, UdpChannelSender<(Address, Bytes)>, CloseReasonCell) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
-        let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+        let (sender, receiver) = udp_channel::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+        let close_reason = CloseReasonCell::default();
 
         let mut assoc = UdpAssociationContext {
             context,
@@ -227,20 +468,35 @@ impl UdpAssociationContext {
             outbound_ipv4_socket: None,
             outbound_ipv6_socket: None,
             keepalive_tx,
-            keepalive_flag: false,
+            keepalive_throttle: KeepAliveThrottle::default(),
             inbound,
+            destination_tracker: destination_limit.map(DestinationTracker::new),
+            padding,
+            max_bytes,
+            relayed_bytes: 0,
+            close_reason: close_reason.clone(),
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
-        (handle, sender)
+        (handle, sender, close_reason)
     }
 
-    async fn dispatch_packet(&mut self, mut receiver: mpsc::Receiver<(Address, Bytes)>) {
+    async fn dispatch_packet(&mut self, mut receiver: UdpChannelReceiver<(Address, Bytes)>) {
         let mut outbound_ipv4_buffer = Vec::new();
         let mut outbound_ipv6_buffer = Vec::new();
-        let mut keepalive_interval = time::interval(Duration::from_secs(1));
 
         loop {
+            if let Some(max_bytes) = self.max_bytes {
+                if self.relayed_bytes > max_bytes {
+                    warn!(
+                        "udp association for {} relayed {} bytes, exceeded quota {} bytes, closing",
+                        self.peer_addr, self.relayed_bytes, max_bytes
+                    );
+                    self.close_reason.set(UdpAssociationCloseReason::QuotaExceeded);
+                    break;
+                }
+            }
+
             tokio::select! {
                 packet_received_opt = receiver.recv() => {
                     let (target_addr, data) = match packet_received_opt {
@@ -283,16 +539,6 @@ impl UdpAssociationContext {
                     let addr = Address::from(addr);
                     self.send_received_respond_packet(&addr, &outbound_ipv6_buffer[..n]).await;
                 }
-
-                _ = keepalive_interval.tick() => {
-                    if self.keepalive_flag {
-                        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
-                            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
-                        } else {
-                            self.keepalive_flag = false;
-                        }
-                    }
-                }
             }
         }
 
@@ -329,14 +575,27 @@ impl UdpAssociationContext {
             return;
         }
 
-        if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
-            error!(
-                "udp relay {} -> {} with {} bytes, error: {}",
-                self.peer_addr,
-                target_addr,
-                data.len(),
-                err
-            );
+        if let Some(ref mut tracker) = self.destination_tracker {
+            if !tracker.allow(target_addr, Instant::now()) {
+                warn!(
+                    "udp client {} outbound {} dropped, exceeded {} destinations in {:?} ({} dropped)",
+                    self.peer_addr, target_addr, tracker.limit.max_destinations, tracker.limit.window, tracker.dropped
+                );
+                return;
+            }
+        }
+
+        match self.dispatch_received_outbound_packet(target_addr, data).await {
+            Ok(..) => self.relayed_bytes += data.len() as u64,
+            Err(err) => {
+                error!(
+                    "udp relay {} -> {} with {} bytes, error: {}",
+                    self.peer_addr,
+                    target_addr,
+                    data.len(),
+                    err
+                );
+            }
         }
     }
 
@@ -386,11 +645,45 @@ impl UdpAssociationContext {
         Ok(())
     }
 
+    fn keep_alive(&mut self) {
+        // Keep association alive in map, refreshes are coalesced to avoid flooding the manager
+        if !self.keepalive_throttle.should_refresh() {
+            return;
+        }
+
+        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
+            debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
+        } else {
+            self.keepalive_throttle.refreshed();
+        }
+    }
+
     async fn send_received_respond_packet(&mut self, addr: &Address, data: &[u8]) {
         trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
 
-        // Keep association alive in map
-        self.keepalive_flag = true;
+        self.keep_alive();
+
+        let payload_len = data.len();
+        let padded;
+        let data = match self.padding {
+            None => data,
+            Some(ref padding) => match padding.pad(data) {
+                Ok(p) => {
+                    padded = p;
+                    &padded
+                }
+                Err(err) => {
+                    warn!(
+                        "udp failed to pad {} bytes to client {}, from target {}, error: {}",
+                        data.len(),
+                        self.peer_addr,
+                        addr,
+                        err
+                    );
+                    return;
+                }
+            },
+        };
 
         // Send back to client
         if let Err(err) = self.inbound.send_to(self.peer_addr, addr, data).await {
@@ -403,6 +696,165 @@ impl UdpAssociationContext {
             );
         } else {
             trace!("udp relay {} <- {} with {} bytes", self.peer_addr, addr, data.len());
+            self.relayed_bytes += payload_len as u64;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use shadowsocks::crypto::v1::CipherKind;
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn destination_limit_exceeded() {
+        let limit = UdpDestinationLimit {
+            max_destinations: 16,
+            window: Duration::from_secs(60),
+        };
+        let mut tracker = DestinationTracker::new(limit);
+        let now = Instant::now();
+
+        let target = |port: u16| Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), port));
+        for port in 0..16 {
+            assert!(tracker.allow(&target(port), now));
+        }
+        for port in 16..1000 {
+            assert!(!tracker.allow(&target(port), now));
+        }
+        assert_eq!(tracker.dropped, 984);
+
+        // Destinations already seen are still allowed
+        assert!(tracker.allow(&target(0), now));
+        assert!(tracker.allow(&target(15), now));
+
+        // New window
+        assert!(tracker.allow(&target(1000), now + limit.window));
+        assert_eq!(tracker.destinations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn association_closed_exceeding_bytes_quota() {
+        const PACKET_SIZE: usize = 1000;
+        const MAX_BYTES: u64 = 8 * PACKET_SIZE as u64;
+
+        // Echo server as target
+        let target = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let target_addr = Address::SocketAddress(target.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buffer = [0u8; PACKET_SIZE];
+            while let Ok((n, addr)) = target.recv_from(&mut buffer).await {
+                let _ = target.send_to(&buffer[..n], addr).await;
+            }
+        });
+
+        let context = Arc::new(ServiceContext::new());
+        let inbound = bind_inbound(&context).await;
+        // Responses are sent to a client that never reads
+        let client = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let (keepalive_tx, _keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
+
+        let assoc = UdpAssociation::new(
+            context.clone(),
+            inbound,
+            client.local_addr().unwrap(),
+            keepalive_tx,
+            None,
+            None,
+            Some(MAX_BYTES),
+        );
+
+        // Each packet relays PACKET_SIZE bytes to the target and PACKET_SIZE bytes back
+        let mut sent = 0;
+        while !assoc.is_closed() {
+            assoc
+                .send(
+                    (target_addr.clone(), Bytes::from(vec![0u8; PACKET_SIZE])),
+                    UdpChannelFullPolicy::Drop,
+                )
+                .await
+                .unwrap();
+            sent += 1;
+            assert!(sent <= 20, "association was not closed after {} packets", sent);
+            time::sleep(Duration::from_millis(20)).await;
+
+            if sent < 4 {
+                assert!(!assoc.is_closed());
+            }
+        }
+        assert!(sent > 4);
+
+        wait_closed(&context, UdpAssociationCloseReason::QuotaExceeded, 1).await;
+    }
+
+    #[tokio::test]
+    async fn association_close_reasons() {
+        let target = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let target_addr = Address::SocketAddress(target.local_addr().unwrap());
+
+        let context = Arc::new(ServiceContext::new());
+        let inbound = bind_inbound(&context).await;
+        let time_to_live = Duration::from_millis(200);
+        let mut server = UdpServer::new(context.clone(), Some(time_to_live), Some(1), AcceptOpts::default());
+
+        let peer_addr = |port: u16| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+
+        // The server could keep only 1 association
+        server
+            .send_packet(&inbound, peer_addr(50000), target_addr.clone(), b"first")
+            .await
+            .unwrap();
+        server
+            .send_packet(&inbound, peer_addr(50001), target_addr.clone(), b"second")
+            .await
+            .unwrap();
+        wait_closed(&context, UdpAssociationCloseReason::Evicted, 1).await;
+
+        time::sleep(time_to_live * 2).await;
+        let _ = server.assoc_map.iter();
+        wait_closed(&context, UdpAssociationCloseReason::IdleTimeout, 1).await;
+
+        server
+            .send_packet(&inbound, peer_addr(50002), target_addr, b"third")
+            .await
+            .unwrap();
+        drop(server);
+        wait_closed(&context, UdpAssociationCloseReason::Shutdown, 1).await;
+
+        let stat = context.udp_close_stat_ref();
+        assert_eq!(stat.closed(UdpAssociationCloseReason::Evicted), 1);
+        assert_eq!(stat.closed(UdpAssociationCloseReason::IdleTimeout), 1);
+    }
+
+    async fn bind_inbound(context: &ServiceContext) -> Arc<MonProxySocket> {
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        let inbound = ProxySocket::bind_with_opts(context.context(), &svr_cfg, AcceptOpts::default())
+            .await
+            .unwrap();
+        Arc::new(MonProxySocket::from_socket(inbound, context.flow_stat()))
+    }
+
+    async fn wait_closed(context: &ServiceContext, reason: UdpAssociationCloseReason, expected: usize) {
+        time::timeout(Duration::from_secs(5), async {
+            while context.udp_close_stat_ref().closed(reason) != expected {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("associations closed with reason {} are not {}", reason, expected));
+    }
+}
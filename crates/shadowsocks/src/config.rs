@@ -14,6 +14,8 @@ use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 use log::error;
 use url::{self, Url};
 
+#[cfg(feature = "udp-quic")]
+use crate::relay::udprelay::QuicTransportConfig;
 use crate::{
     crypto::v1::{openssl_bytes_to_key, CipherKind},
     plugin::PluginConfig,
@@ -97,6 +99,45 @@ impl FromStr for Mode {
     }
 }
 
+/// Strategy of connecting to addresses resolved from a domain name
+///
+/// Only matters if the domain name could be resolved to both IPv4 and IPv6 addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResolveStrategy {
+    /// Try IPv4 addresses first, then IPv6 addresses
+    Ipv4First,
+    /// Try IPv6 addresses first, then IPv4 addresses
+    Ipv6First,
+    /// Happy Eyeballs (RFC8305), the address family preferred by `Context::ipv6_first` is tried first
+    /// and the other starts after a short delay
+    ///
+    /// UDP sockets are not connected in parallel, they try the preferred family first.
+    Parallel,
+}
+
+impl fmt::Display for ResolveStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveStrategy::Ipv4First => f.write_str("ipv4_first"),
+            ResolveStrategy::Ipv6First => f.write_str("ipv6_first"),
+            ResolveStrategy::Parallel => f.write_str("parallel"),
+        }
+    }
+}
+
+impl FromStr for ResolveStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4_first" => Ok(ResolveStrategy::Ipv4First),
+            "ipv6_first" => Ok(ResolveStrategy::Ipv6First),
+            "parallel" => Ok(ResolveStrategy::Parallel),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Server's weight
 ///
 /// Commonly for using in balancer
@@ -173,6 +214,19 @@ pub struct ServerConfig {
 
     /// Weight
     weight: ServerWeight,
+
+    /// Strategy of connecting to the resolved addresses if `addr` is a domain name
+    resolve_strategy: Option<ResolveStrategy>,
+
+    /// Name of the server pool that this server belongs to
+    pool: Option<String>,
+
+    /// Maximum flows relaying through this server simultaneously
+    max_flows: Option<usize>,
+
+    /// Relay UDP packets inside a QUIC connection
+    #[cfg(feature = "udp-quic")]
+    quic_transport: Option<QuicTransportConfig>,
 }
 
 impl ServerConfig {
@@ -199,6 +253,11 @@ impl ServerConfig {
             id: None,
             mode: Mode::TcpAndUdp, // Server serves TCP & UDP by default
             weight: ServerWeight::new(),
+            resolve_strategy: None,
+            pool: None,
+            max_flows: None,
+            #[cfg(feature = "udp-quic")]
+            quic_transport: None,
         }
     }
 
@@ -325,6 +384,61 @@ impl ServerConfig {
         self.weight = weight;
     }
 
+    /// Get strategy of connecting to server's resolved addresses
+    ///
+    /// `None` follows the default behavior, which is `ResolveStrategy::Parallel` for TCP
+    pub fn resolve_strategy(&self) -> Option<ResolveStrategy> {
+        self.resolve_strategy
+    }
+
+    /// Set strategy of connecting to server's resolved addresses
+    pub fn set_resolve_strategy(&mut self, strategy: ResolveStrategy) {
+        self.resolve_strategy = Some(strategy);
+    }
+
+    /// Get name of the server pool
+    ///
+    /// `None` if the server is in the default pool
+    pub fn pool(&self) -> Option<&str> {
+        self.pool.as_deref()
+    }
+
+    /// Set name of the server pool
+    pub fn set_pool<S>(&mut self, pool: S)
+    where
+        S: Into<String>,
+    {
+        self.pool = Some(pool.into());
+    }
+
+    /// Get maximum flows relaying through this server simultaneously
+    ///
+    /// `None` if unlimited
+    pub fn max_flows(&self) -> Option<usize> {
+        self.max_flows
+    }
+
+    /// Set maximum flows relaying through this server simultaneously
+    ///
+    /// Local's balancer skips the server while it is at capacity when choosing servers of its pool.
+    pub fn set_max_flows(&mut self, max_flows: usize) {
+        self.max_flows = Some(max_flows);
+    }
+
+    /// Get QUIC transport of the UDP relay
+    ///
+    /// `None` if UDP packets are sent to the server directly
+    #[cfg(feature = "udp-quic")]
+    pub fn quic_transport(&self) -> Option<&QuicTransportConfig> {
+        self.quic_transport.as_ref()
+    }
+
+    /// Relay UDP packets inside a QUIC connection, the server must have a QUIC endpoint on its address
+    #[cfg(feature = "udp-quic")]
+    pub fn set_quic_transport(&mut self, quic_transport: QuicTransportConfig) {
+        self.quic_transport = Some(quic_transport);
+    }
+
     /// Get URL for QRCode
     /// ```plain
     /// ss:// + base64(method:password@host:port)
@@ -423,6 +537,7 @@ impl ServerConfig {
                             plugin: p.to_owned(),
                             plugin_opts: vsp.next().map(ToOwned::to_owned),
                             plugin_args: Vec::new(), // SIP002 doesn't have arguments for plugins
+                            flow_metadata: false,
                         };
                         svrconfig.set_plugin(plugin);
                     }
@@ -8,12 +8,24 @@ mod resolver;
 mod trust_dns_resolver;
 
 /// Helper macro for resolving host and then process each addresses
+///
+/// An optional `Option<ResolveStrategy>` decides which address family is tried first, `None` follows
+/// `Context::ipv6_first`.
 #[macro_export]
 macro_rules! lookup_then {
-    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {
+        lookup_then!($context, $addr, $port, None, |$resolved_addr| $body)
+    };
+
+    ($context:expr, $addr:expr, $port:expr, $strategy:expr, |$resolved_addr:ident| $body:block) => {{
         use std::net::SocketAddr;
+        use $crate::config::ResolveStrategy;
 
-        let ipv6_first = $context.ipv6_first();
+        let ipv6_first = match $strategy {
+            Some(ResolveStrategy::Ipv4First) => false,
+            Some(ResolveStrategy::Ipv6First) => true,
+            Some(ResolveStrategy::Parallel) | None => $context.ipv6_first(),
+        };
 
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
@@ -68,15 +80,28 @@ macro_rules! lookup_then {
     }};
 }
 
+/// Helper macro for resolving host and then connect to the addresses
+///
+/// An optional `Option<ResolveStrategy>` decides how to connect if both IPv4 and IPv6 addresses are resolved,
+/// `None` behaves as `ResolveStrategy::Parallel`.
 #[macro_export]
 macro_rules! lookup_then_connect {
-    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {
+        lookup_then_connect!($context, $addr, $port, None, |$resolved_addr| $body)
+    };
+
+    ($context:expr, $addr:expr, $port:expr, $strategy:expr, |$resolved_addr:ident| $body:block) => {{
         use futures::future::{self, Either};
         use log::trace;
         use std::{net::SocketAddr, time::Duration};
         use tokio::time;
+        use $crate::config::ResolveStrategy;
 
-        let ipv6_first = $context.ipv6_first();
+        let (ipv6_first, parallel) = match $strategy {
+            Some(ResolveStrategy::Ipv4First) => (false, false),
+            Some(ResolveStrategy::Ipv6First) => (true, false),
+            Some(ResolveStrategy::Parallel) | None => ($context.ipv6_first(), true),
+        };
 
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
@@ -181,6 +206,18 @@ macro_rules! lookup_then_connect {
             connect_v4.await
         } else if !has_v4 && has_v6 {
             connect_v6.await
+        } else if !parallel {
+            if ipv6_first {
+                match connect_v6.await {
+                    Ok(res) => Ok(res),
+                    Err(_v6_err) => connect_v4.await,
+                }
+            } else {
+                match connect_v4.await {
+                    Ok(res) => Ok(res),
+                    Err(_v4_err) => connect_v6.await,
+                }
+            }
         } else {
             if ipv6_first {
                 let v4_fut = async move {
@@ -226,3 +263,100 @@ macro_rules! lookup_then_connect {
         }
     }};
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+
+    use crate::{
+        config::{ResolveStrategy, ServerType},
+        context::Context,
+    };
+
+    use super::*;
+
+    struct MockResolver;
+
+    #[async_trait]
+    impl DnsResolve for MockResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![
+                SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+                SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), port),
+            ])
+        }
+    }
+
+    fn mock_context(ipv6_first: bool) -> Context {
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(MockResolver)));
+        context.set_ipv6_first(ipv6_first);
+        context
+    }
+
+    /// Attempts every resolved addresses by failing all of them, returns the attempt order
+    async fn attempt_order(
+        context: &Context,
+        strategy: Option<ResolveStrategy>,
+        connect: bool,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let attempts = Mutex::new(Vec::new());
+        let result: io::Result<(SocketAddr, ())> = if connect {
+            lookup_then_connect!(context, "example.com", 443, strategy, |addr| {
+                attempts.lock().unwrap().push(addr);
+                Err::<(), _>(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+            })
+        } else {
+            lookup_then!(context, "example.com", 443, strategy, |addr| {
+                attempts.lock().unwrap().push(addr);
+                Err::<(), _>(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+            })
+        };
+        assert!(result.is_err());
+        Ok(attempts.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn resolve_strategy_attempt_order() -> io::Result<()> {
+        let v4_1 = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 443);
+        let v4_2 = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 443);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+
+        let context = mock_context(true);
+        for connect in [false, true] {
+            assert_eq!(
+                attempt_order(&context, Some(ResolveStrategy::Ipv4First), connect).await?,
+                [v4_1, v4_2, v6]
+            );
+            assert_eq!(
+                attempt_order(&context, Some(ResolveStrategy::Ipv6First), connect).await?,
+                [v6, v4_1, v4_2]
+            );
+            // Follows `ipv6_first` of context
+            assert_eq!(attempt_order(&context, None, connect).await?, [v6, v4_1, v4_2]);
+            assert_eq!(
+                attempt_order(&mock_context(false), None, connect).await?,
+                [v4_1, v4_2, v6]
+            );
+        }
+
+        // IPv4 never finishes connecting, IPv6 starts after the delay
+        let context = mock_context(false);
+        let (addr, _) = lookup_then_connect!(context, "example.com", 443, Some(ResolveStrategy::Parallel), |addr| {
+            match addr {
+                SocketAddr::V4(..) => futures::future::pending::<io::Result<()>>().await,
+                SocketAddr::V6(..) => Ok(()),
+            }
+        })?;
+        assert_eq!(addr, v6);
+
+        Ok(())
+    }
+}
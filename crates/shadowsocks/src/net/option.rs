@@ -20,6 +20,17 @@ pub struct TcpSocketOpts {
     /// `SO_KEEPALIVE` and sets `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and `TCP_KEEPCNT` respectively,
     /// enables keep-alive messages on connection-oriented sockets
     pub keepalive: Option<Duration>,
+
+    /// Hint of the initial congestion window, in segments
+    ///
+    /// Operating systems only support it per route, so it is ignored by system sockets. It is only honored by
+    /// userspace TCP stacks, such as `sslocal`'s tun mode.
+    pub initial_window: Option<u32>,
+
+    /// `IP_TOS` or `IPV6_TCLASS` by the remote's address family, traffic class of packets sent to remote
+    ///
+    /// Only the DSCP bits are effective, ECN bits are managed by the operating system. Ignored on Windows.
+    pub tos: Option<u8>,
 }
 
 /// Options for connecting to remote server
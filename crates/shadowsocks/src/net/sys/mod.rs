@@ -44,6 +44,12 @@ fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, opts: &C
         socket.set_recv_buffer_size(buf_size)?;
     }
 
+    // Set `IP_TOS` / `IPV6_TCLASS` before connecting, so SYN is marked too
+    #[cfg(unix)]
+    if let Some(tos) = opts.tcp.tos {
+        set_traffic_class(socket, addr, tos)?;
+    }
+
     Ok(())
 }
 
@@ -1,5 +1,6 @@
 use std::{
     io,
+    mem,
     net::SocketAddr,
     os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
 };
@@ -48,6 +49,28 @@ pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool) -> io
     }
 }
 
+/// Set `IP_TOS` or `IPV6_TCLASS` of `socket` by the address family of `addr`
+pub fn set_traffic_class<S: AsRawFd>(socket: &S, addr: SocketAddr, tos: u8) -> io::Result<()> {
+    let (level, name) = match addr {
+        SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_TOS),
+        SocketAddr::V6(..) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+    let value = tos as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const _,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 pub fn set_common_sockopt_after_connect<S: AsRawFd>(stream: &S, opts: &ConnectOpts) -> io::Result<()> {
     let socket = unsafe { Socket::from_raw_fd(stream.as_raw_fd()) };
 
@@ -20,7 +20,7 @@ use tokio::{
     net::{TcpListener as TokioTcpListener, TcpSocket, TcpStream as TokioTcpStream},
 };
 
-use crate::{context::Context, relay::socks5::Address, ServerAddr};
+use crate::{config::ResolveStrategy, context::Context, relay::socks5::Address, ServerAddr};
 
 use super::{
     is_dual_stack_addr,
@@ -45,11 +45,21 @@ impl TcpStream {
         context: &Context,
         addr: &ServerAddr,
         opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        TcpStream::connect_server_with_strategy(context, addr, None, opts).await
+    }
+
+    /// Connects shadowsocks server, choosing the resolved addresses with `strategy`
+    pub async fn connect_server_with_strategy(
+        context: &Context,
+        addr: &ServerAddr,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
     ) -> io::Result<TcpStream> {
         let stream = match *addr {
             ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
             ServerAddr::DomainName(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
+                lookup_then_connect!(context, domain, port, strategy, |addr| {
                     SysTcpStream::connect(addr, opts).await
                 })?
                 .1
@@ -64,11 +74,21 @@ impl TcpStream {
         context: &Context,
         addr: &Address,
         opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        TcpStream::connect_remote_with_strategy(context, addr, None, opts).await
+    }
+
+    /// Connects proxy remote target, choosing the resolved addresses with `strategy`
+    pub async fn connect_remote_with_strategy(
+        context: &Context,
+        addr: &Address,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
     ) -> io::Result<TcpStream> {
         let stream = match *addr {
             Address::SocketAddress(ref addr) => SysTcpStream::connect(*addr, opts).await?,
             Address::DomainNameAddress(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
+                lookup_then_connect!(context, domain, port, strategy, |addr| {
                     SysTcpStream::connect(addr, opts).await
                 })?
                 .1
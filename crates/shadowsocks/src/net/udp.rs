@@ -8,7 +8,7 @@ use std::{
 
 use pin_project::pin_project;
 
-use crate::{context::Context, relay::socks5::Address, ServerAddr};
+use crate::{config::ResolveStrategy, context::Context, relay::socks5::Address, ServerAddr};
 
 use super::{
     sys::{create_inbound_udp_socket, create_outbound_udp_socket},
@@ -27,6 +27,16 @@ impl UdpSocket {
         context: &Context,
         addr: &ServerAddr,
         opts: &ConnectOpts,
+    ) -> io::Result<UdpSocket> {
+        UdpSocket::connect_server_with_strategy(context, addr, None, opts).await
+    }
+
+    /// Connects to shadowsocks server, choosing the resolved addresses with `strategy`
+    pub async fn connect_server_with_strategy(
+        context: &Context,
+        addr: &ServerAddr,
+        strategy: Option<ResolveStrategy>,
+        opts: &ConnectOpts,
     ) -> io::Result<UdpSocket> {
         let socket = match *addr {
             ServerAddr::SocketAddr(ref remote_addr) => {
@@ -35,7 +45,7 @@ impl UdpSocket {
                 socket
             }
             ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(context, dname, port, |remote_addr| {
+                lookup_then!(context, dname, port, strategy, |remote_addr| {
                     let s = create_outbound_udp_socket(From::from(&remote_addr), opts).await?;
                     s.connect(remote_addr).await.map(|_| s)
                 })?
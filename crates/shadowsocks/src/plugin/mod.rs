@@ -11,18 +11,41 @@
 //! |  SS Server +-- Local Loopback --+  Plugin Server (Tunnel)   +--+
 //! +------------+                    +---------------------------+
 //! ```
+//!
+//! ## Flow Metadata
+//!
+//! Local's plugins could opt in to receive metadata of every flow with `PluginConfig::flow_metadata`. These plugins
+//! are started with environment variable `SS_PLUGIN_FLOW_METADATA=1`, and one line is written to their stdin for each
+//! connection made to them:
+//!
+//! ```plain
+//! <CONNECTION ADDRESS> <CLIENT ADDRESS> <TARGET ADDRESS>\n
+//! ```
+//!
+//! - `CONNECTION ADDRESS`: peer address of the connection accepted by the plugin, for matching lines to connections
+//! - `CLIENT ADDRESS`: address of the client that initiated the flow
+//! - `TARGET ADDRESS`: destination of the flow, `host:port` or `ip:port`
+//!
+//! Lines are queued right after the connection is established, plugins must not expect them to arrive before the
+//! connection is accepted. Lines are dropped if the plugin doesn't read its stdin fast enough.
 
 use std::{
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
-    process::ExitStatus,
+    process::{ExitStatus, Stdio},
     time::{Duration, Instant},
 };
 
-use log::{debug, error};
-use tokio::{net::TcpStream, process::Child, time};
+use log::{debug, error, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    process::{Child, ChildStdin},
+    sync::mpsc,
+    time,
+};
 
-use crate::config::ServerAddr;
+use crate::{config::ServerAddr, relay::socks5::Address};
 
 mod obfs_proxy;
 mod ss_plugin;
@@ -33,6 +56,8 @@ pub struct PluginConfig {
     pub plugin: String,
     pub plugin_opts: Option<String>,
     pub plugin_args: Vec<String>,
+    /// Plugin opts in to receive flow metadata from stdin, only for local's plugins
+    pub flow_metadata: bool,
 }
 
 /// Mode of Plugin
@@ -56,10 +81,33 @@ pub enum PluginMode {
     Client,
 }
 
+/// Maximum lines of flow metadata waiting to be written to plugin
+const FLOW_METADATA_QUEUE_SIZE: usize = 1024;
+
+/// Sends flow metadata to plugins which opted in with `PluginConfig::flow_metadata`
+#[derive(Debug, Clone)]
+pub struct PluginFlowMetadataSender {
+    tx: mpsc::Sender<String>,
+}
+
+impl PluginFlowMetadataSender {
+    /// Send metadata of the flow connected to plugin from `conn_addr`
+    pub fn send(&self, conn_addr: SocketAddr, client_addr: SocketAddr, target_addr: &Address) {
+        let line = format!("{} {} {}\n", conn_addr, client_addr, target_addr);
+        if self.tx.try_send(line).is_err() {
+            warn!(
+                "plugin flow metadata of {} <-> {} dropped, plugin is not reading or has exited",
+                client_addr, target_addr
+            );
+        }
+    }
+}
+
 /// A shadowsocks SIP004 Plugin
 pub struct Plugin {
     process: Child,
     local_addr: SocketAddr,
+    flow_metadata: Option<PluginFlowMetadataSender>,
 }
 
 impl Plugin {
@@ -78,7 +126,9 @@ impl Plugin {
 
         let local_addr = get_local_port(loop_ip)?;
 
-        match start_plugin(c, remote_addr, &local_addr, mode) {
+        let flow_metadata = c.flow_metadata && matches!(mode, PluginMode::Client);
+
+        match start_plugin(c, remote_addr, &local_addr, mode, flow_metadata) {
             Err(err) => {
                 error!(
                     "failed to start plugin \"{}\" for server {}, err: {}",
@@ -86,7 +136,7 @@ impl Plugin {
                 );
                 Err(err)
             }
-            Ok(process) => {
+            Ok(mut process) => {
                 match mode {
                     PluginMode::Client => {
                         debug!(
@@ -108,7 +158,20 @@ impl Plugin {
                     }
                 }
 
-                Ok(Plugin { process, local_addr })
+                let flow_metadata = match process.stdin.take() {
+                    Some(stdin) if flow_metadata => {
+                        let (tx, rx) = mpsc::channel(FLOW_METADATA_QUEUE_SIZE);
+                        tokio::spawn(write_flow_metadata(stdin, rx));
+                        Some(PluginFlowMetadataSender { tx })
+                    }
+                    _ => None,
+                };
+
+                Ok(Plugin {
+                    process,
+                    local_addr,
+                    flow_metadata,
+                })
             }
         }
     }
@@ -146,6 +209,11 @@ impl Plugin {
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+
+    /// Get sender of flow metadata, `None` if plugin didn't opt in
+    pub fn flow_metadata_sender(&self) -> Option<PluginFlowMetadataSender> {
+        self.flow_metadata.clone()
+    }
 }
 
 impl Drop for Plugin {
@@ -220,15 +288,33 @@ impl Drop for Plugin {
     }
 }
 
-fn start_plugin(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, mode: PluginMode) -> io::Result<Child> {
+fn start_plugin(
+    plugin: &PluginConfig,
+    remote: &ServerAddr,
+    local: &SocketAddr,
+    mode: PluginMode,
+    flow_metadata: bool,
+) -> io::Result<Child> {
     let mut cmd = if plugin.plugin == "obfsproxy" {
         obfs_proxy::plugin_cmd(plugin, remote, local, mode)
     } else {
         ss_plugin::plugin_cmd(plugin, remote, local, mode)
     };
+    if flow_metadata {
+        cmd.env("SS_PLUGIN_FLOW_METADATA", "1").stdin(Stdio::piped());
+    }
     cmd.spawn()
 }
 
+async fn write_flow_metadata(mut stdin: ChildStdin, mut rx: mpsc::Receiver<String>) {
+    while let Some(line) = rx.recv().await {
+        if let Err(err) = stdin.write_all(line.as_bytes()).await {
+            error!("failed to write flow metadata to plugin, error: {}", err);
+            break;
+        }
+    }
+}
+
 fn get_local_port(loop_ip: IpAddr) -> io::Result<SocketAddr> {
     let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
     listener.local_addr()
@@ -244,4 +330,36 @@ mod test {
         let addr = get_local_port(loop_ip).unwrap();
         println!("{:?}", addr);
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn flow_metadata_echo() {
+        let echo_path = std::env::temp_dir().join(format!("ss-plugin-flow-metadata-{}", std::process::id()));
+
+        // Dummy plugin echoes the first line of metadata to `echo_path`
+        let c = PluginConfig {
+            plugin: "sh".to_owned(),
+            plugin_opts: None,
+            plugin_args: vec![
+                "-c".to_owned(),
+                r#"[ "$SS_PLUGIN_FLOW_METADATA" = 1 ] && read -r line && echo "$line" > "$0""#.to_owned(),
+                echo_path.display().to_string(),
+            ],
+            flow_metadata: true,
+        };
+        let remote_addr = ServerAddr::from("127.0.0.1:8388".parse::<SocketAddr>().unwrap());
+        let plugin = Plugin::start(&c, &remote_addr, PluginMode::Client).unwrap();
+
+        let sender = plugin.flow_metadata_sender().unwrap();
+        sender.send(
+            "127.0.0.1:50000".parse().unwrap(),
+            "192.168.1.2:60000".parse().unwrap(),
+            &Address::DomainNameAddress("example.com".to_owned(), 443),
+        );
+        assert!(plugin.join().await.unwrap().success());
+
+        let echoed = std::fs::read_to_string(&echo_path).unwrap();
+        let _ = std::fs::remove_file(&echo_path);
+        assert_eq!(echoed, "127.0.0.1:50000 192.168.1.2:60000 example.com:443\n");
+    }
 }
@@ -1,7 +1,8 @@
 //! Relay server in local and server side implementations.
 
-pub use self::socks5::Address;
+pub use self::{overhead::CipherOverhead, socks5::Address};
 
+mod overhead;
 pub mod socks5;
 pub mod tcprelay;
 pub mod udprelay;
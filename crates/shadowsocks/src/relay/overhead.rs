@@ -0,0 +1,98 @@
+//! Encryption and framing overhead of ciphers
+
+use crate::crypto::v1::{CipherCategory, CipherKind};
+
+/// Bytes added by a cipher to the relayed data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CipherOverhead {
+    /// Salt (AEAD) or IV (stream) sent before the first chunk of a TCP stream and in every UDP packet
+    pub salt_len: usize,
+    /// Authentication tag of every encrypted chunk, 0 for stream ciphers
+    pub tag_len: usize,
+    /// Encrypted length prefix of every TCP chunk including its tag, 0 for stream ciphers
+    pub length_prefix_len: usize,
+}
+
+impl CipherOverhead {
+    /// Overhead of `method`
+    pub fn new(method: CipherKind) -> CipherOverhead {
+        match method.category() {
+            CipherCategory::None => CipherOverhead {
+                salt_len: 0,
+                tag_len: 0,
+                length_prefix_len: 0,
+            },
+            #[cfg(feature = "stream-cipher")]
+            CipherCategory::Stream => CipherOverhead {
+                salt_len: method.iv_len(),
+                tag_len: 0,
+                length_prefix_len: 0,
+            },
+            CipherCategory::Aead => CipherOverhead {
+                salt_len: method.salt_len(),
+                tag_len: method.tag_len(),
+                // Big-endian u16 length, sealed with its own tag
+                length_prefix_len: 2 + method.tag_len(),
+            },
+        }
+    }
+
+    /// Bytes added once at the beginning of a TCP stream
+    pub fn tcp_stream_overhead(&self) -> usize {
+        self.salt_len
+    }
+
+    /// Bytes added to every chunk of a TCP stream
+    pub fn tcp_chunk_overhead(&self) -> usize {
+        self.length_prefix_len + self.tag_len
+    }
+
+    /// Bytes added to every UDP packet, excluding the target address
+    pub fn udp_packet_overhead(&self) -> usize {
+        self.salt_len + self.tag_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aead_overhead() {
+        let overhead = CipherOverhead::new(CipherKind::AES_128_GCM);
+        assert_eq!(
+            overhead,
+            CipherOverhead {
+                salt_len: 16,
+                tag_len: 16,
+                length_prefix_len: 18,
+            }
+        );
+        assert_eq!(overhead.tcp_stream_overhead(), 16);
+        assert_eq!(overhead.tcp_chunk_overhead(), 34);
+        assert_eq!(overhead.udp_packet_overhead(), 32);
+
+        let overhead = CipherOverhead::new(CipherKind::CHACHA20_POLY1305);
+        assert_eq!(overhead.salt_len, 32);
+        assert_eq!(overhead.tag_len, 16);
+        assert_eq!(overhead.tcp_chunk_overhead(), 34);
+        assert_eq!(overhead.udp_packet_overhead(), 48);
+    }
+
+    #[test]
+    fn none_overhead() {
+        let overhead = CipherOverhead::new(CipherKind::NONE);
+        assert_eq!(overhead.tcp_stream_overhead(), 0);
+        assert_eq!(overhead.tcp_chunk_overhead(), 0);
+        assert_eq!(overhead.udp_packet_overhead(), 0);
+    }
+
+    #[cfg(feature = "stream-cipher")]
+    #[test]
+    fn stream_overhead() {
+        let overhead = CipherOverhead::new(CipherKind::AES_256_CFB128);
+        assert_eq!(overhead.tcp_stream_overhead(), 16);
+        assert_eq!(overhead.tcp_chunk_overhead(), 0);
+        assert_eq!(overhead.udp_packet_overhead(), 16);
+    }
+}
@@ -918,7 +918,7 @@ impl PasswdAuthResponse {
         R: AsyncRead + Unpin,
     {
         let mut buf = [0u8; 2];
-        let _ = r.read_exact(&mut buf);
+        let _ = r.read_exact(&mut buf).await?;
 
         if buf[0] != 0x01 {
             return Err(Error::UnsupportedPasswdAuthVersion(buf[0]));
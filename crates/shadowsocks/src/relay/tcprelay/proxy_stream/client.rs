@@ -104,7 +104,12 @@ where
             Some(d) => {
                 match time::timeout(
                     d,
-                    OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts),
+                    OutboundTcpStream::connect_server_with_strategy(
+                        &context,
+                        svr_cfg.external_addr(),
+                        svr_cfg.resolve_strategy(),
+                        opts,
+                    ),
                 )
                 .await
                 {
@@ -118,7 +123,15 @@ where
                     }
                 }
             }
-            None => OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts).await?,
+            None => {
+                OutboundTcpStream::connect_server_with_strategy(
+                    &context,
+                    svr_cfg.external_addr(),
+                    svr_cfg.resolve_strategy(),
+                    opts,
+                )
+                .await?
+            }
         };
 
         trace!(
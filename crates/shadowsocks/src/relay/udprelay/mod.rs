@@ -50,9 +50,13 @@
 use std::time::Duration;
 
 pub use self::proxy_socket::ProxySocket;
+#[cfg(feature = "udp-quic")]
+pub use self::quic::{QuicProxySocket, QuicTransportConfig, TlsVersion, TlsVersionError};
 
 mod crypto_io;
 pub mod proxy_socket;
+#[cfg(feature = "udp-quic")]
+pub mod quic;
 
 /// The maximum UDP payload size (defined in the original shadowsocks Python)
 ///
@@ -61,5 +65,11 @@ pub mod proxy_socket;
 /// [here](http://support.microsoft.com/kb/822061/)*
 pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 65536;
 
+/// Largest payload of a UDP datagram over IPv4, 65535 bytes minus the IPv4 and UDP headers
+pub const MAXIMUM_UDP_DATAGRAM_SIZE_V4: usize = 65507;
+
+/// Largest payload of a UDP datagram over IPv6 without jumbograms, 65535 bytes minus the UDP header
+pub const MAXIMUM_UDP_DATAGRAM_SIZE_V6: usize = 65527;
+
 /// Default association expire time
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
@@ -5,6 +5,7 @@ use std::{io, net::SocketAddr, time::Duration};
 use bytes::BytesMut;
 use log::{trace, warn};
 use once_cell::sync::Lazy;
+use socket2::SockRef;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
     time,
@@ -15,10 +16,14 @@ use crate::{
     context::SharedContext,
     crypto::v1::CipherKind,
     net::{AcceptOpts, ConnectOpts, UdpSocket as ShadowUdpSocket},
-    relay::socks5::Address,
+    relay::{socks5::Address, CipherOverhead},
 };
 
-use super::crypto_io::{decrypt_payload, encrypt_payload};
+use super::{
+    crypto_io::{decrypt_payload, encrypt_payload},
+    MAXIMUM_UDP_DATAGRAM_SIZE_V4,
+    MAXIMUM_UDP_DATAGRAM_SIZE_V6,
+};
 
 static DEFAULT_CONNECT_OPTS: Lazy<ConnectOpts> = Lazy::new(Default::default);
 
@@ -46,7 +51,9 @@ impl ProxySocket {
     ) -> io::Result<ProxySocket> {
         // Note: Plugins doesn't support UDP relay
 
-        let socket = ShadowUdpSocket::connect_server_with_opts(&context, svr_cfg.addr(), opts).await?;
+        let socket =
+            ShadowUdpSocket::connect_server_with_strategy(&context, svr_cfg.addr(), svr_cfg.resolve_strategy(), opts)
+                .await?;
 
         trace!("connected udp remote {} with {:?}", svr_cfg.addr(), opts);
 
@@ -214,11 +221,31 @@ impl ProxySocket {
         Ok((n, target_addr, addr, recv_n))
     }
 
+    /// Largest payload to `addr` that is carried in one datagram, `send` fails for larger ones instead of splitting them
+    pub fn max_payload_size(&self, addr: &Address) -> usize {
+        // Sent to the connected server, or by an unconnected socket of the same family
+        let socket_addr = SockRef::from(&self.socket)
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_socket())
+            .or_else(|| self.socket.local_addr().ok());
+        let datagram_size = match socket_addr {
+            Some(SocketAddr::V6(saddr)) if saddr.ip().to_ipv4_mapped().is_none() => MAXIMUM_UDP_DATAGRAM_SIZE_V6,
+            _ => MAXIMUM_UDP_DATAGRAM_SIZE_V4,
+        };
+        datagram_size.saturating_sub(CipherOverhead::new(self.method).udp_packet_overhead() + addr.serialized_len())
+    }
+
     /// Get local addr of socket
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.socket.local_addr()
     }
 
+    /// Get the underlying `UdpSocket`, for setting its options
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
     /// Set `send` timeout, `None` will clear timeout
     pub fn set_send_timeout(&mut self, t: Option<Duration>) {
         self.send_timeout = t;
@@ -0,0 +1,732 @@
+//! UDP socket for communicating with shadowsocks' proxy server inside a QUIC connection
+//!
+//! Every shadowsocks UDP packet is carried in a QUIC datagram (RFC 9221) as is. Servers unwrap the datagrams and
+//! handle them like packets received from their UDP sockets, responses are sent back in datagrams of the same
+//! connection. QUIC's TLS doesn't replace shadowsocks' encryption, packets are still encrypted with the server's method.
+
+use std::{
+    fmt::{self, Display},
+    fs,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use log::trace;
+use quinn::{ClientConfig, Connection, Datagrams, Endpoint, EndpointConfig, NewConnection, SendDatagramError};
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate,
+    RootCertStore,
+    ServerName,
+    SupportedCipherSuite,
+};
+use tokio::sync::Mutex;
+use x509_parser::parse_x509_certificate;
+
+use crate::{
+    config::{ServerAddr, ServerConfig},
+    context::SharedContext,
+    crypto::v1::CipherKind,
+    net::{ConnectOpts, UdpSocket as ShadowUdpSocket},
+    relay::{socks5::Address, CipherOverhead},
+};
+
+use super::crypto_io::{decrypt_payload, encrypt_payload};
+
+/// TLS protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+impl Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsVersion::Tls12 => f.write_str("1.2"),
+            TlsVersion::Tls13 => f.write_str("1.3"),
+        }
+    }
+}
+
+/// Error while parsing `TlsVersion` from string
+#[derive(Debug, Clone)]
+pub struct TlsVersionError;
+
+impl Display for TlsVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid TlsVersion, expecting \"1.2\" or \"1.3\"")
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = TlsVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(TlsVersionError),
+        }
+    }
+}
+
+/// QUIC transport of a server's UDP relay
+#[derive(Debug, Clone)]
+pub struct QuicTransportConfig {
+    server_name: String,
+    root_certificates: Vec<Vec<u8>>,
+    min_tls_version: Option<TlsVersion>,
+    cipher_suites: Vec<SupportedCipherSuite>,
+    spki_pins: Vec<[u8; SHA256_OUTPUT_LEN]>,
+}
+
+impl QuicTransportConfig {
+    /// Create a QUIC transport, server's certificate is verified against `server_name`
+    pub fn new<S: Into<String>>(server_name: S) -> QuicTransportConfig {
+        QuicTransportConfig {
+            server_name: server_name.into(),
+            root_certificates: Vec::new(),
+            min_tls_version: None,
+            cipher_suites: Vec::new(),
+            spki_pins: Vec::new(),
+        }
+    }
+
+    /// Name for verifying server's certificate, also sent as TLS SNI
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Trust a DER encoded root certificate, for servers with self-signed certificates
+    ///
+    /// The platform's root certificates are trusted if none is added.
+    pub fn add_root_certificate(&mut self, der: Vec<u8>) {
+        self.root_certificates.push(der);
+    }
+
+    /// Trust root certificates in a PEM or DER file
+    pub fn load_root_certificates<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+        if !data.starts_with(b"-----BEGIN") {
+            self.add_root_certificate(data);
+            return Ok(());
+        }
+
+        let pem = String::from_utf8(data).map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid PEM file"))?;
+        let mut der_base64 = None;
+        for line in pem.lines().map(str::trim) {
+            match line {
+                "-----BEGIN CERTIFICATE-----" => der_base64 = Some(String::new()),
+                "-----END CERTIFICATE-----" => {
+                    if let Some(b) = der_base64.take() {
+                        let der = base64::decode(b).map_err(|err| {
+                            io::Error::new(ErrorKind::InvalidData, format!("invalid PEM file, {}", err))
+                        })?;
+                        self.add_root_certificate(der);
+                    }
+                }
+                _ => {
+                    if let Some(ref mut b) = der_base64 {
+                        b.push_str(line);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Trusted root certificates, DER encoded
+    pub fn root_certificates(&self) -> &[Vec<u8>] {
+        &self.root_certificates
+    }
+
+    /// Require at least TLS `version` for handshakes with the server
+    ///
+    /// QUIC always negotiates TLS 1.3 (RFC 9001), which satisfies every supported minimum. It is kept for making the
+    /// policy explicit in configurations shared with other TLS transports.
+    pub fn set_min_tls_version(&mut self, version: TlsVersion) {
+        self.min_tls_version = Some(version);
+    }
+
+    /// Minimum TLS version required, `None` if not configured
+    pub fn min_tls_version(&self) -> Option<TlsVersion> {
+        self.min_tls_version
+    }
+
+    /// Allow the TLS 1.3 cipher suite `name`, like `TLS13_AES_256_GCM_SHA384`
+    ///
+    /// rustls' safe defaults are offered if none is allowed. Handshakes with servers that don't support any of the
+    /// allowed suites fail.
+    pub fn add_cipher_suite(&mut self, name: &str) -> io::Result<()> {
+        let suite = rustls::ALL_CIPHER_SUITES
+            .iter()
+            .find(|suite| cipher_suite_name(suite) == name)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("unknown cipher suite {}", name)))?;
+        if !matches!(*suite, SupportedCipherSuite::Tls13(..)) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cipher suite {} is not for TLS 1.3, which is the only version of QUIC",
+                    name
+                ),
+            ));
+        }
+
+        if !self.cipher_suites.contains(suite) {
+            self.cipher_suites.push(*suite);
+        }
+        Ok(())
+    }
+
+    /// Names of allowed cipher suites, empty if rustls' defaults are offered
+    pub fn cipher_suites(&self) -> Vec<String> {
+        self.cipher_suites.iter().map(cipher_suite_name).collect()
+    }
+
+    /// Pin server's public key, `pin` is `sha256/` followed by the base64 encoded SHA-256 of its certificate's
+    /// SubjectPublicKeyInfo, like HPKP and curl's `--pinnedpubkey`
+    ///
+    /// Certificates are still verified against root certificates, handshakes fail if the server's key matches none of
+    /// the pins. Keys are not pinned if none is added. Only this QUIC transport is pinned, not TCP or plugin
+    /// connections to the server.
+    pub fn add_spki_pin(&mut self, pin: &str) -> io::Result<()> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid pin {}, expecting \"sha256/\" followed by base64 encoded SHA-256",
+                    pin
+                ),
+            )
+        };
+
+        let hash = pin.strip_prefix("sha256/").ok_or_else(invalid)?;
+        let hash = base64::decode(hash).map_err(|_| invalid())?;
+        let hash: [u8; SHA256_OUTPUT_LEN] = hash.try_into().map_err(|_| invalid())?;
+        if !self.spki_pins.contains(&hash) {
+            self.spki_pins.push(hash);
+        }
+        Ok(())
+    }
+
+    /// Pinned public keys, formatted as they are added
+    pub fn spki_pins(&self) -> Vec<String> {
+        self.spki_pins
+            .iter()
+            .map(|hash| format!("sha256/{}", base64::encode(hash)))
+            .collect()
+    }
+
+    fn client_config(&self) -> io::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        if self.root_certificates.is_empty() {
+            for cert in rustls_native_certs::load_native_certs()? {
+                if let Err(err) = roots.add(&Certificate(cert.0)) {
+                    trace!("failed to add native root certificate, {}", err);
+                }
+            }
+        }
+        for der in self.root_certificates.iter() {
+            roots
+                .add(&Certificate(der.clone()))
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("invalid root certificate, {}", err)))?;
+        }
+
+        // Same as quinn's default client configuration, except for the cipher suites
+        let builder = rustls::ClientConfig::builder();
+        let builder = if self.cipher_suites.is_empty() {
+            builder.with_safe_default_cipher_suites()
+        } else {
+            builder.with_cipher_suites(&self.cipher_suites)
+        };
+        let mut crypto = builder
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?
+            .with_custom_certificate_verifier(Arc::new(PinnedServerVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                spki_pins: self.spki_pins.clone(),
+            }))
+            .with_no_client_auth();
+        crypto.enable_early_data = true;
+
+        Ok(ClientConfig::new(Arc::new(crypto)))
+    }
+}
+
+fn cipher_suite_name(suite: &SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+/// Verifies server's certificate with webpki, then checks its public key against the pins
+struct PinnedServerVerifier {
+    inner: WebPkiVerifier,
+    spki_pins: Vec<[u8; SHA256_OUTPUT_LEN]>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified =
+            self.inner
+                .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        if self.spki_pins.is_empty() {
+            return Ok(verified);
+        }
+
+        let spki = certificate_spki(&end_entity.0).ok_or(rustls::Error::InvalidCertificateEncoding)?;
+        let hash = digest(&SHA256, spki);
+        if self.spki_pins.iter().any(|pin| pin[..] == *hash.as_ref()) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "server's public key matches none of the pins".to_owned(),
+            ))
+        }
+    }
+}
+
+/// DER encoded SubjectPublicKeyInfo of a DER encoded X.509 certificate (RFC 5280)
+fn certificate_spki(cert: &[u8]) -> Option<&[u8]> {
+    match parse_x509_certificate(cert) {
+        Ok(([], cert)) => Some(cert.tbs_certificate.subject_pki.raw),
+        _ => None,
+    }
+}
+
+/// UDP client for communicating with ShadowSocks' server through QUIC
+pub struct QuicProxySocket {
+    // Connections are closed if their endpoint is dropped
+    _endpoint: Endpoint,
+    connection: Connection,
+    datagrams: Mutex<Datagrams>,
+    method: CipherKind,
+    key: Box<[u8]>,
+    context: SharedContext,
+}
+
+impl QuicProxySocket {
+    /// Create a client to communicate with Shadowsocks' QUIC endpoint (outbound)
+    ///
+    /// `svr_cfg` must have a QUIC transport
+    pub async fn connect_with_opts(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        opts: &ConnectOpts,
+    ) -> io::Result<QuicProxySocket> {
+        let quic_cfg = match svr_cfg.quic_transport() {
+            Some(c) => c,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("server {} doesn't have a QUIC transport", svr_cfg.addr()),
+                ))
+            }
+        };
+
+        let remote_addr = match *svr_cfg.addr() {
+            ServerAddr::SocketAddr(addr) => addr,
+            ServerAddr::DomainName(ref dname, port) => {
+                lookup_then!(&context, dname, port, svr_cfg.resolve_strategy(), |addr| {
+                    Ok::<_, io::Error>(addr)
+                })?
+                .1
+            }
+        };
+
+        // Endpoint sends to the server with `sendmsg`, the socket mustn't be connected
+        let socket = ShadowUdpSocket::connect_any_with_opts(&remote_addr, opts).await?;
+        let socket: tokio::net::UdpSocket = socket.into();
+        let (mut endpoint, ..) = Endpoint::new(EndpointConfig::default(), None, socket.into_std()?)?;
+        endpoint.set_default_client_config(quic_cfg.client_config()?);
+
+        let connecting = endpoint
+            .connect(remote_addr, quic_cfg.server_name())
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        let NewConnection {
+            connection, datagrams, ..
+        } = connecting.await?;
+
+        trace!(
+            "connected udp remote {} ({}) through quic with {:?}",
+            svr_cfg.addr(),
+            remote_addr,
+            opts
+        );
+
+        Ok(QuicProxySocket {
+            _endpoint: endpoint,
+            connection,
+            datagrams: Mutex::new(datagrams),
+            method: svr_cfg.method(),
+            key: svr_cfg.key().to_vec().into_boxed_slice(),
+            context,
+        })
+    }
+
+    /// Send a UDP packet to addr through proxy
+    pub async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<usize> {
+        let mut send_buf = BytesMut::new();
+        encrypt_payload(&self.context, self.method, &self.key, addr, payload, &mut send_buf);
+
+        trace!(
+            "UDP server client send to {} through quic, payload length {} bytes, packet length {} bytes",
+            addr,
+            payload.len(),
+            send_buf.len()
+        );
+
+        let send_len = send_buf.len();
+        match self.connection.send_datagram(send_buf.freeze()) {
+            Ok(()) => Ok(send_len),
+            Err(SendDatagramError::TooLarge) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "packet with {} bytes is larger than quic datagram's maximum {:?} bytes",
+                    send_len,
+                    self.connection.max_datagram_size()
+                ),
+            )),
+            Err(SendDatagramError::ConnectionLost(err)) => Err(err.into()),
+            Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+        }
+    }
+
+    /// Largest payload to `addr` that is carried in one datagram, `None` if the server doesn't accept datagrams
+    ///
+    /// It follows the path's MTU, `send` fails for larger ones instead of splitting them.
+    pub fn max_payload_size(&self, addr: &Address) -> Option<usize> {
+        self.connection.max_datagram_size().map(|size| {
+            size.saturating_sub(CipherOverhead::new(self.method).udp_packet_overhead() + addr.serialized_len())
+        })
+    }
+
+    /// Receive packet from Shadowsocks' QUIC endpoint
+    ///
+    /// `recv_buf` has to be big enough to store the whole shadowsocks' packet
+    pub async fn recv(&self, recv_buf: &mut [u8]) -> io::Result<(usize, Address, usize)> {
+        let packet: Bytes = match self.datagrams.lock().await.next().await {
+            Some(Ok(p)) => p,
+            Some(Err(err)) => return Err(err.into()),
+            None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "quic connection closed")),
+        };
+
+        let recv_n = packet.len();
+        if recv_n > recv_buf.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("quic datagram with {} bytes is larger than buffer", recv_n),
+            ));
+        }
+        recv_buf[..recv_n].copy_from_slice(&packet);
+
+        let (n, addr) = decrypt_payload(&self.context, self.method, &self.key, &mut recv_buf[..recv_n]).await?;
+
+        trace!(
+            "UDP server client receive from {} through quic, packet length {} bytes, payload length {} bytes",
+            addr,
+            recv_n,
+            n
+        );
+
+        Ok((n, addr, recv_n))
+    }
+
+    /// Server's address of the QUIC connection
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quinn::ServerConfig as QuicServerConfig;
+    use rustls::PrivateKey;
+
+    use crate::{config::ServerType, context::Context};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn quic_round_trip() {
+        let cert = include_bytes!("testdata/quic-test-cert.der").to_vec();
+        let key = include_bytes!("testdata/quic-test-key.der").to_vec();
+
+        let quic_server_cfg =
+            QuicServerConfig::with_single_cert(vec![Certificate(cert.clone())], PrivateKey(key)).unwrap();
+        let (endpoint, mut incoming) = Endpoint::server(quic_server_cfg, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = endpoint.local_addr().unwrap();
+
+        let mut svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM);
+        let mut quic_cfg = QuicTransportConfig::new("localhost");
+        quic_cfg.add_root_certificate(cert);
+        svr_cfg.set_quic_transport(quic_cfg);
+
+        // Mock server, echoes payloads back from the target
+        let mock_svr_cfg = svr_cfg.clone();
+        tokio::spawn(async move {
+            let context = Context::new_shared(ServerType::Server);
+            let NewConnection {
+                connection,
+                mut datagrams,
+                ..
+            } = incoming.next().await.unwrap().await.unwrap();
+            while let Some(Ok(packet)) = datagrams.next().await {
+                let mut packet = packet.to_vec();
+                let (n, addr) = decrypt_payload(&context, mock_svr_cfg.method(), mock_svr_cfg.key(), &mut packet)
+                    .await
+                    .unwrap();
+                let mut response = BytesMut::new();
+                encrypt_payload(
+                    &context,
+                    mock_svr_cfg.method(),
+                    mock_svr_cfg.key(),
+                    &addr,
+                    &packet[..n],
+                    &mut response,
+                );
+                connection.send_datagram(response.freeze()).unwrap();
+            }
+        });
+
+        let socket = QuicProxySocket::connect_with_opts(
+            Context::new_shared(ServerType::Local),
+            &svr_cfg,
+            &ConnectOpts::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(socket.remote_addr(), server_addr);
+
+        let target_addr = Address::SocketAddress("127.0.0.1:53".parse().unwrap());
+        let payload = b"hello quic";
+        let send_n = socket.send(&target_addr, payload).await.unwrap();
+
+        let mut buffer = [0u8; 2048];
+        let (n, addr, recv_n) = socket.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], payload);
+        assert_eq!(addr, target_addr);
+        assert_eq!(recv_n, send_n);
+    }
+
+    #[tokio::test]
+    async fn quic_disallowed_cipher_suite_rejected() {
+        let cert = include_bytes!("testdata/quic-test-cert.der").to_vec();
+        let key = include_bytes!("testdata/quic-test-key.der").to_vec();
+
+        // Server only supports AES-128-GCM
+        let mut server_crypto = rustls::ServerConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_128_GCM_SHA256])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(cert.clone())], PrivateKey(key))
+            .unwrap();
+        server_crypto.max_early_data_size = u32::MAX;
+        let quic_server_cfg = QuicServerConfig::with_crypto(Arc::new(server_crypto));
+        let (endpoint, mut incoming) = Endpoint::server(quic_server_cfg, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(connecting) = incoming.next().await {
+                let _ = connecting.await;
+            }
+        });
+
+        let mut quic_cfg = QuicTransportConfig::new("localhost");
+        quic_cfg.add_root_certificate(cert);
+        quic_cfg.set_min_tls_version(TlsVersion::Tls13);
+
+        // Allowed suite
+        let mut allowed_cfg = quic_cfg.clone();
+        allowed_cfg.add_cipher_suite("TLS13_AES_128_GCM_SHA256").unwrap();
+        let mut svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM);
+        svr_cfg.set_quic_transport(allowed_cfg);
+        QuicProxySocket::connect_with_opts(
+            Context::new_shared(ServerType::Local),
+            &svr_cfg,
+            &ConnectOpts::default(),
+        )
+        .await
+        .unwrap();
+
+        // Server doesn't support any of the allowed suites
+        let mut disallowed_cfg = quic_cfg.clone();
+        disallowed_cfg
+            .add_cipher_suite("TLS13_CHACHA20_POLY1305_SHA256")
+            .unwrap();
+        assert_eq!(disallowed_cfg.cipher_suites(), ["TLS13_CHACHA20_POLY1305_SHA256"]);
+        svr_cfg.set_quic_transport(disallowed_cfg);
+        let result = QuicProxySocket::connect_with_opts(
+            Context::new_shared(ServerType::Local),
+            &svr_cfg,
+            &ConnectOpts::default(),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn quic_spki_pin() {
+        let cert = include_bytes!("testdata/quic-test-cert.der").to_vec();
+        let key = include_bytes!("testdata/quic-test-key.der").to_vec();
+
+        let quic_server_cfg =
+            QuicServerConfig::with_single_cert(vec![Certificate(cert.clone())], PrivateKey(key)).unwrap();
+        let (endpoint, mut incoming) = Endpoint::server(quic_server_cfg, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(connecting) = incoming.next().await {
+                let _ = connecting.await;
+            }
+        });
+
+        let mut quic_cfg = QuicTransportConfig::new("localhost");
+        quic_cfg.add_root_certificate(cert);
+        assert!(quic_cfg
+            .add_spki_pin("cnnTXmqYhR+aSGIwbrnjySTYrs4Vr41rEdY7u3zRyy0=")
+            .is_err());
+        assert!(quic_cfg.add_spki_pin("sha256/AAAA").is_err());
+
+        // Test certificate's public key
+        let mut matched_cfg = quic_cfg.clone();
+        matched_cfg
+            .add_spki_pin("sha256/cnnTXmqYhR+aSGIwbrnjySTYrs4Vr41rEdY7u3zRyy0=")
+            .unwrap();
+        assert_eq!(
+            matched_cfg.spki_pins(),
+            ["sha256/cnnTXmqYhR+aSGIwbrnjySTYrs4Vr41rEdY7u3zRyy0="]
+        );
+        let mut svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM);
+        svr_cfg.set_quic_transport(matched_cfg);
+        QuicProxySocket::connect_with_opts(
+            Context::new_shared(ServerType::Local),
+            &svr_cfg,
+            &ConnectOpts::default(),
+        )
+        .await
+        .unwrap();
+
+        // Certificate is trusted, but its public key isn't pinned
+        let mut mismatched_cfg = quic_cfg.clone();
+        mismatched_cfg
+            .add_spki_pin("sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+            .unwrap();
+        svr_cfg.set_quic_transport(mismatched_cfg);
+        let result = QuicProxySocket::connect_with_opts(
+            Context::new_shared(ServerType::Local),
+            &svr_cfg,
+            &ConnectOpts::default(),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn malformed_certificate_spki_rejected() {
+        /// DER element of `tag` with `contents`, length in long form if it doesn't fit in 7 bits
+        fn element(tag: u8, contents: &[u8]) -> Vec<u8> {
+            let mut element = vec![tag];
+            match contents.len() {
+                len @ 0..=0x7f => element.push(len as u8),
+                len @ 0x80..=0xff => element.extend_from_slice(&[0x81, len as u8]),
+                len => element.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+            }
+            element.extend_from_slice(contents);
+            element
+        }
+
+        // Certificate's header, tbsCertificate's header, version, fields before subjectPublicKeyInfo, its extensions,
+        // offsets are listed by `openssl asn1parse -inform DER`
+        let cert = include_bytes!("testdata/quic-test-cert.der");
+        let (tbs, signature) = (&cert[8..320], &cert[320..]);
+        let (version, fields, spki, extensions) = (&tbs[..5], &tbs[5..117], &tbs[117..208], &tbs[208..]);
+        assert_eq!(version, [0xa0, 0x03, 0x02, 0x01, 0x02]);
+        assert_eq!(certificate_spki(cert), Some(spki));
+        assert_eq!(
+            digest(&SHA256, spki).as_ref(),
+            base64::decode("cnnTXmqYhR+aSGIwbrnjySTYrs4Vr41rEdY7u3zRyy0=").unwrap()
+        );
+
+        let certificate = |tbs: &[u8]| element(0x30, &[&element(0x30, tbs)[..], signature].concat());
+        assert_eq!(certificate(tbs), cert);
+
+        // Truncated anywhere, in the headers, inside tbsCertificate or after it
+        for len in 0..cert.len() {
+            assert_eq!(certificate_spki(&cert[..len]), None, "truncated to {} bytes", len);
+        }
+        // Trailing data after the certificate
+        assert_eq!(certificate_spki(&[&cert[..], &[0]].concat()), None);
+
+        // Long-form lengths which are not minimal, accepted by the parser but the fields are found as is
+        for header in [
+            &[0x30, 0x83, 0x00, 0x01, 0x91][..],
+            &[0x30, 0x89, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x91],
+        ] {
+            let malformed = [header, &cert[4..]].concat();
+            assert!(certificate_spki(&malformed).is_none_or(|key| key == spki));
+        }
+
+        // Lengths longer than the input, one of them overflows
+        for header in [
+            &[0x30, 0x82, 0x01, 0x92][..],
+            &[0x30, 0x84, 0xff, 0xff, 0xff, 0xff],
+            &[0x30, 0x89, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ] {
+            let malformed = [header, &cert[4..]].concat();
+            assert_eq!(certificate_spki(&malformed), None, "header {:02x?}", header);
+        }
+        // Shorter than tbsCertificate's contents
+        let malformed = [&cert[..4], &[0x30, 0x81, 0x01], &cert[8..]].concat();
+        assert_eq!(certificate_spki(&malformed), None);
+
+        // Version 1 certificate without the version, the same public key is found
+        let v1 = certificate(&[fields, spki].concat());
+        assert_eq!(certificate_spki(&v1), Some(spki));
+        // Missing version, but has extensions which are only in version 3, the parser may accept it
+        let malformed = certificate(&[fields, spki, extensions].concat());
+        assert!(certificate_spki(&malformed).is_none_or(|key| key == spki));
+        // Missing the version and the serial number, fields are shifted by one
+        assert_eq!(
+            certificate_spki(&certificate(&[&fields[22..], spki, extensions].concat())),
+            None
+        );
+    }
+
+    #[test]
+    fn tls_policy_parse() {
+        assert_eq!("1.2".parse::<TlsVersion>().unwrap(), TlsVersion::Tls12);
+        assert_eq!(
+            TlsVersion::Tls13.to_string().parse::<TlsVersion>().unwrap(),
+            TlsVersion::Tls13
+        );
+        assert!("1.1".parse::<TlsVersion>().is_err());
+        assert!(TlsVersion::Tls12 < TlsVersion::Tls13);
+
+        let mut quic_cfg = QuicTransportConfig::new("localhost");
+        assert!(quic_cfg.add_cipher_suite("TLS13_AES_256_GCM_SHA384").is_ok());
+        // TLS 1.2 suites are never negotiated by QUIC
+        assert!(quic_cfg
+            .add_cipher_suite("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384")
+            .is_err());
+        assert!(quic_cfg.add_cipher_suite("NOT_A_SUITE").is_err());
+        assert_eq!(quic_cfg.cipher_suites(), ["TLS13_AES_256_GCM_SHA384"]);
+    }
+}
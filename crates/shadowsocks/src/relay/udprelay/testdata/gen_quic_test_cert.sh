@@ -0,0 +1,15 @@
+#!/bin/sh
+# Generates quic-test-cert.der and quic-test-key.der, a self-signed certificate of "localhost" for unit tests
+#
+# The certificate is not a CA, webpki rejects CA certificates used by servers
+
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 36500 \
+    -subj "/CN=localhost" \
+    -addext "subjectAltName=DNS:localhost" \
+    -addext "basicConstraints=critical,CA:FALSE" \
+    -keyout quic-test-key.pem -outform DER -out quic-test-cert.der
+openssl pkcs8 -topk8 -nocrypt -in quic-test-key.pem -outform DER -out quic-test-key.der
+rm quic-test-key.pem
@@ -1,5 +1,7 @@
 //! Local server launchers
 
+#[cfg(feature = "acl-asn")]
+use std::sync::Arc;
 use std::{net::IpAddr, path::PathBuf, process, time::Duration};
 
 use clap::{Arg, ArgGroup, ArgMatches, Command, ErrorKind as ClapErrorKind};
@@ -7,6 +9,8 @@ use futures::future::{self, Either};
 use log::{info, trace};
 use tokio::{self, runtime::Builder};
 
+#[cfg(feature = "acl-asn")]
+use shadowsocks_service::acl::AsnDatabase;
 #[cfg(feature = "local-redir")]
 use shadowsocks_service::config::RedirType;
 #[cfg(any(feature = "local-dns", feature = "local-tunnel"))]
@@ -145,6 +149,7 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
     .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
+    .arg(Arg::new("INBOUND_TCP_INITIAL_WINDOW").long("inbound-tcp-initial-window").takes_value(true).validator(validator::validate_u32).help("Hint of TCP initial congestion window in segments, only honored by tun"))
     .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_BIND_ADDR").long("outbound-bind-addr").takes_value(true).alias("bind-addr").validator(validator::validate_ip_addr).help("Bind address, outbound socket will bind this address"))
@@ -258,6 +263,16 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
         );
     }
 
+    #[cfg(feature = "acl-asn")]
+    {
+        app = app.arg(
+            Arg::new("ACL_ASN_DATABASE")
+                .long("acl-asn-database")
+                .takes_value(true)
+                .help("Path to MaxMind ASN database (.mmdb) for ASN rules in ACL"),
+        );
+    }
+
     #[cfg(feature = "local-flow-stat")]
     {
         app = app.arg(
@@ -446,6 +461,7 @@ pub fn main(matches: &ArgMatches) {
                     plugin: p.to_owned(),
                     plugin_opts: matches.value_of("PLUGIN_OPT").map(ToOwned::to_owned),
                     plugin_args: Vec::new(),
+                    flow_metadata: false,
                 };
 
                 sc.set_plugin(plugin);
@@ -681,6 +697,18 @@ pub fn main(matches: &ArgMatches) {
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "acl-asn")]
+        if let Some(asn_database_path) = matches.value_of("ACL_ASN_DATABASE") {
+            let asn_database = match AsnDatabase::open(asn_database_path) {
+                Ok(d) => d,
+                Err(err) => {
+                    eprintln!("loading ASN database \"{}\", {}", asn_database_path, err);
+                    process::exit(crate::EXIT_CODE_LOAD_ACL_FAILURE);
+                }
+            };
+            config.asn_database = Some(Arc::new(asn_database));
+        }
+
         if let Some(dns) = matches.value_of("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
@@ -711,6 +739,11 @@ pub fn main(matches: &ArgMatches) {
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
             Err(err) => err.exit(),
         }
+        match matches.value_of_t::<u32>("INBOUND_TCP_INITIAL_WINDOW") {
+            Ok(iw) => config.inbound_tcp_initial_window = Some(iw),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
         match matches.value_of_t::<u32>("OUTBOUND_SEND_BUFFER_SIZE") {
             Ok(bs) => config.outbound_send_buffer_size = Some(bs),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
@@ -1,5 +1,7 @@
 //! Server Manager launchers
 
+#[cfg(feature = "acl-asn")]
+use std::sync::Arc;
 use std::{net::IpAddr, path::PathBuf, process, time::Duration};
 
 use clap::{Arg, ArgGroup, ArgMatches, Command, ErrorKind as ClapErrorKind};
@@ -7,6 +9,8 @@ use futures::future::{self, Either};
 use log::{info, trace};
 use tokio::{self, runtime::Builder};
 
+#[cfg(feature = "acl-asn")]
+use shadowsocks_service::acl::AsnDatabase;
 #[cfg(unix)]
 use shadowsocks_service::config::ManagerServerMode;
 use shadowsocks_service::{
@@ -108,6 +112,16 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .help("Resolve hostname to IPv6 address first"),
         );
 
+    #[cfg(feature = "acl-asn")]
+    {
+        app = app.arg(
+            Arg::new("ACL_ASN_DATABASE")
+                .long("acl-asn-database")
+                .takes_value(true)
+                .help("Path to MaxMind ASN database (.mmdb) for ASN rules in ACL"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -330,6 +344,7 @@ pub fn main(matches: &ArgMatches) {
                     plugin: p.to_owned(),
                     plugin_opts: matches.value_of("PLUGIN_OPT").map(ToOwned::to_owned),
                     plugin_args: Vec::new(),
+                    flow_metadata: false,
                 });
             }
 
@@ -381,6 +396,18 @@ pub fn main(matches: &ArgMatches) {
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "acl-asn")]
+        if let Some(asn_database_path) = matches.value_of("ACL_ASN_DATABASE") {
+            let asn_database = match AsnDatabase::open(asn_database_path) {
+                Ok(d) => d,
+                Err(err) => {
+                    eprintln!("loading ASN database \"{}\", {}", asn_database_path, err);
+                    process::exit(crate::EXIT_CODE_LOAD_ACL_FAILURE);
+                }
+            };
+            config.asn_database = Some(Arc::new(asn_database));
+        }
+
         if let Some(dns) = matches.value_of("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
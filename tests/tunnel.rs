@@ -142,3 +142,183 @@ async fn udp_tunnel() {
 
     assert_eq!(MESSAGE, recv_payload);
 }
+
+/// Echoed packets per second through a UDP tunnel of a single busy client
+///
+/// `local_extra` is inserted into the local's configuration, e.g. `"udp_outbound_pool_size": 4`
+#[cfg(feature = "multi-threaded")]
+async fn udp_tunnel_packet_rate(local_extra: &str, port_base: u16) -> f64 {
+    use std::time::Instant;
+
+    const PACKET_SIZE: usize = 1200;
+    // Packets in flight, the client sends a new one for every echo
+    const WINDOW: usize = 256;
+    const DURATION: Duration = Duration::from_secs(3);
+
+    let (local_port, server_port, echo_port) = (port_base, port_base + 10, port_base + 20);
+
+    tokio::spawn(async move {
+        let socket = UdpSocket::bind(("127.0.0.1", echo_port)).await.unwrap();
+        let mut buffer = [0u8; 65536];
+        loop {
+            let (n, peer_addr) = socket.recv_from(&mut buffer).await.unwrap();
+            let _ = socket.send_to(&buffer[..n], peer_addr).await;
+        }
+    });
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "locals": [
+                    {{
+                        "local_port": {},
+                        "local_address": "127.0.0.1",
+                        "protocol": "tunnel",
+                        "forward_address": "127.0.0.1",
+                        "forward_port": {}
+                    }}
+                ],
+                "server": "127.0.0.1",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm",
+                "mode": "udp_only",
+                {}
+            }}"#,
+            local_port, echo_port, server_port, local_extra
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "server": "127.0.0.1",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm",
+                "mode": "udp_only"
+            }}"#,
+            server_port
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    tokio::spawn(run_server(server_config));
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(("127.0.0.1", local_port)).await.unwrap();
+
+    let payload = [0u8; PACKET_SIZE];
+    let mut buffer = [0u8; 65536];
+    let mut received = 0u64;
+
+    for _ in 0..WINDOW {
+        socket.send(&payload).await.unwrap();
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < DURATION {
+        match time::timeout(Duration::from_millis(100), socket.recv(&mut buffer)).await {
+            Ok(r) => {
+                r.unwrap();
+                received += 1;
+                socket.send(&payload).await.unwrap();
+            }
+            Err(..) => {
+                // Lost packets are replaced to keep the window full
+                for _ in 0..WINDOW {
+                    socket.send(&payload).await.unwrap();
+                }
+            }
+        }
+    }
+
+    received as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Benchmark of striping a UDP tunnel association across outbound sockets
+///
+/// Stripes are relayed by their own tasks, so the improvement depends on spare CPU cores for the runtime's workers.
+///
+/// ```bash
+/// cargo test --release --test tunnel -- --ignored --nocapture udp_tunnel_outbound_pool_bench
+/// ```
+#[cfg(feature = "multi-threaded")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore]
+async fn udp_tunnel_outbound_pool_bench() {
+    let _ = env_logger::try_init();
+
+    let single = udp_tunnel_packet_rate(r#""udp_outbound_pool_size": 1"#, 9410).await;
+    let pooled = udp_tunnel_packet_rate(r#""udp_outbound_pool_size": 4"#, 9510).await;
+
+    println!("udp tunnel outbound pool size 1: {:.0} packets/s", single);
+    println!(
+        "udp tunnel outbound pool size 4: {:.0} packets/s ({:.2}x)",
+        pooled,
+        pooled / single
+    );
+}
+
+/// CPU time consumed by this process, both user and system
+#[cfg(all(
+    feature = "multi-threaded",
+    feature = "local-udp-mmsg",
+    any(target_os = "linux", target_os = "android")
+))]
+fn process_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    assert_eq!(ret, 0, "getrusage failed");
+
+    let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+/// Benchmark of batching a UDP tunnel's datagrams by `recvmmsg` / `sendmmsg`
+///
+/// CPU time is of the whole process, including the client, the echo server and the shadowsocks server, which are the
+/// same in both runs.
+///
+/// ```bash
+/// cargo test --release --features local-udp-mmsg --test tunnel -- --ignored --nocapture udp_tunnel_mmsg_bench
+/// ```
+#[cfg(all(
+    feature = "multi-threaded",
+    feature = "local-udp-mmsg",
+    any(target_os = "linux", target_os = "android")
+))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore]
+async fn udp_tunnel_mmsg_bench() {
+    let _ = env_logger::try_init();
+
+    let mut results = Vec::new();
+    for (batch_size, port_base) in [(1, 9610), (32, 9710)] {
+        let cpu_start = process_cpu_time();
+        let rate = udp_tunnel_packet_rate(&format!(r#""udp_mmsg_batch_size": {}"#, batch_size), port_base).await;
+        let cpu = process_cpu_time() - cpu_start;
+
+        // Packets echoed while measuring, the rate is measured in 3 seconds
+        let packets = rate * 3.0;
+        results.push((batch_size, rate, cpu.as_secs_f64() * 1e6 / packets));
+    }
+
+    let (_, base_rate, base_cpu) = results[0];
+    for (batch_size, rate, cpu) in results {
+        println!(
+            "udp tunnel mmsg batch size {}: {:.0} packets/s ({:.2}x), {:.2} us CPU/packet ({:.2}x)",
+            batch_size,
+            rate,
+            rate / base_rate,
+            cpu,
+            cpu / base_cpu
+        );
+    }
+}